@@ -16,6 +16,8 @@ cargo test --manifest-path ./embassy-executor/Cargo.toml --features metadata-nam
 cargo test --manifest-path ./embassy-futures/Cargo.toml
 cargo test --manifest-path ./embassy-sync/Cargo.toml
 cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml
+cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml --features block-device
+cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml --features time
 cargo test --manifest-path ./embassy-hal-internal/Cargo.toml
 cargo test --manifest-path ./embassy-time/Cargo.toml --features mock-driver,embassy-time-queue-utils/generic-queue-8
 cargo test --manifest-path ./embassy-time-driver/Cargo.toml
@@ -23,6 +25,7 @@ cargo test --manifest-path ./embassy-time-driver/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-dalek
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-salty
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features aes-ctr
 
 cargo test --manifest-path ./embassy-nrf/Cargo.toml --no-default-features --features nrf52840,time-driver-rtc1,gpiote
 
@@ -35,6 +38,13 @@ cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --fe
 cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --features stm32f769ni,time-driver-any,exti,single-bank,test
 cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --features stm32f769ni,time-driver-any,exti,dual-bank,test
 
+cargo test --manifest-path ./embassy-net/Cargo.toml --features dhcpv4-server,medium-ethernet,medium-ip,proto-ipv4,proto-ipv6,tcp-retransmissions,udp,mdns-responder
 cargo test --manifest-path ./embassy-net-adin1110/Cargo.toml
+cargo test --manifest-path ./embassy-net-pcap/Cargo.toml
+cargo test --manifest-path ./embassy-net-slip/Cargo.toml
+cargo test --manifest-path ./embassy-net-virtual/Cargo.toml
+cargo test --manifest-path ./embassy-net-websocket/Cargo.toml
+cargo test --manifest-path ./embassy-usb/Cargo.toml --features max-configuration-count-2
 cargo test --manifest-path ./embassy-usb-dfu/Cargo.toml --features dfu
+cargo test --manifest-path ./embassy-usb-logger/Cargo.toml --features defmt-logger
 cargo test --manifest-path ./embassy-usb-host/Cargo.toml
\ No newline at end of file
@@ -1180,7 +1180,10 @@ impl<'a, BUS: Bus, CHIP: Chip> Runner<'a, BUS, CHIP> {
                         buf[..packet.len()].copy_from_slice(packet);
                         buf.rx_done(packet.len())
                     }
-                    None => warn!("failed to push rxd packet to the channel."),
+                    None => {
+                        warn!("failed to push rxd packet to the channel.");
+                        self.ch.record_rx_dropped();
+                    }
                 }
             }
             _ => {}
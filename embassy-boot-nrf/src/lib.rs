@@ -5,8 +5,8 @@
 mod fmt;
 
 pub use embassy_boot::{
-    AlignedBuffer, BlockingFirmwareState, BlockingFirmwareUpdater, BootError, BootLoaderConfig, FirmwareState,
-    FirmwareUpdater, FirmwareUpdaterConfig,
+    AlignedBuffer, BlockingFirmwareState, BlockingFirmwareUpdater, BootError, BootLoaderConfig, FirmwareCipher,
+    FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig, NoEncryption,
 };
 use embassy_nrf::nvmc::PAGE_SIZE;
 use embassy_nrf::{Peri, wdt};
@@ -32,10 +32,34 @@ impl<const BUFFER_SIZE: usize> BootLoader<BUFFER_SIZE> {
     /// Inspect the bootloader state and perform actions required before booting, such as swapping firmware
     pub fn try_prepare<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
         config: BootLoaderConfig<ACTIVE, DFU, STATE>,
+    ) -> Result<Self, BootError> {
+        Self::try_prepare_with_cipher::<ACTIVE, DFU, STATE>(config, NoEncryption)
+    }
+
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping firmware,
+    /// decrypting the update image with `cipher`
+    pub fn prepare_with_cipher<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
+        config: BootLoaderConfig<ACTIVE, DFU, STATE>,
+        cipher: impl FirmwareCipher,
+    ) -> Self {
+        if let Ok(loader) = Self::try_prepare_with_cipher::<ACTIVE, DFU, STATE>(config, cipher) {
+            loader
+        } else {
+            // Use explicit panic instead of .expect() to ensure this gets routed via defmt/etc.
+            // properly
+            panic!("Boot prepare error")
+        }
+    }
+
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping firmware,
+    /// decrypting the update image with `cipher`
+    pub fn try_prepare_with_cipher<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
+        config: BootLoaderConfig<ACTIVE, DFU, STATE>,
+        cipher: impl FirmwareCipher,
     ) -> Result<Self, BootError> {
         let mut aligned_buf = AlignedBuffer([0; BUFFER_SIZE]);
         let mut boot = embassy_boot::BootLoader::new(config);
-        let _state = boot.prepare_boot(aligned_buf.as_mut())?;
+        let _state = boot.prepare_boot_with_cipher(aligned_buf.as_mut(), cipher)?;
         Ok(Self)
     }
 
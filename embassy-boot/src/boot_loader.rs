@@ -5,7 +5,7 @@ use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
 
-use crate::{DFU_DETACH_MAGIC, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC, State};
+use crate::{DFU_DETACH_MAGIC, FirmwareCipher, NoEncryption, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC, State};
 
 /// Errors returned by bootloader
 #[derive(PartialEq, Eq, Debug)]
@@ -237,6 +237,24 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
     /// |       DFU |            3 |      4 |      5 |      6 |      3 |
     ///
     pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        self.prepare_boot_with_cipher(aligned_buf, NoEncryption)
+    }
+
+    /// Perform necessary boot preparations like [`prepare_boot`](Self::prepare_boot), for update
+    /// images that are stored encrypted in the DFU partition.
+    ///
+    /// Every page of the update image is decrypted with `cipher` before being written to the
+    /// active partition, so the image is only ever present in plaintext in the active partition.
+    /// When reverting, the rejected image is encrypted again on its way back to the DFU partition.
+    /// See [`FirmwareCipher`] for details.
+    ///
+    /// Note that the signature checked by `FirmwareUpdater::verify_and_mark_updated` is computed
+    /// over the DFU partition contents, i.e. the encrypted image.
+    pub fn prepare_boot_with_cipher(
+        &mut self,
+        aligned_buf: &mut [u8],
+        mut cipher: impl FirmwareCipher,
+    ) -> Result<State, BootError> {
         const {
             core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
             core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
@@ -262,11 +280,11 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             //
             if !self.is_swapped(aligned_buf)? {
                 trace!("Swapping");
-                self.swap(aligned_buf)?;
+                self.swap(aligned_buf, &mut cipher)?;
                 trace!("Swapping done");
             } else {
                 trace!("Reverting");
-                self.revert(aligned_buf)?;
+                self.revert(aligned_buf, &mut cipher)?;
 
                 let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
 
@@ -343,6 +361,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         from_offset: u32,
         to_offset: u32,
         aligned_buf: &mut [u8],
+        cipher: &mut impl FirmwareCipher,
     ) -> Result<(), BootError> {
         if self.current_progress(aligned_buf)? <= progress_index {
             let page_size = Self::PAGE_SIZE as u32;
@@ -351,6 +370,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
             for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
                 self.dfu.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                cipher.decrypt(from_offset + offset_in_page, aligned_buf);
                 self.active.write(to_offset + offset_in_page as u32, aligned_buf)?;
             }
 
@@ -365,6 +385,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         from_offset: u32,
         to_offset: u32,
         aligned_buf: &mut [u8],
+        cipher: &mut impl FirmwareCipher,
     ) -> Result<(), BootError> {
         if self.current_progress(aligned_buf)? <= progress_index {
             let page_size = Self::PAGE_SIZE as u32;
@@ -373,6 +394,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
             for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
                 self.active.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                cipher.encrypt(to_offset + offset_in_page, aligned_buf);
                 self.dfu.write(to_offset + offset_in_page as u32, aligned_buf)?;
             }
 
@@ -381,7 +403,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         Ok(())
     }
 
-    fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+    fn swap(&mut self, aligned_buf: &mut [u8], cipher: &mut impl FirmwareCipher) -> Result<(), BootError> {
         let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
         for page_num in 0..page_count {
             let progress_index = (page_num * 2) as usize;
@@ -390,19 +412,31 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             let active_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             let dfu_to_offset = (page_count - page_num) * Self::PAGE_SIZE;
             //trace!("Copy active {} to dfu {}", active_from_offset, dfu_to_offset);
-            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf)?;
+            self.copy_page_once_to_dfu(
+                progress_index,
+                active_from_offset,
+                dfu_to_offset,
+                aligned_buf,
+                &mut NoEncryption,
+            )?;
 
             // Copy DFU page to the active page
             let active_to_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             let dfu_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             //trace!("Copy dfy {} to active {}", dfu_from_offset, active_to_offset);
-            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, aligned_buf)?;
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                aligned_buf,
+                cipher,
+            )?;
         }
 
         Ok(())
     }
 
-    fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+    fn revert(&mut self, aligned_buf: &mut [u8], cipher: &mut impl FirmwareCipher) -> Result<(), BootError> {
         let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
         for page_num in 0..page_count {
             let progress_index = (page_count * 2 + page_num * 2) as usize;
@@ -410,12 +444,18 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             // Copy the bad active page to the DFU page
             let active_from_offset = page_num * Self::PAGE_SIZE;
             let dfu_to_offset = page_num * Self::PAGE_SIZE;
-            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf)?;
+            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf, cipher)?;
 
             // Copy the DFU page back to the active page
             let active_to_offset = page_num * Self::PAGE_SIZE;
             let dfu_from_offset = (page_num + 1) * Self::PAGE_SIZE;
-            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, aligned_buf)?;
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                aligned_buf,
+                &mut NoEncryption,
+            )?;
         }
 
         Ok(())
@@ -0,0 +1,115 @@
+/// Cipher used by the bootloader for update images that are stored encrypted in the DFU partition.
+///
+/// Images are decrypted while they are swapped into the active partition. When a failed update
+/// is reverted, the image is encrypted again while it is copied back to the DFU partition, so it
+/// never remains there in plaintext. The copy of the previous firmware kept in the DFU partition
+/// for reverting is stored unencrypted, as it is already present in plaintext in the active partition.
+///
+/// Chunks are not processed in order: pages are swapped from the end of the image towards the start,
+/// and a page may be processed again when a swap is resumed after power loss. The result for a chunk
+/// must therefore only depend on its `offset` in the image, which makes seekable stream ciphers such
+/// as AES-CTR a natural fit.
+pub trait FirmwareCipher {
+    /// Decrypt `buf` in place. `offset` is the position of the first byte of `buf` in the update image.
+    fn decrypt(&mut self, offset: u32, buf: &mut [u8]);
+
+    /// Encrypt `buf` in place. `offset` is the position of the first byte of `buf` in the update image.
+    fn encrypt(&mut self, offset: u32, buf: &mut [u8]);
+}
+
+/// Cipher for update images that are stored in plaintext.
+pub struct NoEncryption;
+
+impl FirmwareCipher for NoEncryption {
+    fn decrypt(&mut self, _offset: u32, _buf: &mut [u8]) {}
+
+    fn encrypt(&mut self, _offset: u32, _buf: &mut [u8]) {}
+}
+
+impl<T: FirmwareCipher + ?Sized> FirmwareCipher for &mut T {
+    fn decrypt(&mut self, offset: u32, buf: &mut [u8]) {
+        T::decrypt(self, offset, buf)
+    }
+
+    fn encrypt(&mut self, offset: u32, buf: &mut [u8]) {
+        T::encrypt(self, offset, buf)
+    }
+}
+
+#[cfg(feature = "aes-ctr")]
+pub use aes_ctr::Aes128CtrCipher;
+
+#[cfg(feature = "aes-ctr")]
+mod aes_ctr {
+    use aes::Aes128;
+    use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+
+    use super::FirmwareCipher;
+
+    /// AES-128 in counter mode with a 128-bit big-endian counter.
+    ///
+    /// The initial counter block is the `iv`, and the counter is incremented for each 16 byte block
+    /// of the image. This matches the output of e.g.
+    /// `openssl enc -aes-128-ctr -K <key> -iv <iv> -in firmware.bin -out firmware.enc`.
+    pub struct Aes128CtrCipher {
+        cipher: ctr::Ctr128BE<Aes128>,
+    }
+
+    impl Aes128CtrCipher {
+        /// Create a cipher from the key and initial counter block.
+        pub fn new(key: &[u8; 16], iv: &[u8; 16]) -> Self {
+            Self {
+                cipher: ctr::Ctr128BE::new(key.into(), iv.into()),
+            }
+        }
+
+        fn apply_keystream(&mut self, offset: u32, buf: &mut [u8]) {
+            self.cipher.seek(offset);
+            self.cipher.apply_keystream(buf);
+        }
+    }
+
+    impl FirmwareCipher for Aes128CtrCipher {
+        fn decrypt(&mut self, offset: u32, buf: &mut [u8]) {
+            self.apply_keystream(offset, buf)
+        }
+
+        fn encrypt(&mut self, offset: u32, buf: &mut [u8]) {
+            self.apply_keystream(offset, buf)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // NIST SP 800-38A, F.5.1 CTR-AES128.Encrypt
+        const KEY: [u8; 16] = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+        ];
+        const IV: [u8; 16] = [
+            0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+        ];
+        const PLAINTEXT: [u8; 32] = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a, 0xae, 0x2d,
+            0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51,
+        ];
+        const CIPHERTEXT: [u8; 32] = [
+            0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d, 0xb6, 0xce, 0x98, 0x06,
+            0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff, 0x86, 0x17, 0x18, 0x7b, 0xb9, 0xff, 0xfd, 0xff,
+        ];
+
+        #[test]
+        fn test_decrypt_out_of_order() {
+            let mut cipher = Aes128CtrCipher::new(&KEY, &IV);
+
+            let mut buf = CIPHERTEXT;
+            cipher.decrypt(16, &mut buf[16..]);
+            cipher.decrypt(0, &mut buf[..16]);
+            assert_eq!(PLAINTEXT, buf);
+
+            cipher.encrypt(0, &mut buf);
+            assert_eq!(CIPHERTEXT, buf);
+        }
+    }
+}
@@ -10,6 +10,7 @@
 mod fmt;
 
 mod boot_loader;
+mod cipher;
 mod digest_adapters;
 mod firmware_updater;
 #[cfg(test)]
@@ -25,6 +26,9 @@ pub(crate) const STATE_ERASE_VALUE: u8 = 0xFF;
 pub(crate) const STATE_ERASE_VALUE: u8 = 0x00;
 
 pub use boot_loader::{BootError, BootLoader, BootLoaderConfig};
+#[cfg(feature = "aes-ctr")]
+pub use cipher::Aes128CtrCipher;
+pub use cipher::{FirmwareCipher, NoEncryption};
 pub use firmware_updater::{
     BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig,
     FirmwareUpdaterError,
@@ -213,6 +217,75 @@ mod tests {
         assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
     }
 
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_swap_state_power_loss() {
+        use core::cell::RefCell;
+
+        use embassy_embedded_hal::flash::partition::BlockingPartition;
+        use embassy_embedded_hal::flash::{FaultFlash, Overwrite};
+        use embassy_sync::blocking_mutex::Mutex;
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+        const PAGE_SIZE: u32 = 4096;
+        const FIRMWARE_SIZE: usize = 3 * PAGE_SIZE as usize;
+        const ORIGINAL: [u8; FIRMWARE_SIZE] = [0x55; FIRMWARE_SIZE];
+        const UPDATE: [u8; FIRMWARE_SIZE] = [0xAA; FIRMWARE_SIZE];
+
+        // Cut power at every single write and erase of the swap, and check that it completes
+        // after a reboot.
+        for fail_at in 0.. {
+            let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(FaultFlash::<32768, 4096, 4>::default()));
+            let mut active = BlockingPartition::new(&flash, 0, FIRMWARE_SIZE as u32);
+            let dfu = BlockingPartition::new(&flash, FIRMWARE_SIZE as u32, FIRMWARE_SIZE as u32 + PAGE_SIZE);
+            let state = BlockingPartition::new(&flash, 2 * FIRMWARE_SIZE as u32 + PAGE_SIZE, PAGE_SIZE);
+
+            active.write(0, &ORIGINAL).unwrap();
+            let mut aligned = [0; 4];
+            let mut updater = BlockingFirmwareUpdater::new(
+                FirmwareUpdaterConfig {
+                    dfu: dfu.clone(),
+                    state: state.clone(),
+                },
+                &mut aligned,
+            );
+            updater.write_firmware(0, &UPDATE).unwrap();
+            updater.mark_updated().unwrap();
+
+            flash.lock(|f| {
+                let mut f = f.borrow_mut();
+                // A partially written progress word is written again when resuming the swap.
+                f.set_overwrite(Overwrite::And);
+                f.seed(fail_at as u64);
+                f.cut_power_after(fail_at);
+            });
+            let mut bootloader = BootLoader::new(BootLoaderConfig {
+                active: active.clone(),
+                dfu: dfu.clone(),
+                state,
+            });
+            let mut page = [0; 1024];
+            let interrupted = bootloader.prepare_boot(&mut page).is_err();
+
+            // Reboot
+            flash.lock(|f| f.borrow_mut().power_cycle());
+            if interrupted {
+                assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+            }
+
+            let mut read_buf = [0; FIRMWARE_SIZE];
+            active.read(0, &mut read_buf).unwrap();
+            assert_eq!(UPDATE, read_buf, "fail_at {}", fail_at);
+            dfu.clone().read(PAGE_SIZE, &mut read_buf).unwrap();
+            assert_eq!(ORIGINAL, read_buf, "fail_at {}", fail_at);
+
+            if !interrupted {
+                assert!(fail_at > 0);
+                break;
+            }
+        }
+    }
+
     #[test]
     #[cfg(not(feature = "_verify"))]
     fn test_swap_state_active_page_biggest() {
@@ -302,6 +375,82 @@ mod tests {
         assert_eq!(ORIGINAL, read_buf);
     }
 
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_swap_state_encrypted() {
+        // Position dependent keystream, so pages processed at the wrong offset are caught.
+        struct XorCipher;
+
+        impl FirmwareCipher for XorCipher {
+            fn decrypt(&mut self, offset: u32, buf: &mut [u8]) {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b ^= (offset as usize + i) as u8 ^ 0x5A;
+                }
+            }
+
+            fn encrypt(&mut self, offset: u32, buf: &mut [u8]) {
+                self.decrypt(offset, buf)
+            }
+        }
+
+        const FIRMWARE_SIZE: usize = 12288;
+        let flash = AsyncTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<FIRMWARE_SIZE, 4096, 4>::default(),
+            dfu: MemFlash::<16384, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+
+        const ORIGINAL: [u8; FIRMWARE_SIZE] = [0x55; FIRMWARE_SIZE];
+        const UPDATE: [u8; FIRMWARE_SIZE] = [0xAA; FIRMWARE_SIZE];
+        let mut encrypted = UPDATE;
+        XorCipher.encrypt(0, &mut encrypted);
+        let mut aligned = [0; 4];
+
+        block_on(flash.active().erase(0, ORIGINAL.len() as u32)).unwrap();
+        block_on(flash.active().write(0, &ORIGINAL)).unwrap();
+
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.write_firmware(0, &encrypted)).unwrap();
+        block_on(updater.mark_updated()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+
+        let mut page = [0; 1024];
+        assert_eq!(
+            State::Swap,
+            bootloader.prepare_boot_with_cipher(&mut page, XorCipher).unwrap()
+        );
+
+        let mut read_buf = [0; FIRMWARE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+        // Previous firmware is backed up in plaintext
+        flash.dfu().read(4096, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+
+        // Reverting must not run the plaintext backup through the cipher
+        assert_eq!(
+            State::Swap,
+            bootloader.prepare_boot_with_cipher(&mut page, XorCipher).unwrap()
+        );
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+        // The rejected update is encrypted again in the DFU partition
+        flash.dfu().read(0, &mut read_buf).unwrap();
+        assert_eq!(encrypted, read_buf);
+    }
+
     #[test]
     #[cfg(feature = "_verify")]
     fn test_verify() {
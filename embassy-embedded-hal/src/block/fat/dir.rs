@@ -0,0 +1,705 @@
+use block_device_driver::BlockDevice;
+
+use super::{Error, FatType, FileSystem, SECTOR_SIZE, Timestamp};
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTR_DIRECTORY: u8 = 0x10;
+pub(super) const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+const DELETED: u8 = 0xE5;
+
+/// Case flags of the reserved byte, used by Windows to show lowercase 8.3 names.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// Maximum number of long name entries preceding a short name entry.
+const MAX_LONG_NAME_ENTRIES: usize = 20;
+
+/// Attributes of a directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Attributes(u8);
+
+impl Attributes {
+    /// Raw attribute bits.
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// The entry is a directory.
+    pub fn is_directory(&self) -> bool {
+        self.0 & ATTR_DIRECTORY != 0
+    }
+
+    /// The entry is read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.0 & ATTR_READ_ONLY != 0
+    }
+
+    /// The entry is hidden.
+    pub fn is_hidden(&self) -> bool {
+        self.0 & ATTR_HIDDEN != 0
+    }
+
+    /// The entry belongs to the operating system.
+    pub fn is_system(&self) -> bool {
+        self.0 & ATTR_SYSTEM != 0
+    }
+
+    /// The entry was modified since it was last archived.
+    pub fn is_archive(&self) -> bool {
+        self.0 & ATTR_ARCHIVE != 0
+    }
+}
+
+/// Directory entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DirEntry {
+    name: [u8; 12],
+    name_len: u8,
+    attributes: Attributes,
+    size: u32,
+    modified: Timestamp,
+}
+
+impl DirEntry {
+    fn from_raw(raw: &[u8; ENTRY_SIZE]) -> Self {
+        let mut name = [0; 12];
+        let mut name_len = 0;
+        let mut push = |b: u8| {
+            name[name_len] = b;
+            name_len += 1;
+        };
+
+        let case = raw[12];
+        for (i, &b) in raw[..8].iter().enumerate() {
+            if b == b' ' {
+                break;
+            }
+            // A leading 0xE5 byte is stored as 0x05, since 0xE5 marks deleted entries.
+            let b = if i == 0 && b == 0x05 { DELETED } else { b };
+            push(if case & CASE_LOWER_BASE != 0 {
+                b.to_ascii_lowercase()
+            } else {
+                b
+            });
+        }
+        if raw[8] != b' ' {
+            push(b'.');
+            for &b in raw[8..11].iter().take_while(|b| **b != b' ') {
+                push(if case & CASE_LOWER_EXT != 0 {
+                    b.to_ascii_lowercase()
+                } else {
+                    b
+                });
+            }
+        }
+
+        Self {
+            name,
+            name_len: name_len as u8,
+            attributes: Attributes(raw[11]),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            modified: Timestamp::from_fat(
+                u16::from_le_bytes([raw[24], raw[25]]),
+                u16::from_le_bytes([raw[22], raw[23]]),
+            ),
+        }
+    }
+
+    /// Name of the entry, in 8.3 format.
+    ///
+    /// Bytes that are not valid UTF-8 are replaced with `?`.
+    pub fn name(&self) -> &str {
+        let name = &self.name[..self.name_len as usize];
+        match core::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => "?",
+        }
+    }
+
+    /// Raw 8.3 name bytes, as shown by [`name`](Self::name).
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    /// Attributes of the entry.
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    /// The entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attributes.is_directory()
+    }
+
+    /// Size of the file in bytes, or 0 for directories.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Time of the last modification.
+    pub fn modified(&self) -> Timestamp {
+        self.modified
+    }
+}
+
+/// Convert a path component to an 8.3 name, and the case flags to show it as given.
+fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let mut raw = [b' '; 11];
+    match name {
+        "." => raw[0] = b'.',
+        ".." => raw[..2].copy_from_slice(b".."),
+        _ => {
+            let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+            let valid = |s: &str| {
+                s.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&b))
+            };
+            if base.is_empty() || base.len() > 8 || ext.len() > 3 || !valid(base) || !valid(ext) {
+                return None;
+            }
+            raw[..base.len()].copy_from_slice(base.as_bytes());
+            raw[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+            raw.make_ascii_uppercase();
+
+            let lower =
+                |s: &str| s.bytes().any(|b| b.is_ascii_lowercase()) && !s.bytes().any(|b| b.is_ascii_uppercase());
+            let mut case = 0;
+            if lower(base) {
+                case |= CASE_LOWER_BASE;
+            }
+            if lower(ext) {
+                case |= CASE_LOWER_EXT;
+            }
+            return Some((raw, case));
+        }
+    }
+    Some((raw, 0))
+}
+
+/// Location of a directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Slot {
+    pub lba: u32,
+    pub offset: usize,
+}
+
+/// Start of a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum DirStart {
+    /// Fixed size root directory of FAT12 and FAT16.
+    Root,
+    /// Directory stored in a cluster chain.
+    Cluster(u32),
+}
+
+/// Position while iterating over the entries of a directory.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    start: DirStart,
+    cluster: u32,
+    index: u32,
+}
+
+/// Entry found in a directory.
+pub(super) struct Found {
+    pub slot: Slot,
+    raw: [u8; ENTRY_SIZE],
+    long_name: [Slot; MAX_LONG_NAME_ENTRIES],
+    long_name_len: usize,
+}
+
+impl Found {
+    pub fn cluster(&self, fat_type: FatType) -> u32 {
+        entry_cluster(&self.raw, fat_type)
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_le_bytes(self.raw[28..32].try_into().unwrap())
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        Attributes(self.raw[11])
+    }
+}
+
+fn entry_cluster(raw: &[u8; ENTRY_SIZE], fat_type: FatType) -> u32 {
+    let lo = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+    let hi = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+    match fat_type {
+        FatType::Fat32 => (hi << 16) | lo,
+        _ => lo,
+    }
+}
+
+/// Directory being listed, see [`FileSystem::open_dir`].
+#[derive(Debug, Clone)]
+pub struct Dir {
+    cursor: Cursor,
+    done: bool,
+}
+
+/// Result of resolving a path.
+pub(super) struct Resolved {
+    /// Directory holding the last path component.
+    pub parent: DirStart,
+    /// 8.3 name and case flags of the last path component.
+    pub name: ([u8; 11], u8),
+    /// Entry of the last path component, if it exists.
+    pub found: Option<Found>,
+}
+
+impl<D: BlockDevice<SECTOR_SIZE>> FileSystem<D> {
+    fn root(&self) -> DirStart {
+        match self.layout.fat_type {
+            FatType::Fat32 => DirStart::Cluster(self.layout.root_cluster),
+            _ => DirStart::Root,
+        }
+    }
+
+    fn cursor(&self, start: DirStart) -> Cursor {
+        Cursor {
+            start,
+            cluster: match start {
+                DirStart::Root => 0,
+                DirStart::Cluster(cluster) => cluster,
+            },
+            index: 0,
+        }
+    }
+
+    /// Location of the next entry, or `None` at the end of the directory.
+    ///
+    /// At the end of a cluster chain directory, the cursor stays on its last cluster.
+    async fn next_slot(&mut self, cursor: &mut Cursor) -> Result<Option<Slot>, Error<D::Error>> {
+        let base = match cursor.start {
+            DirStart::Root => {
+                if cursor.index == self.layout.root_sectors * ENTRIES_PER_SECTOR {
+                    return Ok(None);
+                }
+                self.layout.root_start
+            }
+            DirStart::Cluster(_) => {
+                if cursor.index == self.layout.sectors_per_cluster * ENTRIES_PER_SECTOR {
+                    match self.next_cluster(cursor.cluster).await? {
+                        Some(next) => {
+                            cursor.cluster = next;
+                            cursor.index = 0;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                self.cluster_lba(cursor.cluster)
+            }
+        };
+
+        let slot = Slot {
+            lba: base + cursor.index / ENTRIES_PER_SECTOR,
+            offset: (cursor.index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE,
+        };
+        cursor.index += 1;
+        Ok(Some(slot))
+    }
+
+    async fn read_entry(&mut self, slot: Slot) -> Result<[u8; ENTRY_SIZE], Error<D::Error>> {
+        let sector = self.data_cache.read(&mut self.dev, slot.lba).await?;
+        Ok(sector[slot.offset..slot.offset + ENTRY_SIZE].try_into().unwrap())
+    }
+
+    async fn write_entry(&mut self, slot: Slot) -> Result<&mut [u8], Error<D::Error>> {
+        let sector = self.data_cache.write(&mut self.dev, slot.lba).await?;
+        Ok(&mut sector[slot.offset..slot.offset + ENTRY_SIZE])
+    }
+
+    async fn find(&mut self, start: DirStart, name: &[u8; 11]) -> Result<Option<Found>, Error<D::Error>> {
+        let mut cursor = self.cursor(start);
+        let mut long_name = [Slot { lba: 0, offset: 0 }; MAX_LONG_NAME_ENTRIES];
+        let mut long_name_len = 0;
+
+        while let Some(slot) = self.next_slot(&mut cursor).await? {
+            let raw = self.read_entry(slot).await?;
+            match (raw[0], raw[11]) {
+                (0, _) => break,
+                (DELETED, _) => long_name_len = 0,
+                (_, ATTR_LONG_NAME) => {
+                    if long_name_len == MAX_LONG_NAME_ENTRIES {
+                        long_name_len = 0;
+                    }
+                    long_name[long_name_len] = slot;
+                    long_name_len += 1;
+                }
+                (_, attr) if attr & ATTR_VOLUME_ID == 0 && raw[..11] == *name => {
+                    return Ok(Some(Found {
+                        slot,
+                        raw,
+                        long_name,
+                        long_name_len,
+                    }));
+                }
+                _ => long_name_len = 0,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find a free entry in a directory, growing it if needed.
+    async fn alloc_slot(&mut self, start: DirStart) -> Result<Slot, Error<D::Error>> {
+        let mut cursor = self.cursor(start);
+        while let Some(slot) = self.next_slot(&mut cursor).await? {
+            let raw = self.read_entry(slot).await?;
+            if raw[0] == 0 || raw[0] == DELETED {
+                return Ok(slot);
+            }
+        }
+
+        match start {
+            DirStart::Root => Err(Error::DirectoryFull),
+            DirStart::Cluster(_) => {
+                let cluster = self.alloc_cluster(Some(cursor.cluster)).await?;
+                self.zero_cluster(cluster).await?;
+                Ok(Slot {
+                    lba: self.cluster_lba(cluster),
+                    offset: 0,
+                })
+            }
+        }
+    }
+
+    fn write_raw_entry(entry: &mut [u8], name: &[u8; 11], case: u8, attributes: u8, cluster: u32, now: Timestamp) {
+        let (date, time) = (now.date().to_le_bytes(), now.time().to_le_bytes());
+        entry.fill(0);
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[12] = case;
+        entry[14..16].copy_from_slice(&time);
+        entry[16..18].copy_from_slice(&date);
+        entry[18..20].copy_from_slice(&date);
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time);
+        entry[24..26].copy_from_slice(&date);
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    /// Create a new entry in a directory.
+    pub(super) async fn create_entry(
+        &mut self,
+        parent: DirStart,
+        name: &([u8; 11], u8),
+        attributes: u8,
+        cluster: u32,
+    ) -> Result<Slot, Error<D::Error>> {
+        let slot = self.alloc_slot(parent).await?;
+        let now = self.now;
+        let entry = self.write_entry(slot).await?;
+        Self::write_raw_entry(entry, &name.0, name.1, attributes, cluster, now);
+        Ok(slot)
+    }
+
+    /// Update the first cluster, size and modification time of an entry.
+    pub(super) async fn update_entry(&mut self, slot: Slot, cluster: u32, size: u32) -> Result<(), Error<D::Error>> {
+        let now = self.now;
+        let entry = self.write_entry(slot).await?;
+        entry[11] |= ATTR_ARCHIVE;
+        entry[18..20].copy_from_slice(&now.date().to_le_bytes());
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&now.time().to_le_bytes());
+        entry[24..26].copy_from_slice(&now.date().to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        Ok(())
+    }
+
+    /// Resolve the directory containing the last component of `path`, and that component.
+    pub(super) async fn resolve(&mut self, path: &str) -> Result<Resolved, Error<D::Error>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty() && *c != ".").peekable();
+        let mut dir = self.root();
+
+        while let Some(component) = components.next() {
+            let name = short_name(component).ok_or(Error::InvalidName)?;
+            let found = self.find(dir, &name.0).await?;
+
+            if components.peek().is_none() {
+                return Ok(Resolved {
+                    parent: dir,
+                    name,
+                    found,
+                });
+            }
+
+            let found = found.ok_or(Error::NotFound)?;
+            if !found.attributes().is_directory() {
+                return Err(Error::NotADirectory);
+            }
+            dir = self.dir_start(found.cluster(self.layout.fat_type));
+        }
+        Err(Error::InvalidName)
+    }
+
+    /// Directory starting at `cluster`, where 0 is the root directory.
+    fn dir_start(&self, cluster: u32) -> DirStart {
+        match cluster {
+            0 => self.root(),
+            cluster => DirStart::Cluster(cluster),
+        }
+    }
+
+    /// Open a directory to list its entries with [`next_entry`](Self::next_entry).
+    ///
+    /// An empty path, or `/`, is the root directory.
+    pub async fn open_dir(&mut self, path: &str) -> Result<Dir, Error<D::Error>> {
+        let start = if path.split('/').all(|c| c.is_empty() || c == ".") {
+            self.root()
+        } else {
+            let resolved = self.resolve(path).await?;
+            let found = resolved.found.ok_or(Error::NotFound)?;
+            if !found.attributes().is_directory() {
+                return Err(Error::NotADirectory);
+            }
+            self.dir_start(found.cluster(self.layout.fat_type))
+        };
+        Ok(Dir {
+            cursor: self.cursor(start),
+            done: false,
+        })
+    }
+
+    /// Next entry of a directory, skipping the `.` and `..` entries.
+    pub async fn next_entry(&mut self, dir: &mut Dir) -> Result<Option<DirEntry>, Error<D::Error>> {
+        while !dir.done {
+            let Some(slot) = self.next_slot(&mut dir.cursor).await? else {
+                break;
+            };
+            let raw = self.read_entry(slot).await?;
+            match raw[0] {
+                0 => break,
+                DELETED | b'.' => {}
+                _ if raw[11] & ATTR_VOLUME_ID != 0 => {}
+                _ => return Ok(Some(DirEntry::from_raw(&raw))),
+            }
+        }
+        dir.done = true;
+        Ok(None)
+    }
+
+    /// Metadata of the file or directory at `path`.
+    pub async fn metadata(&mut self, path: &str) -> Result<DirEntry, Error<D::Error>> {
+        let resolved = self.resolve(path).await?;
+        let found = resolved.found.ok_or(Error::NotFound)?;
+        Ok(DirEntry::from_raw(&found.raw))
+    }
+
+    /// Create a directory. Its parent directory must exist.
+    pub async fn create_dir(&mut self, path: &str) -> Result<(), Error<D::Error>> {
+        let resolved = self.resolve(path).await?;
+        if resolved.found.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+
+        let cluster = self.alloc_cluster(None).await?;
+        self.zero_cluster(cluster).await?;
+
+        let now = self.now;
+        let parent_cluster = match resolved.parent {
+            DirStart::Cluster(cluster) if resolved.parent != self.root() => cluster,
+            // The root directory is referred to as cluster 0, also on FAT32.
+            _ => 0,
+        };
+        let lba = self.cluster_lba(cluster);
+        let sector = self.data_cache.write(&mut self.dev, lba).await?;
+        Self::write_raw_entry(
+            &mut sector[..ENTRY_SIZE],
+            b".          ",
+            0,
+            ATTR_DIRECTORY,
+            cluster,
+            now,
+        );
+        Self::write_raw_entry(
+            &mut sector[ENTRY_SIZE..2 * ENTRY_SIZE],
+            b"..         ",
+            0,
+            ATTR_DIRECTORY,
+            parent_cluster,
+            now,
+        );
+
+        self.create_entry(resolved.parent, &resolved.name, ATTR_DIRECTORY, cluster)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove a file or an empty directory.
+    ///
+    /// Removing a file that is open leaves its handle dangling.
+    pub async fn remove(&mut self, path: &str) -> Result<(), Error<D::Error>> {
+        let resolved = self.resolve(path).await?;
+        let found = resolved.found.ok_or(Error::NotFound)?;
+        let cluster = found.cluster(self.layout.fat_type);
+
+        if found.attributes().is_directory() {
+            if found.raw[..2] == *b". " || found.raw[..2] == *b".." {
+                return Err(Error::InvalidName);
+            }
+            let mut dir = Dir {
+                cursor: self.cursor(self.dir_start(cluster)),
+                done: false,
+            };
+            if self.next_entry(&mut dir).await?.is_some() {
+                return Err(Error::DirectoryNotEmpty);
+            }
+        }
+
+        for slot in found.long_name[..found.long_name_len].iter().chain([&found.slot]) {
+            self.write_entry(*slot).await?[0] = DELETED;
+        }
+        if cluster != 0 {
+            self.free_chain(cluster).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::super::tests::formatted;
+    use super::super::{FatType, Mode};
+    use super::*;
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_name("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(
+            short_name("log.csv"),
+            Some((*b"LOG     CSV", CASE_LOWER_BASE | CASE_LOWER_EXT))
+        );
+        assert_eq!(short_name("Data"), Some((*b"DATA       ", 0)));
+        assert_eq!(short_name("12345678.abc"), Some((*b"12345678ABC", CASE_LOWER_EXT)));
+        assert_eq!(short_name("123456789"), None);
+        assert_eq!(short_name("a.long"), None);
+        assert_eq!(short_name("sp ace"), None);
+        assert_eq!(short_name(".hidden"), None);
+
+        let mut raw = [0; ENTRY_SIZE];
+        raw[..11].copy_from_slice(b"LOG     CSV");
+        raw[12] = CASE_LOWER_BASE;
+        assert_eq!(DirEntry::from_raw(&raw).name(), "log.CSV");
+    }
+
+    async fn list(fs: &mut FileSystem<crate::block::RamDisk<'_>>, path: &str) -> Vec<(alloc::string::String, bool)> {
+        let mut dir = fs.open_dir(path).await.unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = fs.next_entry(&mut dir).await.unwrap() {
+            entries.push((entry.name().into(), entry.is_dir()));
+        }
+        entries
+    }
+
+    async fn directories(fat_type: FatType, size: usize) {
+        let mut mem = vec![0; size];
+        let mut fs = formatted(&mut mem, Some(fat_type)).await;
+
+        fs.create_dir("logs").await.unwrap();
+        fs.create_dir("/logs/2024").await.unwrap();
+        assert_eq!(fs.create_dir("logs").await, Err(Error::AlreadyExists));
+        assert_eq!(fs.create_dir("missing/dir").await, Err(Error::NotFound));
+
+        let file = fs.open("logs/2024/jan.csv", Mode::Truncate).await.unwrap();
+        fs.close(file).await.unwrap();
+        assert_eq!(
+            fs.open("logs/2024/jan.csv/x", Mode::Read).await.err(),
+            Some(Error::NotADirectory)
+        );
+        assert_eq!(fs.open("logs", Mode::Read).await.err(), Some(Error::IsADirectory));
+
+        assert_eq!(list(&mut fs, "/").await, [("logs".into(), true)]);
+        assert_eq!(list(&mut fs, "logs").await, [("2024".into(), true)]);
+        assert_eq!(list(&mut fs, "logs/2024/../2024/.").await, [("jan.csv".into(), false)]);
+
+        assert_eq!(fs.remove("logs/2024").await, Err(Error::DirectoryNotEmpty));
+        fs.remove("logs/2024/jan.csv").await.unwrap();
+        fs.remove("logs/2024").await.unwrap();
+        assert!(list(&mut fs, "logs").await.is_empty());
+        assert_eq!(fs.metadata("logs/2024").await, Err(Error::NotFound));
+
+        // Directories grow beyond one cluster, and can be listed after remounting.
+        let count = fs.cluster_size() as usize / ENTRY_SIZE + 10;
+        for i in 0..count {
+            let file = fs
+                .open(&alloc::format!("logs/f{}.txt", i), Mode::Truncate)
+                .await
+                .unwrap();
+            fs.close(file).await.unwrap();
+        }
+        let disk = fs.unmount().await.unwrap();
+        let mut fs = FileSystem::mount(disk).await.unwrap();
+        let entries = list(&mut fs, "logs").await;
+        assert_eq!(entries.len(), count);
+        assert_eq!(entries[count - 1].0, alloc::format!("f{}.txt", count - 1));
+    }
+
+    #[futures_test::test]
+    async fn directories_fat12() {
+        directories(FatType::Fat12, 1024 * 1024).await;
+    }
+
+    #[futures_test::test]
+    async fn directories_fat32() {
+        directories(FatType::Fat32, 40 * 1024 * 1024).await;
+    }
+
+    #[futures_test::test]
+    async fn root_directory_full() {
+        let mut mem = vec![0; 1024 * 1024];
+        let mut fs = formatted(&mut mem, Some(FatType::Fat12)).await;
+
+        let mut i = 0;
+        let err = loop {
+            match fs.create_dir(&alloc::format!("d{}", i)).await {
+                Ok(()) => i += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, Error::DirectoryFull);
+        assert_eq!(i, 512);
+    }
+
+    #[futures_test::test]
+    async fn long_names_are_removed() {
+        let mut mem = vec![0; 1024 * 1024];
+        let mut fs = formatted(&mut mem, None).await;
+        let file = fs.open("longna~1.txt", Mode::Truncate).await.unwrap();
+        fs.close(file).await.unwrap();
+
+        // Move the entry one slot further, and put a long name entry in front of it.
+        let slot = Slot {
+            lba: fs.layout.root_start,
+            offset: 0,
+        };
+        let entry = fs.read_entry(slot).await.unwrap();
+        let sector = fs.data_cache.write(&mut fs.dev, slot.lba).await.unwrap();
+        sector[ENTRY_SIZE..2 * ENTRY_SIZE].copy_from_slice(&entry);
+        sector[..ENTRY_SIZE].fill(0);
+        sector[0] = 0x41;
+        sector[11] = ATTR_LONG_NAME;
+
+        assert_eq!(list(&mut fs, "").await, [("longna~1.txt".into(), false)]);
+        fs.remove("LONGNA~1.TXT").await.unwrap();
+        let sector = fs.data_cache.read(&mut fs.dev, slot.lba).await.unwrap();
+        assert_eq!(sector[0], DELETED);
+        assert_eq!(sector[ENTRY_SIZE], DELETED);
+    }
+}
@@ -0,0 +1,316 @@
+use block_device_driver::BlockDevice;
+
+use super::dir::{ATTR_ARCHIVE, Slot};
+use super::{Error, FileSystem, SECTOR_SIZE};
+
+/// How to open a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// Open an existing file for reading.
+    Read,
+    /// Open an existing file for reading and writing, at its start.
+    ReadWrite,
+    /// Create a file, or truncate it if it exists, for reading and writing.
+    Truncate,
+    /// Create a file if it does not exist, for reading and writing at its end.
+    Append,
+}
+
+/// Handle of an open file.
+///
+/// The handle does not borrow the [`FileSystem`]: it is passed to its file methods. Once written,
+/// it must be closed with [`FileSystem::close`] for its size to be stored.
+#[derive(Debug)]
+pub struct File {
+    slot: Slot,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    cluster: u32,
+    cluster_index: u32,
+    writable: bool,
+    dirty: bool,
+}
+
+impl File {
+    /// Size of the file in bytes.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Current read and write position.
+    pub fn position(&self) -> u32 {
+        self.pos
+    }
+}
+
+impl<D: BlockDevice<SECTOR_SIZE>> FileSystem<D> {
+    /// Open the file at `path`.
+    pub async fn open(&mut self, path: &str, mode: Mode) -> Result<File, Error<D::Error>> {
+        let resolved = self.resolve(path).await?;
+        let fat_type = self.layout.fat_type;
+
+        let mut file = match resolved.found {
+            Some(found) => {
+                if found.attributes().is_directory() {
+                    return Err(Error::IsADirectory);
+                }
+                if mode != Mode::Read && found.attributes().is_read_only() {
+                    return Err(Error::ReadOnly);
+                }
+                File {
+                    slot: found.slot,
+                    first_cluster: found.cluster(fat_type),
+                    size: found.size(),
+                    pos: 0,
+                    cluster: 0,
+                    cluster_index: 0,
+                    writable: mode != Mode::Read,
+                    dirty: false,
+                }
+            }
+            None if matches!(mode, Mode::Truncate | Mode::Append) => {
+                let slot = self
+                    .create_entry(resolved.parent, &resolved.name, ATTR_ARCHIVE, 0)
+                    .await?;
+                File {
+                    slot,
+                    first_cluster: 0,
+                    size: 0,
+                    pos: 0,
+                    cluster: 0,
+                    cluster_index: 0,
+                    writable: true,
+                    dirty: false,
+                }
+            }
+            None => return Err(Error::NotFound),
+        };
+
+        match mode {
+            Mode::Truncate if file.first_cluster != 0 => {
+                let cluster = file.first_cluster;
+                file.first_cluster = 0;
+                file.size = 0;
+                self.update_entry(file.slot, 0, 0).await?;
+                self.free_chain(cluster).await?;
+            }
+            Mode::Append => file.pos = file.size,
+            _ => {}
+        }
+        Ok(file)
+    }
+
+    /// Read from the current position of `file` into `buf`.
+    ///
+    /// Returns the number of bytes read, which is 0 at the end of the file.
+    pub async fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, Error<D::Error>> {
+        let len = buf.len().min((file.size - file.pos) as usize);
+        let mut done = 0;
+        while done < len {
+            let cluster = self.file_cluster(file, false).await?;
+            let offset = (file.pos % self.cluster_size()) as usize;
+            let lba = self.cluster_lba(cluster) + (offset / SECTOR_SIZE) as u32;
+            let start = offset % SECTOR_SIZE;
+            let n = (SECTOR_SIZE - start).min(len - done);
+
+            let sector = self.data_cache.read(&mut self.dev, lba).await?;
+            buf[done..done + n].copy_from_slice(&sector[start..start + n]);
+            done += n;
+            file.pos += n as u32;
+        }
+        Ok(done)
+    }
+
+    /// Write `data` at the current position of `file`, growing it as needed.
+    ///
+    /// If the disk gets full, the data written until then is kept.
+    pub async fn write(&mut self, file: &mut File, data: &[u8]) -> Result<(), Error<D::Error>> {
+        if !file.writable {
+            return Err(Error::ReadOnly);
+        }
+        if file.pos as u64 + data.len() as u64 > u32::MAX as u64 {
+            return Err(Error::FileTooLarge);
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let cluster = self.file_cluster(file, true).await?;
+            let offset = (file.pos % self.cluster_size()) as usize;
+            let lba = self.cluster_lba(cluster) + (offset / SECTOR_SIZE) as u32;
+            let start = offset % SECTOR_SIZE;
+            let n = (SECTOR_SIZE - start).min(data.len() - done);
+
+            // Sectors that are overwritten completely, or hold no data yet, do not need reading.
+            let sector = if n == SECTOR_SIZE || (start == 0 && file.pos >= file.size) {
+                self.data_cache.overwrite(&mut self.dev, lba).await?
+            } else {
+                self.data_cache.write(&mut self.dev, lba).await?
+            };
+            sector[start..start + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+            file.pos += n as u32;
+            file.size = file.size.max(file.pos);
+            file.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Move the read and write position of `file` to `pos`, which can be at most its size.
+    pub async fn seek(&mut self, file: &mut File, pos: u32) -> Result<(), Error<D::Error>> {
+        if pos > file.size {
+            return Err(Error::InvalidSeek);
+        }
+        file.pos = pos;
+        Ok(())
+    }
+
+    /// Store the size of `file`, and write all cached data to the device.
+    pub async fn flush_file(&mut self, file: &mut File) -> Result<(), Error<D::Error>> {
+        if file.dirty {
+            self.update_entry(file.slot, file.first_cluster, file.size).await?;
+            file.dirty = false;
+        }
+        self.flush().await
+    }
+
+    /// Close `file`, flushing it.
+    pub async fn close(&mut self, mut file: File) -> Result<(), Error<D::Error>> {
+        self.flush_file(&mut file).await
+    }
+
+    /// Cluster holding the current position of `file`, allocated if `allocate` is set.
+    async fn file_cluster(&mut self, file: &mut File, allocate: bool) -> Result<u32, Error<D::Error>> {
+        let target = file.pos / self.cluster_size();
+
+        if file.first_cluster == 0 {
+            if !allocate {
+                return Err(Error::Corrupted);
+            }
+            file.first_cluster = self.alloc_cluster(None).await?;
+            file.dirty = true;
+        }
+        if file.cluster == 0 || file.cluster_index > target {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+
+        while file.cluster_index < target {
+            file.cluster = match self.next_cluster(file.cluster).await? {
+                Some(next) => next,
+                None if allocate => self.alloc_cluster(Some(file.cluster)).await?,
+                None => return Err(Error::Corrupted),
+            };
+            file.cluster_index += 1;
+        }
+        Ok(file.cluster)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::vec;
+
+    use super::super::tests::formatted;
+    use super::super::{FatType, Timestamp};
+    use super::*;
+
+    #[futures_test::test]
+    async fn modes() {
+        let mut mem = vec![0; 1024 * 1024];
+        let mut fs = formatted(&mut mem, None).await;
+        let mut buf = [0; 32];
+
+        assert_eq!(fs.open("log.txt", Mode::Read).await.err(), Some(Error::NotFound));
+        assert_eq!(fs.open("log.txt", Mode::ReadWrite).await.err(), Some(Error::NotFound));
+
+        let mut file = fs.open("log.txt", Mode::Append).await.unwrap();
+        fs.write(&mut file, b"one\n").await.unwrap();
+        fs.close(file).await.unwrap();
+
+        let mut file = fs.open("log.txt", Mode::Append).await.unwrap();
+        assert_eq!(file.position(), 4);
+        fs.write(&mut file, b"two\n").await.unwrap();
+        fs.close(file).await.unwrap();
+
+        let mut file = fs.open("log.txt", Mode::Read).await.unwrap();
+        assert_eq!(fs.write(&mut file, b"x").await, Err(Error::ReadOnly));
+        let n = fs.read(&mut file, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"one\ntwo\n");
+
+        let mut file = fs.open("log.txt", Mode::ReadWrite).await.unwrap();
+        fs.seek(&mut file, 4).await.unwrap();
+        fs.write(&mut file, b"TWO").await.unwrap();
+        assert_eq!(fs.seek(&mut file, 9).await, Err(Error::InvalidSeek));
+        fs.seek(&mut file, 0).await.unwrap();
+        let n = fs.read(&mut file, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"one\nTWO\n");
+        fs.close(file).await.unwrap();
+
+        let free = fs.free_space().await.unwrap();
+        let file = fs.open("log.txt", Mode::Truncate).await.unwrap();
+        assert_eq!(file.size(), 0);
+        fs.close(file).await.unwrap();
+        assert_eq!(fs.metadata("log.txt").await.unwrap().size(), 0);
+        assert_eq!(fs.free_space().await.unwrap(), free + fs.cluster_size() as u64);
+    }
+
+    #[futures_test::test]
+    async fn timestamps() {
+        let mut mem = vec![0; 1024 * 1024];
+        let mut fs = formatted(&mut mem, None).await;
+        let now = Timestamp {
+            year: 2024,
+            month: 5,
+            day: 17,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        fs.set_time(now);
+
+        let mut file = fs.open("data.csv", Mode::Truncate).await.unwrap();
+        fs.write(&mut file, b"1,2,3\n").await.unwrap();
+        fs.close(file).await.unwrap();
+        assert_eq!(fs.metadata("data.csv").await.unwrap().modified(), now);
+    }
+
+    #[futures_test::test]
+    async fn disk_full() {
+        let mut mem = vec![0; 256 * 1024];
+        let mut fs = formatted(&mut mem, Some(FatType::Fat12)).await;
+
+        let mut file = fs.open("big.bin", Mode::Truncate).await.unwrap();
+        let chunk = [0x5A; 1000];
+        let err = loop {
+            if let Err(e) = fs.write(&mut file, &chunk).await {
+                break e;
+            }
+        };
+        assert_eq!(err, Error::DiskFull);
+        fs.close(file).await.unwrap();
+        assert_eq!(fs.free_space().await.unwrap(), 0);
+
+        // Everything written until the disk got full is kept.
+        let size = fs.metadata("big.bin").await.unwrap().size();
+        assert_eq!(size % fs.cluster_size(), 0);
+        let mut file = fs.open("big.bin", Mode::Read).await.unwrap();
+        let mut buf = [0; 1000];
+        let mut total = 0;
+        loop {
+            let n = fs.read(&mut file, &mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(buf[..n].iter().all(|b| *b == 0x5A));
+            total += n as u32;
+        }
+        assert_eq!(total, size);
+
+        fs.remove("big.bin").await.unwrap();
+        assert!(fs.free_space().await.unwrap() >= size as u64);
+    }
+}
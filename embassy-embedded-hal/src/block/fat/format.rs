@@ -0,0 +1,244 @@
+use block_device_driver::BlockDevice;
+
+use super::{Error, FatType, FileSystem, SECTOR_SIZE};
+
+const FAT_COUNT: u32 = 2;
+const MEDIA_FIXED: u8 = 0xF8;
+
+/// Configuration for [`FileSystem::format`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct FormatConfig {
+    /// FAT variant to use, or `None` to pick one based on the device size.
+    pub fat_type: Option<FatType>,
+    /// Volume label, padded with spaces.
+    pub volume_label: [u8; 11],
+    /// Volume serial number.
+    pub volume_id: u32,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            fat_type: None,
+            volume_label: *b"NO NAME    ",
+            volume_id: 0x454D_4253,
+        }
+    }
+}
+
+/// Layout of a filesystem being formatted, in sectors.
+struct Geometry {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    root_entries: u32,
+    fat_size: u32,
+}
+
+impl Geometry {
+    fn new(fat_type: FatType, sectors_per_cluster: u32, total_sectors: u32) -> Option<Self> {
+        let (reserved_sectors, root_entries, entry_bits) = match fat_type {
+            FatType::Fat12 => (1, 512, 12),
+            FatType::Fat16 => (1, 512, 16),
+            FatType::Fat32 => (32, 0, 32),
+        };
+        let root_sectors = root_entries * 32 / SECTOR_SIZE as u32;
+
+        // Size the FAT for the clusters there would be without it, which is slightly too large.
+        let max_clusters = total_sectors.checked_sub(reserved_sectors + root_sectors)? / sectors_per_cluster;
+        let fat_size = ((max_clusters as u64 + 2) * entry_bits).div_ceil(8 * SECTOR_SIZE as u64) as u32;
+
+        let geometry = Self {
+            fat_type,
+            sectors_per_cluster,
+            reserved_sectors,
+            root_entries,
+            fat_size,
+        };
+        let clusters = geometry.cluster_count(total_sectors)?;
+        (FatType::from_cluster_count(clusters) == fat_type).then_some(geometry)
+    }
+
+    fn root_sectors(&self) -> u32 {
+        self.root_entries * 32 / SECTOR_SIZE as u32
+    }
+
+    fn data_start(&self) -> u32 {
+        self.reserved_sectors + FAT_COUNT * self.fat_size + self.root_sectors()
+    }
+
+    fn cluster_count(&self, total_sectors: u32) -> Option<u32> {
+        Some(total_sectors.checked_sub(self.data_start())? / self.sectors_per_cluster)
+    }
+
+    /// Choose the cluster size, preferring the ones used by other operating systems.
+    fn choose(fat_type: FatType, total_sectors: u32) -> Option<Self> {
+        let preferred: u32 = match fat_type {
+            FatType::Fat12 => 1,
+            FatType::Fat16 => match total_sectors {
+                0..=32_680 => 2,
+                32_681..=262_144 => 4,
+                262_145..=524_288 => 8,
+                524_289..=1_048_576 => 16,
+                1_048_577..=2_097_152 => 32,
+                _ => 64,
+            },
+            FatType::Fat32 => match total_sectors {
+                0..=16_777_216 => 8,
+                16_777_217..=33_554_432 => 16,
+                33_554_433..=67_108_864 => 32,
+                _ => 64,
+            },
+        };
+        // Smaller clusters give more clusters, larger ones fewer.
+        let smaller = (0..=preferred.trailing_zeros()).rev().map(|shift| 1 << shift);
+        let larger = (preferred.trailing_zeros() + 1..=7).map(|shift| 1 << shift);
+        smaller
+            .chain(larger)
+            .find_map(|sectors_per_cluster| Self::new(fat_type, sectors_per_cluster, total_sectors))
+    }
+}
+
+impl<D: BlockDevice<SECTOR_SIZE>> FileSystem<D> {
+    /// Create an empty FAT filesystem spanning the whole device, without a partition table.
+    ///
+    /// Without a requested FAT type, FAT12 is used below 4 MiB, FAT16 below 260 MiB and FAT32
+    /// otherwise.
+    pub async fn format(dev: &mut D, config: FormatConfig) -> Result<(), Error<D::Error>> {
+        let total_sectors = (dev.size().await? / SECTOR_SIZE as u64).min(u32::MAX as u64) as u32;
+        let fat_type = config.fat_type.unwrap_or(match total_sectors {
+            0..8400 => FatType::Fat12,
+            8400..532_480 => FatType::Fat16,
+            _ => FatType::Fat32,
+        });
+        let geometry = Geometry::choose(fat_type, total_sectors).ok_or(Error::UnsupportedSize)?;
+
+        let mut block = [aligned::Aligned::<D::Align, _>([0; SECTOR_SIZE])];
+
+        // Clear the reserved sectors, FATs and root directory first, so that an interrupted
+        // format does not leave a valid looking filesystem.
+        let root_cluster_sectors = match fat_type {
+            FatType::Fat32 => geometry.sectors_per_cluster,
+            _ => 0,
+        };
+        for lba in 0..geometry.data_start() + root_cluster_sectors {
+            dev.write(lba, &block).await?;
+        }
+
+        // First sector of each FAT: media type, end of chain marker, and the FAT32 root directory.
+        let sector = &mut block[0];
+        let end = fat_type.end_of_chain();
+        match fat_type {
+            FatType::Fat12 => sector[..3].copy_from_slice(&[MEDIA_FIXED, 0xFF, 0xFF]),
+            FatType::Fat16 => sector[..4].copy_from_slice(&[MEDIA_FIXED, 0xFF, 0xFF, 0xFF]),
+            FatType::Fat32 => {
+                sector[..4].copy_from_slice(&(0x0FFF_FF00 | MEDIA_FIXED as u32).to_le_bytes());
+                sector[4..8].copy_from_slice(&end.to_le_bytes());
+                sector[8..12].copy_from_slice(&end.to_le_bytes());
+            }
+        }
+        for fat in 0..FAT_COUNT {
+            dev.write(geometry.reserved_sectors + fat * geometry.fat_size, &block)
+                .await?;
+        }
+
+        if fat_type == FatType::Fat32 {
+            let clusters = geometry.cluster_count(total_sectors).unwrap();
+            let sector = &mut block[0];
+            sector.fill(0);
+            sector[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+            sector[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+            // All clusters but the root directory are free, and the next one is cluster 3.
+            sector[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+            sector[492..496].copy_from_slice(&3u32.to_le_bytes());
+            sector[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+            dev.write(1, &block).await?;
+            dev.write(7, &block).await?;
+        }
+
+        let sector = &mut block[0];
+        sector.fill(0);
+        write_boot_sector(sector, &geometry, total_sectors, &config);
+        if fat_type == FatType::Fat32 {
+            dev.write(6, &block).await?;
+        }
+        dev.write(0, &block).await?;
+        Ok(())
+    }
+}
+
+fn write_boot_sector(sector: &mut [u8; SECTOR_SIZE], geometry: &Geometry, total_sectors: u32, config: &FormatConfig) {
+    let fat32 = geometry.fat_type == FatType::Fat32;
+
+    sector[..3].copy_from_slice(if fat32 {
+        &[0xEB, 0x58, 0x90]
+    } else {
+        &[0xEB, 0x3C, 0x90]
+    });
+    sector[3..11].copy_from_slice(b"EMBASSY ");
+    sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    sector[13] = geometry.sectors_per_cluster as u8;
+    sector[14..16].copy_from_slice(&(geometry.reserved_sectors as u16).to_le_bytes());
+    sector[16] = FAT_COUNT as u8;
+    sector[17..19].copy_from_slice(&(geometry.root_entries as u16).to_le_bytes());
+    if total_sectors < 0x10000 && !fat32 {
+        sector[19..21].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+    } else {
+        sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    }
+    sector[21] = MEDIA_FIXED;
+    if !fat32 {
+        sector[22..24].copy_from_slice(&(geometry.fat_size as u16).to_le_bytes());
+    }
+    // Sectors per track and heads, only used by legacy BIOS calls.
+    sector[24..26].copy_from_slice(&63u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&255u16.to_le_bytes());
+
+    let (ext, fs_type): (usize, &[u8; 8]) = match geometry.fat_type {
+        FatType::Fat12 => (36, b"FAT12   "),
+        FatType::Fat16 => (36, b"FAT16   "),
+        FatType::Fat32 => {
+            sector[36..40].copy_from_slice(&geometry.fat_size.to_le_bytes());
+            // Root directory cluster, FSInfo sector and backup boot sector.
+            sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+            sector[48..50].copy_from_slice(&1u16.to_le_bytes());
+            sector[50..52].copy_from_slice(&6u16.to_le_bytes());
+            (64, b"FAT32   ")
+        }
+    };
+    sector[ext] = 0x80;
+    sector[ext + 2] = 0x29;
+    sector[ext + 3..ext + 7].copy_from_slice(&config.volume_id.to_le_bytes());
+    sector[ext + 7..ext + 18].copy_from_slice(&config.volume_label);
+    sector[ext + 18..ext + 26].copy_from_slice(fs_type);
+
+    sector[510..].copy_from_slice(&[0x55, 0xAA]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        // 1.44 MB floppy
+        let g = Geometry::choose(FatType::Fat12, 2880).unwrap();
+        assert_eq!((g.sectors_per_cluster, g.fat_size, g.data_start()), (1, 9, 1 + 18 + 32));
+
+        let g = Geometry::choose(FatType::Fat16, 131_072).unwrap();
+        assert_eq!(g.sectors_per_cluster, 4);
+        assert!(g.fat_size as u64 * 256 >= g.cluster_count(131_072).unwrap() as u64 + 2);
+
+        let g = Geometry::choose(FatType::Fat32, 4_194_304).unwrap();
+        assert_eq!(g.sectors_per_cluster, 8);
+        assert!(g.fat_size as u64 * 128 >= g.cluster_count(4_194_304).unwrap() as u64 + 2);
+
+        // Too small for FAT16 and FAT32 with any cluster size.
+        assert!(Geometry::choose(FatType::Fat16, 4000).is_none());
+        assert!(Geometry::choose(FatType::Fat32, 60000).is_none());
+        // Too large for FAT12 even with the largest clusters.
+        assert!(Geometry::choose(FatType::Fat12, 4_000_000).is_none());
+    }
+}
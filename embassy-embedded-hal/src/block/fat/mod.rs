@@ -0,0 +1,718 @@
+//! Async FAT12, FAT16 and FAT32 filesystem on a [`BlockDevice`] with 512 byte blocks.
+//!
+//! The filesystem is found either at the start of the device, or in the first FAT partition of
+//! a MBR partition table. Only short 8.3 names are supported: long file names are ignored when
+//! reading directories, and removed along with their short name entry.
+//!
+//! Files are plain handles that do not borrow the [`FileSystem`], so several can be open at the
+//! same time. Metadata of written files is only updated on disk by [`FileSystem::flush_file`] or
+//! [`FileSystem::close`], and data is cached until [`FileSystem::flush`] or
+//! [`FileSystem::unmount`].
+//!
+//! ```ignore
+//! let mut fs = FileSystem::mount(sd_card).await?;
+//! fs.create_dir("logs").await?;
+//! let mut file = fs.open("logs/temp.csv", Mode::Append).await?;
+//! fs.write(&mut file, b"21.5\n").await?;
+//! fs.close(file).await?;
+//! ```
+
+use aligned::{Aligned, Alignment};
+use block_device_driver::BlockDevice;
+
+mod dir;
+mod file;
+mod format;
+
+pub use dir::{Attributes, Dir, DirEntry};
+pub use file::{File, Mode};
+pub use format::FormatConfig;
+
+const SECTOR_SIZE: usize = 512;
+
+/// FAT filesystem errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The block device failed.
+    Device(E),
+    /// No FAT filesystem was found on the device.
+    NotFormatted,
+    /// The filesystem uses a sector size other than 512 bytes.
+    UnsupportedSectorSize,
+    /// The device size is not supported by the requested FAT type.
+    UnsupportedSize,
+    /// The filesystem structures are inconsistent.
+    Corrupted,
+    /// The path does not exist.
+    NotFound,
+    /// The path already exists.
+    AlreadyExists,
+    /// A path component is not a directory.
+    NotADirectory,
+    /// The path is a directory.
+    IsADirectory,
+    /// The directory to remove is not empty.
+    DirectoryNotEmpty,
+    /// The fixed size root directory of FAT12 and FAT16 is full.
+    DirectoryFull,
+    /// The path is empty, or a component is not a valid 8.3 name.
+    InvalidName,
+    /// The file is read-only, or was not opened for writing.
+    ReadOnly,
+    /// The seek position is past the end of the file.
+    InvalidSeek,
+    /// The file would exceed the maximum FAT file size of 4 GiB - 1.
+    FileTooLarge,
+    /// There are no free clusters left.
+    DiskFull,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Self::Device(e)
+    }
+}
+
+/// FAT variant of a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FatType {
+    /// FAT12, for volumes with less than 4085 clusters.
+    Fat12,
+    /// FAT16, for volumes with less than 65525 clusters.
+    Fat16,
+    /// FAT32.
+    Fat32,
+}
+
+impl FatType {
+    fn from_cluster_count(clusters: u32) -> Self {
+        if clusters < 4085 {
+            Self::Fat12
+        } else if clusters < 65525 {
+            Self::Fat16
+        } else {
+            Self::Fat32
+        }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xFFF,
+            Self::Fat16 => 0xFFFF,
+            Self::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+}
+
+/// Date and time stored in directory entries, with a resolution of 2 seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timestamp {
+    /// Year, from 1980 to 2107.
+    pub year: u16,
+    /// Month, from 1 to 12.
+    pub month: u8,
+    /// Day of the month, from 1 to 31.
+    pub day: u8,
+    /// Hour, from 0 to 23.
+    pub hour: u8,
+    /// Minute, from 0 to 59.
+    pub minute: u8,
+    /// Second, from 0 to 59.
+    pub second: u8,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self {
+            year: 1980,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+}
+
+impl Timestamp {
+    fn date(&self) -> u16 {
+        (self.year.saturating_sub(1980).min(127) << 9) | ((self.month as u16 & 0xF) << 5) | (self.day as u16 & 0x1F)
+    }
+
+    fn time(&self) -> u16 {
+        ((self.hour as u16 & 0x1F) << 11) | ((self.minute as u16 & 0x3F) << 5) | (self.second as u16 / 2)
+    }
+
+    fn from_fat(date: u16, time: u16) -> Self {
+        Self {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0xF) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        }
+    }
+}
+
+/// Value of a FAT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    Free,
+    Next(u32),
+    End,
+    Bad,
+}
+
+/// Cache of a single sector.
+///
+/// Sectors of the FAT are written to every copy of the FAT when flushed.
+struct Cache<A: Alignment> {
+    block: [Aligned<A, [u8; SECTOR_SIZE]>; 1],
+    lba: Option<u32>,
+    dirty: bool,
+    copies: u32,
+    stride: u32,
+}
+
+impl<A: Alignment> Cache<A> {
+    fn new(copies: u32, stride: u32) -> Self {
+        Self {
+            block: [aligned::Aligned([0; SECTOR_SIZE])],
+            lba: None,
+            dirty: false,
+            copies,
+            stride,
+        }
+    }
+
+    async fn flush<D: BlockDevice<SECTOR_SIZE, Align = A>>(&mut self, dev: &mut D) -> Result<(), D::Error> {
+        if let Some(lba) = self.lba
+            && self.dirty
+        {
+            for copy in 0..self.copies {
+                dev.write(lba + copy * self.stride, &self.block).await?;
+            }
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    async fn read<D: BlockDevice<SECTOR_SIZE, Align = A>>(
+        &mut self,
+        dev: &mut D,
+        lba: u32,
+    ) -> Result<&mut [u8; SECTOR_SIZE], D::Error> {
+        if self.lba != Some(lba) {
+            self.flush(dev).await?;
+            self.lba = None;
+            dev.read(lba, &mut self.block).await?;
+            self.lba = Some(lba);
+        }
+        Ok(&mut self.block[0])
+    }
+
+    /// Load a sector for modification.
+    async fn write<D: BlockDevice<SECTOR_SIZE, Align = A>>(
+        &mut self,
+        dev: &mut D,
+        lba: u32,
+    ) -> Result<&mut [u8; SECTOR_SIZE], D::Error> {
+        self.read(dev, lba).await?;
+        self.dirty = true;
+        Ok(&mut self.block[0])
+    }
+
+    /// Load a sector that is going to be overwritten completely, without reading it.
+    async fn overwrite<D: BlockDevice<SECTOR_SIZE, Align = A>>(
+        &mut self,
+        dev: &mut D,
+        lba: u32,
+    ) -> Result<&mut [u8; SECTOR_SIZE], D::Error> {
+        if self.lba != Some(lba) {
+            self.flush(dev).await?;
+            self.lba = Some(lba);
+        }
+        self.dirty = true;
+        Ok(&mut self.block[0])
+    }
+}
+
+/// Location of the filesystem structures, in absolute sectors.
+struct Layout {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    root_start: u32,
+    root_sectors: u32,
+    root_cluster: u32,
+    data_start: u32,
+    cluster_count: u32,
+}
+
+/// Boot sector fields needed to locate the filesystem structures.
+struct Bpb {
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    fat_count: u8,
+    root_entries: u16,
+    total_sectors: u32,
+    fat_size: u32,
+    root_cluster: u32,
+    fs_info: u16,
+}
+
+impl Bpb {
+    fn parse(sector: &[u8; SECTOR_SIZE]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([sector[i], sector[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([sector[i], sector[i + 1], sector[i + 2], sector[i + 3]]);
+
+        let bpb = Self {
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: sector[13],
+            reserved_sectors: u16_at(14),
+            fat_count: sector[16],
+            root_entries: u16_at(17),
+            total_sectors: match u16_at(19) {
+                0 => u32_at(32),
+                n => n as u32,
+            },
+            fat_size: match u16_at(22) {
+                0 => u32_at(36),
+                n => n as u32,
+            },
+            root_cluster: u32_at(44),
+            fs_info: u16_at(48),
+        };
+
+        let valid = matches!(sector[0], 0xEB | 0xE9)
+            && matches!(bpb.bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.reserved_sectors > 0
+            && bpb.fat_count > 0
+            && bpb.fat_size > 0
+            && bpb.total_sectors > 0;
+        valid.then_some(bpb)
+    }
+}
+
+/// FAT filesystem on a block device.
+pub struct FileSystem<D: BlockDevice<SECTOR_SIZE>> {
+    dev: D,
+    layout: Layout,
+    fat_cache: Cache<D::Align>,
+    data_cache: Cache<D::Align>,
+    fs_info: Option<u32>,
+    next_free: u32,
+    now: Timestamp,
+}
+
+impl<D: BlockDevice<SECTOR_SIZE>> FileSystem<D> {
+    /// Mount the FAT filesystem on `dev`.
+    pub async fn mount(mut dev: D) -> Result<Self, Error<D::Error>> {
+        let mut cache = Cache::<D::Align>::new(1, 0);
+
+        let sector = cache.read(&mut dev, 0).await?;
+        if sector[510..] != [0x55, 0xAA] {
+            return Err(Error::NotFormatted);
+        }
+
+        let (start, bpb) = match Bpb::parse(sector) {
+            Some(bpb) => (0, bpb),
+            None => {
+                // Look for a FAT partition in the MBR.
+                let start = (0..4)
+                    .map(|i| &sector[446 + i * 16..462 + i * 16])
+                    .find(|entry| matches!(entry[4], 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E))
+                    .map(|entry| u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]))
+                    .ok_or(Error::NotFormatted)?;
+                let sector = cache.read(&mut dev, start).await?;
+                if sector[510..] != [0x55, 0xAA] {
+                    return Err(Error::NotFormatted);
+                }
+                (start, Bpb::parse(sector).ok_or(Error::NotFormatted)?)
+            }
+        };
+
+        if bpb.bytes_per_sector as usize != SECTOR_SIZE {
+            return Err(Error::UnsupportedSectorSize);
+        }
+
+        let root_sectors = (bpb.root_entries as u32 * 32).div_ceil(SECTOR_SIZE as u32);
+        let fat_start = start + bpb.reserved_sectors as u32;
+        let root_start = fat_start + bpb.fat_count as u32 * bpb.fat_size;
+        let data_start = root_start + root_sectors;
+        let data_sectors = (bpb.total_sectors + start)
+            .checked_sub(data_start)
+            .ok_or(Error::NotFormatted)?;
+        let cluster_count = data_sectors / bpb.sectors_per_cluster as u32;
+        let fat_type = FatType::from_cluster_count(cluster_count);
+
+        let layout = Layout {
+            fat_type,
+            sectors_per_cluster: bpb.sectors_per_cluster as u32,
+            fat_start,
+            root_start,
+            root_sectors,
+            root_cluster: bpb.root_cluster,
+            data_start,
+            cluster_count,
+        };
+        if fat_type == FatType::Fat32 && !layout.is_cluster(bpb.root_cluster) {
+            return Err(Error::NotFormatted);
+        }
+
+        let mut fs_info = None;
+        if fat_type == FatType::Fat32 && bpb.fs_info > 0 && bpb.fs_info < bpb.reserved_sectors {
+            let lba = start + bpb.fs_info as u32;
+            let sector = cache.read(&mut dev, lba).await?;
+            if sector[..4] == 0x4161_5252u32.to_le_bytes() && sector[484..488] == 0x6141_7272u32.to_le_bytes() {
+                fs_info = Some(lba);
+            }
+        }
+
+        Ok(Self {
+            dev,
+            fat_cache: Cache::new(bpb.fat_count as u32, bpb.fat_size),
+            data_cache: cache,
+            layout,
+            fs_info,
+            next_free: 2,
+            now: Timestamp::default(),
+        })
+    }
+
+    /// Write all cached data to the device, and release it.
+    pub async fn unmount(mut self) -> Result<D, Error<D::Error>> {
+        self.flush().await?;
+        Ok(self.dev)
+    }
+
+    /// Write all cached data to the device.
+    ///
+    /// This does not update the size of files being written, see [`flush_file`](Self::flush_file).
+    pub async fn flush(&mut self) -> Result<(), Error<D::Error>> {
+        self.fat_cache.flush(&mut self.dev).await?;
+        self.data_cache.flush(&mut self.dev).await?;
+        Ok(())
+    }
+
+    /// FAT variant of the filesystem.
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    /// Size of a cluster in bytes, the allocation unit of files.
+    pub fn cluster_size(&self) -> u32 {
+        self.layout.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    /// Set the time used for the timestamps of created and modified entries.
+    ///
+    /// Defaults to the earliest FAT timestamp, 1980-01-01 00:00:00.
+    pub fn set_time(&mut self, now: Timestamp) {
+        self.now = now;
+    }
+
+    /// Free space in bytes.
+    ///
+    /// This reads the whole FAT.
+    pub async fn free_space(&mut self) -> Result<u64, Error<D::Error>> {
+        let mut free = 0u64;
+        for cluster in 2..self.layout.cluster_count + 2 {
+            if self.fat_get(cluster).await? == Entry::Free {
+                free += 1;
+            }
+        }
+        Ok(free * self.cluster_size() as u64)
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.layout.data_start + (cluster - 2) * self.layout.sectors_per_cluster
+    }
+
+    /// Byte of the first FAT.
+    async fn fat_byte(&mut self, offset: u32) -> Result<u8, Error<D::Error>> {
+        let lba = self.layout.fat_start + offset / SECTOR_SIZE as u32;
+        let sector = self.fat_cache.read(&mut self.dev, lba).await?;
+        Ok(sector[offset as usize % SECTOR_SIZE])
+    }
+
+    async fn set_fat_byte(&mut self, offset: u32, value: u8) -> Result<(), Error<D::Error>> {
+        let lba = self.layout.fat_start + offset / SECTOR_SIZE as u32;
+        let sector = self.fat_cache.write(&mut self.dev, lba).await?;
+        sector[offset as usize % SECTOR_SIZE] = value;
+        Ok(())
+    }
+
+    async fn fat_get(&mut self, cluster: u32) -> Result<Entry, Error<D::Error>> {
+        let fat_type = self.layout.fat_type;
+        let raw = match fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let value = u16::from_le_bytes([self.fat_byte(offset).await?, self.fat_byte(offset + 1).await?]);
+                if cluster % 2 == 1 {
+                    (value >> 4) as u32
+                } else {
+                    (value & 0xFFF) as u32
+                }
+            }
+            FatType::Fat16 => {
+                let offset = cluster * 2;
+                u16::from_le_bytes([self.fat_byte(offset).await?, self.fat_byte(offset + 1).await?]) as u32
+            }
+            FatType::Fat32 => {
+                let offset = cluster * 4;
+                let lba = self.layout.fat_start + offset / SECTOR_SIZE as u32;
+                let sector = self.fat_cache.read(&mut self.dev, lba).await?;
+                let i = offset as usize % SECTOR_SIZE;
+                u32::from_le_bytes(sector[i..i + 4].try_into().unwrap()) & 0x0FFF_FFFF
+            }
+        };
+
+        let end = fat_type.end_of_chain();
+        match raw {
+            0 => Ok(Entry::Free),
+            raw if raw >= end - 7 => Ok(Entry::End),
+            raw if raw == end - 8 => Ok(Entry::Bad),
+            raw if self.layout.is_cluster(raw) => Ok(Entry::Next(raw)),
+            _ => Err(Error::Corrupted),
+        }
+    }
+
+    async fn fat_set(&mut self, cluster: u32, entry: Entry) -> Result<(), Error<D::Error>> {
+        self.invalidate_fs_info().await?;
+
+        let fat_type = self.layout.fat_type;
+        let raw = match entry {
+            Entry::Free => 0,
+            Entry::Next(next) => next,
+            Entry::End => fat_type.end_of_chain(),
+            Entry::Bad => fat_type.end_of_chain() - 8,
+        };
+        match fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let (lo, hi) = (self.fat_byte(offset).await?, self.fat_byte(offset + 1).await?);
+                let (lo, hi) = if cluster % 2 == 1 {
+                    ((lo & 0x0F) | (raw << 4) as u8, (raw >> 4) as u8)
+                } else {
+                    (raw as u8, (hi & 0xF0) | (raw >> 8) as u8 & 0x0F)
+                };
+                self.set_fat_byte(offset, lo).await?;
+                self.set_fat_byte(offset + 1, hi).await?;
+            }
+            FatType::Fat16 => {
+                let [lo, hi] = (raw as u16).to_le_bytes();
+                self.set_fat_byte(cluster * 2, lo).await?;
+                self.set_fat_byte(cluster * 2 + 1, hi).await?;
+            }
+            FatType::Fat32 => {
+                let offset = cluster * 4;
+                let lba = self.layout.fat_start + offset / SECTOR_SIZE as u32;
+                let sector = self.fat_cache.write(&mut self.dev, lba).await?;
+                let i = offset as usize % SECTOR_SIZE;
+                // The upper 4 bits are reserved and must be preserved.
+                let old = u32::from_le_bytes(sector[i..i + 4].try_into().unwrap());
+                sector[i..i + 4].copy_from_slice(&((old & 0xF000_0000) | raw).to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the free cluster count and next free cluster hints of FAT32 as unknown, since they
+    /// are not kept up to date.
+    async fn invalidate_fs_info(&mut self) -> Result<(), Error<D::Error>> {
+        if let Some(lba) = self.fs_info.take() {
+            let sector = self.data_cache.write(&mut self.dev, lba).await?;
+            sector[488..496].fill(0xFF);
+        }
+        Ok(())
+    }
+
+    /// Allocate a free cluster, and append it to the chain ending with `prev`.
+    async fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, Error<D::Error>> {
+        let count = self.layout.cluster_count;
+        let mut cluster = self.next_free;
+        for _ in 0..count {
+            if !self.layout.is_cluster(cluster) {
+                cluster = 2;
+            }
+            if self.fat_get(cluster).await? == Entry::Free {
+                self.fat_set(cluster, Entry::End).await?;
+                if let Some(prev) = prev {
+                    self.fat_set(prev, Entry::Next(cluster)).await?;
+                }
+                self.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+            cluster += 1;
+        }
+        Err(Error::DiskFull)
+    }
+
+    /// Free the cluster chain starting with `cluster`.
+    async fn free_chain(&mut self, mut cluster: u32) -> Result<(), Error<D::Error>> {
+        for _ in 0..self.layout.cluster_count {
+            let entry = self.fat_get(cluster).await?;
+            self.fat_set(cluster, Entry::Free).await?;
+            match entry {
+                Entry::Next(next) => cluster = next,
+                Entry::End => return Ok(()),
+                _ => return Err(Error::Corrupted),
+            }
+        }
+        Err(Error::Corrupted)
+    }
+
+    /// Next cluster of a chain.
+    async fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error<D::Error>> {
+        match self.fat_get(cluster).await? {
+            Entry::Next(next) => Ok(Some(next)),
+            Entry::End => Ok(None),
+            _ => Err(Error::Corrupted),
+        }
+    }
+
+    async fn zero_cluster(&mut self, cluster: u32) -> Result<(), Error<D::Error>> {
+        let lba = self.cluster_lba(cluster);
+        for sector in 0..self.layout.sectors_per_cluster {
+            self.data_cache.overwrite(&mut self.dev, lba + sector).await?.fill(0);
+        }
+        Ok(())
+    }
+}
+
+impl Layout {
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::block::RamDisk;
+
+    pub(super) async fn formatted(mem: &mut [u8], fat_type: Option<FatType>) -> FileSystem<RamDisk<'_>> {
+        let mut disk = RamDisk::new(mem);
+        let config = FormatConfig {
+            fat_type,
+            ..Default::default()
+        };
+        FileSystem::format(&mut disk, config).await.unwrap();
+        FileSystem::mount(disk).await.unwrap()
+    }
+
+    async fn write_read_remount(fat_type: FatType, size: usize) {
+        let mut mem = vec![0xAB; size];
+        let mut fs = formatted(&mut mem, Some(fat_type)).await;
+        assert_eq!(fs.fat_type(), fat_type);
+
+        let free = fs.free_space().await.unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        let mut file = fs.open("data.bin", Mode::Truncate).await.unwrap();
+        for chunk in data.chunks(333) {
+            fs.write(&mut file, chunk).await.unwrap();
+        }
+        fs.close(file).await.unwrap();
+        let used = data.len().div_ceil(fs.cluster_size() as usize) as u64 * fs.cluster_size() as u64;
+        assert_eq!(fs.free_space().await.unwrap(), free - used);
+
+        let disk = fs.unmount().await.unwrap();
+        let mut fs = FileSystem::mount(disk).await.unwrap();
+        assert_eq!(fs.fat_type(), fat_type);
+        let mut file = fs.open("DATA.BIN", Mode::Read).await.unwrap();
+        assert_eq!(file.size(), data.len() as u32);
+        let mut read = vec![0; data.len() + 10];
+        let mut pos = 0;
+        loop {
+            let n = fs
+                .read(&mut file, &mut read[pos..(pos + 1000).min(data.len() + 10)])
+                .await
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            pos += n;
+        }
+        assert_eq!(&read[..pos], &data[..]);
+
+        fs.remove("data.bin").await.unwrap();
+        assert_eq!(fs.free_space().await.unwrap(), free);
+    }
+
+    #[futures_test::test]
+    async fn fat12() {
+        write_read_remount(FatType::Fat12, 1024 * 1024).await;
+    }
+
+    #[futures_test::test]
+    async fn fat16() {
+        write_read_remount(FatType::Fat16, 16 * 1024 * 1024).await;
+    }
+
+    #[futures_test::test]
+    async fn fat32() {
+        write_read_remount(FatType::Fat32, 40 * 1024 * 1024).await;
+    }
+
+    #[futures_test::test]
+    async fn automatic_fat_type() {
+        for (size, fat_type) in [(1 << 20, FatType::Fat12), (64 << 20, FatType::Fat16)] {
+            let mut mem = vec![0; size];
+            let fs = formatted(&mut mem, None).await;
+            assert_eq!(fs.fat_type(), fat_type);
+        }
+
+        let mut mem = vec![0; 1 << 20];
+        let mut disk = RamDisk::new(&mut mem);
+        let config = FormatConfig {
+            fat_type: Some(FatType::Fat32),
+            ..Default::default()
+        };
+        assert_eq!(FileSystem::format(&mut disk, config).await, Err(Error::UnsupportedSize));
+    }
+
+    #[futures_test::test]
+    async fn mbr_partition() {
+        const START: usize = 63;
+        let mut mem = vec![0; 2 * 1024 * 1024];
+        FileSystem::format(&mut RamDisk::new(&mut mem[START * 512..]), FormatConfig::default())
+            .await
+            .unwrap();
+
+        let mbr = &mut mem[..512];
+        mbr[446 + 4] = 0x01;
+        mbr[446 + 8..446 + 12].copy_from_slice(&(START as u32).to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&(4096 - START as u32).to_le_bytes());
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let mut fs = FileSystem::mount(RamDisk::new(&mut mem)).await.unwrap();
+        let mut file = fs.open("hello.txt", Mode::Truncate).await.unwrap();
+        fs.write(&mut file, b"hello").await.unwrap();
+        fs.close(file).await.unwrap();
+        fs.unmount().await.unwrap();
+
+        let mut fs = FileSystem::mount(RamDisk::new(&mut mem[START * 512..])).await.unwrap();
+        assert_eq!(fs.metadata("hello.txt").await.unwrap().size(), 5);
+    }
+
+    #[futures_test::test]
+    async fn not_formatted() {
+        let mut mem = vec![0; 1024 * 1024];
+        assert!(matches!(
+            FileSystem::mount(RamDisk::new(&mut mem)).await,
+            Err(Error::NotFormatted)
+        ));
+    }
+}
@@ -0,0 +1,19 @@
+//! Block devices, and a FAT filesystem on top of them.
+//!
+//! The common block device trait is [`BlockDevice`] from the `block-device-driver` crate. It is
+//! implemented by the SDMMC `StorageDevice` of embassy-stm32, and by the `MscBlockDevice` adapter
+//! for USB mass storage LUNs of embassy-usb-host (with its `block-device-driver` feature). This
+//! module adds implementations for NOR flash ([`NorFlashBlockDevice`]) and RAM ([`RamDisk`]), and
+//! a [FAT filesystem](fat) working on any of them.
+
+pub use aligned;
+pub use block_device_driver::{
+    BlockDevice, blocks_to_slice, blocks_to_slice_mut, slice_to_blocks, slice_to_blocks_mut,
+};
+
+pub mod fat;
+mod nor_flash;
+mod ram_disk;
+
+pub use nor_flash::NorFlashBlockDevice;
+pub use ram_disk::{RamDisk, RamDiskError};
@@ -0,0 +1,115 @@
+use aligned::{A1, Aligned};
+use block_device_driver::{BlockDevice, blocks_to_slice, blocks_to_slice_mut};
+use embedded_storage_async::nor_flash::NorFlash;
+
+/// Block device on top of NOR flash, such as a [`Partition`](crate::flash::partition::Partition).
+///
+/// Blocks smaller than the flash erase size are written with a read-modify-write cycle of the
+/// erase page holding them, which needs a buffer of one erase page. That cycle is not power-fail
+/// safe: losing power during it can corrupt the other blocks of the erase page.
+///
+/// The block size must be a multiple of the flash read and write sizes.
+pub struct NorFlashBlockDevice<'b, F> {
+    flash: F,
+    buf: &'b mut [u8],
+}
+
+impl<'b, F: NorFlash> NorFlashBlockDevice<'b, F> {
+    /// Create a new block device on `flash`.
+    ///
+    /// `buf` must hold at least one erase page if the erase size is larger than the block size,
+    /// and can be empty otherwise.
+    pub fn new(flash: F, buf: &'b mut [u8]) -> Self {
+        Self { flash, buf }
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F: NorFlash, const SIZE: usize> BlockDevice<SIZE> for NorFlashBlockDevice<'_, F> {
+    type Error = F::Error;
+    type Align = A1;
+
+    async fn read(&mut self, block_address: u32, data: &mut [Aligned<A1, [u8; SIZE]>]) -> Result<(), Self::Error> {
+        assert_eq!(0, SIZE % F::READ_SIZE);
+        let data = blocks_to_slice_mut(data);
+        self.flash.read(block_address * SIZE as u32, data).await
+    }
+
+    async fn write(&mut self, block_address: u32, data: &[Aligned<A1, [u8; SIZE]>]) -> Result<(), Self::Error> {
+        assert_eq!(0, SIZE % F::WRITE_SIZE);
+        let data = blocks_to_slice(data);
+        let start = block_address * SIZE as u32;
+        let end = start + data.len() as u32;
+
+        if SIZE.is_multiple_of(F::ERASE_SIZE) {
+            self.flash.erase(start, end).await?;
+            return self.flash.write(start, data).await;
+        }
+
+        let erase_size = F::ERASE_SIZE as u32;
+        assert!(self.buf.len() >= F::ERASE_SIZE);
+        let page_buf = &mut self.buf[..F::ERASE_SIZE];
+
+        let mut page = start - start % erase_size;
+        while page < end {
+            let from = start.max(page);
+            let to = end.min(page + erase_size);
+            let new = &data[(from - start) as usize..(to - start) as usize];
+
+            if from == page && to == page + erase_size {
+                self.flash.erase(page, to).await?;
+                self.flash.write(page, new).await?;
+            } else {
+                self.flash.read(page, page_buf).await?;
+                let old = &mut page_buf[(from - page) as usize..(to - page) as usize];
+                if old != new {
+                    old.copy_from_slice(new);
+                    self.flash.erase(page, page + erase_size).await?;
+                    self.flash.write(page, page_buf).await?;
+                }
+            }
+            page += erase_size;
+        }
+        Ok(())
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok((self.flash.capacity() / SIZE * SIZE) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    #[futures_test::test]
+    async fn read_modify_write() {
+        let mut buf = [0; 4096];
+        let mut device = NorFlashBlockDevice::new(MemFlash::<16384, 4096, 4>::default(), &mut buf);
+
+        let blocks = [aligned::Aligned([0x11; 512]), aligned::Aligned([0x22; 512])];
+        BlockDevice::<512>::write(&mut device, 7, &blocks).await.unwrap();
+
+        // The blocks straddle two erase pages.
+        let flash = device.into_inner();
+        assert_eq!(flash.erases, [(0, 4096), (4096, 8192)]);
+        assert!(flash.mem[..3584].iter().all(|b| *b == 0xFF));
+        assert!(flash.mem[3584..4096].iter().all(|b| *b == 0x11));
+        assert!(flash.mem[4096..4608].iter().all(|b| *b == 0x22));
+        assert!(flash.mem[4608..].iter().all(|b| *b == 0xFF));
+
+        let mut device = NorFlashBlockDevice::new(flash, &mut buf);
+        let mut read = [aligned::Aligned([0; 512]); 2];
+        BlockDevice::<512>::read(&mut device, 7, &mut read).await.unwrap();
+        assert_eq!(read, blocks);
+
+        // Writing the same data again does not erase anything.
+        BlockDevice::<512>::write(&mut device, 7, &blocks).await.unwrap();
+        assert_eq!(device.into_inner().erases.len(), 2);
+    }
+}
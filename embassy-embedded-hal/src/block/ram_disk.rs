@@ -0,0 +1,65 @@
+use aligned::{A1, Aligned};
+use block_device_driver::{BlockDevice, blocks_to_slice, blocks_to_slice_mut};
+
+/// Errors returned by [`RamDisk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RamDiskError {
+    /// The blocks are out of bounds.
+    OutOfBounds,
+}
+
+/// Block device stored in RAM.
+///
+/// Useful as a scratch disk, or to test filesystem code against a disk image.
+pub struct RamDisk<'a> {
+    mem: &'a mut [u8],
+}
+
+impl<'a> RamDisk<'a> {
+    /// Create a new block device using `mem` as storage.
+    pub fn new(mem: &'a mut [u8]) -> Self {
+        Self { mem }
+    }
+
+    /// Release the underlying memory.
+    pub fn into_inner(self) -> &'a mut [u8] {
+        self.mem
+    }
+
+    fn range(
+        &self,
+        block_address: u32,
+        block_size: usize,
+        len: usize,
+    ) -> Result<core::ops::Range<usize>, RamDiskError> {
+        let start = block_address as usize * block_size;
+        match start.checked_add(len) {
+            Some(end) if end <= self.mem.len() => Ok(start..end),
+            _ => Err(RamDiskError::OutOfBounds),
+        }
+    }
+}
+
+impl<const SIZE: usize> BlockDevice<SIZE> for RamDisk<'_> {
+    type Error = RamDiskError;
+    type Align = A1;
+
+    async fn read(&mut self, block_address: u32, data: &mut [Aligned<A1, [u8; SIZE]>]) -> Result<(), Self::Error> {
+        let data = blocks_to_slice_mut(data);
+        let range = self.range(block_address, SIZE, data.len())?;
+        data.copy_from_slice(&self.mem[range]);
+        Ok(())
+    }
+
+    async fn write(&mut self, block_address: u32, data: &[Aligned<A1, [u8; SIZE]>]) -> Result<(), Self::Error> {
+        let data = blocks_to_slice(data);
+        let range = self.range(block_address, SIZE, data.len())?;
+        self.mem[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok((self.mem.len() / SIZE * SIZE) as u64)
+    }
+}
@@ -0,0 +1,430 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash, check_erase, check_read, check_write,
+};
+use embedded_storage_async::nor_flash::{NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash};
+
+extern crate alloc;
+
+/// Errors returned by [`FaultFlash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FaultFlashError {
+    /// The arguments are not properly aligned.
+    NotAligned,
+    /// The arguments are out of bounds.
+    OutOfBounds,
+    /// A write targeted flash that was not erased, see [`Overwrite::Error`].
+    NotErased,
+    /// Power was lost, the flash is unusable until [`FaultFlash::power_cycle`] is called.
+    PowerLoss,
+}
+
+impl NorFlashError for FaultFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::NotErased | Self::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for FaultFlashError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => Self::NotAligned,
+            _ => Self::OutOfBounds,
+        }
+    }
+}
+
+/// How [`FaultFlash`] handles writes to flash that is not erased.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Overwrite {
+    /// Panic, to catch the violation where it happens.
+    Panic,
+    /// Fail the write with [`FaultFlashError::NotErased`] without modifying the flash.
+    Error,
+    /// Only clear bits, like most NOR flash does when programming a word twice.
+    And,
+}
+
+/// In-memory NOR flash with fault injection, for testing code that needs to survive real world
+/// flash failures.
+///
+/// On top of behaving like an ideal flash, it can:
+///
+/// - Lose power after a number of writes and erases, see [`cut_power_after`](Self::cut_power_after).
+///   The interrupted operation is left partially done: a random prefix of the range is programmed
+///   or erased, and the byte at the cut only has some of its bits changed. Every operation fails
+///   with [`FaultFlashError::PowerLoss`] until [`power_cycle`](Self::power_cycle) is called.
+/// - Detect writes to bytes that are not erased, see [`set_overwrite`](Self::set_overwrite).
+/// - Flip bits, either permanently with [`flip_bit`](Self::flip_bit) or randomly while reading
+///   with [`set_read_bit_flips`](Self::set_read_bit_flips).
+/// - Track the number of writes and the number of erases of every erase page.
+///
+/// Random decisions use a deterministic generator, so a failing run can be replayed with the same
+/// [`seed`](Self::seed).
+pub struct FaultFlash<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> {
+    /// The flash contents.
+    pub mem: [u8; SIZE],
+    erase_counts: Vec<u32>,
+    writes: usize,
+    ops_left: Option<usize>,
+    powered: bool,
+    partial: bool,
+    overwrite: Overwrite,
+    read_bit_flips: Option<u32>,
+    rng: u64,
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE> {
+    /// Create a new flash filled with `fill`.
+    pub fn new(fill: u8) -> Self {
+        Self {
+            mem: [fill; SIZE],
+            erase_counts: vec![0; SIZE / ERASE_SIZE],
+            writes: 0,
+            ops_left: None,
+            powered: true,
+            partial: true,
+            overwrite: Overwrite::Panic,
+            read_bit_flips: None,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    /// Seed the random generator used for partial operations and read bit flips.
+    pub fn seed(&mut self, seed: u64) {
+        // Xorshift gets stuck at zero.
+        self.rng = seed | 1;
+    }
+
+    /// Lose power during the write or erase that follows the next `ops` writes and erases.
+    pub fn cut_power_after(&mut self, ops: usize) {
+        self.ops_left = Some(ops);
+    }
+
+    /// Set whether the operation interrupted by a power loss is partially done, which is the
+    /// default. Otherwise, it does not modify the flash at all.
+    pub fn set_partial_operations(&mut self, partial: bool) {
+        self.partial = partial;
+    }
+
+    /// Check whether the flash has power.
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Restore power after a power loss, and cancel any pending one.
+    pub fn power_cycle(&mut self) {
+        self.powered = true;
+        self.ops_left = None;
+    }
+
+    /// Set how writes to bytes that are not erased are handled. Defaults to [`Overwrite::Panic`].
+    pub fn set_overwrite(&mut self, overwrite: Overwrite) {
+        self.overwrite = overwrite;
+    }
+
+    /// Permanently flip bit `bit` of the byte at `offset`.
+    pub fn flip_bit(&mut self, offset: u32, bit: u8) {
+        self.mem[offset as usize] ^= 1 << bit;
+    }
+
+    /// Flip a random bit in the data returned by one out of `one_in` reads on average, without
+    /// modifying the flash. `None`, the default, disables read bit flips.
+    pub fn set_read_bit_flips(&mut self, one_in: Option<u32>) {
+        self.read_bit_flips = one_in;
+    }
+
+    /// Number of writes done so far, including interrupted ones.
+    pub fn write_count(&self) -> usize {
+        self.writes
+    }
+
+    /// Number of times the erase page `page` was erased, including interrupted erases.
+    pub fn erase_count(&self, page: usize) -> u32 {
+        self.erase_counts[page]
+    }
+
+    /// Highest number of erases of any erase page.
+    pub fn max_erase_count(&self) -> u32 {
+        self.erase_counts.iter().copied().max().unwrap_or(0)
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn random_below(&mut self, n: usize) -> usize {
+        (self.random() % n as u64) as usize
+    }
+
+    /// Count an operation, returning `true` if power is lost during it.
+    fn power_lost(&mut self) -> Result<bool, FaultFlashError> {
+        if !self.powered {
+            return Err(FaultFlashError::PowerLoss);
+        }
+        match &mut self.ops_left {
+            Some(0) => {
+                self.powered = false;
+                self.ops_left = None;
+                Ok(true)
+            }
+            Some(n) => {
+                *n -= 1;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FaultFlashError> {
+        check_read(self, offset, bytes.len())?;
+        if !self.powered {
+            return Err(FaultFlashError::PowerLoss);
+        }
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+
+        if let Some(one_in) = self.read_bit_flips
+            && !bytes.is_empty()
+            && self.random_below(one_in.max(1) as usize) == 0
+        {
+            let bit = self.random_below(bytes.len() * 8);
+            bytes[bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FaultFlashError> {
+        check_write(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        let target = offset..offset + bytes.len();
+        if !self.powered {
+            return Err(FaultFlashError::PowerLoss);
+        }
+
+        if self.mem[target.clone()].iter().any(|b| *b != 0xFF) {
+            match self.overwrite {
+                Overwrite::Panic => panic!("Write to offset {} which is not erased", offset),
+                Overwrite::Error => return Err(FaultFlashError::NotErased),
+                Overwrite::And => {}
+            }
+        }
+
+        let lost = self.power_lost()?;
+        self.writes += 1;
+
+        let done = match (lost, self.partial) {
+            (false, _) => bytes.len(),
+            (true, false) => 0,
+            (true, true) => self.random_below(bytes.len() + 1),
+        };
+        for (mem, byte) in self.mem[target.clone()].iter_mut().zip(bytes).take(done) {
+            *mem &= *byte;
+        }
+        if lost {
+            if self.partial && done < bytes.len() {
+                // Only some of the bits to clear are programmed.
+                let mask = self.random() as u8;
+                self.mem[offset + done] &= bytes[done] | mask;
+            }
+            return Err(FaultFlashError::PowerLoss);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FaultFlashError> {
+        check_erase(self, from, to)?;
+        let lost = self.power_lost()?;
+
+        let (from, to) = (from as usize, to as usize);
+        for page in from / ERASE_SIZE..to / ERASE_SIZE {
+            self.erase_counts[page] += 1;
+        }
+
+        let done = match (lost, self.partial) {
+            (false, _) => to - from,
+            (true, false) => 0,
+            (true, true) => self.random_below(to - from + 1),
+        };
+        self.mem[from..from + done].fill(0xFF);
+        if lost {
+            if self.partial && from + done < to {
+                // Only some of the bits are erased.
+                let mask = self.random() as u8;
+                self.mem[from + done] |= mask;
+            }
+            return Err(FaultFlashError::PowerLoss);
+        }
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> Default
+    for FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    fn default() -> Self {
+        Self::new(0xFF)
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> ErrorType
+    for FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    type Error = FaultFlashError;
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> ReadNorFlash
+    for FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> NorFlash
+    for FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.erase(from, to)
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> AsyncReadNorFlash
+    for FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> AsyncNorFlash
+    for FaultFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write(offset, bytes)
+    }
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.erase(from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Flash = FaultFlash<1024, 256, 4>;
+
+    #[test]
+    fn power_loss() {
+        let mut flash = Flash::default();
+        flash.cut_power_after(1);
+
+        flash.write(0, &[0; 8]).unwrap();
+        assert_eq!(flash.write(256, &[0; 256]), Err(FaultFlashError::PowerLoss));
+        assert!(!flash.is_powered());
+        assert_eq!(flash.read(0, &mut [0; 4]), Err(FaultFlashError::PowerLoss));
+        assert_eq!(flash.erase(0, 256), Err(FaultFlashError::PowerLoss));
+
+        flash.power_cycle();
+        let mut buf = [0xAA; 256];
+        flash.read(256, &mut buf).unwrap();
+        // The interrupted write stopped somewhere in the middle.
+        let done = buf.iter().position(|b| *b != 0).unwrap_or(256);
+        assert!(buf[done + 1..].iter().all(|b| *b == 0xFF));
+        assert_eq!(flash.write_count(), 2);
+    }
+
+    #[test]
+    fn interrupted_erase() {
+        let mut flash = Flash::new(0);
+        flash.cut_power_after(0);
+
+        assert_eq!(flash.erase(0, 512), Err(FaultFlashError::PowerLoss));
+        flash.power_cycle();
+        let done = flash.mem.iter().position(|b| *b != 0xFF).unwrap();
+        assert!(done <= 512);
+        assert!(flash.mem[done + 1..].iter().all(|b| *b == 0));
+        assert_eq!(flash.erase_count(0), 1);
+        assert_eq!(flash.erase_count(1), 1);
+        assert_eq!(flash.erase_count(2), 0);
+    }
+
+    #[test]
+    fn overwrite() {
+        let mut flash = Flash::default();
+        flash.write(0, &[0x0F; 4]).unwrap();
+
+        flash.set_overwrite(Overwrite::Error);
+        assert_eq!(flash.write(0, &[0xF0; 4]), Err(FaultFlashError::NotErased));
+        assert_eq!(flash.mem[..4], [0x0F; 4]);
+
+        flash.set_overwrite(Overwrite::And);
+        flash.write(0, &[0xF1; 4]).unwrap();
+        assert_eq!(flash.mem[..4], [0x01; 4]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn overwrite_panics() {
+        let mut flash = Flash::default();
+        flash.write(0, &[0; 4]).unwrap();
+        let _ = flash.write(0, &[0; 4]);
+    }
+
+    #[test]
+    fn bit_flips() {
+        let mut flash = Flash::default();
+        flash.flip_bit(3, 7);
+        assert_eq!(flash.mem[3], 0x7F);
+
+        flash.erase(0, 256).unwrap();
+        flash.set_read_bit_flips(Some(1));
+        let mut buf = [0; 256];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf.iter().map(|b| b.count_zeros()).sum::<u32>(), 1);
+        assert!(flash.mem.iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn alignment() {
+        let mut flash = Flash::default();
+        assert_eq!(flash.write(1, &[0; 4]), Err(FaultFlashError::NotAligned));
+        assert_eq!(flash.erase(0, 100), Err(FaultFlashError::NotAligned));
+        assert_eq!(flash.read(1020, &mut [0; 8]), Err(FaultFlashError::OutOfBounds));
+    }
+}
@@ -1,8 +1,13 @@
 //! Utilities related to flash.
 
 mod concat_flash;
+#[cfg(any(test, feature = "fault-flash"))]
+mod fault_flash;
 #[cfg(test)]
 pub(crate) mod mem_flash;
 pub mod partition;
+pub mod storage;
 
 pub use concat_flash::ConcatFlash;
+#[cfg(any(test, feature = "fault-flash"))]
+pub use fault_flash::{FaultFlash, FaultFlashError, Overwrite};
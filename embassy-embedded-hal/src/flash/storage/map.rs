@@ -0,0 +1,381 @@
+use embedded_storage_async::nor_flash::NorFlash;
+
+use super::{Error, Item, PageState, Pages};
+
+/// Maximum length of a [`Map`] key.
+pub const MAX_KEY_LEN: usize = 64;
+
+const KIND_VALUE: u8 = 0x01;
+const KIND_REMOVED: u8 = 0x00;
+
+/// Power-fail safe, wear-leveled key/value store.
+///
+/// Every update of a key appends a new item, the last one wins. When the page being written is
+/// full, the next page is opened and the oldest page is garbage collected: the items it holds
+/// that were not overwritten since are copied to the new page, after which it is erased. One
+/// page is always kept erased for this, so the total size of the stored values is limited to
+/// somewhat less than the size of all pages but one.
+///
+/// Each operation either completes or, when interrupted by power loss, leaves the store as if it
+/// never happened.
+///
+/// See the [module documentation](super) for details about the format.
+pub struct Map<S: NorFlash> {
+    pages: Pages<S>,
+}
+
+impl<S: NorFlash> Map<S> {
+    /// Create a new map stored in `flash`.
+    ///
+    /// The whole flash is used by the map, and needs to contain at least two erase pages. Its
+    /// `WRITE_SIZE` and `READ_SIZE` need to be powers of two of at most 32 bytes, which is checked
+    /// at compile time.
+    pub fn new(flash: S) -> Self {
+        Self {
+            pages: Pages::new(flash),
+        }
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> S {
+        self.pages.flash
+    }
+
+    /// Largest value that can be stored for a key of `key_len` bytes.
+    pub fn max_value_len(key_len: usize) -> usize {
+        (Pages::<S>::max_item_len() as usize).saturating_sub(2 + key_len)
+    }
+
+    /// Fetch the value stored for `key` into `buf`.
+    ///
+    /// Returns the part of `buf` holding the value, or `None` if no value is stored for `key`.
+    pub async fn fetch<'b>(&mut self, key: &[u8], buf: &'b mut [u8]) -> Result<Option<&'b mut [u8]>, Error<S::Error>> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(Error::KeyTooLong);
+        }
+
+        match self.find_latest(key).await? {
+            Some((item, KIND_VALUE)) => {
+                let skip = 2 + key.len() as u32;
+                let len = (item.len - skip) as usize;
+                if buf.len() < len {
+                    return Err(Error::BufferTooSmall(len));
+                }
+                self.pages.read_data(&item, skip, &mut buf[..len]).await?;
+                Ok(Some(&mut buf[..len]))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Store `value` for `key`, replacing any previous value.
+    pub async fn store(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error<S::Error>> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(Error::KeyTooLong);
+        }
+        if value.len() > Self::max_value_len(key.len()) {
+            return Err(Error::ItemTooBig);
+        }
+
+        self.append(&[&[KIND_VALUE, key.len() as u8], key, value]).await
+    }
+
+    /// Remove the value stored for `key`, if any.
+    pub async fn remove(&mut self, key: &[u8]) -> Result<(), Error<S::Error>> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(Error::KeyTooLong);
+        }
+
+        match self.find_latest(key).await? {
+            Some((_, KIND_VALUE)) => self.append(&[&[KIND_REMOVED, key.len() as u8], key]).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Erase all keys and values.
+    pub async fn clear(&mut self) -> Result<(), Error<S::Error>> {
+        for page in 0..self.pages.page_count() {
+            self.pages.erase_page(page).await?;
+        }
+        Ok(())
+    }
+
+    async fn append(&mut self, parts: &[&[u8]]) -> Result<(), Error<S::Error>> {
+        let len = parts.iter().map(|p| p.len()).sum::<usize>() as u32;
+
+        let (mut page, mut seq) = self.prepare().await?;
+        for _ in 0..self.pages.page_count() {
+            if let Some(offset) = self.pages.free_offset(page, len).await? {
+                self.pages.write_item(offset, parts).await?;
+                return Ok(());
+            }
+            (page, seq) = self.advance(page, seq).await?;
+        }
+        Err(Error::FullStorage)
+    }
+
+    /// Find the page to write to, and finish any garbage collection interrupted by power loss.
+    async fn prepare(&mut self) -> Result<(u32, u32), Error<S::Error>> {
+        let (page, seq) = match self.pages.newest_page().await? {
+            Some(newest) => newest,
+            None => {
+                self.pages.open_page(0, 0).await?;
+                (0, 0)
+            }
+        };
+
+        self.make_spare(page, self.pages.next_page(page)).await?;
+        Ok((page, seq))
+    }
+
+    /// Open the next page, keeping the one after it erased.
+    async fn advance(&mut self, page: u32, seq: u32) -> Result<(u32, u32), Error<S::Error>> {
+        let next = self.pages.next_page(page);
+        let seq = seq.wrapping_add(1);
+        self.pages.open_page(next, seq).await?;
+
+        self.make_spare(next, self.pages.next_page(next)).await?;
+        Ok((next, seq))
+    }
+
+    /// Make sure `spare` is erased, moving any live items it holds to `page`.
+    async fn make_spare(&mut self, page: u32, spare: u32) -> Result<(), Error<S::Error>> {
+        match self.pages.page_state(spare).await? {
+            PageState::Erased => return Ok(()),
+            PageState::Open(_) => self.collect(spare, page).await?,
+            PageState::Corrupt => {}
+        }
+        self.pages.erase_page(spare).await?;
+        Ok(())
+    }
+
+    /// Copy the items of the oldest page `from` that are still needed to `to`.
+    ///
+    /// Removed keys are dropped, since no older page can hold a value for them.
+    async fn collect(&mut self, from: u32, to: u32) -> Result<(), Error<S::Error>> {
+        let mut offset = Pages::<S>::data_start(from);
+        while let Some(item) = self.pages.next_item(from, offset).await? {
+            offset = item.end;
+
+            let mut key = [0; MAX_KEY_LEN];
+            let Some((kind, key)) = self.read_key(&item, &mut key).await? else {
+                continue;
+            };
+            if kind != KIND_VALUE {
+                continue;
+            }
+
+            if matches!(self.find_latest(key).await?, Some((latest, _)) if latest == item) {
+                let Some(dest) = self.pages.free_offset(to, item.len).await? else {
+                    return Err(Error::FullStorage);
+                };
+                self.pages.copy_item(&item, dest).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the kind and key of an item.
+    async fn read_key<'k>(
+        &mut self,
+        item: &Item,
+        buf: &'k mut [u8; MAX_KEY_LEN],
+    ) -> Result<Option<(u8, &'k [u8])>, S::Error> {
+        if item.len < 2 {
+            return Ok(None);
+        }
+        let mut head = [0; 2];
+        self.pages.read_data(item, 0, &mut head).await?;
+        let [kind, key_len] = head;
+        let key_len = key_len as usize;
+        if key_len > MAX_KEY_LEN || 2 + key_len as u32 > item.len {
+            return Ok(None);
+        }
+        self.pages.read_data(item, 2, &mut buf[..key_len]).await?;
+        Ok(Some((kind, &buf[..key_len])))
+    }
+
+    /// Find the last item written for `key`, and its kind.
+    async fn find_latest(&mut self, key: &[u8]) -> Result<Option<(Item, u8)>, S::Error> {
+        let Some((newest, _)) = self.pages.newest_page().await? else {
+            return Ok(None);
+        };
+
+        let mut latest = None;
+        for page in self.pages.pages_from_oldest(newest) {
+            if !matches!(self.pages.page_state(page).await?, PageState::Open(_)) {
+                continue;
+            }
+
+            let mut offset = Pages::<S>::data_start(page);
+            while let Some(item) = self.pages.next_item(page, offset).await? {
+                offset = item.end;
+
+                let mut buf = [0; MAX_KEY_LEN];
+                if let Some((kind, item_key)) = self.read_key(&item, &mut buf).await?
+                    && item_key == key
+                {
+                    latest = Some((item, kind));
+                }
+            }
+        }
+        Ok(latest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::flash::FaultFlash;
+    use crate::flash::mem_flash::MemFlash;
+
+    extern crate alloc;
+
+    type Flash = MemFlash<1024, 256, 4>;
+
+    #[futures_test::test]
+    async fn store_fetch_remove() {
+        let mut map = Map::new(Flash::default());
+        let mut buf = [0; 32];
+
+        assert_eq!(map.fetch(b"missing", &mut buf).await.unwrap(), None);
+
+        map.store(b"a", b"first").await.unwrap();
+        map.store(b"b", b"second").await.unwrap();
+        map.store(b"a", b"third").await.unwrap();
+
+        assert_eq!(map.fetch(b"a", &mut buf).await.unwrap().unwrap(), b"third");
+        assert_eq!(map.fetch(b"b", &mut buf).await.unwrap().unwrap(), b"second");
+
+        map.remove(b"a").await.unwrap();
+        assert_eq!(map.fetch(b"a", &mut buf).await.unwrap(), None);
+        assert_eq!(map.fetch(b"b", &mut buf).await.unwrap().unwrap(), b"second");
+
+        assert_eq!(map.fetch(b"b", &mut buf[..2]).await, Err(Error::BufferTooSmall(6)));
+    }
+
+    #[futures_test::test]
+    async fn wear_leveling() {
+        let mut map = Map::new(Flash::default());
+        let mut buf = [0; 32];
+
+        for i in 0..1000u32 {
+            map.store(&[(i % 7) as u8], &i.to_le_bytes()).await.unwrap();
+        }
+        for k in 0..7u32 {
+            let value = map.fetch(&[k as u8], &mut buf).await.unwrap().unwrap();
+            let last = (1000 - 7..1000).find(|i| i % 7 == k).unwrap();
+            assert_eq!(value, last.to_le_bytes());
+        }
+
+        let flash = map.into_inner();
+        let mut erases = [0; 4];
+        for (from, _) in flash.erases.iter() {
+            erases[*from as usize / 256] += 1;
+        }
+        let min = *erases.iter().min().unwrap();
+        let max = *erases.iter().max().unwrap();
+        assert!(min > 0 && max - min <= 1, "uneven wear: {:?}", erases);
+    }
+
+    #[futures_test::test]
+    async fn full_storage() {
+        let mut map = Map::new(Flash::default());
+
+        let value = [0xAA; 100];
+        let mut stored = 0;
+        let res = loop {
+            match map.store(&[stored], &value).await {
+                Ok(()) => stored += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(res, Error::FullStorage);
+        assert!(stored >= 2);
+
+        // Everything stored so far is still there.
+        let mut buf = [0; 100];
+        for k in 0..stored {
+            assert_eq!(map.fetch(&[k], &mut buf).await.unwrap().unwrap(), value);
+        }
+    }
+
+    #[futures_test::test]
+    async fn too_big() {
+        let mut map = Map::new(Flash::default());
+
+        assert_eq!(map.store(&[0; MAX_KEY_LEN + 1], b"").await, Err(Error::KeyTooLong));
+        let value = [0; 256];
+        let max = Map::<Flash>::max_value_len(1);
+        assert_eq!(map.store(b"k", &value[..max + 1]).await, Err(Error::ItemTooBig));
+        map.store(b"k", &value[..max]).await.unwrap();
+    }
+
+    /// Run a sequence of updates, cutting power at every single write and erase, and check that
+    /// each key holds either its value from before or after the interrupted update.
+    #[futures_test::test]
+    async fn power_loss() {
+        const KEYS: u8 = 5;
+        const UPDATES: u32 = 40;
+
+        for fail_at in 0.. {
+            let mut flash = FaultFlash::<1024, 256, 4>::default();
+            flash.seed(fail_at as u64);
+            flash.cut_power_after(fail_at);
+            let mut map = Map::new(flash);
+            let mut buf = [0; 16];
+            let mut expected: Vec<Option<u32>> = (0..KEYS).map(|_| None).collect();
+
+            let mut interrupted = None;
+            for i in 0..UPDATES {
+                let key = (i % KEYS as u32) as u8;
+                let res = if i % 11 == 10 {
+                    map.remove(&[key]).await.map(|_| None)
+                } else {
+                    map.store(&[key], &i.to_le_bytes()).await.map(|_| Some(i))
+                };
+                match res {
+                    Ok(value) => expected[key as usize] = value,
+                    Err(_) => {
+                        interrupted = Some((key, i));
+                        break;
+                    }
+                }
+            }
+
+            let Some((key, i)) = interrupted else {
+                // All updates went through, so power loss was tested at every step.
+                assert!(fail_at > 0);
+                break;
+            };
+
+            // Reboot
+            let mut flash = map.into_inner();
+            flash.power_cycle();
+            let mut map = Map::new(flash);
+            for k in 0..KEYS {
+                let value = map
+                    .fetch(&[k], &mut buf)
+                    .await
+                    .unwrap()
+                    .map(|v| u32::from_le_bytes(v.try_into().unwrap()));
+                if k == key && value != expected[k as usize] {
+                    let new = if i % 11 == 10 { None } else { Some(i) };
+                    assert_eq!(value, new, "fail_at {}: interrupted update of key {}", fail_at, k);
+                } else {
+                    assert_eq!(value, expected[k as usize], "fail_at {}: key {}", fail_at, k);
+                }
+            }
+
+            // The map keeps working after power loss
+            for i in UPDATES..UPDATES + 30 {
+                let key = (i % KEYS as u32) as u8;
+                map.store(&[key], &i.to_le_bytes()).await.unwrap();
+                let value = map.fetch(&[key], &mut buf).await.unwrap().unwrap();
+                assert_eq!(value, i.to_le_bytes());
+            }
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Power-fail safe data storage on NOR flash
+//!
+//! This module provides a wear-leveled key/value [`Map`] and a circular log [`Queue`], both
+//! generic over an async [`NorFlash`]. The flash
+//! passed to them is used exclusively by the store, which makes a [`Partition`](crate::flash::partition::Partition)
+//! or a [`ConcatFlash`](crate::flash::ConcatFlash) the natural choice when the store should only
+//! use a region of a larger flash.
+//!
+//! # Format
+//!
+//! The flash is divided into pages of `ERASE_SIZE` bytes, which need to be at least two. Pages are
+//! used round-robin, so erases are spread evenly over the whole flash. Each page starts with a
+//! header holding a sequence number, followed by items that are only ever appended:
+//!
+//! | Field  | Size                | Description                                               |
+//! |--------|---------------------|-----------------------------------------------------------|
+//! | len    | 2                   | Length of the item data.                                  |
+//! | !len   | 2                   | Inverted length, to detect interrupted header writes.     |
+//! | crc    | 4                   | CRC-32 of len and data, to detect interrupted writes.     |
+//! | marker | `WRITE_SIZE`        | Left erased when writing the item, programmed to consume it.|
+//! | data   | len, padded         | Item data, padded to `WRITE_SIZE`.                        |
+//!
+//! An item that was interrupted by power loss fails its CRC check and is ignored. Pages are only
+//! erased once their contents are no longer needed, and are checked to be fully erased before
+//! they are used again, so an interrupted erase does not resurrect stale data either.
+//!
+//! The flash is assumed to read `0xFF` after being erased.
+//!
+//! # Limitations
+//!
+//! Data is moved in and out of the flash through a 32 byte buffer, so the `WRITE_SIZE` and
+//! `READ_SIZE` of the flash need to be powers of two of at most 32 bytes. Flashes with larger
+//! write sizes are rejected at compile time.
+
+mod map;
+mod queue;
+
+use embedded_storage_async::nor_flash::NorFlash;
+pub use map::{MAX_KEY_LEN, Map};
+pub use queue::Queue;
+
+/// Errors returned by the flash storage.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Underlying flash error
+    Flash(E),
+    /// There is not enough free space left to store the item.
+    FullStorage,
+    /// The item does not fit in a single page.
+    ItemTooBig,
+    /// The key is longer than [`MAX_KEY_LEN`].
+    KeyTooLong,
+    /// The buffer is too small to hold the item. Contains the required size.
+    BufferTooSmall(usize),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Self::Flash(e)
+    }
+}
+
+const PAGE_MAGIC: u32 = 0xEB57_0A6E;
+
+/// Size of the buffer used to move data in and out of the flash.
+const CHUNK_SIZE: usize = 32;
+
+#[repr(align(32))]
+struct Chunk([u8; CHUNK_SIZE]);
+
+impl Chunk {
+    const fn new() -> Self {
+        Self([0xFF; CHUNK_SIZE])
+    }
+}
+
+const fn align_up(value: u32, align: u32) -> u32 {
+    value.div_ceil(align) * align
+}
+
+/// State of a page, as read from its header.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PageState {
+    Erased,
+    Open(u32),
+    Corrupt,
+}
+
+/// Location and header of an item.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Item {
+    /// Absolute offset of the item.
+    offset: u32,
+    /// Absolute offset of the item data.
+    data_offset: u32,
+    /// Absolute offset just past the item.
+    end: u32,
+    len: u32,
+    crc: u32,
+}
+
+enum Slot {
+    Free,
+    Item(Item),
+    Corrupt,
+}
+
+/// The page and item format shared by [`Map`] and [`Queue`].
+struct Pages<S: NorFlash> {
+    flash: S,
+}
+
+impl<S: NorFlash> Pages<S> {
+    const ALIGN: u32 = if S::WRITE_SIZE > S::READ_SIZE {
+        S::WRITE_SIZE as u32
+    } else {
+        S::READ_SIZE as u32
+    };
+    const PAGE_HEADER_SIZE: u32 = align_up(16, Self::ALIGN);
+    const ITEM_HEADER_SIZE: u32 = align_up(8, Self::ALIGN);
+    const MARKER_SIZE: u32 = Self::ALIGN;
+
+    fn new(flash: S) -> Self {
+        const {
+            core::assert!(
+                Self::ALIGN.is_power_of_two() && Self::ALIGN as usize <= CHUNK_SIZE,
+                "flash storage needs a write and read size that is a power of two of at most 32 bytes"
+            );
+            core::assert!(S::ERASE_SIZE % Self::ALIGN as usize == 0);
+        }
+        assert!(
+            flash.capacity() / S::ERASE_SIZE >= 2,
+            "flash storage needs at least two pages"
+        );
+        Self { flash }
+    }
+
+    fn page_count(&self) -> u32 {
+        (self.flash.capacity() / S::ERASE_SIZE) as u32
+    }
+
+    fn page_start(page: u32) -> u32 {
+        page * S::ERASE_SIZE as u32
+    }
+
+    fn page_end(page: u32) -> u32 {
+        Self::page_start(page) + S::ERASE_SIZE as u32
+    }
+
+    fn data_start(page: u32) -> u32 {
+        Self::page_start(page) + Self::PAGE_HEADER_SIZE
+    }
+
+    fn item_size(len: u32) -> u32 {
+        Self::ITEM_HEADER_SIZE + Self::MARKER_SIZE + align_up(len, Self::ALIGN)
+    }
+
+    /// Largest item data that fits in an empty page.
+    fn max_item_len() -> u32 {
+        let max = S::ERASE_SIZE as u32 - Self::PAGE_HEADER_SIZE - Self::ITEM_HEADER_SIZE - Self::MARKER_SIZE;
+        (max / Self::ALIGN * Self::ALIGN).min(u16::MAX as u32 - 1)
+    }
+
+    fn next_page(&self, page: u32) -> u32 {
+        (page + 1) % self.page_count()
+    }
+
+    async fn read_chunk(&mut self, offset: u32, len: usize) -> Result<Chunk, S::Error> {
+        let mut chunk = Chunk::new();
+        self.flash.read(offset, &mut chunk.0[..len]).await?;
+        Ok(chunk)
+    }
+
+    async fn page_state(&mut self, page: u32) -> Result<PageState, S::Error> {
+        let header = self
+            .read_chunk(Self::page_start(page), Self::PAGE_HEADER_SIZE as usize)
+            .await?;
+        let word = |i: usize| u32::from_le_bytes(header.0[i * 4..i * 4 + 4].try_into().unwrap());
+
+        if header.0[..16].iter().all(|&b| b == 0xFF) {
+            Ok(PageState::Erased)
+        } else if word(0) == PAGE_MAGIC && word(1) == !word(2) {
+            Ok(PageState::Open(word(1)))
+        } else {
+            Ok(PageState::Corrupt)
+        }
+    }
+
+    /// Find the page that was opened last, and its sequence number.
+    async fn newest_page(&mut self) -> Result<Option<(u32, u32)>, S::Error> {
+        let mut newest = None;
+        for page in 0..self.page_count() {
+            if let PageState::Open(seq) = self.page_state(page).await?
+                && newest.is_none_or(|(_, newest_seq)| seq > newest_seq)
+            {
+                newest = Some((page, seq));
+            }
+        }
+        Ok(newest)
+    }
+
+    /// All pages, from the oldest to the newest one.
+    fn pages_from_oldest(&self, newest: u32) -> impl Iterator<Item = u32> + use<S> {
+        let count = self.page_count();
+        (1..=count).map(move |i| (newest + i) % count)
+    }
+
+    async fn erase_page(&mut self, page: u32) -> Result<(), S::Error> {
+        self.flash.erase(Self::page_start(page), Self::page_end(page)).await
+    }
+
+    /// Start using `page`, making sure it is fully erased first.
+    async fn open_page(&mut self, page: u32, seq: u32) -> Result<(), S::Error> {
+        let mut erased = true;
+        for offset in (Self::page_start(page)..Self::page_end(page)).step_by(CHUNK_SIZE) {
+            let len = CHUNK_SIZE.min((Self::page_end(page) - offset) as usize);
+            if self.read_chunk(offset, len).await?.0[..len].iter().any(|&b| b != 0xFF) {
+                erased = false;
+                break;
+            }
+        }
+        if !erased {
+            self.erase_page(page).await?;
+        }
+
+        let mut header = Chunk::new();
+        header.0[0..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        header.0[4..8].copy_from_slice(&seq.to_le_bytes());
+        header.0[8..12].copy_from_slice(&(!seq).to_le_bytes());
+        self.flash
+            .write(Self::page_start(page), &header.0[..Self::PAGE_HEADER_SIZE as usize])
+            .await
+    }
+
+    async fn slot(&mut self, offset: u32, page_end: u32) -> Result<Slot, S::Error> {
+        if offset + Self::ITEM_HEADER_SIZE > page_end {
+            return Ok(Slot::Corrupt);
+        }
+
+        let header = self.read_chunk(offset, Self::ITEM_HEADER_SIZE as usize).await?;
+        if header.0[..8].iter().all(|&b| b == 0xFF) {
+            return Ok(Slot::Free);
+        }
+
+        let len = u16::from_le_bytes([header.0[0], header.0[1]]);
+        let inv_len = u16::from_le_bytes([header.0[2], header.0[3]]);
+        let crc = u32::from_le_bytes(header.0[4..8].try_into().unwrap());
+        let item = Item {
+            offset,
+            data_offset: offset + Self::ITEM_HEADER_SIZE + Self::MARKER_SIZE,
+            end: offset + Self::item_size(len as u32),
+            len: len as u32,
+            crc,
+        };
+        if len != !inv_len || item.end > page_end {
+            return Ok(Slot::Corrupt);
+        }
+        Ok(Slot::Item(item))
+    }
+
+    /// Find the first item with valid data in `page`, at or after `offset`.
+    async fn next_item(&mut self, page: u32, mut offset: u32) -> Result<Option<Item>, S::Error> {
+        loop {
+            match self.slot(offset, Self::page_end(page)).await? {
+                Slot::Item(item) => {
+                    if self.crc_ok(&item).await? {
+                        return Ok(Some(item));
+                    }
+                    offset = item.end;
+                }
+                Slot::Free | Slot::Corrupt => return Ok(None),
+            }
+        }
+    }
+
+    /// Find the offset where an item of `len` bytes can be appended to `page`.
+    async fn free_offset(&mut self, page: u32, len: u32) -> Result<Option<u32>, S::Error> {
+        let mut offset = Self::data_start(page);
+        loop {
+            match self.slot(offset, Self::page_end(page)).await? {
+                Slot::Item(item) => offset = item.end,
+                Slot::Free if offset + Self::item_size(len) <= Self::page_end(page) => return Ok(Some(offset)),
+                Slot::Free | Slot::Corrupt => return Ok(None),
+            }
+        }
+    }
+
+    async fn crc_ok(&mut self, item: &Item) -> Result<bool, S::Error> {
+        let mut crc = Crc32::new();
+        crc.update(&(item.len as u16).to_le_bytes());
+        let mut pos = 0;
+        while pos < item.len {
+            let len = CHUNK_SIZE.min(align_up(item.len - pos, Self::ALIGN) as usize);
+            let chunk = self.read_chunk(item.data_offset + pos, len).await?;
+            let used = len.min((item.len - pos) as usize);
+            crc.update(&chunk.0[..used]);
+            pos += used as u32;
+        }
+        Ok(crc.finish() == item.crc)
+    }
+
+    /// Read item data starting at `skip` into `buf`.
+    async fn read_data(&mut self, item: &Item, skip: u32, buf: &mut [u8]) -> Result<(), S::Error> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let start = skip + pos as u32;
+            let aligned = start / Self::ALIGN * Self::ALIGN;
+            let lead = (start - aligned) as usize;
+            let len = CHUNK_SIZE.min(align_up((lead + buf.len() - pos) as u32, Self::ALIGN) as usize);
+            let chunk = self.read_chunk(item.data_offset + aligned, len).await?;
+            let used = (len - lead).min(buf.len() - pos);
+            buf[pos..pos + used].copy_from_slice(&chunk.0[lead..lead + used]);
+            pos += used;
+        }
+        Ok(())
+    }
+
+    async fn is_consumed(&mut self, item: &Item) -> Result<bool, S::Error> {
+        let marker = self
+            .read_chunk(item.offset + Self::ITEM_HEADER_SIZE, Self::MARKER_SIZE as usize)
+            .await?;
+        Ok(marker.0[..Self::MARKER_SIZE as usize].iter().any(|&b| b != 0xFF))
+    }
+
+    async fn consume(&mut self, item: &Item) -> Result<(), S::Error> {
+        let marker = Chunk([0; CHUNK_SIZE]);
+        self.flash
+            .write(
+                item.offset + Self::ITEM_HEADER_SIZE,
+                &marker.0[..Self::MARKER_SIZE as usize],
+            )
+            .await
+    }
+
+    /// Write an item at `offset`, with the concatenation of `parts` as data.
+    async fn write_item(&mut self, offset: u32, parts: &[&[u8]]) -> Result<(), S::Error> {
+        let len: usize = parts.iter().map(|p| p.len()).sum();
+        let mut crc = Crc32::new();
+        crc.update(&(len as u16).to_le_bytes());
+        parts.iter().for_each(|p| crc.update(p));
+
+        let mut header = Chunk::new();
+        header.0[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+        header.0[2..4].copy_from_slice(&(!(len as u16)).to_le_bytes());
+        header.0[4..8].copy_from_slice(&crc.finish().to_le_bytes());
+        self.flash
+            .write(offset, &header.0[..Self::ITEM_HEADER_SIZE as usize])
+            .await?;
+
+        let mut chunk = Chunk::new();
+        let mut fill = 0;
+        let mut pos = offset + Self::ITEM_HEADER_SIZE + Self::MARKER_SIZE;
+        for mut part in parts.iter().copied() {
+            while !part.is_empty() {
+                let n = part.len().min(CHUNK_SIZE - fill);
+                chunk.0[fill..fill + n].copy_from_slice(&part[..n]);
+                fill += n;
+                part = &part[n..];
+                if fill == CHUNK_SIZE {
+                    self.flash.write(pos, &chunk.0).await?;
+                    pos += CHUNK_SIZE as u32;
+                    fill = 0;
+                }
+            }
+        }
+        if fill > 0 {
+            let len = align_up(fill as u32, Self::ALIGN) as usize;
+            chunk.0[fill..len].fill(0xFF);
+            self.flash.write(pos, &chunk.0[..len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy an item to `offset`. The copy is not consumed, even if the item is.
+    async fn copy_item(&mut self, item: &Item, offset: u32) -> Result<(), S::Error> {
+        let header = self.read_chunk(item.offset, Self::ITEM_HEADER_SIZE as usize).await?;
+        self.flash
+            .write(offset, &header.0[..Self::ITEM_HEADER_SIZE as usize])
+            .await?;
+
+        let data_len = align_up(item.len, Self::ALIGN);
+        let dest = offset + Self::ITEM_HEADER_SIZE + Self::MARKER_SIZE;
+        let mut pos = 0;
+        while pos < data_len {
+            let len = CHUNK_SIZE.min((data_len - pos) as usize);
+            let chunk = self.read_chunk(item.data_offset + pos, len).await?;
+            self.flash.write(dest + pos, &chunk.0[..len]).await?;
+            pos += len as u32;
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to avoid a lookup table.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 {
+                    (self.0 >> 1) ^ 0xEDB8_8320
+                } else {
+                    self.0 >> 1
+                };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
@@ -0,0 +1,294 @@
+use embedded_storage_async::nor_flash::NorFlash;
+
+use super::{Error, Item, PageState, Pages};
+
+/// Power-fail safe, wear-leveled circular log queue.
+///
+/// Items are appended to the newest page, and popped from the oldest one. Popping an item only
+/// marks it as consumed, pages are erased once all of their items are consumed and the page is
+/// needed again. When the queue is full, [`push`](Self::push) can optionally make room by
+/// dropping the oldest page, which turns the queue into a circular log.
+///
+/// A pushed item is either stored completely or not at all when interrupted by power loss. An
+/// item that was being popped when power was lost may or may not be consumed afterwards, so for
+/// at-least-once processing, [`peek`](Self::peek) at the item first and only pop it once handled.
+///
+/// See the [module documentation](super) for details about the format.
+pub struct Queue<S: NorFlash> {
+    pages: Pages<S>,
+}
+
+impl<S: NorFlash> Queue<S> {
+    /// Create a new queue stored in `flash`.
+    ///
+    /// The whole flash is used by the queue, and needs to contain at least two erase pages. Its
+    /// `WRITE_SIZE` and `READ_SIZE` need to be powers of two of at most 32 bytes, which is checked
+    /// at compile time.
+    pub fn new(flash: S) -> Self {
+        Self {
+            pages: Pages::new(flash),
+        }
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> S {
+        self.pages.flash
+    }
+
+    /// Largest item that can be pushed.
+    pub fn max_item_len() -> usize {
+        Pages::<S>::max_item_len() as usize
+    }
+
+    /// Append `data` to the queue.
+    ///
+    /// If the queue is full, the oldest page of items is dropped to make room if `allow_overwrite`
+    /// is set, otherwise [`Error::FullStorage`] is returned.
+    pub async fn push(&mut self, data: &[u8], allow_overwrite: bool) -> Result<(), Error<S::Error>> {
+        if data.len() > Self::max_item_len() {
+            return Err(Error::ItemTooBig);
+        }
+
+        let (mut page, mut seq) = match self.pages.newest_page().await? {
+            Some(newest) => newest,
+            None => {
+                self.pages.open_page(0, 0).await?;
+                (0, 0)
+            }
+        };
+
+        loop {
+            if let Some(offset) = self.pages.free_offset(page, data.len() as u32).await? {
+                self.pages.write_item(offset, &[data]).await?;
+                return Ok(());
+            }
+
+            // The page after the newest one is the oldest, or erased.
+            let next = self.pages.next_page(page);
+            if let PageState::Open(_) = self.pages.page_state(next).await?
+                && !allow_overwrite
+                && self.next_unconsumed(next).await?.is_some()
+            {
+                return Err(Error::FullStorage);
+            }
+
+            seq = seq.wrapping_add(1);
+            self.pages.open_page(next, seq).await?;
+            page = next;
+        }
+    }
+
+    /// Copy the oldest item into `buf` without removing it from the queue.
+    ///
+    /// Returns the part of `buf` holding the item, or `None` if the queue is empty.
+    pub async fn peek<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<&'b mut [u8]>, Error<S::Error>> {
+        match self.oldest().await? {
+            Some(item) => Ok(Some(self.read(&item, buf).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the oldest item from the queue, copying it into `buf`.
+    ///
+    /// Returns the part of `buf` holding the item, or `None` if the queue is empty.
+    pub async fn pop<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<&'b mut [u8]>, Error<S::Error>> {
+        match self.oldest().await? {
+            Some(item) => {
+                let data = self.read(&item, buf).await?;
+                self.pages.consume(&item).await?;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Check whether the queue holds no items.
+    pub async fn is_empty(&mut self) -> Result<bool, Error<S::Error>> {
+        Ok(self.oldest().await?.is_none())
+    }
+
+    /// Erase all items.
+    pub async fn clear(&mut self) -> Result<(), Error<S::Error>> {
+        for page in 0..self.pages.page_count() {
+            self.pages.erase_page(page).await?;
+        }
+        Ok(())
+    }
+
+    async fn read<'b>(&mut self, item: &Item, buf: &'b mut [u8]) -> Result<&'b mut [u8], Error<S::Error>> {
+        let len = item.len as usize;
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall(len));
+        }
+        self.pages.read_data(item, 0, &mut buf[..len]).await?;
+        Ok(&mut buf[..len])
+    }
+
+    async fn oldest(&mut self) -> Result<Option<Item>, S::Error> {
+        let Some((newest, _)) = self.pages.newest_page().await? else {
+            return Ok(None);
+        };
+
+        for page in self.pages.pages_from_oldest(newest) {
+            if let PageState::Open(_) = self.pages.page_state(page).await?
+                && let Some(item) = self.next_unconsumed(page).await?
+            {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn next_unconsumed(&mut self, page: u32) -> Result<Option<Item>, S::Error> {
+        let mut offset = Pages::<S>::data_start(page);
+        while let Some(item) = self.pages.next_item(page, offset).await? {
+            if !self.pages.is_consumed(&item).await? {
+                return Ok(Some(item));
+            }
+            offset = item.end;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::FaultFlash;
+    use crate::flash::mem_flash::MemFlash;
+
+    type Flash = MemFlash<1024, 256, 4>;
+
+    #[futures_test::test]
+    async fn push_pop() {
+        let mut queue = Queue::new(Flash::default());
+        let mut buf = [0; 32];
+
+        assert!(queue.is_empty().await.unwrap());
+        assert_eq!(queue.pop(&mut buf).await.unwrap(), None);
+
+        queue.push(b"one", false).await.unwrap();
+        queue.push(b"two", false).await.unwrap();
+
+        assert_eq!(queue.peek(&mut buf).await.unwrap().unwrap(), b"one");
+        assert_eq!(queue.pop(&mut buf).await.unwrap().unwrap(), b"one");
+        assert_eq!(queue.pop(&mut buf[..2]).await, Err(Error::BufferTooSmall(3)));
+        assert_eq!(queue.pop(&mut buf).await.unwrap().unwrap(), b"two");
+        assert_eq!(queue.pop(&mut buf).await.unwrap(), None);
+    }
+
+    #[futures_test::test]
+    async fn full_and_overwrite() {
+        let mut queue = Queue::new(Flash::default());
+        let mut buf = [0; 32];
+
+        let mut pushed = 0u32;
+        while queue.push(&pushed.to_le_bytes(), false).await.is_ok() {
+            pushed += 1;
+        }
+        assert_eq!(queue.push(&pushed.to_le_bytes(), false).await, Err(Error::FullStorage));
+
+        // Overwriting drops the oldest page of items.
+        queue.push(&pushed.to_le_bytes(), true).await.unwrap();
+        let first = u32::from_le_bytes(queue.peek(&mut buf).await.unwrap().unwrap().try_into().unwrap());
+        assert!(first > 0);
+
+        // Items are still returned in order.
+        for expected in first..=pushed {
+            let item = queue.pop(&mut buf).await.unwrap().unwrap();
+            assert_eq!(u32::from_le_bytes(item.try_into().unwrap()), expected);
+        }
+        assert!(queue.is_empty().await.unwrap());
+    }
+
+    #[futures_test::test]
+    async fn consumed_pages_are_reused() {
+        let mut queue = Queue::new(Flash::default());
+        let mut buf = [0; 32];
+
+        for i in 0..1000u32 {
+            queue.push(&i.to_le_bytes(), false).await.unwrap();
+            let item = queue.pop(&mut buf).await.unwrap().unwrap();
+            assert_eq!(u32::from_le_bytes(item.try_into().unwrap()), i);
+        }
+    }
+
+    /// Push and pop items, cutting power at every single write and erase, and check that no item
+    /// is lost, duplicated or reordered apart from the one being popped when power was lost.
+    #[futures_test::test]
+    async fn power_loss() {
+        const OPS: u32 = 60;
+
+        for fail_at in 0.. {
+            let mut flash = FaultFlash::<1024, 256, 4>::default();
+            flash.seed(fail_at as u64);
+            flash.cut_power_after(fail_at);
+            let mut queue = Queue::new(flash);
+            let mut buf = [0; 16];
+            let mut pushed = 0u32;
+            let mut popped = 0u32;
+
+            let mut interrupted = None;
+            for i in 0..OPS {
+                let res = if i % 3 == 2 {
+                    queue.pop(&mut buf).await.map(|item| {
+                        assert_eq!(
+                            item.map(|v| u32::from_le_bytes((&*v).try_into().unwrap())),
+                            Some(popped)
+                        );
+                        popped += 1;
+                    })
+                } else {
+                    queue.push(&pushed.to_le_bytes(), false).await.map(|_| pushed += 1)
+                };
+                if res.is_err() {
+                    interrupted = Some(i % 3 == 2);
+                    break;
+                }
+            }
+
+            let Some(was_pop) = interrupted else {
+                assert!(fail_at > 0);
+                break;
+            };
+
+            // Reboot
+            let mut flash = queue.into_inner();
+            flash.power_cycle();
+            let mut queue = Queue::new(flash);
+            let mut next = popped;
+            if was_pop {
+                // The interrupted pop may or may not have consumed the item.
+                let item = queue.peek(&mut buf).await.unwrap();
+                if item.map(|v| u32::from_le_bytes((&*v).try_into().unwrap())) != Some(popped) {
+                    next += 1;
+                }
+            } else {
+                // The interrupted push may or may not have stored the item.
+                pushed += 1;
+            }
+
+            while let Some(item) = queue.pop(&mut buf).await.unwrap() {
+                assert_eq!(
+                    u32::from_le_bytes((&*item).try_into().unwrap()),
+                    next,
+                    "fail_at {}",
+                    fail_at
+                );
+                next += 1;
+            }
+            assert!(
+                next == pushed || (!was_pop && next == pushed - 1),
+                "fail_at {}",
+                fail_at
+            );
+
+            // The queue keeps working after power loss
+            for i in 0..30u32 {
+                queue.push(&i.to_le_bytes(), false).await.unwrap();
+                let item = queue.pop(&mut buf).await.unwrap().unwrap();
+                assert_eq!(u32::from_le_bytes((&*item).try_into().unwrap()), i);
+            }
+        }
+    }
+}
@@ -4,6 +4,8 @@
 #![doc = include_str!("../README.md")]
 
 pub mod adapter;
+#[cfg(feature = "block-device")]
+pub mod block;
 pub mod flash;
 pub mod shared_bus;
 
@@ -0,0 +1,600 @@
+//! Shared bus with prioritized, fair arbitration
+//!
+//! [`BusArbiter`] can be used in place of a [`Mutex`](embassy_sync::mutex::Mutex) to share a bus
+//! between devices. Each device acquires the bus with a [`Priority`]: when the bus is released,
+//! it is handed to the highest priority waiter, and waiters with the same priority are served in
+//! the order they started waiting. Since the bus is only released between transactions, this
+//! lets a time-critical device preempt a bulk transfer at transaction boundaries.
+//!
+//! The arbiter keeps [`BusStats`] about bus contention, and with the `time` feature enabled the
+//! bus can be acquired with a timeout, so one stuck device cannot starve the others forever.
+//!
+//! # Example (nrf52)
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::shared_bus::asynch::arbiter::{BusArbiter, Priority};
+//! use embassy_embedded_hal::shared_bus::asynch::i2c::PrioritizedI2cDevice;
+//! use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+//!
+//! static I2C_BUS: StaticCell<BusArbiter<NoopRawMutex, Twim<TWISPI0>>> = StaticCell::new();
+//! let i2c = Twim::new(p.TWISPI0, Irqs, p.P0_03, p.P0_04, twim::Config::default());
+//! let i2c_bus = I2C_BUS.init(BusArbiter::new(i2c));
+//!
+//! // The IMU must be read on time, the EEPROM can wait.
+//! let mut imu = PrioritizedI2cDevice::new(i2c_bus, Priority::High);
+//! imu.set_timeout(Some(Duration::from_millis(2)));
+//! let eeprom = PrioritizedI2cDevice::new(i2c_bus, Priority::Low);
+//! ```
+
+use core::cell::{RefCell, UnsafeCell};
+use core::future::poll_fn;
+use core::ops::{Deref, DerefMut};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+#[cfg(feature = "time")]
+use embassy_time::{Duration, Instant};
+
+/// Priority used to acquire a [`BusArbiter`].
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// Background transfers, such as bulk EEPROM or flash writes.
+    Low,
+    /// Default priority.
+    Normal,
+    /// Time-critical transfers.
+    High,
+}
+
+const PRIORITY_LEVELS: usize = 3;
+
+/// Maximum number of waiters per priority level that are served in order.
+///
+/// Further waiters are not lost, but only get a place in the queue once earlier ones are served.
+const QUEUE_WINDOW: u32 = u32::BITS;
+
+/// Bus contention statistics collected by a [`BusArbiter`].
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct BusStats {
+    /// Number of times the bus was acquired.
+    pub acquisitions: u32,
+    /// Number of acquisitions that had to wait for another device.
+    pub contended: u32,
+    /// Number of acquisitions that timed out.
+    pub timeouts: u32,
+    /// Longest time a device had to wait for the bus.
+    #[cfg(feature = "time")]
+    pub max_wait: Duration,
+}
+
+/// Error returned when the bus could not be acquired before the timeout expired.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LockTimeout;
+
+/// Ticket lock for a single priority level.
+///
+/// Waiters take a ticket and are served in ticket order. Bit `n` of `abandoned` is set when the
+/// waiter holding ticket `serving + n` stopped waiting, so it can be skipped.
+struct Level {
+    next_ticket: u32,
+    serving: u32,
+    abandoned: u32,
+    wakers: MultiWakerRegistration<4>,
+}
+
+impl Level {
+    const fn new() -> Self {
+        Self {
+            next_ticket: 0,
+            serving: 0,
+            abandoned: 0,
+            wakers: MultiWakerRegistration::new(),
+        }
+    }
+
+    fn has_waiters(&self) -> bool {
+        self.next_ticket != self.serving
+    }
+
+    fn take_ticket(&mut self) -> Option<u32> {
+        if self.next_ticket.wrapping_sub(self.serving) < QUEUE_WINDOW {
+            let ticket = self.next_ticket;
+            self.next_ticket = self.next_ticket.wrapping_add(1);
+            Some(ticket)
+        } else {
+            None
+        }
+    }
+
+    fn advance(&mut self) {
+        loop {
+            self.serving = self.serving.wrapping_add(1);
+            self.abandoned >>= 1;
+            if self.abandoned & 1 == 0 || !self.has_waiters() {
+                break;
+            }
+        }
+    }
+
+    fn abandon(&mut self, ticket: u32) {
+        if ticket == self.serving {
+            self.advance();
+        } else {
+            self.abandoned |= 1 << ticket.wrapping_sub(self.serving);
+        }
+    }
+}
+
+struct State {
+    locked: bool,
+    levels: [Level; PRIORITY_LEVELS],
+    stats: BusStats,
+}
+
+impl State {
+    fn wake_all(&mut self) {
+        for level in &mut self.levels {
+            level.wakers.wake();
+        }
+    }
+}
+
+/// Shared bus with prioritized, fair arbitration between devices.
+///
+/// See the [module documentation](self) for details.
+pub struct BusArbiter<M: RawMutex, BUS> {
+    state: BlockingMutex<M, RefCell<State>>,
+    bus: UnsafeCell<BUS>,
+}
+
+unsafe impl<M: RawMutex + Send, BUS: Send> Send for BusArbiter<M, BUS> {}
+unsafe impl<M: RawMutex + Sync, BUS: Send> Sync for BusArbiter<M, BUS> {}
+
+impl<M: RawMutex, BUS> BusArbiter<M, BUS> {
+    /// Create a new `BusArbiter` owning `bus`.
+    pub const fn new(bus: BUS) -> Self {
+        Self {
+            state: BlockingMutex::new(RefCell::new(State {
+                locked: false,
+                levels: [Level::new(), Level::new(), Level::new()],
+                stats: BusStats {
+                    acquisitions: 0,
+                    contended: 0,
+                    timeouts: 0,
+                    #[cfg(feature = "time")]
+                    max_wait: Duration::from_ticks(0),
+                },
+            })),
+            bus: UnsafeCell::new(bus),
+        }
+    }
+
+    /// Acquire the bus with the given priority, waiting as long as needed.
+    ///
+    /// This is cancel-safe: dropping the returned future gives up the place in the queue.
+    pub async fn lock(&self, priority: Priority) -> BusGuard<'_, M, BUS> {
+        let mut waiter = Waiter {
+            arbiter: self,
+            priority,
+            ticket: None,
+            #[cfg(feature = "time")]
+            since: None,
+        };
+
+        poll_fn(|cx| {
+            let acquired = self.state.lock(|s| {
+                let mut s = s.borrow_mut();
+                if waiter.try_acquire(&mut s) {
+                    true
+                } else {
+                    s.levels[priority as usize].wakers.register(cx.waker());
+                    false
+                }
+            });
+
+            if acquired { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await;
+
+        BusGuard { arbiter: self }
+    }
+
+    /// Acquire the bus with the given priority, giving up after `timeout`.
+    #[cfg(feature = "time")]
+    pub async fn lock_with_timeout(
+        &self,
+        priority: Priority,
+        timeout: Duration,
+    ) -> Result<BusGuard<'_, M, BUS>, LockTimeout> {
+        match embassy_time::with_timeout(timeout, self.lock(priority)).await {
+            Ok(guard) => Ok(guard),
+            Err(_) => {
+                self.state.lock(|s| {
+                    let mut s = s.borrow_mut();
+                    s.stats.timeouts = s.stats.timeouts.saturating_add(1);
+                });
+                Err(LockTimeout)
+            }
+        }
+    }
+
+    /// Try to acquire the bus immediately.
+    ///
+    /// This only succeeds if the bus is free and no other device is waiting for it, regardless
+    /// of their priority.
+    pub fn try_lock(&self) -> Option<BusGuard<'_, M, BUS>> {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            if s.locked || s.levels.iter().any(Level::has_waiters) {
+                None
+            } else {
+                s.locked = true;
+                s.stats.acquisitions = s.stats.acquisitions.saturating_add(1);
+                Some(BusGuard { arbiter: self })
+            }
+        })
+    }
+
+    /// Get the bus contention statistics collected so far.
+    pub fn stats(&self) -> BusStats {
+        self.state.lock(|s| s.borrow().stats)
+    }
+
+    /// Reset the bus contention statistics.
+    pub fn reset_stats(&self) {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.stats = BusStats {
+                acquisitions: 0,
+                contended: 0,
+                timeouts: 0,
+                #[cfg(feature = "time")]
+                max_wait: Duration::from_ticks(0),
+            };
+        })
+    }
+
+    /// Consume the arbiter, returning the bus.
+    pub fn into_inner(self) -> BUS {
+        self.bus.into_inner()
+    }
+
+    /// Get a mutable reference to the bus.
+    ///
+    /// No locking is needed, since this takes the arbiter by mutable reference.
+    pub fn get_mut(&mut self) -> &mut BUS {
+        self.bus.get_mut()
+    }
+}
+
+/// A device waiting for the bus. Gives up its place in the queue when dropped.
+struct Waiter<'a, M: RawMutex, BUS> {
+    arbiter: &'a BusArbiter<M, BUS>,
+    priority: Priority,
+    ticket: Option<u32>,
+    #[cfg(feature = "time")]
+    since: Option<Instant>,
+}
+
+impl<M: RawMutex, BUS> Waiter<'_, M, BUS> {
+    fn try_acquire(&mut self, s: &mut State) -> bool {
+        let p = self.priority as usize;
+
+        if self.ticket.is_none() {
+            // Don't queue up if we can take the bus right away.
+            if !s.locked && !s.levels.iter().any(Level::has_waiters) {
+                s.locked = true;
+                s.stats.acquisitions = s.stats.acquisitions.saturating_add(1);
+                return true;
+            }
+            self.ticket = s.levels[p].take_ticket();
+        }
+
+        let acquired = match self.ticket {
+            Some(ticket) => {
+                !s.locked && s.levels[p].serving == ticket && !s.levels[p + 1..].iter().any(Level::has_waiters)
+            }
+            None => false,
+        };
+
+        if !acquired {
+            #[cfg(feature = "time")]
+            if self.since.is_none() {
+                self.since = Some(Instant::now());
+            }
+            return false;
+        }
+
+        s.locked = true;
+        s.levels[p].advance();
+        self.ticket = None;
+
+        s.stats.acquisitions = s.stats.acquisitions.saturating_add(1);
+        s.stats.contended = s.stats.contended.saturating_add(1);
+        #[cfg(feature = "time")]
+        if let Some(since) = self.since {
+            s.stats.max_wait = s.stats.max_wait.max(Instant::now().saturating_duration_since(since));
+        }
+        true
+    }
+}
+
+impl<M: RawMutex, BUS> Drop for Waiter<'_, M, BUS> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            self.arbiter.state.lock(|s| {
+                let mut s = s.borrow_mut();
+                s.levels[self.priority as usize].abandon(ticket);
+                s.wake_all();
+            })
+        }
+    }
+}
+
+/// Exclusive access to the bus of a [`BusArbiter`].
+///
+/// The bus is released to the next waiter when this guard is dropped.
+#[clippy::has_significant_drop]
+#[must_use = "if unused the bus will immediately be released"]
+pub struct BusGuard<'a, M: RawMutex, BUS> {
+    arbiter: &'a BusArbiter<M, BUS>,
+}
+
+impl<M: RawMutex, BUS> Drop for BusGuard<'_, M, BUS> {
+    fn drop(&mut self) {
+        self.arbiter.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.locked = false;
+            s.wake_all();
+        })
+    }
+}
+
+impl<M: RawMutex, BUS> Deref for BusGuard<'_, M, BUS> {
+    type Target = BUS;
+    fn deref(&self) -> &Self::Target {
+        // Safety: the BusGuard represents exclusive access to the bus.
+        unsafe { &*(self.arbiter.bus.get() as *const BUS) }
+    }
+}
+
+impl<M: RawMutex, BUS> DerefMut for BusGuard<'_, M, BUS> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: the BusGuard represents exclusive access to the bus.
+        unsafe { &mut *(self.arbiter.bus.get()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use futures_test::task::noop_context;
+
+    use super::*;
+
+    fn poll<F: core::future::Future>(fut: core::pin::Pin<&mut F>) -> Option<F::Output> {
+        match fut.poll(&mut noop_context()) {
+            Poll::Ready(v) => Some(v),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn higher_priority_preempts_waiting_devices() {
+        let bus = BusArbiter::<NoopRawMutex, u32>::new(0);
+
+        let guard = bus.try_lock().unwrap();
+
+        let mut low = pin!(bus.lock(Priority::Low));
+        let mut high = pin!(bus.lock(Priority::High));
+        assert!(poll(low.as_mut()).is_none());
+        assert!(poll(high.as_mut()).is_none());
+
+        drop(guard);
+
+        // Low started waiting first, but high is served first.
+        assert!(poll(low.as_mut()).is_none());
+        let guard = poll(high.as_mut()).unwrap();
+        assert!(poll(low.as_mut()).is_none());
+
+        drop(guard);
+        assert!(poll(low.as_mut()).is_some());
+
+        let stats = bus.stats();
+        assert_eq!(stats.acquisitions, 3);
+        assert_eq!(stats.contended, 2);
+    }
+
+    #[test]
+    fn same_priority_is_served_in_order() {
+        let bus = BusArbiter::<NoopRawMutex, u32>::new(0);
+
+        let guard = bus.try_lock().unwrap();
+
+        let mut first = pin!(bus.lock(Priority::Normal));
+        let mut second = pin!(bus.lock(Priority::Normal));
+        let mut third = pin!(bus.lock(Priority::Normal));
+        assert!(poll(first.as_mut()).is_none());
+        assert!(poll(second.as_mut()).is_none());
+        assert!(poll(third.as_mut()).is_none());
+
+        drop(guard);
+
+        assert!(poll(third.as_mut()).is_none());
+        assert!(poll(second.as_mut()).is_none());
+        let mut guard = poll(first.as_mut()).unwrap();
+        *guard += 1;
+        drop(guard);
+
+        assert!(poll(third.as_mut()).is_none());
+        let mut guard = poll(second.as_mut()).unwrap();
+        *guard += 1;
+        drop(guard);
+
+        let mut guard = poll(third.as_mut()).unwrap();
+        *guard += 1;
+        drop(guard);
+
+        assert_eq!(*bus.try_lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn cancelled_waiter_is_skipped() {
+        let bus = BusArbiter::<NoopRawMutex, u32>::new(0);
+
+        let guard = bus.try_lock().unwrap();
+
+        let mut first = pin!(bus.lock(Priority::Normal));
+        assert!(poll(first.as_mut()).is_none());
+        {
+            let mut cancelled = pin!(bus.lock(Priority::Normal));
+            assert!(poll(cancelled.as_mut()).is_none());
+        }
+        let mut third = pin!(bus.lock(Priority::Normal));
+        assert!(poll(third.as_mut()).is_none());
+        {
+            let mut cancelled = pin!(bus.lock(Priority::High));
+            assert!(poll(cancelled.as_mut()).is_none());
+        }
+
+        drop(guard);
+
+        drop(poll(first.as_mut()).unwrap());
+        drop(poll(third.as_mut()).unwrap());
+
+        // Nobody is waiting anymore.
+        assert!(bus.try_lock().is_some());
+    }
+
+    #[cfg(feature = "time")]
+    struct Bus;
+
+    #[cfg(feature = "time")]
+    impl embedded_hal_1::i2c::ErrorType for Bus {
+        type Error = core::convert::Infallible;
+    }
+
+    #[cfg(feature = "time")]
+    impl embedded_hal_async::i2c::I2c for Bus {
+        async fn transaction(
+            &mut self,
+            _address: u8,
+            _operations: &mut [embedded_hal_1::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "time")]
+    impl embedded_hal_1::spi::ErrorType for Bus {
+        type Error = core::convert::Infallible;
+    }
+
+    #[cfg(feature = "time")]
+    impl embedded_hal_async::spi::SpiBus for Bus {
+        async fn read(&mut self, _words: &mut [u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write(&mut self, _words: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn transfer(&mut self, _read: &mut [u8], _write: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn transfer_in_place(&mut self, _words: &mut [u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "time")]
+    struct Pin;
+
+    #[cfg(feature = "time")]
+    impl embedded_hal_1::digital::ErrorType for Pin {
+        type Error = core::convert::Infallible;
+    }
+
+    #[cfg(feature = "time")]
+    impl embedded_hal_1::digital::OutputPin for Pin {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    // The only test using the time driver, the timeouts would race with other tests advancing it.
+    #[cfg(feature = "time")]
+    #[test]
+    fn timeouts() {
+        use embassy_time::MockDriver;
+        use embedded_hal_async::i2c::I2c as _;
+        use embedded_hal_async::spi::SpiDevice as _;
+
+        use crate::shared_bus::asynch::i2c::PrioritizedI2cDevice;
+        use crate::shared_bus::asynch::spi::PrioritizedSpiDevice;
+        use crate::shared_bus::{I2cDeviceError, SpiDeviceError};
+
+        let bus = BusArbiter::<NoopRawMutex, Bus>::new(Bus);
+        let timeout = Duration::from_millis(10);
+
+        // Another priority holds the bus.
+        let mut high = pin!(bus.lock(Priority::High));
+        let guard = poll(high.as_mut()).unwrap();
+
+        let mut timed = pin!(bus.lock_with_timeout(Priority::Low, timeout));
+        let mut waiting = pin!(bus.lock(Priority::Low));
+        assert!(poll(timed.as_mut()).is_none());
+        assert!(poll(waiting.as_mut()).is_none());
+
+        MockDriver::get().advance(timeout);
+        assert!(matches!(poll(timed.as_mut()), Some(Err(LockTimeout))));
+        assert_eq!(bus.stats().timeouts, 1);
+
+        // The abandoned ticket does not block the next waiter.
+        drop(guard);
+        drop(poll(waiting.as_mut()).unwrap());
+
+        // Acquired before the timeout.
+        let guard = bus.try_lock().unwrap();
+        let mut timed = pin!(bus.lock_with_timeout(Priority::Low, timeout));
+        assert!(poll(timed.as_mut()).is_none());
+        drop(guard);
+        drop(poll(timed.as_mut()).unwrap().unwrap());
+        assert_eq!(bus.stats().timeouts, 1);
+
+        // Devices report the timeout.
+        let guard = bus.try_lock().unwrap();
+        let mut i2c = PrioritizedI2cDevice::new(&bus, Priority::Normal);
+        i2c.set_timeout(Some(timeout));
+        let mut spi = PrioritizedSpiDevice::new(&bus, Pin, Priority::Normal);
+        spi.set_timeout(Some(timeout));
+        {
+            let mut i2c_write = pin!(i2c.write(0x10, &[1]));
+            let mut spi_write = pin!(spi.write(&[1]));
+            assert!(poll(i2c_write.as_mut()).is_none());
+            assert!(poll(spi_write.as_mut()).is_none());
+
+            MockDriver::get().advance(timeout);
+            assert!(matches!(poll(i2c_write.as_mut()), Some(Err(I2cDeviceError::Timeout))));
+            assert!(matches!(poll(spi_write.as_mut()), Some(Err(SpiDeviceError::Timeout))));
+        }
+        assert_eq!(bus.stats().timeouts, 3);
+
+        drop(guard);
+        assert!(matches!(poll(pin!(i2c.write(0x10, &[1]))), Some(Ok(()))));
+        assert!(matches!(poll(pin!(spi.write(&[1]))), Some(Ok(()))));
+    }
+}
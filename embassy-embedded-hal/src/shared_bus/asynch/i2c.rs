@@ -24,10 +24,13 @@
 
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::mutex::Mutex;
+#[cfg(feature = "time")]
+use embassy_time::Duration;
 use embedded_hal_async::i2c;
 
 use crate::SetConfig;
 use crate::shared_bus::I2cDeviceError;
+use crate::shared_bus::asynch::arbiter::{BusArbiter, BusGuard, Priority};
 
 /// I2C device on a shared bus.
 pub struct I2cDevice<'a, M: RawMutex, BUS> {
@@ -182,3 +185,110 @@ where
         Ok(())
     }
 }
+
+/// I2C device on a shared bus managed by a [`BusArbiter`], acquiring the bus with a fixed [`Priority`].
+///
+/// With the `time` feature enabled, a timeout can be set after which operations fail with
+/// [`I2cDeviceError::Timeout`] if the bus could not be acquired.
+pub struct PrioritizedI2cDevice<'a, M: RawMutex, BUS> {
+    bus: &'a BusArbiter<M, BUS>,
+    priority: Priority,
+    #[cfg(feature = "time")]
+    timeout: Option<Duration>,
+}
+
+impl<'a, M: RawMutex, BUS> PrioritizedI2cDevice<'a, M, BUS> {
+    /// Create a new `PrioritizedI2cDevice`.
+    pub fn new(bus: &'a BusArbiter<M, BUS>, priority: Priority) -> Self {
+        Self {
+            bus,
+            priority,
+            #[cfg(feature = "time")]
+            timeout: None,
+        }
+    }
+
+    /// Change the device's priority at runtime
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Set the maximum time to wait for the bus, or `None` to wait forever.
+    #[cfg(feature = "time")]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    async fn lock<E>(&self) -> Result<BusGuard<'a, M, BUS>, I2cDeviceError<E>> {
+        #[cfg(feature = "time")]
+        if let Some(timeout) = self.timeout {
+            return self
+                .bus
+                .lock_with_timeout(self.priority, timeout)
+                .await
+                .map_err(|_| I2cDeviceError::Timeout);
+        }
+        Ok(self.bus.lock(self.priority).await)
+    }
+}
+
+impl<'a, M: RawMutex, BUS> Clone for PrioritizedI2cDevice<'a, M, BUS> {
+    fn clone(&self) -> Self {
+        Self {
+            bus: self.bus,
+            priority: self.priority,
+            #[cfg(feature = "time")]
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<'a, M: RawMutex, BUS> i2c::ErrorType for PrioritizedI2cDevice<'a, M, BUS>
+where
+    BUS: i2c::ErrorType,
+{
+    type Error = I2cDeviceError<BUS::Error>;
+}
+
+impl<M, BUS> i2c::I2c for PrioritizedI2cDevice<'_, M, BUS>
+where
+    M: RawMutex,
+    BUS: i2c::I2c,
+{
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), I2cDeviceError<BUS::Error>> {
+        let mut bus = self.lock().await?;
+        bus.read(address, read).await.map_err(I2cDeviceError::I2c)?;
+        Ok(())
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), I2cDeviceError<BUS::Error>> {
+        let mut bus = self.lock().await?;
+        bus.write(address, write).await.map_err(I2cDeviceError::I2c)?;
+        Ok(())
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), I2cDeviceError<BUS::Error>> {
+        let mut bus = self.lock().await?;
+        bus.write_read(address, write, read)
+            .await
+            .map_err(I2cDeviceError::I2c)?;
+        Ok(())
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [embedded_hal_async::i2c::Operation<'_>],
+    ) -> Result<(), I2cDeviceError<BUS::Error>> {
+        let mut bus = self.lock().await?;
+        bus.transaction(address, operations)
+            .await
+            .map_err(I2cDeviceError::I2c)?;
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
 //! Asynchronous shared bus implementations for embedded-hal-async
+pub mod arbiter;
 pub mod i2c;
 pub mod spi;
@@ -28,12 +28,15 @@
 use embassy_hal_internal::drop::OnDrop;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::mutex::Mutex;
+#[cfg(feature = "time")]
+use embassy_time::Duration;
 use embedded_hal_1::digital::OutputPin;
 use embedded_hal_1::spi::Operation;
 use embedded_hal_async::spi;
 
 use crate::SetConfig;
 use crate::shared_bus::SpiDeviceError;
+use crate::shared_bus::asynch::arbiter::{BusArbiter, BusGuard, Priority};
 
 /// SPI device on a shared bus.
 pub struct SpiDevice<'a, M: RawMutex, BUS, CS> {
@@ -210,3 +213,118 @@ where
         Ok(())
     }
 }
+
+/// SPI device on a shared bus managed by a [`BusArbiter`], acquiring the bus with a fixed [`Priority`].
+///
+/// With the `time` feature enabled, a timeout can be set after which transactions fail with
+/// [`SpiDeviceError::Timeout`] if the bus could not be acquired.
+pub struct PrioritizedSpiDevice<'a, M: RawMutex, BUS, CS> {
+    bus: &'a BusArbiter<M, BUS>,
+    cs: CS,
+    priority: Priority,
+    #[cfg(feature = "time")]
+    timeout: Option<Duration>,
+}
+
+impl<'a, M: RawMutex, BUS, CS> PrioritizedSpiDevice<'a, M, BUS, CS> {
+    /// Create a new `PrioritizedSpiDevice`.
+    pub fn new(bus: &'a BusArbiter<M, BUS>, cs: CS, priority: Priority) -> Self {
+        Self {
+            bus,
+            cs,
+            priority,
+            #[cfg(feature = "time")]
+            timeout: None,
+        }
+    }
+
+    /// Change the device's priority at runtime
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Set the maximum time to wait for the bus, or `None` to wait forever.
+    #[cfg(feature = "time")]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    async fn lock<E, CsE>(&self) -> Result<BusGuard<'a, M, BUS>, SpiDeviceError<E, CsE>> {
+        #[cfg(feature = "time")]
+        if let Some(timeout) = self.timeout {
+            return self
+                .bus
+                .lock_with_timeout(self.priority, timeout)
+                .await
+                .map_err(|_| SpiDeviceError::Timeout);
+        }
+        Ok(self.bus.lock(self.priority).await)
+    }
+}
+
+impl<'a, M, BUS, CS> spi::ErrorType for PrioritizedSpiDevice<'a, M, BUS, CS>
+where
+    BUS: spi::ErrorType,
+    CS: OutputPin,
+    M: RawMutex,
+{
+    type Error = SpiDeviceError<BUS::Error, CS::Error>;
+}
+
+impl<M, BUS, CS, Word> spi::SpiDevice<Word> for PrioritizedSpiDevice<'_, M, BUS, CS>
+where
+    M: RawMutex,
+    BUS: spi::SpiBus<Word>,
+    CS: OutputPin,
+    Word: Copy + 'static,
+{
+    async fn transaction(&mut self, operations: &mut [spi::Operation<'_, Word>]) -> Result<(), Self::Error> {
+        if cfg!(not(feature = "time")) && operations.iter().any(|op| matches!(op, Operation::DelayNs(_))) {
+            return Err(SpiDeviceError::DelayNotSupported);
+        }
+
+        let mut bus = self.lock().await?;
+        self.cs.set_low().map_err(SpiDeviceError::Cs)?;
+
+        let cs_drop = OnDrop::new(|| {
+            // Please see comment in SpiDevice for an explanation of this drop handler.
+            let _ = self.cs.set_high();
+        });
+
+        let op_res = 'ops: {
+            for op in operations {
+                let res = match op {
+                    Operation::Read(buf) => bus.read(buf).await,
+                    Operation::Write(buf) => bus.write(buf).await,
+                    Operation::Transfer(read, write) => bus.transfer(read, write).await,
+                    Operation::TransferInPlace(buf) => bus.transfer_in_place(buf).await,
+                    #[cfg(not(feature = "time"))]
+                    Operation::DelayNs(_) => unreachable!(),
+                    #[cfg(feature = "time")]
+                    Operation::DelayNs(ns) => match bus.flush().await {
+                        Err(e) => Err(e),
+                        Ok(()) => {
+                            embassy_time::Timer::after_nanos(*ns as _).await;
+                            Ok(())
+                        }
+                    },
+                };
+                if let Err(e) = res {
+                    break 'ops Err(e);
+                }
+            }
+            Ok(())
+        };
+
+        // On failure, it's important to still flush and deassert CS.
+        let flush_res = bus.flush().await;
+        cs_drop.defuse();
+        let cs_res = self.cs.set_high();
+
+        op_res.map_err(SpiDeviceError::Spi)?;
+        flush_res.map_err(SpiDeviceError::Spi)?;
+        cs_res.map_err(SpiDeviceError::Cs)?;
+
+        Ok(())
+    }
+}
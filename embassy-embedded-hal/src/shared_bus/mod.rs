@@ -9,11 +9,14 @@ pub mod blocking;
 /// Error returned by I2C device implementations in this crate.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum I2cDeviceError<BUS> {
     /// An operation on the inner I2C bus failed.
     I2c(BUS),
     /// Configuration of the inner I2C bus failed.
     Config,
+    /// The bus could not be acquired before the timeout expired.
+    Timeout,
 }
 
 impl<BUS> i2c::Error for I2cDeviceError<BUS>
@@ -24,6 +27,7 @@ where
         match self {
             Self::I2c(e) => e.kind(),
             Self::Config => i2c::ErrorKind::Other,
+            Self::Timeout => i2c::ErrorKind::Other,
         }
     }
 }
@@ -41,6 +45,8 @@ pub enum SpiDeviceError<BUS, CS> {
     DelayNotSupported,
     /// The SPI bus could not be configured.
     Config,
+    /// The bus could not be acquired before the timeout expired.
+    Timeout,
 }
 
 impl<BUS, CS> spi::Error for SpiDeviceError<BUS, CS>
@@ -54,6 +60,7 @@ where
             Self::Cs(_) => spi::ErrorKind::Other,
             Self::DelayNotSupported => spi::ErrorKind::Other,
             Self::Config => spi::ErrorKind::Other,
+            Self::Timeout => spi::ErrorKind::Other,
         }
     }
 }
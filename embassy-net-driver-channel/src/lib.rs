@@ -11,7 +11,7 @@ use core::ops::{Deref, DerefMut};
 use core::task::{Context, Poll};
 
 pub use embassy_net_driver as driver;
-use embassy_net_driver::{Capabilities, LinkState};
+use embassy_net_driver::{Capabilities, LinkState, Statistics};
 use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::waitqueue::WakerRegistration;
@@ -47,6 +47,7 @@ struct Shared {
     link_state: LinkState,
     waker: WakerRegistration,
     hardware_address: driver::HardwareAddress,
+    statistics: Statistics,
 }
 
 /// Channel runner.
@@ -189,6 +190,30 @@ impl<'d, const MTU: usize> Runner<'d, MTU> {
         });
     }
 
+    /// Record an inbound packet dropped by the driver, for example because the channel was full.
+    pub fn record_rx_dropped(&self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.statistics.rx_dropped = s.statistics.rx_dropped.wrapping_add(1);
+        });
+    }
+
+    /// Record an inbound packet received with errors, such as a bad CRC.
+    pub fn record_rx_error(&self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.statistics.rx_errors = s.statistics.rx_errors.wrapping_add(1);
+        });
+    }
+
+    /// Record an outbound packet the driver failed to transmit.
+    pub fn record_tx_error(&self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.statistics.tx_errors = s.statistics.tx_errors.wrapping_add(1);
+        });
+    }
+
     /// Wait until there is space for more inbound packets and return a slot.
     pub async fn rx_buf(&mut self) -> RxSlot<'_, MTU> {
         self.rx_chan.send().await.into()
@@ -244,6 +269,30 @@ impl<'d> StateRunner<'d> {
             s.waker.wake();
         });
     }
+
+    /// Record an inbound packet dropped by the driver, for example because the channel was full.
+    pub fn record_rx_dropped(&self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.statistics.rx_dropped = s.statistics.rx_dropped.wrapping_add(1);
+        });
+    }
+
+    /// Record an inbound packet received with errors, such as a bad CRC.
+    pub fn record_rx_error(&self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.statistics.rx_errors = s.statistics.rx_errors.wrapping_add(1);
+        });
+    }
+
+    /// Record an outbound packet the driver failed to transmit.
+    pub fn record_tx_error(&self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.statistics.tx_errors = s.statistics.tx_errors.wrapping_add(1);
+        });
+    }
 }
 
 impl<'d, const MTU: usize> RxRunner<'d, MTU> {
@@ -311,6 +360,7 @@ pub fn new<'d, const MTU: usize, const N_RX: usize, const N_TX: usize>(
             link_state: LinkState::Down,
             hardware_address,
             waker: WakerRegistration::new(),
+            statistics: Statistics::default(),
         })),
     });
 
@@ -398,6 +448,10 @@ impl<'d, const MTU: usize> embassy_net_driver::Driver for Device<'d, MTU> {
             s.link_state
         })
     }
+
+    fn statistics(&self) -> Statistics {
+        self.shared.lock(|s| s.borrow().statistics)
+    }
 }
 
 /// A rx token.
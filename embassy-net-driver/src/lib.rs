@@ -81,6 +81,16 @@ pub trait Driver {
     /// what kind of packet the sent/received bytes are, and determines some behaviors of
     /// the interface. For example, ARP/NDISC address resolution is only done for Ethernet mediums.
     fn hardware_address(&self) -> HardwareAddress;
+
+    /// Get the statistics of the device.
+    ///
+    /// These are the counters only the driver knows about, such as the packets it dropped. The
+    /// stack counts the packets and bytes it exchanges with the driver itself.
+    ///
+    /// The default implementation returns all zeros, for drivers that don't keep statistics.
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
 }
 
 impl<T: ?Sized + Driver> Driver for &mut T {
@@ -108,6 +118,9 @@ impl<T: ?Sized + Driver> Driver for &mut T {
     fn hardware_address(&self) -> HardwareAddress {
         T::hardware_address(self)
     }
+    fn statistics(&self) -> Statistics {
+        T::statistics(self)
+    }
 }
 
 /// A token to receive a single network packet.
@@ -209,6 +222,21 @@ impl Default for Checksum {
     }
 }
 
+/// Statistics of a network device, see [`Driver::statistics`].
+///
+/// The counters start at zero and wrap around on overflow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Statistics {
+    /// Received packets dropped by the device, for example because its receive buffers were full.
+    pub rx_dropped: u32,
+    /// Packets received with errors, such as a bad CRC or an invalid length.
+    pub rx_errors: u32,
+    /// Packets the device failed to transmit.
+    pub tx_errors: u32,
+}
+
 /// The link state of a network device.
 #[derive(PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
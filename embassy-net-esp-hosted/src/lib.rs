@@ -287,7 +287,10 @@ where
                     buf[..payload.len()].copy_from_slice(payload);
                     buf.rx_done(payload.len())
                 }
-                None => warn!("failed to push rxd packet to the channel."),
+                None => {
+                    warn!("failed to push rxd packet to the channel.");
+                    self.ch.record_rx_dropped();
+                }
             },
             // serial
             2 => {
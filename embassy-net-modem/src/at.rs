@@ -0,0 +1,365 @@
+//! Async AT command client.
+//!
+//! The [`Runner`] owns the serial port (or a [`cmux`](crate::cmux) channel) and reads the lines sent by the
+//! modem. Lines answering the command being executed are collected into its response, up to the final result
+//! code. The other lines are unsolicited result codes (URCs), which are queued for
+//! [`Client::receive_urc`].
+//!
+//! Commands are executed one at a time with [`Client::command`]. The client is `Copy`, so it can be shared
+//! by several tasks.
+//!
+//! Responses and URCs are returned as raw bytes, and can be parsed with a crate such as
+//! [`at-commands`](https://crates.io/crates/at-commands).
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, with_timeout};
+use embedded_io_async::{BufRead, Write};
+use heapless::Vec;
+
+/// Error returned by [`Client::command`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The modem did not answer with a final result code in time.
+    Timeout,
+    /// The command or its response does not fit in the buffers.
+    BufferTooSmall,
+    /// The modem answered `ERROR`.
+    Error,
+    /// The modem answered `+CME ERROR: <n>`, in numeric mode (`AT+CMEE=1`).
+    CmeError(u16),
+    /// The modem answered `+CMS ERROR: <n>`.
+    CmsError(u16),
+    /// The modem answered another final result code: `NO CARRIER`, `BUSY`, `NO ANSWER` or `NO DIALTONE`.
+    Failed,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "Timeout"),
+            Self::BufferTooSmall => write!(f, "Buffer too small"),
+            Self::Error => write!(f, "ERROR"),
+            Self::CmeError(n) => write!(f, "+CME ERROR: {}", n),
+            Self::CmsError(n) => write!(f, "+CMS ERROR: {}", n),
+            Self::Failed => write!(f, "Failed"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Error returned by [`Runner::run`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RunError<E> {
+    /// Reading from the serial port failed.
+    Read(E),
+    /// Writing to the serial port failed.
+    Write(E),
+    /// Reading from the serial port got EOF.
+    Eof,
+}
+
+/// Configuration for [`Runner::run`].
+#[derive(Debug, Clone, Copy, Default)]
+#[non_exhaustive]
+pub struct Config<'a> {
+    /// Prefixes of the URCs the modem may send while a command is executing, for example `b"+CREG:"`.
+    ///
+    /// Without them, such URCs are taken as part of the command response. A line matching a prefix is still
+    /// part of the response if the command has the same name, for example `+CREG: 0,1` answering `AT+CREG?`.
+    ///
+    /// Lines received while no command is executing are always URCs.
+    pub urc_prefixes: &'a [&'a [u8]],
+}
+
+/// Internal state for the AT client.
+///
+/// `N` is the maximum length of a command, of a line and of a response. `URCS` is the number of URCs
+/// that can be queued.
+pub struct State<const N: usize, const URCS: usize> {
+    inner: BlockingMutex<NoopRawMutex, RefCell<Inner<N>>>,
+    lock: Mutex<NoopRawMutex, ()>,
+    request: Signal<NoopRawMutex, ()>,
+    done: Signal<NoopRawMutex, Result<(), Error>>,
+    urcs: Channel<NoopRawMutex, Vec<u8, N>, URCS>,
+}
+
+struct Inner<const N: usize> {
+    command: Vec<u8, N>,
+    response: Vec<u8, N>,
+    /// A command is executing.
+    pending: bool,
+    /// The response did not fit in `response`.
+    overflow: bool,
+}
+
+impl<const N: usize, const URCS: usize> State<N, URCS> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            inner: BlockingMutex::new(RefCell::new(Inner {
+                command: Vec::new(),
+                response: Vec::new(),
+                pending: false,
+                overflow: false,
+            })),
+            lock: Mutex::new(()),
+            request: Signal::new(),
+            done: Signal::new(),
+            urcs: Channel::new(),
+        }
+    }
+}
+
+impl<const N: usize, const URCS: usize> Default for State<N, URCS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create an AT client.
+///
+/// This returns two structs:
+/// - a `Client` to execute commands and receive URCs.
+/// - a `Runner`. You must call `.run()` on it in a background task.
+pub fn new<'d, const N: usize, const URCS: usize>(
+    state: &'d mut State<N, URCS>,
+) -> (Client<'d, N, URCS>, Runner<'d, N, URCS>) {
+    let state = &*state;
+    (Client { state }, Runner { state })
+}
+
+/// Handle to execute AT commands.
+#[derive(Clone, Copy)]
+pub struct Client<'d, const N: usize, const URCS: usize> {
+    state: &'d State<N, URCS>,
+}
+
+impl<'d, const N: usize, const URCS: usize> Client<'d, N, URCS> {
+    /// Execute an AT command, and wait for its final result code.
+    ///
+    /// `command` includes the `AT` prefix, for example `b"AT+CGMI"`. Trailing CR and LF are ignored, so
+    /// commands built with a terminator work too.
+    ///
+    /// On success, the response lines are copied into `response`, each one followed by CRLF and ending with
+    /// the final result code (`OK` or `CONNECT`), and the length of the response is returned.
+    ///
+    /// If the modem answers `CONNECT`, it switches to data mode: the runner stops reading, and must be
+    /// canceled to hand the serial port over to the data protocol, typically PPP.
+    pub async fn command(&self, command: &[u8], response: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let _guard = self.state.lock.lock().await;
+
+        let command = command.trim_ascii_end();
+        self.state.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            inner.command.clear();
+            inner
+                .command
+                .extend_from_slice(command)
+                .map_err(|_| Error::BufferTooSmall)?;
+            inner.response.clear();
+            inner.overflow = false;
+            inner.pending = true;
+            Ok(())
+        })?;
+        self.state.done.reset();
+        self.state.request.signal(());
+
+        let result = with_timeout(timeout, self.state.done.wait()).await;
+
+        self.state.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            // Lines received from now on, such as a late response, are not taken for this command's.
+            inner.pending = false;
+            match result {
+                Err(_) => {
+                    warn!("at: command timed out");
+                    Err(Error::Timeout)
+                }
+                Ok(Err(e)) => Err(e),
+                Ok(Ok(())) => {
+                    let len = inner.response.len();
+                    if inner.overflow || len > response.len() {
+                        return Err(Error::BufferTooSmall);
+                    }
+                    response[..len].copy_from_slice(&inner.response);
+                    Ok(len)
+                }
+            }
+        })
+    }
+
+    /// Wait for the next unsolicited result code.
+    ///
+    /// URCs received while the queue is full are dropped.
+    pub async fn receive_urc(&self) -> Vec<u8, N> {
+        self.state.urcs.receive().await
+    }
+}
+
+/// Background runner for the AT client.
+///
+/// You must call `.run()` in a background task for the client to operate.
+pub struct Runner<'d, const N: usize, const URCS: usize> {
+    state: &'d State<N, URCS>,
+}
+
+impl<'d, const N: usize, const URCS: usize> Runner<'d, N, URCS> {
+    /// You must call this in a background task for the client to operate.
+    ///
+    /// If reading/writing to the underlying serial port fails, the error is returned, and the command being
+    /// executed times out.
+    ///
+    /// Once a command is answered with `CONNECT`, this stops reading from the serial port and never returns.
+    /// Cancel it to hand the serial port over to the data protocol.
+    ///
+    /// It is allowed to cancel this function's future (i.e. drop it). After this function returns or is
+    /// canceled, you can call it again.
+    pub async fn run<RW: BufRead + Write>(
+        &mut self,
+        mut rw: RW,
+        config: &Config<'_>,
+    ) -> Result<Infallible, RunError<RW::Error>> {
+        let mut line = Vec::<u8, N>::new();
+        let mut overflow = false;
+        let mut command = Vec::<u8, N>::new();
+
+        loop {
+            match select(rw.fill_buf(), self.state.request.wait()).await {
+                Either::First(r) => {
+                    let data = r.map_err(RunError::Read)?;
+                    if data.is_empty() {
+                        return Err(RunError::Eof);
+                    }
+
+                    let mut n = 0;
+                    let mut connected = false;
+                    for &byte in data {
+                        n += 1;
+                        if byte == b'\r' || byte == b'\n' {
+                            if overflow {
+                                warn!("at: dropping line longer than the buffer");
+                            } else if !line.is_empty() {
+                                connected = self.handle_line(&line, config);
+                            }
+                            line.clear();
+                            overflow = false;
+                            if connected {
+                                break;
+                            }
+                        } else if line.push(byte).is_err() {
+                            overflow = true;
+                        }
+                    }
+                    rw.consume(n);
+
+                    if connected {
+                        debug!("at: data mode");
+                        return core::future::pending().await;
+                    }
+                }
+                Either::Second(()) => {
+                    let send = self.state.inner.lock(|inner| {
+                        let inner = inner.borrow();
+                        command.clone_from(&inner.command);
+                        // The command may have timed out before we got to send it.
+                        inner.pending
+                    });
+                    if send {
+                        rw.write_all(&command).await.map_err(RunError::Write)?;
+                        rw.write_all(b"\r").await.map_err(RunError::Write)?;
+                        rw.flush().await.map_err(RunError::Write)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a line received from the modem, returning whether it switched to data mode.
+    fn handle_line(&self, line: &[u8], config: &Config<'_>) -> bool {
+        self.state.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            if inner.pending {
+                // Echo of the command, if enabled with `ATE1`.
+                if line.eq_ignore_ascii_case(&inner.command) {
+                    return false;
+                }
+
+                if let Some(result) = final_result(line) {
+                    inner.push_response(line);
+                    inner.pending = false;
+                    let connected = result == Ok(true);
+                    self.state.done.signal(result.map(|_| ()));
+                    return connected;
+                }
+
+                let urc = config.urc_prefixes.iter().any(|p| line.starts_with(p)) && !answers(line, &inner.command);
+                if !urc {
+                    inner.push_response(line);
+                    return false;
+                }
+            }
+
+            // Lines are at most `N` long, this can't fail.
+            let urc = unwrap!(Vec::from_slice(line).ok());
+            if self.state.urcs.try_send(urc).is_err() {
+                warn!("at: URC queue full, dropping URC");
+            }
+            false
+        })
+    }
+}
+
+impl<const N: usize> Inner<N> {
+    fn push_response(&mut self, line: &[u8]) {
+        if self.response.extend_from_slice(line).is_err() || self.response.extend_from_slice(b"\r\n").is_err() {
+            self.overflow = true;
+        }
+    }
+}
+
+/// Parse a final result code, returning `Ok(true)` for `CONNECT`.
+fn final_result(line: &[u8]) -> Option<Result<bool, Error>> {
+    fn code(line: &[u8], prefix: &[u8]) -> Option<u16> {
+        let n = line.strip_prefix(prefix)?.trim_ascii();
+        core::str::from_utf8(n).ok()?.parse().ok()
+    }
+
+    match line {
+        b"OK" => Some(Ok(false)),
+        b"ERROR" => Some(Err(Error::Error)),
+        b"NO CARRIER" | b"BUSY" | b"NO ANSWER" | b"NO DIALTONE" => Some(Err(Error::Failed)),
+        // `CONNECT` may be followed by the connection speed.
+        _ if line.starts_with(b"CONNECT") => Some(Ok(true)),
+        _ if line.starts_with(b"+CME ERROR:") => {
+            Some(Err(code(line, b"+CME ERROR:").map_or(Error::Error, Error::CmeError)))
+        }
+        _ if line.starts_with(b"+CMS ERROR:") => {
+            Some(Err(code(line, b"+CMS ERROR:").map_or(Error::Error, Error::CmsError)))
+        }
+        _ => None,
+    }
+}
+
+/// Check whether `line` is an information response to `command`, such as `+CREG: 0,1` for `AT+CREG?`.
+fn answers(line: &[u8], command: &[u8]) -> bool {
+    let Some(name) = command.get(2..) else {
+        return false;
+    };
+    let len = name.iter().position(|&b| b == b'=' || b == b'?').unwrap_or(name.len());
+    let name = &name[..len];
+    !name.is_empty()
+        && line.len() > name.len()
+        && line[..name.len()].eq_ignore_ascii_case(name)
+        && line[name.len()] == b':'
+}
@@ -0,0 +1,573 @@
+//! GSM 07.10 (3GPP TS 27.010) multiplexer, in basic option.
+//!
+//! The [`Runner`] owns the serial port, which the modem must already have switched to multiplexing mode,
+//! usually with `AT+CMUX=0`. It opens the control channel (DLCI 0) and one data channel per [`Channel`],
+//! numbered from DLCI 1, then carries their data in UIH frames.
+//!
+//! Each [`Channel`] is a virtual serial port implementing [`embedded_io_async`]'s `Read`, `BufRead` and
+//! `Write`, so an [`at`](crate::at) client or `embassy-net-ppp` can run on it. Channels can be written
+//! before the runner has opened them; the data is sent once they are open.
+//!
+//! The runner answers the modem status (MSC), flow control and test commands of the modem on the control
+//! channel. Convergence layer 1 is used, without the optional modem status octet in data frames.
+
+use core::convert::Infallible;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::{Pipe, Reader, Writer};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, with_timeout};
+use embedded_io_async::{BufRead, ErrorType, Read, Write};
+use heapless::Vec;
+
+/// Maximum value of [`Config::max_frame_size`].
+pub const MAX_FRAME_SIZE: usize = 1509;
+
+const FLAG: u8 = 0xF9;
+const EA: u8 = 0x01;
+const CR: u8 = 0x02;
+const PF: u8 = 0x10;
+
+const SABM: u8 = 0x2F;
+const UA: u8 = 0x63;
+const DM: u8 = 0x0F;
+const DISC: u8 = 0x43;
+const UIH: u8 = 0xEF;
+const UI: u8 = 0x03;
+
+// Control channel message types, without the C/R and EA bits.
+const MSG_TEST: u8 = 0x20;
+const MSG_FCON: u8 = 0xA0;
+const MSG_FCOFF: u8 = 0x60;
+const MSG_MSC: u8 = 0xE0;
+const MSG_CLD: u8 = 0xC0;
+const MSG_NSC: u8 = 0x10;
+
+/// V.24 signals sent in the modem status commands: Ready To Communicate, Ready To Receive and Data Valid.
+const V24_SIGNALS: u8 = EA | 0x04 | 0x08 | 0x80;
+
+const RETRIES: usize = 3;
+
+/// Error returned by [`Runner::run`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RunError<E> {
+    /// Reading from the serial port failed.
+    Read(E),
+    /// Writing to the serial port failed.
+    Write(E),
+    /// Reading from the serial port got EOF.
+    Eof,
+    /// The modem did not acknowledge the opening of a channel.
+    Timeout,
+    /// The modem refused to open a channel.
+    Rejected,
+    /// The modem closed the multiplexer.
+    Closed,
+}
+
+/// Multiplexer configuration.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config {
+    /// Maximum length of the information field of the frames (N1).
+    ///
+    /// This must match the value configured in the modem with `AT+CMUX`, which defaults to 31 bytes.
+    pub max_frame_size: usize,
+    /// How long to wait for the modem to acknowledge the opening of a channel (T1), before retrying.
+    pub ack_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 31,
+            ack_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Internal state for the multiplexer.
+///
+/// `CHANNELS` is the number of data channels, `N` the size of the receive and transmit buffers of each one.
+pub struct State<const CHANNELS: usize, const N: usize> {
+    channels: [ChannelState<N>; CHANNELS],
+    tx_ready: Signal<NoopRawMutex, ()>,
+}
+
+struct ChannelState<const N: usize> {
+    rx: Pipe<NoopRawMutex, N>,
+    tx: Pipe<NoopRawMutex, N>,
+}
+
+impl<const CHANNELS: usize, const N: usize> State<CHANNELS, N> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            channels: [const {
+                ChannelState {
+                    rx: Pipe::new(),
+                    tx: Pipe::new(),
+                }
+            }; CHANNELS],
+            tx_ready: Signal::new(),
+        }
+    }
+}
+
+impl<const CHANNELS: usize, const N: usize> Default for State<CHANNELS, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a multiplexer.
+///
+/// This returns two things:
+/// - the `Channel`s, for DLCIs 1 to `CHANNELS`.
+/// - a `Runner`. You must call `.run()` on it in a background task.
+pub fn new<'d, const CHANNELS: usize, const N: usize>(
+    state: &'d mut State<CHANNELS, N>,
+    config: Config,
+) -> ([Channel<'d, N>; CHANNELS], Runner<'d, CHANNELS, N>) {
+    assert!(config.max_frame_size > 0 && config.max_frame_size <= MAX_FRAME_SIZE);
+    assert!(CHANNELS < 64);
+
+    let tx_ready = &state.tx_ready;
+    let mut channels = Vec::<_, CHANNELS>::new();
+    let mut ends = Vec::<_, CHANNELS>::new();
+    for ch in state.channels.iter_mut() {
+        let (rx_reader, rx_writer) = ch.rx.split();
+        let (tx_reader, tx_writer) = ch.tx.split();
+        let _ = channels.push(Channel {
+            rx: rx_reader,
+            tx: tx_writer,
+            tx_ready,
+        });
+        let _ = ends.push(ChannelEnd {
+            rx: rx_writer,
+            tx: tx_reader,
+        });
+    }
+
+    let runner = Runner {
+        ends: unwrap!(ends.into_array().ok()),
+        tx_ready,
+        tx_stopped: false,
+        config,
+    };
+    (unwrap!(channels.into_array().ok()), runner)
+}
+
+/// A multiplexer channel.
+///
+/// Reading and writing never fail: while the runner is not running, reads wait and writes are buffered.
+pub struct Channel<'d, const N: usize> {
+    rx: Reader<'d, NoopRawMutex, N>,
+    tx: Writer<'d, NoopRawMutex, N>,
+    tx_ready: &'d Signal<NoopRawMutex, ()>,
+}
+
+impl<'d, const N: usize> ErrorType for Channel<'d, N> {
+    type Error = Infallible;
+}
+
+impl<'d, const N: usize> Read for Channel<'d, N> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.rx.read(buf).await)
+    }
+}
+
+impl<'d, const N: usize> BufRead for Channel<'d, N> {
+    async fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        Ok(self.rx.fill_buf().await)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.rx.consume(amt)
+    }
+}
+
+impl<'d, const N: usize> Write for Channel<'d, N> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.tx.write(buf).await;
+        self.tx_ready.signal(());
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        // The runner sends the buffered data as soon as it can, there's no way to wait for it.
+        Ok(())
+    }
+}
+
+struct ChannelEnd<'d, const N: usize> {
+    rx: Writer<'d, NoopRawMutex, N>,
+    tx: Reader<'d, NoopRawMutex, N>,
+}
+
+/// Background runner for the multiplexer.
+///
+/// You must call `.run()` in a background task for the multiplexer to operate.
+pub struct Runner<'d, const CHANNELS: usize, const N: usize> {
+    ends: [ChannelEnd<'d, N>; CHANNELS],
+    tx_ready: &'d Signal<NoopRawMutex, ()>,
+    /// The modem asked us to stop sending data, with FCoff.
+    tx_stopped: bool,
+    config: Config,
+}
+
+impl<'d, const CHANNELS: usize, const N: usize> Runner<'d, CHANNELS, N> {
+    /// You must call this in a background task for the multiplexer to operate.
+    ///
+    /// This opens all channels, then carries their data until reading/writing to the underlying serial port
+    /// fails or the modem closes the multiplexer, returning the error.
+    ///
+    /// Data received on a channel is buffered until it is read. When the buffer is full, this stops reading
+    /// from the serial port, so all channels must be read continuously.
+    ///
+    /// It is allowed to cancel this function's future (i.e. drop it), which drops the frame being received.
+    /// The modem stays in multiplexing mode, use [`close`](Self::close) to leave it. After this function
+    /// returns or is canceled, you can call it again.
+    pub async fn run<RW: Read + Write>(&mut self, mut rw: RW) -> Result<Infallible, RunError<RW::Error>> {
+        let mut parser = Parser::new();
+        let mut rx_buf = [0; 256];
+
+        for dlci in 0..=CHANNELS as u8 {
+            self.open(&mut rw, &mut parser, &mut rx_buf, dlci).await?;
+        }
+        for dlci in 1..=CHANNELS as u8 {
+            let msc = [MSG_MSC | CR | EA, (2 << 1) | EA, (dlci << 2) | CR | EA, V24_SIGNALS];
+            send_frame(&mut rw, 0, UIH, true, &msc).await?;
+        }
+        debug!("cmux: {} channels open", CHANNELS);
+
+        // Send the data written before the channels were open.
+        self.send_pending(&mut rw).await?;
+
+        loop {
+            match select(rw.read(&mut rx_buf), self.tx_ready.wait()).await {
+                Either::First(r) => {
+                    let n = r.map_err(RunError::Read)?;
+                    if n == 0 {
+                        return Err(RunError::Eof);
+                    }
+                    for &byte in &rx_buf[..n] {
+                        if let Some(frame) = parser.feed(byte) {
+                            self.handle_frame(&mut rw, frame).await?;
+                        }
+                    }
+                }
+                Either::Second(()) => self.send_pending(&mut rw).await?,
+            }
+        }
+    }
+
+    /// Close the multiplexer, switching the modem back to AT command mode on the serial port.
+    ///
+    /// Call this after [`run`](Self::run) returned or was canceled. This does not wait for the modem's
+    /// acknowledgement.
+    pub async fn close<W: Write>(&mut self, mut w: W) -> Result<(), W::Error> {
+        let cld = [MSG_CLD | CR | EA, EA];
+        match send_frame(&mut w, 0, UIH, true, &cld).await {
+            Err(RunError::Write(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open a channel with SABM, and wait for the modem's UA.
+    async fn open<RW: Read + Write>(
+        &mut self,
+        rw: &mut RW,
+        parser: &mut Parser,
+        rx_buf: &mut [u8],
+        dlci: u8,
+    ) -> Result<(), RunError<RW::Error>> {
+        let ack_timeout = self.config.ack_timeout;
+        for _ in 0..RETRIES {
+            send_frame(rw, dlci, SABM | PF, true, &[]).await?;
+
+            let wait_ua = async {
+                loop {
+                    let n = rw.read(rx_buf).await.map_err(RunError::Read)?;
+                    if n == 0 {
+                        return Err(RunError::Eof);
+                    }
+                    for &byte in &rx_buf[..n] {
+                        if let Some(frame) = parser.feed(byte) {
+                            match (frame.control, frame.dlci == dlci) {
+                                (UA, true) => return Ok(()),
+                                (DM, true) => return Err(RunError::Rejected),
+                                _ => self.handle_frame(rw, frame).await?,
+                            }
+                        }
+                    }
+                }
+            };
+            if let Ok(r) = with_timeout(ack_timeout, wait_ua).await {
+                return r;
+            }
+            warn!("cmux: no UA for DLCI {}, retrying", dlci);
+        }
+        Err(RunError::Timeout)
+    }
+
+    async fn handle_frame<W: Write>(&mut self, w: &mut W, frame: Frame<'_>) -> Result<(), RunError<W::Error>> {
+        match frame.control {
+            UIH | UI if frame.dlci == 0 => self.handle_control(w, frame.info).await?,
+            UIH | UI => match self.ends.get_mut(usize::from(frame.dlci) - 1) {
+                Some(end) => {
+                    let Ok(()) = end.rx.write_all(frame.info).await;
+                }
+                None => warn!("cmux: dropping data for unknown DLCI {}", frame.dlci),
+            },
+            SABM => {
+                // The channels are opened by us, but there's no harm in the modem doing it too.
+                let control = if usize::from(frame.dlci) <= CHANNELS { UA } else { DM };
+                send_frame(w, frame.dlci, control | PF, false, &[]).await?;
+            }
+            DISC => {
+                send_frame(w, frame.dlci, UA | PF, false, &[]).await?;
+                if frame.dlci == 0 {
+                    return Err(RunError::Closed);
+                }
+                warn!("cmux: modem closed DLCI {}", frame.dlci);
+            }
+            // Late answers to our SABM.
+            UA | DM => {}
+            _ => debug!("cmux: ignoring frame with control {:02x}", frame.control),
+        }
+        Ok(())
+    }
+
+    /// Handle a control channel message, answering the modem's commands.
+    async fn handle_control<W: Write>(&mut self, w: &mut W, info: &[u8]) -> Result<(), RunError<W::Error>> {
+        let [kind, len, value @ ..] = info else {
+            return Ok(());
+        };
+        // Our messages are short, so a two octet length is never needed.
+        if kind & CR == 0 || len & EA == 0 {
+            return Ok(());
+        }
+        let value = &value[..usize::from(len >> 1).min(value.len())];
+
+        let mut resp = Vec::<u8, 8>::new();
+        match kind & !(CR | EA) {
+            MSG_MSC | MSG_TEST | MSG_FCON | MSG_FCOFF | MSG_CLD => {
+                let value = &value[..value.len().min(6)];
+                let _ = resp.push(kind & !CR);
+                let _ = resp.push(((value.len() as u8) << 1) | EA);
+                let _ = resp.extend_from_slice(value);
+            }
+            _ => {
+                debug!("cmux: unsupported control message {:02x}", kind);
+                let _ = resp.extend_from_slice(&[MSG_NSC | EA, (1 << 1) | EA, *kind]);
+            }
+        }
+        send_frame(w, 0, UIH, true, &resp).await?;
+
+        match kind & !(CR | EA) {
+            MSG_FCON => {
+                self.tx_stopped = false;
+                self.tx_ready.signal(());
+            }
+            MSG_FCOFF => self.tx_stopped = true,
+            MSG_CLD => return Err(RunError::Closed),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Send the data written to the channels.
+    async fn send_pending<W: Write>(&mut self, w: &mut W) -> Result<(), RunError<W::Error>> {
+        if self.tx_stopped {
+            return Ok(());
+        }
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let buf = &mut buf[..self.config.max_frame_size];
+        for (i, end) in self.ends.iter_mut().enumerate() {
+            while let Ok(n) = end.tx.try_read(buf) {
+                send_frame(w, i as u8 + 1, UIH, true, &buf[..n]).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send a frame. `cr` is set for commands and for data, and cleared for responses.
+async fn send_frame<W: Write>(
+    w: &mut W,
+    dlci: u8,
+    control: u8,
+    cr: bool,
+    info: &[u8],
+) -> Result<(), RunError<W::Error>> {
+    let mut buf = [0; MAX_FRAME_SIZE + 7];
+    buf[0] = FLAG;
+    buf[1] = (dlci << 2) | if cr { CR } else { 0 } | EA;
+    buf[2] = control;
+    let mut n = 3;
+    if info.len() < 128 {
+        buf[n] = ((info.len() as u8) << 1) | EA;
+        n += 1;
+    } else {
+        buf[n] = (info.len() << 1) as u8;
+        buf[n + 1] = (info.len() >> 7) as u8;
+        n += 2;
+    }
+    let header = n;
+    buf[n..n + info.len()].copy_from_slice(info);
+    n += info.len();
+    // The FCS of UIH frames only covers the header.
+    let fcs_len = if control & !PF == UIH { header } else { n };
+    buf[n] = 0xFF - crc(&buf[1..fcs_len]);
+    buf[n + 1] = FLAG;
+    n += 2;
+
+    w.write_all(&buf[..n]).await.map_err(RunError::Write)?;
+    w.flush().await.map_err(RunError::Write)
+}
+
+fn crc(data: &[u8]) -> u8 {
+    let mut crc = 0xFF;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xE0 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+struct Frame<'a> {
+    dlci: u8,
+    /// Control field, without the P/F bit.
+    control: u8,
+    info: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+enum ParserState {
+    Flag,
+    Address,
+    Control,
+    Length,
+    Length2,
+    Info,
+    Fcs,
+    End,
+}
+
+/// Basic option frame parser.
+///
+/// Basic option does not escape the flags, so frames are delimited by their length field.
+struct Parser {
+    state: ParserState,
+    /// Address, control and length fields, then the information field.
+    buf: [u8; MAX_FRAME_SIZE + 4],
+    header: usize,
+    len: usize,
+    info_len: usize,
+    fcs_ok: bool,
+}
+
+impl Parser {
+    fn new() -> Self {
+        Self {
+            state: ParserState::Flag,
+            buf: [0; MAX_FRAME_SIZE + 4],
+            header: 0,
+            len: 0,
+            info_len: 0,
+            fcs_ok: false,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buf[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<Frame<'_>> {
+        self.state = match self.state {
+            ParserState::Flag if byte == FLAG => ParserState::Address,
+            ParserState::Flag => ParserState::Flag,
+            // Consecutive flags, between frames.
+            ParserState::Address if byte == FLAG => ParserState::Address,
+            ParserState::Address => {
+                self.len = 0;
+                self.push(byte);
+                ParserState::Control
+            }
+            ParserState::Control => {
+                self.push(byte);
+                ParserState::Length
+            }
+            ParserState::Length => {
+                self.push(byte);
+                self.info_len = usize::from(byte >> 1);
+                if byte & EA == 0 {
+                    ParserState::Length2
+                } else {
+                    self.start_info()
+                }
+            }
+            ParserState::Length2 => {
+                self.push(byte);
+                self.info_len |= usize::from(byte) << 7;
+                self.start_info()
+            }
+            ParserState::Info => {
+                self.push(byte);
+                if self.len == self.header + self.info_len {
+                    ParserState::Fcs
+                } else {
+                    ParserState::Info
+                }
+            }
+            ParserState::Fcs => {
+                // The FCS of UIH frames only covers the header.
+                let fcs_len = if self.buf[1] & !PF == UIH {
+                    self.header
+                } else {
+                    self.len
+                };
+                self.fcs_ok = byte == 0xFF - crc(&self.buf[..fcs_len]);
+                ParserState::End
+            }
+            ParserState::End => {
+                if byte != FLAG {
+                    warn!("cmux: missing closing flag");
+                    self.state = ParserState::Flag;
+                    return None;
+                }
+                // The closing flag may be the opening flag of the next frame.
+                self.state = ParserState::Address;
+                if !self.fcs_ok {
+                    warn!("cmux: bad FCS");
+                    return None;
+                }
+                return Some(Frame {
+                    dlci: self.buf[0] >> 2,
+                    control: self.buf[1] & !PF,
+                    info: &self.buf[self.header..self.len],
+                });
+            }
+        };
+        None
+    }
+
+    fn start_info(&mut self) -> ParserState {
+        self.header = self.len;
+        if self.info_len > MAX_FRAME_SIZE {
+            warn!("cmux: dropping frame longer than the maximum frame size");
+            ParserState::Flag
+        } else if self.info_len == 0 {
+            ParserState::Fcs
+        } else {
+            ParserState::Info
+        }
+    }
+}
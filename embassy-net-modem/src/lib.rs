@@ -0,0 +1,9 @@
+#![no_std]
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+// must be first
+mod fmt;
+
+pub mod at;
+pub mod cmux;
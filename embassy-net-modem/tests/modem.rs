@@ -0,0 +1,259 @@
+use core::convert::Infallible;
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net_modem::{at, cmux};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::{Pipe, Reader, Writer};
+use embassy_time::Duration;
+use embedded_io_async::{BufRead, ErrorType, Read, Write};
+
+/// One end of an in-memory serial link.
+struct Port<'a> {
+    rx: Reader<'a, NoopRawMutex, 1024>,
+    tx: Writer<'a, NoopRawMutex, 1024>,
+}
+
+impl ErrorType for Port<'_> {
+    type Error = Infallible;
+}
+
+impl Read for Port<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        Ok(self.rx.read(buf).await)
+    }
+}
+
+impl BufRead for Port<'_> {
+    async fn fill_buf(&mut self) -> Result<&[u8], Infallible> {
+        Ok(self.rx.fill_buf().await)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.rx.consume(amt)
+    }
+}
+
+impl Write for Port<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        Ok(self.tx.write(buf).await)
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+fn link<'a>(a: &'a mut Pipe<NoopRawMutex, 1024>, b: &'a mut Pipe<NoopRawMutex, 1024>) -> (Port<'a>, Port<'a>) {
+    let (a_rx, a_tx) = a.split();
+    let (b_rx, b_tx) = b.split();
+    (Port { rx: a_rx, tx: b_tx }, Port { rx: b_rx, tx: a_tx })
+}
+
+async fn read_line(port: &mut Port<'_>) -> Vec<u8> {
+    let mut line = Vec::new();
+    loop {
+        let mut byte = [0];
+        port.read_exact(&mut byte).await.unwrap();
+        match byte[0] {
+            b'\r' => return line,
+            b => line.push(b),
+        }
+    }
+}
+
+#[test]
+fn at_commands_and_urcs() {
+    let (mut a, mut b) = (Pipe::new(), Pipe::new());
+    let (host, mut modem) = link(&mut a, &mut b);
+
+    let mut state = at::State::<256, 4>::new();
+    let (client, mut runner) = at::new(&mut state);
+    let mut config = at::Config::default();
+    config.urc_prefixes = &[b"+CREG:"];
+
+    let modem = async {
+        loop {
+            let line = read_line(&mut modem).await;
+            let resp: &[u8] = match &line[..] {
+                b"AT" => b"\r\nOK\r\n",
+                // Echo, and an URC in the middle of the response.
+                b"AT+CSQ" => b"AT+CSQ\r\r\n+CREG: 5\r\n\r\n+CSQ: 20,99\r\n\r\nOK\r\n",
+                b"AT+CREG?" => b"\r\n+CREG: 0,1\r\n\r\nOK\r\n",
+                b"AT+CPIN?" => b"\r\n+CME ERROR: 10\r\n",
+                b"ATD*99#" => b"\r\nCONNECT 150000000\r\n~\x7d\x23~",
+                b"AT+SLOW" => b"",
+                _ => b"\r\nERROR\r\n",
+            };
+            modem.write_all(resp).await.unwrap();
+        }
+    };
+
+    let test = async {
+        let mut resp = [0; 64];
+        let timeout = Duration::from_secs(1);
+
+        let n = client.command(b"AT", &mut resp, timeout).await.unwrap();
+        assert_eq!(&resp[..n], b"OK\r\n");
+
+        // Trailing terminators are ignored.
+        let n = client.command(b"AT+CSQ\r\n", &mut resp, timeout).await.unwrap();
+        assert_eq!(&resp[..n], b"+CSQ: 20,99\r\nOK\r\n");
+        assert_eq!(&client.receive_urc().await[..], b"+CREG: 5");
+
+        let n = client.command(b"AT+CREG?", &mut resp, timeout).await.unwrap();
+        assert_eq!(&resp[..n], b"+CREG: 0,1\r\nOK\r\n");
+
+        assert_eq!(
+            client.command(b"AT+CPIN?", &mut resp, timeout).await,
+            Err(at::Error::CmeError(10))
+        );
+        assert_eq!(
+            client.command(b"AT+FOO", &mut resp, timeout).await,
+            Err(at::Error::Error)
+        );
+        assert_eq!(
+            client.command(b"AT+CSQ", &mut resp[..8], timeout).await,
+            Err(at::Error::BufferTooSmall)
+        );
+        assert_eq!(
+            client.command(b"AT+SLOW", &mut resp, Duration::from_millis(50)).await,
+            Err(at::Error::Timeout)
+        );
+
+        let n = client.command(b"ATD*99#", &mut resp, timeout).await.unwrap();
+        assert_eq!(&resp[..n], b"CONNECT 150000000\r\n");
+    };
+
+    match block_on(select3(runner.run(host, &config), modem, test)) {
+        Either3::First(r) => panic!("runner returned {:?}", r),
+        Either3::Second(()) => unreachable!(),
+        Either3::Third(()) => {}
+    }
+}
+
+fn crc(data: &[u8]) -> u8 {
+    let mut crc = 0xFF;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xE0 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Read a frame, returning its address, control and information fields.
+async fn read_frame(port: &mut Port<'_>) -> (u8, u8, Vec<u8>) {
+    let mut byte = [0];
+    loop {
+        port.read_exact(&mut byte).await.unwrap();
+        if byte[0] != 0xF9 {
+            break;
+        }
+    }
+    let mut header = vec![byte[0], 0, 0];
+    port.read_exact(&mut header[1..]).await.unwrap();
+    assert_eq!(header[2] & 1, 1, "two octet length");
+    let mut info = vec![0; usize::from(header[2] >> 1)];
+    port.read_exact(&mut info).await.unwrap();
+    let mut trailer = [0; 2];
+    port.read_exact(&mut trailer).await.unwrap();
+    let covered = if header[1] & !0x10 == 0xEF {
+        header.clone()
+    } else {
+        [&header[..], &info].concat()
+    };
+    assert_eq!(trailer, [0xFF - crc(&covered), 0xF9]);
+    (header[0], header[1], info)
+}
+
+async fn write_frame(port: &mut Port<'_>, address: u8, control: u8, info: &[u8]) {
+    let header = [address, control, ((info.len() as u8) << 1) | 1];
+    port.write_all(&[0xF9]).await.unwrap();
+    port.write_all(&header).await.unwrap();
+    port.write_all(info).await.unwrap();
+    port.write_all(&[0xFF - crc(&header), 0xF9]).await.unwrap();
+}
+
+#[test]
+fn cmux_channels() {
+    let (mut a, mut b) = (Pipe::new(), Pipe::new());
+    let (host, mut modem) = link(&mut a, &mut b);
+
+    let mut state = cmux::State::<2, 256>::new();
+    let ([mut control, mut data], mut runner) = cmux::new(&mut state, cmux::Config::default());
+
+    let mut at_state = at::State::<256, 4>::new();
+    let (client, mut at_runner) = at::new(&mut at_state);
+
+    let modem = async {
+        let mut raw = [0; 6];
+        modem.read_exact(&mut raw).await.unwrap();
+        assert_eq!(raw, [0xF9, 0x03, 0x3F, 0x01, 0x1C, 0xF9]);
+        write_frame(&mut modem, 0x03, 0x73, &[]).await;
+
+        for dlci in 1..=2 {
+            let (address, control, _) = read_frame(&mut modem).await;
+            assert_eq!((address, control), ((dlci << 2) | 3, 0x3F));
+            write_frame(&mut modem, address, 0x73, &[]).await;
+        }
+        for dlci in 1..=2 {
+            let (address, control, info) = read_frame(&mut modem).await;
+            assert_eq!((address, control), (0x03, 0xEF));
+            assert_eq!(info, [0xE3, 0x05, (dlci << 2) | 3, 0x8D]);
+        }
+
+        // Modem status command from the modem, which must be answered.
+        write_frame(&mut modem, 0x01, 0xEF, &[0xE3, 0x05, 0x07, 0x8D]).await;
+
+        loop {
+            let (address, control, info) = read_frame(&mut modem).await;
+            assert_eq!(control, 0xEF);
+            match address >> 2 {
+                0 => {
+                    assert_eq!(info, [0xE1, 0x05, 0x07, 0x8D]);
+                }
+                1 => {
+                    assert_eq!(info, b"AT+CGMI\r");
+                    write_frame(&mut modem, 0x05, 0xEF, b"\r\nembassy\r\n\r\nOK\r\n").await;
+                }
+                // Loopback.
+                2 => {
+                    write_frame(&mut modem, 0x09, 0xEF, &info).await;
+                }
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    let test = async {
+        let at = async {
+            let mut resp = [0; 64];
+            let n = client
+                .command(b"AT+CGMI", &mut resp, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(&resp[..n], b"embassy\r\nOK\r\n");
+        };
+        let loopback = async {
+            // Longer than the frame size, written before the channel is open.
+            let sent: Vec<u8> = (0..200).collect();
+            data.write_all(&sent).await.unwrap();
+            let mut received = [0; 200];
+            data.read_exact(&mut received).await.unwrap();
+            assert_eq!(received[..], sent[..]);
+        };
+        match select(at_runner.run(&mut control, &at::Config::default()), join(at, loopback)).await {
+            Either::First(r) => panic!("AT runner returned {:?}", r),
+            Either::Second(_) => {}
+        }
+    };
+
+    match block_on(select3(runner.run(host), modem, test)) {
+        Either3::First(r) => panic!("runner returned {:?}", r),
+        Either3::Second(()) => unreachable!(),
+        Either3::Third(()) => {}
+    }
+}
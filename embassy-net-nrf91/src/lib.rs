@@ -666,6 +666,8 @@ impl StateInner {
                                 unsafe { ptr::copy_nonoverlapping(msg.data, buf.as_mut_ptr(), len) }
                                 fence(Ordering::SeqCst); // synchronize volatile accesses with the nonvolatile copy_nonoverlapping.
                                 buf.rx_done(len);
+                            } else {
+                                ch.record_rx_dropped();
                             }
                             false
                         }
@@ -0,0 +1,506 @@
+#![no_std]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+// must go first!
+mod fmt;
+
+use core::cell::Cell;
+use core::convert::Infallible;
+use core::task::Context;
+
+pub use embassy_net_driver as driver;
+use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_time::Instant;
+use embedded_io_async::Write;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_IEEE802_15_4_NOFCS: u16 = 230;
+
+/// Length of an enhanced packet block without the packet data.
+const PACKET_BLOCK_OVERHEAD: usize = 28 + 12 + 4;
+
+/// Capture configuration.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config {
+    /// Maximum number of bytes captured from each packet.
+    ///
+    /// Longer packets are truncated in the capture, which still records their original length.
+    pub snap_len: usize,
+    /// Capture received packets.
+    pub rx: bool,
+    /// Capture transmitted packets.
+    pub tx: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            snap_len: 65535,
+            rx: true,
+            tx: true,
+        }
+    }
+}
+
+/// Capture state.
+///
+/// Holds a buffer of `N` bytes for the captured packets waiting to be written by the [`Runner`].
+pub struct State<const N: usize> {
+    pipe: Pipe<NoopRawMutex, N>,
+    dropped: Cell<u32>,
+}
+
+impl<const N: usize> State<N> {
+    /// Create a new capture state.
+    pub const fn new() -> Self {
+        Self {
+            pipe: Pipe::new(),
+            dropped: Cell::new(0),
+        }
+    }
+}
+
+impl<const N: usize> Default for State<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Capture a driver.
+///
+/// This returns two structs:
+/// - a [`Device`] wrapping `driver`, that you must pass to the `embassy-net` stack instead of it.
+/// - a [`Runner`]. You must call `.run()` on it in a background task to write the capture.
+pub fn new<'d, D: Driver, const N: usize>(
+    driver: D,
+    state: &'d mut State<N>,
+    config: Config,
+) -> (Device<'d, D, N>, Runner<'d, N>) {
+    let link_type = match driver.hardware_address() {
+        HardwareAddress::Ethernet(_) => LINKTYPE_ETHERNET,
+        HardwareAddress::Ieee802154(_) => LINKTYPE_IEEE802_15_4_NOFCS,
+        _ => LINKTYPE_RAW,
+    };
+    let state = &*state;
+    (
+        Device {
+            inner: driver,
+            capture: Capture {
+                state,
+                snap_len: config.snap_len,
+                rx: config.rx,
+                tx: config.tx,
+            },
+        },
+        Runner {
+            state,
+            link_type,
+            snap_len: config.snap_len as u32,
+            pending: 0,
+        },
+    )
+}
+
+/// Direction of a captured packet.
+#[derive(Clone, Copy)]
+enum Direction {
+    Inbound = 1,
+    Outbound = 2,
+}
+
+#[derive(Clone, Copy)]
+struct Capture<'d, const N: usize> {
+    state: &'d State<N>,
+    snap_len: usize,
+    rx: bool,
+    tx: bool,
+}
+
+impl<'d, const N: usize> Capture<'d, N> {
+    /// Queue an enhanced packet block for the runner.
+    fn packet(&self, direction: Direction, packet: &[u8]) {
+        let enabled = match direction {
+            Direction::Inbound => self.rx,
+            Direction::Outbound => self.tx,
+        };
+        if !enabled {
+            return;
+        }
+
+        let captured = packet.len().min(self.snap_len);
+        let padding = (4 - captured % 4) % 4;
+        let total = PACKET_BLOCK_OVERHEAD + captured + padding;
+
+        // Records must be written whole, the runner relies on the block lengths.
+        let pipe = &self.state.pipe;
+        if pipe.free_capacity() < total {
+            trace!("capture buffer full, dropping packet");
+            self.state.dropped.set(self.state.dropped.get().wrapping_add(1));
+            return;
+        }
+
+        let timestamp = Instant::now().as_micros();
+        let mut header = [0; 28];
+        put_u32(&mut header[0..], ENHANCED_PACKET_BLOCK);
+        put_u32(&mut header[4..], total as u32);
+        put_u32(&mut header[8..], 0); // interface id
+        put_u32(&mut header[12..], (timestamp >> 32) as u32);
+        put_u32(&mut header[16..], timestamp as u32);
+        put_u32(&mut header[20..], captured as u32);
+        put_u32(&mut header[24..], packet.len() as u32);
+
+        let mut trailer = [0; 16];
+        put_u16(&mut trailer[0..], OPT_EPB_FLAGS);
+        put_u16(&mut trailer[2..], 4);
+        put_u32(&mut trailer[4..], direction as u32);
+        put_u16(&mut trailer[8..], OPT_ENDOFOPT);
+        put_u16(&mut trailer[10..], 0);
+        put_u32(&mut trailer[12..], total as u32);
+
+        unwrap!(pipe.try_write_all(&header).ok());
+        unwrap!(pipe.try_write_all(&packet[..captured]).ok());
+        unwrap!(pipe.try_write_all(&[0; 3][..padding]).ok());
+        unwrap!(pipe.try_write_all(&trailer).ok());
+    }
+}
+
+/// Capturing driver.
+///
+/// Pass it to the `embassy-net` stack instead of the wrapped driver.
+pub struct Device<'d, D: Driver, const N: usize> {
+    inner: D,
+    capture: Capture<'d, N>,
+}
+
+impl<'d, D: Driver, const N: usize> Device<'d, D, N> {
+    /// Get a reference to the wrapped driver.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped driver.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+
+impl<'d, D: Driver, const N: usize> Driver for Device<'d, D, N> {
+    type RxToken<'a>
+        = RxToken<'d, D::RxToken<'a>, N>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'d, D::TxToken<'a>, N>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let capture = self.capture;
+        self.inner
+            .receive(cx)
+            .map(|(rx, tx)| (RxToken { inner: rx, capture }, TxToken { inner: tx, capture }))
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        let capture = self.capture;
+        self.inner.transmit(cx).map(|tx| TxToken { inner: tx, capture })
+    }
+
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        self.inner.link_state(cx)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        self.inner.hardware_address()
+    }
+}
+
+/// Token to receive a packet from a capturing [`Device`].
+#[doc(hidden)]
+pub struct RxToken<'d, T, const N: usize> {
+    inner: T,
+    capture: Capture<'d, N>,
+}
+
+impl<'d, T: driver::RxToken, const N: usize> driver::RxToken for RxToken<'d, T, N> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.inner.consume(|buf| {
+            self.capture.packet(Direction::Inbound, buf);
+            f(buf)
+        })
+    }
+}
+
+/// Token to transmit a packet through a capturing [`Device`].
+#[doc(hidden)]
+pub struct TxToken<'d, T, const N: usize> {
+    inner: T,
+    capture: Capture<'d, N>,
+}
+
+impl<'d, T: driver::TxToken, const N: usize> driver::TxToken for TxToken<'d, T, N> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.inner.consume(len, |buf| {
+            let r = f(buf);
+            self.capture.packet(Direction::Outbound, buf);
+            r
+        })
+    }
+}
+
+/// Background runner writing the capture.
+///
+/// You must call `.run()` in a background task for the capture to be written.
+pub struct Runner<'d, const N: usize> {
+    state: &'d State<N>,
+    link_type: u16,
+    snap_len: u32,
+    /// Bytes of the current block not yet taken out of the buffer.
+    pending: usize,
+}
+
+impl<'d, const N: usize> Runner<'d, N> {
+    /// Write the capture to `sink`.
+    ///
+    /// This starts a new pcapng section, then writes the captured packets as they come.
+    ///
+    /// If writing fails, the error is returned. It is also allowed to cancel this function's
+    /// future (i.e. drop it). After this function returns or is canceled, you can call it again,
+    /// for example when a USB host reconnects, to continue the capture in a new section.
+    pub async fn run<W: Write>(&mut self, mut sink: W) -> Result<Infallible, W::Error> {
+        let mut buf = [0; 256];
+
+        // Skip the rest of a block interrupted by a previous run, it can't be resumed.
+        while self.pending > 0 {
+            let len = self.pending.min(buf.len());
+            self.pending -= self.state.pipe.read(&mut buf[..len]).await;
+        }
+
+        sink.write_all(&section_header_block()).await?;
+        sink.write_all(&interface_description_block(self.link_type, self.snap_len))
+            .await?;
+        sink.flush().await?;
+
+        loop {
+            // The pipe only holds whole blocks, read the type and length of the next one.
+            let mut header = [0; 8];
+            let mut read = 0;
+            while read < header.len() {
+                read += self.state.pipe.read(&mut header[read..]).await;
+            }
+            self.pending = u32::from_le_bytes(unwrap!(header[4..8].try_into())) as usize - header.len();
+            sink.write_all(&header).await?;
+
+            while self.pending > 0 {
+                let len = self.pending.min(buf.len());
+                let n = self.state.pipe.read(&mut buf[..len]).await;
+                self.pending -= n;
+                sink.write_all(&buf[..n]).await?;
+            }
+
+            if self.state.pipe.is_empty() {
+                sink.flush().await?;
+            }
+        }
+    }
+
+    /// Get the number of packets dropped from the capture because the buffer was full.
+    pub fn dropped(&self) -> u32 {
+        self.state.dropped.get()
+    }
+}
+
+fn section_header_block() -> [u8; 28] {
+    let mut block = [0; 28];
+    put_u32(&mut block[0..], SECTION_HEADER_BLOCK);
+    put_u32(&mut block[4..], 28);
+    put_u32(&mut block[8..], BYTE_ORDER_MAGIC);
+    put_u16(&mut block[12..], 1); // major version
+    put_u16(&mut block[14..], 0); // minor version
+    block[16..24].copy_from_slice(&(-1i64).to_le_bytes()); // section length, unspecified
+    put_u32(&mut block[24..], 28);
+    block
+}
+
+fn interface_description_block(link_type: u16, snap_len: u32) -> [u8; 32] {
+    let mut block = [0; 32];
+    put_u32(&mut block[0..], INTERFACE_DESCRIPTION_BLOCK);
+    put_u32(&mut block[4..], 32);
+    put_u16(&mut block[8..], link_type);
+    put_u32(&mut block[12..], snap_len);
+    // Timestamps are in microseconds.
+    put_u16(&mut block[16..], OPT_IF_TSRESOL);
+    put_u16(&mut block[18..], 1);
+    block[20] = 6;
+    put_u16(&mut block[24..], OPT_ENDOFOPT);
+    put_u32(&mut block[28..], 32);
+    block
+}
+
+fn put_u16(buf: &mut [u8], value: u16) {
+    buf[..2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], value: u32) {
+    buf[..4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::{Duration, MockDriver};
+
+    use super::*;
+
+    /// Take the blocks queued for the runner.
+    fn take<const N: usize>(state: &State<N>, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while let Ok(n) = state.pipe.try_read(&mut buf[len..]) {
+            len += n;
+        }
+        len
+    }
+
+    #[test]
+    fn section_header() {
+        assert_eq!(
+            section_header_block(),
+            [
+                0x0A, 0x0D, 0x0D, 0x0A, 0x1C, 0x00, 0x00, 0x00, 0x4D, 0x3C, 0x2B, 0x1A, 0x01, 0x00, 0x00, 0x00, //
+                0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x1C, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn interface_description() {
+        assert_eq!(
+            interface_description_block(LINKTYPE_ETHERNET, 1514),
+            [
+                0x01, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xEA, 0x05, 0x00, 0x00, //
+                0x09, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00,
+            ]
+        );
+        let block = interface_description_block(LINKTYPE_IEEE802_15_4_NOFCS, 127);
+        assert_eq!(block[8..16], [0xE6, 0x00, 0x00, 0x00, 0x7F, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn enhanced_packets() {
+        let state = State::<256>::new();
+        let capture = Capture {
+            state: &state,
+            snap_len: 6,
+            rx: true,
+            tx: false,
+        };
+        let mut buf = [0; 256];
+
+        // The only test using the time driver.
+        MockDriver::get().reset();
+        MockDriver::get().advance(Duration::from_micros(0x1_0000_0002));
+
+        // Odd lengths are padded to 32 bits.
+        capture.packet(Direction::Inbound, &[1, 2, 3, 4, 5]);
+        let len = take(&state, &mut buf);
+        assert_eq!(
+            buf[..len],
+            [
+                0x06, 0x00, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // type, length, interface
+                0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // timestamp
+                0x05, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // captured and original lengths
+                0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x00, 0x00, // data and padding
+                0x02, 0x00, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, // epb_flags, inbound
+                0x00, 0x00, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, // end of options, length
+            ]
+        );
+
+        // Packets longer than the snap length are truncated, with their original length.
+        capture.packet(Direction::Inbound, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let len = take(&state, &mut buf);
+        assert_eq!(len, 52);
+        assert_eq!(buf[4..8], [0x34, 0x00, 0x00, 0x00]);
+        assert_eq!(buf[20..28], [0x06, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00]);
+        assert_eq!(buf[28..36], [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x00, 0x00]);
+
+        // Lengths multiple of 32 bits are not padded.
+        let capture = Capture { tx: true, ..capture };
+        capture.packet(Direction::Outbound, &[1, 2, 3, 4]);
+        let len = take(&state, &mut buf);
+        assert_eq!(len, 48);
+        assert_eq!(buf[4..8], [0x30, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            buf[28..48],
+            [
+                0x01, 0x02, 0x03, 0x04, 0x02, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30,
+                0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_directions() {
+        let state = State::<256>::new();
+        let capture = Capture {
+            state: &state,
+            snap_len: 65535,
+            rx: false,
+            tx: true,
+        };
+        capture.packet(Direction::Inbound, &[1, 2, 3, 4]);
+        assert!(state.pipe.is_empty());
+        let capture = Capture {
+            rx: true,
+            tx: false,
+            ..capture
+        };
+        capture.packet(Direction::Outbound, &[1, 2, 3, 4]);
+        assert!(state.pipe.is_empty());
+        assert_eq!(state.dropped.get(), 0);
+    }
+
+    #[test]
+    fn buffer_full() {
+        let state = State::<100>::new();
+        let capture = Capture {
+            state: &state,
+            snap_len: 65535,
+            rx: true,
+            tx: true,
+        };
+        let mut buf = [0; 100];
+
+        // Blocks are queued whole, or dropped.
+        capture.packet(Direction::Outbound, &[0; 40]);
+        capture.packet(Direction::Outbound, &[0; 13]);
+        assert_eq!(state.dropped.get(), 1);
+        assert_eq!(take(&state, &mut buf), 84);
+        capture.packet(Direction::Outbound, &[0; 13]);
+        assert_eq!(take(&state, &mut buf), 60);
+        assert_eq!(state.dropped.get(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+#![no_std]
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+// must be first
+mod fmt;
+
+use core::convert::Infallible;
+use core::mem::MaybeUninit;
+
+use embassy_futures::select::{Either, select};
+use embassy_net_driver_channel as ch;
+use embassy_net_driver_channel::driver::LinkState;
+use embedded_io_async::{BufRead, Write};
+
+const MTU: usize = 1500;
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Type alias for the embassy-net driver.
+pub type Device<'d> = embassy_net_driver_channel::Device<'d, MTU>;
+
+/// Internal state for the embassy-net integration.
+pub struct State<const N_RX: usize, const N_TX: usize> {
+    ch_state: ch::State<MTU, N_RX, N_TX>,
+}
+
+impl<const N_RX: usize, const N_TX: usize> State<N_RX, N_TX> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            ch_state: ch::State::new(),
+        }
+    }
+}
+
+impl<const N_RX: usize, const N_TX: usize> Default for State<N_RX, N_TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Background runner for the driver.
+///
+/// You must call `.run()` in a background task for the driver to operate.
+pub struct Runner<'d> {
+    ch: ch::Runner<'d, MTU>,
+}
+
+/// Error returned by [`Runner::run`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RunError<E> {
+    /// Reading from the serial port failed.
+    Read(E),
+    /// Writing to the serial port failed.
+    Write(E),
+    /// Reading from the serial port got EOF.
+    Eof,
+}
+
+impl<'d> Runner<'d> {
+    /// You must call this in a background task for the driver to operate.
+    ///
+    /// SLIP has no link negotiation, so the link state is set to Up while this runs. If
+    /// reading/writing to the underlying serial port fails, the link state is set to Down and the
+    /// error is returned.
+    ///
+    /// It is allowed to cancel this function's future (i.e. drop it). This will set the link state
+    /// to Down, and drop the frame being received.
+    ///
+    /// After this function returns or is canceled, you can call it again.
+    pub async fn run<RW: BufRead + Write>(&mut self, mut rw: RW) -> Result<Infallible, RunError<RW::Error>> {
+        let (state_chan, mut rx_chan, mut tx_chan) = self.ch.borrow_split();
+        state_chan.set_link_state(LinkState::Up);
+        let _ondrop = OnDrop::new(|| state_chan.set_link_state(LinkState::Down));
+
+        let mut decoder = Decoder::new();
+        // Worst case, every byte is escaped.
+        let mut tx_buf = [0; 2 * MTU + 2];
+
+        loop {
+            let rx_fut = async {
+                let buf = rx_chan.rx_buf().await;
+                match rw.fill_buf().await {
+                    Ok([]) => Err(RunError::Eof),
+                    Ok(rx_data) => Ok((buf, rx_data)),
+                    Err(e) => Err(RunError::Read(e)),
+                }
+            };
+            let tx_fut = tx_chan.tx_buf();
+            match select(rx_fut, tx_fut).await {
+                Either::First(r) => {
+                    let (mut buf, rx_data) = r?;
+                    let (n, done) = decoder.decode(rx_data);
+                    rw.consume(n);
+
+                    if done {
+                        let pkt = decoder.frame();
+                        buf[..pkt.len()].copy_from_slice(pkt);
+                        buf.rx_done(pkt.len());
+                        decoder.reset();
+                    }
+                }
+                Either::Second(pkt) => {
+                    let n = encode(&pkt, &mut tx_buf);
+                    rw.write_all(&tx_buf[..n]).await.map_err(RunError::Write)?;
+                    pkt.tx_done();
+                }
+            }
+        }
+    }
+}
+
+/// Create a SLIP embassy-net driver instance.
+///
+/// This returns two structs:
+/// - a `Device` that you must pass to the `embassy-net` stack.
+/// - a `Runner`. You must call `.run()` on it in a background task.
+pub fn new<'a, const N_RX: usize, const N_TX: usize>(state: &'a mut State<N_RX, N_TX>) -> (Device<'a>, Runner<'a>) {
+    let (runner, device) = ch::new(&mut state.ch_state, ch::driver::HardwareAddress::Ip);
+    (device, Runner { ch: runner })
+}
+
+/// Encode `pkt` into a frame in `buf`, returning the frame length.
+fn encode(pkt: &[u8], buf: &mut [u8]) -> usize {
+    // A leading END flushes the line noise received by the peer before the frame.
+    let mut n = 0;
+    buf[n] = END;
+    n += 1;
+    for &byte in pkt {
+        match byte {
+            END => {
+                buf[n..n + 2].copy_from_slice(&[ESC, ESC_END]);
+                n += 2;
+            }
+            ESC => {
+                buf[n..n + 2].copy_from_slice(&[ESC, ESC_ESC]);
+                n += 2;
+            }
+            _ => {
+                buf[n] = byte;
+                n += 1;
+            }
+        }
+    }
+    buf[n] = END;
+    n + 1
+}
+
+struct Decoder {
+    buf: [u8; MTU],
+    len: usize,
+    escaped: bool,
+    /// The frame is longer than the MTU, and will be dropped.
+    overflow: bool,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Self {
+            buf: [0; MTU],
+            len: 0,
+            escaped: false,
+            overflow: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.escaped = false;
+        self.overflow = false;
+    }
+
+    fn frame(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Decode `data` up to the end of the first frame.
+    ///
+    /// Returns the number of bytes consumed, and whether a frame was completed.
+    fn decode(&mut self, data: &[u8]) -> (usize, bool) {
+        for (i, &byte) in data.iter().enumerate() {
+            let byte = match (self.escaped, byte) {
+                (_, END) => {
+                    if self.overflow {
+                        warn!("slip: dropping frame longer than the MTU");
+                    } else if self.len > 0 {
+                        return (i + 1, true);
+                    }
+                    // Empty frames separate frames, ignore them.
+                    self.reset();
+                    continue;
+                }
+                (false, ESC) => {
+                    self.escaped = true;
+                    continue;
+                }
+                (true, ESC_END) => END,
+                (true, ESC_ESC) => ESC,
+                // Protocol violation, RFC 1055 suggests keeping the byte.
+                (_, byte) => byte,
+            };
+            self.escaped = false;
+
+            if self.len < MTU {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+        }
+        (data.len(), false)
+    }
+}
+
+struct OnDrop<F: FnOnce()> {
+    f: MaybeUninit<F>,
+}
+
+impl<F: FnOnce()> OnDrop<F> {
+    fn new(f: F) -> Self {
+        Self { f: MaybeUninit::new(f) }
+    }
+}
+
+impl<F: FnOnce()> Drop for OnDrop<F> {
+    fn drop(&mut self) {
+        unsafe { self.f.as_ptr().read()() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `chunks`, as returned by successive `fill_buf` calls, checking the frames against
+    /// `expected`.
+    fn check(chunks: &[&[u8]], expected: &[&[u8]]) {
+        let mut decoder = Decoder::new();
+        let mut frames = expected.iter();
+        for chunk in chunks {
+            let mut data = *chunk;
+            while !data.is_empty() {
+                let (n, done) = decoder.decode(data);
+                assert!(n > 0 && n <= data.len());
+                data = &data[n..];
+                if done {
+                    assert_eq!(decoder.frame(), *frames.next().expect("unexpected frame"));
+                    decoder.reset();
+                }
+            }
+        }
+        assert!(frames.next().is_none(), "missing frame");
+    }
+
+    #[test]
+    fn encode_escapes() {
+        let mut buf = [0; 16];
+        let n = encode(&[1, END, 2, ESC, 3], &mut buf);
+        assert_eq!(buf[..n], [END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END]);
+        let n = encode(&[ESC_END, ESC_ESC], &mut buf);
+        assert_eq!(buf[..n], [END, ESC_END, ESC_ESC, END]);
+        let n = encode(&[], &mut buf);
+        assert_eq!(buf[..n], [END, END]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0; 2 * MTU + 2];
+
+        let mut pkt = [0; 256];
+        for (i, byte) in pkt.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let n = encode(&pkt, &mut buf);
+        assert_eq!(n, pkt.len() + 4);
+        check(&[&buf[..n]], &[&pkt]);
+
+        // Frames of the MTU, whose bytes are all escaped.
+        for byte in [END, ESC] {
+            let pkt = [byte; MTU];
+            let n = encode(&pkt, &mut buf);
+            assert_eq!(n, buf.len());
+            check(&[&buf[..n]], &[&pkt]);
+        }
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        // The escaped byte is kept.
+        check(&[&[END, 1, ESC, 0x42, 2, END]], &[&[1, 0x42, 2]]);
+        check(&[&[ESC, ESC, ESC_ESC, END]], &[&[ESC, ESC_ESC]]);
+        // An END after an ESC ends the frame, and the escape does not apply to the next frame.
+        check(&[&[1, ESC, END, ESC_END, END]], &[&[1], &[ESC_END]]);
+    }
+
+    #[test]
+    fn empty_frames() {
+        check(&[&[END, END, END, 1, END, END, 2, END]], &[&[1], &[2]]);
+        check(&[&[END], &[END], &[END]], &[]);
+    }
+
+    #[test]
+    fn mtu_overflow() {
+        let mut data = [0x55; MTU + 1];
+        data[MTU] = END;
+        check(&[&data[..MTU], &[END]], &[&[0x55; MTU]]);
+
+        // The frame is dropped, and the decoder resyncs at the next END.
+        check(&[&[END], &data[..MTU], &[0x55, 0x55, END, 1, 2, END]], &[&[1, 2]]);
+        check(&[&[0x55; MTU], &[ESC, ESC_END, END], &[3, END]], &[&[3]]);
+    }
+
+    #[test]
+    fn split_frames() {
+        let mut buf = [0; 64];
+        let pkt = [1, END, 2, ESC, 3, ESC, END];
+        let n = encode(&pkt, &mut buf);
+        assert_eq!(n, 13);
+        let frame = &buf[..n];
+
+        // Frames split at every position, including between an ESC and the escaped byte.
+        for i in 0..=n {
+            check(&[&frame[..i], &frame[i..]], &[&pkt]);
+        }
+        let bytes: [&[u8]; 13] = core::array::from_fn(|i| &frame[i..i + 1]);
+        check(&bytes, &[&pkt]);
+
+        // Several frames in a chunk, the last one split.
+        let mut data = [0; 128];
+        data[..n].copy_from_slice(frame);
+        data[n..2 * n].copy_from_slice(frame);
+        data[2 * n..3 * n].copy_from_slice(frame);
+        check(&[&data[..2 * n + 4], &data[2 * n + 4..3 * n]], &[&pkt, &pkt, &pkt]);
+    }
+}
@@ -0,0 +1,371 @@
+#![no_std]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+// must go first!
+mod fmt;
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::pin;
+use core::task::Context;
+
+pub use embassy_net_driver as driver;
+use embassy_net_driver::{Capabilities, HardwareAddress, LinkState};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+/// Network conditions simulated by a [`Switch`].
+///
+/// They apply to every packet, on its way from the sending port to each receiving port.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config {
+    /// Delay before a packet is delivered.
+    pub latency: Duration,
+    /// Maximum random delay added to the latency of each packet.
+    ///
+    /// Packets sent shortly after each other can be reordered.
+    pub jitter: Duration,
+    /// Probability for a packet to be lost, between 0 and 1.
+    pub loss: f32,
+    /// Probability for a packet to be held back by [`reorder_delay`](Self::reorder_delay), between 0 and 1.
+    pub reorder: f32,
+    /// Extra delay of the packets held back for reordering.
+    pub reorder_delay: Duration,
+    /// Seed of the random number generator, to make the simulated conditions reproducible.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_ticks(0),
+            jitter: Duration::from_ticks(0),
+            loss: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            seed: 0x853c_49e6_748f_ea9b,
+        }
+    }
+}
+
+/// Packet counters of a [`Switch`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Stats {
+    /// Packets sent by the ports.
+    pub transmitted: u32,
+    /// Packets received by the ports.
+    pub received: u32,
+    /// Packet copies lost according to [`Config::loss`].
+    pub lost: u32,
+    /// Packet copies dropped because the receiving port queue was full or a link was down.
+    pub dropped: u32,
+}
+
+/// An in-memory Ethernet switch.
+///
+/// Every port is an [`embassy-net` driver](driver::Driver), so [`embassy-net`] stacks can be
+/// connected together without any hardware or OS support, for example in tests.
+///
+/// Ethernet frames are delivered to the port having their destination address, or to all other
+/// ports for broadcast and multicast frames. Other ports, like [`HardwareAddress::Ip`] ones,
+/// receive all the packets sent on the switch.
+///
+/// `MTU` is the maximum packet size, including the Ethernet header. Each port can hold `QUEUE`
+/// packets that are not yet received, further packets are dropped.
+///
+/// [`embassy-net`]: https://crates.io/crates/embassy-net
+pub struct Switch<const PORTS: usize, const MTU: usize = 1514, const QUEUE: usize = 16> {
+    inner: Mutex<NoopRawMutex, RefCell<Inner<PORTS, MTU, QUEUE>>>,
+}
+
+/// An in-memory point-to-point link, a [`Switch`] with two ports.
+pub type Link<const MTU: usize = 1514, const QUEUE: usize = 16> = Switch<2, MTU, QUEUE>;
+
+struct Inner<const PORTS: usize, const MTU: usize, const QUEUE: usize> {
+    config: Config,
+    rng: u64,
+    stats: Stats,
+    ports: [PortState<MTU, QUEUE>; PORTS],
+}
+
+struct PortState<const MTU: usize, const QUEUE: usize> {
+    hardware_address: Option<HardwareAddress>,
+    link_state: LinkState,
+    /// Packets on their way to the port, sorted by delivery time.
+    queue: Vec<Packet<MTU>, QUEUE>,
+    rx_waker: WakerRegistration,
+    link_waker: WakerRegistration,
+}
+
+struct Packet<const MTU: usize> {
+    deliver_at: Instant,
+    len: usize,
+    data: [u8; MTU],
+}
+
+impl<const PORTS: usize, const MTU: usize, const QUEUE: usize> Switch<PORTS, MTU, QUEUE> {
+    /// Create a new switch.
+    pub fn new(config: Config) -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                rng: config.seed | 1,
+                config,
+                stats: Stats::default(),
+                ports: [const {
+                    PortState {
+                        hardware_address: None,
+                        link_state: LinkState::Up,
+                        queue: Vec::new(),
+                        rx_waker: WakerRegistration::new(),
+                        link_waker: WakerRegistration::new(),
+                    }
+                }; PORTS],
+            })),
+        }
+    }
+
+    /// Get the driver of a port.
+    ///
+    /// Use [`HardwareAddress::Ethernet`] for Ethernet stacks, and [`HardwareAddress::Ip`] for
+    /// stacks sending bare IP packets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of range, or if the port was already taken.
+    pub fn port(&self, index: usize, hardware_address: HardwareAddress) -> Port<'_, PORTS, MTU, QUEUE> {
+        self.with(|i| {
+            let port = &mut i.ports[index];
+            if port.hardware_address.is_some() {
+                panic!("Port {} was already taken", index);
+            }
+            port.hardware_address = Some(hardware_address);
+        });
+        Port { switch: self, index }
+    }
+
+    /// Get the simulated network conditions.
+    pub fn config(&self) -> Config {
+        self.with(|i| i.config.clone())
+    }
+
+    /// Change the simulated network conditions.
+    ///
+    /// Packets already on their way are not affected.
+    pub fn set_config(&self, config: Config) {
+        self.with(|i| i.config = config)
+    }
+
+    /// Set the link state of a port.
+    ///
+    /// Packets to or from a port whose link is down are dropped, including the ones already on
+    /// their way to it.
+    pub fn set_link_state(&self, index: usize, link_state: LinkState) {
+        self.with(|i| {
+            let port = &mut i.ports[index];
+            port.link_state = link_state;
+            if link_state == LinkState::Down {
+                i.stats.dropped += port.queue.len() as u32;
+                port.queue.clear();
+            }
+            port.link_waker.wake();
+        })
+    }
+
+    /// Get the packet counters.
+    pub fn stats(&self) -> Stats {
+        self.with(|i| i.stats)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Inner<PORTS, MTU, QUEUE>) -> R) -> R {
+        self.inner.lock(|i| f(&mut i.borrow_mut()))
+    }
+}
+
+impl<const PORTS: usize, const MTU: usize, const QUEUE: usize> Inner<PORTS, MTU, QUEUE> {
+    /// xorshift64*
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && ((self.random() >> 40) as f32 / (1u64 << 24) as f32) < probability
+    }
+
+    fn forward(&mut self, from: usize, data: &[u8]) {
+        self.stats.transmitted += 1;
+        if self.ports[from].link_state == LinkState::Down {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let now = Instant::now();
+        for to in 0..PORTS {
+            if to == from || !self.accepts(to, data) {
+                continue;
+            }
+            if self.ports[to].link_state == LinkState::Down {
+                self.stats.dropped += 1;
+                continue;
+            }
+            if self.chance(self.config.loss) {
+                trace!("lost packet from port {} to port {}", from, to);
+                self.stats.lost += 1;
+                continue;
+            }
+
+            let mut delay = self.config.latency;
+            let jitter = self.config.jitter.as_ticks();
+            if jitter > 0 {
+                delay += Duration::from_ticks(self.random() % (jitter + 1));
+            }
+            if self.chance(self.config.reorder) {
+                delay += self.config.reorder_delay;
+            }
+
+            let mut packet = Packet {
+                deliver_at: now + delay,
+                len: data.len(),
+                data: [0; MTU],
+            };
+            packet.data[..data.len()].copy_from_slice(data);
+
+            let port = &mut self.ports[to];
+            let pos = port.queue.partition_point(|p| p.deliver_at <= packet.deliver_at);
+            if port.queue.insert(pos, packet).is_err() {
+                trace!("dropped packet from port {} to port {}, queue full", from, to);
+                self.stats.dropped += 1;
+                continue;
+            }
+            port.rx_waker.wake();
+        }
+    }
+
+    /// Whether port `to` receives the frame.
+    fn accepts(&self, to: usize, data: &[u8]) -> bool {
+        match self.ports[to].hardware_address {
+            Some(HardwareAddress::Ethernet(addr)) => {
+                // Broadcast and multicast addresses have the lowest bit of the first octet set.
+                data.len() >= 6 && (data[0] & 1 != 0 || data[..6] == addr)
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+/// A port of a [`Switch`].
+///
+/// This is the [`driver::Driver`] to pass to the `embassy-net` stack.
+pub struct Port<'d, const PORTS: usize, const MTU: usize, const QUEUE: usize> {
+    switch: &'d Switch<PORTS, MTU, QUEUE>,
+    index: usize,
+}
+
+impl<'d, const PORTS: usize, const MTU: usize, const QUEUE: usize> Port<'d, PORTS, MTU, QUEUE> {
+    /// Get the index of the port in the switch.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<'d, const PORTS: usize, const MTU: usize, const QUEUE: usize> driver::Driver for Port<'d, PORTS, MTU, QUEUE> {
+    type RxToken<'a>
+        = RxToken<MTU>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a, PORTS, MTU, QUEUE>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.switch.with(|i| {
+            let port = &mut i.ports[self.index];
+            port.rx_waker.register(cx.waker());
+            let deliver_at = port.queue.first()?.deliver_at;
+            // The timer wakes the stack up when the packet is due.
+            if deliver_at > Instant::now() && pin!(Timer::at(deliver_at)).poll(cx).is_pending() {
+                return None;
+            }
+            i.stats.received += 1;
+            Some(port.queue.remove(0))
+        })?;
+        Some((
+            RxToken { packet },
+            TxToken {
+                switch: self.switch,
+                index: self.index,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        Some(TxToken {
+            switch: self.switch,
+            index: self.index,
+        })
+    }
+
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        self.switch.with(|i| {
+            let port = &mut i.ports[self.index];
+            port.link_waker.register(cx.waker());
+            port.link_state
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut caps = Capabilities::default();
+        caps.max_transmission_unit = MTU;
+        caps
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        self.switch.with(|i| unwrap!(i.ports[self.index].hardware_address))
+    }
+}
+
+/// Token to receive a packet from a [`Port`].
+#[doc(hidden)]
+pub struct RxToken<const MTU: usize> {
+    packet: Packet<MTU>,
+}
+
+impl<const MTU: usize> driver::RxToken for RxToken<MTU> {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.packet.data[..self.packet.len])
+    }
+}
+
+/// Token to send a packet through a [`Port`].
+#[doc(hidden)]
+pub struct TxToken<'a, const PORTS: usize, const MTU: usize, const QUEUE: usize> {
+    switch: &'a Switch<PORTS, MTU, QUEUE>,
+    index: usize,
+}
+
+impl<'a, const PORTS: usize, const MTU: usize, const QUEUE: usize> driver::TxToken for TxToken<'a, PORTS, MTU, QUEUE> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buf = [0; MTU];
+        let r = f(&mut buf[..len]);
+        self.switch.with(|i| i.forward(self.index, &buf[..len]));
+        r
+    }
+}
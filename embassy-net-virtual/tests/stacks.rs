@@ -0,0 +1,151 @@
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::{Either3, Either4, select3, select4};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_net_virtual::driver::{HardwareAddress, LinkState};
+use embassy_net_virtual::{Config, Link, Port, Switch};
+use embassy_time::{Duration, Timer, with_timeout};
+
+fn address(host: u8) -> Ipv4Address {
+    Ipv4Address::new(10, 0, 0, host)
+}
+
+fn new_stack<'d, const PORTS: usize>(
+    switch: &'d Switch<PORTS>,
+    host: u8,
+    resources: &'d mut StackResources<4>,
+) -> (Stack<'d>, embassy_net::Runner<'d, Port<'d, PORTS, 1514, 16>>) {
+    let port = switch.port(host as usize - 1, HardwareAddress::Ethernet([2, 0, 0, 0, 0, host]));
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(address(host), 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
+    embassy_net::new(port, config, resources, host as u64)
+}
+
+#[test]
+fn tcp_over_lossy_link() {
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(2);
+    config.jitter = Duration::from_millis(3);
+    config.loss = 0.05;
+    config.reorder = 0.05;
+    let link = Link::new(config);
+
+    let mut resources_a = StackResources::new();
+    let mut resources_b = StackResources::new();
+    let (stack_a, mut runner_a) = new_stack(&link, 1, &mut resources_a);
+    let (stack_b, mut runner_b) = new_stack(&link, 2, &mut resources_b);
+
+    let data: [u8; 16 * 1024] = core::array::from_fn(|i| (i * 7 % 251) as u8);
+
+    let client = async {
+        let (mut rx, mut tx) = ([0; 2048], [0; 2048]);
+        let mut socket = TcpSocket::new(stack_a, &mut rx, &mut tx);
+        socket.connect((address(2), 1234)).await.unwrap();
+        embedded_io_async::Write::write_all(&mut socket, &data).await.unwrap();
+        socket.close();
+        socket.flush().await.unwrap();
+    };
+    let server = async {
+        // Large enough for the window to stay open, smoltcp does not probe closed windows.
+        let (mut rx, mut tx) = ([0; 32 * 1024], [0; 2048]);
+        let mut socket = TcpSocket::new(stack_b, &mut rx, &mut tx);
+        socket.accept(1234).await.unwrap();
+        // One byte more than sent, to read the end of the stream.
+        let mut received = [0; 16 * 1024 + 1];
+        let mut len = 0;
+        loop {
+            match socket.read(&mut received[len..]).await.unwrap() {
+                0 => break,
+                n => len += n,
+            }
+        }
+        assert_eq!(received[..len], data);
+    };
+
+    let test = with_timeout(Duration::from_secs(60), join(client, server));
+    match block_on(select3(runner_a.run(), runner_b.run(), test)) {
+        Either3::Third(r) => r.unwrap(),
+        _ => unreachable!(),
+    };
+
+    let stats = link.stats();
+    assert!(stats.lost > 0);
+    assert_eq!(stats.transmitted, stats.received + stats.lost + stats.dropped);
+}
+
+#[test]
+fn switch_delivers_by_address() {
+    let switch = Switch::<3>::new(Config::default());
+
+    let mut resources_a = StackResources::new();
+    let mut resources_b = StackResources::new();
+    let mut resources_c = StackResources::new();
+    let (stack_a, mut runner_a) = new_stack(&switch, 1, &mut resources_a);
+    let (stack_b, mut runner_b) = new_stack(&switch, 2, &mut resources_b);
+    let (stack_c, mut runner_c) = new_stack(&switch, 3, &mut resources_c);
+
+    let test = async {
+        let mut buffers = [[0; 256]; 6];
+        let mut meta = [[PacketMetadata::EMPTY; 4]; 6];
+        let [rx_a, tx_a, rx_b, tx_b, rx_c, tx_c] = &mut buffers;
+        let [rx_meta_a, tx_meta_a, rx_meta_b, tx_meta_b, rx_meta_c, tx_meta_c] = &mut meta;
+        let mut socket_a = UdpSocket::new(stack_a, rx_meta_a, rx_a, tx_meta_a, tx_a);
+        let mut socket_b = UdpSocket::new(stack_b, rx_meta_b, rx_b, tx_meta_b, tx_b);
+        let mut socket_c = UdpSocket::new(stack_c, rx_meta_c, rx_c, tx_meta_c, tx_c);
+        socket_a.bind(1000).unwrap();
+        socket_b.bind(1000).unwrap();
+        socket_c.bind(1000).unwrap();
+
+        // Broadcasts reach every other port.
+        socket_a.send_to(b"all", (Ipv4Address::BROADCAST, 1000)).await.unwrap();
+        let mut buf = [0; 16];
+        let (n, _) = socket_b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"all");
+        let (n, _) = socket_c.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"all");
+
+        // Unicasts only reach the port having the destination address.
+        socket_a.send_to(b"b", (address(2), 1000)).await.unwrap();
+        let (n, meta) = socket_b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"b");
+        assert_eq!(meta.endpoint.addr, address(1).into());
+        Timer::after_millis(10).await;
+        assert!(socket_c.try_recv_from(&mut buf).is_err());
+    };
+
+    let test = with_timeout(Duration::from_secs(10), test);
+    match block_on(select4(runner_a.run(), runner_b.run(), runner_c.run(), test)) {
+        Either4::Fourth(r) => r.unwrap(),
+        _ => unreachable!(),
+    };
+}
+
+#[test]
+fn link_down() {
+    let link = Link::new(Config::default());
+
+    let mut resources_a = StackResources::new();
+    let mut resources_b = StackResources::new();
+    let (stack_a, mut runner_a) = new_stack(&link, 1, &mut resources_a);
+    let (_stack_b, mut runner_b) = new_stack(&link, 2, &mut resources_b);
+
+    let test = async {
+        stack_a.wait_link_up().await;
+        link.set_link_state(0, LinkState::Down);
+        stack_a.wait_link_down().await;
+        assert!(!stack_a.is_link_up());
+        link.set_link_state(0, LinkState::Up);
+        stack_a.wait_link_up().await;
+    };
+
+    let test = with_timeout(Duration::from_secs(10), test);
+    match block_on(select3(runner_a.run(), runner_b.run(), test)) {
+        Either3::Third(r) => r.unwrap(),
+        _ => unreachable!(),
+    };
+}
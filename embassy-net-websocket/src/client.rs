@@ -0,0 +1,85 @@
+//! Client side of the opening handshake.
+
+use base64ct::{Base64, Encoding};
+use embedded_io_async::{Read, Write};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::frame::MaskGen;
+use crate::http::{self, ACCEPT_LEN, KEY_LEN};
+use crate::{Error, Role, WebSocket};
+
+/// Options of the opening handshake.
+#[derive(Debug, Clone, Copy, Default)]
+#[non_exhaustive]
+pub struct Config<'a> {
+    /// Value of the `Origin` header, which browsers send. Servers may use it to reject connections
+    /// initiated by other sites.
+    pub origin: Option<&'a str>,
+    /// Subprotocols requested, in order of preference.
+    pub protocols: &'a [&'a str],
+    /// Additional header fields, such as `Authorization`.
+    pub headers: &'a [(&'a str, &'a str)],
+}
+
+/// Open a WebSocket connection to `path` on `host`, on a connected `stream`.
+///
+/// `rng` is used to generate the handshake nonce and the masking keys, which must be
+/// unpredictable. `buf` receives the HTTP response.
+///
+/// Returns the connection, and the subprotocol chosen by the server, if any.
+pub async fn connect<'b, S: Read + Write, R: RngCore + CryptoRng>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    config: &Config<'_>,
+    rng: &mut R,
+    buf: &'b mut [u8],
+) -> Result<(WebSocket<S>, Option<&'b str>), Error<S::Error>> {
+    let mut nonce = [0; 16];
+    rng.fill_bytes(&mut nonce);
+    let mut key = [0; KEY_LEN];
+    let key = unwrap!(Base64::encode(&nonce, &mut key).ok());
+
+    #[rustfmt::skip]
+    http::write_parts(&mut stream, &[
+        "GET ", path, " HTTP/1.1\r\n",
+        "Host: ", host, "\r\n",
+        "Upgrade: websocket\r\n",
+        "Connection: Upgrade\r\n",
+        "Sec-WebSocket-Key: ", key, "\r\n",
+        "Sec-WebSocket-Version: 13\r\n",
+    ]).await?;
+    if let Some(origin) = config.origin {
+        http::write_parts(&mut stream, &["Origin: ", origin, "\r\n"]).await?;
+    }
+    for (i, protocol) in config.protocols.iter().enumerate() {
+        let prefix = if i == 0 { "Sec-WebSocket-Protocol: " } else { ", " };
+        http::write_parts(&mut stream, &[prefix, protocol]).await?;
+    }
+    if !config.protocols.is_empty() {
+        http::write_parts(&mut stream, &["\r\n"]).await?;
+    }
+    for (name, value) in config.headers {
+        http::write_parts(&mut stream, &[name, ": ", value, "\r\n"]).await?;
+    }
+    http::write_parts(&mut stream, &["\r\n"]).await?;
+    stream.flush().await.map_err(Error::Io)?;
+
+    let head = http::read_head(&mut stream, buf).await?;
+    let status = head.start.split(' ').nth(1);
+    if status != Some("101") {
+        warn!("websocket: handshake rejected with status {}", status.unwrap_or(""));
+        return Err(Error::Handshake);
+    }
+    let mut accept = [0; ACCEPT_LEN];
+    let upgraded = head.has_token("Upgrade", "websocket")
+        && head.has_token("Connection", "upgrade")
+        && head.header("Sec-WebSocket-Accept") == Some(http::accept_key(key, &mut accept));
+    let protocol = head.header("Sec-WebSocket-Protocol");
+    if !upgraded || protocol.is_some_and(|p| !config.protocols.contains(&p)) {
+        warn!("websocket: invalid handshake response");
+        return Err(Error::Handshake);
+    }
+
+    Ok((WebSocket::new(stream, Role::Client, MaskGen::new(rng)), protocol))
+}
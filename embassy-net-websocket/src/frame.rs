@@ -0,0 +1,149 @@
+//! Frame format, RFC 6455 section 5.2.
+
+use rand_core::{CryptoRng, RngCore};
+use sha1::{Digest, Sha1};
+
+/// Largest header: 2 bytes, 8 bytes of extended payload length and 4 bytes of masking key.
+pub(crate) const MAX_HEADER_LEN: usize = 14;
+
+/// Largest payload of a control frame.
+pub(crate) const MAX_CONTROL_LEN: usize = 125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum OpCode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl OpCode {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xA => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub fin: bool,
+    pub opcode: OpCode,
+    pub mask: Option<[u8; 4]>,
+    pub len: u64,
+}
+
+impl Header {
+    /// Length of the header starting with `buf`, as far as it can be known from the bytes
+    /// already received.
+    pub fn len(buf: &[u8]) -> usize {
+        match buf {
+            [_, b, ..] => {
+                let ext = match b & 0x7F {
+                    126 => 2,
+                    127 => 8,
+                    _ => 0,
+                };
+                let mask = if b & 0x80 != 0 { 4 } else { 0 };
+                2 + ext + mask
+            }
+            _ => 2,
+        }
+    }
+
+    /// Decode a complete header, returning `None` if it uses reserved bits or opcodes.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        // No extension is negotiated, so the RSV bits must be zero.
+        if buf[0] & 0x70 != 0 {
+            return None;
+        }
+        let opcode = OpCode::from_bits(buf[0] & 0x0F)?;
+        let (len, rest) = match buf[1] & 0x7F {
+            126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, &buf[4..]),
+            127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), &buf[10..]),
+            n => (n as u64, &buf[2..]),
+        };
+        let mask = (buf[1] & 0x80 != 0).then(|| [rest[0], rest[1], rest[2], rest[3]]);
+        Some(Self {
+            fin: buf[0] & 0x80 != 0,
+            opcode,
+            mask,
+            len,
+        })
+    }
+
+    /// Encode the header into `buf`, returning its length.
+    pub fn encode(&self, buf: &mut [u8; MAX_HEADER_LEN]) -> usize {
+        buf[0] = (self.fin as u8) << 7 | self.opcode as u8;
+        let mut n = match self.len {
+            0..=125 => {
+                buf[1] = self.len as u8;
+                2
+            }
+            126..=0xFFFF => {
+                buf[1] = 126;
+                buf[2..4].copy_from_slice(&(self.len as u16).to_be_bytes());
+                4
+            }
+            _ => {
+                buf[1] = 127;
+                buf[2..10].copy_from_slice(&self.len.to_be_bytes());
+                10
+            }
+        };
+        if let Some(mask) = self.mask {
+            buf[1] |= 0x80;
+            buf[n..n + 4].copy_from_slice(&mask);
+            n += 4;
+        }
+        n
+    }
+}
+
+/// Mask or unmask `data`, which starts at `offset` in the payload.
+pub(crate) fn apply_mask(mask: [u8; 4], offset: u64, data: &mut [u8]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[((offset + i as u64) % 4) as usize];
+    }
+}
+
+/// Generator of the masking keys of a client.
+///
+/// The keys are hashes of a counter and of a secret drawn from the application's cryptographically
+/// secure random number generator, so that they can't be predicted by the intermediaries between
+/// the client and the server, even from the previous keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MaskGen {
+    secret: [u8; 16],
+    counter: u64,
+}
+
+impl MaskGen {
+    pub fn new<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut secret = [0; 16];
+        rng.fill_bytes(&mut secret);
+        Self { secret, counter: 0 }
+    }
+
+    pub fn next_mask(&mut self) -> [u8; 4] {
+        let hash = Sha1::new()
+            .chain_update(self.secret)
+            .chain_update(self.counter.to_le_bytes())
+            .finalize();
+        self.counter = self.counter.wrapping_add(1);
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+}
@@ -0,0 +1,86 @@
+//! The HTTP/1.1 subset needed by the opening handshake, RFC 6455 section 4.
+
+use base64ct::{Base64, Encoding};
+use embedded_io_async::{Read, Write};
+use sha1::{Digest, Sha1};
+
+use crate::Error;
+
+/// Appended to the key of the client to compute the accept value of the server.
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Length of a base64 encoded key: 16 bytes.
+pub(crate) const KEY_LEN: usize = 24;
+
+/// Length of a base64 encoded accept value: a SHA-1 digest.
+pub(crate) const ACCEPT_LEN: usize = 28;
+
+/// Compute the `Sec-WebSocket-Accept` value answering `key`.
+pub(crate) fn accept_key<'a>(key: &str, buf: &'a mut [u8; ACCEPT_LEN]) -> &'a str {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID);
+    unwrap!(Base64::encode(&sha1.finalize(), buf).ok())
+}
+
+/// Read the head of an HTTP message into `buf`, up to the empty line.
+///
+/// The stream is read byte by byte, so that the bytes following the head, such as the first
+/// frames, are left in the stream.
+pub(crate) async fn read_head<'a, S: Read>(stream: &mut S, buf: &'a mut [u8]) -> Result<Head<'a>, Error<S::Error>> {
+    let mut len = 0;
+    while !buf[..len].ends_with(b"\r\n\r\n") {
+        if len == buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        stream.read_exact(&mut buf[len..len + 1]).await?;
+        len += 1;
+    }
+    let head = core::str::from_utf8(&buf[..len - 4]).map_err(|_| Error::Handshake)?;
+    let (start, headers) = head.split_once("\r\n").unwrap_or((head, ""));
+    Ok(Head { start, headers })
+}
+
+/// Head of an HTTP request or response.
+pub(crate) struct Head<'a> {
+    /// Request line or status line.
+    pub start: &'a str,
+    headers: &'a str,
+}
+
+impl<'a> Head<'a> {
+    /// Values of the header fields named `name`.
+    pub fn headers(&self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .filter(move |(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    /// Value of the first header field named `name`.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers(name).next()
+    }
+
+    /// Items of the comma separated lists of the header fields named `name`.
+    pub fn list(&self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.headers(name)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+    }
+
+    /// Whether the header fields named `name` contain `token`, case insensitively.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.list(name).any(|item| item.eq_ignore_ascii_case(token))
+    }
+}
+
+/// Write the concatenation of `parts`.
+pub(crate) async fn write_parts<S: Write>(stream: &mut S, parts: &[&str]) -> Result<(), Error<S::Error>> {
+    for part in parts {
+        stream.write_all(part.as_bytes()).await.map_err(Error::Io)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,679 @@
+#![no_std]
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+// must be first
+mod fmt;
+
+pub mod client;
+mod frame;
+mod http;
+pub mod server;
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, ReadExactError, Write};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::frame::{Header, MAX_CONTROL_LEN, MAX_HEADER_LEN, MaskGen, OpCode};
+
+/// Error returned by the WebSocket functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Error of the underlying stream.
+    Io(E),
+    /// The stream ended without a close handshake.
+    UnexpectedEof,
+    /// The opening handshake failed: the HTTP request or response is invalid, or is not a
+    /// WebSocket upgrade.
+    Handshake,
+    /// The peer violated the protocol. A close frame was sent, and the connection can't be used anymore.
+    Protocol,
+    /// A text message is not valid UTF-8. A close frame was sent, and the connection can't be used anymore.
+    InvalidUtf8,
+    /// The buffer is too small for the HTTP head, or for the message. The rest of the message is
+    /// discarded.
+    BufferTooSmall,
+    /// The payload of a ping, or the reason of a close frame, is too long for a control frame.
+    ControlTooLarge,
+    /// The connection is closed, in the direction of the operation.
+    Closed,
+}
+
+impl<E> From<ReadExactError<E>> for Error<E> {
+    fn from(e: ReadExactError<E>) -> Self {
+        match e {
+            ReadExactError::UnexpectedEof => Self::UnexpectedEof,
+            ReadExactError::Other(e) => Self::Io(e),
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {:?}", e),
+            Self::UnexpectedEof => write!(f, "Unexpected end of stream"),
+            Self::Handshake => write!(f, "Handshake failed"),
+            Self::Protocol => write!(f, "Protocol error"),
+            Self::InvalidUtf8 => write!(f, "Invalid UTF-8 in text message"),
+            Self::BufferTooSmall => write!(f, "Buffer too small"),
+            Self::ControlTooLarge => write!(f, "Control frame payload too large"),
+            Self::Closed => write!(f, "Closed"),
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::error::Error for Error<E> {}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Io(e) => e.kind(),
+            Self::UnexpectedEof => ErrorKind::ConnectionAborted,
+            Self::Handshake | Self::Protocol | Self::InvalidUtf8 => ErrorKind::InvalidData,
+            Self::BufferTooSmall | Self::ControlTooLarge => ErrorKind::InvalidInput,
+            Self::Closed => ErrorKind::NotConnected,
+        }
+    }
+}
+
+/// Type of a data message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageType {
+    /// UTF-8 text.
+    Text,
+    /// Binary data.
+    Binary,
+}
+
+impl From<MessageType> for OpCode {
+    fn from(ty: MessageType) -> Self {
+        match ty {
+            MessageType::Text => OpCode::Text,
+            MessageType::Binary => OpCode::Binary,
+        }
+    }
+}
+
+/// Message received with [`WebSocket::read_message`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Message<'a> {
+    /// A text message.
+    Text(&'a str),
+    /// A binary message.
+    Binary(&'a [u8]),
+    /// The peer closed the connection, with an optional status.
+    ///
+    /// The close handshake was answered, the stream can now be closed.
+    Close(Option<CloseFrame<'a>>),
+}
+
+/// Status of a close frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CloseFrame<'a> {
+    /// Status code.
+    pub code: CloseCode,
+    /// Reason, for debugging.
+    pub reason: &'a str,
+}
+
+/// Status code of a close frame, see RFC 6455 section 7.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    /// The purpose of the connection was fulfilled.
+    pub const NORMAL: Self = Self(1000);
+    /// The endpoint is going away, such as a server shutting down.
+    pub const GOING_AWAY: Self = Self(1001);
+    /// The connection is failed because of a protocol error.
+    pub const PROTOCOL_ERROR: Self = Self(1002);
+    /// The endpoint can't accept this type of data.
+    pub const UNSUPPORTED_DATA: Self = Self(1003);
+    /// A message contains data inconsistent with its type, such as invalid UTF-8 in a text message.
+    pub const INVALID_PAYLOAD: Self = Self(1007);
+    /// A message violates the policy of the endpoint.
+    pub const POLICY_VIOLATION: Self = Self(1008);
+    /// A message is too big to process.
+    pub const MESSAGE_TOO_BIG: Self = Self(1009);
+    /// The server encountered an unexpected condition.
+    pub const INTERNAL_ERROR: Self = Self(1011);
+
+    /// Whether the code can be sent in a close frame.
+    fn is_valid(self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Client,
+    Server,
+}
+
+/// Data frame being received.
+#[derive(Debug, Clone, Copy, Default)]
+struct DataFrame {
+    /// Payload bytes not read yet.
+    remaining: u64,
+    /// Payload bytes read.
+    offset: u64,
+    mask: Option<[u8; 4]>,
+    fin: bool,
+    /// A message is being received: the rest of this frame, or further frames, are part of it.
+    in_message: bool,
+}
+
+/// Control frame being received, or to send.
+#[derive(Debug, Clone, Copy)]
+struct ControlFrame {
+    opcode: OpCode,
+    mask: Option<[u8; 4]>,
+    len: usize,
+    read: usize,
+    buf: [u8; MAX_CONTROL_LEN],
+}
+
+/// A WebSocket connection, on an [`embedded-io-async`](embedded_io_async) stream such as an
+/// `embassy-net` `TcpSocket` or a TLS session.
+///
+/// Create it with [`client::connect`] or [`server::Request::accept`], which perform the opening
+/// handshake.
+///
+/// Pings are answered while reading, so the application should keep reading the connection to
+/// keep it alive. Reading is cancel-safe: if [`read_message`](Self::read_message) is cancelled,
+/// for example by a `select` with a timer, the message being received is discarded but the
+/// connection stays usable.
+///
+/// Writes are not flushed: call [`flush`](Self::flush) when needed by the stream, for example with
+/// TLS to send the records.
+pub struct WebSocket<S> {
+    stream: S,
+    role: Role,
+    /// Masking keys, used by clients only.
+    masks: MaskGen,
+
+    header: [u8; MAX_HEADER_LEN],
+    header_len: usize,
+    data: DataFrame,
+    control: Option<ControlFrame>,
+    /// Pong or close frame answering a control frame, to send.
+    reply: Option<ControlFrame>,
+    /// Payload of the close frame received.
+    close: Option<ControlFrame>,
+    /// A fragmented message is being sent.
+    tx_in_message: bool,
+    rx_closed: bool,
+    tx_closed: bool,
+}
+
+impl<S> WebSocket<S> {
+    fn new(stream: S, role: Role, masks: MaskGen) -> Self {
+        Self {
+            stream,
+            role,
+            masks,
+            header: [0; MAX_HEADER_LEN],
+            header_len: 0,
+            data: DataFrame::default(),
+            control: None,
+            reply: None,
+            close: None,
+            tx_in_message: false,
+            rx_closed: false,
+            tx_closed: false,
+        }
+    }
+
+    /// Create the client side of a connection on `stream`, on which the opening handshake was
+    /// already done.
+    ///
+    /// `rng` is used to generate the masking keys, which must be unpredictable.
+    pub fn new_client<R: RngCore + CryptoRng>(stream: S, rng: &mut R) -> Self {
+        Self::new(stream, Role::Client, MaskGen::new(rng))
+    }
+
+    /// Create the server side of a connection on `stream`, on which the opening handshake was
+    /// already done.
+    pub fn new_server(stream: S) -> Self {
+        Self::new(stream, Role::Server, MaskGen::default())
+    }
+
+    /// Get the close frame received from the peer, if any.
+    pub fn close_frame(&self) -> Option<CloseFrame<'_>> {
+        let close = self.close.as_ref()?;
+        let [hi, lo, reason @ ..] = &close.buf[..close.len] else {
+            return None;
+        };
+        Some(CloseFrame {
+            code: CloseCode(u16::from_be_bytes([*hi, *lo])),
+            reason: core::str::from_utf8(reason).unwrap_or_default(),
+        })
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Consume the connection, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// Read the next data message into `buf`.
+    ///
+    /// Fragmented messages are reassembled. Pings received meanwhile are answered.
+    ///
+    /// When the peer closes the connection, the close frame is answered and returned as
+    /// [`Message::Close`]. Reading again then returns [`Error::Closed`].
+    pub async fn read_message<'a>(&'a mut self, buf: &'a mut [u8]) -> Result<Message<'a>, Error<S::Error>> {
+        self.skip_message().await?;
+        let ty = match self.next_frame().await? {
+            Some(OpCode::Text) => MessageType::Text,
+            Some(_) => MessageType::Binary,
+            None => return Ok(Message::Close(self.close_frame())),
+        };
+
+        let mut len = 0;
+        loop {
+            if len == buf.len() {
+                if self.read_data(&mut [0]).await? > 0 {
+                    self.skip_message().await?;
+                    return Err(Error::BufferTooSmall);
+                }
+                break;
+            }
+            match self.read_data(&mut buf[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+
+        match ty {
+            MessageType::Text => match core::str::from_utf8(&buf[..len]) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(CloseCode::INVALID_PAYLOAD, Error::InvalidUtf8).await),
+            },
+            MessageType::Binary => Ok(Message::Binary(&buf[..len])),
+        }
+    }
+
+    /// Wait for the next data message, and return a reader for its payload.
+    ///
+    /// This allows receiving messages larger than the available memory. The reader returns 0 at
+    /// the end of the message. Unlike [`read_message`](Self::read_message), the UTF-8 encoding of
+    /// text messages is not checked.
+    ///
+    /// When the peer closes the connection, the close frame is answered and [`Error::Closed`] is
+    /// returned. The status is available with [`close_frame`](Self::close_frame).
+    pub async fn next_message(&mut self) -> Result<MessageReader<'_, S>, Error<S::Error>> {
+        self.skip_message().await?;
+        let ty = match self.next_frame().await? {
+            Some(OpCode::Text) => MessageType::Text,
+            Some(_) => MessageType::Binary,
+            None => return Err(Error::Closed),
+        };
+        Ok(MessageReader { ws: self, ty })
+    }
+
+    /// Send a data message, in a single frame.
+    pub async fn write_message(&mut self, ty: MessageType, data: &[u8]) -> Result<(), Error<S::Error>> {
+        self.start_data().await?;
+        self.write_frame(true, ty.into(), data).await
+    }
+
+    /// Start sending a fragmented message, and return a writer for its payload.
+    ///
+    /// Each write sends a frame. The message ends with [`MessageWriter::finish`], or when another
+    /// message is sent.
+    pub async fn start_message(&mut self, ty: MessageType) -> Result<MessageWriter<'_, S>, Error<S::Error>> {
+        self.start_data().await?;
+        Ok(MessageWriter {
+            ws: self,
+            opcode: ty.into(),
+        })
+    }
+
+    /// Send a ping, which the peer answers with a pong carrying the same `data`.
+    pub async fn ping(&mut self, data: &[u8]) -> Result<(), Error<S::Error>> {
+        if self.tx_closed {
+            return Err(Error::Closed);
+        }
+        if data.len() > MAX_CONTROL_LEN {
+            return Err(Error::ControlTooLarge);
+        }
+        self.write_frame(true, OpCode::Ping, data).await
+    }
+
+    /// Start the close handshake, with a status `code` and `reason`.
+    ///
+    /// No message can be sent afterwards. The messages sent by the peer meanwhile can still be
+    /// read, until [`read_message`](Self::read_message) returns [`Message::Close`], answering
+    /// this one. The stream can then be closed.
+    pub async fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), Error<S::Error>> {
+        if self.tx_closed {
+            return Err(Error::Closed);
+        }
+        if 2 + reason.len() > MAX_CONTROL_LEN {
+            return Err(Error::ControlTooLarge);
+        }
+        let mut payload = [0; MAX_CONTROL_LEN];
+        payload[..2].copy_from_slice(&code.0.to_be_bytes());
+        payload[2..2 + reason.len()].copy_from_slice(reason.as_bytes());
+        self.tx_closed = true;
+        self.write_frame(true, OpCode::Close, &payload[..2 + reason.len()])
+            .await?;
+        self.flush().await
+    }
+
+    /// Flush the underlying stream.
+    pub async fn flush(&mut self) -> Result<(), Error<S::Error>> {
+        self.stream.flush().await.map_err(Error::Io)
+    }
+
+    /// Read the payload of the current message, returning 0 at its end.
+    async fn read_data(&mut self, buf: &mut [u8]) -> Result<usize, Error<S::Error>> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.data.remaining > 0 {
+                break;
+            }
+            if !self.data.in_message {
+                return Ok(0);
+            }
+            if self.next_frame().await?.is_none() {
+                return Err(Error::Closed);
+            }
+        }
+
+        let len = buf.len().min(self.data.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.stream.read(&mut buf[..len]).await.map_err(Error::Io)?;
+        if n == 0 {
+            return Err(Error::UnexpectedEof);
+        }
+        if let Some(mask) = self.data.mask {
+            frame::apply_mask(mask, self.data.offset, &mut buf[..n]);
+        }
+        self.data.offset += n as u64;
+        self.data.remaining -= n as u64;
+        if self.data.remaining == 0 && self.data.fin {
+            self.data.in_message = false;
+        }
+        Ok(n)
+    }
+
+    /// Discard the rest of the current message, if it was not read completely.
+    async fn skip_message(&mut self) -> Result<(), Error<S::Error>> {
+        let mut buf = [0; 64];
+        while self.read_data(&mut buf).await? > 0 {}
+        Ok(())
+    }
+
+    /// Receive frames until the next data frame, handling the control frames.
+    ///
+    /// Returns the opcode of the data frame, or `None` if the peer closed the connection.
+    async fn next_frame(&mut self) -> Result<Option<OpCode>, Error<S::Error>> {
+        loop {
+            self.send_reply().await?;
+            if self.rx_closed {
+                return Err(Error::Closed);
+            }
+
+            if let Some(control) = &mut self.control {
+                while control.read < control.len {
+                    let n = self
+                        .stream
+                        .read(&mut control.buf[control.read..control.len])
+                        .await
+                        .map_err(Error::Io)?;
+                    if n == 0 {
+                        return Err(Error::UnexpectedEof);
+                    }
+                    control.read += n;
+                }
+                let mut control = unwrap!(self.control.take());
+                if let Some(mask) = control.mask {
+                    frame::apply_mask(mask, 0, &mut control.buf[..control.len]);
+                }
+                if self.handle_control(control).await? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let header = self.read_header().await?;
+            if header.opcode.is_control() {
+                self.control = Some(ControlFrame {
+                    opcode: header.opcode,
+                    mask: header.mask,
+                    len: header.len as usize,
+                    read: 0,
+                    buf: [0; MAX_CONTROL_LEN],
+                });
+                continue;
+            }
+
+            // Continuation frames, and only them, continue a fragmented message.
+            if (header.opcode == OpCode::Continuation) != self.data.in_message {
+                return Err(self.fail(CloseCode::PROTOCOL_ERROR, Error::Protocol).await);
+            }
+            self.data = DataFrame {
+                remaining: header.len,
+                offset: 0,
+                mask: header.mask,
+                fin: header.fin,
+                in_message: !(header.fin && header.len == 0),
+            };
+            return Ok(Some(header.opcode));
+        }
+    }
+
+    /// Read and check a frame header.
+    async fn read_header(&mut self) -> Result<Header, Error<S::Error>> {
+        // The bytes received are kept in `self`, so that this is cancel-safe.
+        loop {
+            let len = Header::len(&self.header[..self.header_len]);
+            if self.header_len == len {
+                break;
+            }
+            let n = self
+                .stream
+                .read(&mut self.header[self.header_len..len])
+                .await
+                .map_err(Error::Io)?;
+            if n == 0 {
+                return Err(Error::UnexpectedEof);
+            }
+            self.header_len += n;
+        }
+        let header = Header::decode(&self.header[..self.header_len]);
+        self.header_len = 0;
+
+        let Some(header) = header else {
+            return Err(self.fail(CloseCode::PROTOCOL_ERROR, Error::Protocol).await);
+        };
+        // Clients mask all their frames, and servers none.
+        let masked = self.role == Role::Server;
+        let control_valid = !header.opcode.is_control() || (header.fin && header.len <= MAX_CONTROL_LEN as u64);
+        if header.mask.is_some() != masked || !control_valid {
+            return Err(self.fail(CloseCode::PROTOCOL_ERROR, Error::Protocol).await);
+        }
+        Ok(header)
+    }
+
+    /// Handle a control frame, returning whether it closed the connection.
+    async fn handle_control(&mut self, control: ControlFrame) -> Result<bool, Error<S::Error>> {
+        let payload = &control.buf[..control.len];
+        match control.opcode {
+            OpCode::Ping => {
+                trace!("websocket: ping");
+                if !self.tx_closed {
+                    self.reply = Some(ControlFrame {
+                        opcode: OpCode::Pong,
+                        ..control
+                    });
+                }
+                Ok(false)
+            }
+            OpCode::Close => {
+                // The payload is empty, or a status code followed by a UTF-8 reason.
+                let valid = match payload {
+                    [] => true,
+                    [_] => false,
+                    [hi, lo, reason @ ..] => {
+                        CloseCode(u16::from_be_bytes([*hi, *lo])).is_valid() && core::str::from_utf8(reason).is_ok()
+                    }
+                };
+                if !valid {
+                    return Err(self.fail(CloseCode::PROTOCOL_ERROR, Error::Protocol).await);
+                }
+                debug!("websocket: close received");
+                self.rx_closed = true;
+                self.close = Some(control);
+                if !self.tx_closed {
+                    // Echo the status code.
+                    self.tx_closed = true;
+                    self.reply = Some(ControlFrame {
+                        len: control.len.min(2),
+                        ..control
+                    });
+                }
+                self.send_reply().await?;
+                Ok(true)
+            }
+            // Pongs may be unsolicited, and pings sent are not tracked.
+            _ => Ok(false),
+        }
+    }
+
+    /// Send the frame answering the last control frame received, if any.
+    async fn send_reply(&mut self) -> Result<(), Error<S::Error>> {
+        if let Some(reply) = self.reply.take() {
+            self.write_frame(true, reply.opcode, &reply.buf[..reply.len]).await?;
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Fail the connection: send a close frame with `code` if none was sent yet, and stop reading.
+    async fn fail(&mut self, code: CloseCode, error: Error<S::Error>) -> Error<S::Error> {
+        warn!("websocket: failing the connection with code {}", code.0);
+        self.rx_closed = true;
+        if !self.tx_closed {
+            self.tx_closed = true;
+            let _ = self.write_frame(true, OpCode::Close, &code.0.to_be_bytes()).await;
+            let _ = self.flush().await;
+        }
+        error
+    }
+
+    /// Prepare to send a data message.
+    async fn start_data(&mut self) -> Result<(), Error<S::Error>> {
+        if self.tx_closed {
+            return Err(Error::Closed);
+        }
+        // End the fragmented message of a writer dropped without `finish`.
+        if self.tx_in_message {
+            self.tx_in_message = false;
+            self.write_frame(true, OpCode::Continuation, &[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, fin: bool, opcode: OpCode, payload: &[u8]) -> Result<(), Error<S::Error>> {
+        let mask = match self.role {
+            Role::Client => Some(self.masks.next_mask()),
+            Role::Server => None,
+        };
+        let mut header = [0; MAX_HEADER_LEN];
+        let len = Header {
+            fin,
+            opcode,
+            mask,
+            len: payload.len() as u64,
+        }
+        .encode(&mut header);
+        self.stream.write_all(&header[..len]).await.map_err(Error::Io)?;
+
+        match mask {
+            None => self.stream.write_all(payload).await.map_err(Error::Io)?,
+            Some(mask) => {
+                // Mask the payload in chunks, without modifying it.
+                let mut buf = [0; 128];
+                for (i, chunk) in payload.chunks(buf.len()).enumerate() {
+                    let buf = &mut buf[..chunk.len()];
+                    buf.copy_from_slice(chunk);
+                    frame::apply_mask(mask, (i * 128) as u64, buf);
+                    self.stream.write_all(buf).await.map_err(Error::Io)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reader for the payload of a message, see [`WebSocket::next_message`].
+pub struct MessageReader<'a, S> {
+    ws: &'a mut WebSocket<S>,
+    ty: MessageType,
+}
+
+impl<S> MessageReader<'_, S> {
+    /// Get the type of the message.
+    pub fn message_type(&self) -> MessageType {
+        self.ty
+    }
+}
+
+impl<S: ErrorType> ErrorType for MessageReader<'_, S> {
+    type Error = Error<S::Error>;
+}
+
+impl<S: Read + Write> Read for MessageReader<'_, S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.ws.read_data(buf).await
+    }
+}
+
+/// Writer for the payload of a fragmented message, see [`WebSocket::start_message`].
+pub struct MessageWriter<'a, S> {
+    ws: &'a mut WebSocket<S>,
+    opcode: OpCode,
+}
+
+impl<S: Read + Write> MessageWriter<'_, S> {
+    /// End the message.
+    pub async fn finish(self) -> Result<(), Error<S::Error>> {
+        self.ws.tx_in_message = false;
+        self.ws.write_frame(true, self.opcode, &[]).await
+    }
+}
+
+impl<S: ErrorType> ErrorType for MessageWriter<'_, S> {
+    type Error = Error<S::Error>;
+}
+
+impl<S: Read + Write> Write for MessageWriter<'_, S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.ws.write_frame(false, self.opcode, buf).await?;
+        self.ws.tx_in_message = true;
+        self.opcode = OpCode::Continuation;
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.ws.flush().await
+    }
+}
@@ -0,0 +1,128 @@
+//! Server side of the opening handshake.
+
+use base64ct::{Base64, Encoding};
+use embedded_io_async::{Read, Write};
+
+use crate::http::{self, ACCEPT_LEN, Head};
+use crate::{Error, WebSocket};
+
+/// HTTP request received by a server.
+///
+/// A server reads the request with [`Request::read`], then checks it, for example its
+/// [`path`](Self::path), and answers with [`accept`](Self::accept) or [`reject`](Self::reject).
+/// Requests which are not WebSocket upgrades can also be answered by the application, on the same
+/// stream.
+pub struct Request<'a> {
+    head: Head<'a>,
+    method: &'a str,
+    path: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Read the head of an HTTP request from `stream` into `buf`.
+    ///
+    /// The body of the request, if any, is not read.
+    pub async fn read<S: Read>(stream: &mut S, buf: &'a mut [u8]) -> Result<Self, Error<S::Error>> {
+        let head = http::read_head(stream, buf).await?;
+        let mut start = head.start.split(' ');
+        let (Some(method), Some(path), Some(version), None) = (start.next(), start.next(), start.next(), start.next())
+        else {
+            return Err(Error::Handshake);
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(Error::Handshake);
+        }
+        Ok(Self { head, method, path })
+    }
+
+    /// Get the method of the request.
+    pub fn method(&self) -> &'a str {
+        self.method
+    }
+
+    /// Get the target of the request, such as `/ws` or `/ws?token=1`.
+    pub fn path(&self) -> &'a str {
+        self.path
+    }
+
+    /// Get the value of the first header field named `name`, case insensitively.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.head.header(name)
+    }
+
+    /// Whether the request is a WebSocket upgrade.
+    pub fn is_websocket(&self) -> bool {
+        self.method == "GET"
+            && self.head.has_token("Upgrade", "websocket")
+            && self.head.has_token("Connection", "upgrade")
+            && self.head.header("Sec-WebSocket-Key").is_some()
+    }
+
+    /// Get the subprotocols requested by the client, in order of preference.
+    pub fn protocols(&self) -> impl Iterator<Item = &'a str> {
+        self.head.list("Sec-WebSocket-Protocol")
+    }
+
+    /// Accept the WebSocket upgrade, with one of the [`protocols`](Self::protocols) requested by
+    /// the client, or none.
+    ///
+    /// If the request is not a valid upgrade, it is rejected and [`Error::Handshake`] is returned.
+    pub async fn accept<S: Read + Write>(
+        self,
+        mut stream: S,
+        protocol: Option<&str>,
+    ) -> Result<WebSocket<S>, Error<S::Error>> {
+        let key = self.head.header("Sec-WebSocket-Key").unwrap_or_default();
+        let key_valid = matches!(Base64::decode(key, &mut [0; 16]), Ok(nonce) if nonce.len() == 16);
+        if !self.is_websocket() || !key_valid {
+            warn!("websocket: invalid upgrade request");
+            self.reject(&mut stream, 400).await?;
+            return Err(Error::Handshake);
+        }
+        if self.head.header("Sec-WebSocket-Version") != Some("13") {
+            warn!("websocket: unsupported version");
+            #[rustfmt::skip]
+            http::write_parts(&mut stream, &[
+                "HTTP/1.1 426 Upgrade Required\r\n",
+                "Sec-WebSocket-Version: 13\r\n",
+                "Content-Length: 0\r\n",
+                "\r\n",
+            ]).await?;
+            stream.flush().await.map_err(Error::Io)?;
+            return Err(Error::Handshake);
+        }
+
+        let mut accept = [0; ACCEPT_LEN];
+        #[rustfmt::skip]
+        http::write_parts(&mut stream, &[
+            "HTTP/1.1 101 Switching Protocols\r\n",
+            "Upgrade: websocket\r\n",
+            "Connection: Upgrade\r\n",
+            "Sec-WebSocket-Accept: ", http::accept_key(key, &mut accept), "\r\n",
+        ]).await?;
+        if let Some(protocol) = protocol {
+            http::write_parts(&mut stream, &["Sec-WebSocket-Protocol: ", protocol, "\r\n"]).await?;
+        }
+        http::write_parts(&mut stream, &["\r\n"]).await?;
+        stream.flush().await.map_err(Error::Io)?;
+
+        Ok(WebSocket::new_server(stream))
+    }
+
+    /// Reject the request with an empty response with HTTP `status`, such as 404.
+    pub async fn reject<S: Write>(self, stream: &mut S, status: u16) -> Result<(), Error<S::Error>> {
+        let digits = [
+            b'0' + (status / 100 % 10) as u8,
+            b'0' + (status / 10 % 10) as u8,
+            b'0' + (status % 10) as u8,
+        ];
+        #[rustfmt::skip]
+        http::write_parts(stream, &[
+            "HTTP/1.1 ", unwrap!(core::str::from_utf8(&digits).ok()), " \r\n",
+            "Content-Length: 0\r\n",
+            "Connection: close\r\n",
+            "\r\n",
+        ]).await?;
+        stream.flush().await.map_err(Error::Io)
+    }
+}
@@ -0,0 +1,331 @@
+use core::convert::Infallible;
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_net_websocket::server::Request;
+use embassy_net_websocket::{CloseCode, CloseFrame, Error, Message, MessageType, client};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::{Pipe, Reader, Writer};
+use embedded_io_async::{ErrorType, Read, Write};
+use rand_core::{CryptoRng, RngCore};
+
+/// One end of an in-memory stream.
+struct Port<'a> {
+    rx: Reader<'a, NoopRawMutex, 1024>,
+    tx: Writer<'a, NoopRawMutex, 1024>,
+}
+
+impl ErrorType for Port<'_> {
+    type Error = Infallible;
+}
+
+impl Read for Port<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        Ok(self.rx.read(buf).await)
+    }
+}
+
+impl Write for Port<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        Ok(self.tx.write(buf).await)
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Deterministic generator, standing in for a hardware one.
+struct TestRng(u64);
+
+impl RngCore for TestRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        for chunk in dst.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+impl CryptoRng for TestRng {}
+
+fn link<'a>(a: &'a mut Pipe<NoopRawMutex, 1024>, b: &'a mut Pipe<NoopRawMutex, 1024>) -> (Port<'a>, Port<'a>) {
+    let (a_rx, a_tx) = a.split();
+    let (b_rx, b_tx) = b.split();
+    (Port { rx: a_rx, tx: b_tx }, Port { rx: b_rx, tx: a_tx })
+}
+
+#[test]
+fn client_and_server() {
+    let (mut a, mut b) = (Pipe::new(), Pipe::new());
+    let (client_port, mut server_port) = link(&mut a, &mut b);
+
+    let client = async {
+        let mut buf = [0; 512];
+        let mut config = client::Config::default();
+        config.protocols = &["v2.dashboard", "v1.dashboard"];
+        let (mut ws, protocol) =
+            client::connect(client_port, "device.local", "/ws", &config, &mut TestRng(42), &mut buf)
+                .await
+                .unwrap();
+        assert_eq!(protocol, Some("v1.dashboard"));
+
+        ws.write_message(MessageType::Text, b"hello").await.unwrap();
+        // Larger than the masking chunks, and than the 7 bit length.
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        ws.write_message(MessageType::Binary, &data).await.unwrap();
+        ws.ping(b"ping").await.unwrap();
+
+        let mut buf = [0; 512];
+        assert_eq!(ws.read_message(&mut buf).await, Ok(Message::Text("fragmented message")));
+        assert_eq!(ws.read_message(&mut buf[..4]).await, Err(Error::BufferTooSmall));
+        assert_eq!(ws.read_message(&mut buf).await, Ok(Message::Binary(&[1, 2, 3])));
+
+        // Streamed.
+        let mut reader = ws.next_message().await.unwrap();
+        assert_eq!(reader.message_type(), MessageType::Binary);
+        let mut received = Vec::new();
+        let mut chunk = [0; 7];
+        loop {
+            match reader.read(&mut chunk).await.unwrap() {
+                0 => break,
+                n => received.extend_from_slice(&chunk[..n]),
+            }
+        }
+        assert_eq!(received, data);
+
+        assert_eq!(
+            ws.read_message(&mut buf).await,
+            Ok(Message::Close(Some(CloseFrame {
+                code: CloseCode::GOING_AWAY,
+                reason: "bye",
+            })))
+        );
+        assert_eq!(ws.read_message(&mut buf).await, Err(Error::Closed));
+        assert_eq!(ws.write_message(MessageType::Text, b"late").await, Err(Error::Closed));
+    };
+
+    let server = async {
+        let mut buf = [0; 512];
+        let request = Request::read(&mut server_port, &mut buf).await.unwrap();
+        assert_eq!(request.path(), "/ws");
+        assert_eq!(request.header("host"), Some("device.local"));
+        assert!(request.is_websocket());
+        let protocol = request.protocols().find(|p| *p == "v1.dashboard");
+        let mut ws = request.accept(server_port, protocol).await.unwrap();
+
+        let mut buf = [0; 512];
+        assert_eq!(ws.read_message(&mut buf).await, Ok(Message::Text("hello")));
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        assert_eq!(ws.read_message(&mut buf).await, Ok(Message::Binary(&data)));
+
+        let mut writer = ws.start_message(MessageType::Text).await.unwrap();
+        writer.write_all(b"fragmented").await.unwrap();
+        writer.write_all(b" message").await.unwrap();
+        writer.finish().await.unwrap();
+        ws.write_message(MessageType::Text, b"too long").await.unwrap();
+        // A writer dropped without `finish` is ended by the next message.
+        {
+            let mut writer = ws.start_message(MessageType::Binary).await.unwrap();
+            writer.write_all(&[1, 2]).await.unwrap();
+            writer.write_all(&[3]).await.unwrap();
+        }
+        ws.write_message(MessageType::Binary, &data).await.unwrap();
+
+        ws.close(CloseCode::GOING_AWAY, "bye").await.unwrap();
+        // The client answers the close, after the pong of its ping.
+        assert_eq!(
+            ws.read_message(&mut buf).await,
+            Ok(Message::Close(Some(CloseFrame {
+                code: CloseCode::GOING_AWAY,
+                reason: "",
+            })))
+        );
+    };
+
+    block_on(join(client, server));
+}
+
+/// Read what is available, which is a whole response or frame in these tests.
+async fn read_some(port: &mut Port<'_>) -> Vec<u8> {
+    let mut buf = [0; 512];
+    let n = port.read(&mut buf).await.unwrap();
+    buf[..n].to_vec()
+}
+
+#[test]
+fn server_rfc_examples() {
+    let (mut a, mut b) = (Pipe::new(), Pipe::new());
+    let (mut client, mut server_port) = link(&mut a, &mut b);
+
+    let client = async {
+        // Example of RFC 6455 section 1.3.
+        client
+            .write_all(
+                b"GET /chat HTTP/1.1\r\n\
+                Host: server.example.com\r\n\
+                Upgrade: websocket\r\n\
+                Connection: keep-alive, Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Origin: http://example.com\r\n\
+                Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let response = read_some(&mut client).await;
+        let response = core::str::from_utf8(&response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        // Examples of RFC 6455 section 5.7: a masked "Hello", a fragmented one, and a ping.
+        client
+            .write_all(&[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58])
+            .await
+            .unwrap();
+        assert_eq!(read_some(&mut client).await, [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        client
+            .write_all(&[0x01, 0x83, 0, 0, 0, 0, 0x48, 0x65, 0x6c])
+            .await
+            .unwrap();
+        client.write_all(&[0x89, 0x81, 0, 0, 0, 0, 0x21]).await.unwrap();
+        client.write_all(&[0x80, 0x82, 0, 0, 0, 0, 0x6c, 0x6f]).await.unwrap();
+        // The pong is sent while reading the message, before the answer.
+        assert_eq!(
+            read_some(&mut client).await,
+            [0x8a, 0x01, 0x21, 0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+
+        // Clients must mask their frames.
+        client.write_all(&[0x81, 0x01, 0x48]).await.unwrap();
+        assert_eq!(read_some(&mut client).await, [0x88, 0x02, 0x03, 0xea]);
+    };
+
+    let server = async {
+        let mut buf = [0; 512];
+        let request = Request::read(&mut server_port, &mut buf).await.unwrap();
+        let mut ws = request.accept(server_port, None).await.unwrap();
+
+        let mut buf = [0; 512];
+        for _ in 0..2 {
+            let Ok(Message::Text(text)) = ws.read_message(&mut buf).await else {
+                panic!()
+            };
+            assert_eq!(text, "Hello");
+            ws.write_message(MessageType::Text, b"Hello").await.unwrap();
+        }
+        assert_eq!(ws.read_message(&mut buf).await, Err(Error::Protocol));
+    };
+
+    block_on(join(client, server));
+}
+
+/// Send `frames` from a client after the opening handshake, and check the message read by the
+/// server, and the frames it replies with.
+fn check_server(frames: &[&[u8]], expected: Result<Message<'_>, Error<Infallible>>, reply: &[u8]) {
+    let (mut a, mut b) = (Pipe::new(), Pipe::new());
+    let (mut client, mut server_port) = link(&mut a, &mut b);
+
+    let client = async {
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                Host: device.local\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        assert!(read_some(&mut client).await.starts_with(b"HTTP/1.1 101 "));
+        for frame in frames {
+            client.write_all(frame).await.unwrap();
+        }
+        if !reply.is_empty() {
+            assert_eq!(read_some(&mut client).await, reply);
+        }
+    };
+
+    let server = async {
+        let mut buf = [0; 512];
+        let request = Request::read(&mut server_port, &mut buf).await.unwrap();
+        let mut ws = request.accept(server_port, None).await.unwrap();
+        let mut buf = [0; 512];
+        assert_eq!(ws.read_message(&mut buf).await, expected);
+    };
+
+    block_on(join(client, server));
+}
+
+/// Close frame with the protocol error status.
+const CLOSE_PROTOCOL_ERROR: [u8; 4] = [0x88, 0x02, 0x03, 0xea];
+
+#[test]
+fn server_rejects_unmasked_frame() {
+    check_server(
+        &[&[0x81, 0x02, b'h', b'i']],
+        Err(Error::Protocol),
+        &CLOSE_PROTOCOL_ERROR,
+    );
+}
+
+#[test]
+fn server_rejects_fragmented_control_frame() {
+    // Ping without FIN, followed by its continuation.
+    check_server(
+        &[&[0x09, 0x81, 0, 0, 0, 0, b'a'], &[0x80, 0x81, 0, 0, 0, 0, b'b']],
+        Err(Error::Protocol),
+        &CLOSE_PROTOCOL_ERROR,
+    );
+}
+
+#[test]
+fn server_rejects_large_control_frame() {
+    // Ping of 126 bytes, with a 16 bit length.
+    let mut ping = vec![0x89, 0xfe, 0x00, 126, 0, 0, 0, 0];
+    ping.extend_from_slice(&[b'a'; 126]);
+    check_server(&[&ping], Err(Error::Protocol), &CLOSE_PROTOCOL_ERROR);
+}
+
+#[test]
+fn server_answers_ping_within_fragmented_message() {
+    check_server(
+        &[
+            &[0x01, 0x82, 0, 0, 0, 0, b'H', b'e'],
+            &[0x89, 0x82, 0, 0, 0, 0, b'p', b'1'],
+            &[0x00, 0x81, 0, 0, 0, 0, b'l'],
+            // Unsolicited pongs are ignored.
+            &[0x8a, 0x80, 0, 0, 0, 0],
+            &[0x80, 0x82, 0, 0, 0, 0, b'l', b'o'],
+        ],
+        Ok(Message::Text("Hello")),
+        &[0x8a, 0x02, b'p', b'1'],
+    );
+}
+
+#[test]
+fn server_checks_utf8_across_fragments() {
+    // "é" split between two fragments.
+    check_server(
+        &[&[0x01, 0x81, 0, 0, 0, 0, 0xc3], &[0x80, 0x81, 0, 0, 0, 0, 0xa9]],
+        Ok(Message::Text("é")),
+        &[],
+    );
+    // A lead byte followed by an ASCII character in the next fragment.
+    check_server(
+        &[&[0x01, 0x81, 0, 0, 0, 0, 0xc3], &[0x80, 0x81, 0, 0, 0, 0, 0x28]],
+        Err(Error::InvalidUtf8),
+        &[0x88, 0x02, 0x03, 0xef],
+    );
+}
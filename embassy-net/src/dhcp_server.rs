@@ -0,0 +1,731 @@
+//! DHCPv4 server.
+//!
+//! Hands out addresses from a pool to the clients on the local link, for example the stations
+//! connected to a Wi-Fi access point, or the host of a USB network device. The stack running the
+//! server must have a static IPv4 address in the subnet of the pool. On a stack with several
+//! interfaces, the server runs on the interface having that address.
+//!
+//! Leases are kept in a fixed-size table. When it is full, expired leases are reused, and clients
+//! that do not fit get no address.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use smoltcp::wire::{
+    DHCP_CLIENT_PORT, DHCP_MAX_DNS_SERVER_COUNT, DHCP_SERVER_PORT, DhcpMessageType, DhcpOption, DhcpPacket, DhcpRepr,
+    EthernetAddress, IpEndpoint, IpListenEndpoint, Ipv4Address, Ipv4Cidr,
+};
+
+use crate::Stack;
+use crate::udp::{PacketMetadata, UdpSocket};
+
+const OPT_RENEWAL_TIME_VALUE: u8 = 58;
+const OPT_REBINDING_TIME_VALUE: u8 = 59;
+
+/// Some clients drop replies shorter than a BOOTP message.
+const MIN_MESSAGE_SIZE: usize = 300;
+/// How long an offered address is reserved for the client.
+const OFFER_DURATION: Duration = Duration::from_secs(60);
+
+/// DHCP server configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config {
+    /// Address of the server, and subnet of the clients.
+    pub address: Ipv4Cidr,
+    /// First address handed out to clients.
+    pub pool_start: Ipv4Address,
+    /// Number of addresses handed out to clients, starting at `pool_start`.
+    pub pool_size: u32,
+    /// Default gateway sent to the clients.
+    pub router: Option<Ipv4Address>,
+    /// DNS servers sent to the clients.
+    pub dns_servers: Vec<Ipv4Address, DHCP_MAX_DNS_SERVER_COUNT>,
+    /// Duration of the leases.
+    pub lease_duration: Duration,
+    /// Server port. This is almost always 67. Do not change unless you know what you're doing.
+    pub server_port: u16,
+    /// Client port. This is almost always 68. Do not change unless you know what you're doing.
+    pub client_port: u16,
+}
+
+impl Config {
+    /// Create a configuration handing out `pool_size` addresses starting at `pool_start`.
+    ///
+    /// The server address is sent to the clients as their default gateway, and the leases last one
+    /// hour.
+    pub fn new(address: Ipv4Cidr, pool_start: Ipv4Address, pool_size: u32) -> Self {
+        Self {
+            address,
+            pool_start,
+            pool_size,
+            router: Some(address.address()),
+            dns_servers: Vec::new(),
+            lease_duration: Duration::from_secs(3600),
+            server_port: DHCP_SERVER_PORT,
+            client_port: DHCP_CLIENT_PORT,
+        }
+    }
+}
+
+/// Address leased to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Lease {
+    /// Hardware address of the client.
+    pub hardware_address: EthernetAddress,
+    /// Address leased to the client.
+    pub address: Ipv4Address,
+    /// When the lease expires, unless the client renews it.
+    pub expires: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Offered to the client, waiting for its request.
+    Offered,
+    /// Acknowledged to the client.
+    Bound,
+    /// Declined by the client because another host uses it.
+    Declined,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    lease: Lease,
+    state: State,
+}
+
+/// Fields of a client message that are needed to handle it.
+struct Request {
+    message_type: DhcpMessageType,
+    transaction_id: u32,
+    client_hardware_address: EthernetAddress,
+    client_ip: Ipv4Address,
+    relay_agent_ip: Ipv4Address,
+    broadcast: bool,
+    requested_ip: Option<Ipv4Address>,
+    server_identifier: Option<Ipv4Address>,
+}
+
+impl Request {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let packet = DhcpPacket::new_checked(buf).ok()?;
+        let repr = DhcpRepr::parse(&packet).ok()?;
+        Some(Self {
+            message_type: repr.message_type,
+            transaction_id: repr.transaction_id,
+            client_hardware_address: repr.client_hardware_address,
+            client_ip: repr.client_ip,
+            relay_agent_ip: repr.relay_agent_ip,
+            broadcast: repr.broadcast,
+            requested_ip: repr.requested_ip,
+            server_identifier: repr.server_identifier,
+        })
+    }
+}
+
+/// Reply to a client message.
+struct Reply {
+    message_type: DhcpMessageType,
+    your_ip: Ipv4Address,
+}
+
+/// Socket buffers for a [`DhcpServer`].
+pub struct DhcpServerResources {
+    rx_meta: [PacketMetadata; 4],
+    rx_buffer: [u8; 2048],
+    tx_meta: [PacketMetadata; 4],
+    tx_buffer: [u8; 2048],
+}
+
+impl DhcpServerResources {
+    /// Create a new set of socket buffers.
+    pub const fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; 4],
+            rx_buffer: [0; 2048],
+            tx_meta: [PacketMetadata::EMPTY; 4],
+            tx_buffer: [0; 2048],
+        }
+    }
+}
+
+impl Default for DhcpServerResources {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DHCPv4 server with a table of up to `N` leases.
+pub struct DhcpServer<'d, const N: usize> {
+    socket: UdpSocket<'d>,
+    table: LeaseTable<N>,
+}
+
+/// Addresses handed out by the server, and the protocol logic deciding them.
+struct LeaseTable<const N: usize> {
+    config: Config,
+    slots: [Option<Slot>; N],
+}
+
+impl<'d, const N: usize> DhcpServer<'d, N> {
+    /// Create a new DHCP server on `stack`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server port in `config` is 0, or if no interface of the stack has a free
+    /// socket slot.
+    pub fn new(stack: Stack<'d>, resources: &'d mut DhcpServerResources, config: Config) -> Self {
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut resources.rx_meta,
+            &mut resources.rx_buffer,
+            &mut resources.tx_meta,
+            &mut resources.tx_buffer,
+        );
+        // Binding to the server address puts the socket on the interface having it.
+        unwrap!(socket.bind(IpListenEndpoint {
+            addr: Some(config.address.address().into()),
+            port: config.server_port,
+        }));
+        Self {
+            socket,
+            table: LeaseTable {
+                config,
+                slots: [None; N],
+            },
+        }
+    }
+
+    /// Run the server.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            let request = self.socket.recv_from_with(|buf, _| Request::parse(buf)).await;
+            let Some(request) = request else {
+                debug!("dhcp server: invalid message");
+                continue;
+            };
+            if let Some(reply) = self.table.handle(&request, Instant::now()) {
+                self.send(&request, &reply).await;
+            }
+        }
+    }
+
+    /// Leases currently held by clients.
+    pub fn leases(&self) -> impl Iterator<Item = &Lease> {
+        self.table.leases(Instant::now())
+    }
+
+    async fn send(&mut self, request: &Request, reply: &Reply) {
+        let config = &self.table.config;
+        let lease = config.lease_duration.as_secs() as u32;
+        let renew = (lease / 2).to_be_bytes();
+        let rebind = (lease / 8 * 7).to_be_bytes();
+        let timers = [
+            DhcpOption {
+                kind: OPT_RENEWAL_TIME_VALUE,
+                data: &renew,
+            },
+            DhcpOption {
+                kind: OPT_REBINDING_TIME_VALUE,
+                data: &rebind,
+            },
+        ];
+
+        let nak = reply.message_type == DhcpMessageType::Nak;
+        let assigns = reply.message_type == DhcpMessageType::Offer
+            || (reply.message_type == DhcpMessageType::Ack && request.message_type == DhcpMessageType::Request);
+        let repr = DhcpRepr {
+            message_type: reply.message_type,
+            transaction_id: request.transaction_id,
+            secs: 0,
+            client_hardware_address: request.client_hardware_address,
+            client_ip: match reply.message_type {
+                DhcpMessageType::Ack => request.client_ip,
+                _ => Ipv4Address::UNSPECIFIED,
+            },
+            your_ip: reply.your_ip,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: config.router.filter(|_| !nak),
+            subnet_mask: (!nak).then(|| config.address.netmask()),
+            relay_agent_ip: request.relay_agent_ip,
+            broadcast: request.broadcast,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(config.address.address()),
+            parameter_request_list: None,
+            dns_servers: (!nak && !config.dns_servers.is_empty()).then(|| config.dns_servers.clone()),
+            max_size: None,
+            lease_duration: assigns.then_some(lease),
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: if assigns { &timers } else { &[] },
+        };
+
+        // Clients that have an address get unicast replies, others get broadcast ones since they
+        // cannot answer ARP requests yet.
+        let endpoint = if !request.relay_agent_ip.is_unspecified() {
+            IpEndpoint::new(request.relay_agent_ip.into(), config.server_port)
+        } else if !nak && !request.client_ip.is_unspecified() {
+            IpEndpoint::new(request.client_ip.into(), config.client_port)
+        } else {
+            IpEndpoint::new(Ipv4Address::BROADCAST.into(), config.client_port)
+        };
+
+        let len = repr.buffer_len().max(MIN_MESSAGE_SIZE);
+        let result = self
+            .socket
+            .send_to_with(len, endpoint, |buf| {
+                buf.fill(0);
+                let mut packet = DhcpPacket::new_unchecked(buf);
+                unwrap!(repr.emit(&mut packet));
+                (len, ())
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("dhcp server: failed to send reply: {:?}", e);
+        }
+    }
+}
+
+impl<const N: usize> LeaseTable<N> {
+    fn leases(&self, now: Instant) -> impl Iterator<Item = &Lease> {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(move |slot| slot.state == State::Bound && slot.lease.expires > now)
+            .map(|slot| &slot.lease)
+    }
+
+    fn handle(&mut self, request: &Request, now: Instant) -> Option<Reply> {
+        let client = request.client_hardware_address;
+        let server = self.config.address.address();
+
+        match request.message_type {
+            DhcpMessageType::Discover => {
+                let address = self.allocate(client, request.requested_ip, now)?;
+                self.bind(client, address, State::Offered, now);
+                debug!("dhcp server: offering {} to {}", address, client);
+                Some(Reply {
+                    message_type: DhcpMessageType::Offer,
+                    your_ip: address,
+                })
+            }
+            DhcpMessageType::Request => {
+                if let Some(id) = request.server_identifier
+                    && id != server
+                {
+                    // The client accepted the offer of another server.
+                    self.release(client, None, State::Offered);
+                    return None;
+                }
+
+                // Selecting and init-reboot clients request an address, renewing and rebinding ones
+                // use the address they have.
+                let address = match request.requested_ip {
+                    Some(address) => address,
+                    None if !request.client_ip.is_unspecified() => request.client_ip,
+                    None => return None,
+                };
+                if self.allocate(client, Some(address), now) == Some(address) {
+                    self.bind(client, address, State::Bound, now);
+                    debug!("dhcp server: leased {} to {}", address, client);
+                    Some(Reply {
+                        message_type: DhcpMessageType::Ack,
+                        your_ip: address,
+                    })
+                } else {
+                    debug!("dhcp server: refusing {} to {}", address, client);
+                    Some(Reply {
+                        message_type: DhcpMessageType::Nak,
+                        your_ip: Ipv4Address::UNSPECIFIED,
+                    })
+                }
+            }
+            DhcpMessageType::Decline => {
+                let address = request.requested_ip?;
+                warn!("dhcp server: {} declined {}", client, address);
+                let expires = now + self.config.lease_duration;
+                if let Some(slot) = self.slot_mut(client)
+                    && slot.lease.address == address
+                {
+                    // Keep the address out of the pool for a while.
+                    slot.lease.hardware_address = EthernetAddress([0; 6]);
+                    slot.lease.expires = expires;
+                    slot.state = State::Declined;
+                }
+                None
+            }
+            DhcpMessageType::Release => {
+                debug!("dhcp server: {} released {}", client, request.client_ip);
+                self.release(client, Some(request.client_ip), State::Bound);
+                None
+            }
+            DhcpMessageType::Inform => Some(Reply {
+                message_type: DhcpMessageType::Ack,
+                your_ip: Ipv4Address::UNSPECIFIED,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Choose the address for a client, preferring the address it already has, then `requested`.
+    ///
+    /// When a `requested` address is given that is not available, another one is only chosen for
+    /// clients that are discovering.
+    fn allocate(&self, client: EthernetAddress, requested: Option<Ipv4Address>, now: Instant) -> Option<Ipv4Address> {
+        let current = self.slot(client).map(|slot| slot.lease.address);
+        // Without a slot in the lease table, a new client cannot get an address.
+        if current.is_none()
+            && !self
+                .slots
+                .iter()
+                .any(|slot| slot.is_none_or(|slot| slot.lease.expires <= now))
+        {
+            return None;
+        }
+        if let Some(address) = requested
+            && (current == Some(address) || (self.in_pool(address) && self.is_free(address, now)))
+        {
+            return Some(address);
+        }
+        if let Some(address) = current {
+            return Some(address);
+        }
+
+        let start = u32::from(self.config.pool_start);
+        (start..start.saturating_add(self.config.pool_size))
+            .map(Ipv4Address::from)
+            .find(|&address| address != self.config.address.address() && self.is_free(address, now))
+    }
+
+    /// Record the lease of `address` to `client`, replacing its previous one.
+    fn bind(&mut self, client: EthernetAddress, address: Ipv4Address, state: State, now: Instant) {
+        let duration = match state {
+            State::Offered => OFFER_DURATION,
+            _ => self.config.lease_duration,
+        };
+        let lease = Lease {
+            hardware_address: client,
+            address,
+            expires: now + duration,
+        };
+
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.is_some_and(|slot| slot.lease.hardware_address == client))
+            .or_else(|| self.slots.iter().position(|slot| slot.is_none()))
+            .or_else(|| {
+                // Reuse the lease that expired first.
+                (0..N)
+                    .filter(|&i| self.slots[i].is_some_and(|slot| slot.lease.expires <= now))
+                    .min_by_key(|&i| self.slots[i].map(|slot| slot.lease.expires))
+            });
+        let Some(index) = index else { return };
+
+        match &mut self.slots[index] {
+            // An offer must not shorten a lease the client already has.
+            Some(slot) if state == State::Offered && slot.lease.address == address && slot.lease.expires > now => {}
+            slot => *slot = Some(Slot { lease, state }),
+        }
+    }
+
+    /// Remove the lease of `client` if it is in `state`, and for `address` if given.
+    fn release(&mut self, client: EthernetAddress, address: Option<Ipv4Address>, state: State) {
+        for entry in self.slots.iter_mut() {
+            if let Some(slot) = entry
+                && slot.lease.hardware_address == client
+                && slot.state == state
+                && address.is_none_or(|address| slot.lease.address == address)
+            {
+                *entry = None;
+            }
+        }
+    }
+
+    fn slot(&self, client: EthernetAddress) -> Option<&Slot> {
+        self.slots
+            .iter()
+            .flatten()
+            .find(|slot| slot.lease.hardware_address == client && slot.state != State::Declined)
+    }
+
+    fn slot_mut(&mut self, client: EthernetAddress) -> Option<&mut Slot> {
+        self.slots
+            .iter_mut()
+            .flatten()
+            .find(|slot| slot.lease.hardware_address == client && slot.state != State::Declined)
+    }
+
+    fn in_pool(&self, address: Ipv4Address) -> bool {
+        let offset = u32::from(address).wrapping_sub(u32::from(self.config.pool_start));
+        offset < self.config.pool_size && address != self.config.address.address()
+    }
+
+    fn is_free(&self, address: Ipv4Address, now: Instant) -> bool {
+        !self
+            .slots
+            .iter()
+            .flatten()
+            .any(|slot| slot.lease.address == address && slot.lease.expires > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+    const CLIENT_A: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x0a]);
+    const CLIENT_B: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]);
+    const CLIENT_C: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x0c]);
+
+    fn addr(last: u8) -> Ipv4Address {
+        Ipv4Address::new(192, 168, 4, last)
+    }
+
+    /// Pool of `pool_size` addresses starting at the server address, which is skipped.
+    fn table<const N: usize>(pool_size: u32) -> LeaseTable<N> {
+        LeaseTable {
+            config: Config::new(Ipv4Cidr::new(SERVER, 24), SERVER, pool_size),
+            slots: [None; N],
+        }
+    }
+
+    fn request(message_type: DhcpMessageType, client: EthernetAddress) -> Request {
+        Request {
+            message_type,
+            transaction_id: 0x1234_5678,
+            client_hardware_address: client,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            server_identifier: None,
+        }
+    }
+
+    fn discover(client: EthernetAddress, requested_ip: Option<Ipv4Address>) -> Request {
+        Request {
+            requested_ip,
+            ..request(DhcpMessageType::Discover, client)
+        }
+    }
+
+    fn select(client: EthernetAddress, address: Ipv4Address) -> Request {
+        Request {
+            requested_ip: Some(address),
+            server_identifier: Some(SERVER),
+            ..request(DhcpMessageType::Request, client)
+        }
+    }
+
+    fn reply<const N: usize>(
+        table: &mut LeaseTable<N>,
+        request: &Request,
+        now: Instant,
+    ) -> Option<(DhcpMessageType, Ipv4Address)> {
+        table
+            .handle(request, now)
+            .map(|reply| (reply.message_type, reply.your_ip))
+    }
+
+    fn leases<const N: usize>(table: &LeaseTable<N>, now: Instant) -> Vec<(EthernetAddress, Ipv4Address), N> {
+        table
+            .leases(now)
+            .map(|lease| (lease.hardware_address, lease.address))
+            .collect()
+    }
+
+    #[test]
+    fn test_discover_request_ack() {
+        let mut table = table::<4>(8);
+        let now = Instant::from_secs(100);
+
+        let offer = reply(&mut table, &discover(CLIENT_A, None), now);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(2))));
+        assert_eq!(leases(&table, now), []);
+
+        let ack = reply(&mut table, &select(CLIENT_A, addr(2)), now);
+        assert_eq!(ack, Some((DhcpMessageType::Ack, addr(2))));
+        assert_eq!(leases(&table, now), [(CLIENT_A, addr(2))]);
+        assert_eq!(
+            table.leases(now).next().unwrap().expires,
+            now + table.config.lease_duration
+        );
+
+        // Another client gets the next address.
+        let offer = reply(&mut table, &discover(CLIENT_B, None), now);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(3))));
+
+        // Renewing keeps the address.
+        let renew = Request {
+            client_ip: addr(2),
+            ..request(DhcpMessageType::Request, CLIENT_A)
+        };
+        let later = now + Duration::from_secs(1800);
+        assert_eq!(reply(&mut table, &renew, later), Some((DhcpMessageType::Ack, addr(2))));
+        assert_eq!(
+            table.leases(later).next().unwrap().expires,
+            later + table.config.lease_duration
+        );
+    }
+
+    #[test]
+    fn test_requested_ip() {
+        let mut table = table::<4>(8);
+        let now = Instant::from_secs(100);
+
+        // A free address in the pool is offered.
+        let offer = reply(&mut table, &discover(CLIENT_A, Some(addr(5))), now);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(5))));
+        let ack = reply(&mut table, &select(CLIENT_A, addr(5)), now);
+        assert_eq!(ack, Some((DhcpMessageType::Ack, addr(5))));
+
+        // An address leased to another client is not, nor is one outside of the pool.
+        let offer = reply(&mut table, &discover(CLIENT_B, Some(addr(5))), now);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(2))));
+        let offer = reply(&mut table, &discover(CLIENT_C, Some(addr(100))), now);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(3))));
+
+        // An init-reboot client gets the free address it asks for.
+        let reboot = Request {
+            requested_ip: Some(addr(7)),
+            ..request(DhcpMessageType::Request, CLIENT_C)
+        };
+        assert_eq!(reply(&mut table, &reboot, now), Some((DhcpMessageType::Ack, addr(7))));
+        assert_eq!(leases(&table, now), [(CLIENT_A, addr(5)), (CLIENT_C, addr(7))]);
+    }
+
+    #[test]
+    fn test_nak() {
+        let mut table = table::<4>(8);
+        let now = Instant::from_secs(100);
+        let nak = Some((DhcpMessageType::Nak, Ipv4Address::UNSPECIFIED));
+
+        // Outside of the pool, or the address of the server.
+        assert_eq!(
+            reply(&mut table, &select(CLIENT_A, Ipv4Address::new(10, 0, 0, 2)), now),
+            nak
+        );
+        assert_eq!(reply(&mut table, &select(CLIENT_A, addr(9)), now), nak);
+        assert_eq!(reply(&mut table, &select(CLIENT_A, SERVER), now), nak);
+
+        // Leased to another client.
+        reply(&mut table, &discover(CLIENT_A, None), now);
+        reply(&mut table, &select(CLIENT_A, addr(2)), now);
+        assert_eq!(reply(&mut table, &select(CLIENT_B, addr(2)), now), nak);
+        assert_eq!(leases(&table, now), [(CLIENT_A, addr(2))]);
+
+        // Requests for another server are ignored, and drop the offer.
+        reply(&mut table, &discover(CLIENT_B, None), now);
+        let other = Request {
+            server_identifier: Some(Ipv4Address::new(192, 168, 4, 254)),
+            ..select(CLIENT_B, addr(3))
+        };
+        assert_eq!(reply(&mut table, &other, now), None);
+        assert!(table.slot(CLIENT_B).is_none());
+    }
+
+    #[test]
+    fn test_lease_expiry() {
+        let mut table = table::<1>(8);
+        let now = Instant::from_secs(100);
+
+        reply(&mut table, &discover(CLIENT_A, None), now);
+        reply(&mut table, &select(CLIENT_A, addr(2)), now);
+
+        // The table is full until the lease expires.
+        let expires = now + table.config.lease_duration;
+        let before = expires - Duration::from_secs(1);
+        assert_eq!(reply(&mut table, &discover(CLIENT_B, None), before), None);
+        assert_eq!(leases(&table, before), [(CLIENT_A, addr(2))]);
+
+        assert_eq!(leases(&table, expires), []);
+        let offer = reply(&mut table, &discover(CLIENT_B, None), expires);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(2))));
+        let ack = reply(&mut table, &select(CLIENT_B, addr(2)), expires);
+        assert_eq!(ack, Some((DhcpMessageType::Ack, addr(2))));
+        assert_eq!(leases(&table, expires), [(CLIENT_B, addr(2))]);
+
+        // The previous client lost its address.
+        let nak = Some((DhcpMessageType::Nak, Ipv4Address::UNSPECIFIED));
+        assert_eq!(reply(&mut table, &select(CLIENT_A, addr(2)), expires), nak);
+    }
+
+    #[test]
+    fn test_offer_expiry() {
+        let mut table = table::<4>(2);
+        let now = Instant::from_secs(100);
+
+        // An offer reserves the address for a while only.
+        reply(&mut table, &discover(CLIENT_A, None), now);
+        assert_eq!(reply(&mut table, &discover(CLIENT_B, None), now), None);
+        let offer = reply(&mut table, &discover(CLIENT_B, None), now + OFFER_DURATION);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(2))));
+    }
+
+    #[test]
+    fn test_pool_exhausted() {
+        let mut table = table::<4>(3);
+        let now = Instant::from_secs(100);
+
+        for (client, address) in [(CLIENT_A, addr(2)), (CLIENT_B, addr(3))] {
+            reply(&mut table, &discover(client, None), now);
+            let ack = reply(&mut table, &select(client, address), now);
+            assert_eq!(ack, Some((DhcpMessageType::Ack, address)));
+        }
+
+        assert_eq!(reply(&mut table, &discover(CLIENT_C, None), now), None);
+
+        // Released addresses go back to the pool.
+        let release = Request {
+            client_ip: addr(2),
+            ..request(DhcpMessageType::Release, CLIENT_A)
+        };
+        assert_eq!(reply(&mut table, &release, now), None);
+        let offer = reply(&mut table, &discover(CLIENT_C, None), now);
+        assert_eq!(offer, Some((DhcpMessageType::Offer, addr(2))));
+    }
+
+    #[test]
+    fn test_parse() {
+        let repr = DhcpRepr {
+            message_type: DhcpMessageType::Request,
+            transaction_id: 0x1234_5678,
+            secs: 0,
+            client_hardware_address: CLIENT_A,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip: Ipv4Address::UNSPECIFIED,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: None,
+            subnet_mask: None,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: true,
+            requested_ip: Some(addr(2)),
+            client_identifier: None,
+            server_identifier: Some(SERVER),
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        };
+        let mut buf = [0; MIN_MESSAGE_SIZE];
+        repr.emit(&mut DhcpPacket::new_unchecked(&mut buf[..])).unwrap();
+
+        let request = Request::parse(&buf).unwrap();
+        assert_eq!(request.message_type, DhcpMessageType::Request);
+        assert_eq!(request.transaction_id, 0x1234_5678);
+        assert_eq!(request.client_hardware_address, CLIENT_A);
+        assert!(request.broadcast);
+        assert_eq!(request.requested_ip, Some(addr(2)));
+        assert_eq!(request.server_identifier, Some(SERVER));
+
+        assert!(Request::parse(&buf[..100]).is_none());
+    }
+}
@@ -4,6 +4,8 @@ use embassy_net_driver::{Capabilities, Checksum, Driver, RxToken, TxToken};
 use smoltcp::phy::{self, Medium};
 use smoltcp::time::Instant;
 
+use crate::stats::Counters;
+
 pub(crate) struct DriverAdapter<'d, 'c, T>
 where
     T: Driver,
@@ -13,6 +15,7 @@ where
     pub inner: &'d mut T,
     pub medium: Medium,
     pub tx_exhausted: bool,
+    pub counters: &'d Counters,
 }
 
 impl<'d, 'c, T> phy::Device for DriverAdapter<'d, 'c, T>
@@ -20,23 +23,41 @@ where
     T: Driver,
 {
     type RxToken<'a>
-        = RxTokenAdapter<T::RxToken<'a>>
+        = RxTokenAdapter<'a, T::RxToken<'a>>
     where
         Self: 'a;
     type TxToken<'a>
-        = TxTokenAdapter<T::TxToken<'a>>
+        = TxTokenAdapter<'a, T::TxToken<'a>>
     where
         Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        self.inner
-            .receive(unwrap!(self.cx.as_deref_mut()))
-            .map(|(rx, tx)| (RxTokenAdapter(rx), TxTokenAdapter(tx)))
+        let counters = self.counters;
+        let medium = self.medium;
+        self.inner.receive(unwrap!(self.cx.as_deref_mut())).map(|(rx, tx)| {
+            (
+                RxTokenAdapter { inner: rx, counters },
+                TxTokenAdapter {
+                    inner: tx,
+                    counters,
+                    medium,
+                },
+            )
+        })
     }
 
     /// Construct a transmit token.
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        let token = self.inner.transmit(unwrap!(self.cx.as_deref_mut())).map(TxTokenAdapter);
+        let counters = self.counters;
+        let medium = self.medium;
+        let token = self
+            .inner
+            .transmit(unwrap!(self.cx.as_deref_mut()))
+            .map(|inner| TxTokenAdapter {
+                inner,
+                counters,
+                medium,
+            });
 
         self.tx_exhausted = token.is_none();
 
@@ -75,11 +96,15 @@ where
     }
 }
 
-pub(crate) struct RxTokenAdapter<T>(T)
+pub(crate) struct RxTokenAdapter<'a, T>
 where
-    T: RxToken;
+    T: RxToken,
+{
+    inner: T,
+    counters: &'a Counters,
+}
 
-impl<T> phy::RxToken for RxTokenAdapter<T>
+impl<'a, T> phy::RxToken for RxTokenAdapter<'a, T>
 where
     T: RxToken,
 {
@@ -87,19 +112,25 @@ where
     where
         F: FnOnce(&[u8]) -> R,
     {
-        self.0.consume(|buf| {
+        self.inner.consume(|buf| {
             #[cfg(feature = "packet-trace")]
             trace!("embassy device rx: {:02x}", buf);
+            self.counters.rx(buf.len());
             f(buf)
         })
     }
 }
 
-pub(crate) struct TxTokenAdapter<T>(T)
+pub(crate) struct TxTokenAdapter<'a, T>
 where
-    T: TxToken;
+    T: TxToken,
+{
+    inner: T,
+    counters: &'a Counters,
+    medium: Medium,
+}
 
-impl<T> phy::TxToken for TxTokenAdapter<T>
+impl<'a, T> phy::TxToken for TxTokenAdapter<'a, T>
 where
     T: TxToken,
 {
@@ -107,10 +138,11 @@ where
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        self.0.consume(len, |buf| {
+        self.inner.consume(len, |buf| {
             let r = f(buf);
             #[cfg(feature = "packet-trace")]
             trace!("embassy device tx: {:02x}", buf);
+            self.counters.tx(self.medium, buf);
             r
         })
     }
@@ -4,7 +4,7 @@ use core::future::{Future, poll_fn};
 use core::mem;
 use core::task::{Context, Poll};
 
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 pub use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::socket::icmp;
 pub use smoltcp::socket::icmp::{Endpoint as IcmpEndpoint, PacketMetadata};
@@ -14,11 +14,12 @@ pub use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr};
 #[cfg(feature = "proto-ipv6")]
 pub use smoltcp::wire::{Icmpv6Message, Icmpv6Packet, Icmpv6Repr};
 
-use crate::{Stack, TryError};
+use crate::{BindInterfaceError, InterfaceId, SocketId, Stack, TryError};
 
 /// Error returned by [`IcmpSocket::bind`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum BindError {
     /// The socket was already open.
     InvalidState,
@@ -26,6 +27,17 @@ pub enum BindError {
     InvalidEndpoint,
     /// No route to host.
     NoRoute,
+    /// The interface routing to the host has no free socket slot.
+    NoFreeSocket,
+}
+
+impl From<BindInterfaceError> for BindError {
+    fn from(e: BindInterfaceError) -> Self {
+        match e {
+            BindInterfaceError::InvalidState => Self::InvalidState,
+            BindInterfaceError::NoFreeSocket => Self::NoFreeSocket,
+        }
+    }
 }
 
 /// Error returned by [`IcmpSocket::send_to`].
@@ -51,11 +63,15 @@ pub enum RecvError {
 /// An ICMP socket.
 pub struct IcmpSocket<'a> {
     stack: Stack<'a>,
-    handle: SocketHandle,
+    id: SocketId,
 }
 
 impl<'a> IcmpSocket<'a> {
     /// Create a new ICMP socket using the provided stack and buffers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no interface of the stack has a free socket slot.
     pub fn new(
         stack: Stack<'a>,
         rx_meta: &'a mut [PacketMetadata],
@@ -63,18 +79,18 @@ impl<'a> IcmpSocket<'a> {
         tx_meta: &'a mut [PacketMetadata],
         tx_buffer: &'a mut [u8],
     ) -> Self {
-        let handle = stack.with_mut(|i| {
+        let id = stack.with_mut(|i| {
             let rx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(rx_meta) };
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(icmp::Socket::new(
+            i.add_socket(icmp::Socket::new(
                 icmp::PacketBuffer::new(rx_meta, rx_buffer),
                 icmp::PacketBuffer::new(tx_meta, tx_buffer),
             ))
         });
 
-        Self { stack, handle }
+        Self { stack, id }
     }
 
     /// Bind the socket to the given endpoint.
@@ -95,18 +111,36 @@ impl<'a> IcmpSocket<'a> {
         }
     }
 
+    /// Bind the socket to a network interface of the stack.
+    ///
+    /// Sockets are created on the interface with the preferred default route, and only send and
+    /// receive packets on that interface.
+    pub fn bind_to_interface(&mut self, iface: InterfaceId) -> Result<(), BindInterfaceError> {
+        if self.is_open() {
+            return Err(BindInterfaceError::InvalidState);
+        }
+        self.id = self.stack.with_mut(|i| i.move_socket(self.id, iface))?;
+        Ok(())
+    }
+
+    /// Move the socket to the interface used to reach `addr`.
+    fn route_to(&mut self, addr: IpAddress) -> Result<(), BindError> {
+        self.id = self.stack.with_mut(|i| i.route_socket(self.id, addr))?;
+        Ok(())
+    }
+
     fn with<R>(&self, f: impl FnOnce(&icmp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
-            let socket = i.sockets.get::<icmp::Socket>(self.handle);
-            f(socket, &i.iface)
+            let (socket, iface) = i.socket::<icmp::Socket>(self.id);
+            f(socket, iface)
         })
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut icmp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<icmp::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<icmp::Socket>(self.id);
+            let res = f(socket, iface);
+            i.wake(self.id);
             res
         })
     }
@@ -481,7 +515,7 @@ impl<'a> IcmpSocket<'a> {
 
 impl Drop for IcmpSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack.with_mut(|i| i.remove_socket(self.id));
     }
 }
 
@@ -680,6 +714,9 @@ pub mod ping {
 
             // Create the socket and set hop limit and bind it to the endpoint with the ident
             let mut socket = IcmpSocket::new(self.stack, self.rx_meta, self.rx_buffer, self.tx_meta, self.tx_buffer);
+            if let Err(e) = socket.route_to(unwrap!(params.target)) {
+                return Err(PingError::SocketBindError(e));
+            }
             socket.set_hop_limit(params.hop_limit);
             if let Err(e) = socket.bind(IcmpEndpoint::Ident(self.ident)) {
                 return Err(PingError::SocketBindError(e));
@@ -742,6 +779,9 @@ pub mod ping {
 
             // Create the socket and set hop limit and bind it to the endpoint with the ident
             let mut socket = IcmpSocket::new(self.stack, self.rx_meta, self.rx_buffer, self.tx_meta, self.tx_buffer);
+            if let Err(e) = socket.route_to(unwrap!(params.target)) {
+                return Err(PingError::SocketBindError(e));
+            }
             socket.set_hop_limit(params.hop_limit);
             if let Err(e) = socket.bind(IcmpEndpoint::Ident(self.ident)) {
                 return Err(PingError::SocketBindError(e));
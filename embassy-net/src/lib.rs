@@ -13,13 +13,18 @@ compile_error!("You must enable at least one of the following features: proto-ip
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+#[cfg(feature = "dhcpv4-server")]
+pub mod dhcp_server;
 #[cfg(feature = "dns")]
 pub mod dns;
 mod driver_util;
 #[cfg(feature = "icmp")]
 pub mod icmp;
+#[cfg(feature = "mdns-responder")]
+pub mod mdns_responder;
 #[cfg(feature = "raw")]
 pub mod raw;
+mod stats;
 #[cfg(feature = "tcp")]
 pub mod tcp;
 mod time;
@@ -41,10 +46,9 @@ use heapless::Vec;
 pub use smoltcp::config::DNS_MAX_SERVER_COUNT;
 #[cfg(feature = "multicast")]
 pub use smoltcp::iface::MulticastError;
-#[cfg(any(feature = "dns", feature = "dhcpv4"))]
-use smoltcp::iface::SocketHandle;
-use smoltcp::iface::{Interface, SocketSet, SocketStorage};
+use smoltcp::iface::{Interface as SmolInterface, SocketHandle, SocketSet, SocketStorage};
 use smoltcp::phy::Medium;
+use smoltcp::socket::AnySocket;
 #[cfg(feature = "dhcpv4")]
 use smoltcp::socket::dhcpv4::{self, RetryConfig};
 #[cfg(feature = "medium-ethernet")]
@@ -62,6 +66,8 @@ pub use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
 pub use smoltcp::wire::{Ipv6Address, Ipv6Cidr};
 
 use crate::driver_util::DriverAdapter;
+use crate::stats::Counters;
+pub use crate::stats::{InterfaceStatistics, SocketInfo};
 use crate::time::{instant_from_smoltcp, instant_to_smoltcp};
 
 const LOCAL_PORT_MIN: u16 = 1025;
@@ -70,6 +76,10 @@ const LOCAL_PORT_MAX: u16 = 65535;
 const MAX_QUERIES: usize = 4;
 #[cfg(feature = "dhcpv4-hostname")]
 const MAX_HOSTNAME_LEN: usize = 32;
+/// Maximum number of network interfaces of a [`Stack`].
+pub const MAX_INTERFACES: usize = 4;
+/// Maximum number of routes added with [`Stack::add_route`].
+pub const MAX_ROUTES: usize = 8;
 
 /// Error returned by `try_*` socket methods.
 ///
@@ -87,10 +97,32 @@ pub enum TryError<T> {
 
 /// Memory resources needed for a network stack.
 pub struct StackResources<const SOCK: usize> {
-    sockets: MaybeUninit<[SocketStorage<'static>; SOCK]>,
+    iface: InterfaceResources<SOCK>,
     inner: MaybeUninit<RefCell<Inner>>,
     #[cfg(feature = "dns")]
     queries: MaybeUninit<[Option<dns::DnsQuery>; MAX_QUERIES]>,
+}
+
+impl<const SOCK: usize> StackResources<SOCK> {
+    /// Create a new set of stack resources.
+    pub const fn new() -> Self {
+        Self {
+            iface: InterfaceResources::new(),
+            inner: MaybeUninit::uninit(),
+            #[cfg(feature = "dns")]
+            queries: MaybeUninit::uninit(),
+        }
+    }
+}
+
+/// Memory resources needed for an additional network interface.
+///
+/// See [`Stack::add_interface`].
+pub struct InterfaceResources<const SOCK: usize> {
+    sockets: MaybeUninit<[SocketStorage<'static>; SOCK]>,
+    state: MaybeUninit<IfaceState>,
+    #[cfg(feature = "tcp-retransmissions")]
+    tcp_counters: MaybeUninit<[Option<stats::TcpCounters>; SOCK]>,
     #[cfg(feature = "dhcpv4-hostname")]
     hostname: HostnameResources,
 }
@@ -101,14 +133,14 @@ struct HostnameResources {
     data: MaybeUninit<[u8; MAX_HOSTNAME_LEN]>,
 }
 
-impl<const SOCK: usize> StackResources<SOCK> {
-    /// Create a new set of stack resources.
+impl<const SOCK: usize> InterfaceResources<SOCK> {
+    /// Create a new set of interface resources.
     pub const fn new() -> Self {
         Self {
             sockets: MaybeUninit::uninit(),
-            inner: MaybeUninit::uninit(),
-            #[cfg(feature = "dns")]
-            queries: MaybeUninit::uninit(),
+            state: MaybeUninit::uninit(),
+            #[cfg(feature = "tcp-retransmissions")]
+            tcp_counters: MaybeUninit::uninit(),
             #[cfg(feature = "dhcpv4-hostname")]
             hostname: HostnameResources {
                 option: MaybeUninit::uninit(),
@@ -118,6 +150,12 @@ impl<const SOCK: usize> StackResources<SOCK> {
     }
 }
 
+impl<const SOCK: usize> Default for InterfaceResources<SOCK> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Static IP address configuration.
 #[cfg(feature = "proto-ipv4")]
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -276,12 +314,89 @@ pub enum ConfigV6 {
     Slaac,
 }
 
+/// Identifier of a network interface of a [`Stack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceId(u8);
+
+impl InterfaceId {
+    /// The interface the stack was created with.
+    pub const PRIMARY: Self = Self(0);
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Route to a network through one of the interfaces of a [`Stack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Route {
+    /// Destination network.
+    pub destination: IpCidr,
+    /// Router to send the packets to.
+    ///
+    /// Only point-to-point interfaces, like PPP, can do without one.
+    pub gateway: Option<IpAddress>,
+    /// Interface to send the packets on.
+    pub interface: InterfaceId,
+    /// Metric of the route.
+    ///
+    /// Among the routes with the longest prefix matching a destination, the one with the
+    /// lowest metric is used.
+    pub metric: u32,
+}
+
+/// Error returned by [`Stack::add_route`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RouteError {
+    /// The routing table of the stack is full.
+    TableFull,
+    /// The interface of the route is not an interface of the stack.
+    UnknownInterface,
+}
+
+impl core::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TableFull => f.write_str("TableFull"),
+            Self::UnknownInterface => f.write_str("UnknownInterface"),
+        }
+    }
+}
+
+impl core::error::Error for RouteError {}
+
+/// Error returned when binding a socket to an interface.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BindInterfaceError {
+    /// The socket is open.
+    InvalidState,
+    /// The interface has no free socket slot.
+    NoFreeSocket,
+}
+
+impl core::fmt::Display for BindInterfaceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidState => f.write_str("InvalidState"),
+            Self::NoFreeSocket => f.write_str("NoFreeSocket"),
+        }
+    }
+}
+
+impl core::error::Error for BindInterfaceError {}
+
 /// Network stack runner.
 ///
-/// You must call [`Runner::run()`] in a background task for the network stack to work.
+/// You must call [`Runner::run()`] in a background task for the network stack to work. Every
+/// interface of the stack has its own runner.
 pub struct Runner<'d, D: Driver> {
     driver: D,
     stack: Stack<'d>,
+    iface: InterfaceId,
 }
 
 /// Network stack handle
@@ -293,16 +408,45 @@ pub struct Stack<'d> {
     inner: &'d RefCell<Inner>,
 }
 
+/// Network interface handle
+///
+/// Use this to get the state and configuration of one of the interfaces of a [`Stack`]. It's
+/// `Copy`, so you can pass it by value instead of by reference.
+#[derive(Copy, Clone)]
+pub struct Interface<'d> {
+    stack: Stack<'d>,
+    id: InterfaceId,
+}
+
 pub(crate) struct Inner {
-    pub(crate) sockets: SocketSet<'static>, // Lifetime type-erased.
-    pub(crate) iface: Interface,
-    /// Waker used for triggering polls.
-    pub(crate) waker: WakerRegistration,
+    ifaces: Vec<&'static mut IfaceState, MAX_INTERFACES>, // Lifetime type-erased.
+    routes: Vec<Route, MAX_ROUTES>,
     /// Waker used for waiting for link up or config up.
     state_waker: WakerRegistration,
-    hardware_address: HardwareAddress,
     next_local_port: u16,
+    random_seed: u64,
+    #[cfg(feature = "dns")]
+    dns_socket: SocketId,
+    #[cfg(feature = "dns")]
+    dns_waker: WakerRegistration,
+    /// Number of DNS queries in progress.
+    #[cfg(feature = "dns")]
+    dns_queries: usize,
+}
+
+/// State of a network interface.
+pub(crate) struct IfaceState {
+    iface: SmolInterface,
+    sockets: SocketSet<'static>, // Lifetime type-erased.
+    socket_capacity: usize,
+    /// Routes of the stack installed in the routing table of the interface.
+    installed_routes: Vec<(IpCidr, IpAddress), MAX_ROUTES>,
+    /// Waker used for triggering polls.
+    waker: WakerRegistration,
+    hardware_address: HardwareAddress,
     link_up: bool,
+    metric: u32,
+    counters: Counters,
     #[cfg(feature = "proto-ipv4")]
     static_v4: Option<StaticConfigV4>,
     #[cfg(feature = "proto-ipv6")]
@@ -311,25 +455,86 @@ pub(crate) struct Inner {
     slaac: bool,
     #[cfg(feature = "dhcpv4")]
     dhcp_socket: Option<SocketHandle>,
-    #[cfg(feature = "dns")]
-    dns_socket: SocketHandle,
-    #[cfg(feature = "dns")]
-    dns_waker: WakerRegistration,
     #[cfg(feature = "dhcpv4-hostname")]
     hostname: *mut HostnameResources,
 }
 
+/// Socket in the socket set of one of the interfaces.
+#[derive(Clone, Copy)]
+pub(crate) struct SocketId {
+    iface: InterfaceId,
+    handle: SocketHandle,
+}
+
 fn _assert_covariant<'a, 'b: 'a>(x: Stack<'b>) -> Stack<'a> {
     x
 }
 
+unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
+    core::mem::transmute(x)
+}
+
 /// Create a new network stack.
+///
+/// The stack is created with a single interface, [`InterfaceId::PRIMARY`], using `driver`. More
+/// interfaces can be added with [`Stack::add_interface`].
 pub fn new<'d, D: Driver, const SOCK: usize>(
     mut driver: D,
     config: Config,
     resources: &'d mut StackResources<SOCK>,
     random_seed: u64,
 ) -> (Stack<'d>, Runner<'d, D>) {
+    #[allow(unused_mut)]
+    let mut state = new_interface(&mut driver, &config, &mut resources.iface, random_seed);
+
+    let next_local_port = (random_seed % (LOCAL_PORT_MAX - LOCAL_PORT_MIN) as u64) as u16 + LOCAL_PORT_MIN;
+
+    #[cfg(feature = "dns")]
+    let dns_socket = SocketId {
+        iface: InterfaceId::PRIMARY,
+        handle: state.sockets.add(dns::Socket::new(
+            &[],
+            managed::ManagedSlice::Borrowed(unsafe {
+                transmute_slice(resources.queries.write([const { None }; MAX_QUERIES]))
+            }),
+        )),
+    };
+
+    let mut inner = Inner {
+        ifaces: Vec::new(),
+        routes: Vec::new(),
+        state_waker: WakerRegistration::new(),
+        next_local_port,
+        random_seed,
+        #[cfg(feature = "dns")]
+        dns_socket,
+        #[cfg(feature = "dns")]
+        dns_waker: WakerRegistration::new(),
+        #[cfg(feature = "dns")]
+        dns_queries: 0,
+    };
+    unwrap!(inner.ifaces.push(state).ok());
+    inner.configure(InterfaceId::PRIMARY, config);
+
+    let inner = &*resources.inner.write(RefCell::new(inner));
+    let stack = Stack { inner };
+    (
+        stack,
+        Runner {
+            driver,
+            stack,
+            iface: InterfaceId::PRIMARY,
+        },
+    )
+}
+
+#[cfg_attr(not(feature = "slaac"), allow(unused_variables))]
+fn new_interface<D: Driver, const SOCK: usize>(
+    driver: &mut D,
+    config: &Config,
+    resources: &mut InterfaceResources<SOCK>,
+    random_seed: u64,
+) -> &'static mut IfaceState {
     let (hardware_address, medium) = to_smoltcp_hardware_address(driver.hardware_address());
     let mut iface_cfg = smoltcp::iface::Config::new(hardware_address);
     iface_cfg.random_seed = random_seed;
@@ -338,44 +543,40 @@ pub fn new<'d, D: Driver, const SOCK: usize>(
         iface_cfg.slaac = matches!(config.ipv6, ConfigV6::Slaac);
     }
 
-    #[allow(unused_mut)]
-    let mut iface = Interface::new(
+    let iface = SmolInterface::new(
         iface_cfg,
         &mut DriverAdapter {
-            inner: &mut driver,
+            inner: driver,
             cx: None,
             medium,
             tx_exhausted: false,
+            counters: &Counters::new(
+                #[cfg(feature = "tcp-retransmissions")]
+                &mut [],
+            ),
         },
         instant_to_smoltcp(Instant::now()),
     );
 
-    unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
-        core::mem::transmute(x)
-    }
-
     let sockets = resources.sockets.write([SocketStorage::EMPTY; SOCK]);
-    #[allow(unused_mut)]
-    let mut sockets: SocketSet<'static> = SocketSet::new(unsafe { transmute_slice(sockets) });
-
-    let next_local_port = (random_seed % (LOCAL_PORT_MAX - LOCAL_PORT_MIN) as u64) as u16 + LOCAL_PORT_MIN;
+    let sockets: SocketSet<'static> = SocketSet::new(unsafe { transmute_slice(sockets) });
 
-    #[cfg(feature = "dns")]
-    let dns_socket = sockets.add(dns::Socket::new(
-        &[],
-        managed::ManagedSlice::Borrowed(unsafe {
-            transmute_slice(resources.queries.write([const { None }; MAX_QUERIES]))
-        }),
-    ));
+    #[cfg(feature = "tcp-retransmissions")]
+    let tcp_counters = unsafe { transmute_slice(resources.tcp_counters.write([None; SOCK])) };
 
-    let mut inner = Inner {
-        sockets,
+    let state = resources.state.write(IfaceState {
         iface,
+        sockets,
+        socket_capacity: SOCK,
+        installed_routes: Vec::new(),
         waker: WakerRegistration::new(),
-        state_waker: WakerRegistration::new(),
-        next_local_port,
         hardware_address,
         link_up: false,
+        metric: 0,
+        counters: Counters::new(
+            #[cfg(feature = "tcp-retransmissions")]
+            tcp_counters,
+        ),
         #[cfg(feature = "proto-ipv4")]
         static_v4: None,
         #[cfg(feature = "proto-ipv6")]
@@ -384,23 +585,12 @@ pub fn new<'d, D: Driver, const SOCK: usize>(
         slaac: false,
         #[cfg(feature = "dhcpv4")]
         dhcp_socket: None,
-        #[cfg(feature = "dns")]
-        dns_socket,
-        #[cfg(feature = "dns")]
-        dns_waker: WakerRegistration::new(),
         #[cfg(feature = "dhcpv4-hostname")]
         hostname: &mut resources.hostname,
-    };
-
-    #[cfg(feature = "proto-ipv4")]
-    inner.set_config_v4(config.ipv4);
-    #[cfg(feature = "proto-ipv6")]
-    inner.set_config_v6(config.ipv6);
-    inner.apply_static_config();
-
-    let inner = &*resources.inner.write(RefCell::new(inner));
-    let stack = Stack { inner };
-    (stack, Runner { driver, stack })
+    });
+    // safety: the state lives for as long as the stack exists, because `new()` and
+    // `add_interface()` borrow the resources for `'d`.
+    unsafe { core::mem::transmute::<&mut IfaceState, &'static mut IfaceState>(state) }
 }
 
 fn to_smoltcp_hardware_address(addr: driver::HardwareAddress) -> (HardwareAddress, Medium) {
@@ -432,51 +622,146 @@ impl<'d> Stack<'d> {
         f(&mut self.inner.borrow_mut())
     }
 
-    /// Get the hardware address of the network interface.
+    /// Add a network interface to the stack.
+    ///
+    /// The returned runner must be run in a background task, like the one of the primary
+    /// interface. Each interface has its own configuration, and sockets are routed to one of them
+    /// according to the routes of the stack, see [`Stack::add_route`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack already has [`MAX_INTERFACES`] interfaces.
+    pub fn add_interface<D: Driver, const SOCK: usize>(
+        &self,
+        mut driver: D,
+        config: Config,
+        resources: &'d mut InterfaceResources<SOCK>,
+    ) -> (Interface<'d>, Runner<'d, D>) {
+        let id = self.with_mut(|i| {
+            let id = InterfaceId(i.ifaces.len() as u8);
+            if i.ifaces.is_full() {
+                panic!("Too many interfaces, the maximum is {}", MAX_INTERFACES);
+            }
+            let random_seed = i.random_seed ^ (id.0 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let state = new_interface(&mut driver, &config, resources, random_seed);
+            unwrap!(i.ifaces.push(state).ok());
+            i.configure(id, config);
+            id
+        });
+        (
+            self.interface(id),
+            Runner {
+                driver,
+                stack: *self,
+                iface: id,
+            },
+        )
+    }
+
+    /// Get a handle to the interface with the given identifier.
+    pub fn interface(&self, id: InterfaceId) -> Interface<'d> {
+        Interface { stack: *self, id }
+    }
+
+    /// Get handles to all the interfaces of the stack.
+    pub fn interfaces(&self) -> impl Iterator<Item = Interface<'d>> + 'd {
+        let stack = *self;
+        let count = self.with(|i| i.ifaces.len());
+        (0..count).map(move |index| stack.interface(InterfaceId(index as u8)))
+    }
+
+    /// Get information about the sockets of the stack, on all interfaces, at most `N` of them.
+    ///
+    /// This includes the sockets used internally by the stack, for DNS and DHCP.
+    pub fn sockets<const N: usize>(&self) -> Vec<SocketInfo, N> {
+        self.with(|i| {
+            i.ifaces
+                .iter()
+                .enumerate()
+                .flat_map(|(index, state)| {
+                    let iface = InterfaceId(index as u8);
+                    (state.sockets.iter()).map(move |(_, socket)| SocketInfo::new(iface, socket, &state.counters))
+                })
+                .take(N)
+                .collect()
+        })
+    }
+
+    /// Add a route through one of the interfaces.
+    ///
+    /// Besides these routes, each interface has a route to the networks of its addresses, and a
+    /// default route if it has a default gateway, with the metric of the interface. Routes through
+    /// interfaces whose link is down are not used.
+    ///
+    /// The gateways of the routes are looked up in the routing table of the interface, which
+    /// holds two routes by default and always holds its default gateways first. Routes whose
+    /// gateway does not fit are still used to choose the interface, but their packets are sent to
+    /// the default gateway of the interface. The size of this table can be increased with the
+    /// `iface-max-route-count-*` features of smoltcp.
+    pub fn add_route(&self, route: Route) -> Result<(), RouteError> {
+        self.with_mut(|i| {
+            if route.interface.index() >= i.ifaces.len() {
+                return Err(RouteError::UnknownInterface);
+            }
+            let iface = route.interface;
+            i.routes.push(route).map_err(|_| RouteError::TableFull)?;
+            i.install_routes(iface);
+            Ok(())
+        })
+    }
+
+    /// Remove a route added with [`Stack::add_route`].
+    ///
+    /// Returns whether the route was found.
+    pub fn remove_route(&self, route: &Route) -> bool {
+        self.with_mut(|i| {
+            let Some(pos) = i.routes.iter().position(|r| r == route) else {
+                return false;
+            };
+            i.routes.remove(pos);
+            i.install_routes(route.interface);
+            true
+        })
+    }
+
+    /// Get the routes added with [`Stack::add_route`].
+    pub fn routes(&self) -> Vec<Route, MAX_ROUTES> {
+        self.with(|i| i.routes.clone())
+    }
+
+    /// Get the interface used to reach `addr`, if any.
+    pub fn route(&self, addr: IpAddress) -> Option<InterfaceId> {
+        self.with(|i| i.lookup_route(addr))
+    }
+
+    fn primary(&self) -> Interface<'d> {
+        self.interface(InterfaceId::PRIMARY)
+    }
+
+    /// Get the hardware address of the primary network interface.
     pub fn hardware_address(&self) -> HardwareAddress {
-        self.with(|i| i.hardware_address)
+        self.primary().hardware_address()
     }
 
-    /// Check whether the link is up.
+    /// Check whether the link of the primary interface is up.
     pub fn is_link_up(&self) -> bool {
-        self.with(|i| i.link_up)
+        self.primary().is_link_up()
     }
 
-    /// Check whether the network stack has a valid IP configuration.
+    /// Check whether the primary interface has a valid IP configuration.
     /// This is true if the network stack has a static IP configuration or if DHCP has completed
     pub fn is_config_up(&self) -> bool {
-        let v4_up;
-        let v6_up;
-
-        #[cfg(feature = "proto-ipv4")]
-        {
-            v4_up = self.config_v4().is_some();
-        }
-        #[cfg(not(feature = "proto-ipv4"))]
-        {
-            v4_up = false;
-        }
-
-        #[cfg(feature = "proto-ipv6")]
-        {
-            v6_up = self.config_v6().is_some();
-        }
-        #[cfg(not(feature = "proto-ipv6"))]
-        {
-            v6_up = false;
-        }
-
-        v4_up || v6_up
+        self.primary().is_config_up()
     }
 
     /// Wait for the network device to obtain a link signal.
     pub async fn wait_link_up(&self) {
-        self.wait(|| self.is_link_up()).await
+        self.primary().wait_link_up().await
     }
 
     /// Wait for the network device to lose link signal.
     pub async fn wait_link_down(&self) {
-        self.wait(|| !self.is_link_up()).await
+        self.primary().wait_link_down().await
     }
 
     /// Wait for the network stack to obtain a valid IP configuration.
@@ -509,12 +794,12 @@ impl<'d> Stack<'d> {
     /// // ...
     /// ```
     pub async fn wait_config_up(&self) {
-        self.wait(|| self.is_config_up()).await
+        self.primary().wait_config_up().await
     }
 
     /// Wait for the network stack to lose a valid IP configuration.
     pub async fn wait_config_down(&self) {
-        self.wait(|| !self.is_config_up()).await
+        self.primary().wait_config_down().await
     }
 
     fn wait<'a>(&'a self, mut predicate: impl FnMut() -> bool + 'a) -> impl Future<Output = ()> + 'a {
@@ -535,40 +820,38 @@ impl<'d> Stack<'d> {
         })
     }
 
-    /// Get the current IPv4 configuration.
+    /// Get the current IPv4 configuration of the primary interface.
     ///
     /// If using DHCP, this will be None if DHCP hasn't been able to
     /// acquire an IP address, or Some if it has.
     #[cfg(feature = "proto-ipv4")]
     pub fn config_v4(&self) -> Option<StaticConfigV4> {
-        self.with(|i| i.static_v4.clone())
+        self.primary().config_v4()
     }
 
-    /// Get the current IPv6 configuration.
+    /// Get the current IPv6 configuration of the primary interface.
     #[cfg(feature = "proto-ipv6")]
     pub fn config_v6(&self) -> Option<StaticConfigV6> {
-        self.with(|i| i.static_v6.clone())
+        self.primary().config_v6()
     }
 
-    /// Set the IPv4 configuration.
+    /// Set the IPv4 configuration of the primary interface.
     #[cfg(feature = "proto-ipv4")]
     pub fn set_config_v4(&self, config: ConfigV4) {
-        self.with_mut(|i| {
-            i.set_config_v4(config);
-            i.apply_static_config();
-        })
+        self.primary().set_config_v4(config)
     }
 
-    /// Set the IPv6 configuration.
+    /// Set the IPv6 configuration of the primary interface.
     #[cfg(feature = "proto-ipv6")]
     pub fn set_config_v6(&self, config: ConfigV6) {
-        self.with_mut(|i| {
-            i.set_config_v6(config);
-            i.apply_static_config();
-        })
+        self.primary().set_config_v6(config)
     }
 
     /// Make a query for a given name and return the corresponding IP addresses.
+    ///
+    /// The query goes through the interface with the lowest metric that has DNS servers and its
+    /// link up, or through the primary interface if there is none. Every interface that can be
+    /// used needs a socket slot for it.
     #[cfg(feature = "dns")]
     pub async fn dns_query(
         &self,
@@ -594,10 +877,16 @@ impl<'d> Stack<'d> {
 
         let query = poll_fn(|cx| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
-                match socket.start_query(i.iface.context(), name, qtype) {
+                // The socket can only change interface while no query is in progress.
+                if i.dns_queries == 0 {
+                    i.update_dns_interface();
+                }
+                let id = i.dns_socket;
+                let (socket, iface) = i.socket_mut::<dns::Socket>(id);
+                match socket.start_query(iface.context(), name, qtype) {
                     Ok(handle) => {
-                        i.waker.wake();
+                        i.dns_queries += 1;
+                        i.wake(id);
                         Poll::Ready(Ok(handle))
                     }
                     Err(dns::StartQueryError::NoFreeSlot) => {
@@ -635,18 +924,20 @@ impl<'d> Stack<'d> {
 
         let drop = OnDrop::new(|| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
-                socket.cancel_query(query);
-                i.waker.wake();
+                let id = i.dns_socket;
+                i.socket_mut::<dns::Socket>(id).0.cancel_query(query);
+                i.dns_queries -= 1;
+                i.wake(id);
                 i.dns_waker.wake();
             })
         });
 
         let res = poll_fn(|cx| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
+                let (socket, _) = i.socket_mut::<dns::Socket>(i.dns_socket);
                 match socket.get_query_result(query) {
                     Ok(addrs) => {
+                        i.dns_queries -= 1;
                         i.dns_waker.wake();
                         Poll::Ready(Ok(addrs))
                     }
@@ -655,6 +946,7 @@ impl<'d> Stack<'d> {
                         Poll::Pending
                     }
                     Err(e) => {
+                        i.dns_queries -= 1;
                         i.dns_waker.wake();
                         Poll::Ready(Err(e.into()))
                     }
@@ -671,122 +963,354 @@ impl<'d> Stack<'d> {
 
 #[cfg(feature = "multicast")]
 impl<'d> Stack<'d> {
-    /// Join a multicast group.
+    /// Join a multicast group on the primary interface.
     pub fn join_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
-        self.with_mut(|i| i.iface.join_multicast_group(addr))
+        self.primary().join_multicast_group(addr)
     }
 
-    /// Leave a multicast group.
+    /// Leave a multicast group on the primary interface.
     pub fn leave_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
-        self.with_mut(|i| i.iface.leave_multicast_group(addr))
+        self.primary().leave_multicast_group(addr)
     }
 
-    /// Get whether the network stack has joined the given multicast group.
+    /// Get whether the primary interface has joined the given multicast group.
     pub fn has_multicast_group(&self, addr: impl Into<IpAddress>) -> bool {
-        self.with(|i| i.iface.has_multicast_group(addr))
+        self.primary().has_multicast_group(addr)
     }
 }
 
-impl Inner {
-    #[cfg(feature = "slaac")]
-    fn get_link_local_address(&self) -> IpCidr {
-        let ll_prefix = Ipv6Cidr::new(Ipv6Cidr::LINK_LOCAL_PREFIX.address(), 64);
-        Ipv6Cidr::from_link_prefix(&ll_prefix, self.hardware_address)
-            .unwrap()
-            .into()
+impl<'d> Interface<'d> {
+    fn with<R>(&self, f: impl FnOnce(&IfaceState) -> R) -> R {
+        self.stack.with(|i| f(i.ifaces[self.id.index()]))
     }
 
-    #[allow(clippy::absurd_extreme_comparisons)]
-    pub fn get_local_port(&mut self) -> u16 {
-        let res = self.next_local_port;
-        self.next_local_port = if res >= LOCAL_PORT_MAX { LOCAL_PORT_MIN } else { res + 1 };
-        res
+    /// Get the identifier of the interface.
+    pub fn id(&self) -> InterfaceId {
+        self.id
     }
 
-    #[cfg(feature = "proto-ipv4")]
-    pub fn set_config_v4(&mut self, config: ConfigV4) {
-        // Handle static config.
-        self.static_v4 = match config.clone() {
-            ConfigV4::None => None,
-            #[cfg(feature = "dhcpv4")]
-            ConfigV4::Dhcp(_) => None,
-            ConfigV4::Static(c) => Some(c),
-        };
+    /// Get the hardware address of the interface.
+    pub fn hardware_address(&self) -> HardwareAddress {
+        self.with(|s| s.hardware_address)
+    }
 
-        // Handle DHCP config.
-        #[cfg(feature = "dhcpv4")]
-        match config {
-            ConfigV4::Dhcp(c) => {
-                // Create the socket if it doesn't exist.
-                if self.dhcp_socket.is_none() {
-                    let socket = smoltcp::socket::dhcpv4::Socket::new();
-                    let handle = self.sockets.add(socket);
-                    self.dhcp_socket = Some(handle);
-                }
+    /// Check whether the link is up.
+    pub fn is_link_up(&self) -> bool {
+        self.with(|s| s.link_up)
+    }
 
-                // Configure it
-                let socket = self.sockets.get_mut::<dhcpv4::Socket>(unwrap!(self.dhcp_socket));
-                socket.set_ignore_naks(c.ignore_naks);
-                socket.set_max_lease_duration(c.max_lease_duration.map(crate::time::duration_to_smoltcp));
-                socket.set_ports(c.server_port, c.client_port);
-                socket.set_retry_config(c.retry_config);
+    /// Check whether the interface has a valid IP configuration.
+    /// This is true if the interface has a static IP configuration or if DHCP has completed
+    pub fn is_config_up(&self) -> bool {
+        self.with(|s| s.is_config_up())
+    }
 
-                socket.set_outgoing_options(&[]);
-                #[cfg(feature = "dhcpv4-hostname")]
-                if let Some(h) = c.hostname {
-                    // safety:
-                    // - we just did set_outgoing_options([]) so we know the socket is no longer holding a reference.
-                    // - we know this pointer lives for as long as the stack exists, because `new()` borrows
-                    //   the resources for `'d`. Therefore it's OK to pass a reference to this to smoltcp.
-                    let hostname = unsafe { &mut *self.hostname };
+    /// Wait for the network device to obtain a link signal.
+    pub async fn wait_link_up(&self) {
+        self.stack.wait(|| self.is_link_up()).await
+    }
 
-                    // create data
-                    let data = hostname.data.write([0; MAX_HOSTNAME_LEN]);
-                    data[..h.len()].copy_from_slice(h.as_bytes());
-                    let data: &[u8] = &data[..h.len()];
+    /// Wait for the network device to lose link signal.
+    pub async fn wait_link_down(&self) {
+        self.stack.wait(|| !self.is_link_up()).await
+    }
 
-                    // set the option.
-                    let option = hostname.option.write(smoltcp::wire::DhcpOption { data, kind: 12 });
-                    socket.set_outgoing_options(core::slice::from_ref(option));
-                }
+    /// Wait for the interface to obtain a valid IP configuration.
+    pub async fn wait_config_up(&self) {
+        self.stack.wait(|| self.is_config_up()).await
+    }
 
-                socket.reset();
+    /// Wait for the interface to lose a valid IP configuration.
+    pub async fn wait_config_down(&self) {
+        self.stack.wait(|| !self.is_config_up()).await
+    }
+
+    /// Get the current IPv4 configuration.
+    ///
+    /// If using DHCP, this will be None if DHCP hasn't been able to
+    /// acquire an IP address, or Some if it has.
+    #[cfg(feature = "proto-ipv4")]
+    pub fn config_v4(&self) -> Option<StaticConfigV4> {
+        self.with(|s| s.static_v4.clone())
+    }
+
+    /// Get the current IPv6 configuration.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn config_v6(&self) -> Option<StaticConfigV6> {
+        self.with(|s| s.static_v6.clone())
+    }
+
+    /// Set the IPv4 configuration.
+    #[cfg(feature = "proto-ipv4")]
+    pub fn set_config_v4(&self, config: ConfigV4) {
+        self.stack.with_mut(|i| {
+            i.ifaces[self.id.index()].set_config_v4(config);
+            i.apply_static_config(self.id);
+        })
+    }
+
+    /// Set the IPv6 configuration.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn set_config_v6(&self, config: ConfigV6) {
+        self.stack.with_mut(|i| {
+            i.ifaces[self.id.index()].set_config_v6(config);
+            i.apply_static_config(self.id);
+        })
+    }
+
+    /// Get the statistics of the interface.
+    ///
+    /// The counters maintained by the driver, such as [`rx_dropped`](InterfaceStatistics::rx_dropped),
+    /// are updated when the stack polls the interface.
+    pub fn statistics(&self) -> InterfaceStatistics {
+        self.with(|s| s.counters.get())
+    }
+
+    /// Get the metric of the interface.
+    pub fn metric(&self) -> u32 {
+        self.with(|s| s.metric)
+    }
+
+    /// Set the metric of the interface, used for its default route and the routes to the
+    /// networks of its addresses. Defaults to 0.
+    ///
+    /// When several interfaces have a default route, the one with the lowest metric is used.
+    pub fn set_metric(&self, metric: u32) {
+        self.stack.with_mut(|i| i.ifaces[self.id.index()].metric = metric)
+    }
+}
+
+#[cfg(feature = "multicast")]
+impl<'d> Interface<'d> {
+    /// Join a multicast group.
+    pub fn join_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
+        self.stack
+            .with_mut(|i| i.ifaces[self.id.index()].iface.join_multicast_group(addr))
+    }
+
+    /// Leave a multicast group.
+    pub fn leave_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
+        self.stack
+            .with_mut(|i| i.ifaces[self.id.index()].iface.leave_multicast_group(addr))
+    }
+
+    /// Get whether the interface has joined the given multicast group.
+    pub fn has_multicast_group(&self, addr: impl Into<IpAddress>) -> bool {
+        self.with(|s| s.iface.has_multicast_group(addr))
+    }
+}
+
+impl Inner {
+    #[allow(clippy::absurd_extreme_comparisons)]
+    pub fn get_local_port(&mut self) -> u16 {
+        let res = self.next_local_port;
+        self.next_local_port = if res >= LOCAL_PORT_MAX { LOCAL_PORT_MIN } else { res + 1 };
+        res
+    }
+
+    /// Add a socket to the interface of the preferred default route, or to the first interface
+    /// with a free socket slot if that one has none.
+    ///
+    /// Panics if no interface has a free socket slot.
+    pub(crate) fn add_socket<T: AnySocket<'static>>(&mut self, socket: T) -> SocketId {
+        let preferred = self.preferred_interface(|s| s.has_gateway());
+        let iface = if self.ifaces[preferred.index()].has_free_socket() {
+            preferred
+        } else {
+            match self.ifaces.iter().position(|s| s.has_free_socket()) {
+                Some(index) => InterfaceId(index as u8),
+                None => panic!("No free socket slot on any interface of the stack"),
             }
-            _ => {
-                // Remove DHCP socket if any.
-                if let Some(socket) = self.dhcp_socket {
-                    self.sockets.remove(socket);
-                    self.dhcp_socket = None;
+        };
+        let handle = self.ifaces[iface.index()].sockets.add(socket);
+        SocketId { iface, handle }
+    }
+
+    pub(crate) fn remove_socket(&mut self, id: SocketId) {
+        self.ifaces[id.iface.index()].sockets.remove(id.handle);
+    }
+
+    #[cfg(any(feature = "tcp", feature = "udp", feature = "icmp"))]
+    pub(crate) fn socket<T: AnySocket<'static>>(&self, id: SocketId) -> (&T, &SmolInterface) {
+        let state = &self.ifaces[id.iface.index()];
+        (state.sockets.get::<T>(id.handle), &state.iface)
+    }
+
+    pub(crate) fn socket_mut<T: AnySocket<'static>>(&mut self, id: SocketId) -> (&mut T, &mut SmolInterface) {
+        let state = &mut *self.ifaces[id.iface.index()];
+        (state.sockets.get_mut::<T>(id.handle), &mut state.iface)
+    }
+
+    /// Wake the runner of the interface of a socket.
+    pub(crate) fn wake(&mut self, id: SocketId) {
+        self.ifaces[id.iface.index()].waker.wake();
+    }
+
+    /// Move a socket to the socket set of another interface.
+    #[cfg(any(feature = "dns", feature = "tcp", feature = "udp", feature = "icmp"))]
+    pub(crate) fn move_socket(&mut self, id: SocketId, iface: InterfaceId) -> Result<SocketId, BindInterfaceError> {
+        if id.iface == iface {
+            return Ok(id);
+        }
+        if !self.ifaces[iface.index()].has_free_socket() {
+            return Err(BindInterfaceError::NoFreeSocket);
+        }
+        let socket = self.ifaces[id.iface.index()].sockets.remove(id.handle);
+        let handle = self.ifaces[iface.index()].sockets.add(socket);
+        let id = SocketId { iface, handle };
+        self.wake(id);
+        Ok(id)
+    }
+
+    /// Move a socket to the interface used to reach `addr`, if there is one.
+    #[cfg(any(feature = "tcp", feature = "icmp"))]
+    pub(crate) fn route_socket(&mut self, id: SocketId, addr: IpAddress) -> Result<SocketId, BindInterfaceError> {
+        match self.lookup_route(addr) {
+            Some(iface) => self.move_socket(id, iface),
+            None => Ok(id),
+        }
+    }
+
+    /// Move a socket to the interface having `addr`, if there is one.
+    #[cfg(any(feature = "tcp", feature = "udp"))]
+    pub(crate) fn bind_socket(&mut self, id: SocketId, addr: IpAddress) -> Result<SocketId, BindInterfaceError> {
+        let iface = self.ifaces.iter().position(|s| s.iface.has_ip_addr(addr));
+        match iface {
+            Some(index) => self.move_socket(id, InterfaceId(index as u8)),
+            None => Ok(id),
+        }
+    }
+
+    /// Find the interface used to reach `addr`.
+    ///
+    /// The route with the longest matching prefix wins, then the one with the lowest metric, then
+    /// the one of the interface added first.
+    fn lookup_route(&self, addr: IpAddress) -> Option<InterfaceId> {
+        let mut best: Option<(u8, u32, InterfaceId)> = None;
+        let mut consider = |prefix_len: u8, metric: u32, iface: InterfaceId| {
+            if best.is_none_or(|(p, m, _)| prefix_len > p || (prefix_len == p && metric < m)) {
+                best = Some((prefix_len, metric, iface));
+            }
+        };
+
+        for (index, state) in self.ifaces.iter().enumerate() {
+            if !state.link_up {
+                continue;
+            }
+            let iface = InterfaceId(index as u8);
+            for cidr in state.iface.ip_addrs() {
+                if cidr.contains_addr(&addr) {
+                    consider(cidr.prefix_len(), state.metric, iface);
                 }
             }
+            if state.has_default_route(addr) {
+                consider(0, state.metric, iface);
+            }
+        }
+        for route in &self.routes {
+            let state = &self.ifaces[route.interface.index()];
+            if state.link_up && state.has_address_for(addr) && route.destination.contains_addr(&addr) {
+                consider(route.destination.prefix_len(), route.metric, route.interface);
+            }
         }
+
+        best.map(|(_, _, iface)| iface)
     }
 
-    #[cfg(feature = "proto-ipv6")]
-    pub fn set_config_v6(&mut self, config: ConfigV6) {
-        #[cfg(feature = "slaac")]
+    /// Find the interface with the lowest metric matching `f` whose link is up, or the primary
+    /// interface if there is none.
+    fn preferred_interface(&self, f: impl Fn(&IfaceState) -> bool) -> InterfaceId {
+        self.ifaces
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.link_up && f(s))
+            .min_by_key(|(index, s)| (s.metric, *index))
+            .map(|(index, _)| InterfaceId(index as u8))
+            .unwrap_or(InterfaceId::PRIMARY)
+    }
+
+    /// Install the gateways of the routes through an interface in its routing table, as far as
+    /// there is room left by its default gateways.
+    fn install_routes(&mut self, id: InterfaceId) {
+        let state = &mut *self.ifaces[id.index()];
+        state.uninstall_routes();
+
+        let mut routes: Vec<&Route, MAX_ROUTES> = self.routes.iter().filter(|r| r.interface == id).collect();
+        // Prefer the most specific routes when they don't all fit.
+        routes.sort_unstable_by_key(|r| core::cmp::Reverse(r.destination.prefix_len()));
+
+        let installed = &mut state.installed_routes;
+        state.iface.routes_mut().update(|table| {
+            for route in routes {
+                let Some(gateway) = route.gateway else { continue };
+                let key = (route.destination, gateway);
+                if table.iter().any(|r| (r.cidr, r.via_router) == key) {
+                    continue;
+                }
+                let route = smoltcp::iface::Route {
+                    cidr: route.destination,
+                    via_router: gateway,
+                    preferred_until: None,
+                    expires_at: None,
+                };
+                if table.push(route).is_err() {
+                    warn!("Interface routing table full, route to {:?} not installed", key.0);
+                    break;
+                }
+                unwrap!(installed.push(key).ok());
+            }
+        });
+    }
+
+    fn configure(&mut self, id: InterfaceId, config: Config) {
+        #[cfg(feature = "proto-ipv4")]
+        self.ifaces[id.index()].set_config_v4(config.ipv4);
+        #[cfg(feature = "proto-ipv6")]
+        self.ifaces[id.index()].set_config_v6(config.ipv6);
+        self.apply_static_config(id);
+    }
+
+    /// Move the DNS socket to the preferred interface for queries.
+    #[cfg(feature = "dns")]
+    fn update_dns_interface(&mut self) {
+        let iface = self.preferred_interface(|s| !s.dns_servers().is_empty());
+        if let Ok(id) = self.move_socket(self.dns_socket, iface)
+            && id.iface != self.dns_socket.iface
         {
-            self.slaac = matches!(config, ConfigV6::Slaac);
+            self.dns_socket = id;
+            self.update_dns_servers();
         }
-        self.static_v6 = match config {
-            ConfigV6::None => None,
-            ConfigV6::Static(c) => Some(c),
-            #[cfg(feature = "slaac")]
-            ConfigV6::Slaac => None,
-        };
     }
 
-    fn apply_static_config(&mut self) {
+    /// Give the DNS socket the DNS servers of its interface.
+    #[cfg(feature = "dns")]
+    fn update_dns_servers(&mut self) {
+        let state = &mut *self.ifaces[self.dns_socket.iface.index()];
+        let dns_servers = state.dns_servers();
+        if !dns_servers.is_empty() {
+            let count = if dns_servers.len() > DNS_MAX_SERVER_COUNT {
+                warn!("Number of DNS servers exceeds DNS_MAX_SERVER_COUNT, truncating list.");
+                DNS_MAX_SERVER_COUNT
+            } else {
+                dns_servers.len()
+            };
+            state
+                .sockets
+                .get_mut::<smoltcp::socket::dns::Socket>(self.dns_socket.handle)
+                .update_servers(&dns_servers[..count]);
+        }
+    }
+
+    fn apply_static_config(&mut self, id: InterfaceId) {
+        let state = &mut *self.ifaces[id.index()];
         let mut addrs = Vec::new();
-        #[cfg(feature = "dns")]
-        let mut dns_servers: Vec<_, 6> = Vec::new();
         #[cfg(feature = "proto-ipv4")]
         let mut gateway_v4 = None;
         #[cfg(feature = "proto-ipv6")]
         let mut gateway_v6 = None;
 
         #[cfg(feature = "proto-ipv4")]
-        if let Some(config) = &self.static_v4 {
+        if let Some(config) = &state.static_v4 {
             debug!("IPv4: UP");
             debug!("   IP address:      {:?}", config.address);
             debug!("   Default gateway: {:?}", config.gateway);
@@ -796,14 +1320,13 @@ impl Inner {
             #[cfg(feature = "dns")]
             for s in &config.dns_servers {
                 debug!("   DNS server:      {:?}", s);
-                unwrap!(dns_servers.push(s.clone().into()).ok());
             }
         } else {
             info!("IPv4: DOWN");
         }
 
         #[cfg(feature = "proto-ipv6")]
-        if let Some(config) = &self.static_v6 {
+        if let Some(config) = &state.static_v6 {
             debug!("IPv6: UP");
             debug!("   IP address:      {:?}", config.address);
             debug!("   Default gateway: {:?}", config.gateway);
@@ -813,59 +1336,53 @@ impl Inner {
             #[cfg(feature = "dns")]
             for s in &config.dns_servers {
                 debug!("   DNS server:      {:?}", s);
-                unwrap!(dns_servers.push(s.clone().into()).ok());
             }
         } else {
             info!("IPv6: DOWN");
         }
 
         // Apply addresses
-        self.iface.update_ip_addrs(|a| {
+        state.iface.update_ip_addrs(|a| {
             *a = addrs;
         });
 
         // Add the link local-address
         #[cfg(feature = "slaac")]
         {
-            let ll_address = self.get_link_local_address();
-            self.iface.update_ip_addrs(|a| {
+            let ll_address = state.get_link_local_address();
+            state.iface.update_ip_addrs(|a| {
                 let _ = a.push(ll_address);
             })
         }
 
-        // Apply gateways
+        // Apply gateways, which take precedence over the routes of the stack.
+        state.uninstall_routes();
         #[cfg(feature = "proto-ipv4")]
         if let Some(gateway) = gateway_v4 {
-            unwrap!(self.iface.routes_mut().add_default_ipv4_route(gateway));
+            unwrap!(state.iface.routes_mut().add_default_ipv4_route(gateway));
         } else {
-            self.iface.routes_mut().remove_default_ipv4_route();
+            state.iface.routes_mut().remove_default_ipv4_route();
         }
         #[cfg(feature = "proto-ipv6")]
         if let Some(gateway) = gateway_v6 {
-            unwrap!(self.iface.routes_mut().add_default_ipv6_route(gateway));
+            unwrap!(state.iface.routes_mut().add_default_ipv6_route(gateway));
         } else {
-            self.iface.routes_mut().remove_default_ipv6_route();
+            state.iface.routes_mut().remove_default_ipv6_route();
         }
+        self.install_routes(id);
 
         // Apply DNS servers
         #[cfg(feature = "dns")]
-        if !dns_servers.is_empty() {
-            let count = if dns_servers.len() > DNS_MAX_SERVER_COUNT {
-                warn!("Number of DNS servers exceeds DNS_MAX_SERVER_COUNT, truncating list.");
-                DNS_MAX_SERVER_COUNT
-            } else {
-                dns_servers.len()
-            };
-            self.sockets
-                .get_mut::<smoltcp::socket::dns::Socket>(self.dns_socket)
-                .update_servers(&dns_servers[..count]);
+        if self.dns_socket.iface == id {
+            self.update_dns_servers();
         }
 
         self.state_waker.wake();
     }
 
-    fn poll<D: Driver>(&mut self, cx: &mut Context<'_>, driver: &mut D) {
-        self.waker.register(cx.waker());
+    fn poll<D: Driver>(&mut self, cx: &mut Context<'_>, id: InterfaceId, driver: &mut D) {
+        let state = &mut *self.ifaces[id.index()];
+        state.waker.register(cx.waker());
 
         let (_hardware_addr, medium) = to_smoltcp_hardware_address(driver.hardware_address());
 
@@ -880,7 +1397,7 @@ impl Inner {
                 _ => false,
             };
             if do_set {
-                self.iface.set_hardware_addr(_hardware_addr);
+                state.iface.set_hardware_addr(_hardware_addr);
             }
         }
 
@@ -890,17 +1407,30 @@ impl Inner {
             inner: driver,
             medium,
             tx_exhausted: false,
+            counters: &state.counters,
         };
-        self.iface.poll(timestamp, &mut smoldev, &mut self.sockets);
+        state.iface.poll(timestamp, &mut smoldev, &mut state.sockets);
         let tx_exhausted = smoldev.tx_exhausted;
 
+        if tx_exhausted {
+            state.counters.tx_stall();
+        }
+        state.counters.set_driver(driver.statistics());
+        #[cfg(feature = "tcp-retransmissions")]
+        state.counters.retain_tcp(|local, remote| {
+            state.sockets.iter().any(|(_, s)| {
+                smoltcp::socket::tcp::Socket::downcast(s)
+                    .is_some_and(|s| s.local_endpoint() == Some(local) && s.remote_endpoint() == Some(remote))
+            })
+        });
+
         // Update link up
-        let old_link_up = self.link_up;
-        self.link_up = driver.link_state(cx) == LinkState::Up;
+        let old_link_up = state.link_up;
+        state.link_up = driver.link_state(cx) == LinkState::Up;
 
         // Print when changed
-        if old_link_up != self.link_up {
-            info!("link_up = {:?}", self.link_up);
+        if old_link_up != state.link_up {
+            info!("link_up = {:?}", state.link_up);
             self.state_waker.wake();
         }
 
@@ -909,21 +1439,21 @@ impl Inner {
 
         #[cfg(feature = "dhcpv4")]
         {
-            configure |= if let Some(dhcp_handle) = self.dhcp_socket {
-                let socket = self.sockets.get_mut::<dhcpv4::Socket>(dhcp_handle);
+            configure |= if let Some(dhcp_handle) = state.dhcp_socket {
+                let socket = state.sockets.get_mut::<dhcpv4::Socket>(dhcp_handle);
 
-                if self.link_up {
-                    if old_link_up != self.link_up {
+                if state.link_up {
+                    if old_link_up != state.link_up {
                         socket.reset();
                     }
                     match socket.poll() {
                         None => false,
                         Some(dhcpv4::Event::Deconfigured) => {
-                            self.static_v4 = None;
+                            state.static_v4 = None;
                             true
                         }
                         Some(dhcpv4::Event::Configured(config)) => {
-                            self.static_v4 = Some(StaticConfigV4 {
+                            state.static_v4 = Some(StaticConfigV4 {
                                 address: config.address,
                                 gateway: config.router,
                                 dns_servers: config.dns_servers,
@@ -933,7 +1463,7 @@ impl Inner {
                     }
                 } else if old_link_up {
                     socket.reset();
-                    self.static_v4 = None;
+                    state.static_v4 = None;
                     true
                 } else {
                     false
@@ -944,15 +1474,15 @@ impl Inner {
         }
 
         #[cfg(feature = "slaac")]
-        if self.slaac && self.iface.slaac_updated_at() == timestamp {
-            let ipv6_address = self.iface.ip_addrs().iter().find_map(|addr| match addr {
+        if state.slaac && state.iface.slaac_updated_at() == timestamp {
+            let ipv6_address = state.iface.ip_addrs().iter().find_map(|addr| match addr {
                 IpCidr::Ipv6(ip6_address) if !Ipv6Cidr::LINK_LOCAL_PREFIX.contains_addr(&ip6_address.address()) => {
                     Some(ip6_address)
                 }
                 _ => None,
             });
-            self.static_v6 = if let Some(address) = ipv6_address {
-                let gateway = self
+            state.static_v6 = if let Some(address) = ipv6_address {
+                let gateway = state
                     .iface
                     .routes()
                     .get_default_ipv6_route()
@@ -975,10 +1505,11 @@ impl Inner {
         }
 
         if configure {
-            self.apply_static_config()
+            self.apply_static_config(id)
         }
 
-        if let Some(poll_at) = self.iface.poll_at(timestamp, &mut self.sockets)
+        let state = &mut *self.ifaces[id.index()];
+        if let Some(poll_at) = state.iface.poll_at(timestamp, &mut state.sockets)
             && !tx_exhausted
         {
             let t = pin!(Timer::at(instant_from_smoltcp(poll_at)));
@@ -989,16 +1520,416 @@ impl Inner {
     }
 }
 
+impl IfaceState {
+    #[cfg(feature = "slaac")]
+    fn get_link_local_address(&self) -> IpCidr {
+        let ll_prefix = Ipv6Cidr::new(Ipv6Cidr::LINK_LOCAL_PREFIX.address(), 64);
+        Ipv6Cidr::from_link_prefix(&ll_prefix, self.hardware_address)
+            .unwrap()
+            .into()
+    }
+
+    fn is_config_up(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut up = false;
+        #[cfg(feature = "proto-ipv4")]
+        {
+            up |= self.static_v4.is_some();
+        }
+        #[cfg(feature = "proto-ipv6")]
+        {
+            up |= self.static_v6.is_some();
+        }
+        up
+    }
+
+    /// Whether the interface has an address to send packets to `addr` from.
+    fn has_address_for(&self, addr: IpAddress) -> bool {
+        match addr {
+            #[cfg(feature = "proto-ipv4")]
+            IpAddress::Ipv4(_) => self.static_v4.is_some(),
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(_) => self.static_v6.is_some(),
+        }
+    }
+
+    /// Whether the interface has a default gateway for `addr`.
+    fn has_default_route(&self, addr: IpAddress) -> bool {
+        match addr {
+            #[cfg(feature = "proto-ipv4")]
+            IpAddress::Ipv4(_) => self.static_v4.as_ref().is_some_and(|c| c.gateway.is_some()),
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(_) => self.static_v6.as_ref().is_some_and(|c| c.gateway.is_some()),
+        }
+    }
+
+    /// Whether the socket set of the interface has a free slot.
+    fn has_free_socket(&self) -> bool {
+        self.sockets.iter().count() < self.socket_capacity
+    }
+
+    /// Remove the routes installed by [`Inner::install_routes`] from the routing table.
+    fn uninstall_routes(&mut self) {
+        let installed = &mut self.installed_routes;
+        self.iface
+            .routes_mut()
+            .update(|table| table.retain(|r| !installed.contains(&(r.cidr, r.via_router))));
+        installed.clear();
+    }
+
+    /// Whether the interface has a default gateway for any IP version.
+    fn has_gateway(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut gateway = false;
+        #[cfg(feature = "proto-ipv4")]
+        {
+            gateway |= self.static_v4.as_ref().is_some_and(|c| c.gateway.is_some());
+        }
+        #[cfg(feature = "proto-ipv6")]
+        {
+            gateway |= self.static_v6.as_ref().is_some_and(|c| c.gateway.is_some());
+        }
+        gateway
+    }
+
+    #[cfg(feature = "dns")]
+    fn dns_servers(&self) -> Vec<IpAddress, 6> {
+        #[allow(unused_mut)]
+        let mut dns_servers = Vec::new();
+        #[cfg(feature = "proto-ipv4")]
+        if let Some(config) = &self.static_v4 {
+            for s in &config.dns_servers {
+                unwrap!(dns_servers.push((*s).into()).ok());
+            }
+        }
+        #[cfg(feature = "proto-ipv6")]
+        if let Some(config) = &self.static_v6 {
+            for s in &config.dns_servers {
+                unwrap!(dns_servers.push((*s).into()).ok());
+            }
+        }
+        dns_servers
+    }
+
+    #[cfg(feature = "proto-ipv4")]
+    fn set_config_v4(&mut self, config: ConfigV4) {
+        // Handle static config.
+        self.static_v4 = match config.clone() {
+            ConfigV4::None => None,
+            #[cfg(feature = "dhcpv4")]
+            ConfigV4::Dhcp(_) => None,
+            ConfigV4::Static(c) => Some(c),
+        };
+
+        // Handle DHCP config.
+        #[cfg(feature = "dhcpv4")]
+        match config {
+            ConfigV4::Dhcp(c) => {
+                // Create the socket if it doesn't exist.
+                if self.dhcp_socket.is_none() {
+                    let socket = smoltcp::socket::dhcpv4::Socket::new();
+                    let handle = self.sockets.add(socket);
+                    self.dhcp_socket = Some(handle);
+                }
+
+                // Configure it
+                let socket = self.sockets.get_mut::<dhcpv4::Socket>(unwrap!(self.dhcp_socket));
+                socket.set_ignore_naks(c.ignore_naks);
+                socket.set_max_lease_duration(c.max_lease_duration.map(crate::time::duration_to_smoltcp));
+                socket.set_ports(c.server_port, c.client_port);
+                socket.set_retry_config(c.retry_config);
+
+                socket.set_outgoing_options(&[]);
+                #[cfg(feature = "dhcpv4-hostname")]
+                if let Some(h) = c.hostname {
+                    // safety:
+                    // - we just did set_outgoing_options([]) so we know the socket is no longer holding a reference.
+                    // - we know this pointer lives for as long as the stack exists, because `new()` borrows
+                    //   the resources for `'d`. Therefore it's OK to pass a reference to this to smoltcp.
+                    let hostname = unsafe { &mut *self.hostname };
+
+                    // create data
+                    let data = hostname.data.write([0; MAX_HOSTNAME_LEN]);
+                    data[..h.len()].copy_from_slice(h.as_bytes());
+                    let data: &[u8] = &data[..h.len()];
+
+                    // set the option.
+                    let option = hostname.option.write(smoltcp::wire::DhcpOption { data, kind: 12 });
+                    socket.set_outgoing_options(core::slice::from_ref(option));
+                }
+
+                socket.reset();
+            }
+            _ => {
+                // Remove DHCP socket if any.
+                if let Some(socket) = self.dhcp_socket {
+                    self.sockets.remove(socket);
+                    self.dhcp_socket = None;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "proto-ipv6")]
+    fn set_config_v6(&mut self, config: ConfigV6) {
+        #[cfg(feature = "slaac")]
+        {
+            self.slaac = matches!(config, ConfigV6::Slaac);
+        }
+        self.static_v6 = match config {
+            ConfigV6::None => None,
+            ConfigV6::Static(c) => Some(c),
+            #[cfg(feature = "slaac")]
+            ConfigV6::Slaac => None,
+        };
+    }
+}
+
 impl<'d, D: Driver> Runner<'d, D> {
     /// Run the network stack.
     ///
     /// You must call this in a background task, to process network events.
     pub async fn run(&mut self) -> ! {
         poll_fn(|cx| {
-            self.stack.with_mut(|i| i.poll(cx, &mut self.driver));
+            self.stack.with_mut(|i| i.poll(cx, self.iface, &mut self.driver));
             Poll::<()>::Pending
         })
         .await;
         unreachable!()
     }
 }
+
+#[cfg(all(
+    test,
+    feature = "proto-ipv4",
+    feature = "medium-ethernet",
+    feature = "tcp",
+    feature = "udp"
+))]
+mod tests {
+    use core::future::Future;
+
+    use embassy_futures::block_on;
+    use embassy_futures::join::join3;
+    use embassy_futures::select::{Either, select, select_array};
+    use embassy_net_virtual::driver::{HardwareAddress as DriverAddress, LinkState};
+    use embassy_net_virtual::{Config as LinkConfig, Link, Port};
+    use embassy_time::{Duration, with_timeout};
+
+    use super::*;
+    use crate::tcp::{ConnectError, TcpSocket};
+    use crate::udp::{BindError, PacketMetadata, UdpSocket};
+
+    type TestPort<'d> = Port<'d, 2, 1514, 16>;
+
+    /// Address of the peers, the same on both links.
+    const PEER: Ipv4Address = Ipv4Address::new(10, 9, 0, 1);
+    const PRIMARY_ADDR: Ipv4Address = Ipv4Address::new(10, 9, 0, 10);
+    const SECOND_ADDR: Ipv4Address = Ipv4Address::new(10, 9, 0, 20);
+
+    fn ipv4(address: Ipv4Address, prefix_len: u8, gateway: Option<Ipv4Address>) -> Config {
+        Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(address, prefix_len),
+            gateway,
+            dns_servers: Vec::new(),
+        })
+    }
+
+    fn port(link: &Link, index: usize, host: u8) -> TestPort<'_> {
+        link.port(index, DriverAddress::Ethernet([2, 0, 0, 0, 0, host]))
+    }
+
+    fn cidr(address: Ipv4Address, prefix_len: u8) -> IpCidr {
+        IpCidr::Ipv4(Ipv4Cidr::new(address, prefix_len))
+    }
+
+    /// Run the runners until `test` completes, failing it after 10 seconds.
+    fn run<const N: usize>(runners: &mut [Runner<'_, TestPort<'_>>; N], test: impl Future<Output = ()>) {
+        let runners = select_array(runners.each_mut().map(|r| r.run()));
+        match block_on(select(runners, with_timeout(Duration::from_secs(10), test))) {
+            Either::Second(r) => r.unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Accept a connection on port 1234 of `stack`.
+    async fn accept(stack: Stack<'_>) {
+        let (mut rx, mut tx) = ([0; 256], [0; 256]);
+        let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+        socket.accept(1234).await.unwrap();
+    }
+
+    /// Connect to port 1234 of the peer, returning the local address used.
+    async fn connect(stack: Stack<'_>, bind: Option<InterfaceId>) -> Result<IpAddress, ConnectError> {
+        let (mut rx, mut tx) = ([0; 256], [0; 256]);
+        let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+        if let Some(iface) = bind {
+            socket.bind_to_interface(iface)?;
+        }
+        socket.connect((PEER, 1234)).await?;
+        Ok(socket.local_endpoint().unwrap().addr)
+    }
+
+    #[test]
+    fn route_selection() {
+        let (link_a, link_b) = (Link::new(LinkConfig::default()), Link::new(LinkConfig::default()));
+        let mut resources = StackResources::<4>::new();
+        let mut iface_resources = InterfaceResources::<4>::new();
+        let primary_config = ipv4(Ipv4Address::new(10, 0, 0, 1), 24, Some(Ipv4Address::new(10, 0, 0, 254)));
+        let (stack, runner_a) = new(port(&link_a, 0, 1), primary_config, &mut resources, 1);
+        let second_config = ipv4(Ipv4Address::new(10, 1, 0, 1), 16, Some(Ipv4Address::new(10, 1, 0, 254)));
+        let (second, runner_b) = stack.add_interface(port(&link_b, 0, 2), second_config, &mut iface_resources);
+        let primary = stack.interface(InterfaceId::PRIMARY);
+
+        let route = |destination, interface, metric| Route {
+            destination,
+            gateway: None,
+            interface,
+            metric,
+        };
+        let route_to = |a, b, c, d| stack.route(IpAddress::v4(a, b, c, d));
+
+        let test = async {
+            primary.wait_link_up().await;
+            second.wait_link_up().await;
+
+            // Networks of the interface addresses, then default routes, preferring the first interface.
+            assert_eq!(route_to(10, 0, 0, 5), Some(InterfaceId::PRIMARY));
+            assert_eq!(route_to(10, 1, 2, 3), Some(second.id()));
+            assert_eq!(route_to(8, 8, 8, 8), Some(InterfaceId::PRIMARY));
+            primary.set_metric(10);
+            assert_eq!(route_to(8, 8, 8, 8), Some(second.id()));
+
+            // Longest prefix first, whatever the metric.
+            let wide = route(cidr(Ipv4Address::new(192, 168, 0, 0), 16), InterfaceId::PRIMARY, 100);
+            let narrow = route(cidr(Ipv4Address::new(192, 168, 1, 0), 24), second.id(), 200);
+            stack.add_route(wide.clone()).unwrap();
+            stack.add_route(narrow.clone()).unwrap();
+            assert_eq!(route_to(192, 168, 1, 1), Some(second.id()));
+            assert_eq!(route_to(192, 168, 2, 1), Some(InterfaceId::PRIMARY));
+
+            // Then the lowest metric.
+            let via_primary = route(cidr(Ipv4Address::new(172, 16, 0, 0), 12), InterfaceId::PRIMARY, 5);
+            let via_second = route(cidr(Ipv4Address::new(172, 16, 0, 0), 12), second.id(), 1);
+            stack.add_route(via_primary.clone()).unwrap();
+            stack.add_route(via_second.clone()).unwrap();
+            assert_eq!(route_to(172, 16, 0, 1), Some(second.id()));
+            assert!(stack.remove_route(&via_second));
+            assert!(!stack.remove_route(&via_second));
+            assert_eq!(route_to(172, 16, 0, 1), Some(InterfaceId::PRIMARY));
+            assert_eq!(stack.routes().as_slice(), [wide, narrow, via_primary]);
+
+            assert_eq!(
+                stack.add_route(route(cidr(Ipv4Address::UNSPECIFIED, 0), InterfaceId(3), 0)),
+                Err(RouteError::UnknownInterface)
+            );
+
+            // Routes through an interface whose link is down are skipped.
+            link_b.set_link_state(0, LinkState::Down);
+            second.wait_link_down().await;
+            assert_eq!(route_to(192, 168, 1, 1), Some(InterfaceId::PRIMARY));
+            assert_eq!(route_to(8, 8, 8, 8), Some(InterfaceId::PRIMARY));
+            link_a.set_link_state(0, LinkState::Down);
+            primary.wait_link_down().await;
+            assert_eq!(route_to(8, 8, 8, 8), None);
+        };
+        run(&mut [runner_a, runner_b], test);
+    }
+
+    #[test]
+    fn failover() {
+        let (link_a, link_b) = (Link::new(LinkConfig::default()), Link::new(LinkConfig::default()));
+        let [mut resources, mut resources_a, mut resources_b] = [const { StackResources::<4>::new() }; 3];
+        let mut iface_resources = InterfaceResources::<4>::new();
+        let (stack, runner) = new(port(&link_a, 0, 1), ipv4(PRIMARY_ADDR, 24, None), &mut resources, 1);
+        let (second, runner_second) =
+            stack.add_interface(port(&link_b, 0, 2), ipv4(SECOND_ADDR, 24, None), &mut iface_resources);
+        let (peer_a, runner_a) = new(port(&link_a, 1, 3), ipv4(PEER, 24, None), &mut resources_a, 3);
+        let (peer_b, runner_b) = new(port(&link_b, 1, 4), ipv4(PEER, 24, None), &mut resources_b, 4);
+        second.set_metric(1);
+
+        let client = async {
+            stack.wait_link_up().await;
+            second.wait_link_up().await;
+            assert_eq!(connect(stack, None).await, Ok(PRIMARY_ADDR.into()));
+
+            link_a.set_link_state(0, LinkState::Down);
+            stack.wait_link_down().await;
+            assert_eq!(connect(stack, None).await, Ok(SECOND_ADDR.into()));
+        };
+        let test = async {
+            join3(client, accept(peer_a), accept(peer_b)).await;
+        };
+        run(&mut [runner, runner_second, runner_a, runner_b], test);
+    }
+
+    #[test]
+    fn bind_to_interface() {
+        let (link_a, link_b) = (Link::new(LinkConfig::default()), Link::new(LinkConfig::default()));
+        let [mut resources, mut resources_a, mut resources_b] = [const { StackResources::<4>::new() }; 3];
+        // A single socket slot, to run out of them.
+        let mut iface_resources = InterfaceResources::<1>::new();
+        let (stack, runner) = new(port(&link_a, 0, 1), ipv4(PRIMARY_ADDR, 24, None), &mut resources, 1);
+        let (second, runner_second) =
+            stack.add_interface(port(&link_b, 0, 2), ipv4(SECOND_ADDR, 24, None), &mut iface_resources);
+        let (_peer_a, runner_a) = new(port(&link_a, 1, 3), ipv4(PEER, 24, None), &mut resources_a, 3);
+        let (peer_b, runner_b) = new(port(&link_b, 1, 4), ipv4(PEER, 24, None), &mut resources_b, 4);
+
+        let test = async {
+            stack.wait_link_up().await;
+            second.wait_link_up().await;
+            assert_eq!(stack.route(PEER.into()), Some(InterfaceId::PRIMARY));
+
+            // The bound socket goes through the second interface, filling its socket set.
+            let mut buffers = [[0; 256]; 8];
+            let mut meta = [[PacketMetadata::EMPTY; 4]; 8];
+            let [rx, tx, rx_peer, tx_peer, rx_a, tx_a, rx_b, tx_b] = &mut buffers;
+            let [
+                rx_meta,
+                tx_meta,
+                rx_meta_peer,
+                tx_meta_peer,
+                rx_meta_a,
+                tx_meta_a,
+                rx_meta_b,
+                tx_meta_b,
+            ] = &mut meta;
+            let mut socket = UdpSocket::new(stack, rx_meta, rx, tx_meta, tx);
+            socket.bind_to_interface(second.id()).unwrap();
+            socket.bind(1000).unwrap();
+            let mut peer_socket = UdpSocket::new(peer_b, rx_meta_peer, rx_peer, tx_meta_peer, tx_peer);
+            peer_socket.bind(1000).unwrap();
+            socket.send_to(b"hello", (PEER, 1000)).await.unwrap();
+            let mut buf = [0; 16];
+            let (n, meta) = peer_socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hello");
+            assert_eq!(meta.endpoint.addr, SECOND_ADDR.into());
+
+            // Sockets can't be moved to the full interface, nor routed to it.
+            let (mut rx, mut tx) = ([0; 256], [0; 256]);
+            let mut tcp = TcpSocket::new(stack, &mut rx, &mut tx);
+            assert_eq!(
+                tcp.bind_to_interface(second.id()),
+                Err(BindInterfaceError::NoFreeSocket)
+            );
+            stack.interface(InterfaceId::PRIMARY).set_metric(1);
+            assert_eq!(stack.route(PEER.into()), Some(second.id()));
+            assert_eq!(tcp.connect((PEER, 1234)).await, Err(ConnectError::NoFreeSocket));
+
+            // Open sockets stay on their interface.
+            assert_eq!(
+                socket.bind_to_interface(InterfaceId::PRIMARY),
+                Err(BindInterfaceError::InvalidState)
+            );
+            socket.close();
+            socket.bind_to_interface(InterfaceId::PRIMARY).unwrap();
+
+            // Binding to the address of an interface moves the socket to it.
+            let mut socket_a = UdpSocket::new(stack, rx_meta_a, rx_a, tx_meta_a, tx_a);
+            socket_a.bind((SECOND_ADDR, 1000)).unwrap();
+            let mut socket_b = UdpSocket::new(stack, rx_meta_b, rx_b, tx_meta_b, tx_b);
+            assert_eq!(socket_b.bind((SECOND_ADDR, 1001)), Err(BindError::NoFreeSocket));
+        };
+        run(&mut [runner, runner_second, runner_a, runner_b], test);
+    }
+}
@@ -0,0 +1,1603 @@
+//! mDNS responder and DNS-SD service advertisement.
+//!
+//! Answers the multicast DNS queries of the local link for the host name of the device
+//! (`<hostname>.local`), and advertises [DNS-SD](https://www.rfc-editor.org/rfc/rfc6763) services so
+//! that they can be browsed, for example with `avahi-browse` or the Bonjour browsers.
+//!
+//! Names are probed before being used, then announced, as specified by
+//! [RFC 6762](https://www.rfc-editor.org/rfc/rfc6762). When another host already uses one of them,
+//! it is renamed by appending a number, for example `device-2.local` or `Thermostat (2)`, and probed
+//! again.
+//!
+//! The responder runs on the primary interface of the stack, and answers with its addresses. The
+//! driver must receive the frames sent to the mDNS multicast groups.
+
+use core::fmt::Write as _;
+use core::iter;
+
+use embassy_time::{Duration, Instant, with_deadline};
+use heapless::{String, Vec};
+#[cfg(feature = "proto-ipv4")]
+use smoltcp::wire::Ipv4Address;
+#[cfg(feature = "proto-ipv6")]
+use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::udp::{PacketMetadata, UdpMetadata, UdpSocket};
+use crate::{InterfaceId, Stack};
+
+const MDNS_PORT: u16 = 5353;
+#[cfg(feature = "proto-ipv4")]
+const MDNS_IPV4_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+#[cfg(feature = "proto-ipv6")]
+const MDNS_IPV6_GROUP: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// Cache-flush bit of record classes, and unicast-response bit of question classes.
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_MASK: u16 = 0x000f;
+
+const HEADER_LEN: usize = 12;
+/// Largest message sent, so that it fits in the minimum IPv6 MTU.
+const MAX_MESSAGE_SIZE: usize = 1232;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_TXT_LEN: usize = 255;
+const MAX_ADDRESSES: usize = 4;
+const MAX_QUESTIONS: usize = 8;
+/// Limit of compression pointers followed in a name, to stop on loops.
+const MAX_POINTERS: usize = 16;
+
+/// TTL of the records naming the host, as recommended by RFC 6762.
+const HOST_TTL: u32 = 120;
+/// TTL of the other records, as recommended by RFC 6762.
+const SERVICE_TTL: u32 = 4500;
+/// TTL of the records sent to legacy resolvers, that do not flush their cache.
+const LEGACY_TTL: u32 = 10;
+
+const PROBE_COUNT: u8 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const ANNOUNCE_COUNT: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// After this many conflicts in a row, probes are delayed to limit the traffic.
+const MAX_CONFLICTS: u32 = 15;
+const CONFLICT_DELAY: Duration = Duration::from_secs(5);
+const TIEBREAK_DELAY: Duration = Duration::from_secs(1);
+
+const HOST_A: u8 = 1 << 0;
+const HOST_AAAA: u8 = 1 << 1;
+const SERVICE_PTR: u8 = 1 << 0;
+const SERVICE_SRV: u8 = 1 << 1;
+const SERVICE_TXT: u8 = 1 << 2;
+/// Service type enumeration record, pointing to the type of the service.
+const SERVICE_ENUM: u8 = 1 << 3;
+
+/// Error returned by [`MdnsResponder::add_service`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The instance name or the service type is not valid.
+    InvalidName,
+    /// A TXT entry is longer than 255 bytes.
+    InvalidTxt,
+    /// The service table is full.
+    TableFull,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::InvalidName => write!(f, "Invalid name"),
+            Error::InvalidTxt => write!(f, "Invalid TXT entry"),
+            Error::TableFull => write!(f, "Service table full"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// DNS-SD service advertised by a [`MdnsResponder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Service<'a> {
+    /// Name of the service instance, shown to the users, for example `Living room thermostat`.
+    pub instance: &'a str,
+    /// Type of the service and its transport protocol, for example `_http._tcp`.
+    pub service_type: &'a str,
+    /// Port of the service.
+    pub port: u16,
+    /// Entries of the TXT record, usually `key=value` pairs.
+    pub txt: &'a [&'a str],
+}
+
+/// Socket buffers for a [`MdnsResponder`].
+pub struct MdnsResponderResources {
+    rx_meta: [PacketMetadata; 4],
+    rx_buffer: [u8; 2048],
+    tx_meta: [PacketMetadata; 4],
+    tx_buffer: [u8; 2048],
+}
+
+impl MdnsResponderResources {
+    /// Create a new set of socket buffers.
+    pub const fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; 4],
+            rx_buffer: [0; 2048],
+            tx_meta: [PacketMetadata::EMPTY; 4],
+            tx_buffer: [0; 2048],
+        }
+    }
+}
+
+impl Default for MdnsResponderResources {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for the interface to have an address.
+    Idle,
+    /// Number of probes sent.
+    Probing(u8),
+    /// Number of announcements sent.
+    Announcing(u8),
+    Running,
+}
+
+/// mDNS responder advertising up to `S` services.
+pub struct MdnsResponder<'d, const S: usize> {
+    stack: Stack<'d>,
+    socket: UdpSocket<'d>,
+    host: Host<'d, S>,
+    state: State,
+    /// When to send the next probe or announcement.
+    next: Instant,
+    /// Number of conflicts since the names were last probed successfully.
+    conflicts: u32,
+}
+
+impl<'d, const S: usize> MdnsResponder<'d, S> {
+    /// Create a new mDNS responder on `stack`, answering for `<hostname>.local`.
+    ///
+    /// This joins the mDNS multicast groups on the primary interface of the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hostname` is not a valid DNS label: it must not be empty, be longer than 63 bytes
+    /// or contain dots. Also panics if no interface of the stack has a free socket slot.
+    pub fn new(stack: Stack<'d>, resources: &'d mut MdnsResponderResources, hostname: &str) -> Self {
+        assert!(is_valid_label(hostname) && !hostname.contains('.'));
+
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut resources.rx_meta,
+            &mut resources.rx_buffer,
+            &mut resources.tx_meta,
+            &mut resources.tx_buffer,
+        );
+        unwrap!(socket.bind_to_interface(InterfaceId::PRIMARY));
+        unwrap!(socket.bind(MDNS_PORT));
+        // Hosts must ignore mDNS messages received with another hop limit.
+        socket.set_hop_limit(Some(255));
+
+        let iface = stack.interface(InterfaceId::PRIMARY);
+        #[cfg(feature = "proto-ipv4")]
+        if let Err(e) = iface.join_multicast_group(MDNS_IPV4_GROUP) {
+            warn!("mdns: failed to join the IPv4 group: {:?}", e);
+        }
+        #[cfg(feature = "proto-ipv6")]
+        if let Err(e) = iface.join_multicast_group(MDNS_IPV6_GROUP) {
+            warn!("mdns: failed to join the IPv6 group: {:?}", e);
+        }
+
+        let hostname = unwrap!(String::try_from(hostname));
+        Self {
+            stack,
+            socket,
+            host: Host {
+                hostname_base: hostname.clone(),
+                hostname,
+                hostname_conflicts: 0,
+                services: Vec::new(),
+                addresses: Vec::new(),
+            },
+            state: State::Idle,
+            next: Instant::now(),
+            conflicts: 0,
+        }
+    }
+
+    /// Advertise a service.
+    ///
+    /// Services can be added while the responder is not running, its names are then probed when
+    /// [`run`](Self::run) is called again.
+    pub fn add_service(&mut self, service: Service<'d>) -> Result<(), Error> {
+        let mut labels = service.service_type.split('.');
+        let valid_type = matches!(
+            (labels.next(), labels.next(), labels.next()),
+            (Some(name), Some("_tcp" | "_udp"), None) if name.starts_with('_') && is_valid_label(name)
+        );
+        if !valid_type || !is_valid_label(service.instance) {
+            return Err(Error::InvalidName);
+        }
+        if service.txt.iter().any(|entry| entry.len() > MAX_TXT_LEN) {
+            return Err(Error::InvalidTxt);
+        }
+
+        let slot = Slot {
+            instance: unwrap!(String::try_from(service.instance)),
+            service,
+            conflicts: 0,
+        };
+        self.host.services.push(slot).map_err(|_| Error::TableFull)?;
+        if self.state != State::Idle {
+            self.restart(Duration::from_ticks(0));
+        }
+        Ok(())
+    }
+
+    /// Host name currently used, without the `.local` domain.
+    ///
+    /// This differs from the one given to [`new`](Self::new) after a conflict with another host.
+    pub fn hostname(&self) -> &str {
+        &self.host.hostname
+    }
+
+    /// Instance names currently used by the services, in the order they were added.
+    pub fn instances(&self) -> impl Iterator<Item = &str> {
+        self.host.services.iter().map(|slot| slot.instance.as_str())
+    }
+
+    /// Run the responder.
+    ///
+    /// It is allowed to cancel this function's future, for example to add services or to send
+    /// goodbye announcements with [`goodbye`](Self::goodbye).
+    pub async fn run(&mut self) -> ! {
+        loop {
+            self.update_addresses();
+
+            let state = self.state;
+            let deadline = match state {
+                // Check the addresses from time to time.
+                State::Idle | State::Running => Instant::now() + Duration::from_secs(1),
+                _ => self.next,
+            };
+            let host = &self.host;
+            let incoming = with_deadline(
+                deadline,
+                self.socket.recv_from_with(|buf, meta| host.parse(buf, &meta, state)),
+            )
+            .await;
+            match incoming {
+                Ok(Some(incoming)) => self.handle(&incoming).await,
+                Ok(None) => {}
+                Err(_) => self.tick().await,
+            }
+        }
+    }
+
+    /// Tell the other hosts that the names and services are going away.
+    ///
+    /// Call this after canceling [`run`](Self::run), for example before shutting the interface
+    /// down. The names are probed again the next time the responder runs.
+    pub async fn goodbye(&mut self) {
+        if matches!(self.state, State::Announcing(_) | State::Running) {
+            self.send_multicast(Outgoing::Announce { max_ttl: 0 }).await;
+        }
+        self.state = State::Idle;
+        self.host.addresses.clear();
+    }
+
+    fn update_addresses(&mut self) {
+        let addresses: Vec<IpAddress, MAX_ADDRESSES> = self.stack.interface(InterfaceId::PRIMARY).with(|s| {
+            s.iface
+                .ip_addrs()
+                .iter()
+                .map(|cidr| cidr.address())
+                .filter(|addr| !addr.is_unspecified())
+                .take(MAX_ADDRESSES)
+                .collect()
+        });
+        if addresses != self.host.addresses {
+            debug!("mdns: addresses changed");
+            self.host.addresses = addresses;
+            // Spread the probes of hosts starting at the same time.
+            self.restart(Duration::from_micros(Instant::now().as_micros() % 250_000));
+        }
+    }
+
+    /// Start probing the names again after `delay`.
+    fn restart(&mut self, delay: Duration) {
+        self.state = match self.host.addresses.is_empty() {
+            true => State::Idle,
+            false => State::Probing(0),
+        };
+        self.next = Instant::now() + delay;
+    }
+
+    async fn tick(&mut self) {
+        let now = Instant::now();
+        match self.state {
+            State::Probing(n) if n < PROBE_COUNT => {
+                self.send_multicast(Outgoing::Probe).await;
+                self.state = State::Probing(n + 1);
+                self.next = now + PROBE_INTERVAL;
+            }
+            State::Probing(_) => {
+                info!("mdns: using {}.local", self.host.hostname.as_str());
+                self.conflicts = 0;
+                self.state = State::Announcing(0);
+                self.next = now;
+            }
+            State::Announcing(n) if n < ANNOUNCE_COUNT => {
+                self.send_multicast(Outgoing::Announce { max_ttl: u32::MAX }).await;
+                self.state = State::Announcing(n + 1);
+                self.next = now + ANNOUNCE_INTERVAL;
+            }
+            State::Announcing(_) => self.state = State::Running,
+            State::Idle | State::Running => {}
+        }
+    }
+
+    async fn handle(&mut self, incoming: &Incoming<S>) {
+        if self.state == State::Idle {
+            return;
+        }
+
+        if incoming.conflict_host || incoming.conflict_services.contains(&true) {
+            self.host.rename(incoming);
+            self.conflicts += 1;
+            let delay = match self.conflicts > MAX_CONFLICTS {
+                true => CONFLICT_DELAY,
+                false => Duration::from_ticks(0),
+            };
+            self.restart(delay);
+        } else if incoming.lost_tiebreak {
+            // Another host is probing the same names, let it win and check again.
+            debug!("mdns: lost simultaneous probe tiebreak");
+            self.restart(TIEBREAK_DELAY);
+        } else if matches!(self.state, State::Announcing(_) | State::Running) && !incoming.answers.is_empty() {
+            let source = incoming.source;
+            let to = if incoming.legacy {
+                source
+            } else if incoming.unicast {
+                IpEndpoint::new(source.addr, MDNS_PORT)
+            } else {
+                group(source.addr)
+            };
+            send(&mut self.socket, &self.host, to, Outgoing::Reply(incoming)).await;
+        }
+    }
+
+    async fn send_multicast(&mut self, outgoing: Outgoing<'_, S>) {
+        for to in self.host.groups() {
+            send(&mut self.socket, &self.host, to, outgoing).await;
+        }
+    }
+}
+
+async fn send<const S: usize>(
+    socket: &mut UdpSocket<'_>,
+    host: &Host<'_, S>,
+    to: IpEndpoint,
+    outgoing: Outgoing<'_, S>,
+) {
+    let result = socket
+        .send_to_with(MAX_MESSAGE_SIZE, to, |buf| {
+            let mut message = Message::new(buf);
+            let (id, flags) = host.write(&mut message, outgoing);
+            (message.finish(id, flags), ())
+        })
+        .await;
+    if let Err(e) = result {
+        warn!("mdns: failed to send message: {:?}", e);
+    }
+}
+
+/// Multicast group of the family of `addr`.
+fn group(addr: IpAddress) -> IpEndpoint {
+    match addr {
+        #[cfg(feature = "proto-ipv4")]
+        IpAddress::Ipv4(_) => IpEndpoint::new(MDNS_IPV4_GROUP.into(), MDNS_PORT),
+        #[cfg(feature = "proto-ipv6")]
+        IpAddress::Ipv6(_) => IpEndpoint::new(MDNS_IPV6_GROUP.into(), MDNS_PORT),
+    }
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty() && label.len() <= MAX_LABEL_LEN
+}
+
+/// Append ` (n)` or `-n` for hosts to `base`, truncating it to fit in a label.
+fn renamed(base: &str, n: u32, host: bool) -> String<MAX_LABEL_LEN> {
+    let mut suffix = String::<16>::new();
+    let _ = match host {
+        true => write!(suffix, "-{}", n),
+        false => write!(suffix, " ({})", n),
+    };
+    let mut end = base.len().min(MAX_LABEL_LEN - suffix.len());
+    while !base.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut name = String::new();
+    unwrap!(name.push_str(&base[..end]));
+    unwrap!(name.push_str(&suffix));
+    name
+}
+
+struct Slot<'d> {
+    service: Service<'d>,
+    /// Instance name currently used.
+    instance: String<MAX_LABEL_LEN>,
+    conflicts: u32,
+}
+
+/// Names and records the responder is authoritative for.
+struct Host<'d, const S: usize> {
+    hostname_base: String<MAX_LABEL_LEN>,
+    hostname: String<MAX_LABEL_LEN>,
+    hostname_conflicts: u32,
+    services: Vec<Slot<'d>, S>,
+    addresses: Vec<IpAddress, MAX_ADDRESSES>,
+}
+
+/// Name in the `.local` domain.
+#[derive(Clone, Copy)]
+struct Name<'a> {
+    /// First label, that can contain dots.
+    label: Option<&'a str>,
+    /// Following labels, separated by dots.
+    domain: &'a str,
+}
+
+impl<'a> Name<'a> {
+    fn labels(self) -> impl Iterator<Item = &'a str> {
+        self.label
+            .into_iter()
+            .chain(self.domain.split('.').filter(|label| !label.is_empty()))
+            .chain(iter::once("local"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameId {
+    Host,
+    /// `_services._dns-sd._udp.local`, listing the service types.
+    Enumeration,
+    /// Type of the service at the index, and of those having the same type.
+    ServiceType(usize),
+    Instance(usize),
+}
+
+/// Records of the host, as `HOST_*` and `SERVICE_*` bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RecordSet<const S: usize> {
+    host: u8,
+    services: [u8; S],
+}
+
+impl<const S: usize> RecordSet<S> {
+    const EMPTY: Self = Self {
+        host: 0,
+        services: [0; S],
+    };
+
+    fn is_empty(&self) -> bool {
+        *self == Self::EMPTY
+    }
+
+    /// Records of the names that must be unique.
+    fn unique() -> Self {
+        Self {
+            host: HOST_A | HOST_AAAA,
+            services: [SERVICE_SRV | SERVICE_TXT; S],
+        }
+    }
+
+    fn all() -> Self {
+        Self {
+            host: HOST_A | HOST_AAAA,
+            services: [SERVICE_PTR | SERVICE_SRV | SERVICE_TXT | SERVICE_ENUM; S],
+        }
+    }
+
+    /// Records useful to the receiver of these ones, that are not in the set.
+    fn additional(&self) -> Self {
+        let mut additional = Self::EMPTY;
+        for (i, &bits) in self.services.iter().enumerate() {
+            if bits & SERVICE_PTR != 0 {
+                additional.services[i] |= (SERVICE_SRV | SERVICE_TXT) & !bits;
+            }
+            if bits & (SERVICE_PTR | SERVICE_SRV) != 0 {
+                additional.host |= (HOST_A | HOST_AAAA) & !self.host;
+            }
+        }
+        additional
+    }
+}
+
+/// Message received from another host.
+struct Incoming<const S: usize> {
+    source: IpEndpoint,
+    id: u16,
+    /// Sent by a resolver that is not a full mDNS implementation.
+    legacy: bool,
+    /// A unicast response was requested.
+    unicast: bool,
+    /// Questions for our names, repeated in replies to legacy resolvers.
+    questions: Vec<(NameId, u16), MAX_QUESTIONS>,
+    answers: RecordSet<S>,
+    conflict_host: bool,
+    conflict_services: [bool; S],
+    lost_tiebreak: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Outgoing<'a, const S: usize> {
+    Probe,
+    Announce { max_ttl: u32 },
+    Reply(&'a Incoming<S>),
+}
+
+impl<'d, const S: usize> Host<'d, S> {
+    fn name(&self, id: NameId) -> Name<'_> {
+        match id {
+            NameId::Host => Name {
+                label: Some(&self.hostname),
+                domain: "",
+            },
+            NameId::Enumeration => Name {
+                label: None,
+                domain: "_services._dns-sd._udp",
+            },
+            NameId::ServiceType(i) => Name {
+                label: None,
+                domain: self.services[i].service.service_type,
+            },
+            NameId::Instance(i) => Name {
+                label: Some(&self.services[i].instance),
+                domain: self.services[i].service.service_type,
+            },
+        }
+    }
+
+    /// Find which of our names is at `pos` in `packet`.
+    fn find(&self, packet: &[u8], pos: usize) -> Option<NameId> {
+        [NameId::Host, NameId::Enumeration]
+            .into_iter()
+            .chain((0..self.services.len()).flat_map(|i| [NameId::ServiceType(i), NameId::Instance(i)]))
+            .find(|&id| name_eq(packet, pos, self.name(id)))
+    }
+
+    fn same_type(&self, i: usize, j: usize) -> bool {
+        let a = self.services[i].service.service_type;
+        let b = self.services[j].service.service_type;
+        a.eq_ignore_ascii_case(b)
+    }
+
+    fn has_family(&self, bit: u8) -> bool {
+        self.addresses.iter().any(|addr| match addr {
+            #[cfg(feature = "proto-ipv4")]
+            IpAddress::Ipv4(_) => bit == HOST_A,
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(_) => bit == HOST_AAAA,
+        })
+    }
+
+    fn has_address(&self, octets: &[u8]) -> bool {
+        self.addresses.iter().any(|addr| match addr {
+            #[cfg(feature = "proto-ipv4")]
+            IpAddress::Ipv4(addr) => addr.octets() == octets,
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(addr) => addr.octets() == octets,
+        })
+    }
+
+    fn groups(&self) -> Vec<IpEndpoint, 2> {
+        let mut groups = Vec::new();
+        for addr in &self.addresses {
+            let group = group(*addr);
+            if !groups.contains(&group) {
+                unwrap!(groups.push(group));
+            }
+        }
+        groups
+    }
+
+    fn parse(&self, packet: &[u8], meta: &UdpMetadata, state: State) -> Option<Incoming<S>> {
+        // Multicast messages we send are not looped back, but check anyway.
+        if self.addresses.contains(&meta.endpoint.addr) {
+            return None;
+        }
+
+        let mut reader = Reader { packet, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let question_count = reader.u16()?;
+        let answer_count = reader.u16()?;
+        let authority_count = reader.u16()?;
+        let additional_count = reader.u16()?;
+        if flags & (OPCODE_MASK | RCODE_MASK) != 0 {
+            return None;
+        }
+
+        let mut incoming = Incoming {
+            source: meta.endpoint,
+            id,
+            legacy: meta.endpoint.port != MDNS_PORT,
+            unicast: false,
+            questions: Vec::new(),
+            answers: RecordSet::EMPTY,
+            conflict_host: false,
+            conflict_services: [false; S],
+            lost_tiebreak: false,
+        };
+
+        if flags & FLAG_RESPONSE != 0 {
+            // The counts are untrusted, their sum can overflow.
+            for _ in 0..answer_count as u32 + authority_count as u32 + additional_count as u32 {
+                let record = reader.record()?;
+                self.check_conflict(packet, &record, &mut incoming);
+            }
+            return Some(incoming);
+        }
+
+        for _ in 0..question_count {
+            let question = reader.question()?;
+            let Some(id) = self.find(packet, question.name) else {
+                continue;
+            };
+            let class = question.class & !CLASS_TOP_BIT;
+            if class != CLASS_IN && class != CLASS_ANY {
+                continue;
+            }
+            let before = incoming.answers;
+            self.answer(id, question.record_type, &mut incoming.answers);
+            if incoming.answers != before {
+                incoming.unicast |= question.class & CLASS_TOP_BIT != 0;
+                let _ = incoming.questions.push((id, question.record_type));
+            }
+        }
+
+        // Known answers of the querier, that we don't need to send again.
+        for _ in 0..answer_count {
+            let record = reader.record()?;
+            if record.record_type == TYPE_PTR && record.ttl >= SERVICE_TTL / 2 {
+                self.suppress(packet, &record, &mut incoming.answers);
+            }
+        }
+
+        // Records another host is probing for.
+        if matches!(state, State::Probing(_)) {
+            for _ in 0..authority_count {
+                let record = reader.record()?;
+                incoming.lost_tiebreak |= self.loses_tiebreak(packet, &record);
+            }
+        }
+
+        Some(incoming)
+    }
+
+    /// Add the records answering a question for `id` to `answers`.
+    fn answer(&self, id: NameId, record_type: u16, answers: &mut RecordSet<S>) {
+        let wants = |t| record_type == t || record_type == TYPE_ANY;
+        match id {
+            NameId::Host => {
+                if wants(TYPE_A) && self.has_family(HOST_A) {
+                    answers.host |= HOST_A;
+                }
+                if wants(TYPE_AAAA) && self.has_family(HOST_AAAA) {
+                    answers.host |= HOST_AAAA;
+                }
+            }
+            NameId::Enumeration if wants(TYPE_PTR) => {
+                for bits in answers.services[..self.services.len()].iter_mut() {
+                    *bits |= SERVICE_ENUM;
+                }
+            }
+            NameId::ServiceType(i) if wants(TYPE_PTR) => {
+                for j in 0..self.services.len() {
+                    if self.same_type(i, j) {
+                        answers.services[j] |= SERVICE_PTR;
+                    }
+                }
+            }
+            NameId::Instance(i) => {
+                if wants(TYPE_SRV) {
+                    answers.services[i] |= SERVICE_SRV;
+                }
+                if wants(TYPE_TXT) {
+                    answers.services[i] |= SERVICE_TXT;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove the shared records the querier already knows from `answers`.
+    fn suppress(&self, packet: &[u8], record: &WireRecord, answers: &mut RecordSet<S>) {
+        match self.find(packet, record.name) {
+            Some(NameId::ServiceType(i)) => {
+                for j in 0..self.services.len() {
+                    if self.same_type(i, j) && name_eq(packet, record.data_pos, self.name(NameId::Instance(j))) {
+                        answers.services[j] &= !SERVICE_PTR;
+                    }
+                }
+            }
+            Some(NameId::Enumeration) => {
+                for j in 0..self.services.len() {
+                    if name_eq(packet, record.data_pos, self.name(NameId::ServiceType(j))) {
+                        answers.services[j] &= !SERVICE_ENUM;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Check whether another host answers with records for our unique names.
+    fn check_conflict(&self, packet: &[u8], record: &WireRecord, incoming: &mut Incoming<S>) {
+        match self.find(packet, record.name) {
+            Some(NameId::Host) if record.record_type == TYPE_A || record.record_type == TYPE_AAAA => {
+                if !self.has_address(record.data) {
+                    debug!("mdns: {}.local is used by another host", self.hostname.as_str());
+                    incoming.conflict_host = true;
+                }
+            }
+            Some(NameId::Instance(i)) if record.record_type == TYPE_SRV => {
+                let port = record.data.get(4..6).map(|port| u16::from_be_bytes([port[0], port[1]]));
+                let ours = port == Some(self.services[i].service.port)
+                    && name_eq(packet, record.data_pos + 6, self.name(NameId::Host));
+                if !ours {
+                    debug!("mdns: {} is used by another host", self.services[i].instance.as_str());
+                    incoming.conflict_services[i] = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Compare a record another host is probing for with ours, as specified by RFC 6762 section
+    /// 8.2, and return whether we lose.
+    ///
+    /// Only the first record of each host is compared.
+    fn loses_tiebreak(&self, packet: &[u8], record: &WireRecord) -> bool {
+        let id = self.find(packet, record.name);
+        let data = match id {
+            Some(NameId::Host) => match self.addresses.first() {
+                #[cfg(feature = "proto-ipv4")]
+                Some(IpAddress::Ipv4(addr)) => Data::A(*addr),
+                #[cfg(feature = "proto-ipv6")]
+                Some(IpAddress::Ipv6(addr)) => Data::Aaaa(*addr),
+                None => return false,
+            },
+            Some(NameId::Instance(i)) => Data::Srv {
+                port: self.services[i].service.port,
+                target: self.name(NameId::Host),
+            },
+            _ => return false,
+        };
+
+        let mut buf = [0; 128];
+        let mut writer = Writer { buf: &mut buf, len: 0 };
+        if writer.data(&data).is_none() {
+            return false;
+        }
+        let ours = (CLASS_IN, data.record_type(), &writer.buf[..writer.len]);
+        let theirs = (record.class & !CLASS_TOP_BIT, record.record_type, record.data);
+        theirs > ours
+    }
+
+    /// Rename the names that conflict in `incoming`.
+    fn rename(&mut self, incoming: &Incoming<S>) {
+        if incoming.conflict_host {
+            self.hostname_conflicts += 1;
+            self.hostname = renamed(&self.hostname_base, self.hostname_conflicts + 1, true);
+            info!("mdns: renamed host to {}.local", self.hostname.as_str());
+        }
+        for (slot, &conflict) in self.services.iter_mut().zip(&incoming.conflict_services) {
+            if conflict {
+                slot.conflicts += 1;
+                slot.instance = renamed(slot.service.instance, slot.conflicts + 1, false);
+                info!("mdns: renamed service to {}", slot.instance.as_str());
+            }
+        }
+    }
+
+    /// Write a message, returning its id and flags.
+    fn write(&self, message: &mut Message, outgoing: Outgoing<'_, S>) -> (u16, u16) {
+        match outgoing {
+            Outgoing::Probe => {
+                message.cache_flush = false;
+                let ids = iter::once(NameId::Host).chain((0..self.services.len()).map(NameId::Instance));
+                for id in ids {
+                    message.question(self.name(id), TYPE_ANY, CLASS_IN | CLASS_TOP_BIT);
+                }
+                self.write_records(message, Section::Authority, &RecordSet::unique());
+                (0, 0)
+            }
+            Outgoing::Announce { max_ttl } => {
+                message.max_ttl = max_ttl;
+                self.write_records(message, Section::Answer, &RecordSet::all());
+                (0, FLAG_RESPONSE | FLAG_AUTHORITATIVE)
+            }
+            Outgoing::Reply(incoming) => {
+                let mut id = 0;
+                if incoming.legacy {
+                    id = incoming.id;
+                    message.max_ttl = LEGACY_TTL;
+                    message.cache_flush = false;
+                    for &(name, record_type) in &incoming.questions {
+                        message.question(self.name(name), record_type, CLASS_IN);
+                    }
+                }
+                self.write_records(message, Section::Answer, &incoming.answers);
+                self.write_records(message, Section::Additional, &incoming.answers.additional());
+                (id, FLAG_RESPONSE | FLAG_AUTHORITATIVE)
+            }
+        }
+    }
+
+    fn write_records(&self, message: &mut Message, section: Section, records: &RecordSet<S>) {
+        let host = self.name(NameId::Host);
+        for addr in &self.addresses {
+            match addr {
+                #[cfg(feature = "proto-ipv4")]
+                IpAddress::Ipv4(addr) if records.host & HOST_A != 0 => {
+                    message.record(section, host, HOST_TTL, true, Data::A(*addr));
+                }
+                #[cfg(feature = "proto-ipv6")]
+                IpAddress::Ipv6(addr) if records.host & HOST_AAAA != 0 => {
+                    message.record(section, host, HOST_TTL, true, Data::Aaaa(*addr));
+                }
+                _ => {}
+            }
+        }
+
+        for (i, slot) in self.services.iter().enumerate() {
+            let bits = records.services[i];
+            let service_type = self.name(NameId::ServiceType(i));
+            let instance = self.name(NameId::Instance(i));
+            if bits & SERVICE_PTR != 0 {
+                message.record(section, service_type, SERVICE_TTL, false, Data::Ptr(instance));
+            }
+            if bits & SERVICE_SRV != 0 {
+                let data = Data::Srv {
+                    port: slot.service.port,
+                    target: host,
+                };
+                message.record(section, instance, HOST_TTL, true, data);
+            }
+            if bits & SERVICE_TXT != 0 {
+                message.record(section, instance, SERVICE_TTL, true, Data::Txt(slot.service.txt));
+            }
+            // Each type is listed once.
+            if bits & SERVICE_ENUM != 0 && !(0..i).any(|j| self.same_type(i, j)) {
+                let enumeration = self.name(NameId::Enumeration);
+                message.record(section, enumeration, SERVICE_TTL, false, Data::Ptr(service_type));
+            }
+        }
+    }
+}
+
+/// Compare the name at `pos` in `packet` with `name`, ignoring ASCII case.
+fn name_eq(packet: &[u8], mut pos: usize, name: Name) -> bool {
+    let mut labels = name.labels();
+    let mut pointers = 0;
+    loop {
+        let Some(&len) = packet.get(pos) else { return false };
+        let len = len as usize;
+        match len & 0xc0 {
+            0xc0 => {
+                let Some(&low) = packet.get(pos + 1) else { return false };
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return false;
+                }
+                pos = (len & 0x3f) << 8 | low as usize;
+            }
+            0 if len == 0 => return labels.next().is_none(),
+            0 => {
+                let Some(label) = packet.get(pos + 1..pos + 1 + len) else {
+                    return false;
+                };
+                match labels.next() {
+                    Some(ours) if ours.as_bytes().eq_ignore_ascii_case(label) => {}
+                    _ => return false,
+                }
+                pos += 1 + len;
+            }
+            _ => return false,
+        }
+    }
+}
+
+struct WireQuestion {
+    name: usize,
+    record_type: u16,
+    class: u16,
+}
+
+struct WireRecord<'a> {
+    name: usize,
+    record_type: u16,
+    class: u16,
+    ttl: u32,
+    data_pos: usize,
+    data: &'a [u8],
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Skip a name, returning its position.
+    fn name(&mut self) -> Option<usize> {
+        let start = self.pos;
+        loop {
+            let len = *self.packet.get(self.pos)? as usize;
+            match len & 0xc0 {
+                0xc0 => {
+                    self.bytes(2)?;
+                    return Some(start);
+                }
+                0 => {
+                    self.bytes(1 + len)?;
+                    if len == 0 {
+                        return Some(start);
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn question(&mut self) -> Option<WireQuestion> {
+        Some(WireQuestion {
+            name: self.name()?,
+            record_type: self.u16()?,
+            class: self.u16()?,
+        })
+    }
+
+    fn record(&mut self) -> Option<WireRecord<'a>> {
+        let name = self.name()?;
+        let record_type = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let data_pos = self.pos;
+        Some(WireRecord {
+            name,
+            record_type,
+            class,
+            ttl,
+            data_pos,
+            data: self.bytes(len)?,
+        })
+    }
+}
+
+enum Data<'a> {
+    #[cfg(feature = "proto-ipv4")]
+    A(Ipv4Address),
+    #[cfg(feature = "proto-ipv6")]
+    Aaaa(Ipv6Address),
+    Ptr(Name<'a>),
+    Srv {
+        port: u16,
+        target: Name<'a>,
+    },
+    Txt(&'a [&'a str]),
+}
+
+impl Data<'_> {
+    fn record_type(&self) -> u16 {
+        match self {
+            #[cfg(feature = "proto-ipv4")]
+            Data::A(_) => TYPE_A,
+            #[cfg(feature = "proto-ipv6")]
+            Data::Aaaa(_) => TYPE_AAAA,
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Txt(_) => TYPE_TXT,
+        }
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn name(&mut self, name: Name) -> Option<()> {
+        for label in name.labels() {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn data(&mut self, data: &Data) -> Option<()> {
+        match data {
+            #[cfg(feature = "proto-ipv4")]
+            Data::A(addr) => self.bytes(&addr.octets()),
+            #[cfg(feature = "proto-ipv6")]
+            Data::Aaaa(addr) => self.bytes(&addr.octets()),
+            Data::Ptr(name) => self.name(*name),
+            Data::Srv { port, target } => {
+                self.u16(0)?; // priority
+                self.u16(0)?; // weight
+                self.u16(*port)?;
+                self.name(*target)
+            }
+            // A TXT record has at least one string, even empty.
+            Data::Txt([]) => self.bytes(&[0]),
+            Data::Txt(entries) => {
+                for entry in entries.iter() {
+                    self.bytes(&[entry.len() as u8])?;
+                    self.bytes(entry.as_bytes())?;
+                }
+                Some(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Section {
+    Answer = 1,
+    Authority = 2,
+    Additional = 3,
+}
+
+/// Message being written. Questions and records must be added in the order of their sections.
+struct Message<'a> {
+    writer: Writer<'a>,
+    counts: [u16; 4],
+    /// Cap of the record TTLs.
+    max_ttl: u32,
+    /// Set the cache-flush bit of unique records.
+    cache_flush: bool,
+    truncated: bool,
+}
+
+impl<'a> Message<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            writer: Writer { buf, len: HEADER_LEN },
+            counts: [0; 4],
+            max_ttl: u32::MAX,
+            cache_flush: true,
+            truncated: false,
+        }
+    }
+
+    fn question(&mut self, name: Name, record_type: u16, class: u16) {
+        self.add(0, |w| {
+            w.name(name)?;
+            w.u16(record_type)?;
+            w.u16(class)
+        });
+    }
+
+    fn record(&mut self, section: Section, name: Name, ttl: u32, unique: bool, data: Data) {
+        let class = match unique && self.cache_flush {
+            true => CLASS_IN | CLASS_TOP_BIT,
+            false => CLASS_IN,
+        };
+        let ttl = ttl.min(self.max_ttl);
+        self.add(section as usize, |w| {
+            w.name(name)?;
+            w.u16(data.record_type())?;
+            w.u16(class)?;
+            w.u32(ttl)?;
+            let len_pos = w.len;
+            w.u16(0)?;
+            w.data(&data)?;
+            let len = (w.len - len_pos - 2) as u16;
+            w.buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+            Some(())
+        });
+    }
+
+    /// Add an entry, or drop it if it does not fit.
+    fn add(&mut self, section: usize, f: impl FnOnce(&mut Writer) -> Option<()>) {
+        let len = self.writer.len;
+        if !self.truncated && f(&mut self.writer).is_some() {
+            self.counts[section] += 1;
+        } else {
+            if !self.truncated {
+                warn!("mdns: message too large, dropping records");
+            }
+            self.truncated = true;
+            self.writer.len = len;
+        }
+    }
+
+    fn finish(self, id: u16, flags: u16) -> usize {
+        let header = &mut self.writer.buf[..HEADER_LEN];
+        header[0..2].copy_from_slice(&id.to_be_bytes());
+        header[2..4].copy_from_slice(&flags.to_be_bytes());
+        for (i, count) in self.counts.iter().enumerate() {
+            header[4 + 2 * i..6 + 2 * i].copy_from_slice(&count.to_be_bytes());
+        }
+        self.writer.len
+    }
+}
+
+#[cfg(all(test, feature = "proto-ipv4"))]
+mod tests {
+    use super::*;
+
+    const ADDRESS: IpAddress = IpAddress::v4(192, 168, 1, 10);
+    const PEER: IpAddress = IpAddress::v4(192, 168, 1, 20);
+    const CLASS_QU: u16 = CLASS_IN | CLASS_TOP_BIT;
+
+    /// Host with a web server and a printer.
+    fn host() -> Host<'static, 2> {
+        let mut host = Host {
+            hostname_base: String::try_from("device").unwrap(),
+            hostname: String::try_from("device").unwrap(),
+            hostname_conflicts: 0,
+            services: Vec::new(),
+            addresses: Vec::from_slice(&[ADDRESS]).unwrap(),
+        };
+        for service in [
+            Service {
+                instance: "Web",
+                service_type: "_http._tcp",
+                port: 80,
+                txt: &["path=/"],
+            },
+            Service {
+                instance: "Printer",
+                service_type: "_ipp._tcp",
+                port: 631,
+                txt: &[],
+            },
+        ] {
+            let slot = Slot {
+                instance: String::try_from(service.instance).unwrap(),
+                service,
+                conflicts: 0,
+            };
+            host.services.push(slot).ok().unwrap();
+        }
+        host
+    }
+
+    fn header(id: u16, flags: u16, counts: [u16; 4]) -> Vec<u8, 512> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&id.to_be_bytes()).unwrap();
+        packet.extend_from_slice(&flags.to_be_bytes()).unwrap();
+        for count in counts {
+            packet.extend_from_slice(&count.to_be_bytes()).unwrap();
+        }
+        packet
+    }
+
+    /// Writes a name, such as `device.local`.
+    fn name(packet: &mut Vec<u8, 512>, name: &str) {
+        for label in name.split('.') {
+            packet.push(label.len() as u8).unwrap();
+            packet.extend_from_slice(label.as_bytes()).unwrap();
+        }
+        packet.push(0).unwrap();
+    }
+
+    /// Writes the type and class of a question, after its name.
+    fn question(packet: &mut Vec<u8, 512>, record_type: u16, class: u16) {
+        packet.extend_from_slice(&record_type.to_be_bytes()).unwrap();
+        packet.extend_from_slice(&class.to_be_bytes()).unwrap();
+    }
+
+    /// Writes a record, after its name.
+    fn record(packet: &mut Vec<u8, 512>, record_type: u16, ttl: u32, data: &[u8]) {
+        question(packet, record_type, CLASS_IN);
+        packet.extend_from_slice(&ttl.to_be_bytes()).unwrap();
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes()).unwrap();
+        packet.extend_from_slice(data).unwrap();
+    }
+
+    /// Query with a question for `qname`.
+    fn query(qname: &str, record_type: u16, class: u16) -> Vec<u8, 512> {
+        let mut packet = header(0, 0, [1, 0, 0, 0]);
+        name(&mut packet, qname);
+        question(&mut packet, record_type, class);
+        packet
+    }
+
+    fn parse(host: &Host<'_, 2>, packet: &[u8]) -> Option<Incoming<2>> {
+        host.parse(packet, &IpEndpoint::new(PEER, MDNS_PORT).into(), State::Running)
+    }
+
+    fn answers(host: u8, services: [u8; 2]) -> RecordSet<2> {
+        RecordSet { host, services }
+    }
+
+    /// Writes the message sent for `outgoing`.
+    fn write(host: &Host<'_, 2>, outgoing: Outgoing<'_, 2>, buf: &mut [u8]) -> usize {
+        let mut message = Message::new(buf);
+        let (id, flags) = host.write(&mut message, outgoing);
+        message.finish(id, flags)
+    }
+
+    /// Reads the records of a message, returning their names, types, classes and TTLs.
+    fn read_records(packet: &[u8]) -> Vec<(usize, u16, u16, u32), 16> {
+        let mut reader = Reader { packet, pos: 0 };
+        reader.bytes(4).unwrap();
+        let questions = reader.u16().unwrap();
+        let count: u16 = (0..3).map(|_| reader.u16().unwrap()).sum();
+        for _ in 0..questions {
+            reader.question().unwrap();
+        }
+        let records = (0..count)
+            .map(|_| reader.record().unwrap())
+            .map(|r| (r.name, r.record_type, r.class, r.ttl))
+            .collect();
+        assert_eq!(reader.pos, packet.len());
+        records
+    }
+
+    #[test]
+    fn queries() {
+        let host = host();
+
+        let incoming = parse(&host, &query("device.local", TYPE_A, CLASS_IN)).unwrap();
+        assert_eq!(incoming.answers, answers(HOST_A, [0, 0]));
+        assert!(!incoming.unicast && !incoming.legacy);
+        // Names are compared ignoring case, and unicast responses can be requested.
+        let incoming = parse(&host, &query("DEVICE.Local", TYPE_ANY, CLASS_QU)).unwrap();
+        assert_eq!(incoming.answers, answers(HOST_A, [0, 0]));
+        assert!(incoming.unicast);
+
+        // The host has no IPv6 address, and other classes and names are not answered.
+        for packet in [
+            query("device.local", TYPE_AAAA, CLASS_IN),
+            query("device.local", TYPE_A, 3),
+            query("other.local", TYPE_A, CLASS_IN),
+            query("device.local.local", TYPE_A, CLASS_IN),
+            query("Web._http._tcp.local", TYPE_A, CLASS_IN),
+        ] {
+            assert!(parse(&host, &packet).unwrap().answers.is_empty());
+        }
+
+        let incoming = parse(&host, &query("_http._tcp.local", TYPE_PTR, CLASS_IN)).unwrap();
+        assert_eq!(incoming.answers, answers(0, [SERVICE_PTR, 0]));
+        let incoming = parse(&host, &query("web._HTTP._tcp.local", TYPE_ANY, CLASS_IN)).unwrap();
+        assert_eq!(incoming.answers, answers(0, [SERVICE_SRV | SERVICE_TXT, 0]));
+        let incoming = parse(&host, &query("Printer._ipp._tcp.local", TYPE_TXT, CLASS_IN)).unwrap();
+        assert_eq!(incoming.answers, answers(0, [0, SERVICE_TXT]));
+        let incoming = parse(&host, &query("_services._dns-sd._udp.local", TYPE_PTR, CLASS_IN)).unwrap();
+        assert_eq!(incoming.answers, answers(0, [SERVICE_ENUM, SERVICE_ENUM]));
+
+        // Queries from another port are from legacy resolvers, which get the questions back.
+        let packet = query("device.local", TYPE_A, CLASS_IN);
+        let incoming = host
+            .parse(&packet, &IpEndpoint::new(PEER, 40000).into(), State::Running)
+            .unwrap();
+        assert!(incoming.legacy);
+        assert_eq!(incoming.questions[..], [(NameId::Host, TYPE_A)]);
+
+        // Messages with an opcode or a response code, and our own messages, are ignored.
+        let mut packet = query("device.local", TYPE_A, CLASS_IN);
+        packet[2] = 0x08;
+        assert!(parse(&host, &packet).is_none());
+        packet[2] = 0;
+        packet[3] = 0x01;
+        assert!(parse(&host, &packet).is_none());
+        let packet = query("device.local", TYPE_A, CLASS_IN);
+        let own = host.parse(&packet, &IpEndpoint::new(ADDRESS, MDNS_PORT).into(), State::Running);
+        assert!(own.is_none());
+    }
+
+    #[test]
+    fn compressed_names() {
+        let host = host();
+
+        // The second question points to the first name, and the third one to the `_http._tcp.local`
+        // suffix of the second name.
+        let mut packet = header(0, 0, [3, 0, 0, 0]);
+        name(&mut packet, "Web._http._tcp.local");
+        question(&mut packet, TYPE_SRV, CLASS_IN);
+        packet.extend_from_slice(&[0xC0, 12]).unwrap();
+        question(&mut packet, TYPE_TXT, CLASS_IN);
+        packet.extend_from_slice(&[0xC0, 16]).unwrap();
+        question(&mut packet, TYPE_PTR, CLASS_IN);
+        let incoming = parse(&host, &packet).unwrap();
+        assert_eq!(
+            incoming.answers,
+            answers(0, [SERVICE_PTR | SERVICE_SRV | SERVICE_TXT, 0])
+        );
+
+        // A label followed by a pointer.
+        let mut packet = header(0, 0, [2, 0, 0, 0]);
+        name(&mut packet, "_ipp._tcp.local");
+        question(&mut packet, TYPE_PTR, CLASS_IN);
+        packet.push(7).unwrap();
+        packet.extend_from_slice(b"Printer").unwrap();
+        packet.extend_from_slice(&[0xC0, 12]).unwrap();
+        question(&mut packet, TYPE_SRV, CLASS_IN);
+        let incoming = parse(&host, &packet).unwrap();
+        assert_eq!(incoming.answers, answers(0, [0, SERVICE_PTR | SERVICE_SRV]));
+
+        // Known answers, with a compressed name and data, suppress the shared records if their TTL
+        // is at least half of ours.
+        for (ttl, expected) in [(SERVICE_TTL, 0), (SERVICE_TTL / 2 - 1, SERVICE_PTR)] {
+            let mut packet = header(0, 0, [1, 1, 0, 0]);
+            name(&mut packet, "_http._tcp.local");
+            question(&mut packet, TYPE_PTR, CLASS_IN);
+            packet.extend_from_slice(&[0xC0, 12]).unwrap();
+            record(&mut packet, TYPE_PTR, ttl, &[3, b'W', b'e', b'b', 0xC0, 12]);
+            let incoming = parse(&host, &packet).unwrap();
+            assert_eq!(incoming.answers, answers(0, [expected, 0]));
+        }
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let host = host();
+
+        let mut packet = header(0, 0, [2, 1, 0, 0]);
+        name(&mut packet, "_http._tcp.local");
+        question(&mut packet, TYPE_PTR, CLASS_IN);
+        packet.extend_from_slice(&[0xC0, 12]).unwrap();
+        question(&mut packet, TYPE_ANY, CLASS_IN);
+        packet.extend_from_slice(&[0xC0, 12]).unwrap();
+        record(&mut packet, TYPE_PTR, SERVICE_TTL, &[3, b'W', b'e', b'b', 0xC0, 12]);
+        assert!(parse(&host, &packet).is_some());
+        for len in 0..packet.len() {
+            assert!(parse(&host, &packet[..len]).is_none(), "{} bytes", len);
+        }
+
+        // Records longer than the packet.
+        let mut packet = header(0, FLAG_RESPONSE, [0, 1, 0, 0]);
+        name(&mut packet, "device.local");
+        record(&mut packet, TYPE_A, HOST_TTL, &[192, 168, 1, 11]);
+        let len = packet.len();
+        packet[len - 5] = 5;
+        assert!(parse(&host, &packet).is_none());
+
+        // Counts larger than the records, whose sum overflows.
+        let packet = header(0, FLAG_RESPONSE, [0, 0xFFFF, 0xFFFF, 0xFFFF]);
+        assert!(parse(&host, &packet).is_none());
+    }
+
+    #[test]
+    fn pointer_loops() {
+        let host = host();
+
+        // A pointer to itself, two pointers to each other, and a label followed by a pointer to it.
+        for name in [&[0xC0, 12][..], &[0xC0, 14, 0xC0, 12], &[3, b'W', b'e', b'b', 0xC0, 12]] {
+            let mut packet = header(0, 0, [1, 0, 0, 0]);
+            packet.extend_from_slice(name).unwrap();
+            question(&mut packet, TYPE_ANY, CLASS_IN);
+            let incoming = parse(&host, &packet).unwrap();
+            assert!(incoming.answers.is_empty());
+        }
+
+        // Pointers past the end of the packet, and labels with the reserved bits set.
+        let mut packet = header(0, 0, [1, 0, 0, 0]);
+        packet.extend_from_slice(&[0xC1, 0xFF]).unwrap();
+        question(&mut packet, TYPE_ANY, CLASS_IN);
+        assert!(parse(&host, &packet).unwrap().answers.is_empty());
+        for reserved in [0x40, 0x80] {
+            let mut packet = header(0, 0, [1, 0, 0, 0]);
+            packet.extend_from_slice(&[reserved | 6, 0]).unwrap();
+            question(&mut packet, TYPE_ANY, CLASS_IN);
+            assert!(parse(&host, &packet).is_none());
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        let host = host();
+
+        let response = |record_name: &str, record_type: u16, data: &[u8]| {
+            let mut packet = header(0, FLAG_RESPONSE, [0, 1, 0, 0]);
+            name(&mut packet, record_name);
+            record(&mut packet, record_type, HOST_TTL, data);
+            parse(&host, &packet).unwrap()
+        };
+
+        assert!(response("device.local", TYPE_A, &[192, 168, 1, 11]).conflict_host);
+        assert!(!response("device.local", TYPE_A, &[192, 168, 1, 10]).conflict_host);
+        assert!(!response("other.local", TYPE_A, &[192, 168, 1, 11]).conflict_host);
+
+        // SRV records of another host, or another port.
+        let mut srv = Vec::<u8, 512>::from_slice(&[0, 0, 0, 0, 0, 80]).unwrap();
+        name(&mut srv, "other.local");
+        assert_eq!(
+            response("Web._http._tcp.local", TYPE_SRV, &srv).conflict_services,
+            [true, false]
+        );
+        let mut srv = Vec::<u8, 512>::from_slice(&[0, 0, 0, 0, 0, 81]).unwrap();
+        name(&mut srv, "device.local");
+        assert_eq!(
+            response("Web._http._tcp.local", TYPE_SRV, &srv).conflict_services,
+            [true, false]
+        );
+        srv[5] = 80;
+        assert_eq!(
+            response("Web._http._tcp.local", TYPE_SRV, &srv).conflict_services,
+            [false, false]
+        );
+
+        // Simultaneous probes are compared with our first record.
+        for (address, lost) in [(11, true), (9, false)] {
+            let mut packet = header(0, 0, [1, 0, 1, 0]);
+            name(&mut packet, "device.local");
+            question(&mut packet, TYPE_ANY, CLASS_QU);
+            packet.extend_from_slice(&[0xC0, 12]).unwrap();
+            record(&mut packet, TYPE_A, HOST_TTL, &[192, 168, 1, address]);
+            let meta = IpEndpoint::new(PEER, MDNS_PORT).into();
+            assert_eq!(
+                host.parse(&packet, &meta, State::Probing(1)).unwrap().lost_tiebreak,
+                lost
+            );
+            assert!(!host.parse(&packet, &meta, State::Running).unwrap().lost_tiebreak);
+        }
+    }
+
+    #[test]
+    fn replies() {
+        let host = host();
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+
+        let incoming = parse(&host, &query("device.local", TYPE_A, CLASS_IN)).unwrap();
+        let len = write(&host, Outgoing::Reply(&incoming), &mut buf);
+        assert_eq!(
+            buf[..len],
+            [
+                0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, //
+                6, b'd', b'e', b'v', b'i', b'c', b'e', 5, b'l', b'o', b'c', b'a', b'l', 0, //
+                0x00, 0x01, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04, 192, 168, 1, 10,
+            ]
+        );
+
+        // The records of the instance and the address of the host are additional records.
+        let incoming = parse(&host, &query("_http._tcp.local", TYPE_PTR, CLASS_IN)).unwrap();
+        let len = write(&host, Outgoing::Reply(&incoming), &mut buf);
+        assert_eq!(buf[4..12], [0, 0, 0, 1, 0, 0, 0, 3]);
+        let records = read_records(&buf[..len]);
+        let summary: Vec<(u16, u16, u32), 16> = records.iter().map(|r| (r.1, r.2, r.3)).collect();
+        assert_eq!(
+            summary[..],
+            [
+                (TYPE_PTR, CLASS_IN, SERVICE_TTL),
+                (TYPE_A, CLASS_QU, HOST_TTL),
+                (TYPE_SRV, CLASS_QU, HOST_TTL),
+                (TYPE_TXT, CLASS_QU, SERVICE_TTL),
+            ]
+        );
+        assert!(name_eq(&buf, records[0].0, host.name(NameId::ServiceType(0))));
+        assert!(name_eq(&buf, records[2].0, host.name(NameId::Instance(0))));
+
+        // Legacy resolvers get the id and the questions back, with short TTLs and no cache-flush bit.
+        let mut packet = query("Web._http._tcp.local", TYPE_TXT, CLASS_IN);
+        packet[..2].copy_from_slice(&0x1234u16.to_be_bytes());
+        let incoming = host
+            .parse(&packet, &IpEndpoint::new(PEER, 40000).into(), State::Running)
+            .unwrap();
+        let len = write(&host, Outgoing::Reply(&incoming), &mut buf);
+        assert_eq!(buf[..12], [0x12, 0x34, 0x84, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(buf[12..packet.len()], packet[12..]);
+        let records = read_records(&buf[..len]);
+        assert_eq!(records[..], [(packet.len(), TYPE_TXT, CLASS_IN, LEGACY_TTL)]);
+        assert_eq!(buf[len - 7..len], [6, b'p', b'a', b't', b'h', b'=', b'/']);
+    }
+
+    #[test]
+    fn probes_and_announcements() {
+        let host = host();
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+
+        // Probes ask for all the records of the unique names, and list ours as authority records.
+        let len = write(&host, Outgoing::Probe, &mut buf);
+        assert_eq!(buf[..12], [0, 0, 0, 0, 0, 3, 0, 0, 0, 5, 0, 0]);
+        let mut reader = Reader {
+            packet: &buf[..len],
+            pos: HEADER_LEN,
+        };
+        for id in [NameId::Host, NameId::Instance(0), NameId::Instance(1)] {
+            let question = reader.question().unwrap();
+            assert!(name_eq(&buf, question.name, host.name(id)));
+            assert_eq!((question.record_type, question.class), (TYPE_ANY, CLASS_QU));
+        }
+        let records = read_records(&buf[..len]);
+        assert!(records.iter().all(|r| r.2 == CLASS_IN));
+        let types: Vec<u16, 16> = records.iter().map(|r| r.1).collect();
+        assert_eq!(types[..], [TYPE_A, TYPE_SRV, TYPE_TXT, TYPE_SRV, TYPE_TXT]);
+        // The printer has an empty TXT record, of an empty string.
+        assert_eq!(buf[len - 3..len], [0, 1, 0]);
+
+        // Goodbyes are announcements with a TTL of 0. Each service type is enumerated.
+        let len = write(&host, Outgoing::Announce { max_ttl: 0 }, &mut buf);
+        assert_eq!(buf[..12], [0, 0, 0x84, 0x00, 0, 0, 0, 9, 0, 0, 0, 0]);
+        let records = read_records(&buf[..len]);
+        assert!(records.iter().all(|r| r.3 == 0));
+        let types: Vec<u16, 16> = records.iter().map(|r| r.1).collect();
+        assert_eq!(
+            types[..],
+            [
+                TYPE_A, TYPE_PTR, TYPE_SRV, TYPE_TXT, TYPE_PTR, TYPE_PTR, TYPE_SRV, TYPE_TXT, TYPE_PTR
+            ]
+        );
+
+        // Records which do not fit are dropped, with the following ones.
+        let mut buf = [0; 100];
+        let len = write(&host, Outgoing::Announce { max_ttl: u32::MAX }, &mut buf);
+        let records = read_records(&buf[..len]);
+        assert_eq!(records.len(), 2);
+        assert_eq!(buf[4..12], [0, 0, 0, 2, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rename() {
+        let mut host = host();
+        let mut incoming = parse(&host, &query("device.local", TYPE_A, CLASS_IN)).unwrap();
+        incoming.conflict_host = true;
+        incoming.conflict_services = [false, true];
+        host.rename(&incoming);
+        host.rename(&incoming);
+        assert_eq!(host.hostname, "device-3");
+        assert_eq!(host.services[0].instance, "Web");
+        assert_eq!(host.services[1].instance, "Printer (3)");
+
+        // Names are truncated to fit in a label, on a character boundary.
+        const LONG: &str = "éééééééééééééééééééééééééééééééé";
+        let name = renamed(LONG, 2, true);
+        assert_eq!(name.len(), 62);
+        assert!(name.starts_with(&LONG[..60]) && name.ends_with("-2"));
+        let name = renamed(LONG, 10, false);
+        assert_eq!(name.len(), 63);
+        assert!(name.starts_with(&LONG[..58]) && name.ends_with(" (10)"));
+    }
+}
@@ -4,12 +4,12 @@ use core::future::{Future, poll_fn};
 use core::mem;
 use core::task::{Context, Poll};
 
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 use smoltcp::socket::raw;
 pub use smoltcp::socket::raw::PacketMetadata;
 pub use smoltcp::wire::{IpProtocol, IpVersion};
 
-use crate::{Stack, TryError};
+use crate::{SocketId, Stack, TryError};
 
 /// Error returned by [`RawSocket::recv`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -22,11 +22,15 @@ pub enum RecvError {
 /// An Raw socket.
 pub struct RawSocket<'a> {
     stack: Stack<'a>,
-    handle: SocketHandle,
+    id: SocketId,
 }
 
 impl<'a> RawSocket<'a> {
     /// Create a new Raw socket using the provided stack and buffers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no interface of the stack has a free socket slot.
     pub fn new(
         stack: Stack<'a>,
         ip_version: Option<IpVersion>,
@@ -36,12 +40,12 @@ impl<'a> RawSocket<'a> {
         tx_meta: &'a mut [PacketMetadata],
         tx_buffer: &'a mut [u8],
     ) -> Self {
-        let handle = stack.with_mut(|i| {
+        let id = stack.with_mut(|i| {
             let rx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(rx_meta) };
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(raw::Socket::new(
+            i.add_socket(raw::Socket::new(
                 ip_version,
                 ip_protocol,
                 raw::PacketBuffer::new(rx_meta, rx_buffer),
@@ -49,14 +53,14 @@ impl<'a> RawSocket<'a> {
             ))
         });
 
-        Self { stack, handle }
+        Self { stack, id }
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut raw::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<raw::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<raw::Socket>(self.id);
+            let res = f(socket, iface);
+            i.wake(self.id);
             res
         })
     }
@@ -218,7 +222,7 @@ impl<'a> RawSocket<'a> {
 
 impl Drop for RawSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack.with_mut(|i| i.remove_socket(self.id));
     }
 }
 
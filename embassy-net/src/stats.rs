@@ -0,0 +1,483 @@
+//! Interface statistics and socket introspection.
+
+use core::cell::Cell;
+#[cfg(feature = "tcp-retransmissions")]
+use core::cell::RefCell;
+
+use embassy_net_driver::Statistics as DriverStatistics;
+use smoltcp::phy::Medium;
+#[cfg(any(feature = "tcp", feature = "udp"))]
+use smoltcp::socket::AnySocket;
+#[cfg(feature = "tcp-retransmissions")]
+use smoltcp::wire::IpEndpoint;
+#[cfg(all(
+    feature = "tcp-retransmissions",
+    any(feature = "medium-ethernet", feature = "medium-ip")
+))]
+use smoltcp::wire::{IpAddress, IpProtocol, TcpPacket};
+
+use crate::InterfaceId;
+
+/// Statistics of a network interface, see [`Interface::statistics`](crate::Interface::statistics).
+///
+/// The counters start at zero when the interface is created, and wrap around on overflow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct InterfaceStatistics {
+    /// Packets received from the driver.
+    pub rx_packets: u32,
+    /// Bytes received from the driver, including the link layer headers.
+    pub rx_bytes: u64,
+    /// Packets sent to the driver.
+    pub tx_packets: u32,
+    /// Bytes sent to the driver, including the link layer headers.
+    pub tx_bytes: u64,
+    /// Received packets dropped by the driver, for example because the stack did not process the
+    /// previous ones fast enough.
+    pub rx_dropped: u32,
+    /// Packets the driver received with errors, such as a bad CRC.
+    pub rx_errors: u32,
+    /// Packets the driver failed to transmit.
+    pub tx_errors: u32,
+    /// Times the stack had packets to send but the driver had no space for them. The packets are
+    /// sent later, this indicates congestion rather than loss.
+    pub tx_stalls: u32,
+}
+
+/// Information about an open socket, see [`Stack::sockets`](crate::Stack::sockets).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum SocketInfo {
+    /// A TCP socket.
+    #[cfg(feature = "tcp")]
+    Tcp(crate::tcp::TcpInfo),
+    /// A UDP socket.
+    #[cfg(feature = "udp")]
+    Udp(crate::udp::UdpInfo),
+    /// Another kind of socket: ICMP and raw sockets, and the sockets used internally by the stack
+    /// for DNS and DHCP.
+    Other {
+        /// Interface the socket is attached to.
+        interface: InterfaceId,
+    },
+}
+
+impl SocketInfo {
+    pub(crate) fn new(interface: InterfaceId, socket: &smoltcp::socket::Socket, counters: &Counters) -> Self {
+        #[cfg(feature = "tcp")]
+        if let Some(s) = smoltcp::socket::tcp::Socket::downcast(socket) {
+            return Self::Tcp(crate::tcp::TcpInfo::new(interface, s, counters));
+        }
+        #[cfg(feature = "udp")]
+        if let Some(s) = smoltcp::socket::udp::Socket::downcast(socket) {
+            return Self::Udp(crate::udp::UdpInfo::new(interface, s));
+        }
+        let _ = (socket, counters);
+        Self::Other { interface }
+    }
+}
+
+/// Counters of an interface, updated while it is polled.
+pub(crate) struct Counters {
+    stats: Cell<InterfaceStatistics>,
+    #[cfg(feature = "tcp-retransmissions")]
+    tcp: RefCell<&'static mut [Option<TcpCounters>]>,
+    /// A TCP connection found no free slot, the closed connections need to be forgotten.
+    #[cfg(feature = "tcp-retransmissions")]
+    tcp_full: Cell<bool>,
+}
+
+/// Retransmission tracking of a TCP connection, identified by its endpoints.
+#[cfg(feature = "tcp-retransmissions")]
+#[derive(Clone, Copy)]
+pub(crate) struct TcpCounters {
+    local: IpEndpoint,
+    remote: IpEndpoint,
+    /// Sequence number following the last one sent.
+    snd_max: u32,
+    retransmissions: u32,
+}
+
+impl Counters {
+    /// Create counters, tracking up to `tcp.len()` TCP connections.
+    pub fn new(#[cfg(feature = "tcp-retransmissions")] tcp: &'static mut [Option<TcpCounters>]) -> Self {
+        Self {
+            stats: Cell::new(InterfaceStatistics::default()),
+            #[cfg(feature = "tcp-retransmissions")]
+            tcp: RefCell::new(tcp),
+            #[cfg(feature = "tcp-retransmissions")]
+            tcp_full: Cell::new(false),
+        }
+    }
+
+    pub fn get(&self) -> InterfaceStatistics {
+        self.stats.get()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut InterfaceStatistics)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    pub fn rx(&self, len: usize) {
+        self.update(|s| {
+            s.rx_packets = s.rx_packets.wrapping_add(1);
+            s.rx_bytes = s.rx_bytes.wrapping_add(len as u64);
+        })
+    }
+
+    #[cfg_attr(not(feature = "tcp-retransmissions"), allow(unused_variables))]
+    pub fn tx(&self, medium: Medium, packet: &[u8]) {
+        self.update(|s| {
+            s.tx_packets = s.tx_packets.wrapping_add(1);
+            s.tx_bytes = s.tx_bytes.wrapping_add(packet.len() as u64);
+        });
+        #[cfg(feature = "tcp-retransmissions")]
+        if let Some(segment) = tcp_segment(medium, packet) {
+            self.tcp_sent(&segment);
+        }
+    }
+
+    pub fn tx_stall(&self) {
+        self.update(|s| s.tx_stalls = s.tx_stalls.wrapping_add(1))
+    }
+
+    pub fn set_driver(&self, driver: DriverStatistics) {
+        self.update(|s| {
+            s.rx_dropped = driver.rx_dropped;
+            s.rx_errors = driver.rx_errors;
+            s.tx_errors = driver.tx_errors;
+        })
+    }
+
+    /// Record a TCP segment sent, counting it as a retransmission if it does not go past the
+    /// data already sent.
+    #[cfg(feature = "tcp-retransmissions")]
+    fn tcp_sent(&self, segment: &TcpSegment) {
+        // Pure ACKs don't consume sequence numbers.
+        if segment.len == 0 {
+            return;
+        }
+        let (local, remote) = (segment.local, segment.remote);
+        let end = segment.seq.wrapping_add(segment.len);
+        let mut tcp = self.tcp.borrow_mut();
+        if let Some(c) = tcp
+            .iter_mut()
+            .flatten()
+            .find(|c| c.local == local && c.remote == remote)
+        {
+            if segment.syn && end != c.snd_max {
+                // A new connection with the endpoints of one that was closed.
+                c.snd_max = end;
+                c.retransmissions = 0;
+            } else if (end.wrapping_sub(c.snd_max) as i32) <= 0 {
+                c.retransmissions = c.retransmissions.wrapping_add(1);
+            } else {
+                c.snd_max = end;
+            }
+        } else if let Some(slot) = tcp.iter_mut().find(|c| c.is_none()) {
+            *slot = Some(TcpCounters {
+                local,
+                remote,
+                snd_max: end,
+                retransmissions: 0,
+            });
+        } else {
+            self.tcp_full.set(true);
+        }
+    }
+
+    /// Get the number of retransmissions of a TCP connection.
+    #[cfg(feature = "tcp-retransmissions")]
+    pub fn tcp_retransmissions(&self, local: IpEndpoint, remote: IpEndpoint) -> u32 {
+        let tcp = self.tcp.borrow();
+        let c = tcp.iter().flatten().find(|c| c.local == local && c.remote == remote);
+        c.map_or(0, |c| c.retransmissions)
+    }
+
+    /// Forget the TCP connections for which `f` returns false, if a connection found no free slot
+    /// since the last time.
+    #[cfg(feature = "tcp-retransmissions")]
+    pub fn retain_tcp(&self, mut f: impl FnMut(IpEndpoint, IpEndpoint) -> bool) {
+        if !self.tcp_full.replace(false) {
+            return;
+        }
+        for slot in self.tcp.borrow_mut().iter_mut() {
+            if slot.is_some_and(|c| !f(c.local, c.remote)) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// A TCP segment sent.
+#[cfg(feature = "tcp-retransmissions")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TcpSegment {
+    local: IpEndpoint,
+    remote: IpEndpoint,
+    seq: u32,
+    /// Length in sequence space, counting the SYN and FIN flags.
+    len: u32,
+    syn: bool,
+}
+
+/// Parse a sent packet as a TCP segment.
+#[cfg(all(
+    feature = "tcp-retransmissions",
+    any(feature = "medium-ethernet", feature = "medium-ip")
+))]
+fn tcp_segment(medium: Medium, packet: &[u8]) -> Option<TcpSegment> {
+    let ip = match medium {
+        #[cfg(feature = "medium-ethernet")]
+        Medium::Ethernet => {
+            let frame = smoltcp::wire::EthernetFrame::new_checked(packet).ok()?;
+            if !matches!(
+                frame.ethertype(),
+                smoltcp::wire::EthernetProtocol::Ipv4 | smoltcp::wire::EthernetProtocol::Ipv6
+            ) {
+                return None;
+            }
+            &packet[packet.len() - frame.payload().len()..]
+        }
+        #[cfg(feature = "medium-ip")]
+        Medium::Ip => packet,
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    };
+
+    let (src, dst, payload): (IpAddress, IpAddress, &[u8]) = match ip.first()? >> 4 {
+        #[cfg(feature = "proto-ipv4")]
+        4 => {
+            let p = smoltcp::wire::Ipv4Packet::new_checked(ip).ok()?;
+            if p.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            (
+                p.src_addr().into(),
+                p.dst_addr().into(),
+                &ip[p.header_len() as usize..p.total_len() as usize],
+            )
+        }
+        #[cfg(feature = "proto-ipv6")]
+        6 => {
+            let p = smoltcp::wire::Ipv6Packet::new_checked(ip).ok()?;
+            // smoltcp doesn't send extension headers with TCP.
+            if p.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            (
+                p.src_addr().into(),
+                p.dst_addr().into(),
+                &ip[p.header_len()..p.total_len()],
+            )
+        }
+        _ => return None,
+    };
+
+    let tcp = TcpPacket::new_checked(payload).ok()?;
+    Some(TcpSegment {
+        local: IpEndpoint::new(src, tcp.src_port()),
+        remote: IpEndpoint::new(dst, tcp.dst_port()),
+        seq: tcp.seq_number().0 as u32,
+        len: tcp.segment_len() as u32,
+        syn: tcp.syn(),
+    })
+}
+
+// 6LoWPAN compresses the headers, don't bother.
+#[cfg(all(
+    feature = "tcp-retransmissions",
+    not(any(feature = "medium-ethernet", feature = "medium-ip"))
+))]
+fn tcp_segment(_medium: Medium, _packet: &[u8]) -> Option<TcpSegment> {
+    None
+}
+
+#[cfg(all(
+    test,
+    feature = "tcp-retransmissions",
+    feature = "proto-ipv4",
+    feature = "proto-ipv6",
+    feature = "medium-ethernet",
+    feature = "medium-ip"
+))]
+mod tests {
+    use heapless::Vec;
+    use smoltcp::wire::{
+        EthernetFrame, EthernetProtocol, Ipv4Address, Ipv4Packet, Ipv6Address, Ipv6Packet, TcpSeqNumber,
+    };
+
+    use super::*;
+
+    const LOCAL_V4: Ipv4Address = Ipv4Address::new(192, 168, 1, 2);
+    const REMOTE_V4: Ipv4Address = Ipv4Address::new(192, 168, 1, 1);
+    const LOCAL_V6: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+    const REMOTE_V6: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+    type Buf = Vec<u8, 128>;
+
+    /// TCP header followed by `payload_len` bytes of data.
+    fn tcp(seq: u32, syn: bool, fin: bool, payload_len: usize) -> Buf {
+        let mut buf = Buf::new();
+        buf.resize(20 + payload_len, 0).unwrap();
+        let mut p = TcpPacket::new_unchecked(&mut buf[..]);
+        p.set_src_port(49152);
+        p.set_dst_port(80);
+        p.set_seq_number(TcpSeqNumber(seq as i32));
+        p.set_header_len(20);
+        p.clear_flags();
+        p.set_syn(syn);
+        p.set_fin(fin);
+        p.set_ack(!syn);
+        buf
+    }
+
+    fn ipv4(protocol: IpProtocol, payload: &[u8]) -> Buf {
+        let mut buf = Buf::new();
+        buf.resize(20, 0).unwrap();
+        let mut p = Ipv4Packet::new_unchecked(&mut buf[..]);
+        p.set_version(4);
+        p.set_header_len(20);
+        p.set_total_len(20 + payload.len() as u16);
+        p.set_hop_limit(64);
+        p.set_next_header(protocol);
+        p.set_src_addr(LOCAL_V4);
+        p.set_dst_addr(REMOTE_V4);
+        buf.extend_from_slice(payload).unwrap();
+        buf
+    }
+
+    fn ipv6(payload: &[u8]) -> Buf {
+        let mut buf = Buf::new();
+        buf.resize(40, 0).unwrap();
+        let mut p = Ipv6Packet::new_unchecked(&mut buf[..]);
+        p.set_version(6);
+        p.set_payload_len(payload.len() as u16);
+        p.set_next_header(IpProtocol::Tcp);
+        p.set_hop_limit(64);
+        p.set_src_addr(LOCAL_V6);
+        p.set_dst_addr(REMOTE_V6);
+        buf.extend_from_slice(payload).unwrap();
+        buf
+    }
+
+    /// Ethernet frame, padded to the minimum frame size.
+    fn ethernet(payload: &[u8]) -> Buf {
+        let mut buf = Buf::new();
+        buf.resize(14, 0).unwrap();
+        EthernetFrame::new_unchecked(&mut buf[..]).set_ethertype(EthernetProtocol::Ipv4);
+        buf.extend_from_slice(payload).unwrap();
+        if buf.len() < 60 {
+            buf.resize(60, 0).unwrap();
+        }
+        buf
+    }
+
+    fn segment_v4(seq: u32, len: u32, syn: bool) -> TcpSegment {
+        TcpSegment {
+            local: IpEndpoint::new(LOCAL_V4.into(), 49152),
+            remote: IpEndpoint::new(REMOTE_V4.into(), 80),
+            seq,
+            len,
+            syn,
+        }
+    }
+
+    #[test]
+    fn test_tcp_segment_ethernet() {
+        // The padding of the frame is not part of the segment.
+        let packet = ethernet(&ipv4(IpProtocol::Tcp, &tcp(1000, false, false, 5)));
+        assert_eq!(packet.len(), 60);
+        assert_eq!(tcp_segment(Medium::Ethernet, &packet), Some(segment_v4(1000, 5, false)));
+
+        // SYN and FIN take a sequence number each.
+        let packet = ethernet(&ipv4(IpProtocol::Tcp, &tcp(u32::MAX, true, false, 0)));
+        assert_eq!(
+            tcp_segment(Medium::Ethernet, &packet),
+            Some(segment_v4(u32::MAX, 1, true))
+        );
+        let packet = ethernet(&ipv4(IpProtocol::Tcp, &tcp(2000, false, true, 10)));
+        assert_eq!(
+            tcp_segment(Medium::Ethernet, &packet),
+            Some(segment_v4(2000, 11, false))
+        );
+    }
+
+    #[test]
+    fn test_tcp_segment_ip() {
+        let packet = ipv4(IpProtocol::Tcp, &tcp(1000, false, false, 0));
+        assert_eq!(tcp_segment(Medium::Ip, &packet), Some(segment_v4(1000, 0, false)));
+
+        let packet = ipv6(&tcp(3000, false, false, 7));
+        let segment = TcpSegment {
+            local: IpEndpoint::new(LOCAL_V6.into(), 49152),
+            remote: IpEndpoint::new(REMOTE_V6.into(), 80),
+            seq: 3000,
+            len: 7,
+            syn: false,
+        };
+        assert_eq!(tcp_segment(Medium::Ip, &packet), Some(segment));
+    }
+
+    #[test]
+    fn test_tcp_segment_invalid() {
+        // Other protocols.
+        let packet = ipv4(IpProtocol::Udp, &tcp(1000, false, false, 5));
+        assert_eq!(tcp_segment(Medium::Ip, &packet), None);
+        let mut packet = ethernet(&ipv4(IpProtocol::Tcp, &tcp(1000, false, false, 5)));
+        EthernetFrame::new_unchecked(&mut packet[..]).set_ethertype(EthernetProtocol::Arp);
+        assert_eq!(tcp_segment(Medium::Ethernet, &packet), None);
+
+        // Truncated packets.
+        let packet = ipv4(IpProtocol::Tcp, &tcp(1000, false, false, 5));
+        assert_eq!(tcp_segment(Medium::Ip, &packet[..packet.len() - 1]), None);
+        let packet = ipv4(IpProtocol::Tcp, &tcp(1000, false, false, 0)[..10]);
+        assert_eq!(tcp_segment(Medium::Ip, &packet), None);
+        assert_eq!(tcp_segment(Medium::Ip, &[]), None);
+        assert_eq!(tcp_segment(Medium::Ethernet, &[0; 10]), None);
+    }
+
+    #[test]
+    fn test_retransmissions() {
+        let mut slots = [None; 1];
+        let counters = Counters::new(unsafe { crate::transmute_slice(&mut slots) });
+        let (local, remote) = (segment_v4(0, 0, false).local, segment_v4(0, 0, false).remote);
+
+        counters.tcp_sent(&segment_v4(100, 1, true));
+        counters.tcp_sent(&segment_v4(100, 1, true));
+        assert_eq!(counters.tcp_retransmissions(local, remote), 1);
+
+        counters.tcp_sent(&segment_v4(101, 10, false));
+        // Pure ACKs are not retransmissions.
+        counters.tcp_sent(&segment_v4(111, 0, false));
+        counters.tcp_sent(&segment_v4(101, 10, false));
+        counters.tcp_sent(&segment_v4(106, 5, false));
+        counters.tcp_sent(&segment_v4(111, 10, false));
+        assert_eq!(counters.tcp_retransmissions(local, remote), 3);
+
+        // A new connection with the same endpoints starts from zero.
+        counters.tcp_sent(&segment_v4(5000, 1, true));
+        assert_eq!(counters.tcp_retransmissions(local, remote), 0);
+
+        // Connections that find no slot get the closed ones forgotten on the next poll.
+        let other = TcpSegment {
+            remote: IpEndpoint::new(REMOTE_V4.into(), 443),
+            ..segment_v4(100, 1, true)
+        };
+        counters.tcp_sent(&other);
+        let mut calls = 0;
+        counters.retain_tcp(|_, _| {
+            calls += 1;
+            false
+        });
+        counters.retain_tcp(|_, _| unreachable!());
+        assert_eq!(calls, 1);
+        counters.tcp_sent(&other);
+        counters.tcp_sent(&other);
+        assert_eq!(counters.tcp_retransmissions(other.local, other.remote), 1);
+    }
+}
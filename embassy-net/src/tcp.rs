@@ -2,24 +2,25 @@
 //!
 //! # Listening
 //!
-//! `embassy-net` does not have a `TcpListener`. Instead, individual `TcpSocket`s can be put into
-//! listening mode by calling [`TcpSocket::accept`].
+//! Individual `TcpSocket`s can be put into listening mode by calling [`TcpSocket::accept`].
 //!
 //! Incoming connections when no socket is listening are rejected. To accept many incoming
-//! connections, create many sockets and put them all into listening mode.
+//! connections, create many sockets and put them all into listening mode, or use a
+//! [`TcpListener`](listener::TcpListener) which does it with a pool of sockets.
 
 use core::future::{Future, poll_fn};
 use core::mem;
 use core::task::{Context, Poll};
 
 use embassy_time::Duration;
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 use smoltcp::socket::tcp;
 pub use smoltcp::socket::tcp::State;
 use smoltcp::wire::{IpEndpoint, IpListenEndpoint};
 
+use crate::stats::Counters;
 use crate::time::duration_to_smoltcp;
-use crate::{Stack, TryError};
+use crate::{BindInterfaceError, Inner, InterfaceId, SocketId, Stack, TryError};
 
 /// Error returned by TcpSocket read/write functions.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -34,6 +35,7 @@ pub enum Error {
 /// Error returned by [`TcpSocket::connect`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum ConnectError {
     /// The socket is already connected or listening.
     InvalidState,
@@ -43,11 +45,23 @@ pub enum ConnectError {
     TimedOut,
     /// No route to host.
     NoRoute,
+    /// The interface routing to the host has no free socket slot.
+    NoFreeSocket,
+}
+
+impl From<BindInterfaceError> for ConnectError {
+    fn from(e: BindInterfaceError) -> Self {
+        match e {
+            BindInterfaceError::InvalidState => Self::InvalidState,
+            BindInterfaceError::NoFreeSocket => Self::NoFreeSocket,
+        }
+    }
 }
 
 /// Error returned by [`TcpSocket::accept`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum AcceptError {
     /// The socket is already connected or listening.
     InvalidState,
@@ -55,11 +69,73 @@ pub enum AcceptError {
     InvalidPort,
     /// The remote host rejected the connection with a RST packet.
     ConnectionReset,
+    /// The interface having the local address has no free socket slot.
+    NoFreeSocket,
+}
+
+impl From<BindInterfaceError> for AcceptError {
+    fn from(e: BindInterfaceError) -> Self {
+        match e {
+            BindInterfaceError::InvalidState => Self::InvalidState,
+            BindInterfaceError::NoFreeSocket => Self::NoFreeSocket,
+        }
+    }
+}
+
+/// Information about a TCP socket, see [`TcpSocket::info`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct TcpInfo {
+    /// Interface the socket is attached to.
+    pub interface: InterfaceId,
+    /// State of the connection.
+    pub state: State,
+    /// Endpoint the socket is listening on, if it is listening.
+    pub listen_endpoint: IpListenEndpoint,
+    /// Local endpoint of the connection, if the socket is connected.
+    pub local_endpoint: Option<IpEndpoint>,
+    /// Remote endpoint of the connection, if the socket is connected.
+    pub remote_endpoint: Option<IpEndpoint>,
+    /// Bytes in the transmit buffer, not acknowledged by the remote endpoint yet.
+    pub send_queue: usize,
+    /// Bytes in the receive buffer, not read yet.
+    pub recv_queue: usize,
+    /// Segments of the connection sent again, after a timeout or a fast retransmit.
+    ///
+    /// Only counted with the `tcp-retransmissions` feature, 0 otherwise.
+    pub retransmissions: u32,
+}
+
+impl TcpInfo {
+    #[cfg_attr(not(feature = "tcp-retransmissions"), allow(unused_variables))]
+    pub(crate) fn new(interface: InterfaceId, socket: &tcp::Socket, counters: &Counters) -> Self {
+        let local_endpoint = socket.local_endpoint();
+        let remote_endpoint = socket.remote_endpoint();
+        #[cfg(feature = "tcp-retransmissions")]
+        let retransmissions = match (local_endpoint, remote_endpoint) {
+            (Some(local), Some(remote)) => counters.tcp_retransmissions(local, remote),
+            _ => 0,
+        };
+        #[cfg(not(feature = "tcp-retransmissions"))]
+        let retransmissions = 0;
+        Self {
+            interface,
+            state: socket.state(),
+            listen_endpoint: socket.listen_endpoint(),
+            local_endpoint,
+            remote_endpoint,
+            send_queue: socket.send_queue(),
+            recv_queue: socket.recv_queue(),
+            retransmissions,
+        }
+    }
 }
 
 /// A TCP socket.
 pub struct TcpSocket<'a> {
     io: TcpIo<'a>,
+    interface_bound: bool,
 }
 
 /// The reader half of a TCP socket.
@@ -226,19 +302,53 @@ impl<'a> TcpWriter<'a> {
 
 impl<'a> TcpSocket<'a> {
     /// Create a new TCP socket on the given stack, with the given buffers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no interface of the stack has a free socket slot. The number of slots of each
+    /// interface is set by its [`StackResources`](crate::StackResources) or
+    /// [`InterfaceResources`](crate::InterfaceResources).
     pub fn new(stack: Stack<'a>, rx_buffer: &'a mut [u8], tx_buffer: &'a mut [u8]) -> Self {
-        let handle = stack.with_mut(|i| {
+        let id = stack.with_mut(|i| {
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(tcp::Socket::new(
+            i.add_socket(tcp::Socket::new(
                 tcp::SocketBuffer::new(rx_buffer),
                 tcp::SocketBuffer::new(tx_buffer),
             ))
         });
 
         Self {
-            io: TcpIo { stack, handle },
+            io: TcpIo { stack, id },
+            interface_bound: false,
+        }
+    }
+
+    /// Bind the socket to a network interface of the stack.
+    ///
+    /// By default, [`connect`](Self::connect) uses the interface routing to the remote host,
+    /// and [`accept`](Self::accept) the interface having the local address, if one is given.
+    /// Sockets listening on any address only accept connections on a single interface, the one
+    /// with the preferred default route when they were created, unless bound with this method.
+    pub fn bind_to_interface(&mut self, iface: InterfaceId) -> Result<(), BindInterfaceError> {
+        if self.io.with(|s, _| s.is_open()) {
+            return Err(BindInterfaceError::InvalidState);
         }
+        self.io.id = self.io.stack.with_mut(|i| i.move_socket(self.io.id, iface))?;
+        self.interface_bound = true;
+        Ok(())
+    }
+
+    /// Move the socket to the interface given by `f`, unless it is open or bound to an interface.
+    fn update_interface(
+        &mut self,
+        f: impl FnOnce(&mut Inner, SocketId) -> Result<SocketId, BindInterfaceError>,
+    ) -> Result<(), BindInterfaceError> {
+        if self.interface_bound || self.io.with(|s, _| s.is_open()) {
+            return Ok(());
+        }
+        self.io.id = self.io.stack.with_mut(|i| f(i, self.io.id))?;
+        Ok(())
     }
 
     /// Return the maximum number of bytes inside the recv buffer.
@@ -321,6 +431,8 @@ impl<'a> TcpSocket<'a> {
     where
         T: Into<IpEndpoint>,
     {
+        let remote_endpoint = remote_endpoint.into();
+        self.update_interface(|i, id| i.route_socket(id, remote_endpoint.addr))?;
         let local_port = self.io.stack.with_mut(|i| i.get_local_port());
 
         match {
@@ -362,6 +474,10 @@ impl<'a> TcpSocket<'a> {
     {
         match self.state() {
             tcp::State::Closed | tcp::State::TimeWait => {
+                let remote_endpoint = remote_endpoint.into();
+                if let Err(e) = self.update_interface(|i, id| i.route_socket(id, remote_endpoint.addr)) {
+                    return Err(TryError::Other(e.into()));
+                }
                 let local_port = self.io.stack.with_mut(|i| i.get_local_port());
                 match self
                     .io
@@ -385,6 +501,10 @@ impl<'a> TcpSocket<'a> {
     where
         T: Into<IpListenEndpoint>,
     {
+        let local_endpoint = local_endpoint.into();
+        if let Some(addr) = local_endpoint.addr {
+            self.update_interface(|i, id| i.bind_socket(id, addr))?;
+        }
         match self.io.with_mut(|s, _| s.listen(local_endpoint)) {
             Ok(()) => {}
             Err(tcp::ListenError::InvalidState) => return Err(AcceptError::InvalidState),
@@ -418,11 +538,19 @@ impl<'a> TcpSocket<'a> {
         T: Into<IpListenEndpoint>,
     {
         match self.state() {
-            tcp::State::Closed | tcp::State::TimeWait => match self.io.with_mut(|s, _| s.listen(local_endpoint)) {
-                Ok(()) => Err(TryError::WouldBlock),
-                Err(tcp::ListenError::InvalidState) => Err(TryError::Other(AcceptError::InvalidState)),
-                Err(tcp::ListenError::Unaddressable) => Err(TryError::Other(AcceptError::InvalidPort)),
-            },
+            tcp::State::Closed | tcp::State::TimeWait => {
+                let local_endpoint = local_endpoint.into();
+                if let Some(addr) = local_endpoint.addr
+                    && let Err(e) = self.update_interface(|i, id| i.bind_socket(id, addr))
+                {
+                    return Err(TryError::Other(e.into()));
+                }
+                match self.io.with_mut(|s, _| s.listen(local_endpoint)) {
+                    Ok(()) => Err(TryError::WouldBlock),
+                    Err(tcp::ListenError::InvalidState) => Err(TryError::Other(AcceptError::InvalidState)),
+                    Err(tcp::ListenError::Unaddressable) => Err(TryError::Other(AcceptError::InvalidPort)),
+                }
+            }
             tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => Err(TryError::WouldBlock),
             _ => Ok(()),
         }
@@ -565,6 +693,15 @@ impl<'a> TcpSocket<'a> {
         self.io.with(|s, _| s.state())
     }
 
+    /// Get information about the socket and its connection, including its retransmissions.
+    pub fn info(&self) -> TcpInfo {
+        let id = self.io.id;
+        self.io.stack.with(|i| {
+            let state = &i.ifaces[id.iface.index()];
+            TcpInfo::new(id.iface, state.sockets.get::<tcp::Socket>(id.handle), &state.counters)
+        })
+    }
+
     /// Close the write half of the socket.
     ///
     /// This closes only the write half of the socket. The read half side remains open, the
@@ -624,7 +761,7 @@ impl<'a> TcpSocket<'a> {
 
 impl<'a> Drop for TcpSocket<'a> {
     fn drop(&mut self) {
-        self.io.stack.with_mut(|i| i.sockets.remove(self.io.handle));
+        self.io.stack.with_mut(|i| i.remove_socket(self.io.id));
     }
 }
 
@@ -643,22 +780,22 @@ fn _assert_covariant_writer<'a, 'b: 'a>(x: TcpWriter<'b>) -> TcpWriter<'a> {
 #[derive(Copy, Clone)]
 struct TcpIo<'a> {
     stack: Stack<'a>,
-    handle: SocketHandle,
+    id: SocketId,
 }
 
 impl<'d> TcpIo<'d> {
     fn with<R>(&self, f: impl FnOnce(&tcp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
-            let socket = i.sockets.get::<tcp::Socket>(self.handle);
-            f(socket, &i.iface)
+            let (socket, iface) = i.socket::<tcp::Socket>(self.id);
+            f(socket, iface)
         })
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut tcp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<tcp::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<tcp::Socket>(self.id);
+            let res = f(socket, iface);
+            i.wake(self.id);
             res
         })
     }
@@ -931,6 +1068,7 @@ mod embedded_io_impls {
                 ConnectError::ConnectionReset => embedded_io_async::ErrorKind::ConnectionReset,
                 ConnectError::TimedOut => embedded_io_async::ErrorKind::TimedOut,
                 ConnectError::NoRoute => embedded_io_async::ErrorKind::NotConnected,
+                ConnectError::NoFreeSocket => embedded_io_async::ErrorKind::OutOfMemory,
                 ConnectError::InvalidState => embedded_io_async::ErrorKind::Other,
             }
         }
@@ -1028,6 +1166,7 @@ impl core::fmt::Display for AcceptError {
             Self::InvalidState => f.write_str("InvalidState"),
             Self::InvalidPort => f.write_str("InvalidPort"),
             Self::ConnectionReset => f.write_str("ConnectionReset"),
+            Self::NoFreeSocket => f.write_str("NoFreeSocket"),
         }
     }
 }
@@ -1035,10 +1174,13 @@ impl core::error::Error for AcceptError {}
 
 /// TCP client compatible with `embedded-nal-async` traits.
 pub mod client {
-    use core::cell::{Cell, UnsafeCell};
+    use core::cell::{Cell, RefCell, UnsafeCell};
     use core::mem::MaybeUninit;
     use core::net::IpAddr;
     use core::ptr::NonNull;
+    use core::task::Waker;
+
+    use embassy_sync::waitqueue::WakerRegistration;
 
     use super::*;
 
@@ -1091,7 +1233,7 @@ pub mod client {
                 IpAddr::V6(_) => panic!("ipv6 support not enabled"),
             };
             let remote_endpoint = (addr, remote.port());
-            let mut socket = TcpConnection::new(self.stack, self.state)?;
+            let mut socket = TcpConnection::new(self.stack, &self.state.pool).ok_or(Error::ConnectionReset)?;
             socket.socket.set_timeout(self.socket_timeout);
             socket
                 .socket
@@ -1102,29 +1244,40 @@ pub mod client {
         }
     }
 
-    /// Opened TCP connection in a [`TcpClient`].
+    /// Opened TCP connection in a [`TcpClient`] or a [`TcpListener`](super::listener::TcpListener).
     pub struct TcpConnection<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> {
-        socket: TcpSocket<'d>,
-        state: &'d TcpClientState<N, TX_SZ, RX_SZ>,
+        pub(super) socket: TcpSocket<'d>,
+        pool: &'d Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
         bufs: NonNull<([u8; TX_SZ], [u8; RX_SZ])>,
     }
 
     impl<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpConnection<'d, N, TX_SZ, RX_SZ> {
-        fn new(stack: Stack<'d>, state: &'d TcpClientState<N, TX_SZ, RX_SZ>) -> Result<Self, Error> {
-            let mut bufs = state.pool.alloc().ok_or(Error::ConnectionReset)?;
-            Ok(Self {
+        /// Create a socket with buffers from `pool`, or return `None` if the pool is exhausted.
+        pub(super) fn new(stack: Stack<'d>, pool: &'d Pool<([u8; TX_SZ], [u8; RX_SZ]), N>) -> Option<Self> {
+            let mut bufs = pool.alloc()?;
+            Some(Self {
                 socket: unsafe { TcpSocket::new(stack, &mut bufs.as_mut().1, &mut bufs.as_mut().0) },
-                state,
+                pool,
                 bufs,
             })
         }
+
+        /// Get the local endpoint of the connection.
+        pub fn local_endpoint(&self) -> Option<IpEndpoint> {
+            self.socket.local_endpoint()
+        }
+
+        /// Get the remote endpoint of the connection.
+        pub fn remote_endpoint(&self) -> Option<IpEndpoint> {
+            self.socket.remote_endpoint()
+        }
     }
 
     impl<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> Drop for TcpConnection<'d, N, TX_SZ, RX_SZ> {
         fn drop(&mut self) {
             unsafe {
                 self.socket.close();
-                self.state.pool.free(self.bufs);
+                self.pool.free(self.bufs);
             }
         }
     }
@@ -1157,7 +1310,7 @@ pub mod client {
 
     /// State for TcpClient
     pub struct TcpClientState<const N: usize, const TX_SZ: usize, const RX_SZ: usize> {
-        pool: Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
+        pub(super) pool: Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
     }
 
     impl<const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpClientState<N, TX_SZ, RX_SZ> {
@@ -1167,25 +1320,27 @@ pub mod client {
         }
     }
 
-    struct Pool<T, const N: usize> {
+    pub(super) struct Pool<T, const N: usize> {
         used: [Cell<bool>; N],
         data: [UnsafeCell<MaybeUninit<T>>; N],
+        waker: RefCell<WakerRegistration>,
     }
 
     impl<T, const N: usize> Pool<T, N> {
         const VALUE: Cell<bool> = Cell::new(false);
         const UNINIT: UnsafeCell<MaybeUninit<T>> = UnsafeCell::new(MaybeUninit::uninit());
 
-        const fn new() -> Self {
+        pub(super) const fn new() -> Self {
             Self {
                 used: [Self::VALUE; N],
                 data: [Self::UNINIT; N],
+                waker: RefCell::new(WakerRegistration::new()),
             }
         }
     }
 
     impl<T, const N: usize> Pool<T, N> {
-        fn alloc(&self) -> Option<NonNull<T>> {
+        pub(super) fn alloc(&self) -> Option<NonNull<T>> {
             for n in 0..N {
                 // this can't race because Pool is not Sync.
                 if !self.used[n].get() {
@@ -1204,6 +1359,255 @@ pub mod client {
             assert!(n >= 0);
             assert!((n as usize) < N);
             self.used[n as usize].set(false);
+            self.waker.borrow_mut().wake();
+        }
+
+        /// Register a waker to be woken when an element is freed.
+        pub(super) fn register_waker(&self, waker: &Waker) {
+            self.waker.borrow_mut().register(waker);
+        }
+    }
+}
+
+/// TCP listener accepting connections on a pool of sockets.
+pub mod listener {
+    use super::client::{Pool, TcpConnection};
+    use super::*;
+
+    /// TCP listener keeping several sockets listening on the same port.
+    ///
+    /// Connections arriving while the application is busy with a previous one are accepted by
+    /// the other listening sockets, up to the backlog, instead of being rejected.
+    ///
+    /// The sockets and their tx and rx buffers according to TX_SZ and RX_SZ are taken from a pool of N,
+    /// shared by the listening sockets and the accepted connections. A connection returns its socket to
+    /// the pool when dropped.
+    pub struct TcpListener<'d, const N: usize, const TX_SZ: usize = 1024, const RX_SZ: usize = 1024> {
+        stack: Stack<'d>,
+        state: &'d TcpListenerState<N, TX_SZ, RX_SZ>,
+        local_endpoint: IpListenEndpoint,
+        backlog: usize,
+        socket_timeout: Option<Duration>,
+        listening: [Option<TcpConnection<'d, N, TX_SZ, RX_SZ>>; N],
+    }
+
+    impl<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpListener<'d, N, TX_SZ, RX_SZ> {
+        /// Create a new `TcpListener` on `local_endpoint`, keeping up to `backlog` sockets listening.
+        ///
+        /// The sockets are put in listening mode immediately, so connections are accepted by the stack
+        /// even before [`accept`](Self::accept) is called.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `backlog` is zero or greater than N, or if no interface of the stack has a free
+        /// socket slot for the sockets of the backlog.
+        pub fn new<T>(
+            stack: Stack<'d>,
+            state: &'d TcpListenerState<N, TX_SZ, RX_SZ>,
+            local_endpoint: T,
+            backlog: usize,
+        ) -> Self
+        where
+            T: Into<IpListenEndpoint>,
+        {
+            assert!(backlog > 0 && backlog <= N, "backlog must be between 1 and N");
+            let mut this = Self {
+                stack,
+                state,
+                local_endpoint: local_endpoint.into(),
+                backlog,
+                socket_timeout: None,
+                listening: [const { None }; N],
+            };
+            this.listen();
+            this
+        }
+
+        /// Set the timeout for each socket created by this `TcpListener`.
+        ///
+        /// If the timeout is set, the socket will be closed if no data is received for the
+        /// specified duration.
+        pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+            self.socket_timeout = timeout;
+            for conn in self.listening.iter_mut().flatten() {
+                conn.socket.set_timeout(timeout);
+            }
+        }
+
+        /// Take sockets from the pool and put them in listening mode until the backlog is full,
+        /// returning the number of listening sockets.
+        fn listen(&mut self) -> usize {
+            let mut listening = self.listening.iter().filter(|s| s.is_some()).count();
+            for slot in self.listening.iter_mut().filter(|s| s.is_none()) {
+                if listening == self.backlog {
+                    break;
+                }
+                let Some(mut conn) = TcpConnection::new(self.stack, &self.state.pool) else {
+                    break;
+                };
+                conn.socket.set_timeout(self.socket_timeout);
+                // Errors are returned by `accept`, which tries again.
+                let _ = conn.socket.try_accept(self.local_endpoint);
+                *slot = Some(conn);
+                listening += 1;
+            }
+            listening
+        }
+
+        /// Accept a connection from a remote host.
+        ///
+        /// This waits until one of the listening sockets receives a connection, and replaces it with
+        /// another socket from the pool. The listening sockets stay open between calls, so connections
+        /// arriving in the meantime are not rejected.
+        ///
+        /// If all the sockets of the pool are used by accepted connections, this waits until one of
+        /// them is dropped.
+        pub async fn accept(&mut self) -> Result<TcpConnection<'d, N, TX_SZ, RX_SZ>, AcceptError> {
+            poll_fn(|cx| self.poll_accept(cx)).await
+        }
+
+        fn poll_accept(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<TcpConnection<'d, N, TX_SZ, RX_SZ>, AcceptError>> {
+            if self.listen() < self.backlog {
+                self.state.pool.register_waker(cx.waker());
+            }
+
+            for i in 0..N {
+                let Some(conn) = &mut self.listening[i] else { continue };
+                match conn.socket.try_accept(self.local_endpoint) {
+                    Ok(()) => {
+                        let conn = unwrap!(self.listening[i].take());
+                        self.listen();
+                        return Poll::Ready(Ok(conn));
+                    }
+                    Err(TryError::WouldBlock) => conn.socket.io.with_mut(|s, _| s.register_send_waker(cx.waker())),
+                    Err(TryError::Other(e)) => return Poll::Ready(Err(e)),
+                }
+            }
+            Poll::Pending
+        }
+    }
+
+    /// State for TcpListener
+    pub struct TcpListenerState<const N: usize, const TX_SZ: usize, const RX_SZ: usize> {
+        pool: Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
+    }
+
+    impl<const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpListenerState<N, TX_SZ, RX_SZ> {
+        /// Create a new `TcpListenerState`.
+        pub const fn new() -> Self {
+            Self { pool: Pool::new() }
+        }
+    }
+
+    impl<const N: usize, const TX_SZ: usize, const RX_SZ: usize> Default for TcpListenerState<N, TX_SZ, RX_SZ> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "proto-ipv4", feature = "medium-ethernet"))]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_futures::select::{Either, select, select_array};
+    use embassy_net_virtual::driver::HardwareAddress;
+    use embassy_net_virtual::{Config as LinkConfig, Link, Port};
+    use embassy_time::{Timer, with_timeout};
+    use heapless::Vec;
+    use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+    use super::listener::{TcpListener, TcpListenerState};
+    use super::*;
+    use crate::{Config, Runner, StackResources, StaticConfigV4, new};
+
+    const SERVER: Ipv4Address = Ipv4Address::new(10, 9, 0, 1);
+    const CLIENT: Ipv4Address = Ipv4Address::new(10, 9, 0, 2);
+
+    fn stack<'d>(
+        link: &'d Link,
+        index: usize,
+        address: Ipv4Address,
+        resources: &'d mut StackResources<8>,
+    ) -> (Stack<'d>, Runner<'d, Port<'d, 2, 1514, 16>>) {
+        let config = Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(address, 24),
+            gateway: None,
+            dns_servers: Vec::new(),
+        });
+        let port = link.port(index, HardwareAddress::Ethernet([2, 0, 0, 0, 0, index as u8 + 1]));
+        new(port, config, resources, index as u64 + 1)
+    }
+
+    #[test]
+    fn listener_pool() {
+        let link = Link::new(LinkConfig::default());
+        let (mut server_resources, mut client_resources) = (StackResources::new(), StackResources::new());
+        let (server, mut server_runner) = stack(&link, 0, SERVER, &mut server_resources);
+        let (client, mut client_runner) = stack(&link, 1, CLIENT, &mut client_resources);
+        let state = TcpListenerState::<2, 256, 256>::new();
+
+        let test = async {
+            server.wait_link_up().await;
+            client.wait_link_up().await;
+            let mut listener = TcpListener::new(server, &state, 1234, 2);
+
+            let mut buffers = [[0; 256]; 6];
+            let [rx_a, tx_a, rx_b, tx_b, rx_c, tx_c] = &mut buffers;
+            let mut a = TcpSocket::new(client, rx_a, tx_a);
+            let mut b = TcpSocket::new(client, rx_b, tx_b);
+            let mut c = TcpSocket::new(client, rx_c, tx_c);
+
+            // Both connections are accepted by the backlog, before `accept` is called.
+            a.connect((SERVER, 1234)).await.unwrap();
+            b.connect((SERVER, 1234)).await.unwrap();
+            let mut conn_a = listener.accept().await.unwrap();
+            let conn_b = listener.accept().await.unwrap();
+            assert_eq!(conn_a.remote_endpoint(), a.local_endpoint());
+            assert_eq!(conn_b.remote_endpoint(), b.local_endpoint());
+
+            // All the sockets are busy, further connections are rejected and `accept` waits.
+            assert_eq!(c.connect((SERVER, 1234)).await, Err(ConnectError::ConnectionReset));
+            match select(listener.accept(), Timer::after_millis(100)).await {
+                Either::First(_) => panic!("accepted without a free socket"),
+                Either::Second(()) => {}
+            }
+
+            // Sockets closed by the remote end are recycled once dropped.
+            a.close();
+            let mut buf = [0; 16];
+            assert_eq!(conn_a.socket.read(&mut buf).await, Ok(0));
+            drop(conn_a);
+            let (conn_c, ()) = join(listener.accept(), async {
+                c.abort();
+                c.flush().await.unwrap();
+                c.connect((SERVER, 1234)).await.unwrap();
+            })
+            .await;
+            let mut conn_c = conn_c.unwrap();
+            assert_eq!(conn_c.remote_endpoint(), c.local_endpoint());
+            conn_c.socket.write(b"hello").await.unwrap();
+            let n = c.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hello");
+
+            // As are sockets closed locally.
+            drop(conn_b);
+            b.abort();
+            b.flush().await.unwrap();
+            let (conn, ()) = join(listener.accept(), async {
+                b.connect((SERVER, 1234)).await.unwrap();
+            })
+            .await;
+            assert_eq!(conn.unwrap().remote_endpoint(), b.local_endpoint());
+        };
+
+        let runners = select_array([server_runner.run(), client_runner.run()]);
+        match block_on(select(runners, with_timeout(Duration::from_secs(10), test))) {
+            Either::Second(r) => r.unwrap(),
+            _ => unreachable!(),
         }
     }
 }
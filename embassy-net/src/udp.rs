@@ -4,21 +4,33 @@ use core::future::{Future, poll_fn};
 use core::mem;
 use core::task::{Context, Poll};
 
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 use smoltcp::socket::udp;
 pub use smoltcp::socket::udp::{PacketMetadata, UdpMetadata};
 use smoltcp::wire::IpListenEndpoint;
 
-use crate::{Stack, TryError};
+use crate::{BindInterfaceError, InterfaceId, SocketId, Stack, TryError};
 
 /// Error returned by [`UdpSocket::bind`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum BindError {
     /// The socket was already open.
     InvalidState,
     /// No route to host.
     NoRoute,
+    /// The interface having the local address has no free socket slot.
+    NoFreeSocket,
+}
+
+impl From<BindInterfaceError> for BindError {
+    fn from(e: BindInterfaceError) -> Self {
+        match e {
+            BindInterfaceError::InvalidState => Self::InvalidState,
+            BindInterfaceError::NoFreeSocket => Self::NoFreeSocket,
+        }
+    }
 }
 
 /// Error returned by [`UdpSocket::send_to`].
@@ -41,14 +53,44 @@ pub enum RecvError {
     Truncated,
 }
 
+/// Information about a UDP socket, see [`UdpSocket::info`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct UdpInfo {
+    /// Interface the socket is attached to.
+    pub interface: InterfaceId,
+    /// Endpoint the socket is bound to.
+    pub endpoint: IpListenEndpoint,
+    /// Bytes of the packets in the transmit buffer, not sent yet.
+    pub send_queue: usize,
+    /// Bytes of the packets in the receive buffer, not read yet.
+    pub recv_queue: usize,
+}
+
+impl UdpInfo {
+    pub(crate) fn new(interface: InterfaceId, socket: &udp::Socket) -> Self {
+        Self {
+            interface,
+            endpoint: socket.endpoint(),
+            send_queue: socket.send_queue(),
+            recv_queue: socket.recv_queue(),
+        }
+    }
+}
+
 /// An UDP socket.
 pub struct UdpSocket<'a> {
     stack: Stack<'a>,
-    handle: SocketHandle,
+    id: SocketId,
 }
 
 impl<'a> UdpSocket<'a> {
     /// Create a new UDP socket using the provided stack and buffers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no interface of the stack has a free socket slot.
     pub fn new(
         stack: Stack<'a>,
         rx_meta: &'a mut [PacketMetadata],
@@ -56,18 +98,18 @@ impl<'a> UdpSocket<'a> {
         tx_meta: &'a mut [PacketMetadata],
         tx_buffer: &'a mut [u8],
     ) -> Self {
-        let handle = stack.with_mut(|i| {
+        let id = stack.with_mut(|i| {
             let rx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(rx_meta) };
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(udp::Socket::new(
+            i.add_socket(udp::Socket::new(
                 udp::PacketBuffer::new(rx_meta, rx_buffer),
                 udp::PacketBuffer::new(tx_meta, tx_buffer),
             ))
         });
 
-        Self { stack, handle }
+        Self { stack, id }
     }
 
     /// Bind the socket to a local endpoint.
@@ -82,6 +124,13 @@ impl<'a> UdpSocket<'a> {
             endpoint.port = self.stack.with_mut(|i| i.get_local_port());
         }
 
+        // Move the socket to the interface having the address, if any.
+        if let Some(addr) = endpoint.addr
+            && !self.is_open()
+        {
+            self.id = self.stack.with_mut(|i| i.bind_socket(self.id, addr))?;
+        }
+
         match self.with_mut(|s, _| s.bind(endpoint)) {
             Ok(()) => Ok(()),
             Err(udp::BindError::InvalidState) => Err(BindError::InvalidState),
@@ -89,18 +138,31 @@ impl<'a> UdpSocket<'a> {
         }
     }
 
+    /// Bind the socket to a network interface of the stack.
+    ///
+    /// Sockets are created on the interface with the preferred default route, and only send and
+    /// receive datagrams on that interface, unless they are bound to a local address of another
+    /// interface with [`bind`](Self::bind).
+    pub fn bind_to_interface(&mut self, iface: InterfaceId) -> Result<(), BindInterfaceError> {
+        if self.is_open() {
+            return Err(BindInterfaceError::InvalidState);
+        }
+        self.id = self.stack.with_mut(|i| i.move_socket(self.id, iface))?;
+        Ok(())
+    }
+
     fn with<R>(&self, f: impl FnOnce(&udp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
-            let socket = i.sockets.get::<udp::Socket>(self.handle);
-            f(socket, &i.iface)
+            let (socket, iface) = i.socket::<udp::Socket>(self.id);
+            f(socket, iface)
         })
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut udp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<udp::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<udp::Socket>(self.id);
+            let res = f(socket, iface);
+            i.wake(self.id);
             res
         })
     }
@@ -456,6 +518,11 @@ impl<'a> UdpSocket<'a> {
         self.with(|s, _| s.endpoint())
     }
 
+    /// Get information about the socket.
+    pub fn info(&self) -> UdpInfo {
+        self.with(|s, _| UdpInfo::new(self.id.iface, s))
+    }
+
     /// Returns whether the socket is open.
 
     pub fn is_open(&self) -> bool {
@@ -505,7 +572,7 @@ impl<'a> UdpSocket<'a> {
 
 impl Drop for UdpSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack.with_mut(|i| i.remove_socket(self.id));
     }
 }
 
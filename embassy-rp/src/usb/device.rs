@@ -472,9 +472,14 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
         }
     }
 
-    async fn enable(&mut self) {}
+    async fn enable(&mut self) {
+        T::regs().sie_ctrl().modify(|w| w.set_pullup_en(true));
+    }
 
-    async fn disable(&mut self) {}
+    async fn disable(&mut self) {
+        // Soft-disconnect from the host.
+        T::regs().sie_ctrl().modify(|w| w.set_pullup_en(false));
+    }
 
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
         Err(Unsupported)
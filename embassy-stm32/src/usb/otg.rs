@@ -410,7 +410,7 @@ impl<'d, T: Instance> embassy_usb_driver::Bus for Bus<'d, T> {
     }
 
     async fn disable(&mut self) {
-        // NOTE: inner call is a no-op
+        // NOTE: inner call only soft-disconnects from the host, the peripheral stays powered.
         self.inner.disable().await
     }
 
@@ -576,6 +576,22 @@ pub struct Bus<'d, T: Instance> {
     inited: bool,
 }
 
+impl<'d, T: Instance> Bus<'d, T> {
+    /// Connect or disconnect the internal pull-up of D+, on the chips that have one.
+    ///
+    /// On the other chips, the pull-up is external and the device can't disconnect from the host.
+    fn set_pullup(connected: bool) {
+        #[cfg(any(usb_v3, usb_v4))]
+        T::regs().bcdr().modify(|w| w.set_dppu(connected));
+
+        #[cfg(stm32l1)]
+        crate::pac::SYSCFG.pmc().modify(|w| w.set_usb_pu(connected));
+
+        #[cfg(not(any(usb_v3, usb_v4, stm32l1)))]
+        let _ = connected;
+    }
+}
+
 impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
     async fn poll(&mut self) -> Event {
         poll_fn(move |cx| {
@@ -735,8 +751,14 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
         trace!("EPR after: {:04x}", epr.read().0);
     }
 
-    async fn enable(&mut self) {}
-    async fn disable(&mut self) {}
+    async fn enable(&mut self) {
+        Self::set_pullup(true);
+    }
+
+    async fn disable(&mut self) {
+        // Soft-disconnect from the host.
+        Self::set_pullup(false);
+    }
 
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
         let regs = T::regs();
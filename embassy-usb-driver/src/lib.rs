@@ -205,6 +205,9 @@ pub trait Bus {
     async fn enable(&mut self);
 
     /// Disable and powers down the USB peripheral.
+    ///
+    /// Drivers should also soft-disconnect from the host, when the hardware allows it, by
+    /// disconnecting the pull-up of D+ or D-.
     async fn disable(&mut self);
 
     /// Wait for a bus-related event.
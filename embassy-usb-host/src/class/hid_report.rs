@@ -611,3 +611,160 @@ impl<'a> Iterator for ItemIter<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use embassy_usb::class::hid_report::HidReport;
+
+    use super::*;
+
+    #[derive(HidReport, Debug, PartialEq)]
+    #[hid(usage_page = usage_page::GENERIC_DESKTOP, usage = usage::MOUSE)]
+    struct MouseReport {
+        #[hid(usage_page = usage_page::BUTTON, usage_min = 1, usage_max = 3, bits = 3)]
+        buttons: u8,
+        #[hid(padding, bits = 5)]
+        _padding: u8,
+        #[hid(usages = [usage::X, usage::Y], relative)]
+        xy: [i8; 2],
+        #[hid(usage = usage::WHEEL, relative)]
+        wheel: i8,
+    }
+
+    #[derive(HidReport, Debug, PartialEq)]
+    #[hid(usage_page = usage_page::GENERIC_DESKTOP, usage = usage::KEYBOARD, report_id = 2)]
+    struct KeyboardReport {
+        #[hid(usage_page = usage_page::KEYBOARD, usage_min = 0xE0, usage_max = 0xE7)]
+        modifiers: u8,
+        #[hid(padding)]
+        _reserved: u8,
+        #[hid(usage_page = usage_page::KEYBOARD, usage_min = 0, usage_max = 0xFF, array)]
+        keycodes: [u8; 6],
+    }
+
+    #[derive(HidReport, Debug, PartialEq)]
+    #[hid(usage_page = usage_page::GENERIC_DESKTOP, usage = usage::GAMEPAD)]
+    struct GamepadReport {
+        #[hid(usages = [usage::X, usage::Y], bits = 12)]
+        xy: [i16; 2],
+        #[hid(usage = usage::HAT_SWITCH, bits = 4, logical_min = 0, logical_max = 7)]
+        hat: u8,
+        #[hid(usage_page = usage_page::BUTTON, usage_min = 1, usage_max = 12)]
+        buttons: [bool; 12],
+        #[hid(usage = usage::SLIDER)]
+        slider: u16,
+    }
+
+    #[test]
+    fn derived_descriptor() {
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x05, 0x01, 0x09, 0x02, 0xA1, 0x01,
+            0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x03, 0x81, 0x02,
+            0x75, 0x05, 0x95, 0x01, 0x81, 0x03,
+            0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x80, 0x25, 0x7F, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06,
+            0x09, 0x38, 0x95, 0x01, 0x81, 0x06,
+            0xC0,
+        ];
+        assert_eq!(MouseReport::DESCRIPTOR, expected);
+        assert_eq!(MouseReport::SIZE, 4);
+        assert_eq!(KeyboardReport::SIZE, 9);
+        assert_eq!(GamepadReport::SIZE, 7);
+    }
+
+    #[test]
+    fn mouse_round_trip() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(MouseReport::DESCRIPTOR);
+        assert!(!desc.has_report_ids);
+
+        let report = MouseReport {
+            buttons: 0b101,
+            _padding: 0,
+            xy: [-5, 100],
+            wheel: -1,
+        };
+        let mut buf = [0; 8];
+        assert_eq!(report.serialize(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], &[0x05, 0xFB, 0x64, 0xFF]);
+
+        let page = usage_page::GENERIC_DESKTOP;
+        assert_eq!(desc.extract_bool(&buf, 0, usage_page::BUTTON, 1), Some(true));
+        assert_eq!(desc.extract_bool(&buf, 0, usage_page::BUTTON, 2), Some(false));
+        assert_eq!(desc.extract_bool(&buf, 0, usage_page::BUTTON, 3), Some(true));
+        assert_eq!(desc.extract_i32(&buf, 0, page, usage::X), Some(-5));
+        assert_eq!(desc.extract_i32(&buf, 0, page, usage::Y), Some(100));
+        assert_eq!(desc.extract_i32(&buf, 0, page, usage::WHEEL), Some(-1));
+        let (wheel, _) = desc.find(0, page, usage::WHEEL).unwrap();
+        assert!(wheel.is_relative());
+
+        assert_eq!(MouseReport::deserialize(&buf[..4]), Ok(report));
+        assert_eq!(
+            MouseReport::deserialize(&buf[..3]),
+            Err(embassy_usb::class::hid_report::Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn keyboard_round_trip() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(KeyboardReport::DESCRIPTOR);
+        assert!(desc.has_report_ids);
+
+        let report = KeyboardReport {
+            modifiers: 0x02, // Left Shift
+            _reserved: 0,
+            keycodes: [0x04, 0x05, 0, 0, 0, 0],
+        };
+        let mut buf = [0; 9];
+        report.serialize(&mut buf).unwrap();
+        assert_eq!(buf, [2, 0x02, 0, 0x04, 0x05, 0, 0, 0, 0]);
+
+        let page = usage_page::KEYBOARD;
+        assert_eq!(desc.extract_bool(&buf, 2, page, 0xE0), Some(false));
+        assert_eq!(desc.extract_bool(&buf, 2, page, 0xE1), Some(true));
+        assert_eq!(desc.extract_bool(&buf, 1, page, 0xE1), None);
+
+        // Array items hold the usages of the pressed keys.
+        let keys = desc.fields().find(|f| !f.is_variable() && !f.is_constant()).unwrap();
+        assert_eq!((keys.usage_min, keys.usage_max, keys.count), (0, 0xFF, 6));
+        assert_eq!((keys.logical_min, keys.logical_max), (0, 0xFF));
+        assert_eq!(keys.extract_u32(&buf[1..], 0), Some(0x04));
+        assert_eq!(keys.extract_u32(&buf[1..], 1), Some(0x05));
+
+        assert_eq!(KeyboardReport::deserialize(&buf), Ok(report));
+        buf[0] = 1;
+        assert_eq!(
+            KeyboardReport::deserialize(&buf),
+            Err(embassy_usb::class::hid_report::Error::WrongReportId)
+        );
+    }
+
+    #[test]
+    fn gamepad_round_trip() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(GamepadReport::DESCRIPTOR);
+
+        let mut buttons = [false; 12];
+        buttons[0] = true;
+        buttons[11] = true;
+        let report = GamepadReport {
+            xy: [-2048, 2047],
+            hat: 5,
+            buttons,
+            slider: 0xBEEF,
+        };
+        let mut buf = [0; 7];
+        report.serialize(&mut buf).unwrap();
+
+        let page = usage_page::GENERIC_DESKTOP;
+        assert_eq!(desc.extract_i32(&buf, 0, page, usage::X), Some(-2048));
+        assert_eq!(desc.extract_i32(&buf, 0, page, usage::Y), Some(2047));
+        assert_eq!(desc.extract_u32(&buf, 0, page, usage::HAT_SWITCH), Some(5));
+        let (hat, _) = desc.find(0, page, usage::HAT_SWITCH).unwrap();
+        assert_eq!((hat.bit_size, hat.logical_min, hat.logical_max), (4, 0, 7));
+        assert_eq!(desc.extract_bool(&buf, 0, usage_page::BUTTON, 1), Some(true));
+        assert_eq!(desc.extract_bool(&buf, 0, usage_page::BUTTON, 2), Some(false));
+        assert_eq!(desc.extract_bool(&buf, 0, usage_page::BUTTON, 12), Some(true));
+        assert_eq!(desc.extract_u32(&buf, 0, page, usage::SLIDER), Some(0xBEEF));
+
+        assert_eq!(GamepadReport::deserialize(&buf), Ok(report));
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::{env, fs};
+
+static CONFIGS: &[(&str, usize)] = &[
+    // Size of the buffer of `defmt` frames waiting to be sent.
+    ("DEFMT_BUFFER_SIZE", 1024),
+    // Size of the largest `defmt` frame, longer frames are dropped.
+    ("DEFMT_MAX_FRAME_SIZE", 256),
+];
+
+fn main() {
+    let crate_name = env::var("CARGO_PKG_NAME")
+        .unwrap()
+        .to_ascii_uppercase()
+        .replace('-', "_");
+
+    // only rebuild if build.rs changed. Otherwise Cargo will rebuild if any
+    // other file changed.
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Rebuild if config envvar changed.
+    for (name, _) in CONFIGS {
+        println!("cargo:rerun-if-env-changed={crate_name}_{name}");
+    }
+
+    let mut data = String::new();
+    for (name, default) in CONFIGS {
+        let value = match env::var(format!("{crate_name}_{name}")) {
+            Ok(value) => {
+                let Ok(value) = value.parse::<usize>() else {
+                    panic!("Invalid value for env var {name}: {value}")
+                };
+                value
+            }
+            Err(_) => *default,
+        };
+        writeln!(&mut data, "pub const {}: usize = {};", name, value).unwrap();
+    }
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let out_file = out_dir.join("config.rs").to_string_lossy().to_string();
+    fs::write(out_file, data).unwrap();
+}
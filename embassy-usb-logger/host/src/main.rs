@@ -0,0 +1,175 @@
+#![doc = include_str!("../README.md")]
+
+use std::io::Write;
+use std::process::ExitCode;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rusb::{Context, Device, DeviceHandle, Direction, Recipient, RequestType, TransferType, UsbContext};
+
+// Must match `embassy_usb_logger::defmt`.
+const USB_CLASS_VENDOR: u8 = 0xFF;
+const DEFMT_SUBCLASS: u8 = 0x64;
+const DEFMT_PROTOCOL: u8 = 0x01;
+const REQ_GET_DROPPED_FRAMES: u8 = 0x01;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+const DROPPED_FRAMES_PERIOD: Duration = Duration::from_secs(1);
+
+/// The logger interface of a device.
+struct Logger {
+    handle: DeviceHandle<Context>,
+    interface: u8,
+    endpoint: u8,
+}
+
+fn parse_id(arg: Option<String>) -> Option<u16> {
+    let arg = arg?;
+    u16::from_str_radix(arg.trim_start_matches("0x"), 16).ok()
+}
+
+/// Finds the logger interface of `device`.
+fn find_interface(device: &Device<Context>) -> Option<(u8, u8)> {
+    let config = device.active_config_descriptor().ok()?;
+    config.interfaces().flat_map(|i| i.descriptors()).find_map(|alt| {
+        if (alt.class_code(), alt.sub_class_code(), alt.protocol_code())
+            != (USB_CLASS_VENDOR, DEFMT_SUBCLASS, DEFMT_PROTOCOL)
+        {
+            return None;
+        }
+        let endpoint = alt
+            .endpoint_descriptors()
+            .find(|ep| ep.direction() == Direction::In && ep.transfer_type() == TransferType::Bulk)?;
+        Some((alt.interface_number(), endpoint.address()))
+    })
+}
+
+fn open(context: &Context, vid: Option<u16>, pid: Option<u16>) -> Option<Logger> {
+    for device in context.devices().ok()?.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
+        };
+        if vid.is_some_and(|vid| vid != desc.vendor_id()) || pid.is_some_and(|pid| pid != desc.product_id()) {
+            continue;
+        }
+        let Some((interface, endpoint)) = find_interface(&device) else {
+            continue;
+        };
+        let handle = match device.open() {
+            Ok(handle) => handle,
+            Err(e) => {
+                eprintln!("cannot open {:04x}:{:04x}: {}", desc.vendor_id(), desc.product_id(), e);
+                continue;
+            }
+        };
+        // Not supported on every platform, where there is no kernel driver to detach anyway.
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        if let Err(e) = handle.claim_interface(interface) {
+            eprintln!("cannot claim interface {}: {}", interface, e);
+            continue;
+        }
+        eprintln!(
+            "reading {:04x}:{:04x}, interface {}",
+            desc.vendor_id(),
+            desc.product_id(),
+            interface
+        );
+        return Some(Logger {
+            handle,
+            interface,
+            endpoint,
+        });
+    }
+    None
+}
+
+fn dropped_frames(logger: &Logger) -> rusb::Result<u32> {
+    let mut buf = [0; 4];
+    let request_type = rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Interface);
+    let n = logger.handle.read_control(
+        request_type,
+        REQ_GET_DROPPED_FRAMES,
+        0,
+        logger.interface as u16,
+        &mut buf,
+        TIMEOUT,
+    )?;
+    if n != buf.len() {
+        return Err(rusb::Error::Other);
+    }
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Copies the stream of `logger` to the standard output, until the device is disconnected.
+fn stream(logger: &Logger) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    let mut buf = [0; 4096];
+    let mut dropped = dropped_frames(logger).unwrap_or(0);
+    let mut checked = Instant::now();
+    loop {
+        match logger.handle.read_bulk(logger.endpoint, &mut buf, TIMEOUT) {
+            Ok(n) => {
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+            }
+            Err(rusb::Error::Timeout) => {}
+            Err(e) => {
+                eprintln!("device disconnected: {}", e);
+                return Ok(());
+            }
+        }
+
+        if checked.elapsed() >= DROPPED_FRAMES_PERIOD {
+            checked = Instant::now();
+            if let Ok(n) = dropped_frames(logger)
+                && n != dropped
+            {
+                eprintln!("{} frames dropped by the device", n.wrapping_sub(dropped));
+                dropped = n;
+            }
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut vid = None;
+    let mut pid = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--vid" => vid = parse_id(args.next()),
+            "--pid" => pid = parse_id(args.next()),
+            _ => {
+                eprintln!("usage: embassy-usb-logger-host [--vid <hex>] [--pid <hex>]");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let context = match Context::new() {
+        Ok(context) => context,
+        Err(e) => {
+            eprintln!("cannot initialize libusb: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut waiting = false;
+    loop {
+        match open(&context, vid, pid) {
+            Some(logger) => {
+                waiting = false;
+                // The standard output is closed, for example `defmt-print` exited.
+                if stream(&logger).is_err() {
+                    return ExitCode::SUCCESS;
+                }
+            }
+            None if !waiting => {
+                eprintln!("waiting for a device...");
+                waiting = true;
+            }
+            None => {}
+        }
+        sleep(Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,395 @@
+//! Global logger of [`defmt`](https://defmt.ferrous-systems.com), streaming its frames over USB.
+//!
+//! With the `defmt-logger` feature, this crate is the global logger of `defmt`, in place of for
+//! example `defmt-rtt`. Frames are buffered, then sent either over a vendor-specific interface,
+//! with [`DefmtClass`] or [`run`], or over a CDC-ACM serial port, with [`with_class`].
+//!
+//! The stream is the one `defmt-rtt` produces, so `defmt-print` decodes it. Over CDC-ACM:
+//!
+//! ```sh
+//! stty -F /dev/ttyACM0 raw && defmt-print -e firmware.elf < /dev/ttyACM0
+//! ```
+//!
+//! The vendor-specific interface needs no serial port driver, and is read with the host tool in
+//! `embassy-usb-logger/host`:
+//!
+//! ```sh
+//! embassy-usb-logger-host | defmt-print -e firmware.elf
+//! ```
+//!
+//! Frames are buffered whole: a frame that does not fit in the buffer, because the host is not
+//! connected or not reading fast enough, is dropped and counted by [`dropped_frames`], so the
+//! stream stays decodable. This requires the default `rzcobs` encoding of `defmt`, which
+//! delimits frames.
+//!
+//! The buffers are set at build time, by environment variables:
+//!
+//! * `EMBASSY_USB_LOGGER_DEFMT_BUFFER_SIZE`: size of the buffer of frames waiting to be sent,
+//!   1024 bytes by default.
+//! * `EMBASSY_USB_LOGGER_DEFMT_MAX_FRAME_SIZE`: size of the largest encoded frame, 256 bytes by
+//!   default. Longer frames are dropped.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::RestoreState;
+use embassy_futures::join::join;
+use embassy_sync::pipe::Pipe;
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::control::{InResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Driver, Endpoint, EndpointIn};
+use embassy_usb::msos::{self, windows_version};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Config, Handler};
+
+use crate::config::{DEFMT_BUFFER_SIZE, DEFMT_MAX_FRAME_SIZE};
+use crate::{CS, MAX_PACKET_SIZE};
+
+/// Interface class of the logger interface, vendor-specific.
+pub const USB_CLASS_VENDOR: u8 = 0xFF;
+/// Interface subclass of the logger interface.
+pub const DEFMT_SUBCLASS: u8 = 0x64;
+/// Interface protocol of the logger interface.
+pub const DEFMT_PROTOCOL: u8 = 0x01;
+
+/// Vendor request to the logger interface, returning [`dropped_frames`] as a little-endian `u32`.
+pub const REQ_GET_DROPPED_FRAMES: u8 = 0x01;
+
+/// Device interface GUID of the logger interface, for WinUSB.
+pub const DEVICE_INTERFACE_GUID: &str = "{4A3B7C1E-52D9-4F6B-8E0A-6C2D91B5F734}";
+
+/// Encoded frames, waiting to be sent.
+static BUFFER: Pipe<CS, DEFMT_BUFFER_SIZE> = Pipe::new();
+
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the number of frames dropped since boot, because the buffer was full or the frame
+/// too long.
+pub fn dropped_frames() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The frame being encoded.
+struct Frame {
+    taken: bool,
+    restore: RestoreState,
+    encoder: ::defmt::Encoder,
+    buf: [u8; DEFMT_MAX_FRAME_SIZE],
+    len: usize,
+    overflow: bool,
+}
+
+struct FrameCell(UnsafeCell<Frame>);
+
+// Only accessed by the logger, in a critical section.
+unsafe impl Sync for FrameCell {}
+
+static FRAME: FrameCell = FrameCell(UnsafeCell::new(Frame {
+    taken: false,
+    restore: RestoreState::invalid(),
+    encoder: ::defmt::Encoder::new(),
+    buf: [0; DEFMT_MAX_FRAME_SIZE],
+    len: 0,
+    overflow: false,
+}));
+
+impl Frame {
+    /// Runs `f` with the encoder, and a function appending its output to the frame.
+    fn encode(&mut self, f: impl FnOnce(&mut ::defmt::Encoder, &mut dyn FnMut(&[u8]))) {
+        let Self {
+            encoder,
+            buf,
+            len,
+            overflow,
+            ..
+        } = self;
+        f(encoder, &mut |bytes| match buf.get_mut(*len..*len + bytes.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(bytes);
+                *len += bytes.len();
+            }
+            None => *overflow = true,
+        });
+    }
+}
+
+#[::defmt::global_logger]
+struct Logger;
+
+unsafe impl ::defmt::Logger for Logger {
+    fn acquire() {
+        // SAFETY: released in `release`.
+        let restore = unsafe { critical_section::acquire() };
+        // SAFETY: in a critical section, and not taken, as checked below.
+        let frame = unsafe { &mut *FRAME.0.get() };
+        if frame.taken {
+            panic!("defmt logger taken reentrantly")
+        }
+        frame.taken = true;
+        frame.restore = restore;
+        frame.len = 0;
+        frame.overflow = false;
+        frame.encode(|encoder, write| encoder.start_frame(write));
+    }
+
+    unsafe fn flush() {
+        // The frames are sent by the USB task, which cannot run while the logger is taken.
+    }
+
+    unsafe fn release() {
+        // SAFETY: the logger is taken, in a critical section.
+        let frame = unsafe { &mut *FRAME.0.get() };
+        frame.encode(|encoder, write| encoder.end_frame(write));
+
+        // Either the whole frame is buffered, or none of it.
+        let bytes = &frame.buf[..frame.len];
+        if frame.overflow || BUFFER.free_capacity() < bytes.len() || BUFFER.try_write_all(bytes).is_err() {
+            DROPPED.store(DROPPED.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+        }
+
+        frame.taken = false;
+        // SAFETY: acquired in `acquire`.
+        unsafe { critical_section::release(frame.restore) };
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        // SAFETY: the logger is taken, in a critical section.
+        let frame = unsafe { &mut *FRAME.0.get() };
+        frame.encode(|encoder, write| encoder.write(bytes, write));
+    }
+}
+
+/// Internal state of [`DefmtClass`].
+pub struct State {
+    control: MaybeUninit<Control>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+        }
+    }
+}
+
+struct Control {
+    iface: InterfaceNumber,
+}
+
+impl Handler for Control {
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Vendor, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_DROPPED_FRAMES if buf.len() >= 4 => {
+                buf[..4].copy_from_slice(&dropped_frames().to_le_bytes());
+                Some(InResponse::Accepted(&buf[..4]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// A vendor-specific interface streaming `defmt` frames on a bulk IN endpoint.
+///
+/// The interface is announced as compatible with WinUSB, with [`DEVICE_INTERFACE_GUID`], if the
+/// MS OS 2.0 descriptors are enabled with [`Builder::msos_descriptor`].
+pub struct DefmtClass<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> DefmtClass<'d, D> {
+    /// Creates a new `DefmtClass` with the provided builder and `max_packet_size` in bytes. For
+    /// full-speed devices, `max_packet_size` has to be one of 8, 16, 32 or 64.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State, max_packet_size: u16) -> Self {
+        let mut func = builder.function(USB_CLASS_VENDOR, DEFMT_SUBCLASS, DEFMT_PROTOCOL);
+        func.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+        func.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+            "DeviceInterfaceGUIDs",
+            msos::PropertyData::RegMultiSz(&[DEVICE_INTERFACE_GUID]),
+        ));
+
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_VENDOR, DEFMT_SUBCLASS, DEFMT_PROTOCOL, None);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control { iface: iface_num });
+        builder.handler(control);
+
+        Self { write_ep }
+    }
+
+    /// Sends the buffered frames to the host. Never returns.
+    pub async fn run(&mut self) -> ! {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        let max_packet_size = (self.write_ep.info().max_packet_size as usize).min(buf.len());
+        loop {
+            self.write_ep.wait_enabled().await;
+            loop {
+                let len = BUFFER.read(&mut buf[..max_packet_size]).await;
+                // A full packet is followed by an empty one, so the host does not wait for more.
+                if self.write_ep.write(&buf[..len]).await.is_err()
+                    || len == max_packet_size && self.write_ep.write(&[]).await.is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the buffered frames over a CDC-ACM serial port, while it is open. Never returns.
+///
+/// This can be used in cases where the usb device is already in use for another connection.
+pub async fn with_class<'d, D: Driver<'d>>(class: CdcAcmClass<'d, D>) -> ! {
+    let mut buf = [0; MAX_PACKET_SIZE as usize];
+    let max_packet_size = (class.max_packet_size() as usize).min(buf.len());
+    let (mut sender, _) = class.split();
+    loop {
+        sender.wait_connection().await;
+        loop {
+            let len = BUFFER.read(&mut buf[..max_packet_size]).await;
+            if sender.write_packet(&buf[..len]).await.is_err()
+                || len == max_packet_size && sender.write_packet(&[]).await.is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// The device state containing buffers that must live as long as the USB peripheral, for [`run`].
+pub struct DeviceState {
+    state: State,
+    config_descriptor: [u8; 128],
+    bos_descriptor: [u8; 64],
+    msos_descriptor: [u8; 256],
+    control_buf: [u8; 64],
+}
+
+impl Default for DeviceState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceState {
+    /// Create a new instance of the device state.
+    pub const fn new() -> Self {
+        Self {
+            state: State::new(),
+            config_descriptor: [0; 128],
+            bos_descriptor: [0; 64],
+            msos_descriptor: [0; 256],
+            control_buf: [0; 64],
+        }
+    }
+}
+
+/// Runs a USB device made of a [`DefmtClass`], using the state and USB driver. Never returns.
+pub async fn run<'d, D: Driver<'d>>(state: &'d mut DeviceState, driver: D) -> ! {
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB defmt logger");
+    config.serial_number = None;
+    config.max_power = 100;
+    config.max_packet_size_0 = MAX_PACKET_SIZE;
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut state.config_descriptor,
+        &mut state.bos_descriptor,
+        &mut state.msos_descriptor,
+        &mut state.control_buf,
+    );
+    builder.msos_descriptor(windows_version::WIN8_1, 0);
+
+    let mut class = DefmtClass::new(&mut builder, &mut state.state, MAX_PACKET_SIZE as u16);
+
+    let mut device = builder.build();
+    join(device.run(), class.run()).await.1
+}
+
+#[cfg(test)]
+mod tests {
+    use ::defmt::Logger as _;
+
+    use super::*;
+
+    /// Logs a frame of `len` bytes, as the `defmt` macros do.
+    fn log(len: usize) {
+        Logger::acquire();
+        // SAFETY: the logger is taken.
+        unsafe {
+            Logger::write(&[0x55; DEFMT_MAX_FRAME_SIZE * 2][..len]);
+            Logger::release();
+        }
+    }
+
+    fn get_dropped_frames() -> u32 {
+        let mut control = Control {
+            iface: InterfaceNumber(2),
+        };
+        // Vendor request to interface 2, IN, of 4 bytes.
+        let req = Request::parse(&[0xC1, REQ_GET_DROPPED_FRAMES, 0, 0, 2, 0, 4, 0]);
+        let mut buf = [0; 8];
+        match control.control_in(req, &mut buf) {
+            Some(InResponse::Accepted(data)) => u32::from_le_bytes(data.try_into().unwrap()),
+            _ => panic!("request rejected"),
+        }
+    }
+
+    // The only test using the logger, whose buffer is global.
+    #[test]
+    fn bounded_buffering() {
+        BUFFER.clear();
+        let dropped = dropped_frames();
+
+        // The first frame of the stream starts with a separator.
+        log(100);
+        let first_len = BUFFER.len();
+        log(100);
+        let frame_len = BUFFER.len() - first_len;
+        assert_eq!(first_len, frame_len + 1);
+        assert_eq!(dropped_frames(), dropped);
+
+        // Frames are buffered whole until the pipe is full, then dropped and counted.
+        let fitting = (DEFMT_BUFFER_SIZE - first_len) / frame_len;
+        for _ in 1..fitting + 3 {
+            log(100);
+        }
+        assert_eq!(BUFFER.len(), first_len + fitting * frame_len);
+        assert_eq!(dropped_frames(), dropped + 3);
+        assert_eq!(get_dropped_frames(), dropped + 3);
+
+        // Frames fit again once the buffer is read.
+        let mut buf = [0; DEFMT_BUFFER_SIZE];
+        let len = BUFFER.try_read(&mut buf).unwrap();
+        assert!(len > first_len);
+        assert_eq!(buf[0], 0);
+        assert_eq!(buf[first_len - 1], 0);
+        BUFFER.clear();
+        log(100);
+        assert_eq!(BUFFER.len(), frame_len);
+
+        // Frames too long for the frame buffer are dropped, even when the pipe has room.
+        log(DEFMT_MAX_FRAME_SIZE);
+        assert_eq!(BUFFER.len(), frame_len);
+        assert_eq!(dropped_frames(), dropped + 4);
+    }
+}
@@ -12,6 +12,14 @@ use embassy_usb::driver::{Driver, EndpointError};
 use embassy_usb::{Builder, Config};
 use log::{Metadata, Record};
 
+#[cfg(feature = "defmt-logger")]
+pub mod defmt;
+
+mod config {
+    #![allow(unused)]
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
 type CS = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
 /// A trait that can be implemented and then passed to the
@@ -0,0 +1,491 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{ToTokens, quote};
+use syn::meta::ParseNestedMeta;
+use syn::{Attribute, Data, DeriveInput, Error, Expr, ExprArray, Fields, Ident, LitInt, Type};
+
+// Flags of main items, as in `embassy_usb::class::hid_report::flags`.
+const CONSTANT: u8 = 1 << 0;
+const VARIABLE: u8 = 1 << 1;
+const RELATIVE: u8 = 1 << 2;
+
+/// Arguments of the struct attribute.
+#[derive(Default)]
+struct ReportArgs {
+    usage_page: Option<Expr>,
+    usage: Option<Expr>,
+    collection: Option<Ident>,
+    report_id: Option<Expr>,
+    kind: Option<Ident>,
+}
+
+/// Arguments of a field attribute.
+#[derive(Default)]
+struct FieldArgs {
+    usage_page: Option<Expr>,
+    usage: Option<Expr>,
+    usages: Option<Vec<Expr>>,
+    usage_min: Option<Expr>,
+    usage_max: Option<Expr>,
+    logical_min: Option<Expr>,
+    logical_max: Option<Expr>,
+    bits: Option<usize>,
+    relative: bool,
+    array: bool,
+    padding: bool,
+}
+
+/// Type of a value of a report.
+#[derive(Clone, Copy)]
+struct Scalar {
+    bits: usize,
+    signed: bool,
+    bool: bool,
+}
+
+impl Scalar {
+    fn parse(ty: &Type) -> Option<Self> {
+        let Type::Path(path) = ty else {
+            return None;
+        };
+        let (bits, signed, bool) = match path.path.get_ident()?.to_string().as_str() {
+            "bool" => (1, false, true),
+            "u8" => (8, false, false),
+            "i8" => (8, true, false),
+            "u16" => (16, false, false),
+            "i16" => (16, true, false),
+            "u32" => (32, false, false),
+            "i32" => (32, true, false),
+            _ => return None,
+        };
+        Some(Self { bits, signed, bool })
+    }
+
+    /// Logical extents of values of `bits` bits.
+    fn logical_extents(self, bits: usize) -> (i32, i32) {
+        if self.bool {
+            (0, 1)
+        } else if self.signed {
+            let max = (1i64 << (bits - 1)) - 1;
+            (-max as i32 - 1, max as i32)
+        } else {
+            (0, ((1i64 << bits) - 1).min(i32::MAX as i64) as i32)
+        }
+    }
+}
+
+/// Items of the report descriptor, omitting the global items that did not change.
+struct Descriptor {
+    calls: TokenStream,
+    usage_page: Option<String>,
+    logical_min: Option<String>,
+    logical_max: Option<String>,
+    report_size: Option<usize>,
+    report_count: Option<usize>,
+    /// Upper bound of the length of the descriptor.
+    capacity: usize,
+}
+
+impl Descriptor {
+    fn call(&mut self, tokens: TokenStream) {
+        self.calls.extend(quote!(.#tokens));
+        // Items take up to 5 bytes.
+        self.capacity += 5;
+    }
+
+    fn usage_page(&mut self, page: &Expr) {
+        let key = page.to_token_stream().to_string();
+        if self.usage_page.as_ref() != Some(&key) {
+            self.call(quote!(usage_page((#page) as u16)));
+            self.usage_page = Some(key);
+        }
+    }
+
+    fn logical_extents(&mut self, min: TokenStream, max: TokenStream) {
+        let key = min.to_string();
+        if self.logical_min.as_ref() != Some(&key) {
+            self.call(quote!(logical_minimum(#min)));
+            self.logical_min = Some(key);
+        }
+        let key = max.to_string();
+        if self.logical_max.as_ref() != Some(&key) {
+            self.call(quote!(logical_maximum(#max)));
+            self.logical_max = Some(key);
+        }
+    }
+
+    fn report_size_count(&mut self, size: usize, count: usize) {
+        if self.report_size != Some(size) {
+            let size = size as u8;
+            self.call(quote!(report_size(#size)));
+            self.report_size = Some(size as usize);
+        }
+        if self.report_count != Some(count) {
+            let count = count as u16;
+            self.call(quote!(report_count(#count)));
+            self.report_count = Some(count as usize);
+        }
+    }
+}
+
+pub fn run(item: TokenStream) -> TokenStream {
+    match expand(item) {
+        Ok(tokens) => tokens,
+        Err(e) => e.into_compile_error(),
+    }
+}
+
+fn expand(item: TokenStream) -> Result<TokenStream, Error> {
+    let input: DeriveInput = syn::parse2(item)?;
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "HID reports must not use generics"));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(name, "HID reports must have named fields")),
+        },
+        _ => return Err(Error::new_spanned(name, "HID reports must be structs")),
+    };
+
+    let args = parse_report_args(&input.attrs)?;
+    let (Some(usage_page), Some(usage)) = (&args.usage_page, &args.usage) else {
+        return Err(Error::new_spanned(
+            name,
+            "missing `#[hid(usage_page = .., usage = ..)]` of the top-level collection",
+        ));
+    };
+    let collection = match args.collection.as_ref().map(|c| c.to_string()).as_deref() {
+        None | Some("application") => quote!(Application),
+        Some("physical") => quote!(Physical),
+        Some("logical") => quote!(Logical),
+        Some(_) => {
+            return Err(Error::new_spanned(
+                &args.collection,
+                "expected `application`, `physical` or `logical`",
+            ));
+        }
+    };
+    let main_item = args.kind.unwrap_or_else(|| Ident::new("input", Span::call_site()));
+
+    let krate = quote!(::embassy_usb::class::hid_report);
+    let mut descriptor = Descriptor {
+        calls: TokenStream::new(),
+        usage_page: None,
+        logical_min: None,
+        logical_max: None,
+        report_size: None,
+        report_count: None,
+        capacity: 0,
+    };
+    descriptor.usage_page(usage_page);
+    descriptor.call(quote!(usage((#usage) as u16)));
+    descriptor.call(quote!(collection(#krate::Collection::#collection)));
+    let report_id = match &args.report_id {
+        Some(id) => {
+            descriptor.call(quote!(report_id((#id) as u8)));
+            quote!(Some((#id) as u8))
+        }
+        None => quote!(None),
+    };
+
+    let mut offset = 0;
+    let mut serialize = TokenStream::new();
+    let mut deserialize = TokenStream::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let f = parse_field_args(&field.attrs)?;
+
+        let (scalar, len, elem_ty) = match &field.ty {
+            Type::Array(array) => {
+                let len: LitInt = match &array.len {
+                    Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Int(len),
+                        ..
+                    }) => len.clone(),
+                    len => return Err(Error::new_spanned(len, "array lengths must be literals")),
+                };
+                (
+                    Scalar::parse(&array.elem),
+                    Some(len.base10_parse::<usize>()?),
+                    &*array.elem,
+                )
+            }
+            ty => (Scalar::parse(ty), None, ty),
+        };
+        let Some(scalar) = scalar else {
+            return Err(Error::new_spanned(
+                &field.ty,
+                "expected `bool`, `u8`, `i8`, `u16`, `i16`, `u32`, `i32`, or an array of them",
+            ));
+        };
+        let bits = f.bits.unwrap_or(scalar.bits);
+        if bits == 0 || bits > scalar.bits {
+            return Err(Error::new_spanned(
+                ident,
+                format!("`bits` must be between 1 and {}", scalar.bits),
+            ));
+        }
+        let page = f.usage_page.as_ref().unwrap_or(usage_page);
+        let range = match (&f.usage_min, &f.usage_max) {
+            (Some(min), Some(max)) => Some((min, max)),
+            (None, None) => None,
+            _ => {
+                return Err(Error::new_spanned(
+                    ident,
+                    "`usage_min` and `usage_max` must be used together",
+                ));
+            }
+        };
+
+        // Size and count of the elements, and flags of the main item.
+        let (size, count, flags);
+        if f.padding {
+            (size, count, flags) = (bits, len.unwrap_or(1), CONSTANT | VARIABLE);
+            descriptor.report_size_count(size, count);
+            descriptor.call(quote!(#main_item(#flags)));
+            deserialize.extend(quote!(#ident: ::core::default::Default::default(),));
+            offset += size * count;
+            continue;
+        }
+
+        descriptor.usage_page(page);
+        if f.array {
+            let Some((min, max)) = range else {
+                return Err(Error::new_spanned(
+                    ident,
+                    "array items need `usage_min` and `usage_max`",
+                ));
+            };
+            (size, count, flags) = (bits, len.unwrap_or(1), 0);
+            descriptor.call(quote!(usage_minimum((#min) as u16)));
+            descriptor.call(quote!(usage_maximum((#max) as u16)));
+            descriptor.logical_extents(
+                logical(&f.logical_min, quote!((#min) as i32)),
+                logical(&f.logical_max, quote!((#max) as i32)),
+            );
+        } else if len.is_none() && range.is_some() {
+            // Bitmap, of one bit per usage.
+            if scalar.signed || scalar.bool {
+                return Err(Error::new_spanned(&field.ty, "bitmaps must be unsigned integers"));
+            }
+            let (min, max) = range.unwrap();
+            (size, count, flags) = (1, bits, VARIABLE);
+            descriptor.call(quote!(usage_minimum((#min) as u16)));
+            descriptor.call(quote!(usage_maximum((#max) as u16)));
+            descriptor.logical_extents(
+                logical(&f.logical_min, quote!(0i32)),
+                logical(&f.logical_max, quote!(1i32)),
+            );
+        } else {
+            (size, count) = (bits, len.unwrap_or(1));
+            flags = if f.relative { VARIABLE | RELATIVE } else { VARIABLE };
+            match (&f.usage, &f.usages, range) {
+                (Some(usage), None, None) if len.is_none() => {
+                    descriptor.call(quote!(usage((#usage) as u16)));
+                }
+                (None, Some(usages), None) if Some(usages.len()) == len => {
+                    for usage in usages {
+                        descriptor.call(quote!(usage((#usage) as u16)));
+                    }
+                }
+                (None, None, Some((min, max))) => {
+                    descriptor.call(quote!(usage_minimum((#min) as u16)));
+                    descriptor.call(quote!(usage_maximum((#max) as u16)));
+                }
+                _ if len.is_none() => {
+                    return Err(Error::new_spanned(
+                        ident,
+                        "expected `usage`, `usage_min` and `usage_max`, or `padding`",
+                    ));
+                }
+                _ => {
+                    return Err(Error::new_spanned(
+                        ident,
+                        "expected one of `usages` for each element, or `usage_min` and `usage_max`",
+                    ));
+                }
+            }
+            let (min, max) = scalar.logical_extents(bits);
+            descriptor.logical_extents(
+                logical(&f.logical_min, min.to_token_stream()),
+                logical(&f.logical_max, max.to_token_stream()),
+            );
+        }
+        descriptor.report_size_count(size, count);
+        descriptor.call(quote!(#main_item(#flags)));
+
+        // Bitmaps are packed as one value of `count` bits.
+        let (bits, count) = if size == 1 && len.is_none() {
+            (count, 1)
+        } else {
+            (size, count)
+        };
+        let read = if scalar.bool {
+            quote!(#krate::read_bits(data, offset, #bits) != 0)
+        } else if scalar.signed {
+            quote!(#krate::read_bits_signed(data, offset, #bits) as #elem_ty)
+        } else {
+            quote!(#krate::read_bits(data, offset, #bits) as #elem_ty)
+        };
+        if len.is_some() {
+            serialize.extend(quote! {
+                for (i, value) in self.#ident.iter().enumerate() {
+                    #krate::write_bits(data, #offset + i * #bits, #bits, *value as u32);
+                }
+            });
+            deserialize.extend(quote! {
+                #ident: ::core::array::from_fn(|i| {
+                    let offset = #offset + i * #bits;
+                    #read
+                }),
+            });
+        } else {
+            serialize.extend(quote! {
+                #krate::write_bits(data, #offset, #bits, self.#ident as u32);
+            });
+            deserialize.extend(quote! {
+                #ident: {
+                    let offset = #offset;
+                    #read
+                },
+            });
+        }
+        offset += bits * count;
+    }
+    descriptor.call(quote!(end_collection()));
+
+    if !offset.is_multiple_of(8) {
+        return Err(Error::new_spanned(
+            name,
+            format!(
+                "HID reports must be a whole number of bytes, add {} bits of padding",
+                8 - offset % 8
+            ),
+        ));
+    }
+    let size = offset / 8 + args.report_id.is_some() as usize;
+    let capacity = descriptor.capacity;
+    let calls = descriptor.calls;
+
+    Ok(quote! {
+        #[automatically_derived]
+        impl #krate::HidReport for #name {
+            const DESCRIPTOR: &'static [u8] = {
+                const BUILDER: #krate::ReportDescriptorBuilder<#capacity> =
+                    #krate::ReportDescriptorBuilder::new() #calls;
+                const BYTES: [u8; BUILDER.len()] = BUILDER.to_array();
+                &BYTES
+            };
+            const REPORT_ID: Option<u8> = #report_id;
+            const SIZE: usize = #size;
+
+            fn serialize(&self, buf: &mut [u8]) -> Result<usize, #krate::Error> {
+                if buf.len() < Self::SIZE {
+                    return Err(#krate::Error::BufferTooSmall);
+                }
+                let data = &mut buf[..Self::SIZE];
+                let data = match Self::REPORT_ID {
+                    Some(id) => {
+                        data[0] = id;
+                        &mut data[1..]
+                    }
+                    None => data,
+                };
+                data.fill(0);
+                #serialize
+                Ok(Self::SIZE)
+            }
+
+            fn deserialize(buf: &[u8]) -> Result<Self, #krate::Error> {
+                if buf.len() < Self::SIZE {
+                    return Err(#krate::Error::BufferTooSmall);
+                }
+                let data = &buf[..Self::SIZE];
+                let data = match Self::REPORT_ID {
+                    Some(id) if data[0] != id => return Err(#krate::Error::WrongReportId),
+                    Some(_) => &data[1..],
+                    None => data,
+                };
+                Ok(Self { #deserialize })
+            }
+        }
+    })
+}
+
+/// Logical extent set by the attribute, or `default`.
+fn logical(arg: &Option<Expr>, default: TokenStream) -> TokenStream {
+    match arg {
+        Some(value) => quote!((#value) as i32),
+        None => default,
+    }
+}
+
+fn hid_attrs(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+    attrs.iter().filter(|a| a.path().is_ident("hid"))
+}
+
+fn parse_report_args(attrs: &[Attribute]) -> Result<ReportArgs, Error> {
+    let mut args = ReportArgs::default();
+    for attr in hid_attrs(attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("usage_page") {
+                args.usage_page = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("usage") {
+                args.usage = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("collection") {
+                args.collection = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("report_id") {
+                args.report_id = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("input") || meta.path.is_ident("output") || meta.path.is_ident("feature") {
+                args.kind = meta.path.get_ident().cloned();
+            } else {
+                return Err(unknown(&meta));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(args)
+}
+
+fn parse_field_args(attrs: &[Attribute]) -> Result<FieldArgs, Error> {
+    let mut args = FieldArgs::default();
+    for attr in hid_attrs(attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("usage_page") {
+                args.usage_page = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("usage") {
+                args.usage = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("usages") {
+                let usages: ExprArray = meta.value()?.parse()?;
+                args.usages = Some(usages.elems.into_iter().collect());
+            } else if meta.path.is_ident("usage_min") {
+                args.usage_min = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("usage_max") {
+                args.usage_max = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("logical_min") {
+                args.logical_min = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("logical_max") {
+                args.logical_max = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("bits") {
+                let bits: LitInt = meta.value()?.parse()?;
+                args.bits = Some(bits.base10_parse()?);
+            } else if meta.path.is_ident("relative") {
+                args.relative = true;
+            } else if meta.path.is_ident("array") {
+                args.array = true;
+            } else if meta.path.is_ident("padding") {
+                args.padding = true;
+            } else {
+                return Err(unknown(&meta));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(args)
+}
+
+fn unknown(meta: &ParseNestedMeta) -> Error {
+    let path = meta.path.to_token_stream().to_string().replace(' ', "");
+    meta.error(format!("unknown hid attribute `{}`", path))
+}
@@ -0,0 +1,38 @@
+#![doc = include_str!("../README.md")]
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+
+mod hid_report;
+
+/// Derives `HidReport` for a struct, generating its report descriptor and the (de)serialization
+/// of its reports.
+///
+/// The following restrictions apply:
+///
+/// * The struct must have named fields, and no generics.
+/// * Fields must be `bool`, `u8`, `i8`, `u16`, `i16`, `u32`, `i32`, or arrays of them.
+/// * The fields must add up to a whole number of bytes.
+///
+/// See `embassy_usb::class::hid_report` for the `#[hid(...)]` attributes.
+///
+/// ## Examples
+///
+/// A keyboard report:
+///
+/// ```rust,ignore
+/// #[derive(HidReport)]
+/// #[hid(usage_page = 0x01, usage = 0x06)] // Generic Desktop, Keyboard
+/// struct KeyboardReport {
+///     #[hid(usage_page = 0x07, usage_min = 0xE0, usage_max = 0xE7)] // Modifiers
+///     modifiers: u8,
+///     #[hid(padding)]
+///     reserved: u8,
+///     #[hid(usage_page = 0x07, usage_min = 0, usage_max = 0xFF, array)] // Keys
+///     keycodes: [u8; 6],
+/// }
+/// ```
+#[proc_macro_derive(HidReport, attributes(hid))]
+pub fn hid_report(item: TokenStream) -> TokenStream {
+    hid_report::run(item.into()).into()
+}
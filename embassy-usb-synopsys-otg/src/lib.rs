@@ -1134,14 +1134,21 @@ impl<'d, const MAX_EP_COUNT: usize> embassy_usb_driver::Bus for Bus<'d, MAX_EP_C
 
     async fn enable(&mut self) {
         trace!("enable");
-        // TODO: enable the peripheral once enable/disable semantics are cleared up in embassy-usb
+
+        // Reconnect after `disable`. The core is initialized, and connected, by the first `poll`.
+        if self.inited {
+            self.instance.regs.dctl().modify(|w| w.set_sdis(false));
+        }
     }
 
     async fn disable(&mut self) {
         trace!("disable");
 
+        // Soft-disconnect from the host.
         // TODO: disable the peripheral once enable/disable semantics are cleared up in embassy-usb
-        //Bus::disable(self);
+        if self.inited {
+            self.instance.regs.dctl().modify(|w| w.set_sdis(true));
+        }
     }
 
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
@@ -1,13 +1,15 @@
 use heapless::Vec;
 
-use crate::config::MAX_HANDLER_COUNT;
+use crate::config::{MAX_CONFIGURATION_COUNT, MAX_HANDLER_COUNT};
 use crate::descriptor::{
     BosWriter, DescriptorWriter, SynchronizationType, UsageType, rewrite_config_descriptor_for_high_speed,
 };
 use crate::driver::{Driver, Endpoint, EndpointAddress, EndpointInfo, EndpointType};
 use crate::msos::{DeviceLevelDescriptor, FunctionLevelDescriptor, MsOsDescriptorWriter};
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::{Handler, Interface, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START, UsbDevice};
+use crate::{
+    CONFIGURATION_VALUE, Configuration, Handler, Interface, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START, UsbDevice,
+};
 
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -160,10 +162,60 @@ impl<'a> Config<'a> {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+/// Options of an additional configuration, added with [`Builder::configuration`].
+///
+/// The first configuration of the device uses the options of [`Config`].
+pub struct ConfigurationOptions<'a> {
+    /// Configuration name string descriptor, shown by some hosts to let users choose a configuration.
+    ///
+    /// Default: (none)
+    pub name: Option<&'a str>,
+
+    /// Whether the device supports remotely waking up the host in this configuration.
+    ///
+    /// Default: `false`
+    pub supports_remote_wakeup: bool,
+
+    /// Whether the device has its own power source in this configuration.
+    ///
+    /// Default: `false`
+    ///
+    /// See also: `max_power`
+    pub self_powered: bool,
+
+    /// Maximum current drawn from the USB bus by the device in this configuration, in milliamps.
+    ///
+    /// Default: 100mA
+    /// Max: 500mA
+    pub max_power: u16,
+}
+
+impl<'a> ConfigurationOptions<'a> {
+    /// Create default configuration options.
+    pub const fn new() -> Self {
+        Self {
+            name: None,
+            supports_remote_wakeup: false,
+            self_powered: false,
+            max_power: 100,
+        }
+    }
+}
+
+impl<'a> Default for ConfigurationOptions<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// [`UsbDevice`] builder.
 pub struct Builder<'d, D: Driver<'d>> {
     config: Config<'d>,
-    handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    handlers: Vec<(u8, &'d mut dyn Handler), MAX_HANDLER_COUNT>,
+    configurations: Vec<Configuration<'d>, MAX_CONFIGURATION_COUNT>,
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
     control_buf: &'d mut [u8],
 
@@ -212,12 +264,26 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         let mut config_descriptor = DescriptorWriter::new(config_descriptor_buf);
         let mut bos_descriptor = BosWriter::new(DescriptorWriter::new(bos_descriptor_buf));
 
-        config_descriptor.configuration(&config);
+        let options = ConfigurationOptions {
+            name: None,
+            supports_remote_wakeup: config.supports_remote_wakeup,
+            self_powered: config.self_powered,
+            max_power: config.max_power,
+        };
+        config_descriptor.configuration(CONFIGURATION_VALUE, None, &options);
         bos_descriptor.bos();
 
+        let mut configurations = Vec::new();
+        let _ = configurations.push(Configuration {
+            name: None,
+            descriptor: 0..0,
+            interfaces: 0..0,
+        });
+
         Builder {
             driver,
             config,
+            configurations,
             interfaces: Vec::new(),
             handlers: Vec::new(),
             control_buf,
@@ -232,9 +298,9 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
 
     /// Creates the [`UsbDevice`] instance with the configuration in this builder.
     pub fn build(mut self) -> UsbDevice<'d, D> {
+        self.end_configuration();
         let msos_descriptor = self.msos_descriptor.build(&mut self.bos_descriptor);
 
-        self.config_descriptor.end_configuration();
         self.bos_descriptor.end_bos();
 
         if self.config.max_speed == UsbDeviceSpeed::High {
@@ -258,6 +324,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             self.driver,
             self.config,
             self.handlers,
+            self.configurations,
             config_descriptor,
             self.bos_descriptor.writer.into_buf(),
             msos_descriptor,
@@ -272,6 +339,65 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         self.control_buf.len()
     }
 
+    /// Add a configuration to the device, and return its `bConfigurationValue`.
+    ///
+    /// The device starts with a single configuration, described by [`Config`]. Functions and
+    /// handlers added after this call belong to the new configuration, until the next call. Each
+    /// configuration has its own interfaces, numbered from 0, and its own endpoints: the endpoints
+    /// of one configuration are not shared with the others.
+    ///
+    /// The host selects one of the configurations. The classes of the other configurations stay
+    /// disabled: their endpoints are not enabled and their handlers are not called.
+    ///
+    /// Panics if more than `max_configuration_count` configurations are added.
+    pub fn configuration(&mut self, options: ConfigurationOptions<'d>) -> u8 {
+        assert!(
+            options.max_power <= 500,
+            "The maximum allowed value for `max_power` is 500mA"
+        );
+
+        self.end_configuration();
+
+        let value = self.configurations.len() as u8 + 1;
+        let name = options.name.map(|name| (self.string(), name));
+        let position = self.config_descriptor.position();
+        let configuration = Configuration {
+            name,
+            descriptor: position..position,
+            interfaces: self.interfaces.len()..self.interfaces.len(),
+        };
+        assert!(
+            self.configurations.push(configuration).is_ok(),
+            "embassy-usb: configuration list full. Increase the `max_configuration_count` compile-time setting. Current value: {}",
+            MAX_CONFIGURATION_COUNT
+        );
+
+        self.config_descriptor
+            .configuration(value, name.map(|(index, _)| index), &options);
+
+        value
+    }
+
+    /// Ends the configuration being built.
+    fn end_configuration(&mut self) {
+        self.config_descriptor.end_configuration();
+        self.msos_descriptor.end_configuration();
+
+        let configuration = self.configurations.last_mut().unwrap();
+        configuration.descriptor.end = self.config_descriptor.position();
+        configuration.interfaces.end = self.interfaces.len();
+    }
+
+    /// Get the `bConfigurationValue` of the configuration being built.
+    pub fn configuration_value(&self) -> u8 {
+        self.configurations.len() as u8
+    }
+
+    /// Index of the first interface of the configuration being built in the interface list.
+    fn first_interface(&self) -> usize {
+        self.configurations.last().unwrap().interfaces.start
+    }
+
     /// Add an USB function.
     ///
     /// If [`Config::composite_with_iads`] is set, this will add an IAD descriptor
@@ -279,7 +405,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// If it's not set, no IAD descriptor is added.
     pub fn function(&mut self, class: u8, subclass: u8, protocol: u8) -> FunctionBuilder<'_, 'd, D> {
-        let first_interface = InterfaceNumber::new(self.interfaces.len() as u8);
+        let first_interface = InterfaceNumber::new((self.interfaces.len() - self.first_interface()) as u8);
         let iface_count_index = if self.config.composite_with_iads {
             self.config_descriptor
                 .iad(first_interface, 0, class, subclass, protocol);
@@ -301,9 +427,14 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// The Handler is called on some USB bus events, and to handle all control requests not already
     /// handled by the USB stack.
+    ///
+    /// The Handler belongs to the configuration being built: while the host has selected another
+    /// configuration, it is not called for control requests and alternate settings, and its
+    /// [`configured`](Handler::configured) callback is not called.
     pub fn handler(&mut self, handler: &'d mut dyn Handler) {
+        let configuration = self.configuration_value();
         assert!(
-            self.handlers.push(handler).is_ok(),
+            self.handlers.push((configuration, handler)).is_ok(),
             "embassy-usb: handler list full. Increase the `max_handler_count` compile-time setting. Current value: {}",
             MAX_HANDLER_COUNT
         );
@@ -356,13 +487,14 @@ impl<'a, 'd, D: Driver<'d>> Drop for FunctionBuilder<'a, 'd, D> {
 impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
     /// Add an interface to the function.
     ///
-    /// Interface numbers are guaranteed to be allocated consecutively, starting from 0.
+    /// Interface numbers are guaranteed to be allocated consecutively, starting from 0 in each
+    /// configuration.
     pub fn interface(&mut self) -> InterfaceBuilder<'_, 'd, D> {
         if let Some(i) = self.iface_count_index {
             self.builder.config_descriptor.buf[i] += 1;
         }
 
-        let number = self.builder.interfaces.len() as _;
+        let number = (self.builder.interfaces.len() - self.builder.first_interface()) as _;
         let iface = Interface {
             current_alt_setting: 0,
             num_alt_settings: 0,
@@ -384,7 +516,9 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
     /// Add an MS OS 2.0 Function Level Feature Descriptor.
     pub fn msos_feature<T: FunctionLevelDescriptor>(&mut self, desc: T) {
         if !self.builder.msos_descriptor.is_in_config_subset() {
-            self.builder.msos_descriptor.configuration(0);
+            // The configuration subset header holds the configuration index, not its value.
+            let index = self.builder.configuration_value() - 1;
+            self.builder.msos_descriptor.configuration(index);
         }
 
         if !self.builder.msos_descriptor.is_in_function_subset() {
@@ -427,7 +561,8 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
     ) -> InterfaceAltBuilder<'_, 'd, D> {
         let number = self.next_alt_setting_number;
         self.next_alt_setting_number += 1;
-        self.builder.interfaces[self.interface_number.0 as usize].num_alt_settings += 1;
+        let index = self.builder.first_interface() + self.interface_number.0 as usize;
+        self.builder.interfaces[index].num_alt_settings += 1;
 
         self.builder.config_descriptor.interface_alt(
             self.interface_number,
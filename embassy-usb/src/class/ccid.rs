@@ -0,0 +1,767 @@
+//! USB Chip/Smart Card Interface Devices (CCID) class implementation.
+//!
+//! Devices implementing this class appear as smart card readers with a single slot, handled by the
+//! standard CCID drivers of hosts and the PC/SC middleware above them, such as pcsc-lite and its
+//! `ccid` driver, or the `usbccid` driver of Windows.
+//!
+//! The reader exchanges APDUs with the host, without the T=0 and T=1 transport protocols of
+//! physical cards. Command APDUs are answered by a [`SmartCard`], such as a secure element behind
+//! the reader, which also gives the answer to reset (ATR) when the host powers the card on. Long
+//! operations of the card keep the host waiting with time extensions.
+//!
+//! The reader is run with [`CcidClass::run`]. Readers of removable cards notify the host of card
+//! insertions and removals: split the class with [`CcidClass::split`] and report them with the
+//! [`Notifier`] while the [`Responder`] runs.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_time::{Duration, Ticker};
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// Smart card interface class.
+const USB_CLASS_CCID: u8 = 0x0B;
+const CCID_SUBCLASS: u8 = 0x00;
+const CCID_PROTOCOL: u8 = 0x00;
+
+/// Smart card device class descriptor type [CCID 5.1].
+const CCID_DESCRIPTOR_TYPE: u8 = 0x21;
+
+// CCID class requests [CCID 5.3]
+const REQ_ABORT: u8 = 0x01;
+
+// Bulk-OUT messages [CCID 6.1]
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_SECURE: u8 = 0x69;
+const PC_TO_RDR_ESCAPE: u8 = 0x6B;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6C;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6D;
+const PC_TO_RDR_ICC_CLOCK: u8 = 0x6E;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const PC_TO_RDR_ABORT: u8 = 0x72;
+const PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x73;
+
+// Bulk-IN messages [CCID 6.2]
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+const RDR_TO_PC_ESCAPE: u8 = 0x83;
+const RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x84;
+
+// Interrupt-IN messages [CCID 6.3]
+const RDR_TO_PC_NOTIFY_SLOT_CHANGE: u8 = 0x50;
+
+/// Length of the header of messages.
+const HEADER_LEN: usize = 10;
+
+/// Longest short APDU: a header, Lc, 255 bytes of data and Le.
+const MAX_APDU_SIZE: usize = 261;
+
+/// Longest message, with a short APDU.
+const MAX_MESSAGE_SIZE: usize = HEADER_LEN + MAX_APDU_SIZE;
+
+/// Maximum supported packet size of the bulk endpoints.
+const MAX_PACKET_SIZE: usize = 512;
+
+// ICC status, in bits 0..1 of bStatus [CCID 6.2.6]
+const ICC_ACTIVE: u8 = 0;
+const ICC_INACTIVE: u8 = 1;
+const ICC_ABSENT: u8 = 2;
+
+// Command status, in bits 6..7 of bStatus
+const COMMAND_FAILED: u8 = 1 << 6;
+const COMMAND_TIME_EXTENSION: u8 = 2 << 6;
+
+// Slot errors, in bError. Errors of a field of the command are its offset.
+const ERR_CMD_NOT_SUPPORTED: u8 = 0x00;
+const ERR_BAD_LENGTH: u8 = 1;
+const ERR_BAD_SLOT: u8 = 5;
+const ERR_BAD_PROTOCOL: u8 = 7;
+const ERR_BAD_LEVEL_PARAMETER: u8 = 8;
+const ERR_HW_ERROR: u8 = 0xFB;
+const ERR_ICC_MUTE: u8 = 0xFE;
+const ERR_CMD_ABORTED: u8 = 0xFF;
+
+/// Interval of the time extensions sent while the card processes a command.
+const TIME_EXTENSION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// T=1 protocol data structure, for the parameters of the slot [CCID 6.2.3].
+const T1_PARAMETERS: [u8; 7] = [
+    0x11, // bmFindexDindex, default Fi and Di
+    0x10, // bmTCCKST1, LRC checksum
+    0x00, // bGuardTimeT1
+    0x4D, // bmWaitingIntegersT1, BWI 4 and CWI 13
+    0x00, // bClockStop, not allowed
+    0xFE, // bIFSC
+    0x00, // bNadValue
+];
+
+/// CCID error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The given buffer was too small for the received packet.
+    BufferOverflow,
+    /// The endpoint is disabled.
+    Disabled,
+}
+
+impl From<EndpointError> for Error {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => Error::BufferOverflow,
+            EndpointError::Disabled => Error::Disabled,
+        }
+    }
+}
+
+/// Failure of the card, reported to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CardError {
+    /// The card does not answer.
+    Mute,
+    /// The card, or its connection to the reader, failed.
+    Hardware,
+}
+
+impl CardError {
+    fn code(self) -> u8 {
+        match self {
+            CardError::Mute => ERR_ICC_MUTE,
+            CardError::Hardware => ERR_HW_ERROR,
+        }
+    }
+}
+
+/// Card in the slot of the reader, which answers the APDUs of the host.
+pub trait SmartCard {
+    /// Powers the card on, and returns its answer to reset (ATR), of at most 33 bytes.
+    ///
+    /// The ATR must announce the T=1 protocol, such as `3B 80 80 01 01`.
+    async fn power_on(&mut self) -> Result<&[u8], CardError>;
+
+    /// Powers the card off.
+    async fn power_off(&mut self) {}
+
+    /// Handles a command APDU, and writes the response APDU to `response`, ending with the status
+    /// word. Returns the length of the response.
+    ///
+    /// The response holds up to 256 bytes of data, with the status word. Longer data is sent
+    /// by the card in parts, with the `61xx` status word and GET RESPONSE commands.
+    ///
+    /// The future is dropped if the host aborts the command.
+    async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, CardError>;
+}
+
+/// Configuration of the [`CcidClass`].
+pub struct Config {
+    /// Max packet size of the bulk endpoints.
+    ///
+    /// Full-speed devices must use 8, 16, 32 or 64 bytes, high-speed ones 512 bytes.
+    pub max_packet_size: u16,
+
+    /// A card is in the slot when the device starts. Changes are reported with the [`Notifier`].
+    pub card_present: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 64,
+            card_present: true,
+        }
+    }
+}
+
+/// Internal state for CCID
+pub struct State<'a> {
+    control: Option<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: None,
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+struct Control<'a> {
+    interface: InterfaceNumber,
+    shared: &'a ControlShared,
+}
+
+/// Shared data between Control and the responder
+struct ControlShared {
+    card_present: AtomicBool,
+    /// The host aborted a command, with the ABORT request, not yet followed by its
+    /// PC_to_RDR_Abort message.
+    abort: AtomicBool,
+    /// Sequence number of the aborted command.
+    abort_seq: AtomicU8,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        ControlShared {
+            card_present: AtomicBool::new(true),
+            abort: AtomicBool::new(false),
+            abort_seq: AtomicU8::new(0),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.abort.store(false, Ordering::Relaxed);
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                u8::from(self.interface) as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            // The slot is in the low byte of wValue, the sequence number in the high byte.
+            REQ_ABORT if req.value as u8 == 0 => {
+                debug!("ccid: abort");
+                self.shared.abort_seq.store((req.value >> 8) as u8, Ordering::Relaxed);
+                self.shared.abort.store(true, Ordering::Relaxed);
+                self.shared.waker.borrow_mut().wake();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                u8::from(self.interface) as u16,
+            )
+        {
+            return None;
+        }
+
+        // GET_CLOCK_FREQUENCIES and GET_DATA_RATES are not supported, the reader has no list of
+        // clock frequencies nor data rates.
+        Some(InResponse::Rejected)
+    }
+}
+
+/// Implementation of a CCID smart card reader, with a single slot.
+pub struct CcidClass<'d, D: Driver<'d>> {
+    responder: Responder<'d, D>,
+    notifier: Notifier<'d, D>,
+}
+
+impl<'d, D: Driver<'d>> CcidClass<'d, D> {
+    /// Creates a new CcidClass with the provided configuration.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        assert!(config.max_packet_size as usize <= MAX_PACKET_SIZE);
+
+        let mut func = builder.function(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL);
+        let mut iface = func.interface();
+        let interface = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL, None);
+
+        // Smart card device class descriptor [CCID 5.1]
+        let mut desc = [0; 52];
+        desc[0..2].copy_from_slice(&0x0110u16.to_le_bytes()); // bcdCCID
+        desc[2] = 0; // bMaxSlotIndex
+        desc[3] = 0x07; // bVoltageSupport, 5V, 3V and 1.8V
+        desc[4..8].copy_from_slice(&0x0000_0002u32.to_le_bytes()); // dwProtocols, T=1
+        desc[8..12].copy_from_slice(&3580u32.to_le_bytes()); // dwDefaultClock, kHz
+        desc[12..16].copy_from_slice(&3580u32.to_le_bytes()); // dwMaximumClock
+        desc[16] = 0; // bNumClockSupported
+        desc[17..21].copy_from_slice(&9600u32.to_le_bytes()); // dwDataRate, bps
+        desc[21..25].copy_from_slice(&9600u32.to_le_bytes()); // dwMaxDataRate
+        desc[25] = 0; // bNumDataRatesSupported
+        desc[26..30].copy_from_slice(&254u32.to_le_bytes()); // dwMaxIFSD
+        desc[30..34].copy_from_slice(&0u32.to_le_bytes()); // dwSynchProtocols
+        desc[34..38].copy_from_slice(&0u32.to_le_bytes()); // dwMechanical
+        // dwFeatures: automatic parameters from the ATR, automatic parameter negotiation,
+        // automatic IFSD exchange, and short APDU level exchanges.
+        desc[38..42].copy_from_slice(&0x0002_0842u32.to_le_bytes());
+        desc[42..46].copy_from_slice(&(MAX_MESSAGE_SIZE as u32).to_le_bytes()); // dwMaxCCIDMessageLength
+        desc[46] = 0xFF; // bClassGetResponse, echoes the class of the APDU
+        desc[47] = 0xFF; // bClassEnvelope
+        desc[48..50].copy_from_slice(&0u16.to_le_bytes()); // wLcdLayout, no LCD
+        desc[50] = 0; // bPINSupport, no PIN pad
+        desc[51] = 1; // bMaxCCIDBusySlots
+        alt.descriptor(CCID_DESCRIPTOR_TYPE, &desc);
+
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, config.max_packet_size);
+        let notify_ep = alt.endpoint_interrupt_in(None, 8, 10);
+
+        drop(func);
+
+        state.shared.card_present.store(config.card_present, Ordering::Relaxed);
+        state.control = Some(Control {
+            interface,
+            shared: &state.shared,
+        });
+        builder.handler(state.control.as_mut().unwrap());
+
+        CcidClass {
+            responder: Responder {
+                read_ep,
+                write_ep,
+                shared: &state.shared,
+                powered: false,
+            },
+            notifier: Notifier {
+                ep: notify_ep,
+                shared: &state.shared,
+            },
+        }
+    }
+
+    /// Splits the class into the responder and the notifier, to run them concurrently.
+    pub fn split(self) -> (Responder<'d, D>, Notifier<'d, D>) {
+        (self.responder, self.notifier)
+    }
+
+    /// Answers the messages of the host with `card`. Never returns.
+    pub async fn run<C: SmartCard>(&mut self, card: &mut C) -> ! {
+        self.responder.run(card).await
+    }
+}
+
+/// Notifies the host of card insertions and removals.
+pub struct Notifier<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    shared: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Notifier<'d, D> {
+    /// Sets whether a card is in the slot, and notifies the host if it changed.
+    ///
+    /// The slot status is updated even if the notification cannot be sent, for example while
+    /// the device is not configured.
+    pub async fn set_card_present(&mut self, present: bool) -> Result<(), Error> {
+        if self.shared.card_present.load(Ordering::Relaxed) == present {
+            return Ok(());
+        }
+        self.shared.card_present.store(present, Ordering::Relaxed);
+        self.shared.waker.borrow_mut().wake();
+
+        // bmSlotICCState: the card is present, and its state changed.
+        self.ep
+            .write(&[RDR_TO_PC_NOTIFY_SLOT_CHANGE, present as u8 | 0x02])
+            .await?;
+        Ok(())
+    }
+}
+
+/// Returns the type of the response message of a command.
+fn response_type(message_type: u8) -> u8 {
+    match message_type {
+        PC_TO_RDR_ICC_POWER_ON | PC_TO_RDR_XFR_BLOCK | PC_TO_RDR_SECURE => RDR_TO_PC_DATA_BLOCK,
+        PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => RDR_TO_PC_PARAMETERS,
+        PC_TO_RDR_ESCAPE => RDR_TO_PC_ESCAPE,
+        PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY => RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY,
+        _ => RDR_TO_PC_SLOT_STATUS,
+    }
+}
+
+/// Header of a command message [CCID 6.1].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Command {
+    message_type: u8,
+    /// Length of the data, as sent by the host.
+    data_len: u32,
+    slot: u8,
+    seq: u8,
+    /// Bytes of the header specific to the message type.
+    params: [u8; 3],
+}
+
+impl Command {
+    /// Parses the header of a message of `len` bytes, received in `message`.
+    fn parse(message: &[u8], len: usize) -> Option<Self> {
+        if len < HEADER_LEN {
+            return None;
+        }
+        let header = message.get(..HEADER_LEN)?;
+        Some(Self {
+            message_type: header[0],
+            data_len: u32::from_le_bytes(header[1..5].try_into().unwrap()),
+            slot: header[5],
+            seq: header[6],
+            params: [header[7], header[8], header[9]],
+        })
+    }
+
+    /// Returns the data of the message, or `None` if it was not received whole or did not fit in
+    /// `message`.
+    fn data<'a>(&self, message: &'a [u8], len: usize) -> Option<&'a [u8]> {
+        // `len` is at least `HEADER_LEN`, checked by `parse`.
+        if len > message.len() || self.data_len as usize > len - HEADER_LEN {
+            return None;
+        }
+        message.get(HEADER_LEN..HEADER_LEN + self.data_len as usize)
+    }
+}
+
+/// Response to a command, sent after its data.
+struct Response {
+    message_type: u8,
+    command_status: u8,
+    error: u8,
+    /// Last byte of the header, specific to the message type.
+    specific: u8,
+    len: usize,
+}
+
+impl Response {
+    fn ok(message_type: u8, len: usize) -> Self {
+        Self {
+            message_type,
+            command_status: 0,
+            error: 0,
+            specific: 0,
+            len,
+        }
+    }
+
+    fn failed(message_type: u8, error: u8) -> Self {
+        Self {
+            message_type,
+            command_status: COMMAND_FAILED,
+            error,
+            specific: 0,
+            len: 0,
+        }
+    }
+}
+
+/// Answers the messages of the host, for the slot of the reader.
+pub struct Responder<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    /// The card is powered on.
+    powered: bool,
+}
+
+impl<'d, D: Driver<'d>> Responder<'d, D> {
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Answers the messages of the host with `card`. Never returns.
+    pub async fn run<C: SmartCard>(&mut self, card: &mut C) -> ! {
+        let mut message = [0; MAX_MESSAGE_SIZE];
+        let mut response = [0; MAX_MESSAGE_SIZE];
+        loop {
+            self.wait_connection().await;
+            loop {
+                match self.handle_message(card, &mut message, &mut response).await {
+                    Ok(()) => {}
+                    Err(Error::Disabled) => break,
+                    Err(Error::BufferOverflow) => warn!("ccid: buffer overflow"),
+                }
+            }
+            // The host powers the card on again once the device is configured.
+            if self.powered {
+                card.power_off().await;
+                self.powered = false;
+            }
+        }
+    }
+
+    fn icc_status(&self) -> u8 {
+        if !self.shared.card_present.load(Ordering::Relaxed) {
+            ICC_ABSENT
+        } else if self.powered {
+            ICC_ACTIVE
+        } else {
+            ICC_INACTIVE
+        }
+    }
+
+    /// Reads a message to `buf`. Returns its length, which is larger than `buf` if it did not fit.
+    async fn read_message(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let max_packet_size = self.max_packet_size() as usize;
+        let mut len = 0;
+        loop {
+            let n = self.read_ep.read(&mut packet[..max_packet_size]).await?;
+            if let Some(dst) = buf.get_mut(len..) {
+                let copied = n.min(dst.len());
+                dst[..copied].copy_from_slice(&packet[..copied]);
+            }
+            len = len.saturating_add(n);
+
+            // Messages end with a short packet, or once their data is received.
+            if n < max_packet_size {
+                return Ok(len);
+            }
+            if len >= HEADER_LEN {
+                let data_len = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+                if len >= HEADER_LEN.saturating_add(data_len) {
+                    return Ok(len);
+                }
+            }
+        }
+    }
+
+    /// Writes a response message to the command `seq`, with its data already in `buf`.
+    async fn write_message(&mut self, buf: &mut [u8], seq: u8, response: &Response) -> Result<(), Error> {
+        buf[0] = response.message_type;
+        buf[1..5].copy_from_slice(&(response.len as u32).to_le_bytes());
+        buf[5] = 0; // bSlot
+        buf[6] = seq;
+        buf[7] = self.icc_status() | response.command_status;
+        buf[8] = response.error;
+        buf[9] = response.specific;
+
+        let max_packet_size = self.max_packet_size() as usize;
+        let message = &buf[..HEADER_LEN + response.len];
+        for packet in message.chunks(max_packet_size) {
+            self.write_ep.write(packet).await?;
+        }
+        // Messages of a multiple of the packet size end with a zero-length packet.
+        if message.len().is_multiple_of(max_packet_size) {
+            self.write_ep.write(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles a message of the host, and sends its response.
+    async fn handle_message<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        message: &mut [u8; MAX_MESSAGE_SIZE],
+        response: &mut [u8; MAX_MESSAGE_SIZE],
+    ) -> Result<(), Error> {
+        let len = self.read_message(message).await?;
+        let Some(command) = Command::parse(message, len) else {
+            warn!("ccid: invalid message");
+            return Ok(());
+        };
+
+        let Command {
+            message_type,
+            slot,
+            seq,
+            params,
+            ..
+        } = command;
+        let response_type = response_type(message_type);
+        trace!("ccid: message {:02x}, seq {}", message_type, seq);
+
+        let result = if slot != 0 {
+            Response::failed(response_type, ERR_BAD_SLOT)
+        } else if message_type == PC_TO_RDR_ABORT {
+            // The abort is over, once both the ABORT request and this message are received.
+            self.shared.abort.store(false, Ordering::Relaxed);
+            Response::ok(response_type, 0)
+        } else if self.shared.abort.load(Ordering::Relaxed) {
+            Response::failed(response_type, ERR_CMD_ABORTED)
+        } else if let Some(data) = command.data(message, len) {
+            self.handle_command(card, message_type, seq, params, data, &mut response[HEADER_LEN..])
+                .await?
+        } else {
+            Response::failed(response_type, ERR_BAD_LENGTH)
+        };
+
+        self.write_message(response, seq, &result).await
+    }
+
+    async fn handle_command<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        message_type: u8,
+        seq: u8,
+        params: [u8; 3],
+        data: &[u8],
+        response: &mut [u8],
+    ) -> Result<Response, Error> {
+        let response_type = response_type(message_type);
+        let present = self.shared.card_present.load(Ordering::Relaxed);
+        if !present && self.powered {
+            card.power_off().await;
+            self.powered = false;
+        }
+
+        match message_type {
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ICC_CLOCK => Ok(Response::ok(response_type, 0)),
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if self.powered {
+                    card.power_off().await;
+                    self.powered = false;
+                }
+                Ok(Response::ok(response_type, 0))
+            }
+            _ if !present => Ok(Response::failed(response_type, ERR_ICC_MUTE)),
+            PC_TO_RDR_ICC_POWER_ON => {
+                // The voltage is selected automatically.
+                self.powered = false;
+                match card.power_on().await {
+                    Ok(atr) => {
+                        let len = atr.len().min(response.len());
+                        response[..len].copy_from_slice(&atr[..len]);
+                        self.powered = true;
+                        Ok(Response::ok(response_type, len))
+                    }
+                    Err(e) => Ok(Response::failed(response_type, e.code())),
+                }
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                // The parameters of the T=1 protocol are fixed.
+                if message_type == PC_TO_RDR_SET_PARAMETERS && params[0] != 1 {
+                    return Ok(Response::failed(response_type, ERR_BAD_PROTOCOL));
+                }
+                response[..T1_PARAMETERS.len()].copy_from_slice(&T1_PARAMETERS);
+                let mut result = Response::ok(response_type, T1_PARAMETERS.len());
+                result.specific = 1; // bProtocolNum, T=1
+                Ok(result)
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                let level = u16::from_le_bytes([params[1], params[2]]);
+                self.transmit(card, seq, level, data, response).await
+            }
+            _ => Ok(Response::failed(response_type, ERR_CMD_NOT_SUPPORTED)),
+        }
+    }
+
+    /// Exchanges an APDU with the card, sending time extensions while it processes it.
+    async fn transmit<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        seq: u8,
+        level: u16,
+        command: &[u8],
+        response: &mut [u8],
+    ) -> Result<Response, Error> {
+        if !self.powered {
+            return Ok(Response::failed(RDR_TO_PC_DATA_BLOCK, ERR_ICC_MUTE));
+        }
+        // Short APDUs are exchanged in a single message.
+        if level != 0 {
+            return Ok(Response::failed(RDR_TO_PC_DATA_BLOCK, ERR_BAD_LEVEL_PARAMETER));
+        }
+
+        let shared = self.shared;
+        let max_len = response.len();
+        let mut fut = pin!(card.transmit(command, response));
+        let mut ticker = Ticker::every(TIME_EXTENSION_INTERVAL);
+        loop {
+            let abort = poll_fn(|cx| {
+                if shared.abort.load(Ordering::Relaxed) && shared.abort_seq.load(Ordering::Relaxed) == seq {
+                    Poll::Ready(())
+                } else {
+                    shared.waker.borrow_mut().register(cx.waker());
+                    Poll::Pending
+                }
+            });
+
+            match select3(fut.as_mut(), ticker.next(), abort).await {
+                Either3::First(Ok(len)) => return Ok(Response::ok(RDR_TO_PC_DATA_BLOCK, len.min(max_len))),
+                Either3::First(Err(e)) => return Ok(Response::failed(RDR_TO_PC_DATA_BLOCK, e.code())),
+                Either3::Second(()) => {
+                    let time_extension = Response {
+                        message_type: RDR_TO_PC_DATA_BLOCK,
+                        command_status: COMMAND_TIME_EXTENSION,
+                        error: 1, // Multiplier of the block waiting time
+                        specific: 0,
+                        len: 0,
+                    };
+                    self.write_message(&mut [0; HEADER_LEN], seq, &time_extension).await?;
+                }
+                Either3::Third(()) => {
+                    debug!("ccid: command {} aborted", seq);
+                    return Ok(Response::failed(RDR_TO_PC_DATA_BLOCK, ERR_CMD_ABORTED));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data_len: u32, data: &[u8]) -> [u8; MAX_MESSAGE_SIZE] {
+        let mut message = [0; MAX_MESSAGE_SIZE];
+        message[0] = PC_TO_RDR_XFR_BLOCK;
+        message[1..5].copy_from_slice(&data_len.to_le_bytes());
+        message[6] = 7;
+        message[HEADER_LEN..HEADER_LEN + data.len()].copy_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn parse_command() {
+        let m = message(4, &[0x00, 0xA4, 0x04, 0x00]);
+        let command = Command::parse(&m, HEADER_LEN + 4).unwrap();
+        assert_eq!(command.message_type, PC_TO_RDR_XFR_BLOCK);
+        assert_eq!((command.slot, command.seq), (0, 7));
+        assert_eq!(command.data(&m, HEADER_LEN + 4), Some(&[0x00, 0xA4, 0x04, 0x00][..]));
+    }
+
+    #[test]
+    fn short_message() {
+        let m = message(0, &[]);
+        assert_eq!(Command::parse(&m, HEADER_LEN - 1), None);
+        assert_eq!(Command::parse(&m[..4], HEADER_LEN), None);
+    }
+
+    #[test]
+    fn malformed_length() {
+        // dwLength larger than the received data, up to overflowing `HEADER_LEN + dwLength`.
+        for data_len in [5, MAX_APDU_SIZE as u32 + 1, u32::MAX - HEADER_LEN as u32 + 1, u32::MAX] {
+            let m = message(data_len, &[0; 4]);
+            let command = Command::parse(&m, HEADER_LEN + 4).unwrap();
+            assert_eq!(command.data(&m, HEADER_LEN + 4), None);
+        }
+
+        // Messages longer than the buffer.
+        let m = message(MAX_APDU_SIZE as u32 + 1, &[]);
+        let command = Command::parse(&m, MAX_MESSAGE_SIZE + 1).unwrap();
+        assert_eq!(command.data(&m, MAX_MESSAGE_SIZE + 1), None);
+    }
+}
@@ -0,0 +1,850 @@
+//! FIDO CTAPHID transport, for security keys, over the HID class.
+//!
+//! Implements the HID transport of the Client to Authenticator Protocol (CTAP 2.1, section 11.2):
+//! channel allocation, the fragmentation and reassembly of messages, ping, timeouts, keepalive and
+//! cancellation. The application receives whole CTAP2 (CBOR) and, optionally, CTAP1 (U2F)
+//! messages with [`CtapHid::read_request`], and answers them with [`CtapHid::write_response`].
+//!
+//! While processing a request, for example waiting for the user presence, the application runs
+//! its processing in [`CtapHid::with_keepalive`], which keeps the host informed and handles
+//! cancellations.
+
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::pin;
+
+use embassy_futures::select::{Either3, select3};
+use embassy_time::{Duration, Instant, Ticker, with_deadline};
+
+use super::hid::{self, HidBootProtocol, HidReader, HidReaderWriter, HidSubclass, HidWriter, ReadError};
+use crate::Builder;
+use crate::driver::{Driver, EndpointError};
+
+/// HID report descriptor of FIDO authenticators, with 64-byte input and output reports.
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0xD0, 0xF1, // Usage Page (FIDO Alliance)
+    0x09, 0x01, // Usage (CTAPHID)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Input Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0x09, 0x21, //   Usage (Output Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x91, 0x02, //   Output (Data, Var, Abs)
+    0xC0, // End Collection
+];
+
+/// Size of the HID reports.
+const PACKET_SIZE: usize = 64;
+
+/// Data bytes of initialization packets.
+const INIT_DATA_LEN: usize = PACKET_SIZE - 7;
+
+/// Data bytes of continuation packets.
+const CONT_DATA_LEN: usize = PACKET_SIZE - 5;
+
+/// Maximum size of a message: an initialization packet and 128 continuation packets.
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA_LEN + 128 * CONT_DATA_LEN;
+
+/// Channel of the INIT requests allocating channels.
+const BROADCAST_CHANNEL: u32 = 0xFFFF_FFFF;
+
+/// Interval of the keepalive messages, while processing a request.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+// CTAPHID commands
+const CMD_PING: u8 = 0x01;
+const CMD_MSG: u8 = 0x03;
+const CMD_INIT: u8 = 0x06;
+const CMD_WINK: u8 = 0x08;
+const CMD_CBOR: u8 = 0x10;
+const CMD_CANCEL: u8 = 0x11;
+const CMD_KEEPALIVE: u8 = 0x3B;
+const CMD_ERROR: u8 = 0x3F;
+
+// CTAPHID error codes
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_MSG_TIMEOUT: u8 = 0x05;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0B;
+
+// Capabilities, reported in INIT responses
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+const CAPABILITY_NMSG: u8 = 0x08;
+
+/// Version of the CTAPHID protocol.
+const PROTOCOL_VERSION: u8 = 2;
+
+/// CTAP2 status of cancelled requests.
+const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2D;
+
+/// CTAPHID error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The endpoint is disabled.
+    Disabled,
+    /// The host cancelled the request, which was answered by the transport.
+    Cancelled,
+}
+
+impl From<EndpointError> for Error {
+    fn from(_: EndpointError) -> Self {
+        // Reports always fit the endpoints.
+        Error::Disabled
+    }
+}
+
+/// Configuration of the [`CtapHid`] transport.
+pub struct Config {
+    /// Major version of the device, reported to the host.
+    pub major_version: u8,
+    /// Minor version of the device, reported to the host.
+    pub minor_version: u8,
+    /// Build version of the device, reported to the host.
+    pub build_version: u8,
+
+    /// Support the wink request, reported as [`Command::Wink`].
+    pub wink: bool,
+
+    /// Support CTAP1 (U2F) messages, reported as [`Command::Msg`].
+    pub ctap1: bool,
+
+    /// Maximum time between the packets of a message, before it is abandoned.
+    pub message_timeout: Duration,
+
+    /// Interval at which the host polls the device for reports.
+    pub poll_ms: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            major_version: 1,
+            minor_version: 0,
+            build_version: 0,
+            wink: false,
+            ctap1: false,
+            message_timeout: Duration::from_millis(500),
+            poll_ms: 5,
+        }
+    }
+}
+
+/// Command of a request from the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// A CTAP2 message, encoded in CBOR.
+    Cbor,
+    /// A CTAP1 (U2F) message.
+    Msg,
+    /// The host asks the authenticator to make itself visible, for example by blinking a light.
+    /// It is answered with an empty response.
+    Wink,
+}
+
+impl Command {
+    fn code(self) -> u8 {
+        match self {
+            Command::Cbor => CMD_CBOR,
+            Command::Msg => CMD_MSG,
+            Command::Wink => CMD_WINK,
+        }
+    }
+}
+
+/// A request from the host, to be answered with [`CtapHid::write_response`].
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request<'a> {
+    /// The channel of the request, where the response is sent.
+    pub channel: u32,
+    /// The command.
+    pub command: Command,
+    /// The message.
+    pub data: &'a [u8],
+}
+
+/// Status reported by keepalive messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum KeepaliveStatus {
+    /// The authenticator is processing the request.
+    Processing = 1,
+    /// The authenticator is waiting for the user presence.
+    UpNeeded = 2,
+}
+
+/// Internal state for CTAPHID.
+pub struct State<'d> {
+    hid: hid::State<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        State { hid: hid::State::new() }
+    }
+}
+
+/// A message being received.
+struct Transaction {
+    channel: u32,
+    command: u8,
+    len: usize,
+    received: usize,
+    seq: u8,
+    deadline: Instant,
+}
+
+/// What to do after receiving a packet, or on a message timeout.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Action {
+    /// Nothing, wait for the next packet.
+    None,
+    /// Send an error to a channel.
+    Error(u32, u8),
+    /// Answer an INIT request sent on a channel.
+    Init(u32, [u8; 17]),
+    /// Echo a ping of `len` bytes, received in the message buffer.
+    Ping(u32, usize),
+    /// Pass a request of `len` bytes, received in the message buffer, to the application.
+    Request(u32, Command, usize),
+    /// Answer a cancelled request.
+    Cancel(u32),
+}
+
+/// Packet handling of the transport: channel allocation and message reassembly.
+struct Protocol {
+    config: Config,
+    /// Channel allocated by the next INIT request.
+    next_channel: u32,
+    transaction: Option<Transaction>,
+}
+
+impl Protocol {
+    fn new(config: Config) -> Self {
+        Self {
+            config,
+            next_channel: 1,
+            transaction: None,
+        }
+    }
+
+    /// Time at which the message being received is abandoned, if there is one.
+    fn deadline(&self) -> Option<Instant> {
+        self.transaction.as_ref().map(|t| t.deadline)
+    }
+
+    /// Abandon the message being received, after its deadline.
+    fn timeout(&mut self) -> Action {
+        match self.transaction.take() {
+            Some(t) => {
+                debug!("ctaphid: message timeout on channel {:x}", t.channel);
+                Action::Error(t.channel, ERR_MSG_TIMEOUT)
+            }
+            None => Action::None,
+        }
+    }
+
+    /// Handle a packet received at `now`, reassembling messages in `buf`.
+    fn packet(&mut self, packet: &[u8; PACKET_SIZE], buf: &mut [u8], now: Instant) -> Action {
+        let channel = u32::from_be_bytes(packet[..4].try_into().unwrap());
+        if packet[4] & 0x80 == 0 {
+            // Continuation packet
+            let Some(t) = self.transaction.as_mut() else {
+                return Action::None;
+            };
+            if t.channel != channel {
+                return Action::Error(channel, ERR_CHANNEL_BUSY);
+            }
+            if packet[4] != t.seq {
+                self.transaction = None;
+                return Action::Error(channel, ERR_INVALID_SEQ);
+            }
+            let n = CONT_DATA_LEN.min(t.len - t.received);
+            buf[t.received..t.received + n].copy_from_slice(&packet[5..5 + n]);
+            t.received += n;
+            t.seq += 1;
+            t.deadline = now + self.config.message_timeout;
+        } else {
+            // Initialization packet
+            let command = packet[4] & 0x7F;
+            let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+
+            if command == CMD_INIT {
+                // Resynchronizes the channel.
+                if self.transaction.as_ref().is_some_and(|t| t.channel == channel) {
+                    self.transaction = None;
+                }
+                return self.init(channel, packet, len);
+            }
+            if let Some(t) = &self.transaction {
+                if t.channel == channel {
+                    self.transaction = None;
+                    return Action::Error(channel, ERR_INVALID_SEQ);
+                }
+                return Action::Error(channel, ERR_CHANNEL_BUSY);
+            }
+            if !self.is_allocated(channel) {
+                return Action::Error(channel, ERR_INVALID_CHANNEL);
+            }
+            if command == CMD_CANCEL {
+                // There is nothing to cancel.
+                return Action::None;
+            }
+            if len > MAX_MESSAGE_SIZE || len > buf.len() {
+                return Action::Error(channel, ERR_INVALID_LEN);
+            }
+
+            let n = INIT_DATA_LEN.min(len);
+            buf[..n].copy_from_slice(&packet[7..7 + n]);
+            self.transaction = Some(Transaction {
+                channel,
+                command,
+                len,
+                received: n,
+                seq: 0,
+                deadline: now + self.config.message_timeout,
+            });
+        }
+
+        let Some(t) = self.transaction.take_if(|t| t.received == t.len) else {
+            return Action::None;
+        };
+        let command = match t.command {
+            CMD_PING => return Action::Ping(t.channel, t.len),
+            CMD_CBOR => Command::Cbor,
+            CMD_MSG if self.config.ctap1 => Command::Msg,
+            CMD_WINK if self.config.wink => Command::Wink,
+            _ => return Action::Error(t.channel, ERR_INVALID_CMD),
+        };
+        Action::Request(t.channel, command, t.len)
+    }
+
+    /// Handle a packet received while processing a request of `channel`.
+    ///
+    /// Returns whether the host abandoned the request, with the action.
+    fn busy_packet(&mut self, channel: u32, packet: &[u8; PACKET_SIZE]) -> (Action, bool) {
+        // Continuation packets can only belong to messages rejected as busy.
+        if packet[4] & 0x80 == 0 {
+            return (Action::None, false);
+        }
+        let from = u32::from_be_bytes(packet[..4].try_into().unwrap());
+        let command = packet[4] & 0x7F;
+        let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+
+        if command == CMD_INIT {
+            (self.init(from, packet, len), from == channel)
+        } else if from == channel && command == CMD_CANCEL {
+            debug!("ctaphid: request cancelled on channel {:x}", channel);
+            (Action::Cancel(channel), true)
+        } else {
+            (Action::Error(from, ERR_CHANNEL_BUSY), false)
+        }
+    }
+
+    fn is_allocated(&self, channel: u32) -> bool {
+        channel != 0 && channel != BROADCAST_CHANNEL && channel < self.next_channel
+    }
+
+    /// Answer an INIT request, allocating a channel if sent on the broadcast one.
+    fn init(&mut self, channel: u32, packet: &[u8; PACKET_SIZE], len: usize) -> Action {
+        // The request is a nonce.
+        if len != 8 {
+            return Action::Error(channel, ERR_INVALID_LEN);
+        }
+
+        let allocated = if channel == BROADCAST_CHANNEL {
+            let allocated = self.next_channel;
+            self.next_channel = if allocated + 1 == BROADCAST_CHANNEL {
+                1
+            } else {
+                allocated + 1
+            };
+            allocated
+        } else if self.is_allocated(channel) {
+            channel
+        } else {
+            return Action::Error(channel, ERR_INVALID_CHANNEL);
+        };
+        debug!("ctaphid: init channel {:x}", allocated);
+
+        let mut capabilities = CAPABILITY_CBOR;
+        if self.config.wink {
+            capabilities |= CAPABILITY_WINK;
+        }
+        if !self.config.ctap1 {
+            capabilities |= CAPABILITY_NMSG;
+        }
+
+        let mut response = [0; 17];
+        response[..8].copy_from_slice(&packet[7..15]); // nonce
+        response[8..12].copy_from_slice(&allocated.to_be_bytes()); // channel
+        response[12] = PROTOCOL_VERSION;
+        response[13] = self.config.major_version;
+        response[14] = self.config.minor_version;
+        response[15] = self.config.build_version;
+        response[16] = capabilities;
+        Action::Init(channel, response)
+    }
+}
+
+/// Split a message in an initialization packet and continuation packets.
+fn message_packets(channel: u32, command: u8, data: &[u8]) -> impl Iterator<Item = [u8; PACKET_SIZE]> + '_ {
+    assert!(data.len() <= MAX_MESSAGE_SIZE);
+
+    let mut init = [0; PACKET_SIZE];
+    init[..4].copy_from_slice(&channel.to_be_bytes());
+    init[4] = 0x80 | command;
+    init[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    let n = INIT_DATA_LEN.min(data.len());
+    init[7..7 + n].copy_from_slice(&data[..n]);
+
+    let continuations = data[n..].chunks(CONT_DATA_LEN).enumerate().map(move |(seq, chunk)| {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = seq as u8;
+        packet[5..5 + chunk.len()].copy_from_slice(chunk);
+        packet
+    });
+    core::iter::once(init).chain(continuations)
+}
+
+/// CTAPHID transport of a FIDO authenticator.
+pub struct CtapHid<'d, D: Driver<'d>> {
+    reader: HidReader<'d, D, PACKET_SIZE>,
+    writer: HidWriter<'d, D, PACKET_SIZE>,
+    protocol: Protocol,
+}
+
+impl<'d, D: Driver<'d>> CtapHid<'d, D> {
+    /// Creates a new CtapHid, with a HID interface.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        let hid_config = hid::Config {
+            report_descriptor: REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: config.poll_ms,
+            max_packet_size: PACKET_SIZE as u16,
+            hid_subclass: HidSubclass::No,
+            hid_boot_protocol: HidBootProtocol::None,
+        };
+        let (reader, writer) = HidReaderWriter::new(builder, &mut state.hid, hid_config).split();
+
+        CtapHid {
+            reader,
+            writer,
+            protocol: Protocol::new(config),
+        }
+    }
+
+    /// Waits for the USB host to enable this interface.
+    pub async fn ready(&mut self) {
+        self.reader.ready().await;
+    }
+
+    /// Reads the next request from the host, into `buf`.
+    ///
+    /// Pings and channel allocations are answered while waiting. Messages larger than `buf`, which
+    /// should be [`MAX_MESSAGE_SIZE`] bytes long, are rejected.
+    pub async fn read_request<'a>(&mut self, buf: &'a mut [u8]) -> Result<Request<'a>, Error> {
+        let mut packet = [0; PACKET_SIZE];
+
+        loop {
+            let result = match self.protocol.deadline() {
+                Some(deadline) => match with_deadline(deadline, self.reader.read(&mut packet)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        let action = self.protocol.timeout();
+                        self.perform(action, buf).await?;
+                        continue;
+                    }
+                },
+                None => self.reader.read(&mut packet).await,
+            };
+            match result {
+                Ok(_) => {}
+                Err(ReadError::Disabled) => return Err(Error::Disabled),
+                // Incomplete reports are dropped.
+                Err(_) => continue,
+            }
+
+            match self.protocol.packet(&packet, buf, Instant::now()) {
+                Action::Request(channel, command, len) => {
+                    return Ok(Request {
+                        channel,
+                        command,
+                        data: &buf[..len],
+                    });
+                }
+                action => self.perform(action, buf).await?,
+            }
+        }
+    }
+
+    /// Runs `fut`, processing a request of `channel`, while sending keepalive messages with
+    /// `status`.
+    ///
+    /// Other channels are told that the authenticator is busy. If the host cancels the request,
+    /// `fut` is dropped, the request is answered and [`Error::Cancelled`] is returned.
+    pub async fn with_keepalive<F: Future>(
+        &mut self,
+        channel: u32,
+        status: &Cell<KeepaliveStatus>,
+        fut: F,
+    ) -> Result<F::Output, Error> {
+        let mut fut = pin!(fut);
+        let mut ticker = Ticker::every(KEEPALIVE_INTERVAL);
+        let mut packet = [0; PACKET_SIZE];
+
+        loop {
+            match select3(fut.as_mut(), ticker.next(), self.reader.read(&mut packet)).await {
+                Either3::First(output) => return Ok(output),
+                Either3::Second(()) => {
+                    self.write_message(channel, CMD_KEEPALIVE, &[status.get() as u8])
+                        .await?;
+                }
+                Either3::Third(Err(ReadError::Disabled)) => return Err(Error::Disabled),
+                Either3::Third(Err(_)) => {}
+                Either3::Third(Ok(_)) => {
+                    let (action, abandoned) = self.protocol.busy_packet(channel, &packet);
+                    self.perform(action, &[]).await?;
+                    if abandoned {
+                        return Err(Error::Cancelled);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the response to a request of the host.
+    pub async fn write_response(&mut self, channel: u32, command: Command, data: &[u8]) -> Result<(), Error> {
+        self.write_message(channel, command.code(), data).await
+    }
+
+    /// Send the answer of an action, with the message received in `buf`.
+    async fn perform(&mut self, action: Action, buf: &[u8]) -> Result<(), Error> {
+        match action {
+            Action::None => Ok(()),
+            Action::Error(channel, error) => self.write_message(channel, CMD_ERROR, &[error]).await,
+            Action::Init(channel, response) => self.write_message(channel, CMD_INIT, &response).await,
+            Action::Ping(channel, len) => self.write_message(channel, CMD_PING, &buf[..len]).await,
+            Action::Request(..) => unreachable!(),
+            Action::Cancel(channel) => {
+                self.write_message(channel, CMD_CBOR, &[CTAP2_ERR_KEEPALIVE_CANCEL])
+                    .await
+            }
+        }
+    }
+
+    /// Write a message, split in an initialization packet and continuation packets.
+    async fn write_message(&mut self, channel: u32, command: u8, data: &[u8]) -> Result<(), Error> {
+        for packet in message_packets(channel, command, data) {
+            self.writer.write(&packet).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const T0: Instant = Instant::from_millis(1000);
+
+    fn init_packet(channel: u32, command: u8, len: u16, data: &[u8]) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = 0x80 | command;
+        packet[5..7].copy_from_slice(&len.to_be_bytes());
+        packet[7..7 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn cont_packet(channel: u32, seq: u8) -> [u8; PACKET_SIZE] {
+        let mut packet = [0xAA; PACKET_SIZE];
+        packet[..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = seq;
+        packet
+    }
+
+    /// Protocol with channels 1 and 2 allocated.
+    fn allocated(config: Config) -> Protocol {
+        let mut protocol = Protocol::new(config);
+        for _ in 0..2 {
+            let init = init_packet(BROADCAST_CHANNEL, CMD_INIT, 8, &NONCE);
+            assert!(matches!(protocol.packet(&init, &mut [], T0), Action::Init(..)));
+        }
+        protocol
+    }
+
+    #[test]
+    fn init() {
+        let mut protocol = Protocol::new(Config::default());
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+
+        let broadcast = init_packet(BROADCAST_CHANNEL, CMD_INIT, 8, &NONCE);
+        let Action::Init(BROADCAST_CHANNEL, response) = protocol.packet(&broadcast, &mut buf, T0) else {
+            panic!()
+        };
+        assert_eq!(response[..8], NONCE);
+        assert_eq!(
+            response[8..],
+            [0, 0, 0, 1, 2, 1, 0, 0, CAPABILITY_CBOR | CAPABILITY_NMSG]
+        );
+        let Action::Init(_, response) = protocol.packet(&broadcast, &mut buf, T0) else {
+            panic!()
+        };
+        assert_eq!(response[8..12], [0, 0, 0, 2]);
+
+        // Allocated channels can be initialized again, the others are invalid.
+        let Action::Init(1, response) = protocol.packet(&init_packet(1, CMD_INIT, 8, &NONCE), &mut buf, T0) else {
+            panic!()
+        };
+        assert_eq!(response[8..12], [0, 0, 0, 1]);
+        let init = init_packet(3, CMD_INIT, 8, &NONCE);
+        assert_eq!(
+            protocol.packet(&init, &mut buf, T0),
+            Action::Error(3, ERR_INVALID_CHANNEL)
+        );
+        let init = init_packet(0, CMD_INIT, 8, &NONCE);
+        assert_eq!(
+            protocol.packet(&init, &mut buf, T0),
+            Action::Error(0, ERR_INVALID_CHANNEL)
+        );
+        let ping = init_packet(3, CMD_PING, 1, &[0]);
+        assert_eq!(
+            protocol.packet(&ping, &mut buf, T0),
+            Action::Error(3, ERR_INVALID_CHANNEL)
+        );
+
+        let init = init_packet(BROADCAST_CHANNEL, CMD_INIT, 7, &NONCE);
+        assert_eq!(
+            protocol.packet(&init, &mut buf, T0),
+            Action::Error(BROADCAST_CHANNEL, ERR_INVALID_LEN)
+        );
+    }
+
+    #[test]
+    fn init_capabilities() {
+        let mut protocol = Protocol::new(Config {
+            major_version: 3,
+            minor_version: 4,
+            build_version: 5,
+            wink: true,
+            ctap1: true,
+            ..Config::default()
+        });
+        let init = init_packet(BROADCAST_CHANNEL, CMD_INIT, 8, &NONCE);
+        let Action::Init(_, response) = protocol.packet(&init, &mut [], T0) else {
+            panic!()
+        };
+        assert_eq!(response[12..], [2, 3, 4, 5, CAPABILITY_CBOR | CAPABILITY_WINK]);
+    }
+
+    #[test]
+    fn commands() {
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let mut protocol = allocated(Config::default());
+        let mut packet = |protocol: &mut Protocol, command, len| {
+            protocol.packet(&init_packet(1, command, len, &[0x42; 16]), &mut buf, T0)
+        };
+
+        assert_eq!(packet(&mut protocol, CMD_PING, 16), Action::Ping(1, 16));
+        assert_eq!(
+            packet(&mut protocol, CMD_CBOR, 16),
+            Action::Request(1, Command::Cbor, 16)
+        );
+        assert_eq!(packet(&mut protocol, CMD_CBOR, 0), Action::Request(1, Command::Cbor, 0));
+        assert_eq!(packet(&mut protocol, CMD_MSG, 16), Action::Error(1, ERR_INVALID_CMD));
+        assert_eq!(packet(&mut protocol, CMD_WINK, 0), Action::Error(1, ERR_INVALID_CMD));
+        assert_eq!(packet(&mut protocol, 0x7E, 0), Action::Error(1, ERR_INVALID_CMD));
+        // There is nothing to cancel.
+        assert_eq!(packet(&mut protocol, CMD_CANCEL, 0), Action::None);
+
+        let mut protocol = allocated(Config {
+            wink: true,
+            ctap1: true,
+            ..Config::default()
+        });
+        assert_eq!(packet(&mut protocol, CMD_MSG, 16), Action::Request(1, Command::Msg, 16));
+        assert_eq!(packet(&mut protocol, CMD_WINK, 0), Action::Request(1, Command::Wink, 0));
+        assert_eq!(buf[..16], [0x42; 16]);
+    }
+
+    #[test]
+    fn max_message() {
+        let data: [u8; MAX_MESSAGE_SIZE] = core::array::from_fn(|i| (i * 7 % 251) as u8);
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let mut protocol = allocated(Config::default());
+
+        let mut packets = message_packets(2, CMD_CBOR, &data);
+        let init = packets.next().unwrap();
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::None);
+        for seq in 0..127 {
+            let packet = packets.next().unwrap();
+            assert_eq!(packet[4], seq);
+            assert_eq!(protocol.packet(&packet, &mut buf, T0), Action::None);
+        }
+        let last = packets.next().unwrap();
+        assert_eq!(last[4], 127);
+        assert!(packets.next().is_none());
+        assert_eq!(
+            protocol.packet(&last, &mut buf, T0),
+            Action::Request(2, Command::Cbor, MAX_MESSAGE_SIZE)
+        );
+        assert_eq!(buf, data);
+
+        // Larger messages, or larger than the buffer, are rejected.
+        let init = init_packet(2, CMD_CBOR, MAX_MESSAGE_SIZE as u16 + 1, &[]);
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::Error(2, ERR_INVALID_LEN));
+        let init = init_packet(2, CMD_CBOR, 101, &[]);
+        assert_eq!(
+            protocol.packet(&init, &mut buf[..100], T0),
+            Action::Error(2, ERR_INVALID_LEN)
+        );
+        assert!(protocol.deadline().is_none());
+    }
+
+    #[test]
+    fn message_packets_padding() {
+        let packets: [[u8; PACKET_SIZE]; 2] = {
+            let mut it = message_packets(0x0102_0304, CMD_PING, &[0x42; INIT_DATA_LEN + 2]);
+            [it.next().unwrap(), it.next().unwrap()]
+        };
+        assert_eq!(
+            packets[0][..7],
+            [1, 2, 3, 4, 0x80 | CMD_PING, 0, INIT_DATA_LEN as u8 + 2]
+        );
+        assert_eq!(packets[0][7..], [0x42; INIT_DATA_LEN]);
+        assert_eq!(packets[1][..7], [1, 2, 3, 4, 0, 0x42, 0x42]);
+        assert_eq!(packets[1][7..], [0; PACKET_SIZE - 7]);
+
+        let mut it = message_packets(1, CMD_ERROR, &[ERR_INVALID_SEQ]);
+        let packet = it.next().unwrap();
+        assert_eq!(packet[..8], [0, 0, 0, 1, 0x80 | CMD_ERROR, 0, 1, ERR_INVALID_SEQ]);
+        assert_eq!(packet[8..], [0; PACKET_SIZE - 8]);
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn invalid_seq() {
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let mut protocol = allocated(Config::default());
+
+        let init = init_packet(1, CMD_CBOR, 200, &[]);
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::None);
+        assert_eq!(protocol.packet(&cont_packet(1, 0), &mut buf, T0), Action::None);
+        assert_eq!(
+            protocol.packet(&cont_packet(1, 2), &mut buf, T0),
+            Action::Error(1, ERR_INVALID_SEQ)
+        );
+        // The message is abandoned.
+        assert!(protocol.deadline().is_none());
+        assert_eq!(protocol.packet(&cont_packet(1, 1), &mut buf, T0), Action::None);
+    }
+
+    #[test]
+    fn interleaved_channels() {
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let mut protocol = allocated(Config::default());
+
+        let init = init_packet(1, CMD_CBOR, 100, &[0x11; INIT_DATA_LEN]);
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::None);
+
+        // Other channels are busy, until the message is complete.
+        let other = init_packet(2, CMD_PING, 1, &[0]);
+        assert_eq!(
+            protocol.packet(&other, &mut buf, T0),
+            Action::Error(2, ERR_CHANNEL_BUSY)
+        );
+        assert_eq!(
+            protocol.packet(&cont_packet(2, 0), &mut buf, T0),
+            Action::Error(2, ERR_CHANNEL_BUSY)
+        );
+        assert_eq!(
+            protocol.packet(&cont_packet(1, 0), &mut buf, T0),
+            Action::Request(1, Command::Cbor, 100)
+        );
+        assert_eq!(buf[..INIT_DATA_LEN], [0x11; INIT_DATA_LEN]);
+        assert_eq!(buf[INIT_DATA_LEN..100], [0xAA; 100 - INIT_DATA_LEN]);
+        assert_eq!(protocol.packet(&other, &mut buf, T0), Action::Ping(2, 1));
+
+        // A new message on the same channel is an invalid sequence, and abandons both.
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::None);
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::Error(1, ERR_INVALID_SEQ));
+        assert!(protocol.deadline().is_none());
+
+        // INIT resynchronizes the channel.
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::None);
+        let resync = init_packet(1, CMD_INIT, 8, &NONCE);
+        assert!(matches!(protocol.packet(&resync, &mut buf, T0), Action::Init(1, _)));
+        assert!(protocol.deadline().is_none());
+    }
+
+    #[test]
+    fn message_timeout() {
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let mut protocol = allocated(Config::default());
+        let timeout = Config::default().message_timeout;
+
+        let init = init_packet(1, CMD_CBOR, 200, &[]);
+        assert_eq!(protocol.packet(&init, &mut buf, T0), Action::None);
+        assert!(protocol.deadline() == Some(T0 + timeout));
+        let t1 = T0 + Duration::from_millis(300);
+        assert_eq!(protocol.packet(&cont_packet(1, 0), &mut buf, t1), Action::None);
+        assert!(protocol.deadline() == Some(t1 + timeout));
+
+        assert_eq!(protocol.timeout(), Action::Error(1, ERR_MSG_TIMEOUT));
+        assert!(protocol.deadline().is_none());
+        assert_eq!(protocol.timeout(), Action::None);
+        assert_eq!(protocol.packet(&cont_packet(1, 1), &mut buf, t1), Action::None);
+    }
+
+    #[test]
+    fn keepalive() {
+        let mut protocol = allocated(Config::default());
+
+        let cancel = init_packet(1, CMD_CANCEL, 0, &[]);
+        assert_eq!(protocol.busy_packet(1, &cancel), (Action::Cancel(1), true));
+        assert_eq!(
+            protocol.busy_packet(2, &cancel),
+            (Action::Error(1, ERR_CHANNEL_BUSY), false)
+        );
+        let request = init_packet(2, CMD_CBOR, 1, &[0]);
+        assert_eq!(
+            protocol.busy_packet(1, &request),
+            (Action::Error(2, ERR_CHANNEL_BUSY), false)
+        );
+        assert_eq!(protocol.busy_packet(1, &cont_packet(2, 0)), (Action::None, false));
+
+        // Channels can be allocated, but initializing the channel of the request abandons it.
+        let broadcast = init_packet(BROADCAST_CHANNEL, CMD_INIT, 8, &NONCE);
+        let (Action::Init(BROADCAST_CHANNEL, response), false) = protocol.busy_packet(1, &broadcast) else {
+            panic!()
+        };
+        assert_eq!(response[8..12], [0, 0, 0, 3]);
+        let init = init_packet(1, CMD_INIT, 8, &NONCE);
+        assert!(matches!(protocol.busy_packet(1, &init), (Action::Init(1, _), true)));
+    }
+}
@@ -7,6 +7,7 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(feature = "usbd-hid")]
 use usbd_hid::descriptor::AsInputReport;
 
+use super::hid_report::HidReport;
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
@@ -243,6 +244,11 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
         self.writer.write_serialize(r).await
     }
 
+    /// Writes an input report, serialized with [`HidReport`].
+    pub async fn write_report<R: HidReport>(&mut self, report: &R) -> Result<(), EndpointError> {
+        self.writer.write_report(report).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         self.writer.write(report).await
@@ -331,6 +337,15 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
         self.write(&buf[0..size]).await
     }
 
+    /// Writes an input report, serialized with [`HidReport`].
+    pub async fn write_report<R: HidReport>(&mut self, report: &R) -> Result<(), EndpointError> {
+        let mut buf: [u8; N] = [0; N];
+        let Ok(size) = report.serialize(&mut buf) else {
+            return Err(EndpointError::BufferOverflow);
+        };
+        self.write(&buf[0..size]).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         assert!(report.len() <= N);
@@ -0,0 +1,329 @@
+//! HID report descriptors and reports, without `usbd-hid`.
+//!
+//! [`ReportDescriptorBuilder`] writes report descriptors item by item, in `const` contexts. With
+//! the `hid-derive` feature, `#[derive(HidReport)]` generates the report descriptor of a struct,
+//! and the (de)serialization of its reports, with the [`HidReport`] trait.
+//!
+//! # Deriving reports
+//!
+//! The struct attribute sets the usage of the top-level collection, and optionally the report ID
+//! and the kind of the report: `input` (the default), `output` or `feature`. Each field is one
+//! main item, whose usages are set by its attribute:
+//!
+//! - `usage = U`: a single value, of an integer or `bool` field.
+//! - `usage_min = A, usage_max = B` on an integer field: a bitmap of buttons, one bit per usage,
+//!   the least significant bit being `A`.
+//! - `usages = [U, V, ..]` or `usage_min = A, usage_max = B` on an array field: one value per usage.
+//! - `array` with `usage_min = A, usage_max = B`: an array item, whose elements contain the usages
+//!   that are active, such as the keys pressed on a keyboard.
+//! - `padding`: constant bits.
+//!
+//! Fields can also set their `usage_page` (defaults to the one of the struct), their size in
+//! `bits` (defaults to the size of their type), their `logical_min` and `logical_max` (default to
+//! the range of the size) and whether they are `relative`. Fields are packed in the report without
+//! alignment, which must be a whole number of bytes.
+//!
+//! ```ignore
+//! use embassy_usb::class::hid_report::HidReport;
+//!
+//! #[derive(HidReport)]
+//! #[hid(usage_page = 0x01, usage = 0x02)] // Generic Desktop, Mouse
+//! struct MouseReport {
+//!     #[hid(usage_page = 0x09, usage_min = 1, usage_max = 3, bits = 3)] // Buttons 1 to 3
+//!     buttons: u8,
+//!     #[hid(padding, bits = 5)]
+//!     _padding: u8,
+//!     #[hid(usages = [0x30, 0x31], relative)] // X and Y
+//!     xy: [i8; 2],
+//!     #[hid(usage = 0x38, relative)] // Wheel
+//!     wheel: i8,
+//! }
+//!
+//! let config = hid::Config {
+//!     report_descriptor: MouseReport::DESCRIPTOR,
+//!     ..
+//! };
+//! ```
+
+#[cfg(feature = "hid-derive")]
+pub use embassy_usb_macros::HidReport;
+
+/// Flags of Input, Output and Feature items.
+pub mod flags {
+    /// The field carries no data, such as padding. When clear, the field is data.
+    pub const CONSTANT: u8 = 1 << 0;
+    /// Each element is the value of a usage. When clear, the elements contain the active usages.
+    pub const VARIABLE: u8 = 1 << 1;
+    /// Values are relative, such as mouse movements. When clear, values are absolute.
+    pub const RELATIVE: u8 = 1 << 2;
+    /// Values wrap around at the logical extents.
+    pub const WRAP: u8 = 1 << 3;
+    /// Values outside of the logical extents mean that the control is not engaged.
+    pub const NULL_STATE: u8 = 1 << 6;
+}
+
+/// Kind of a collection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Collection {
+    /// Group of axes, such as a pointer.
+    Physical = 0,
+    /// Top-level collection, of a device.
+    Application = 1,
+    /// Group of related fields.
+    Logical = 2,
+}
+
+// Item prefixes, with the type and tag, without the size.
+const MAIN_INPUT: u8 = 0x80;
+const MAIN_OUTPUT: u8 = 0x90;
+const MAIN_COLLECTION: u8 = 0xA0;
+const MAIN_FEATURE: u8 = 0xB0;
+const MAIN_END_COLLECTION: u8 = 0xC0;
+const GLOBAL_USAGE_PAGE: u8 = 0x04;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x14;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x24;
+const GLOBAL_REPORT_SIZE: u8 = 0x74;
+const GLOBAL_REPORT_ID: u8 = 0x84;
+const GLOBAL_REPORT_COUNT: u8 = 0x94;
+const LOCAL_USAGE: u8 = 0x08;
+const LOCAL_USAGE_MINIMUM: u8 = 0x18;
+const LOCAL_USAGE_MAXIMUM: u8 = 0x28;
+
+/// Builder of HID report descriptors, of up to `N` bytes.
+///
+/// Items are encoded with the smallest size holding their data, so that for example logical
+/// extents up to 127 take one byte, and 255 two bytes.
+///
+/// ```
+/// use embassy_usb::class::hid_report::{Collection, ReportDescriptorBuilder, flags};
+///
+/// const BUILDER: ReportDescriptorBuilder<32> = ReportDescriptorBuilder::new()
+///     .usage_page(0x0C) // Consumer
+///     .usage(0x01) // Consumer Control
+///     .collection(Collection::Application)
+///     .logical_minimum(0)
+///     .logical_maximum(0x3FF)
+///     .usage_minimum(0)
+///     .usage_maximum(0x3FF)
+///     .report_size(16)
+///     .report_count(1)
+///     .input(0)
+///     .end_collection();
+/// const DESCRIPTOR: &[u8] = BUILDER.as_slice();
+/// assert_eq!(DESCRIPTOR.len(), 23);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReportDescriptorBuilder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for ReportDescriptorBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReportDescriptorBuilder<N> {
+    /// Creates an empty descriptor.
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0 }
+    }
+
+    /// Adds a Usage Page item.
+    pub const fn usage_page(self, page: u16) -> Self {
+        self.unsigned(GLOBAL_USAGE_PAGE, page as u32)
+    }
+
+    /// Adds a Usage item.
+    pub const fn usage(self, usage: u16) -> Self {
+        self.unsigned(LOCAL_USAGE, usage as u32)
+    }
+
+    /// Adds a Usage Minimum item.
+    pub const fn usage_minimum(self, usage: u16) -> Self {
+        self.unsigned(LOCAL_USAGE_MINIMUM, usage as u32)
+    }
+
+    /// Adds a Usage Maximum item.
+    pub const fn usage_maximum(self, usage: u16) -> Self {
+        self.unsigned(LOCAL_USAGE_MAXIMUM, usage as u32)
+    }
+
+    /// Adds a Logical Minimum item.
+    pub const fn logical_minimum(self, value: i32) -> Self {
+        self.signed(GLOBAL_LOGICAL_MINIMUM, value)
+    }
+
+    /// Adds a Logical Maximum item.
+    pub const fn logical_maximum(self, value: i32) -> Self {
+        self.signed(GLOBAL_LOGICAL_MAXIMUM, value)
+    }
+
+    /// Adds a Report Size item, the number of bits of each element of the next main items.
+    pub const fn report_size(self, bits: u8) -> Self {
+        self.unsigned(GLOBAL_REPORT_SIZE, bits as u32)
+    }
+
+    /// Adds a Report Count item, the number of elements of the next main items.
+    pub const fn report_count(self, count: u16) -> Self {
+        self.unsigned(GLOBAL_REPORT_COUNT, count as u32)
+    }
+
+    /// Adds a Report ID item. The reports then start with their ID.
+    pub const fn report_id(self, id: u8) -> Self {
+        core::assert!(id != 0, "report ID 0 is reserved");
+        self.unsigned(GLOBAL_REPORT_ID, id as u32)
+    }
+
+    /// Adds a Collection item, which must be closed by [`end_collection`](Self::end_collection).
+    pub const fn collection(self, collection: Collection) -> Self {
+        self.unsigned(MAIN_COLLECTION, collection as u32)
+    }
+
+    /// Adds an End Collection item.
+    pub const fn end_collection(self) -> Self {
+        self.item(MAIN_END_COLLECTION, 0, 0)
+    }
+
+    /// Adds an Input item, with [`flags`].
+    pub const fn input(self, flags: u8) -> Self {
+        self.unsigned(MAIN_INPUT, flags as u32)
+    }
+
+    /// Adds an Output item, with [`flags`].
+    pub const fn output(self, flags: u8) -> Self {
+        self.unsigned(MAIN_OUTPUT, flags as u32)
+    }
+
+    /// Adds a Feature item, with [`flags`].
+    pub const fn feature(self, flags: u8) -> Self {
+        self.unsigned(MAIN_FEATURE, flags as u32)
+    }
+
+    /// Returns the length of the descriptor.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the descriptor has no items.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the descriptor.
+    pub const fn as_slice(&self) -> &[u8] {
+        self.buf.split_at(self.len).0
+    }
+
+    /// Returns the descriptor as an array, of exactly its length.
+    pub const fn to_array<const M: usize>(&self) -> [u8; M] {
+        core::assert!(M == self.len, "array length must be the length of the descriptor");
+        let mut array = [0; M];
+        let mut i = 0;
+        while i < M {
+            array[i] = self.buf[i];
+            i += 1;
+        }
+        array
+    }
+
+    const fn unsigned(self, prefix: u8, data: u32) -> Self {
+        let size = if data <= 0xFF {
+            1
+        } else if data <= 0xFFFF {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, data, size)
+    }
+
+    const fn signed(self, prefix: u8, data: i32) -> Self {
+        let size = if data >= i8::MIN as i32 && data <= i8::MAX as i32 {
+            1
+        } else if data >= i16::MIN as i32 && data <= i16::MAX as i32 {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, data as u32, size)
+    }
+
+    const fn item(mut self, prefix: u8, data: u32, size: usize) -> Self {
+        core::assert!(self.len + 1 + size <= N, "report descriptor buffer too small");
+        // Sizes of 0, 1, 2 and 4 bytes are encoded as 0, 1, 2 and 3.
+        self.buf[self.len] = prefix | if size == 4 { 3 } else { size as u8 };
+        let bytes = data.to_le_bytes();
+        let mut i = 0;
+        while i < size {
+            self.buf[self.len + 1 + i] = bytes[i];
+            i += 1;
+        }
+        self.len += 1 + size;
+        self
+    }
+}
+
+/// Error when serializing or deserializing a report.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The buffer is smaller than the report.
+    BufferTooSmall,
+    /// The report does not start with the expected report ID.
+    WrongReportId,
+}
+
+/// A HID report, with its report descriptor.
+///
+/// It is usually implemented with `#[derive(HidReport)]`, from the `hid-derive` feature.
+pub trait HidReport: Sized {
+    /// Report descriptor, of a top-level collection holding this report.
+    const DESCRIPTOR: &'static [u8];
+    /// Report ID, which prefixes the report if set.
+    const REPORT_ID: Option<u8>;
+    /// Size of the report in bytes, including the report ID.
+    const SIZE: usize;
+
+    /// Serializes the report in `buf`, returning its size.
+    fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Deserializes a report from `buf`.
+    fn deserialize(buf: &[u8]) -> Result<Self, Error>;
+}
+
+/// Writes the `bits` least significant bits of `value` at bit `offset` of `buf`, least significant
+/// bit first as in HID reports.
+pub fn write_bits(buf: &mut [u8], offset: usize, bits: usize, value: u32) {
+    for i in 0..bits {
+        let bit = offset + i;
+        let mask = 1 << (bit % 8);
+        if value & (1 << i) != 0 {
+            buf[bit / 8] |= mask;
+        } else {
+            buf[bit / 8] &= !mask;
+        }
+    }
+}
+
+/// Reads `bits` bits at bit `offset` of `buf`, least significant bit first as in HID reports.
+pub fn read_bits(buf: &[u8], offset: usize, bits: usize) -> u32 {
+    let mut value = 0;
+    for i in 0..bits {
+        let bit = offset + i;
+        if buf[bit / 8] & (1 << (bit % 8)) != 0 {
+            value |= 1 << i;
+        }
+    }
+    value
+}
+
+/// Reads a signed value of `bits` bits at bit `offset` of `buf`, extending its sign.
+pub fn read_bits_signed(buf: &[u8], offset: usize, bits: usize) -> i32 {
+    let value = read_bits(buf, offset, bits);
+    let shift = 32 - bits as u32;
+    ((value << shift) as i32) >> shift
+}
@@ -1,9 +1,16 @@
 //! Implementations of well-known USB classes.
+pub mod ccid;
 pub mod cdc_acm;
 pub mod cdc_ncm;
 pub mod cmsis_dap_v2;
+pub mod ctaphid;
 pub mod dfu;
 pub mod hid;
+pub mod hid_report;
 pub mod midi;
+pub mod mtp;
+pub mod printer;
 pub mod uac1;
+pub mod usbtmc;
+pub mod uvc;
 pub mod web_usb;
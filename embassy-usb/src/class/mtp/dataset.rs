@@ -0,0 +1,231 @@
+//! Encoding of PTP datasets [PTP 5.3].
+
+/// Writer of a dataset to a buffer. Overflows are detected once written.
+pub(super) struct DatasetWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> DatasetWriter<'a> {
+    pub(super) fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Returns the dataset, or `None` if it did not fit in the buffer.
+    pub(super) fn finish(self) -> Option<&'a [u8]> {
+        (!self.overflow).then_some(&self.buf[..self.len])
+    }
+
+    pub(super) fn bytes(&mut self, data: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + data.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(data);
+                self.len += data.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    pub(super) fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    pub(super) fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub(super) fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub(super) fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Writes an array of 16-bit values.
+    pub(super) fn u16_array(&mut self, values: &[u16]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.u16(*value);
+        }
+    }
+
+    /// Writes a string: its length in UTF-16 code units with the null terminator, then the code
+    /// units. Empty strings are a single zero. Strings are truncated to 254 code units.
+    pub(super) fn str(&mut self, s: &str) {
+        let len = s.encode_utf16().take(254).count();
+        if len == 0 {
+            self.u8(0);
+            return;
+        }
+        self.u8(len as u8 + 1);
+        for unit in s.encode_utf16().take(len) {
+            self.u16(unit);
+        }
+        self.u16(0);
+    }
+}
+
+/// Reader of a received dataset.
+pub(super) struct DatasetReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DatasetReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.data.split_first_chunk::<N>()?;
+        self.data = rest;
+        Some(*bytes)
+    }
+
+    pub(super) fn skip(&mut self, n: usize) -> Option<()> {
+        self.data = self.data.get(n..)?;
+        Some(())
+    }
+
+    pub(super) fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub(super) fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    /// Reads a string, converted to UTF-8 in `buf`. Characters which do not fit are dropped.
+    pub(super) fn str<'b>(&mut self, buf: &'b mut [u8]) -> Option<&'b str> {
+        let [len] = self.take()?;
+        let units = self.data.get(..len as usize * 2)?;
+        self.data = &self.data[units.len()..];
+
+        let units = units
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|unit| *unit != 0);
+        let mut n = 0;
+        for c in char::decode_utf16(units) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            if n + c.len_utf8() > buf.len() {
+                break;
+            }
+            n += c.encode_utf8(&mut buf[n..]).len();
+        }
+        // Only whole characters were written.
+        core::str::from_utf8(&buf[..n]).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer() {
+        let mut buf = [0; 64];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.u8(0x01);
+        w.u16(0x0302);
+        w.u32(0x0706_0504);
+        w.u64(0x0F0E_0D0C_0B0A_0908);
+        w.u16_array(&[0x1001, 0x1002]);
+        assert_eq!(
+            w.finish().unwrap(),
+            &[
+                0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, //
+                0x02, 0x00, 0x00, 0x00, 0x01, 0x10, 0x02, 0x10,
+            ]
+        );
+    }
+
+    #[test]
+    fn writer_strings() {
+        let mut buf = [0; 64];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.str("Hé");
+        w.str("");
+        // A character outside of the BMP is a surrogate pair.
+        w.str("🦀");
+        assert_eq!(
+            w.finish().unwrap(),
+            &[
+                3, b'H', 0, 0xE9, 0, 0, 0, //
+                0, //
+                3, 0x3E, 0xD8, 0x80, 0xDD, 0, 0,
+            ]
+        );
+
+        // Long strings are truncated to 254 code units, and the null terminator.
+        let mut buf = [0; 600];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.str(core::str::from_utf8(&[b'a'; 300]).unwrap());
+        let data = w.finish().unwrap();
+        assert_eq!(data.len(), 1 + 255 * 2);
+        assert_eq!(data[0], 255);
+        assert_eq!(&data[data.len() - 4..], &[b'a', 0, 0, 0]);
+    }
+
+    #[test]
+    fn writer_overflow() {
+        let mut buf = [0; 4];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.u16(1);
+        w.u32(2);
+        // Values written after an overflow do not hide it.
+        w.u8(3);
+        assert!(w.finish().is_none());
+
+        let mut buf = [0; 4];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.u32(2);
+        assert_eq!(w.finish().unwrap(), &[2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reader() {
+        let mut buf = [0; 64];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.u16(0x1234);
+        w.u32(0x5678_9ABC);
+        w.str("Hé🦀");
+        w.str("");
+        w.u16(0xDEF0);
+        let data = w.finish().unwrap();
+
+        let mut r = DatasetReader::new(data);
+        assert_eq!(r.u16(), Some(0x1234));
+        assert_eq!(r.u32(), Some(0x5678_9ABC));
+        let mut s = [0; 16];
+        assert_eq!(r.str(&mut s), Some("Hé🦀"));
+        assert_eq!(r.str(&mut s), Some(""));
+        assert_eq!(r.u16(), Some(0xDEF0));
+        assert_eq!(r.u16(), None);
+        assert_eq!(r.skip(1), None);
+    }
+
+    #[test]
+    fn reader_strings() {
+        // Characters which do not fit in the buffer are dropped.
+        let data = [4, b'H', 0, 0xE9, 0, b'!', 0, 0, 0];
+        let mut s = [0; 2];
+        assert_eq!(DatasetReader::new(&data).str(&mut s), Some("H"));
+
+        // Unpaired surrogates are replaced.
+        let data = [2, 0x3E, 0xD8, 0, 0];
+        let mut s = [0; 8];
+        assert_eq!(DatasetReader::new(&data).str(&mut s), Some("\u{FFFD}"));
+
+        // Strings longer than the dataset.
+        let data = [4, b'H', 0, b'i', 0];
+        assert_eq!(DatasetReader::new(&data).str(&mut s), None);
+        assert_eq!(DatasetReader::new(&[]).str(&mut s), None);
+        assert_eq!(DatasetReader::new(&[0; 3]).u32(), None);
+    }
+}
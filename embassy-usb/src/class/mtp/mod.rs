@@ -0,0 +1,1769 @@
+//! Media Transfer Protocol (MTP) responder, over the USB Still Image class (PTP).
+//!
+//! Devices implementing this class appear as media devices on computers and phones, browsed
+//! with their file managers without custom drivers. Files and folders are exposed by an
+//! [`ObjectStore`], which the host lists, reads and, if the store allows it, writes and deletes.
+//!
+//! The responder is run with [`MtpClass::run`]. To notify the host of changes of the store, such
+//! as a new file, split the class with [`MtpClass::split`] and send [`Event`]s with the
+//! [`EventSender`] while the [`Responder`] runs.
+//!
+//! The following operations are supported, with the MTP object properties needed by hosts to
+//! list objects without reading their whole descriptions:
+//! - GetDeviceInfo, OpenSession, CloseSession
+//! - GetStorageIDs, GetStorageInfo
+//! - GetNumObjects, GetObjectHandles, GetObjectInfo, GetObject, GetPartialObject
+//! - SendObjectInfo, SendObject, DeleteObject
+//! - GetObjectPropsSupported, GetObjectPropDesc, GetObjectPropValue
+
+use core::cell::RefCell;
+use core::future::{Future, poll_fn};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod dataset;
+mod store;
+
+use dataset::{DatasetReader, DatasetWriter};
+pub use store::{ALL_STORAGES, ObjectInfo, ObjectStore, Parent, ResponseCode, StorageInfo, StorageType, format};
+
+/// Still image interface class.
+const USB_CLASS_STILL_IMAGE: u8 = 0x06;
+const STILL_IMAGE_SUBCLASS: u8 = 0x01;
+const PTP_PROTOCOL: u8 = 0x01;
+
+// Still image class requests [PTP USB 5.2]
+const REQ_CANCEL: u8 = 0x64;
+const REQ_DEVICE_RESET: u8 = 0x66;
+const REQ_GET_DEVICE_STATUS: u8 = 0x67;
+
+/// Cancellation code of the cancel request.
+const CANCEL_CODE: u16 = 0x4001;
+
+// Container types [PTP USB D.7.1]
+const CONTAINER_COMMAND: u16 = 1;
+const CONTAINER_DATA: u16 = 2;
+const CONTAINER_RESPONSE: u16 = 3;
+const CONTAINER_EVENT: u16 = 4;
+
+/// Length of the header of containers.
+const HEADER_LEN: usize = 12;
+
+/// Maximum supported packet size of the bulk endpoints.
+const MAX_PACKET_SIZE: usize = 512;
+
+/// Size of the buffer of datasets, sent and received.
+const DATASET_SIZE: usize = 512;
+
+/// Size of the longest event container, with 3 parameters.
+const EVENT_SIZE: u16 = 24;
+
+// Operation codes [PTP 10.4, MTP D.2]
+const OP_GET_DEVICE_INFO: u16 = 0x1001;
+const OP_OPEN_SESSION: u16 = 0x1002;
+const OP_CLOSE_SESSION: u16 = 0x1003;
+const OP_GET_STORAGE_IDS: u16 = 0x1004;
+const OP_GET_STORAGE_INFO: u16 = 0x1005;
+const OP_GET_NUM_OBJECTS: u16 = 0x1006;
+const OP_GET_OBJECT_HANDLES: u16 = 0x1007;
+const OP_GET_OBJECT_INFO: u16 = 0x1008;
+const OP_GET_OBJECT: u16 = 0x1009;
+const OP_DELETE_OBJECT: u16 = 0x100B;
+const OP_SEND_OBJECT_INFO: u16 = 0x100C;
+const OP_SEND_OBJECT: u16 = 0x100D;
+const OP_GET_PARTIAL_OBJECT: u16 = 0x101B;
+const OP_GET_OBJECT_PROPS_SUPPORTED: u16 = 0x9801;
+const OP_GET_OBJECT_PROP_DESC: u16 = 0x9802;
+const OP_GET_OBJECT_PROP_VALUE: u16 = 0x9803;
+
+const OPERATIONS: &[u16] = &[
+    OP_GET_DEVICE_INFO,
+    OP_OPEN_SESSION,
+    OP_CLOSE_SESSION,
+    OP_GET_STORAGE_IDS,
+    OP_GET_STORAGE_INFO,
+    OP_GET_NUM_OBJECTS,
+    OP_GET_OBJECT_HANDLES,
+    OP_GET_OBJECT_INFO,
+    OP_GET_OBJECT,
+    OP_DELETE_OBJECT,
+    OP_SEND_OBJECT_INFO,
+    OP_SEND_OBJECT,
+    OP_GET_PARTIAL_OBJECT,
+    OP_GET_OBJECT_PROPS_SUPPORTED,
+    OP_GET_OBJECT_PROP_DESC,
+    OP_GET_OBJECT_PROP_VALUE,
+];
+
+// Event codes [PTP 12.5]
+const EV_OBJECT_ADDED: u16 = 0x4002;
+const EV_OBJECT_REMOVED: u16 = 0x4003;
+const EV_STORE_ADDED: u16 = 0x4004;
+const EV_STORE_REMOVED: u16 = 0x4005;
+const EV_OBJECT_INFO_CHANGED: u16 = 0x4007;
+const EV_DEVICE_INFO_CHANGED: u16 = 0x4008;
+const EV_STORAGE_INFO_CHANGED: u16 = 0x400C;
+
+const EVENTS: &[u16] = &[
+    EV_OBJECT_ADDED,
+    EV_OBJECT_REMOVED,
+    EV_STORE_ADDED,
+    EV_STORE_REMOVED,
+    EV_OBJECT_INFO_CHANGED,
+    EV_DEVICE_INFO_CHANGED,
+    EV_STORAGE_INFO_CHANGED,
+];
+
+// Object property codes [MTP B.2]
+const PROP_STORAGE_ID: u16 = 0xDC01;
+const PROP_OBJECT_FORMAT: u16 = 0xDC02;
+const PROP_OBJECT_SIZE: u16 = 0xDC04;
+const PROP_OBJECT_FILE_NAME: u16 = 0xDC07;
+const PROP_DATE_MODIFIED: u16 = 0xDC09;
+const PROP_PARENT_OBJECT: u16 = 0xDC0B;
+
+const OBJECT_PROPS: &[u16] = &[
+    PROP_STORAGE_ID,
+    PROP_OBJECT_FORMAT,
+    PROP_OBJECT_SIZE,
+    PROP_OBJECT_FILE_NAME,
+    PROP_DATE_MODIFIED,
+    PROP_PARENT_OBJECT,
+];
+
+// Data types [PTP 5.3]
+const TYPE_UINT16: u16 = 0x0004;
+const TYPE_UINT32: u16 = 0x0006;
+const TYPE_UINT64: u16 = 0x0008;
+const TYPE_STR: u16 = 0xFFFF;
+
+/// Association type of folders [PTP 5.5.2].
+const ASSOCIATION_GENERIC_FOLDER: u16 = 0x0001;
+
+/// Parent handle of the objects of the root folder, in operation parameters.
+const ROOT: u32 = 0xFFFF_FFFF;
+
+/// MTP error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The given buffer was too small for the received packet.
+    BufferOverflow,
+    /// The endpoint is disabled.
+    Disabled,
+    /// The host cancelled the transaction, or reset the device.
+    Cancelled,
+}
+
+impl From<EndpointError> for Error {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => Error::BufferOverflow,
+            EndpointError::Disabled => Error::Disabled,
+        }
+    }
+}
+
+/// Configuration of the [`MtpClass`].
+pub struct Config<'a> {
+    /// Manufacturer, in the device info.
+    pub manufacturer: &'a str,
+    /// Model, in the device info. Hosts show it as the name of the device.
+    pub model: &'a str,
+    /// Version of the device, in the device info.
+    pub device_version: &'a str,
+    /// Serial number, in the device info. Hosts use it to recognize the device.
+    pub serial_number: &'a str,
+
+    /// Formats of the objects the host may send, one of [`format`].
+    pub formats: &'a [u16],
+
+    /// Max packet size of the bulk endpoints, at least 64 bytes, up to 512 bytes.
+    ///
+    /// Full-speed devices must use 64 bytes, high-speed ones 512 bytes.
+    pub max_packet_size: u16,
+}
+
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        Self {
+            manufacturer: "",
+            model: "",
+            device_version: "",
+            serial_number: "",
+            formats: &[format::UNDEFINED, format::ASSOCIATION],
+            max_packet_size: 64,
+        }
+    }
+}
+
+/// Event notifying the host of a change of the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// An object was added, with its handle.
+    ObjectAdded(u32),
+    /// An object was removed, with its handle.
+    ObjectRemoved(u32),
+    /// A storage was added, with its ID.
+    StoreAdded(u32),
+    /// A storage was removed, with its ID.
+    StoreRemoved(u32),
+    /// The description of an object changed, with its handle.
+    ObjectInfoChanged(u32),
+    /// The description of a storage changed, such as its free space, with its ID.
+    StorageInfoChanged(u32),
+    /// The description of the device changed.
+    DeviceInfoChanged,
+}
+
+/// Internal state for MTP
+pub struct State<'a> {
+    control: Option<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: None,
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+struct Control<'a> {
+    interface: InterfaceNumber,
+    shared: &'a ControlShared,
+}
+
+/// Shared data between Control and the responder
+struct ControlShared {
+    /// A session is open.
+    session_open: AtomicBool,
+    /// The host cancelled the transaction in progress, or reset the device.
+    cancel: AtomicBool,
+    /// The responder is not ready for the next transaction, after a cancellation.
+    busy: AtomicBool,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        ControlShared {
+            session_open: AtomicBool::new(false),
+            cancel: AtomicBool::new(false),
+            busy: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+
+    /// Closes the session, and stops the transaction in progress.
+    fn reset(&self) {
+        self.session_open.store(false, Ordering::Relaxed);
+        self.cancel.store(true, Ordering::Relaxed);
+        self.waker.borrow_mut().wake();
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.reset();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            self.shared.reset();
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                u8::from(self.interface) as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_CANCEL if data.len() >= 6 && u16::from_le_bytes([data[0], data[1]]) == CANCEL_CODE => {
+                debug!("mtp: cancel");
+                self.shared.cancel.store(true, Ordering::Relaxed);
+                self.shared.busy.store(true, Ordering::Relaxed);
+                self.shared.waker.borrow_mut().wake();
+                Some(OutResponse::Accepted)
+            }
+            REQ_DEVICE_RESET => {
+                debug!("mtp: device reset");
+                self.shared.busy.store(true, Ordering::Relaxed);
+                self.shared.reset();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                u8::from(self.interface) as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_DEVICE_STATUS => {
+                let code = if self.shared.busy.load(Ordering::Relaxed) {
+                    ResponseCode::DeviceBusy
+                } else {
+                    ResponseCode::Ok
+                };
+                buf[..2].copy_from_slice(&4u16.to_le_bytes()); // wLength
+                buf[2..4].copy_from_slice(&(code as u16).to_le_bytes());
+                Some(InResponse::Accepted(&buf[..4]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// Implementation of an MTP responder.
+pub struct MtpClass<'d, D: Driver<'d>> {
+    responder: Responder<'d, D>,
+    events: EventSender<'d, D>,
+}
+
+impl<'d, D: Driver<'d>> MtpClass<'d, D> {
+    /// Creates a new MtpClass with the provided configuration.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        // Command containers must fit in a packet.
+        assert!(config.max_packet_size >= 64);
+        assert!(config.max_packet_size as usize <= MAX_PACKET_SIZE);
+
+        let mut func = builder.function(USB_CLASS_STILL_IMAGE, STILL_IMAGE_SUBCLASS, PTP_PROTOCOL);
+        let mut iface = func.interface();
+        let interface = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_STILL_IMAGE, STILL_IMAGE_SUBCLASS, PTP_PROTOCOL, None);
+
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, config.max_packet_size);
+        let event_ep = alt.endpoint_interrupt_in(None, EVENT_SIZE, 10);
+
+        drop(func);
+
+        state.control = Some(Control {
+            interface,
+            shared: &state.shared,
+        });
+        builder.handler(state.control.as_mut().unwrap());
+
+        MtpClass {
+            responder: Responder {
+                read_ep,
+                write_ep,
+                shared: &state.shared,
+                config,
+                operation: 0,
+                transaction_id: 0,
+                pending_object: None,
+            },
+            events: EventSender {
+                ep: event_ep,
+                shared: &state.shared,
+            },
+        }
+    }
+
+    /// Splits the class into the responder and the event sender, to run them concurrently.
+    pub fn split(self) -> (Responder<'d, D>, EventSender<'d, D>) {
+        (self.responder, self.events)
+    }
+
+    /// Answers the operations of the host on `store`. Never returns.
+    pub async fn run<S: ObjectStore>(&mut self, store: &mut S) -> ! {
+        self.responder.run(store).await
+    }
+}
+
+/// Sends [`Event`]s to the host.
+pub struct EventSender<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    shared: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> EventSender<'d, D> {
+    /// Sends an event to the host.
+    ///
+    /// Events are only sent while the host has a session open, and dropped otherwise.
+    pub async fn send(&mut self, event: Event) -> Result<(), Error> {
+        if !self.shared.session_open.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let (code, param) = match event {
+            Event::ObjectAdded(handle) => (EV_OBJECT_ADDED, Some(handle)),
+            Event::ObjectRemoved(handle) => (EV_OBJECT_REMOVED, Some(handle)),
+            Event::StoreAdded(id) => (EV_STORE_ADDED, Some(id)),
+            Event::StoreRemoved(id) => (EV_STORE_REMOVED, Some(id)),
+            Event::ObjectInfoChanged(handle) => (EV_OBJECT_INFO_CHANGED, Some(handle)),
+            Event::StorageInfoChanged(id) => (EV_STORAGE_INFO_CHANGED, Some(id)),
+            Event::DeviceInfoChanged => (EV_DEVICE_INFO_CHANGED, None),
+        };
+
+        let mut packet = [0; HEADER_LEN + 4];
+        let len = HEADER_LEN + param.map_or(0, |_| 4);
+        // Events are not related to a transaction.
+        write_header(&mut packet, len as u32, CONTAINER_EVENT, code, 0);
+        if let Some(param) = param {
+            packet[HEADER_LEN..].copy_from_slice(&param.to_le_bytes());
+        }
+        self.ep.write(&packet[..len]).await?;
+        Ok(())
+    }
+}
+
+/// Writes a container header.
+fn write_header(buf: &mut [u8], len: u32, container_type: u16, code: u16, transaction_id: u32) {
+    buf[0..4].copy_from_slice(&len.to_le_bytes());
+    buf[4..6].copy_from_slice(&container_type.to_le_bytes());
+    buf[6..8].copy_from_slice(&code.to_le_bytes());
+    buf[8..12].copy_from_slice(&transaction_id.to_le_bytes());
+}
+
+/// Runs `fut` until the host cancels the transaction.
+async fn cancellable<T>(
+    shared: &ControlShared,
+    fut: impl Future<Output = Result<T, EndpointError>>,
+) -> Result<T, Error> {
+    let cancel = poll_fn(|cx| {
+        if shared.cancel.load(Ordering::Relaxed) {
+            Poll::Ready(())
+        } else {
+            shared.waker.borrow_mut().register(cx.waker());
+            Poll::Pending
+        }
+    });
+
+    match select(fut, cancel).await {
+        Either::First(result) => Ok(result?),
+        Either::Second(()) => Err(Error::Cancelled),
+    }
+}
+
+/// Why an operation failed.
+enum Failure {
+    /// The operation is answered with this response code.
+    Response(ResponseCode),
+    /// The transaction stopped.
+    Usb(Error),
+}
+
+impl From<ResponseCode> for Failure {
+    fn from(code: ResponseCode) -> Self {
+        Failure::Response(code)
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Self {
+        Failure::Usb(error)
+    }
+}
+
+/// Parameters of the response of a successful operation.
+#[derive(Default)]
+struct Params {
+    values: [u32; 3],
+    len: usize,
+}
+
+impl Params {
+    fn new(values: &[u32]) -> Self {
+        let mut params = Self::default();
+        params.values[..values.len()].copy_from_slice(values);
+        params.len = values.len();
+        params
+    }
+}
+
+/// Data phase from the responder to the host, split in packets.
+struct DataIn<'a, E: EndpointIn> {
+    ep: &'a mut E,
+    shared: &'a ControlShared,
+    packet: [u8; MAX_PACKET_SIZE],
+    len: usize,
+    max_packet_size: usize,
+}
+
+impl<'a, E: EndpointIn> DataIn<'a, E> {
+    /// Starts a data container of `len` bytes, without its header.
+    fn new(ep: &'a mut E, shared: &'a ControlShared, code: u16, transaction_id: u32, len: u64) -> Self {
+        let max_packet_size = ep.info().max_packet_size as usize;
+        let mut packet = [0; MAX_PACKET_SIZE];
+        // Containers of 4GB or more have the maximum length.
+        let len = (HEADER_LEN as u64 + len).min(u32::MAX as u64) as u32;
+        write_header(&mut packet, len, CONTAINER_DATA, code, transaction_id);
+        Self {
+            ep,
+            shared,
+            packet,
+            len: HEADER_LEN,
+            max_packet_size,
+        }
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let n = data.len().min(self.max_packet_size - self.len);
+            self.packet[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == self.max_packet_size {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        cancellable(self.shared, self.ep.write(&self.packet[..self.len])).await?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Ends the data phase, with a short packet, which is empty after full packets.
+    async fn finish(mut self) -> Result<(), Error> {
+        self.flush().await
+    }
+}
+
+/// Answers the operations of the host on an [`ObjectStore`].
+pub struct Responder<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    config: Config<'d>,
+    /// Operation and transaction in progress.
+    operation: u16,
+    transaction_id: u32,
+    /// Object created by SendObjectInfo, with its size, to be sent with SendObject.
+    pending_object: Option<(u32, u64)>,
+}
+
+impl<'d, D: Driver<'d>> Responder<'d, D> {
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Answers the operations of the host on `store`. Never returns.
+    pub async fn run<S: ObjectStore>(&mut self, store: &mut S) -> ! {
+        loop {
+            self.wait_connection().await;
+            loop {
+                match self.transaction(store).await {
+                    Ok(()) => {}
+                    Err(Error::Disabled) => break,
+                    Err(Error::Cancelled) => debug!("mtp: transaction cancelled"),
+                    Err(Error::BufferOverflow) => warn!("mtp: buffer overflow"),
+                }
+                // A cancelled transaction is over, the responder is ready for the next one.
+                if self.shared.cancel.load(Ordering::Relaxed) {
+                    self.shared.cancel.store(false, Ordering::Relaxed);
+                    self.shared.busy.store(false, Ordering::Relaxed);
+                    self.pending_object = None;
+                }
+            }
+        }
+    }
+
+    async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        cancellable(self.shared, self.read_ep.read(buf)).await
+    }
+
+    /// Handles a transaction: a command, with its data phase, and the response.
+    async fn transaction<S: ObjectStore>(&mut self, store: &mut S) -> Result<(), Error> {
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let max_packet_size = self.max_packet_size() as usize;
+        let n = self.read_packet(&mut packet[..max_packet_size]).await?;
+        if n == 0 {
+            // End of a data phase of a multiple of the packet size.
+            return Ok(());
+        }
+        if n < HEADER_LEN || u16::from_le_bytes([packet[4], packet[5]]) != CONTAINER_COMMAND {
+            warn!("mtp: invalid command container");
+            return Ok(());
+        }
+
+        let code = u16::from_le_bytes([packet[6], packet[7]]);
+        self.operation = code;
+        self.transaction_id = u32::from_le_bytes(packet[8..12].try_into().unwrap());
+        let mut params = [0; 5];
+        for (param, bytes) in params.iter_mut().zip(packet[HEADER_LEN..n].chunks_exact(4)) {
+            *param = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        trace!("mtp: operation {:04x} {:?}", code, params);
+
+        let result = match code {
+            OP_GET_DEVICE_INFO => self.get_device_info().await,
+            OP_OPEN_SESSION => self.open_session(params[0]),
+            // Data sent by the host is read even if the operation fails.
+            OP_SEND_OBJECT_INFO => self.send_object_info(store, params).await,
+            OP_SEND_OBJECT => self.send_object(store).await,
+            _ if !self.shared.session_open.load(Ordering::Relaxed) => Err(ResponseCode::SessionNotOpen.into()),
+            OP_CLOSE_SESSION => {
+                self.shared.session_open.store(false, Ordering::Relaxed);
+                self.pending_object = None;
+                Ok(Params::default())
+            }
+            OP_GET_STORAGE_IDS => self.get_storage_ids(store).await,
+            OP_GET_STORAGE_INFO => self.get_storage_info(store, params[0]).await,
+            OP_GET_NUM_OBJECTS => match store.object_count(params[0], parent(params[2])).await {
+                Ok(count) => Ok(Params::new(&[count])),
+                Err(code) => Err(code.into()),
+            },
+            OP_GET_OBJECT_HANDLES => self.get_object_handles(store, params[0], parent(params[2])).await,
+            OP_GET_OBJECT_INFO => self.get_object_info(store, params[0]).await,
+            OP_GET_OBJECT => self.get_object(store, params[0], 0, u64::MAX).await,
+            OP_GET_PARTIAL_OBJECT => {
+                self.get_object(store, params[0], params[1] as u64, params[2] as u64)
+                    .await
+            }
+            OP_DELETE_OBJECT => match store.delete(params[0]).await {
+                Ok(()) => Ok(Params::default()),
+                Err(code) => Err(code.into()),
+            },
+            OP_GET_OBJECT_PROPS_SUPPORTED => {
+                let mut buf = [0; DATASET_SIZE];
+                let mut w = DatasetWriter::new(&mut buf);
+                w.u16_array(OBJECT_PROPS);
+                self.send_dataset(w).await
+            }
+            OP_GET_OBJECT_PROP_DESC => self.get_object_prop_desc(params[0] as u16).await,
+            OP_GET_OBJECT_PROP_VALUE => self.get_object_prop_value(store, params[0], params[1] as u16).await,
+            _ => Err(ResponseCode::OperationNotSupported.into()),
+        };
+
+        let (code, params) = match result {
+            Ok(params) => (ResponseCode::Ok, params),
+            Err(Failure::Response(code)) => (code, Params::default()),
+            Err(Failure::Usb(e)) => return Err(e),
+        };
+        self.respond(code, &params.values[..params.len]).await
+    }
+
+    async fn respond(&mut self, code: ResponseCode, params: &[u32]) -> Result<(), Error> {
+        let mut packet = [0; HEADER_LEN + 12];
+        let len = HEADER_LEN + 4 * params.len();
+        write_header(
+            &mut packet,
+            len as u32,
+            CONTAINER_RESPONSE,
+            code as u16,
+            self.transaction_id,
+        );
+        for (bytes, param) in packet[HEADER_LEN..len].chunks_exact_mut(4).zip(params) {
+            bytes.copy_from_slice(&param.to_le_bytes());
+        }
+        cancellable(self.shared, self.write_ep.write(&packet[..len])).await
+    }
+
+    /// Sends a dataset in the data phase.
+    async fn send_dataset(&mut self, w: DatasetWriter<'_>) -> Result<Params, Failure> {
+        let Some(dataset) = w.finish() else {
+            warn!("mtp: dataset too large");
+            return Err(ResponseCode::GeneralError.into());
+        };
+        let mut data = DataIn::new(
+            &mut self.write_ep,
+            self.shared,
+            self.operation,
+            self.transaction_id,
+            dataset.len() as u64,
+        );
+        data.write(dataset).await?;
+        data.finish().await?;
+        Ok(Params::default())
+    }
+
+    async fn get_device_info(&mut self) -> Result<Params, Failure> {
+        let mut buf = [0; DATASET_SIZE];
+        let mut w = DatasetWriter::new(&mut buf);
+        // DeviceInfo dataset [PTP 5.5.1]
+        w.u16(100); // StandardVersion
+        w.u32(6); // VendorExtensionID, Microsoft
+        w.u16(100); // VendorExtensionVersion
+        w.str("microsoft.com: 1.0; "); // VendorExtensionDesc
+        w.u16(0); // FunctionalMode
+        w.u16_array(OPERATIONS);
+        w.u16_array(EVENTS);
+        w.u16_array(&[]); // DevicePropertiesSupported
+        w.u16_array(&[]); // CaptureFormats
+        w.u16_array(self.config.formats); // PlaybackFormats
+        w.str(self.config.manufacturer);
+        w.str(self.config.model);
+        w.str(self.config.device_version);
+        w.str(self.config.serial_number);
+        self.send_dataset(w).await
+    }
+
+    fn open_session(&mut self, session_id: u32) -> Result<Params, Failure> {
+        if session_id == 0 {
+            return Err(ResponseCode::InvalidParameter.into());
+        }
+        if self.shared.session_open.load(Ordering::Relaxed) {
+            return Err(ResponseCode::SessionAlreadyOpen.into());
+        }
+        debug!("mtp: open session {}", session_id);
+        self.shared.session_open.store(true, Ordering::Relaxed);
+        self.pending_object = None;
+        Ok(Params::default())
+    }
+
+    async fn get_storage_ids<S: ObjectStore>(&mut self, store: &mut S) -> Result<Params, Failure> {
+        let mut buf = [0; DATASET_SIZE];
+        let mut w = DatasetWriter::new(&mut buf);
+        let ids = store.storage_ids();
+        w.u32(ids.len() as u32);
+        for id in ids {
+            w.u32(*id);
+        }
+        self.send_dataset(w).await
+    }
+
+    async fn get_storage_info<S: ObjectStore>(&mut self, store: &mut S, storage_id: u32) -> Result<Params, Failure> {
+        if !store.storage_ids().contains(&storage_id) {
+            return Err(ResponseCode::InvalidStorageId.into());
+        }
+        let info = store.storage_info(storage_id).await?;
+
+        let mut buf = [0; DATASET_SIZE];
+        let mut w = DatasetWriter::new(&mut buf);
+        // StorageInfo dataset [PTP 5.5.3]
+        w.u16(info.storage_type as u16);
+        w.u16(0x0002); // FilesystemType, generic hierarchical
+        w.u16(info.read_only as u16); // AccessCapability, read-only without deletion
+        w.u64(info.max_capacity);
+        w.u64(info.free_space);
+        w.u32(0xFFFF_FFFF); // FreeSpaceInImages, unused
+        w.str(info.description);
+        w.str(info.volume_label);
+        self.send_dataset(w).await
+    }
+
+    async fn get_object_handles<S: ObjectStore>(
+        &mut self,
+        store: &mut S,
+        storage_id: u32,
+        parent: Parent,
+    ) -> Result<Params, Failure> {
+        let count = store.object_count(storage_id, parent).await?;
+        let mut data = DataIn::new(
+            &mut self.write_ep,
+            self.shared,
+            self.operation,
+            self.transaction_id,
+            4 + 4 * count as u64,
+        );
+        data.write(&count.to_le_bytes()).await?;
+
+        let mut handles = [0; 32];
+        let mut offset = 0;
+        while offset < count {
+            let n = match store.object_handles(storage_id, parent, offset, &mut handles).await {
+                Ok(n) if n > 0 => n.min(handles.len()).min((count - offset) as usize),
+                _ => {
+                    // The data phase ends early.
+                    data.finish().await?;
+                    return Err(ResponseCode::IncompleteTransfer.into());
+                }
+            };
+            for handle in &handles[..n] {
+                data.write(&handle.to_le_bytes()).await?;
+            }
+            offset += n as u32;
+        }
+        data.finish().await?;
+        Ok(Params::default())
+    }
+
+    async fn get_object_info<S: ObjectStore>(&mut self, store: &mut S, handle: u32) -> Result<Params, Failure> {
+        let info = store.object_info(handle).await?;
+
+        let mut buf = [0; DATASET_SIZE];
+        let mut w = DatasetWriter::new(&mut buf);
+        // ObjectInfo dataset [PTP 5.5.2]
+        w.u32(info.storage_id);
+        w.u16(info.format);
+        w.u16(0); // ProtectionStatus
+        w.u32(info.size.min(u32::MAX as u64) as u32); // ObjectCompressedSize
+        w.u16(0); // ThumbFormat
+        w.u32(0); // ThumbCompressedSize
+        w.u32(0); // ThumbPixWidth
+        w.u32(0); // ThumbPixHeight
+        w.u32(0); // ImagePixWidth
+        w.u32(0); // ImagePixHeight
+        w.u32(0); // ImageBitDepth
+        w.u32(info.parent);
+        w.u16(if info.is_folder() {
+            ASSOCIATION_GENERIC_FOLDER
+        } else {
+            0
+        }); // AssociationType
+        w.u32(0); // AssociationDesc
+        w.u32(0); // SequenceNumber
+        w.str(info.name);
+        w.str(""); // CaptureDate
+        w.str(info.modified);
+        w.str(""); // Keywords
+        self.send_dataset(w).await
+    }
+
+    /// Sends up to `max_len` bytes of an object from `offset`.
+    async fn get_object<S: ObjectStore>(
+        &mut self,
+        store: &mut S,
+        handle: u32,
+        offset: u64,
+        max_len: u64,
+    ) -> Result<Params, Failure> {
+        let size = store.object_info(handle).await?.size;
+        if offset > size {
+            return Err(ResponseCode::InvalidParameter.into());
+        }
+        let len = (size - offset).min(max_len);
+
+        let mut data = DataIn::new(
+            &mut self.write_ep,
+            self.shared,
+            self.operation,
+            self.transaction_id,
+            len,
+        );
+        let mut chunk = [0; MAX_PACKET_SIZE];
+        let mut sent = 0;
+        while sent < len {
+            let max = (len - sent).min(chunk.len() as u64) as usize;
+            let n = match store.read(handle, offset + sent, &mut chunk[..max]).await {
+                Ok(n) if n > 0 => n.min(max),
+                _ => {
+                    // The data phase ends early.
+                    data.finish().await?;
+                    return Err(ResponseCode::IncompleteTransfer.into());
+                }
+            };
+            data.write(&chunk[..n]).await?;
+            sent += n as u64;
+        }
+        data.finish().await?;
+        // GetPartialObject returns the number of bytes sent.
+        Ok(Params::new(&[len.min(u32::MAX as u64) as u32]))
+    }
+
+    /// Reads the data phase into `buf`. Returns its length, or `None` if it did not fit.
+    async fn read_data(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let max_packet_size = self.max_packet_size() as usize;
+        let mut len = 0;
+        let mut overflow = false;
+        let mut first = true;
+        loop {
+            let n = self.read_packet(&mut packet[..max_packet_size]).await?;
+            let data = if first {
+                packet.get(HEADER_LEN..n).unwrap_or(&[])
+            } else {
+                &packet[..n]
+            };
+            first = false;
+            match buf.get_mut(len..len + data.len()) {
+                Some(dst) => {
+                    dst.copy_from_slice(data);
+                    len += data.len();
+                }
+                None => overflow = true,
+            }
+            if n < max_packet_size {
+                return Ok((!overflow).then_some(len));
+            }
+        }
+    }
+
+    async fn send_object_info<S: ObjectStore>(&mut self, store: &mut S, params: [u32; 5]) -> Result<Params, Failure> {
+        let mut buf = [0; DATASET_SIZE];
+        let len = self.read_data(&mut buf).await?;
+        if !self.shared.session_open.load(Ordering::Relaxed) {
+            return Err(ResponseCode::SessionNotOpen.into());
+        }
+        let Some(len) = len else {
+            return Err(ResponseCode::GeneralError.into());
+        };
+
+        // The storage is chosen by the responder if not given.
+        let storage_id = match params[0] {
+            0 => *store.storage_ids().first().ok_or(ResponseCode::StoreNotAvailable)?,
+            id => id,
+        };
+        let parent = match params[1] {
+            ROOT => 0,
+            handle => handle,
+        };
+
+        let mut name = [0; 256];
+        let mut modified = [0; 32];
+        let Some(mut info) = parse_object_info(&buf[..len], &mut name, &mut modified) else {
+            return Err(ResponseCode::InvalidParameter.into());
+        };
+        info.storage_id = storage_id;
+        info.parent = parent;
+
+        let handle = store.create(&info).await?;
+        debug!("mtp: object {} created", handle);
+        self.pending_object = Some((handle, info.size));
+        Ok(Params::new(&[storage_id, parent, handle]))
+    }
+
+    async fn send_object<S: ObjectStore>(&mut self, store: &mut S) -> Result<Params, Failure> {
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let max_packet_size = self.max_packet_size() as usize;
+        let n = self.read_packet(&mut packet[..max_packet_size]).await?;
+        let pending = match self.pending_object.take() {
+            Some(pending) if self.shared.session_open.load(Ordering::Relaxed) => Ok(pending),
+            Some(_) => Err(ResponseCode::SessionNotOpen),
+            None => Err(ResponseCode::NoValidObjectInfo),
+        };
+
+        // Containers of 4GB or more have the maximum length, and end with a short packet.
+        let container_len = packet.get(..4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()));
+        let len = match container_len {
+            u32::MAX => u64::MAX,
+            len => (len as u64).saturating_sub(HEADER_LEN as u64),
+        };
+
+        // The data phase is read whole, even if the object cannot be written.
+        let mut result = pending.map(|_| ());
+        let mut received = 0;
+        let (mut start, mut n) = (HEADER_LEN.min(n), n);
+        loop {
+            let data = &packet[start..n];
+            if let Ok((handle, _)) = pending
+                && result.is_ok()
+                && !data.is_empty()
+            {
+                result = store.write(handle, received, data).await;
+            }
+            received += data.len() as u64;
+            if n < max_packet_size || received >= len {
+                break;
+            }
+            n = self.read_packet(&mut packet[..max_packet_size]).await?;
+            start = 0;
+        }
+
+        result?;
+        if let Ok((handle, size)) = pending
+            && size != u64::MAX
+            && received != size
+        {
+            warn!("mtp: object {} is {} bytes long, expected {}", handle, received, size);
+            return Err(ResponseCode::IncompleteTransfer.into());
+        }
+        Ok(Params::default())
+    }
+
+    async fn get_object_prop_desc(&mut self, prop: u16) -> Result<Params, Failure> {
+        let datatype = prop_datatype(prop)?;
+
+        let mut buf = [0; DATASET_SIZE];
+        let mut w = DatasetWriter::new(&mut buf);
+        // ObjectPropDesc dataset [MTP 5.3.2.3]
+        w.u16(prop);
+        w.u16(datatype);
+        w.u8(0); // Get/Set, read-only
+        match datatype {
+            TYPE_UINT16 => w.u16(0),
+            TYPE_UINT32 => w.u32(0),
+            TYPE_UINT64 => w.u64(0),
+            _ => w.str(""),
+        } // DefaultValue
+        w.u32(0); // GroupCode
+        w.u8(0); // FormFlag, none
+        self.send_dataset(w).await
+    }
+
+    async fn get_object_prop_value<S: ObjectStore>(
+        &mut self,
+        store: &mut S,
+        handle: u32,
+        prop: u16,
+    ) -> Result<Params, Failure> {
+        prop_datatype(prop)?;
+        let info = store.object_info(handle).await?;
+
+        let mut buf = [0; DATASET_SIZE];
+        let mut w = DatasetWriter::new(&mut buf);
+        match prop {
+            PROP_STORAGE_ID => w.u32(info.storage_id),
+            PROP_OBJECT_FORMAT => w.u16(info.format),
+            PROP_OBJECT_SIZE => w.u64(info.size),
+            PROP_OBJECT_FILE_NAME => w.str(info.name),
+            PROP_DATE_MODIFIED => w.str(info.modified),
+            _ => w.u32(info.parent),
+        }
+        self.send_dataset(w).await
+    }
+}
+
+/// Returns the parent of the objects listed by GetNumObjects and GetObjectHandles.
+fn parent(param: u32) -> Parent {
+    match param {
+        0 => Parent::All,
+        ROOT => Parent::Root,
+        handle => Parent::Folder(handle),
+    }
+}
+
+/// Parses an ObjectInfo dataset [PTP 5.5.2], without its storage and parent.
+fn parse_object_info<'a>(data: &[u8], name: &'a mut [u8], modified: &'a mut [u8]) -> Option<ObjectInfo<'a>> {
+    let mut r = DatasetReader::new(data);
+    r.skip(4)?; // StorageID
+    let format = r.u16()?;
+    r.skip(2)?; // ProtectionStatus
+    let size = r.u32()?; // ObjectCompressedSize
+    r.skip(26)?; // Thumbnail and image
+    r.skip(4)?; // ParentObject
+    r.skip(10)?; // Association and sequence number
+    let name = r.str(name)?;
+    r.str(&mut [])?; // CaptureDate
+    let modified = r.str(modified)?;
+
+    Some(ObjectInfo {
+        storage_id: 0,
+        format,
+        // Objects of 4GB or more have the maximum size.
+        size: if size == u32::MAX { u64::MAX } else { size as u64 },
+        parent: 0,
+        name,
+        modified,
+    })
+}
+
+/// Returns the datatype of a supported object property.
+fn prop_datatype(prop: u16) -> Result<u16, ResponseCode> {
+    match prop {
+        PROP_STORAGE_ID | PROP_PARENT_OBJECT => Ok(TYPE_UINT32),
+        PROP_OBJECT_FORMAT => Ok(TYPE_UINT16),
+        PROP_OBJECT_SIZE => Ok(TYPE_UINT64),
+        PROP_OBJECT_FILE_NAME | PROP_DATE_MODIFIED => Ok(TYPE_STR),
+        _ => Err(ResponseCode::InvalidObjectPropCode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::iter::once;
+
+    use embassy_futures::block_on;
+    use heapless::{String, Vec};
+
+    use super::*;
+    use crate::driver::EndpointType;
+    use crate::test_driver::TestDriver;
+
+    const STORAGE: u32 = 0x0001_0001;
+    const INTERFACE: u16 = 0;
+
+    struct TestObject {
+        handle: u32,
+        parent: u32,
+        format: u16,
+        name: String<32>,
+        data: Vec<u8, 256>,
+    }
+
+    /// Store with a folder, a file in the root folder, and a file in the folder.
+    struct TestStore {
+        objects: Vec<TestObject, 8>,
+        next_handle: u32,
+    }
+
+    impl TestStore {
+        fn new() -> Self {
+            let mut store = Self {
+                objects: Vec::new(),
+                next_handle: 1,
+            };
+            store.add(0, format::ASSOCIATION, "docs", b"");
+            store.add(0, format::TEXT, "a.txt", b"hello");
+            store.add(1, format::TEXT, "b.txt", b"world");
+            store
+        }
+
+        fn add(&mut self, parent: u32, format: u16, name: &str, data: &[u8]) -> u32 {
+            let handle = self.next_handle;
+            self.next_handle += 1;
+            let object = TestObject {
+                handle,
+                parent,
+                format,
+                name: String::try_from(name).unwrap(),
+                data: Vec::from_slice(data).unwrap(),
+            };
+            self.objects.push(object).ok().unwrap();
+            handle
+        }
+
+        fn get(&mut self, handle: u32) -> Result<&mut TestObject, ResponseCode> {
+            self.objects
+                .iter_mut()
+                .find(|object| object.handle == handle)
+                .ok_or(ResponseCode::InvalidObjectHandle)
+        }
+
+        fn children(&self, parent: Parent) -> impl Iterator<Item = &TestObject> {
+            self.objects.iter().filter(move |object| match parent {
+                Parent::All => true,
+                Parent::Root => object.parent == 0,
+                Parent::Folder(handle) => object.parent == handle,
+            })
+        }
+    }
+
+    impl ObjectStore for TestStore {
+        fn storage_ids(&self) -> &[u32] {
+            &[STORAGE]
+        }
+
+        async fn storage_info(&mut self, _storage_id: u32) -> Result<StorageInfo<'_>, ResponseCode> {
+            Ok(StorageInfo {
+                storage_type: StorageType::FixedRam,
+                read_only: false,
+                max_capacity: 0x1_0000_0000,
+                free_space: 1024,
+                description: "Flash",
+                volume_label: "",
+            })
+        }
+
+        async fn object_count(&mut self, _storage_id: u32, parent: Parent) -> Result<u32, ResponseCode> {
+            Ok(self.children(parent).count() as u32)
+        }
+
+        async fn object_handles(
+            &mut self,
+            _storage_id: u32,
+            parent: Parent,
+            offset: u32,
+            handles: &mut [u32],
+        ) -> Result<usize, ResponseCode> {
+            let mut n = 0;
+            for (dst, object) in handles.iter_mut().zip(self.children(parent).skip(offset as usize)) {
+                *dst = object.handle;
+                n += 1;
+            }
+            Ok(n)
+        }
+
+        async fn object_info(&mut self, handle: u32) -> Result<ObjectInfo<'_>, ResponseCode> {
+            let object = self.get(handle)?;
+            Ok(ObjectInfo {
+                storage_id: STORAGE,
+                format: object.format,
+                size: object.data.len() as u64,
+                parent: object.parent,
+                name: &object.name,
+                modified: "20240102T030405",
+            })
+        }
+
+        async fn read(&mut self, handle: u32, offset: u64, buf: &mut [u8]) -> Result<usize, ResponseCode> {
+            let data = self.get(handle)?.data.get(offset as usize..).unwrap_or(&[]);
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        async fn create(&mut self, info: &ObjectInfo<'_>) -> Result<u32, ResponseCode> {
+            if info.size > 256 {
+                return Err(ResponseCode::ObjectTooLarge);
+            }
+            Ok(self.add(info.parent, info.format, info.name, b""))
+        }
+
+        async fn write(&mut self, handle: u32, offset: u64, data: &[u8]) -> Result<(), ResponseCode> {
+            let object = self.get(handle)?;
+            assert_eq!(offset, object.data.len() as u64);
+            object.data.extend_from_slice(data).map_err(|_| ResponseCode::StoreFull)
+        }
+
+        async fn delete(&mut self, handle: u32) -> Result<(), ResponseCode> {
+            self.get(handle)?;
+            self.objects.retain(|object| object.handle != handle);
+            Ok(())
+        }
+    }
+
+    fn responder(shared: &ControlShared) -> Responder<'_, TestDriver> {
+        let mut driver = TestDriver::default();
+        Responder {
+            read_ep: driver.alloc_endpoint_out(EndpointType::Bulk, None, 64, 0).unwrap(),
+            write_ep: driver.alloc_endpoint_in(EndpointType::Bulk, None, 64, 0).unwrap(),
+            shared,
+            config: Config {
+                manufacturer: "Embassy",
+                model: "Storage",
+                ..Default::default()
+            },
+            operation: 0,
+            transaction_id: 0,
+            pending_object: None,
+        }
+    }
+
+    fn command(code: u16, transaction_id: u32, params: &[u32]) -> Vec<u8, 64> {
+        let len = HEADER_LEN + 4 * params.len();
+        let mut packet = Vec::new();
+        packet.resize(len, 0).unwrap();
+        write_header(&mut packet, len as u32, CONTAINER_COMMAND, code, transaction_id);
+        for (bytes, param) in packet[HEADER_LEN..].chunks_exact_mut(4).zip(params) {
+            bytes.copy_from_slice(&param.to_le_bytes());
+        }
+        packet
+    }
+
+    /// Data container sent by the host with the container length `len`, split in packets.
+    fn data(code: u16, transaction_id: u32, len: usize, data: &[u8]) -> impl Iterator<Item = Vec<u8, 64>> {
+        let mut container: Vec<u8, 1024> = Vec::new();
+        container.resize(HEADER_LEN, 0).unwrap();
+        write_header(&mut container, len as u32, CONTAINER_DATA, code, transaction_id);
+        container.extend_from_slice(data).unwrap();
+        let packets: Vec<Vec<u8, 64>, 16> = container
+            .chunks(64)
+            .map(|chunk| Vec::from_slice(chunk).unwrap())
+            .collect();
+        // Transfers of a multiple of the packet size end with an empty packet.
+        let zlp = container.len().is_multiple_of(64).then(Vec::new);
+        packets.into_iter().chain(zlp)
+    }
+
+    /// Runs a transaction on the packets sent by the host. Returns the data sent by the responder,
+    /// without its header, and the response code and parameters.
+    fn transaction(
+        responder: &mut Responder<'_, TestDriver>,
+        store: &mut TestStore,
+        packets: impl IntoIterator<Item = Vec<u8, 64>>,
+    ) -> (Vec<u8, 1024>, u16, Vec<u32, 3>) {
+        for packet in packets {
+            let packet = Vec::from_slice(&packet).unwrap();
+            responder.read_ep.packets.push_back(packet).unwrap();
+        }
+        block_on(responder.transaction(store)).unwrap();
+        let written = &mut responder.write_ep.packets;
+        let response = written.pop_back().expect("no response");
+
+        let mut data: Vec<u8, 1024> = Vec::new();
+        if let Some(first) = written.front() {
+            assert_eq!(u16::from_le_bytes([first[4], first[5]]), CONTAINER_DATA);
+            assert_eq!(first[6..8], responder.operation.to_le_bytes());
+            assert_eq!(first[8..12], responder.transaction_id.to_le_bytes());
+            let len = u32::from_le_bytes(first[..4].try_into().unwrap()) as usize;
+            // The data phase ends with a short packet.
+            let count = written.len();
+            for (i, packet) in written.iter().enumerate() {
+                assert!((packet.len() == 64) == (i + 1 < count));
+                data.extend_from_slice(packet).unwrap();
+            }
+            assert_eq!(data.len(), len);
+            data = Vec::from_slice(&data[HEADER_LEN..]).unwrap();
+        }
+        written.clear();
+
+        assert_eq!(u16::from_le_bytes([response[4], response[5]]), CONTAINER_RESPONSE);
+        assert_eq!(response[8..12], responder.transaction_id.to_le_bytes());
+        assert_eq!(
+            u32::from_le_bytes(response[..4].try_into().unwrap()) as usize,
+            response.len()
+        );
+        let code = u16::from_le_bytes([response[6], response[7]]);
+        let params = response[HEADER_LEN..]
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        (data, code, params)
+    }
+
+    /// Runs a transaction on a packet which is not answered.
+    fn ignored(responder: &mut Responder<'_, TestDriver>, store: &mut TestStore, packet: &[u8]) {
+        let packet = Vec::from_slice(packet).unwrap();
+        responder.read_ep.packets.push_back(packet).unwrap();
+        block_on(responder.transaction(store)).unwrap();
+        assert!(responder.write_ep.packets.is_empty());
+    }
+
+    fn open_session(responder: &mut Responder<'_, TestDriver>, store: &mut TestStore) {
+        let (_, code, _) = transaction(responder, store, [command(OP_OPEN_SESSION, 0, &[1])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+    }
+
+    /// ObjectInfo dataset of a file sent by the host.
+    fn object_info(name: &str, size: u32) -> Vec<u8, 128> {
+        let mut buf = [0; 128];
+        let mut w = DatasetWriter::new(&mut buf);
+        w.u32(0); // StorageID
+        w.u16(format::TEXT);
+        w.u16(0); // ProtectionStatus
+        w.u32(size);
+        w.bytes(&[0; 26]); // Thumbnail and image
+        w.u32(0); // ParentObject
+        w.bytes(&[0; 10]); // Association and sequence number
+        w.str(name);
+        w.str(""); // CaptureDate
+        w.str("20240102T030405");
+        w.str(""); // Keywords
+        Vec::from_slice(w.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sessions() {
+        let shared = ControlShared::new();
+        let mut responder = responder(&shared);
+        let mut store = TestStore::new();
+
+        // The device info is sent without a session.
+        let (data, code, params) = transaction(&mut responder, &mut store, [command(OP_GET_DEVICE_INFO, 0, &[])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert!(params.is_empty());
+        assert_eq!(data[..8], [100, 0, 6, 0, 0, 0, 100, 0]);
+        let mut r = DatasetReader::new(&data[8..]);
+        let mut s = [0; 32];
+        assert_eq!(r.str(&mut s), Some("microsoft.com: 1.0; "));
+        assert_eq!(r.u16(), Some(0)); // FunctionalMode
+        assert_eq!(r.u32(), Some(OPERATIONS.len() as u32));
+
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_GET_STORAGE_IDS, 1, &[])]);
+        assert_eq!(code, ResponseCode::SessionNotOpen as u16);
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_OPEN_SESSION, 2, &[0])]);
+        assert_eq!(code, ResponseCode::InvalidParameter as u16);
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_OPEN_SESSION, 3, &[1])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_OPEN_SESSION, 4, &[2])]);
+        assert_eq!(code, ResponseCode::SessionAlreadyOpen as u16);
+
+        let (data, code, _) = transaction(&mut responder, &mut store, [command(OP_GET_STORAGE_IDS, 5, &[])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(data[..], [1, 0, 0, 0, 0x01, 0x00, 0x01, 0x00]);
+
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_CLOSE_SESSION, 6, &[])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert!(!shared.session_open.load(Ordering::Relaxed));
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_GET_STORAGE_IDS, 7, &[])]);
+        assert_eq!(code, ResponseCode::SessionNotOpen as u16);
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let shared = ControlShared::new();
+        let mut responder = responder(&shared);
+        let mut store = TestStore::new();
+        open_session(&mut responder, &mut store);
+
+        // Containers shorter than a header, or which are not commands, are ignored.
+        ignored(&mut responder, &mut store, &command(OP_GET_STORAGE_IDS, 1, &[])[..8]);
+        let mut packet = command(OP_GET_STORAGE_IDS, 1, &[]);
+        packet[4] = CONTAINER_DATA as u8;
+        ignored(&mut responder, &mut store, &packet);
+        // The empty packet ending a data phase of a multiple of the packet size.
+        ignored(&mut responder, &mut store, &[]);
+
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(0x1234, 2, &[])]);
+        assert_eq!(code, ResponseCode::OperationNotSupported as u16);
+
+        // Parameters after the fifth are ignored.
+        let (_, code, params) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_NUM_OBJECTS, 3, &[STORAGE, 0, ROOT, 0, 0, 7, 8])],
+        );
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(params[..], [2]);
+
+        // Missing parameters are 0.
+        let (_, code, params) = transaction(&mut responder, &mut store, [command(OP_GET_NUM_OBJECTS, 4, &[STORAGE])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(params[..], [3]);
+    }
+
+    #[test]
+    fn store_dispatch() {
+        let shared = ControlShared::new();
+        let mut responder = responder(&shared);
+        let mut store = TestStore::new();
+        open_session(&mut responder, &mut store);
+
+        let (data, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_STORAGE_INFO, 1, &[STORAGE])],
+        );
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(
+            data[..26],
+            [
+                0x03, 0x00, 0x02, 0x00, 0x00, 0x00, // Type, filesystem, access
+                0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // MaxCapacity
+                0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // FreeSpace
+                0xFF, 0xFF, 0xFF, 0xFF, // FreeSpaceInImages
+            ]
+        );
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_GET_STORAGE_INFO, 2, &[5])]);
+        assert_eq!(code, ResponseCode::InvalidStorageId as u16);
+
+        let (_, _, params) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_NUM_OBJECTS, 3, &[STORAGE, 0, 1])],
+        );
+        assert_eq!(params[..], [1]);
+        let (data, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_OBJECT_HANDLES, 4, &[STORAGE, 0, ROOT])],
+        );
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(data[..], [2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+
+        let (data, code, _) = transaction(&mut responder, &mut store, [command(OP_GET_OBJECT_INFO, 5, &[1])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        // The storage, parent and association type are not parsed from datasets sent by hosts.
+        assert_eq!(data[..4], STORAGE.to_le_bytes());
+        assert_eq!(data[42..44], ASSOCIATION_GENERIC_FOLDER.to_le_bytes());
+        let (mut name, mut modified) = ([0; 16], [0; 16]);
+        let info = parse_object_info(&data, &mut name, &mut modified).unwrap();
+        assert_eq!((info.format, info.size), (format::ASSOCIATION, 0));
+        assert_eq!((info.name, info.modified), ("docs", "20240102T030405"));
+
+        let (data, code, params) = transaction(&mut responder, &mut store, [command(OP_GET_OBJECT, 6, &[2])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!((&data[..], &params[..]), (&b"hello"[..], &[5][..]));
+        let (data, _, params) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_PARTIAL_OBJECT, 7, &[2, 1, 3])],
+        );
+        assert_eq!((&data[..], &params[..]), (&b"ell"[..], &[3][..]));
+        let (data, _, params) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_PARTIAL_OBJECT, 8, &[2, 4, 100])],
+        );
+        assert_eq!((&data[..], &params[..]), (&b"o"[..], &[1][..]));
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_PARTIAL_OBJECT, 9, &[2, 6, 1])],
+        );
+        assert_eq!(code, ResponseCode::InvalidParameter as u16);
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_GET_OBJECT, 10, &[9])]);
+        assert_eq!(code, ResponseCode::InvalidObjectHandle as u16);
+
+        let (data, _, _) = transaction(
+            &mut responder,
+            &mut store,
+            [command(
+                OP_GET_OBJECT_PROP_VALUE,
+                11,
+                &[3, PROP_OBJECT_FILE_NAME as u32],
+            )],
+        );
+        assert_eq!(data[..], [6, b'b', 0, b'.', 0, b't', 0, b'x', 0, b't', 0, 0, 0]);
+        let (data, _, _) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_OBJECT_PROP_VALUE, 12, &[3, PROP_PARENT_OBJECT as u32])],
+        );
+        assert_eq!(data[..], [1, 0, 0, 0]);
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_OBJECT_PROP_VALUE, 13, &[3, 0xDC44])],
+        );
+        assert_eq!(code, ResponseCode::InvalidObjectPropCode as u16);
+
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_DELETE_OBJECT, 14, &[3])]);
+        assert_eq!(code, ResponseCode::Ok as u16);
+        let (_, _, params) = transaction(
+            &mut responder,
+            &mut store,
+            [command(OP_GET_NUM_OBJECTS, 15, &[STORAGE])],
+        );
+        assert_eq!(params[..], [2]);
+        let (_, code, _) = transaction(&mut responder, &mut store, [command(OP_DELETE_OBJECT, 16, &[3])]);
+        assert_eq!(code, ResponseCode::InvalidObjectHandle as u16);
+    }
+
+    #[test]
+    fn send_object() {
+        let shared = ControlShared::new();
+        let mut responder = responder(&shared);
+        let mut store = TestStore::new();
+        open_session(&mut responder, &mut store);
+
+        // Objects are only sent after their info.
+        let content = [0x5A; 100];
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 1, &[])).chain(data(OP_SEND_OBJECT, 1, HEADER_LEN + 100, &content)),
+        );
+        assert_eq!(code, ResponseCode::NoValidObjectInfo as u16);
+        assert!(responder.read_ep.packets.is_empty());
+
+        let info = object_info("new.txt", 100);
+        let (_, code, params) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT_INFO, 2, &[0, ROOT])).chain(data(
+                OP_SEND_OBJECT_INFO,
+                2,
+                HEADER_LEN + info.len(),
+                &info,
+            )),
+        );
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(params[..], [STORAGE, 0, 4]);
+
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 3, &[])).chain(data(OP_SEND_OBJECT, 3, HEADER_LEN + 100, &content)),
+        );
+        assert_eq!(code, ResponseCode::Ok as u16);
+        let object = store.get(4).unwrap();
+        assert_eq!(
+            (object.parent, object.format, &object.name[..]),
+            (0, format::TEXT, "new.txt")
+        );
+        assert_eq!(object.data[..], content);
+
+        // The info is used once.
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 4, &[])).chain(data(OP_SEND_OBJECT, 4, HEADER_LEN + 100, &content)),
+        );
+        assert_eq!(code, ResponseCode::NoValidObjectInfo as u16);
+
+        // An object filling the first packet, which is not followed by an empty packet.
+        let info = object_info("full.txt", 52);
+        let packets = once(command(OP_SEND_OBJECT_INFO, 5, &[STORAGE, 1])).chain(data(
+            OP_SEND_OBJECT_INFO,
+            5,
+            HEADER_LEN + info.len(),
+            &info,
+        ));
+        let (_, _, params) = transaction(&mut responder, &mut store, packets);
+        assert_eq!(params[..], [STORAGE, 1, 5]);
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 6, &[])).chain(data(OP_SEND_OBJECT, 6, HEADER_LEN + 52, &content[..52])),
+        );
+        assert_eq!(code, ResponseCode::Ok as u16);
+        assert_eq!(store.get(5).unwrap().data[..], content[..52]);
+        // The empty packet ending the data phase is read as the next transaction.
+        assert_eq!(responder.read_ep.packets.len(), 1);
+        block_on(responder.transaction(&mut store)).unwrap();
+        assert!(responder.write_ep.packets.is_empty());
+    }
+
+    #[test]
+    fn truncated_containers() {
+        let shared = ControlShared::new();
+        let mut responder = responder(&shared);
+        let mut store = TestStore::new();
+        open_session(&mut responder, &mut store);
+
+        let info = object_info("short.txt", 100);
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT_INFO, 1, &[0, ROOT])).chain(data(
+                OP_SEND_OBJECT_INFO,
+                1,
+                HEADER_LEN + 40,
+                &info[..40],
+            )),
+        );
+        assert_eq!(code, ResponseCode::InvalidParameter as u16);
+        // A data container shorter than its header.
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            [
+                command(OP_SEND_OBJECT_INFO, 2, &[0, ROOT]),
+                Vec::from_slice(&[0; 8]).unwrap(),
+            ],
+        );
+        assert_eq!(code, ResponseCode::InvalidParameter as u16);
+        assert_eq!(store.objects.len(), 3);
+
+        // The host announces 100 bytes, and ends the data phase after 50.
+        let (_, _, params) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT_INFO, 3, &[0, ROOT])).chain(data(
+                OP_SEND_OBJECT_INFO,
+                3,
+                HEADER_LEN + info.len(),
+                &info,
+            )),
+        );
+        let handle = params[2];
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 4, &[])).chain(data(OP_SEND_OBJECT, 4, HEADER_LEN + 100, &[1; 50])),
+        );
+        assert_eq!(code, ResponseCode::IncompleteTransfer as u16);
+        assert_eq!(store.get(handle).unwrap().data.len(), 50);
+    }
+
+    #[test]
+    fn oversized_containers() {
+        let shared = ControlShared::new();
+        let mut responder = responder(&shared);
+        let mut store = TestStore::new();
+        open_session(&mut responder, &mut store);
+
+        // Datasets larger than the buffer are read whole, and rejected.
+        let dataset = [0; DATASET_SIZE + 100];
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT_INFO, 1, &[0, ROOT])).chain(data(
+                OP_SEND_OBJECT_INFO,
+                1,
+                HEADER_LEN + dataset.len(),
+                &dataset,
+            )),
+        );
+        assert_eq!(code, ResponseCode::GeneralError as u16);
+        assert!(responder.read_ep.packets.is_empty());
+
+        // Objects larger than their info.
+        let info = object_info("big.txt", 10);
+        let (_, _, params) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT_INFO, 2, &[0, ROOT])).chain(data(
+                OP_SEND_OBJECT_INFO,
+                2,
+                HEADER_LEN + info.len(),
+                &info,
+            )),
+        );
+        let handle = params[2];
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 3, &[])).chain(data(OP_SEND_OBJECT, 3, HEADER_LEN + 20, &[1; 20])),
+        );
+        assert_eq!(code, ResponseCode::IncompleteTransfer as u16);
+        assert_eq!(store.get(handle).unwrap().data.len(), 20);
+
+        // Objects rejected by the store are read whole.
+        let info = object_info("huge.txt", 1000);
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT_INFO, 4, &[0, ROOT])).chain(data(
+                OP_SEND_OBJECT_INFO,
+                4,
+                HEADER_LEN + info.len(),
+                &info,
+            )),
+        );
+        assert_eq!(code, ResponseCode::ObjectTooLarge as u16);
+        let (_, code, _) = transaction(
+            &mut responder,
+            &mut store,
+            once(command(OP_SEND_OBJECT, 5, &[])).chain(data(OP_SEND_OBJECT, 5, HEADER_LEN + 490, &[1; 490])),
+        );
+        assert_eq!(code, ResponseCode::NoValidObjectInfo as u16);
+        assert!(responder.read_ep.packets.is_empty());
+    }
+
+    #[test]
+    fn cancel() {
+        let shared = ControlShared::new();
+        let mut control = Control {
+            interface: InterfaceNumber::new(INTERFACE as u8),
+            shared: &shared,
+        };
+        let request = |request: u8, length: u16| {
+            let mut buf = [0xA1, request, 0, 0, 0, 0, 0, 0];
+            if request != REQ_GET_DEVICE_STATUS {
+                buf[0] = 0x21;
+            }
+            buf[4..6].copy_from_slice(&INTERFACE.to_le_bytes());
+            buf[6..8].copy_from_slice(&length.to_le_bytes());
+            Request::parse(&buf)
+        };
+        let status = |control: &mut Control| {
+            let mut buf = [0; 64];
+            match control.control_in(request(REQ_GET_DEVICE_STATUS, 64), &mut buf) {
+                Some(InResponse::Accepted(data)) => <[u8; 4]>::try_from(data).unwrap(),
+                _ => panic!("status rejected"),
+            }
+        };
+
+        assert_eq!(status(&mut control), [4, 0, 0x01, 0x20]);
+        let data = [0x01, 0x40, 1, 0, 0, 0];
+        assert_eq!(
+            control.control_out(request(REQ_CANCEL, 4), &data[..4]),
+            Some(OutResponse::Rejected)
+        );
+        assert_eq!(
+            control.control_out(request(REQ_CANCEL, 6), &data),
+            Some(OutResponse::Accepted)
+        );
+        assert!(shared.cancel.load(Ordering::Relaxed));
+        assert_eq!(status(&mut control), [4, 0, 0x19, 0x20]);
+
+        // Device reset closes the session.
+        shared.busy.store(false, Ordering::Relaxed);
+        shared.session_open.store(true, Ordering::Relaxed);
+        assert_eq!(
+            control.control_out(request(REQ_DEVICE_RESET, 0), &[]),
+            Some(OutResponse::Accepted)
+        );
+        assert!(!shared.session_open.load(Ordering::Relaxed));
+        assert_eq!(status(&mut control), [4, 0, 0x19, 0x20]);
+    }
+}
@@ -0,0 +1,191 @@
+//! Object store of the MTP responder.
+
+/// Response code of an operation [PTP 10.3, MTP F.3].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u16)]
+#[allow(missing_docs)]
+pub enum ResponseCode {
+    Ok = 0x2001,
+    GeneralError = 0x2002,
+    SessionNotOpen = 0x2003,
+    InvalidTransactionId = 0x2004,
+    OperationNotSupported = 0x2005,
+    ParameterNotSupported = 0x2006,
+    IncompleteTransfer = 0x2007,
+    InvalidStorageId = 0x2008,
+    InvalidObjectHandle = 0x2009,
+    DevicePropNotSupported = 0x200A,
+    InvalidObjectFormatCode = 0x200B,
+    StoreFull = 0x200C,
+    ObjectWriteProtected = 0x200D,
+    StoreReadOnly = 0x200E,
+    AccessDenied = 0x200F,
+    StoreNotAvailable = 0x2013,
+    SpecificationByFormatUnsupported = 0x2014,
+    NoValidObjectInfo = 0x2015,
+    DeviceBusy = 0x2019,
+    InvalidParentObject = 0x201A,
+    InvalidParameter = 0x201D,
+    SessionAlreadyOpen = 0x201E,
+    TransactionCancelled = 0x201F,
+    InvalidObjectPropCode = 0xA801,
+    ObjectTooLarge = 0xA809,
+}
+
+/// Object format codes [PTP 6.2].
+pub mod format {
+    /// Undefined, for files of unknown formats.
+    pub const UNDEFINED: u16 = 0x3000;
+    /// Association, for folders.
+    pub const ASSOCIATION: u16 = 0x3001;
+    /// Script.
+    pub const SCRIPT: u16 = 0x3002;
+    /// Executable.
+    pub const EXECUTABLE: u16 = 0x3003;
+    /// Text.
+    pub const TEXT: u16 = 0x3004;
+    /// HTML.
+    pub const HTML: u16 = 0x3005;
+    /// WAV audio.
+    pub const WAV: u16 = 0x3008;
+    /// MP3 audio.
+    pub const MP3: u16 = 0x3009;
+    /// JPEG image.
+    pub const EXIF_JPEG: u16 = 0x3801;
+    /// BMP image.
+    pub const BMP: u16 = 0x3804;
+    /// PNG image.
+    pub const PNG: u16 = 0x380B;
+}
+
+/// Type of a storage [PTP 5.5.3].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u16)]
+pub enum StorageType {
+    /// Fixed read-only memory.
+    FixedRom = 0x0001,
+    /// Removable read-only memory.
+    RemovableRom = 0x0002,
+    /// Fixed memory, such as internal flash.
+    FixedRam = 0x0003,
+    /// Removable memory, such as a memory card.
+    RemovableRam = 0x0004,
+}
+
+/// Description of a storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StorageInfo<'a> {
+    /// Type of the storage.
+    pub storage_type: StorageType,
+    /// The host cannot write nor delete objects.
+    pub read_only: bool,
+    /// Capacity, in bytes.
+    pub max_capacity: u64,
+    /// Free space, in bytes.
+    pub free_space: u64,
+    /// Description, shown by hosts as the name of the storage.
+    pub description: &'a str,
+    /// Volume label.
+    pub volume_label: &'a str,
+}
+
+/// Description of an object: a file or a folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ObjectInfo<'a> {
+    /// Storage of the object.
+    pub storage_id: u32,
+    /// Format of the object, one of [`format`]. Folders are [`format::ASSOCIATION`].
+    pub format: u16,
+    /// Size, in bytes.
+    pub size: u64,
+    /// Handle of the folder of the object, 0 for the root folder.
+    pub parent: u32,
+    /// File name.
+    pub name: &'a str,
+    /// Modification date, as `YYYYMMDDThhmmss`, or empty.
+    pub modified: &'a str,
+}
+
+impl ObjectInfo<'_> {
+    /// Returns whether the object is a folder.
+    pub fn is_folder(&self) -> bool {
+        self.format == format::ASSOCIATION
+    }
+}
+
+/// Objects listed by [`ObjectStore::object_handles`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Parent {
+    /// All the objects of the storage.
+    All,
+    /// The objects of the root folder.
+    Root,
+    /// The objects of this folder.
+    Folder(u32),
+}
+
+/// Storage ID selecting all the storages, in [`ObjectStore::object_count`] and
+/// [`ObjectStore::object_handles`].
+pub const ALL_STORAGES: u32 = 0xFFFF_FFFF;
+
+/// Files and folders exposed by the MTP responder.
+///
+/// Objects are identified by handles, which must be unique across storages, and different from
+/// 0 and `0xFFFFFFFF`. Storages are identified by IDs, different from 0 and `0xFFFFFFFF`, such as
+/// `0x00010001` for the first storage.
+///
+/// Stores are read-only by default: implement [`create`](Self::create),
+/// [`write`](Self::write) and [`delete`](Self::delete) to let the host modify them.
+pub trait ObjectStore {
+    /// Returns the IDs of the storages.
+    fn storage_ids(&self) -> &[u32];
+
+    /// Returns the description of a storage.
+    async fn storage_info(&mut self, storage_id: u32) -> Result<StorageInfo<'_>, ResponseCode>;
+
+    /// Returns the number of objects of the storage in `parent`.
+    async fn object_count(&mut self, storage_id: u32, parent: Parent) -> Result<u32, ResponseCode>;
+
+    /// Writes the handles of the objects of the storage in `parent` to `handles`, skipping the
+    /// first `offset` ones. Returns the number of handles written.
+    ///
+    /// The objects must be listed in the same order, and be as many as [`object_count`](Self::object_count).
+    async fn object_handles(
+        &mut self,
+        storage_id: u32,
+        parent: Parent,
+        offset: u32,
+        handles: &mut [u32],
+    ) -> Result<usize, ResponseCode>;
+
+    /// Returns the description of an object.
+    async fn object_info(&mut self, handle: u32) -> Result<ObjectInfo<'_>, ResponseCode>;
+
+    /// Reads the data of an object from `offset` to `buf`. Returns the number of bytes read, which
+    /// is only smaller than `buf` at the end of the object.
+    async fn read(&mut self, handle: u32, offset: u64, buf: &mut [u8]) -> Result<usize, ResponseCode>;
+
+    /// Creates an object, which data is then written with [`write`](Self::write). Returns its
+    /// handle.
+    async fn create(&mut self, info: &ObjectInfo<'_>) -> Result<u32, ResponseCode> {
+        let _ = info;
+        Err(ResponseCode::StoreReadOnly)
+    }
+
+    /// Writes the data of an object created by [`create`](Self::create), from `offset`.
+    async fn write(&mut self, handle: u32, offset: u64, data: &[u8]) -> Result<(), ResponseCode> {
+        let _ = (handle, offset, data);
+        Err(ResponseCode::StoreReadOnly)
+    }
+
+    /// Deletes an object. Folders are deleted with their objects.
+    async fn delete(&mut self, handle: u32) -> Result<(), ResponseCode> {
+        let _ = handle;
+        Err(ResponseCode::ObjectWriteProtected)
+    }
+}
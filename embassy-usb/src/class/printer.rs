@@ -0,0 +1,420 @@
+//! USB Printer class implementation.
+//!
+//! Printers implementing this class are handled by the standard printer drivers of hosts, such
+//! as the `usblp` driver of Linux and CUPS, without custom drivers. The host identifies the
+//! printer, and picks a driver for it, with its IEEE 1284 device ID, such as
+//! `MFG:Embassy;MDL:Label Printer;CMD:ZPL;CLS:PRINTER;`.
+//!
+//! Print jobs, in the command language of the printer, are received with
+//! [`PrinterClass::read_packet`]. Bidirectional printers can also send status data back to the
+//! host, with [`PrinterClass::write_packet`]. The port status, read by the host, is set with
+//! [`PrinterClass::set_port_status`].
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// Printer interface class.
+const USB_CLASS_PRINTER: u8 = 0x07;
+const PRINTER_SUBCLASS: u8 = 0x01;
+const PROTOCOL_UNIDIRECTIONAL: u8 = 0x01;
+const PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+// Printer class requests [Printer 4.2]
+const REQ_GET_DEVICE_ID: u8 = 0x00;
+const REQ_GET_PORT_STATUS: u8 = 0x01;
+const REQ_SOFT_RESET: u8 = 0x02;
+
+/// Printer error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The given buffer was too small for the received packet.
+    BufferOverflow,
+    /// The endpoint is disabled.
+    Disabled,
+    /// The host reset the printer, discarding the data in progress.
+    Reset,
+}
+
+impl From<EndpointError> for Error {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => Error::BufferOverflow,
+            EndpointError::Disabled => Error::Disabled,
+        }
+    }
+}
+
+/// Configuration of the [`PrinterClass`].
+pub struct Config<'a> {
+    /// IEEE 1284 device ID, returned to GET_DEVICE_ID requests, such as
+    /// `MFG:Embassy;MDL:Label Printer;CMD:ZPL;CLS:PRINTER;`.
+    ///
+    /// The control buffer must hold it, with two more bytes.
+    pub device_id: &'a str,
+
+    /// Max packet size of the bulk endpoints.
+    ///
+    /// Full-speed devices must use 8, 16, 32 or 64 bytes, high-speed ones 512 bytes.
+    pub max_packet_size: u16,
+
+    /// Add a bulk IN endpoint, to send status data back to the host.
+    pub bidirectional: bool,
+}
+
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        Self {
+            device_id: "",
+            max_packet_size: 64,
+            bidirectional: false,
+        }
+    }
+}
+
+/// Port status of the printer, read by the host with GET_PORT_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortStatus {
+    /// The printer is out of paper.
+    pub paper_empty: bool,
+    /// The printer is selected, online.
+    pub selected: bool,
+    /// The printer is in an error state.
+    pub error: bool,
+}
+
+impl Default for PortStatus {
+    fn default() -> Self {
+        Self {
+            paper_empty: false,
+            selected: true,
+            error: false,
+        }
+    }
+}
+
+impl PortStatus {
+    fn to_byte(self) -> u8 {
+        (self.paper_empty as u8) << 5 | (self.selected as u8) << 4 | (!self.error as u8) << 3
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        Self {
+            paper_empty: byte & 0x20 != 0,
+            selected: byte & 0x10 != 0,
+            error: byte & 0x08 == 0,
+        }
+    }
+}
+
+/// Internal state for the printer class.
+pub struct State<'a> {
+    control: Option<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: None,
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Implementation of a USB printer.
+pub struct PrinterClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: Option<D::EndpointIn>,
+    shared: &'d ControlShared,
+}
+
+struct Control<'a> {
+    interface: InterfaceNumber,
+    device_id: &'a str,
+    shared: &'a ControlShared,
+}
+
+/// Shared data between Control and PrinterClass
+struct ControlShared {
+    port_status: AtomicU8,
+    /// The host reset the printer, not yet reported to the application.
+    reset: AtomicBool,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        ControlShared {
+            port_status: AtomicU8::new(0x18), // Selected, no error.
+            reset: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+
+    fn take_reset(&self) -> bool {
+        let reset = self.reset.load(Ordering::Relaxed);
+        if reset {
+            self.reset.store(false, Ordering::Relaxed);
+        }
+        reset
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                u8::from(self.interface) as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SOFT_RESET => {
+                debug!("printer: soft reset");
+                self.shared.reset.store(true, Ordering::Relaxed);
+                self.shared.waker.borrow_mut().wake();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+
+        match req.request {
+            // The interface is in the high byte of wIndex, the alternate setting in the low byte.
+            REQ_GET_DEVICE_ID if (req.index >> 8) as u8 == u8::from(self.interface) => {
+                let len = self.device_id.len() + 2;
+                buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+                buf[2..len].copy_from_slice(self.device_id.as_bytes());
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            REQ_GET_PORT_STATUS if req.index == u8::from(self.interface) as u16 => {
+                buf[0] = self.shared.port_status.load(Ordering::Relaxed);
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'d, D: Driver<'d>> PrinterClass<'d, D> {
+    /// Creates a new PrinterClass with the provided configuration.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        assert!(builder.control_buf_len() >= config.device_id.len() + 2);
+
+        let protocol = if config.bidirectional {
+            PROTOCOL_BIDIRECTIONAL
+        } else {
+            PROTOCOL_UNIDIRECTIONAL
+        };
+
+        let mut func = builder.function(USB_CLASS_PRINTER, PRINTER_SUBCLASS, protocol);
+        let mut iface = func.interface();
+        let interface = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_PRINTER, PRINTER_SUBCLASS, protocol, None);
+
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = config
+            .bidirectional
+            .then(|| alt.endpoint_bulk_in(None, config.max_packet_size));
+
+        drop(func);
+
+        state.control = Some(Control {
+            interface,
+            device_id: config.device_id,
+            shared: &state.shared,
+        });
+        builder.handler(state.control.as_mut().unwrap());
+
+        PrinterClass {
+            read_ep,
+            write_ep,
+            shared: &state.shared,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Gets the port status, returned to GET_PORT_STATUS requests.
+    pub fn port_status(&self) -> PortStatus {
+        PortStatus::from_byte(self.shared.port_status.load(Ordering::Relaxed))
+    }
+
+    /// Sets the port status, returned to GET_PORT_STATUS requests.
+    pub fn set_port_status(&self, status: PortStatus) {
+        self.shared.port_status.store(status.to_byte(), Ordering::Relaxed);
+    }
+
+    /// Reads a packet of print data from the host.
+    ///
+    /// Returns [`Error::Reset`] once when the host resets the printer, to discard the job in
+    /// progress.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let shared = self.shared;
+        let reset = poll_fn(|cx| {
+            if shared.take_reset() {
+                Poll::Ready(())
+            } else {
+                shared.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        });
+
+        match select(self.read_ep.read(buf), reset).await {
+            Either::First(n) => Ok(n?),
+            Either::Second(()) => Err(Error::Reset),
+        }
+    }
+
+    /// Writes a packet of status data to the host. Only for bidirectional printers.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), Error> {
+        let ep = self.write_ep.as_mut().expect("not a bidirectional printer");
+        ep.write(data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+
+    const INTERFACE: u8 = 1;
+    const DEVICE_ID: &str = "MFG:Embassy;MDL:Label Printer;CMD:ZPL;CLS:PRINTER;";
+
+    fn request(request_type: u8, request: u8, index: u16, length: u16) -> Request {
+        let mut buf = [request_type, request, 0, 0, 0, 0, 0, 0];
+        buf[4..6].copy_from_slice(&index.to_le_bytes());
+        buf[6..8].copy_from_slice(&length.to_le_bytes());
+        Request::parse(&buf)
+    }
+
+    /// Send a GET request, returning the response if it was handled.
+    fn get(control: &mut Control, request_code: u8, index: u16) -> Option<Vec<u8, 64>> {
+        let mut buf = [0; 64];
+        match control.control_in(request(0xA1, request_code, index, 64), &mut buf)? {
+            InResponse::Accepted(data) => Some(Vec::from_slice(data).unwrap()),
+            InResponse::Rejected => panic!("request rejected"),
+        }
+    }
+
+    #[test]
+    fn device_id() {
+        let shared = ControlShared::new();
+        let mut control = Control {
+            interface: InterfaceNumber::new(INTERFACE),
+            device_id: DEVICE_ID,
+            shared: &shared,
+        };
+
+        // The length is big-endian, and includes itself.
+        let data = get(&mut control, REQ_GET_DEVICE_ID, 0x0100).unwrap();
+        assert_eq!(data[..2], [0, DEVICE_ID.len() as u8 + 2]);
+        assert_eq!(data[2..], *DEVICE_ID.as_bytes());
+        // The alternate setting is in the low byte of wIndex.
+        let data = get(&mut control, REQ_GET_DEVICE_ID, 0x0102).unwrap();
+        assert_eq!(data.len(), DEVICE_ID.len() + 2);
+
+        assert!(get(&mut control, REQ_GET_DEVICE_ID, 0x0000).is_none());
+        assert!(get(&mut control, REQ_GET_DEVICE_ID, INTERFACE as u16).is_none());
+
+        let mut control = Control {
+            interface: InterfaceNumber::new(INTERFACE),
+            device_id: "",
+            shared: &shared,
+        };
+        let data = get(&mut control, REQ_GET_DEVICE_ID, 0x0100).unwrap();
+        assert_eq!(data[..], [0, 2]);
+    }
+
+    #[test]
+    fn port_status() {
+        let shared = ControlShared::new();
+        let mut control = Control {
+            interface: InterfaceNumber::new(INTERFACE),
+            device_id: DEVICE_ID,
+            shared: &shared,
+        };
+
+        assert_eq!(PortStatus::from_byte(0x18), PortStatus::default());
+        let data = get(&mut control, REQ_GET_PORT_STATUS, INTERFACE as u16).unwrap();
+        assert_eq!(data[..], [0x18]);
+
+        let status = PortStatus {
+            paper_empty: true,
+            selected: false,
+            error: true,
+        };
+        assert_eq!(status.to_byte(), 0x20);
+        assert_eq!(PortStatus::from_byte(0x20), status);
+        shared.port_status.store(status.to_byte(), Ordering::Relaxed);
+        let data = get(&mut control, REQ_GET_PORT_STATUS, INTERFACE as u16).unwrap();
+        assert_eq!(data[..], [0x20]);
+
+        assert!(get(&mut control, REQ_GET_PORT_STATUS, 0).is_none());
+        // Standard requests are not handled by the class.
+        let mut buf = [0; 64];
+        let req = request(0x81, REQ_GET_PORT_STATUS, INTERFACE as u16, 64);
+        assert!(control.control_in(req, &mut buf).is_none());
+    }
+
+    #[test]
+    fn soft_reset() {
+        let shared = ControlShared::new();
+        let mut control = Control {
+            interface: InterfaceNumber::new(INTERFACE),
+            device_id: DEVICE_ID,
+            shared: &shared,
+        };
+
+        assert!(!shared.take_reset());
+        let req = request(0x21, REQ_SOFT_RESET, INTERFACE as u16, 0);
+        assert_eq!(control.control_out(req, &[]), Some(OutResponse::Accepted));
+        // The reset is reported once.
+        assert!(shared.take_reset());
+        assert!(!shared.take_reset());
+
+        let req = request(0x21, REQ_SOFT_RESET, 0, 0);
+        assert_eq!(control.control_out(req, &[]), None);
+        let req = request(0x21, 0x10, INTERFACE as u16, 0);
+        assert_eq!(control.control_out(req, &[]), Some(OutResponse::Rejected));
+    }
+}
@@ -0,0 +1,1038 @@
+//! USB Test and Measurement Class (USBTMC) implementation, with the USB488 subclass.
+//!
+//! Instruments implementing this class are found by VISA libraries and by the `usbtmc` driver of
+//! Linux, without custom drivers. With the USB488 subclass, they are seen as IEEE 488.2 (SCPI)
+//! instruments, supporting triggers, service requests and the status byte.
+//!
+//! Messages are exchanged with [`UsbtmcClass::read_message`] and [`UsbtmcClass::write_response`].
+//! The abort and clear requests of the host are handled by the class; they make the message in
+//! progress fail with [`Error::Aborted`].
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use core::task::Poll;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// Application specific interface class.
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const USBTMC_SUBCLASS: u8 = 0x03;
+const USBTMC_PROTOCOL: u8 = 0x00;
+const USB488_PROTOCOL: u8 = 0x01;
+
+// USBTMC requests [USBTMC 4.2.1]
+const REQ_INITIATE_ABORT_BULK_OUT: u8 = 1;
+const REQ_CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+const REQ_INITIATE_ABORT_BULK_IN: u8 = 3;
+const REQ_CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+const REQ_INITIATE_CLEAR: u8 = 5;
+const REQ_CHECK_CLEAR_STATUS: u8 = 6;
+const REQ_GET_CAPABILITIES: u8 = 7;
+const REQ_INDICATOR_PULSE: u8 = 64;
+
+// USB488 requests [USB488 4.3]
+const REQ_READ_STATUS_BYTE: u8 = 128;
+const REQ_REN_CONTROL: u8 = 160;
+const REQ_GO_TO_LOCAL: u8 = 161;
+const REQ_LOCAL_LOCKOUT: u8 = 162;
+
+// USBTMC_status values [USBTMC Table 16, USB488 Table 9]
+const STATUS_SUCCESS: u8 = 0x01;
+const STATUS_PENDING: u8 = 0x02;
+const STATUS_INTERRUPT_IN_BUSY: u8 = 0x20;
+const STATUS_FAILED: u8 = 0x80;
+const STATUS_TRANSFER_NOT_IN_PROGRESS: u8 = 0x81;
+
+// Bulk message identifiers [USBTMC Table 2, USB488 Table 2]
+const DEV_DEP_MSG_OUT: u8 = 1;
+const REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+const DEV_DEP_MSG_IN: u8 = 2;
+const VENDOR_SPECIFIC_OUT: u8 = 126;
+const TRIGGER: u8 = 128;
+
+/// Length of the header of bulk messages.
+const HEADER_LEN: usize = 12;
+
+/// Maximum supported packet size of the bulk endpoints.
+const MAX_PACKET_SIZE: usize = 512;
+
+/// Length of the GET_CAPABILITIES response.
+const CAPABILITIES_LEN: usize = 24;
+
+/// Request service bit of the status byte.
+const STB_RQS: u8 = 0x40;
+
+/// USBTMC error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The given buffer was too small for the received message, which was discarded.
+    BufferOverflow,
+    /// The endpoint is disabled.
+    Disabled,
+    /// The host aborted the transfer, or cleared the device.
+    Aborted,
+}
+
+impl From<EndpointError> for Error {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => Error::BufferOverflow,
+            EndpointError::Disabled => Error::Disabled,
+        }
+    }
+}
+
+/// Configuration of the [`UsbtmcClass`].
+pub struct Config {
+    /// Max packet size of the bulk endpoints, up to 512 bytes.
+    ///
+    /// Full-speed devices must use 64 bytes, high-speed ones 512 bytes.
+    pub max_packet_size: u16,
+
+    /// Implement the USB488 subclass, for IEEE 488.2 instruments.
+    ///
+    /// This adds an interrupt endpoint, for service requests and the status byte, and the
+    /// trigger and remote/local requests.
+    pub usb488: bool,
+
+    /// Report the instrument as SCPI compliant. Only for USB488 devices.
+    pub scpi: bool,
+
+    /// The instrument is talk-only: it only sends messages.
+    pub talk_only: bool,
+
+    /// The instrument is listen-only: it only receives messages.
+    pub listen_only: bool,
+
+    /// Support the indicator pulse request, reported as [`Message::IndicatorPulse`].
+    pub indicator_pulse: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 64,
+            usb488: true,
+            scpi: true,
+            talk_only: false,
+            listen_only: false,
+            indicator_pulse: false,
+        }
+    }
+}
+
+/// Message received from the host.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Message<'a> {
+    /// A whole device dependent message, such as a SCPI command.
+    Data(&'a [u8]),
+    /// The host reads a device dependent message, which must be sent with
+    /// [`UsbtmcClass::write_response`].
+    ReadRequest(ReadRequest),
+    /// The host triggered the instrument (USB488 TRIGGER, like the GPIB GET command).
+    Trigger,
+    /// The host asks the instrument to make itself visible, for example by blinking a light.
+    IndicatorPulse,
+}
+
+/// Request of the host to read a device dependent message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReadRequest {
+    tag: u8,
+    /// Maximum length of the response.
+    pub max_len: u32,
+    /// The response ends after this character, if enabled by the host.
+    pub term_char: Option<u8>,
+}
+
+/// Internal state for USBTMC
+pub struct State<'a> {
+    control: Option<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: None,
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Implementation of a USBTMC instrument.
+pub struct UsbtmcClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    interrupt_ep: Option<D::EndpointIn>,
+    shared: &'d ControlShared,
+    indicator_pulse: bool,
+}
+
+struct Control<'a> {
+    interface: InterfaceNumber,
+    read_ep: u8,
+    write_ep: u8,
+    capabilities: [u8; CAPABILITIES_LEN],
+    config_usb488: bool,
+    config_indicator_pulse: bool,
+    shared: &'a ControlShared,
+}
+
+/// Shared data between Control and UsbtmcClass
+struct ControlShared {
+    /// Tag of the Bulk-OUT transfer in progress, zero if none.
+    out_tag: AtomicU8,
+    /// Bytes received in the last Bulk-OUT transfer.
+    out_bytes: AtomicU32,
+    /// Tag of the Bulk-IN transfer in progress, zero if none.
+    in_tag: AtomicU8,
+    /// Bytes sent in the last Bulk-IN transfer.
+    in_bytes: AtomicU32,
+    /// The Bulk-OUT transfer in progress was aborted, or the device cleared.
+    abort_out: AtomicBool,
+    /// The Bulk-IN transfer in progress was aborted, or the device cleared.
+    abort_in: AtomicBool,
+
+    /// The status byte of USB488 instruments.
+    status_byte: AtomicU8,
+    /// Tag of a READ_STATUS_BYTE request to answer on the interrupt endpoint, zero if none.
+    status_tag: AtomicU8,
+    /// The remote enable (REN) state.
+    remote_enabled: AtomicBool,
+    /// The local lockout state.
+    local_lockout: AtomicBool,
+    indicator_pulse: AtomicBool,
+
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        ControlShared {
+            out_tag: AtomicU8::new(0),
+            out_bytes: AtomicU32::new(0),
+            in_tag: AtomicU8::new(0),
+            in_bytes: AtomicU32::new(0),
+            abort_out: AtomicBool::new(false),
+            abort_in: AtomicBool::new(false),
+            status_byte: AtomicU8::new(0),
+            status_tag: AtomicU8::new(0),
+            remote_enabled: AtomicBool::new(false),
+            local_lockout: AtomicBool::new(false),
+            indicator_pulse: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+
+    fn reset(&self) {
+        self.out_tag.store(0, Ordering::Relaxed);
+        self.in_tag.store(0, Ordering::Relaxed);
+        self.abort_out.store(true, Ordering::Relaxed);
+        self.abort_in.store(true, Ordering::Relaxed);
+        self.status_tag.store(0, Ordering::Relaxed);
+        self.remote_enabled.store(false, Ordering::Relaxed);
+        self.local_lockout.store(false, Ordering::Relaxed);
+        self.indicator_pulse.store(false, Ordering::Relaxed);
+    }
+
+    fn take(flag: &AtomicBool) -> bool {
+        let set = flag.load(Ordering::Relaxed);
+        if set {
+            flag.store(false, Ordering::Relaxed);
+        }
+        set
+    }
+}
+
+impl<'d> Control<'d> {
+    fn interface_request<'a>(&mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
+        let shared = self.shared;
+        match req.request {
+            REQ_GET_CAPABILITIES => {
+                buf[..CAPABILITIES_LEN].copy_from_slice(&self.capabilities);
+                InResponse::Accepted(&buf[..CAPABILITIES_LEN])
+            }
+            REQ_INITIATE_CLEAR => {
+                debug!("usbtmc: clear");
+                shared.abort_out.store(true, Ordering::Relaxed);
+                shared.abort_in.store(true, Ordering::Relaxed);
+                shared.out_tag.store(0, Ordering::Relaxed);
+                buf[0] = STATUS_SUCCESS;
+                InResponse::Accepted(&buf[..1])
+            }
+            REQ_CHECK_CLEAR_STATUS => {
+                // While a response is being sent, the host must read the Bulk-IN endpoint.
+                let pending = shared.in_tag.load(Ordering::Relaxed) != 0;
+                buf[0] = if pending { STATUS_PENDING } else { STATUS_SUCCESS };
+                buf[1] = pending as u8; // bmClear
+                InResponse::Accepted(&buf[..2])
+            }
+            REQ_INDICATOR_PULSE => {
+                buf[0] = if self.config_indicator_pulse {
+                    shared.indicator_pulse.store(true, Ordering::Relaxed);
+                    shared.waker.borrow_mut().wake();
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_FAILED
+                };
+                InResponse::Accepted(&buf[..1])
+            }
+            REQ_READ_STATUS_BYTE if self.config_usb488 => {
+                let tag = req.value as u8;
+                if !(2..=127).contains(&tag) {
+                    return InResponse::Rejected;
+                }
+                // The status byte is sent on the interrupt endpoint.
+                buf[0] = if shared.status_tag.load(Ordering::Relaxed) != 0 {
+                    STATUS_INTERRUPT_IN_BUSY
+                } else {
+                    shared.status_tag.store(tag, Ordering::Relaxed);
+                    shared.waker.borrow_mut().wake();
+                    STATUS_SUCCESS
+                };
+                buf[1] = tag;
+                buf[2] = 0x00;
+                InResponse::Accepted(&buf[..3])
+            }
+            REQ_REN_CONTROL if self.config_usb488 => {
+                let enabled = req.value & 0xFF != 0;
+                shared.remote_enabled.store(enabled, Ordering::Relaxed);
+                if !enabled {
+                    shared.local_lockout.store(false, Ordering::Relaxed);
+                }
+                buf[0] = STATUS_SUCCESS;
+                InResponse::Accepted(&buf[..1])
+            }
+            REQ_GO_TO_LOCAL | REQ_LOCAL_LOCKOUT if self.config_usb488 => {
+                buf[0] = if shared.remote_enabled.load(Ordering::Relaxed) {
+                    shared
+                        .local_lockout
+                        .store(req.request == REQ_LOCAL_LOCKOUT, Ordering::Relaxed);
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_FAILED
+                };
+                InResponse::Accepted(&buf[..1])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+
+    fn endpoint_request<'a>(&mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        let shared = self.shared;
+        let ep = req.index as u8;
+        let tag = req.value as u8;
+
+        let (status, len) = match req.request {
+            REQ_INITIATE_ABORT_BULK_OUT if ep == self.read_ep => {
+                let current = shared.out_tag.load(Ordering::Relaxed);
+                buf[1] = current;
+                let status = if current == 0 {
+                    STATUS_FAILED
+                } else if current != tag {
+                    STATUS_TRANSFER_NOT_IN_PROGRESS
+                } else {
+                    debug!("usbtmc: abort bulk out {}", tag);
+                    shared.abort_out.store(true, Ordering::Relaxed);
+                    shared.out_tag.store(0, Ordering::Relaxed);
+                    STATUS_SUCCESS
+                };
+                (status, 2)
+            }
+            REQ_CHECK_ABORT_BULK_OUT_STATUS if ep == self.read_ep => {
+                buf[1..4].fill(0);
+                buf[4..8].copy_from_slice(&shared.out_bytes.load(Ordering::Relaxed).to_le_bytes()); // NBYTES_RXD
+                (STATUS_SUCCESS, 8)
+            }
+            REQ_INITIATE_ABORT_BULK_IN if ep == self.write_ep => {
+                let current = shared.in_tag.load(Ordering::Relaxed);
+                buf[1] = current;
+                let status = if current == 0 {
+                    STATUS_FAILED
+                } else if current != tag {
+                    STATUS_TRANSFER_NOT_IN_PROGRESS
+                } else {
+                    debug!("usbtmc: abort bulk in {}", tag);
+                    shared.abort_in.store(true, Ordering::Relaxed);
+                    STATUS_SUCCESS
+                };
+                (status, 2)
+            }
+            REQ_CHECK_ABORT_BULK_IN_STATUS if ep == self.write_ep => {
+                // The transfer ends with a short packet, to be read by the host.
+                let pending = shared.in_tag.load(Ordering::Relaxed) != 0;
+                buf[1] = pending as u8; // bmAbortBulkIn
+                buf[2..4].fill(0);
+                buf[4..8].copy_from_slice(&shared.in_bytes.load(Ordering::Relaxed).to_le_bytes()); // NBYTES_TXD
+                (if pending { STATUS_PENDING } else { STATUS_SUCCESS }, 8)
+            }
+            _ => return None,
+        };
+
+        buf[0] = status;
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.reset();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            self.shared.reset();
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Class {
+            return None;
+        }
+
+        match req.recipient {
+            Recipient::Interface if req.index == u8::from(self.interface) as u16 => {
+                Some(self.interface_request(req, buf))
+            }
+            Recipient::Endpoint => self.endpoint_request(req, buf),
+            _ => None,
+        }
+    }
+}
+
+impl<'d, D: Driver<'d>> UsbtmcClass<'d, D> {
+    /// Creates a new UsbtmcClass with the provided configuration.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        assert!(builder.control_buf_len() >= CAPABILITIES_LEN);
+        assert!(config.max_packet_size as usize >= HEADER_LEN);
+        assert!(config.max_packet_size as usize <= MAX_PACKET_SIZE);
+
+        let protocol = if config.usb488 {
+            USB488_PROTOCOL
+        } else {
+            USBTMC_PROTOCOL
+        };
+
+        let mut func = builder.function(USB_CLASS_APPLICATION_SPECIFIC, USBTMC_SUBCLASS, protocol);
+        let mut iface = func.interface();
+        let interface = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_APPLICATION_SPECIFIC, USBTMC_SUBCLASS, protocol, None);
+
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, config.max_packet_size);
+        // Service requests and status bytes are two bytes long.
+        let interrupt_ep = config.usb488.then(|| alt.endpoint_interrupt_in(None, 2, 1));
+
+        drop(func);
+
+        // GET_CAPABILITIES response [USBTMC Table 37, USB488 Table 8]
+        let mut capabilities = [0; CAPABILITIES_LEN];
+        capabilities[0] = STATUS_SUCCESS;
+        capabilities[2..4].copy_from_slice(&0x0100u16.to_le_bytes()); // bcdUSBTMC
+        capabilities[4] =
+            (config.indicator_pulse as u8) << 2 | (config.talk_only as u8) << 1 | config.listen_only as u8;
+        capabilities[5] = 0x01; // TermChar supported
+        if config.usb488 {
+            capabilities[12..14].copy_from_slice(&0x0100u16.to_le_bytes()); // bcdUSB488
+            capabilities[14] = 0x07; // USB488.2 interface, with REN_CONTROL, GO_TO_LOCAL, LOCAL_LOCKOUT and TRIGGER
+            capabilities[15] = (config.scpi as u8) << 3 | 0x07; // SR1, RL1, DT1
+        }
+
+        state.control = Some(Control {
+            interface,
+            read_ep: read_ep.info().addr.into(),
+            write_ep: write_ep.info().addr.into(),
+            capabilities,
+            config_usb488: config.usb488,
+            config_indicator_pulse: config.indicator_pulse,
+            shared: &state.shared,
+        });
+        builder.handler(state.control.as_mut().unwrap());
+
+        UsbtmcClass {
+            read_ep,
+            write_ep,
+            interrupt_ep,
+            shared: &state.shared,
+            indicator_pulse: config.indicator_pulse,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Gets the status byte, returned to READ_STATUS_BYTE requests.
+    pub fn status_byte(&self) -> u8 {
+        self.shared.status_byte.load(Ordering::Relaxed)
+    }
+
+    /// Sets the status byte, returned to READ_STATUS_BYTE requests.
+    ///
+    /// Instruments usually keep it up to date with their IEEE 488.2 status registers.
+    pub fn set_status_byte(&self, status_byte: u8) {
+        self.shared.status_byte.store(status_byte, Ordering::Relaxed);
+    }
+
+    /// Gets the remote enable (REN) state, set by the host.
+    pub fn remote_enabled(&self) -> bool {
+        self.shared.remote_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Gets the local lockout state, set by the host: the front panel of the instrument must be
+    /// disabled.
+    pub fn local_lockout(&self) -> bool {
+        self.shared.local_lockout.load(Ordering::Relaxed)
+    }
+
+    /// Requests service from the host (SRQ), with the given status byte. Only for USB488
+    /// instruments.
+    ///
+    /// The request service bit of the status byte is set.
+    pub async fn request_service(&mut self, status_byte: u8) -> Result<(), Error> {
+        let status_byte = status_byte | STB_RQS;
+        self.set_status_byte(status_byte);
+        let ep = self.interrupt_ep.as_mut().expect("not a USB488 instrument");
+        ep.write(&[0x81, status_byte]).await?;
+        Ok(())
+    }
+
+    /// Reads the next message from the host.
+    ///
+    /// Device dependent messages, which may be split in several transfers, are returned once
+    /// complete. If `buf` is too small for one, it is discarded and [`Error::BufferOverflow`] is
+    /// returned.
+    ///
+    /// The status byte requested by the host is sent while waiting for messages.
+    pub async fn read_message<'a>(&mut self, buf: &'a mut [u8]) -> Result<Message<'a>, Error> {
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let max_packet_size = self.max_packet_size() as usize;
+        // Length of the message in `buf`.
+        let mut len = 0;
+        let mut overflow = false;
+        // Bytes of the transfer in progress still to be received, with the alignment bytes.
+        let mut remaining = 0;
+        // Bytes of the message in the transfer in progress still to be received.
+        let mut data_remaining = 0;
+        let mut eom = false;
+        let mut discard = false;
+
+        loop {
+            let idle = len == 0 && remaining == 0;
+            let n = match self.read_packet(&mut packet[..max_packet_size], idle).await? {
+                Some(n) => n,
+                None => return Ok(Message::IndicatorPulse),
+            };
+
+            if ControlShared::take(&self.shared.abort_out) {
+                // This packet starts a new transfer.
+                len = 0;
+                overflow = false;
+                remaining = 0;
+            }
+
+            let mut data = &packet[..n];
+            if remaining == 0 {
+                if n < HEADER_LEN || packet[1] == 0 || packet[1] != !packet[2] {
+                    warn!("usbtmc: invalid message header");
+                    continue;
+                }
+                let tag = packet[1];
+                let transfer_size = u32::from_le_bytes(packet[4..8].try_into().unwrap());
+
+                match packet[0] {
+                    DEV_DEP_MSG_OUT | VENDOR_SPECIFIC_OUT => {
+                        // Transfers are padded to a multiple of 4 bytes, which must fit in the
+                        // 32-bit TransferSize on all targets.
+                        let Some(padded) = transfer_size.checked_add(3) else {
+                            warn!("usbtmc: invalid transfer size");
+                            continue;
+                        };
+                        // Vendor specific messages are not supported, and skipped.
+                        discard = packet[0] == VENDOR_SPECIFIC_OUT;
+                        eom = packet[8] & 0x01 != 0;
+                        data_remaining = transfer_size as usize;
+                        remaining = (padded & !3) as usize;
+                        self.shared.out_tag.store(tag, Ordering::Relaxed);
+                        self.shared.out_bytes.store(0, Ordering::Relaxed);
+                        data = &packet[HEADER_LEN..n];
+                    }
+                    REQUEST_DEV_DEP_MSG_IN => {
+                        let term_char = (packet[8] & 0x02 != 0).then_some(packet[9]);
+                        self.shared.abort_in.store(false, Ordering::Relaxed);
+                        self.shared.in_tag.store(tag, Ordering::Relaxed);
+                        self.shared.in_bytes.store(0, Ordering::Relaxed);
+                        return Ok(Message::ReadRequest(ReadRequest {
+                            tag,
+                            max_len: transfer_size,
+                            term_char,
+                        }));
+                    }
+                    TRIGGER if self.interrupt_ep.is_some() => return Ok(Message::Trigger),
+                    id => {
+                        warn!("usbtmc: unsupported message {}", id);
+                        continue;
+                    }
+                }
+            }
+
+            let transfer = data.len().min(remaining);
+            let message = transfer.min(data_remaining);
+            if !discard {
+                if len + message > buf.len() {
+                    overflow = true;
+                } else {
+                    buf[len..len + message].copy_from_slice(&data[..message]);
+                    len += message;
+                }
+            }
+            remaining -= transfer;
+            data_remaining -= message;
+            self.shared.out_bytes.store(
+                self.shared.out_bytes.load(Ordering::Relaxed) + message as u32,
+                Ordering::Relaxed,
+            );
+
+            // Transfers end with a short packet, or once complete.
+            if remaining == 0 || n < max_packet_size {
+                remaining = 0;
+                self.shared.out_tag.store(0, Ordering::Relaxed);
+                if eom && !discard {
+                    if overflow {
+                        return Err(Error::BufferOverflow);
+                    }
+                    return Ok(Message::Data(&buf[..len]));
+                }
+            }
+        }
+    }
+
+    /// Reads a packet, sending the status byte when requested by the host.
+    ///
+    /// Returns `None` for an indicator pulse request, if `idle`.
+    async fn read_packet(&mut self, packet: &mut [u8], idle: bool) -> Result<Option<usize>, Error> {
+        loop {
+            let shared = self.shared;
+            let notification = poll_fn(|cx| {
+                if shared.status_tag.load(Ordering::Relaxed) != 0
+                    || (idle && shared.indicator_pulse.load(Ordering::Relaxed))
+                {
+                    Poll::Ready(())
+                } else {
+                    shared.waker.borrow_mut().register(cx.waker());
+                    Poll::Pending
+                }
+            });
+
+            match select(self.read_ep.read(packet), notification).await {
+                Either::First(n) => return Ok(Some(n?)),
+                Either::Second(()) => {
+                    let tag = shared.status_tag.load(Ordering::Relaxed);
+                    if tag != 0 {
+                        let status_byte = shared.status_byte.load(Ordering::Relaxed);
+                        if let Some(ep) = self.interrupt_ep.as_mut() {
+                            ep.write(&[0x80 | tag, status_byte]).await?;
+                        }
+                        shared.status_tag.store(0, Ordering::Relaxed);
+                    }
+                    if idle && self.indicator_pulse && ControlShared::take(&shared.indicator_pulse) {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the response to a read request of the host.
+    ///
+    /// Up to [`ReadRequest::max_len`] bytes of `data` are sent, ending after the termination
+    /// character if enabled. Returns the number of bytes sent: the remainder, if any, is to be
+    /// sent to the next read request.
+    pub async fn write_response(&mut self, request: &ReadRequest, data: &[u8]) -> Result<usize, Error> {
+        let mut len = data.len().min(request.max_len as usize);
+        let mut term_char_found = false;
+        if let Some(term_char) = request.term_char
+            && let Some(i) = data[..len].iter().position(|&b| b == term_char)
+        {
+            len = i + 1;
+            term_char_found = true;
+        }
+        let eom = len == data.len();
+        let result = self
+            .write_transfer(request.tag, &data[..len], eom, term_char_found)
+            .await;
+        self.shared.in_tag.store(0, Ordering::Relaxed);
+        result.map(|()| len)
+    }
+
+    async fn write_transfer(&mut self, tag: u8, data: &[u8], eom: bool, term_char: bool) -> Result<(), Error> {
+        let max_packet_size = self.max_packet_size() as usize;
+        let mut packet = [0; MAX_PACKET_SIZE];
+
+        // DEV_DEP_MSG_IN header [USBTMC Table 9]
+        packet[0] = DEV_DEP_MSG_IN;
+        packet[1] = tag;
+        packet[2] = !tag;
+        packet[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes()); // TransferSize
+        packet[8] = (term_char as u8) << 1 | eom as u8; // bmTransferAttributes
+
+        // The transfer is padded to a multiple of 4 bytes.
+        let data_len = data.len();
+        let total = (HEADER_LEN + data_len + 3) & !3;
+        let mut offset = HEADER_LEN;
+        let mut sent = 0;
+        let mut data = data;
+
+        while sent < total {
+            let n = data.len().min(max_packet_size - offset);
+            packet[offset..offset + n].copy_from_slice(&data[..n]);
+            data = &data[n..];
+            let end = (offset + n).max((total - sent).min(max_packet_size));
+            packet[offset + n..end].fill(0);
+
+            if ControlShared::take(&self.shared.abort_in) {
+                // End the transfer with a short packet, read by the host.
+                if sent > 0 {
+                    self.write_ep.write(&[]).await?;
+                }
+                return Err(Error::Aborted);
+            }
+            self.write_ep.write(&packet[..end]).await?;
+            sent += end;
+            // Headers and alignment bytes are not counted.
+            let data_sent = sent.saturating_sub(HEADER_LEN).min(data_len);
+            self.shared.in_bytes.store(data_sent as u32, Ordering::Relaxed);
+            offset = 0;
+        }
+
+        if total.is_multiple_of(max_packet_size) {
+            self.write_ep.write(&[]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use heapless::Vec;
+
+    use super::*;
+    use crate::driver::EndpointType;
+    use crate::test_driver::TestDriver;
+
+    const INTERFACE: u8 = 0;
+    const READ_EP: u8 = 0x01;
+    const WRITE_EP: u8 = 0x81;
+    const EOM: u8 = 0x01;
+
+    fn class(shared: &ControlShared) -> UsbtmcClass<'_, TestDriver> {
+        let mut driver = TestDriver::default();
+        UsbtmcClass {
+            read_ep: driver.alloc_endpoint_out(EndpointType::Bulk, None, 64, 0).unwrap(),
+            write_ep: driver.alloc_endpoint_in(EndpointType::Bulk, None, 64, 0).unwrap(),
+            interrupt_ep: Some(driver.alloc_endpoint_in(EndpointType::Interrupt, None, 2, 1).unwrap()),
+            shared,
+            indicator_pulse: false,
+        }
+    }
+
+    fn control(shared: &ControlShared, usb488: bool) -> Control<'_> {
+        Control {
+            interface: InterfaceNumber::new(INTERFACE),
+            read_ep: READ_EP,
+            write_ep: WRITE_EP,
+            capabilities: [0; CAPABILITIES_LEN],
+            config_usb488: usb488,
+            config_indicator_pulse: false,
+            shared,
+        }
+    }
+
+    fn header(msg_id: u8, tag: u8, transfer_size: u32, attributes: u8) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0] = msg_id;
+        header[1] = tag;
+        header[2] = !tag;
+        header[4..8].copy_from_slice(&transfer_size.to_le_bytes());
+        header[8] = attributes;
+        header
+    }
+
+    /// Queues a Bulk-OUT transfer of the host, padded with non-zero alignment bytes.
+    fn send(class: &mut UsbtmcClass<'_, TestDriver>, header: [u8; HEADER_LEN], data: &[u8]) {
+        let mut transfer: Vec<u8, 1024> = Vec::from_slice(&header).unwrap();
+        transfer.extend_from_slice(data).unwrap();
+        while !transfer.len().is_multiple_of(4) {
+            transfer.push(0xAA).unwrap();
+        }
+        for packet in transfer.chunks(64) {
+            class
+                .read_ep
+                .packets
+                .push_back(Vec::from_slice(packet).unwrap())
+                .unwrap();
+        }
+    }
+
+    /// Queues a device dependent message in a single transfer.
+    fn send_message(class: &mut UsbtmcClass<'_, TestDriver>, tag: u8, data: &[u8]) {
+        send(class, header(DEV_DEP_MSG_OUT, tag, data.len() as u32, EOM), data);
+    }
+
+    fn request(request_type: u8, request: u8, value: u16, index: u16) -> Request {
+        let mut buf = [request_type, request, 0, 0, 0, 0, 0x40, 0];
+        buf[2..4].copy_from_slice(&value.to_le_bytes());
+        buf[4..6].copy_from_slice(&index.to_le_bytes());
+        Request::parse(&buf)
+    }
+
+    /// Send a class request to the interface, returning the response if it was accepted.
+    fn get(control: &mut Control, request_code: u8, value: u16) -> Option<Vec<u8, 64>> {
+        let mut buf = [0; 64];
+        match control.control_in(request(0xA1, request_code, value, INTERFACE as u16), &mut buf) {
+            Some(InResponse::Accepted(data)) => Some(Vec::from_slice(data).unwrap()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bulk_out_messages() {
+        let shared = ControlShared::new();
+        let mut class = class(&shared);
+        let mut buf = [0; 256];
+
+        send_message(&mut class, 1, b"*IDN?\n");
+        assert_eq!(block_on(class.read_message(&mut buf)), Ok(Message::Data(b"*IDN?\n")));
+        assert_eq!(shared.out_tag.load(Ordering::Relaxed), 0);
+        assert_eq!(shared.out_bytes.load(Ordering::Relaxed), 6);
+
+        // A message split in two transfers, complete with the EOM bit.
+        send(&mut class, header(DEV_DEP_MSG_OUT, 2, 5, 0), b"MEAS:");
+        send_message(&mut class, 3, b"VOLT?\n");
+        assert_eq!(
+            block_on(class.read_message(&mut buf)),
+            Ok(Message::Data(b"MEAS:VOLT?\n"))
+        );
+
+        // Transfers of several packets, with the alignment bytes in the last packet, or filling
+        // the last packet.
+        for len in [100, 53, 52, 116] {
+            let data: Vec<u8, 256> = (0..len).map(|i| i as u8).collect();
+            send_message(&mut class, 4, &data);
+            assert_eq!(block_on(class.read_message(&mut buf)), Ok(Message::Data(&data[..])));
+            assert!(class.read_ep.packets.is_empty());
+        }
+
+        // Messages larger than the buffer are discarded.
+        send_message(&mut class, 5, b"*IDN?\n");
+        send_message(&mut class, 6, b"*RST\n");
+        let mut buf = [0; 5];
+        assert_eq!(block_on(class.read_message(&mut buf)), Err(Error::BufferOverflow));
+        assert_eq!(block_on(class.read_message(&mut buf)), Ok(Message::Data(b"*RST\n")));
+
+        assert_eq!(block_on(class.read_message(&mut buf)), Err(Error::Disabled));
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let shared = ControlShared::new();
+        let mut class = class(&shared);
+        let mut buf = [0; 64];
+
+        // Packets shorter than a header.
+        let packet = header(DEV_DEP_MSG_OUT, 1, 0, EOM);
+        class
+            .read_ep
+            .packets
+            .push_back(Vec::from_slice(&packet[..8]).unwrap())
+            .unwrap();
+        // Tag 0, and a wrong inverted tag.
+        send(&mut class, header(DEV_DEP_MSG_OUT, 0, 4, EOM), b"*CLS");
+        let mut packet = header(DEV_DEP_MSG_OUT, 1, 4, EOM);
+        packet[2] = 0;
+        send(&mut class, packet, b"*CLS");
+        // Unknown and unsupported messages.
+        send(&mut class, header(200, 1, 4, EOM), b"*CLS");
+        send(&mut class, header(VENDOR_SPECIFIC_OUT, 1, 4, EOM), b"*CLS");
+        // The alignment bytes would overflow the 32-bit TransferSize.
+        for size in [u32::MAX - 2, u32::MAX] {
+            send(&mut class, header(DEV_DEP_MSG_OUT, 1, size, EOM), b"*CLS");
+        }
+        send_message(&mut class, 2, b"*RST\n");
+
+        assert_eq!(block_on(class.read_message(&mut buf)), Ok(Message::Data(b"*RST\n")));
+        assert!(class.read_ep.packets.is_empty());
+    }
+
+    #[test]
+    fn bulk_in_messages() {
+        let shared = ControlShared::new();
+        let mut class = class(&shared);
+        let mut buf = [0; 64];
+
+        // REQUEST_DEV_DEP_MSG_IN with the TermChar enabled.
+        let mut packet = header(REQUEST_DEV_DEP_MSG_IN, 7, 100, 0x02);
+        packet[9] = b'\n';
+        send(&mut class, packet, &[]);
+        let request = ReadRequest {
+            tag: 7,
+            max_len: 100,
+            term_char: Some(b'\n'),
+        };
+        assert_eq!(
+            block_on(class.read_message(&mut buf)),
+            Ok(Message::ReadRequest(request))
+        );
+        assert_eq!(shared.in_tag.load(Ordering::Relaxed), 7);
+
+        // The response ends after the TermChar, without the EOM bit as data remains.
+        assert_eq!(block_on(class.write_response(&request, b"1.5\n2.5\n")), Ok(4));
+        let packet = class.write_ep.packets.pop_front().unwrap();
+        assert_eq!(
+            packet[..],
+            [
+                DEV_DEP_MSG_IN,
+                7,
+                !7,
+                0,
+                4,
+                0,
+                0,
+                0,
+                0x02,
+                0,
+                0,
+                0, //
+                b'1',
+                b'.',
+                b'5',
+                b'\n',
+            ]
+        );
+        assert_eq!(shared.in_tag.load(Ordering::Relaxed), 0);
+
+        send(&mut class, header(REQUEST_DEV_DEP_MSG_IN, 8, 2, 0), &[]);
+        let Ok(Message::ReadRequest(request)) = block_on(class.read_message(&mut buf)) else {
+            panic!("no read request");
+        };
+        // The response is limited to max_len, and padded.
+        assert_eq!(block_on(class.write_response(&request, b"2.5\n")), Ok(2));
+        let packet = class.write_ep.packets.pop_front().unwrap();
+        assert_eq!(packet[4..9], [2, 0, 0, 0, 0x00]);
+        assert_eq!(packet[HEADER_LEN..], [b'2', b'.', 0, 0]);
+        assert!(class.write_ep.packets.is_empty());
+
+        send(&mut class, header(TRIGGER, 9, 0, 0), &[]);
+        assert_eq!(block_on(class.read_message(&mut buf)), Ok(Message::Trigger));
+    }
+
+    #[test]
+    fn read_status_byte() {
+        let shared = ControlShared::new();
+        let mut control = control(&shared, true);
+
+        // The status byte is sent on the interrupt endpoint, with the tag.
+        assert_eq!(
+            get(&mut control, REQ_READ_STATUS_BYTE, 2).unwrap()[..],
+            [STATUS_SUCCESS, 2, 0]
+        );
+        assert_eq!(shared.status_tag.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            get(&mut control, REQ_READ_STATUS_BYTE, 3).unwrap()[..],
+            [STATUS_INTERRUPT_IN_BUSY, 3, 0]
+        );
+        assert_eq!(shared.status_tag.load(Ordering::Relaxed), 2);
+
+        // Tags are between 2 and 127.
+        shared.status_tag.store(0, Ordering::Relaxed);
+        assert!(get(&mut control, REQ_READ_STATUS_BYTE, 1).is_none());
+        assert!(get(&mut control, REQ_READ_STATUS_BYTE, 128).is_none());
+        assert_eq!(
+            get(&mut control, REQ_READ_STATUS_BYTE, 127).unwrap()[..],
+            [STATUS_SUCCESS, 127, 0]
+        );
+
+        // USB488 requests are rejected by USBTMC instruments.
+        let mut control = super::tests::control(&shared, false);
+        assert!(get(&mut control, REQ_READ_STATUS_BYTE, 2).is_none());
+        assert!(get(&mut control, REQ_REN_CONTROL, 1).is_none());
+    }
+
+    #[test]
+    fn clear() {
+        let shared = ControlShared::new();
+        let mut control = control(&shared, true);
+
+        shared.out_tag.store(3, Ordering::Relaxed);
+        assert_eq!(get(&mut control, REQ_INITIATE_CLEAR, 0).unwrap()[..], [STATUS_SUCCESS]);
+        assert_eq!(shared.out_tag.load(Ordering::Relaxed), 0);
+        assert!(shared.abort_out.load(Ordering::Relaxed));
+        assert!(shared.abort_in.load(Ordering::Relaxed));
+        assert_eq!(
+            get(&mut control, REQ_CHECK_CLEAR_STATUS, 0).unwrap()[..],
+            [STATUS_SUCCESS, 0]
+        );
+
+        // The clear is pending while a response is sent.
+        shared.in_tag.store(4, Ordering::Relaxed);
+        assert_eq!(
+            get(&mut control, REQ_CHECK_CLEAR_STATUS, 0).unwrap()[..],
+            [STATUS_PENDING, 1]
+        );
+
+        // The next transfer starts a new message.
+        let mut class = class(&shared);
+        let mut buf = [0; 64];
+        send_message(&mut class, 5, b"*CLS\n");
+        assert_eq!(block_on(class.read_message(&mut buf)), Ok(Message::Data(b"*CLS\n")));
+        assert!(!shared.abort_out.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn abort_bulk_out() {
+        let shared = ControlShared::new();
+        let mut control = control(&shared, true);
+        let mut abort = |tag: u16| {
+            let mut buf = [0; 64];
+            let req = request(0xA2, REQ_INITIATE_ABORT_BULK_OUT, tag, READ_EP as u16);
+            match control.control_in(req, &mut buf) {
+                Some(InResponse::Accepted(data)) => <[u8; 2]>::try_from(data).unwrap(),
+                _ => panic!("request rejected"),
+            }
+        };
+
+        assert_eq!(abort(3), [STATUS_FAILED, 0]);
+        shared.out_tag.store(3, Ordering::Relaxed);
+        assert_eq!(abort(4), [STATUS_TRANSFER_NOT_IN_PROGRESS, 3]);
+        assert!(!shared.abort_out.load(Ordering::Relaxed));
+        assert_eq!(abort(3), [STATUS_SUCCESS, 3]);
+        assert!(shared.abort_out.load(Ordering::Relaxed));
+        assert_eq!(shared.out_tag.load(Ordering::Relaxed), 0);
+    }
+}
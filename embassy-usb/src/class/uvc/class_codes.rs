@@ -0,0 +1,94 @@
+//! Video Device Class Codes as defined in Universal Serial Bus Device Class
+//! Definition for Video Devices, Revision 1.5, Appendix A, and in the payload
+//! specifications for uncompressed and MJPEG formats.
+#![allow(dead_code)]
+
+/// Video Interface Class Code
+pub const CC_VIDEO: u8 = 0x0E;
+
+// Video Interface Subclass Codes
+pub const SC_UNDEFINED: u8 = 0x00;
+pub const SC_VIDEOCONTROL: u8 = 0x01;
+pub const SC_VIDEOSTREAMING: u8 = 0x02;
+pub const SC_VIDEO_INTERFACE_COLLECTION: u8 = 0x03;
+
+// Video Interface Protocol Codes
+pub const PC_PROTOCOL_UNDEFINED: u8 = 0x00;
+pub const PC_PROTOCOL_15: u8 = 0x01;
+
+// Video Class-Specific Descriptor Types
+pub const CS_UNDEFINED: u8 = 0x20;
+pub const CS_DEVICE: u8 = 0x21;
+pub const CS_CONFIGURATION: u8 = 0x22;
+pub const CS_STRING: u8 = 0x23;
+pub const CS_INTERFACE: u8 = 0x24;
+pub const CS_ENDPOINT: u8 = 0x25;
+
+// Video Class-Specific VC Interface Descriptor Subtypes
+pub const VC_DESCRIPTOR_UNDEFINED: u8 = 0x00;
+pub const VC_HEADER: u8 = 0x01;
+pub const VC_INPUT_TERMINAL: u8 = 0x02;
+pub const VC_OUTPUT_TERMINAL: u8 = 0x03;
+pub const VC_SELECTOR_UNIT: u8 = 0x04;
+pub const VC_PROCESSING_UNIT: u8 = 0x05;
+pub const VC_EXTENSION_UNIT: u8 = 0x06;
+pub const VC_ENCODING_UNIT: u8 = 0x07;
+
+// Video Class-Specific VS Interface Descriptor Subtypes
+pub const VS_UNDEFINED: u8 = 0x00;
+pub const VS_INPUT_HEADER: u8 = 0x01;
+pub const VS_OUTPUT_HEADER: u8 = 0x02;
+pub const VS_STILL_IMAGE_FRAME: u8 = 0x03;
+pub const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+pub const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+pub const VS_FORMAT_MJPEG: u8 = 0x06;
+pub const VS_FRAME_MJPEG: u8 = 0x07;
+pub const VS_COLORFORMAT: u8 = 0x0D;
+
+// Video Class-Specific Request Codes
+pub const RC_UNDEFINED: u8 = 0x00;
+pub const SET_CUR: u8 = 0x01;
+pub const GET_CUR: u8 = 0x81;
+pub const GET_MIN: u8 = 0x82;
+pub const GET_MAX: u8 = 0x83;
+pub const GET_RES: u8 = 0x84;
+pub const GET_LEN: u8 = 0x85;
+pub const GET_INFO: u8 = 0x86;
+pub const GET_DEF: u8 = 0x87;
+
+// VideoControl Interface Control Selectors
+pub const VC_CONTROL_UNDEFINED: u8 = 0x00;
+pub const VC_VIDEO_POWER_MODE_CONTROL: u8 = 0x01;
+pub const VC_REQUEST_ERROR_CODE_CONTROL: u8 = 0x02;
+
+// VideoStreaming Interface Control Selectors
+pub const VS_CONTROL_UNDEFINED: u8 = 0x00;
+pub const VS_PROBE_CONTROL: u8 = 0x01;
+pub const VS_COMMIT_CONTROL: u8 = 0x02;
+
+// GET_INFO capabilities
+pub const INFO_GET_SUPPORTED: u8 = 0x01;
+pub const INFO_SET_SUPPORTED: u8 = 0x02;
+
+// Request Error Codes
+pub const ERROR_NONE: u8 = 0x00;
+pub const ERROR_INVALID_CONTROL: u8 = 0x06;
+pub const ERROR_INVALID_REQUEST: u8 = 0x07;
+
+// Terminal Types
+pub const TT_STREAMING: u16 = 0x0101;
+pub const ITT_CAMERA: u16 = 0x0201;
+
+// Payload header fields (bmHeaderInfo)
+pub const HEADER_FID: u8 = 0x01;
+pub const HEADER_EOF: u8 = 0x02;
+pub const HEADER_PTS: u8 = 0x04;
+pub const HEADER_SCR: u8 = 0x08;
+pub const HEADER_STI: u8 = 0x20;
+pub const HEADER_ERR: u8 = 0x40;
+pub const HEADER_EOH: u8 = 0x80;
+
+/// GUID of the YUY2 uncompressed format.
+pub const GUID_YUY2: [u8; 16] = [
+    b'Y', b'U', b'Y', b'2', 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
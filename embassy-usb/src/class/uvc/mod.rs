@@ -0,0 +1,1155 @@
+//! USB Video Class (UVC) 1.1 and 1.5 - Camera device
+//!
+//! Provides a class with a video control interface, advertising a camera, and a video streaming
+//! interface (device to host). Hosts handle such devices with their standard webcam drivers.
+//!
+//! The following can be configured:
+//! - the video formats, uncompressed YUY2 and MJPEG, each with its frame sizes and frame intervals
+//! - the streaming endpoint, isochronous or bulk
+//!
+//! The format, frame size and frame interval are negotiated by the host with the probe and commit
+//! controls. Once streaming, the selected [`Settings`] are returned by [`Camera::wait_streaming`],
+//! and frames are written with [`Camera::write_frame`] or a [`FrameWriter`].
+//!
+//! Note that the device must be built with [`Config::composite_with_iads`](crate::Config::composite_with_iads)
+//! set, as the class uses an interface association descriptor.
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::Vec;
+
+mod class_codes;
+
+use class_codes::*;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// Maximum supported packet size of the streaming endpoint.
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+/// Maximum number of discrete frame intervals per frame size.
+pub const MAX_FRAME_INTERVAL_COUNT: usize = 8;
+
+/// Arbitrary unique identifier for the camera terminal.
+const CAMERA_TERMINAL_ID: u8 = 0x01;
+
+/// Arbitrary unique identifier for the output terminal.
+const OUTPUT_TERMINAL_ID: u8 = 0x02;
+
+/// Clock frequency reported in the video control header and probe, in Hz.
+///
+/// Payload headers carry no presentation or source clock timestamps, so this is informative only.
+const CLOCK_FREQUENCY_HZ: u32 = 48_000_000;
+
+/// Length of the payload headers, without presentation or source clock timestamps.
+const PAYLOAD_HEADER_LEN: usize = 2;
+
+/// Length of the probe and commit controls of UVC 1.5, the largest ones.
+const MAX_PROBE_LEN: usize = 48;
+
+/// Frame intervals are in units of 100 ns.
+const INTERVALS_PER_SECOND: u64 = 10_000_000;
+
+/// Version of the video class specification implemented by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Version {
+    /// UVC 1.1, supported by all hosts.
+    Uvc11,
+    /// UVC 1.5.
+    Uvc15,
+}
+
+impl Version {
+    fn bcd(self) -> u16 {
+        match self {
+            Version::Uvc11 => 0x0110,
+            Version::Uvc15 => 0x0150,
+        }
+    }
+
+    fn protocol(self) -> u8 {
+        match self {
+            Version::Uvc11 => PC_PROTOCOL_UNDEFINED,
+            Version::Uvc15 => PC_PROTOCOL_15,
+        }
+    }
+
+    /// Length of the probe and commit controls.
+    fn probe_len(self) -> usize {
+        match self {
+            Version::Uvc11 => 34,
+            Version::Uvc15 => MAX_PROBE_LEN,
+        }
+    }
+}
+
+/// Type of the video streaming endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transfer {
+    /// Isochronous endpoint, with guaranteed bandwidth.
+    ///
+    /// The streaming interface has a zero-bandwidth alternate setting, and the host starts and stops
+    /// streaming by selecting the operational one.
+    Isochronous,
+    /// Bulk endpoint, using the bandwidth left by other devices.
+    ///
+    /// Streaming starts when the host commits the settings.
+    Bulk,
+}
+
+/// A frame size of a video format.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame<'a> {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// Supported frame intervals in units of 100 ns, such as `333_333` for 30 frames per second.
+    ///
+    /// The first one is the default. Up to [`MAX_FRAME_INTERVAL_COUNT`] intervals can be given.
+    pub intervals: &'a [u32],
+}
+
+impl Frame<'_> {
+    /// Maximum size of a frame in bytes, that of an uncompressed one.
+    fn max_frame_size(&self) -> u32 {
+        self.width as u32 * self.height as u32 * 2
+    }
+
+    /// Bit rate at `interval`, in bits per second.
+    fn bit_rate(&self, interval: u32) -> u32 {
+        let bits = self.max_frame_size() as u64 * 8;
+        (bits * INTERVALS_PER_SECOND / interval.max(1) as u64).min(u32::MAX as u64) as u32
+    }
+}
+
+/// A video format, with its frame sizes.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Format<'a> {
+    /// Uncompressed YUY2 (YUYV 4:2:2), 16 bits per pixel.
+    Yuy2(&'a [Frame<'a>]),
+    /// Motion JPEG, each frame being a JPEG image.
+    Mjpeg(&'a [Frame<'a>]),
+}
+
+impl<'a> Format<'a> {
+    /// Get the frame sizes of the format.
+    pub fn frames(&self) -> &'a [Frame<'a>] {
+        match self {
+            Format::Yuy2(frames) | Format::Mjpeg(frames) => frames,
+        }
+    }
+
+    /// Length of the class-specific descriptors of the format, with its frames.
+    fn descriptors_len(&self) -> usize {
+        let format_len = match self {
+            Format::Yuy2(_) => 27,
+            Format::Mjpeg(_) => 11,
+        };
+        let frames_len: usize = self.frames().iter().map(|f| 26 + 4 * f.intervals.len()).sum();
+        // Followed by a color matching descriptor.
+        format_len + frames_len + 6
+    }
+}
+
+/// Configuration of the [`Camera`] class.
+pub struct Config<'a> {
+    /// Supported video formats, the first one being the default.
+    pub formats: &'a [Format<'a>],
+
+    /// Type of the streaming endpoint.
+    pub transfer: Transfer,
+
+    /// Max packet size of the streaming endpoint, up to [`MAX_PACKET_SIZE`].
+    ///
+    /// Full-speed devices can use up to 1023 bytes for isochronous and 64 bytes for bulk endpoints,
+    /// high-speed ones 1024 and 512 bytes.
+    pub max_packet_size: u16,
+
+    /// Implemented version of the video class specification.
+    pub version: Version,
+}
+
+/// Video stream settings committed by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Settings {
+    /// Index of the format in [`Config::formats`].
+    pub format: usize,
+    /// Index of the frame size in the frames of the format.
+    pub frame: usize,
+    /// Frame interval in units of 100 ns.
+    pub frame_interval: u32,
+}
+
+/// Fields of the probe and commit controls handled by the device.
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    /// One-based index of the format.
+    format_index: u8,
+    /// One-based index of the frame.
+    frame_index: u8,
+    frame_interval: u32,
+}
+
+impl Probe {
+    fn settings(&self) -> Settings {
+        Settings {
+            format: self.format_index as usize - 1,
+            frame: self.frame_index as usize - 1,
+            frame_interval: self.frame_interval,
+        }
+    }
+}
+
+/// Internal state for the USB Video Class.
+pub struct State<'d> {
+    control: Option<Control<'d>>,
+    shared: SharedControl,
+    packet: [u8; MAX_PACKET_SIZE],
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: None,
+            shared: SharedControl::default(),
+            packet: [0; MAX_PACKET_SIZE],
+        }
+    }
+}
+
+/// Implementation of the USB video class, as a camera.
+pub struct Camera<'d, D: Driver<'d>> {
+    streaming_endpoint: D::EndpointIn,
+    shared: &'d SharedControl,
+    packet: &'d mut [u8; MAX_PACKET_SIZE],
+    max_packet_size: usize,
+    fid: bool,
+}
+
+impl<'d, D: Driver<'d>> Camera<'d, D> {
+    /// Creates a new [`Camera`] device.
+    ///
+    /// Panics if no format, frame size or frame interval is given, or if the max packet size is
+    /// too large.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        assert!(!config.formats.is_empty(), "no video format");
+        assert!(config.formats.len() < u8::MAX as usize);
+        for format in config.formats {
+            assert!(!format.frames().is_empty(), "no frame size");
+            assert!(format.frames().len() < u8::MAX as usize);
+            for frame in format.frames() {
+                assert!(!frame.intervals.is_empty(), "no frame interval");
+                assert!(frame.intervals.len() <= MAX_FRAME_INTERVAL_COUNT);
+            }
+        }
+        assert!(config.max_packet_size as usize > PAYLOAD_HEADER_LEN);
+        assert!(config.max_packet_size as usize <= MAX_PACKET_SIZE);
+
+        let version = config.version;
+        let mut func = builder.function(CC_VIDEO, SC_VIDEO_INTERFACE_COLLECTION, PC_PROTOCOL_UNDEFINED);
+
+        // Video control interface [UVC 3.7]
+        let mut interface = func.interface();
+        let control_interface = interface.interface_number();
+        let streaming_interface = u8::from(control_interface) + 1;
+        let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOCONTROL, version.protocol(), None);
+
+        // Terminal topology:
+        // Camera terminal (the sensor) -> Output terminal (the streaming interface)
+
+        // ==============================================
+        // Camera Terminal Descriptor [UVC 3.7.2.3]
+        let camera_terminal_descriptor = [
+            VC_INPUT_TERMINAL,  // bDescriptorSubtype
+            CAMERA_TERMINAL_ID, // bTerminalID
+            ITT_CAMERA as u8,
+            (ITT_CAMERA >> 8) as u8, // wTerminalType
+            0x00,                    // bAssocTerminal (none)
+            0x00,                    // iTerminal (none)
+            0x00,
+            0x00, // wObjectiveFocalLengthMin (not supported)
+            0x00,
+            0x00, // wObjectiveFocalLengthMax (not supported)
+            0x00,
+            0x00, // wOcularFocalLength (not supported)
+            0x03, // bControlSize
+            0x00,
+            0x00,
+            0x00, // bmControls (none)
+        ];
+
+        // ==============================================
+        // Output Terminal Descriptor [UVC 3.7.2.2]
+        let output_terminal_descriptor = [
+            VC_OUTPUT_TERMINAL, // bDescriptorSubtype
+            OUTPUT_TERMINAL_ID, // bTerminalID
+            TT_STREAMING as u8,
+            (TT_STREAMING >> 8) as u8, // wTerminalType
+            0x00,                      // bAssocTerminal (none)
+            CAMERA_TERMINAL_ID,        // bSourceID
+            0x00,                      // iTerminal (none)
+        ];
+
+        // ==================================================
+        // Class-specific VC Interface Header Descriptor [UVC 3.7.2]
+        const DESCRIPTOR_HEADER_SIZE: usize = 2;
+        const HEADER_DESCRIPTOR_SIZE: usize = 11;
+
+        let total_length = 3 * DESCRIPTOR_HEADER_SIZE
+            + HEADER_DESCRIPTOR_SIZE
+            + camera_terminal_descriptor.len()
+            + output_terminal_descriptor.len();
+
+        let clock = CLOCK_FREQUENCY_HZ.to_le_bytes();
+        let header_descriptor: [u8; HEADER_DESCRIPTOR_SIZE] = [
+            VC_HEADER, // bDescriptorSubtype
+            version.bcd() as u8,
+            (version.bcd() >> 8) as u8, // bcdUVC
+            total_length as u8,
+            (total_length >> 8) as u8, // wTotalLength
+            clock[0],
+            clock[1],
+            clock[2],
+            clock[3],            // dwClockFrequency
+            0x01,                // bInCollection (1 streaming interface)
+            streaming_interface, // baInterfaceNr
+        ];
+
+        alt.descriptor(CS_INTERFACE, &header_descriptor);
+        alt.descriptor(CS_INTERFACE, &camera_terminal_descriptor);
+        alt.descriptor(CS_INTERFACE, &output_terminal_descriptor);
+
+        // ==============================================================
+        // Video streaming interface, zero-bandwidth for isochronous transfers [UVC 3.9]
+        let mut interface = func.interface();
+        let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, version.protocol(), None);
+
+        let (ep_type, interval_ms) = match config.transfer {
+            Transfer::Isochronous => (EndpointType::Isochronous, 1),
+            Transfer::Bulk => (EndpointType::Bulk, 0),
+        };
+        let streaming_endpoint = alt.alloc_endpoint_in(ep_type, None, config.max_packet_size, interval_ms);
+
+        // ==================================================
+        // Class-specific VS Input Header Descriptor [UVC 3.9.2.1]
+        let format_count = config.formats.len();
+        let total_length = DESCRIPTOR_HEADER_SIZE
+            + 11
+            + format_count
+            + config.formats.iter().map(Format::descriptors_len).sum::<usize>();
+
+        let mut input_header_descriptor: Vec<u8, { 11 + u8::MAX as usize }> = Vec::from_slice(&[
+            VS_INPUT_HEADER,    // bDescriptorSubtype
+            format_count as u8, // bNumFormats
+            total_length as u8,
+            (total_length >> 8) as u8,             // wTotalLength
+            streaming_endpoint.info().addr.into(), // bEndpointAddress
+            0x00,                                  // bmInfo (no dynamic format change)
+            OUTPUT_TERMINAL_ID,                    // bTerminalLink
+            0x00,                                  // bStillCaptureMethod (none)
+            0x00,                                  // bTriggerSupport (none)
+            0x00,                                  // bTriggerUsage
+            0x01,                                  // bControlSize
+        ])
+        .unwrap();
+
+        // No controls per format
+        for _format in config.formats {
+            input_header_descriptor.push(0x00).unwrap();
+        }
+
+        alt.descriptor(CS_INTERFACE, &input_header_descriptor);
+
+        for (format_index, format) in config.formats.iter().enumerate() {
+            let format_index = format_index as u8 + 1;
+            let frame_count = format.frames().len() as u8;
+
+            // ==================================================
+            // Format Descriptors [UVC Uncompressed 3.1.1, UVC MJPEG 3.1.1]
+            let frame_subtype = match format {
+                Format::Yuy2(_) => {
+                    let mut descriptor = [0; 25];
+                    descriptor[..3].copy_from_slice(&[
+                        VS_FORMAT_UNCOMPRESSED, // bDescriptorSubtype
+                        format_index,           // bFormatIndex
+                        frame_count,            // bNumFrameDescriptors
+                    ]);
+                    descriptor[3..19].copy_from_slice(&GUID_YUY2); // guidFormat
+                    descriptor[19..].copy_from_slice(&[
+                        16,   // bBitsPerPixel
+                        0x01, // bDefaultFrameIndex
+                        0x00, // bAspectRatioX
+                        0x00, // bAspectRatioY
+                        0x00, // bmInterlaceFlags (progressive)
+                        0x00, // bCopyProtect (none)
+                    ]);
+                    alt.descriptor(CS_INTERFACE, &descriptor);
+                    VS_FRAME_UNCOMPRESSED
+                }
+                Format::Mjpeg(_) => {
+                    alt.descriptor(
+                        CS_INTERFACE,
+                        &[
+                            VS_FORMAT_MJPEG, // bDescriptorSubtype
+                            format_index,    // bFormatIndex
+                            frame_count,     // bNumFrameDescriptors
+                            0x00,            // bmFlags (variable size samples)
+                            0x01,            // bDefaultFrameIndex
+                            0x00,            // bAspectRatioX
+                            0x00,            // bAspectRatioY
+                            0x00,            // bmInterlaceFlags (progressive)
+                            0x00,            // bCopyProtect (none)
+                        ],
+                    );
+                    VS_FRAME_MJPEG
+                }
+            };
+
+            // ==================================================
+            // Frame Descriptors [UVC Uncompressed 3.1.2, UVC MJPEG 3.1.2]
+            for (frame_index, frame) in format.frames().iter().enumerate() {
+                let min_interval = frame.intervals.iter().copied().min().unwrap();
+                let max_interval = frame.intervals.iter().copied().max().unwrap();
+
+                let mut descriptor: Vec<u8, { 24 + 4 * MAX_FRAME_INTERVAL_COUNT }> = Vec::from_slice(&[
+                    frame_subtype,         // bDescriptorSubtype
+                    frame_index as u8 + 1, // bFrameIndex
+                    0x00,                  // bmCapabilities (no still image)
+                ])
+                .unwrap();
+                descriptor.extend_from_slice(&frame.width.to_le_bytes()).unwrap(); // wWidth
+                descriptor.extend_from_slice(&frame.height.to_le_bytes()).unwrap(); // wHeight
+                descriptor
+                    .extend_from_slice(&frame.bit_rate(max_interval).to_le_bytes())
+                    .unwrap(); // dwMinBitRate
+                descriptor
+                    .extend_from_slice(&frame.bit_rate(min_interval).to_le_bytes())
+                    .unwrap(); // dwMaxBitRate
+                descriptor
+                    .extend_from_slice(&frame.max_frame_size().to_le_bytes())
+                    .unwrap(); // dwMaxVideoFrameBufferSize
+                descriptor.extend_from_slice(&frame.intervals[0].to_le_bytes()).unwrap(); // dwDefaultFrameInterval
+                descriptor.push(frame.intervals.len() as u8).unwrap(); // bFrameIntervalType (discrete)
+                for interval in frame.intervals {
+                    descriptor.extend_from_slice(&interval.to_le_bytes()).unwrap(); // dwFrameInterval
+                }
+
+                alt.descriptor(CS_INTERFACE, &descriptor);
+            }
+
+            // ==================================================
+            // Color Matching Descriptor [UVC 3.9.2.6]
+            alt.descriptor(
+                CS_INTERFACE,
+                &[
+                    VS_COLORFORMAT, // bDescriptorSubtype
+                    0x01,           // bColorPrimaries (BT.709, sRGB)
+                    0x01,           // bTransferCharacteristics (BT.709)
+                    0x04,           // bMatrixCoefficients (SMPTE 170M)
+                ],
+            );
+        }
+
+        match config.transfer {
+            Transfer::Isochronous => {
+                // ==================================================
+                // Video streaming interface, operational [UVC 3.9.1]
+                let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, version.protocol(), None);
+                alt.endpoint_descriptor(
+                    streaming_endpoint.info(),
+                    SynchronizationType::Asynchronous,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+            Transfer::Bulk => {
+                alt.endpoint_descriptor(
+                    streaming_endpoint.info(),
+                    SynchronizationType::NoSynchronization,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+        }
+
+        // Free up the builder.
+        drop(func);
+
+        let State {
+            control,
+            shared,
+            packet,
+        } = state;
+        let shared: &'d SharedControl = shared;
+        let default = Probe {
+            format_index: 1,
+            frame_index: 1,
+            frame_interval: config.formats[0].frames()[0].intervals[0],
+        };
+        shared.commit.lock(|x| x.set(default));
+
+        *control = Some(Control {
+            shared,
+            control_interface,
+            streaming_interface: InterfaceNumber::new(streaming_interface),
+            formats: config.formats,
+            transfer: config.transfer,
+            max_packet_size: config.max_packet_size,
+            version,
+            probe: default,
+            error_code: ERROR_NONE,
+        });
+
+        builder.handler(control.as_mut().unwrap());
+
+        Self {
+            streaming_endpoint,
+            shared,
+            packet,
+            max_packet_size: config.max_packet_size as usize,
+            fid: false,
+        }
+    }
+
+    /// Waits for the host to start streaming, and returns the committed settings.
+    ///
+    /// Frames must then be written with these settings, until a write fails because the host
+    /// stopped streaming or changed the settings.
+    pub async fn wait_streaming(&mut self) -> Settings {
+        let shared = self.shared;
+        poll_fn(|cx| {
+            if shared.streaming.load(Ordering::Relaxed) {
+                shared.changed.store(false, Ordering::Relaxed);
+                Poll::Ready(shared.commit.lock(|x| x.get()).settings())
+            } else {
+                shared.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Writes a whole video frame.
+    ///
+    /// Returns [`EndpointError::Disabled`] when the host stopped streaming or committed other
+    /// settings, in which case [`wait_streaming`](Self::wait_streaming) must be called again.
+    pub async fn write_frame(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        let mut frame = self.frame();
+        frame.write(data).await?;
+        frame.finish().await
+    }
+
+    /// Starts writing a video frame in parts, for example line by line.
+    pub fn frame(&mut self) -> FrameWriter<'_, 'd, D> {
+        self.fid = !self.fid;
+        FrameWriter {
+            camera: self,
+            len: PAYLOAD_HEADER_LEN,
+        }
+    }
+}
+
+/// Writer of a video frame, split in payloads.
+///
+/// Every packet is a payload, starting with a header. The last one of the frame, marked as its end,
+/// is sent by [`finish`](Self::finish). A frame dropped before being finished is left incomplete;
+/// the host detects the start of the next one.
+pub struct FrameWriter<'a, 'd, D: Driver<'d>> {
+    camera: &'a mut Camera<'d, D>,
+    /// Length of the pending packet, including its header.
+    len: usize,
+}
+
+impl<'a, 'd, D: Driver<'d>> FrameWriter<'a, 'd, D> {
+    /// Writes the next part of the frame.
+    ///
+    /// Data is sent by packets, so a part of the frame may stay pending until the next write.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), EndpointError> {
+        while !data.is_empty() {
+            // Full packets are only sent once more data comes, as the last one must be marked.
+            if self.len == self.camera.max_packet_size {
+                self.send(0).await?;
+            }
+            let n = data.len().min(self.camera.max_packet_size - self.len);
+            self.camera.packet[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Sends the end of the frame.
+    pub async fn finish(mut self) -> Result<(), EndpointError> {
+        self.send(HEADER_EOF).await
+    }
+
+    async fn send(&mut self, flags: u8) -> Result<(), EndpointError> {
+        let camera = &mut *self.camera;
+        if camera.shared.changed.load(Ordering::Relaxed) {
+            return Err(EndpointError::Disabled);
+        }
+
+        camera.packet[0] = PAYLOAD_HEADER_LEN as u8; // bHeaderLength
+        camera.packet[1] = HEADER_EOH | flags | if camera.fid { HEADER_FID } else { 0 }; // bmHeaderInfo
+        camera.streaming_endpoint.write(&camera.packet[..self.len]).await?;
+        self.len = PAYLOAD_HEADER_LEN;
+        Ok(())
+    }
+}
+
+struct Control<'d> {
+    shared: &'d SharedControl,
+    control_interface: InterfaceNumber,
+    streaming_interface: InterfaceNumber,
+    formats: &'d [Format<'d>],
+    transfer: Transfer,
+    max_packet_size: u16,
+    version: Version,
+    /// Settings under negotiation.
+    probe: Probe,
+    /// Error code of the last request, reported by the request error code control.
+    error_code: u8,
+}
+
+/// Shared data between [`Control`] and the [`Camera`] class.
+struct SharedControl {
+    /// The committed settings.
+    commit: CriticalSectionMutex<Cell<Probe>>,
+
+    /// Whether the host is streaming.
+    streaming: AtomicBool,
+
+    // Notification mechanism.
+    waker: RefCell<WakerRegistration>,
+    changed: AtomicBool,
+}
+
+impl Default for SharedControl {
+    fn default() -> Self {
+        SharedControl {
+            commit: CriticalSectionMutex::new(Cell::new(Probe {
+                format_index: 1,
+                frame_index: 1,
+                frame_interval: 0,
+            })),
+            streaming: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+            changed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SharedControl {
+    fn set_streaming(&self, streaming: bool) {
+        self.streaming.store(streaming, Ordering::Relaxed);
+        self.changed.store(true, Ordering::Relaxed);
+        self.waker.borrow_mut().wake();
+    }
+}
+
+impl<'d> Control<'d> {
+    fn default_probe(&self) -> Probe {
+        Probe {
+            format_index: 1,
+            frame_index: 1,
+            frame_interval: self.formats[0].frames()[0].intervals[0],
+        }
+    }
+
+    /// Adjust the settings requested by the host to supported ones.
+    fn negotiate(&self, data: &[u8]) -> Probe {
+        let mut format_index = data[2];
+        if format_index == 0 || format_index as usize > self.formats.len() {
+            format_index = 1;
+        }
+        let frames = self.formats[format_index as usize - 1].frames();
+
+        let mut frame_index = data[3];
+        if frame_index == 0 || frame_index as usize > frames.len() {
+            frame_index = 1;
+        }
+        let intervals = frames[frame_index as usize - 1].intervals;
+
+        // Zero lets the device choose; other intervals are rounded to the closest supported one.
+        let requested = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let frame_interval = if requested == 0 {
+            intervals[0]
+        } else {
+            intervals
+                .iter()
+                .copied()
+                .min_by_key(|interval| interval.abs_diff(requested))
+                .unwrap()
+        };
+
+        Probe {
+            format_index,
+            frame_index,
+            frame_interval,
+        }
+    }
+
+    /// Serialize a probe or commit control [UVC 4.3.1.1].
+    fn write_probe(&self, probe: &Probe, buf: &mut [u8; MAX_PROBE_LEN]) -> usize {
+        let frame = &self.formats[probe.format_index as usize - 1].frames()[probe.frame_index as usize - 1];
+
+        buf.fill(0);
+        // bmHint, wKeyFrameRate, wPFrameRate, wCompQuality, wCompWindowSize and wDelay are zero.
+        buf[2] = probe.format_index; // bFormatIndex
+        buf[3] = probe.frame_index; // bFrameIndex
+        buf[4..8].copy_from_slice(&probe.frame_interval.to_le_bytes()); // dwFrameInterval
+        buf[18..22].copy_from_slice(&frame.max_frame_size().to_le_bytes()); // dwMaxVideoFrameSize
+        // Every packet is a payload.
+        buf[22..26].copy_from_slice(&(self.max_packet_size as u32).to_le_bytes()); // dwMaxPayloadTransferSize
+        buf[26..30].copy_from_slice(&CLOCK_FREQUENCY_HZ.to_le_bytes()); // dwClockFrequency
+        buf[30] = HEADER_FID | HEADER_EOF; // bmFramingInfo
+        buf[31] = 0x01; // bPreferedVersion
+        buf[32] = 0x01; // bMinVersion
+        buf[33] = 0x01; // bMaxVersion
+        // The UVC 1.5 fields, for encoding units, are zero.
+
+        self.version.probe_len()
+    }
+
+    fn streaming_set_request(&mut self, req: Request, data: &[u8]) -> OutResponse {
+        let selector = (req.value >> 8) as u8;
+
+        if req.request != SET_CUR {
+            self.error_code = ERROR_INVALID_REQUEST;
+            return OutResponse::Rejected;
+        }
+        if !matches!(selector, VS_PROBE_CONTROL | VS_COMMIT_CONTROL) {
+            self.error_code = ERROR_INVALID_CONTROL;
+            return OutResponse::Rejected;
+        }
+        // UVC 1.0 hosts send shorter controls, whose fields used here are the same.
+        if data.len() < 26 {
+            self.error_code = ERROR_INVALID_REQUEST;
+            return OutResponse::Rejected;
+        }
+
+        self.probe = self.negotiate(data);
+        if selector == VS_COMMIT_CONTROL {
+            debug!(
+                "uvc: commit format {} frame {} interval {}",
+                self.probe.format_index, self.probe.frame_index, self.probe.frame_interval
+            );
+            self.shared.commit.lock(|x| x.set(self.probe));
+            // Isochronous streaming starts with the selection of the operational alternate setting.
+            self.shared.set_streaming(self.transfer == Transfer::Bulk);
+        }
+
+        self.error_code = ERROR_NONE;
+        OutResponse::Accepted
+    }
+
+    fn streaming_get_request<'a>(&mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
+        let selector = (req.value >> 8) as u8;
+
+        if !matches!(selector, VS_PROBE_CONTROL | VS_COMMIT_CONTROL) {
+            self.error_code = ERROR_INVALID_CONTROL;
+            return InResponse::Rejected;
+        }
+
+        let probe = match req.request {
+            GET_CUR if selector == VS_COMMIT_CONTROL => self.shared.commit.lock(|x| x.get()),
+            GET_CUR => self.probe,
+            GET_MIN | GET_MAX | GET_DEF => self.default_probe(),
+            GET_LEN => {
+                self.error_code = ERROR_NONE;
+                let len = self.version.probe_len() as u16;
+                buf[..2].copy_from_slice(&len.to_le_bytes());
+                return InResponse::Accepted(&buf[..2]);
+            }
+            GET_INFO => {
+                self.error_code = ERROR_NONE;
+                buf[0] = INFO_GET_SUPPORTED | INFO_SET_SUPPORTED;
+                return InResponse::Accepted(&buf[..1]);
+            }
+            _ => {
+                self.error_code = ERROR_INVALID_REQUEST;
+                return InResponse::Rejected;
+            }
+        };
+
+        let mut data = [0; MAX_PROBE_LEN];
+        let len = self.write_probe(&probe, &mut data).min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+
+        self.error_code = ERROR_NONE;
+        InResponse::Accepted(&buf[..len])
+    }
+
+    fn control_get_request<'a>(&mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
+        let selector = (req.value >> 8) as u8;
+        // Only the request error code control of the interface itself is supported.
+        let entity = (req.index >> 8) as u8;
+
+        if entity != 0 || selector != VC_REQUEST_ERROR_CODE_CONTROL {
+            self.error_code = ERROR_INVALID_CONTROL;
+            return InResponse::Rejected;
+        }
+
+        match req.request {
+            GET_CUR => buf[0] = self.error_code,
+            GET_INFO => buf[0] = INFO_GET_SUPPORTED,
+            _ => {
+                self.error_code = ERROR_INVALID_REQUEST;
+                return InResponse::Rejected;
+            }
+        }
+        InResponse::Accepted(&buf[..1])
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.probe = self.default_probe();
+        self.error_code = ERROR_NONE;
+        self.shared.set_streaming(false);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            self.shared.set_streaming(false);
+        }
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface == self.streaming_interface && self.transfer == Transfer::Isochronous {
+            debug!("uvc: streaming {}", alternate_setting != 0);
+            self.shared.set_streaming(alternate_setting != 0);
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+
+        let iface = InterfaceNumber::new(req.index as u8);
+        if iface == self.streaming_interface {
+            Some(self.streaming_set_request(req, data))
+        } else if iface == self.control_interface {
+            // No control of the video control interface can be set.
+            self.error_code = ERROR_INVALID_CONTROL;
+            Some(OutResponse::Rejected)
+        } else {
+            None
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+
+        let iface = InterfaceNumber::new(req.index as u8);
+        if iface == self.streaming_interface {
+            Some(self.streaming_get_request(req, buf))
+        } else if iface == self.control_interface {
+            Some(self.control_get_request(req, buf))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: &[Format<'static>] = &[
+        Format::Yuy2(&[
+            Frame {
+                width: 640,
+                height: 480,
+                intervals: &[333_333, 666_666],
+            },
+            Frame {
+                width: 320,
+                height: 240,
+                intervals: &[333_333],
+            },
+        ]),
+        Format::Mjpeg(&[Frame {
+            width: 1280,
+            height: 720,
+            intervals: &[333_333, 500_000, 1_000_000],
+        }]),
+    ];
+
+    const CONTROL_INTERFACE: u16 = 0;
+    const STREAMING_INTERFACE: u16 = 1;
+
+    fn control(shared: &SharedControl, transfer: Transfer, version: Version) -> Control<'_> {
+        let mut control = Control {
+            shared,
+            control_interface: InterfaceNumber::new(CONTROL_INTERFACE as u8),
+            streaming_interface: InterfaceNumber::new(STREAMING_INTERFACE as u8),
+            formats: FORMATS,
+            transfer,
+            max_packet_size: 1024,
+            version,
+            probe: Probe {
+                format_index: 1,
+                frame_index: 1,
+                frame_interval: 0,
+            },
+            error_code: ERROR_NONE,
+        };
+        control.reset();
+        control
+    }
+
+    fn request(request_type: u8, request: u8, selector: u8, iface: u16, length: u16) -> Request {
+        let mut buf = [request_type, request, 0, selector, 0, 0, 0, 0];
+        buf[4..6].copy_from_slice(&iface.to_le_bytes());
+        buf[6..8].copy_from_slice(&length.to_le_bytes());
+        Request::parse(&buf)
+    }
+
+    /// Probe or commit control of UVC 1.1 with the given format, frame and interval.
+    fn probe(format_index: u8, frame_index: u8, frame_interval: u32) -> [u8; 34] {
+        let mut data = [0; 34];
+        data[0] = 0x01; // bmHint: dwFrameInterval
+        data[2] = format_index;
+        data[3] = frame_index;
+        data[4..8].copy_from_slice(&frame_interval.to_le_bytes());
+        data
+    }
+
+    fn set(control: &mut Control, selector: u8, iface: u16, data: &[u8]) -> OutResponse {
+        let req = request(0x21, SET_CUR, selector, iface, data.len() as u16);
+        control.control_out(req, data).unwrap()
+    }
+
+    /// Send a GET request, returning the response if it was accepted.
+    fn get(control: &mut Control, request_code: u8, selector: u8, iface: u16) -> Option<Vec<u8, 64>> {
+        let mut buf = [0; 64];
+        let req = request(0xA1, request_code, selector, iface, 64);
+        match control.control_in(req, &mut buf).unwrap() {
+            InResponse::Accepted(data) => Some(Vec::from_slice(data).unwrap()),
+            InResponse::Rejected => None,
+        }
+    }
+
+    /// The format, frame and interval of a probe or commit control.
+    fn fields(data: &[u8]) -> (u8, u8, u32) {
+        (data[2], data[3], u32::from_le_bytes(data[4..8].try_into().unwrap()))
+    }
+
+    fn error_code(control: &mut Control) -> u8 {
+        get(control, GET_CUR, VC_REQUEST_ERROR_CODE_CONTROL, CONTROL_INTERFACE).unwrap()[0]
+    }
+
+    #[test]
+    fn probe_response() {
+        let shared = SharedControl::default();
+        let mut control = control(&shared, Transfer::Bulk, Version::Uvc11);
+
+        let response = set(
+            &mut control,
+            VS_PROBE_CONTROL,
+            STREAMING_INTERFACE,
+            &probe(2, 1, 450_000),
+        );
+        assert_eq!(response, OutResponse::Accepted);
+        let data = get(&mut control, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(
+            data.as_slice(),
+            [
+                0x00, 0x00, // bmHint
+                0x02, 0x01, // bFormatIndex, bFrameIndex
+                0x20, 0xA1, 0x07, 0x00, // dwFrameInterval: 500_000, the closest one
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x20, 0x1C, 0x00, // dwMaxVideoFrameSize: 1280 * 720 * 2
+                0x00, 0x04, 0x00, 0x00, // dwMaxPayloadTransferSize
+                0x00, 0x6C, 0xDC, 0x02, // dwClockFrequency: 48 MHz
+                0x03, 0x01, 0x01, 0x01, // bmFramingInfo, bPreferedVersion, bMinVersion, bMaxVersion
+            ]
+        );
+
+        // UVC 1.5 controls are longer, with zero encoding fields.
+        let mut control = self::control(&shared, Transfer::Bulk, Version::Uvc15);
+        let data = get(&mut control, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(data.len(), 48);
+        assert_eq!(fields(&data), (1, 1, 333_333));
+        assert_eq!(data[34..], [0; 14]);
+    }
+
+    #[test]
+    fn probe_clamping() {
+        let shared = SharedControl::default();
+        let mut control = control(&shared, Transfer::Bulk, Version::Uvc11);
+        let mut negotiate = |data: &[u8]| {
+            assert_eq!(
+                set(&mut control, VS_PROBE_CONTROL, STREAMING_INTERFACE, data),
+                OutResponse::Accepted
+            );
+            fields(&get(&mut control, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap())
+        };
+
+        assert_eq!(negotiate(&probe(1, 2, 333_333)), (1, 2, 333_333));
+        assert_eq!(negotiate(&probe(1, 1, 600_000)), (1, 1, 666_666));
+        assert_eq!(negotiate(&probe(1, 1, u32::MAX)), (1, 1, 666_666));
+        assert_eq!(negotiate(&probe(1, 1, 1)), (1, 1, 333_333));
+        // Zero lets the device choose the default interval.
+        assert_eq!(negotiate(&probe(2, 1, 0)), (2, 1, 333_333));
+        // Invalid formats and frames fall back to the first ones.
+        assert_eq!(negotiate(&probe(0, 1, 400_000)), (1, 1, 333_333));
+        assert_eq!(negotiate(&probe(3, 1, 500_000)), (1, 1, 666_666));
+        assert_eq!(negotiate(&probe(1, 0, 666_666)), (1, 1, 666_666));
+        assert_eq!(negotiate(&probe(2, 2, 1_000_000)), (2, 1, 1_000_000));
+        // UVC 1.0 hosts send 26 bytes.
+        assert_eq!(negotiate(&probe(2, 1, 500_000)[..26]), (2, 1, 500_000));
+    }
+
+    #[test]
+    fn probe_requests() {
+        let shared = SharedControl::default();
+        let mut control = control(&shared, Transfer::Bulk, Version::Uvc11);
+
+        set(
+            &mut control,
+            VS_PROBE_CONTROL,
+            STREAMING_INTERFACE,
+            &probe(2, 1, 1_000_000),
+        );
+        for request in [GET_MIN, GET_MAX, GET_DEF] {
+            let data = get(&mut control, request, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+            assert_eq!(data.len(), 34);
+            assert_eq!(fields(&data), (1, 1, 333_333));
+        }
+        let data = get(&mut control, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(fields(&data), (2, 1, 1_000_000));
+        let data = get(&mut control, GET_LEN, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(data.as_slice(), [34, 0]);
+        let data = get(&mut control, GET_INFO, VS_COMMIT_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(data.as_slice(), [INFO_GET_SUPPORTED | INFO_SET_SUPPORTED]);
+        assert_eq!(error_code(&mut control), ERROR_NONE);
+
+        // Responses are cut to the requested length.
+        let mut buf = [0; 64];
+        let req = request(0xA1, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE, 26);
+        let response = control.control_in(req, &mut buf[..26]).unwrap();
+        assert!(matches!(response, InResponse::Accepted(data) if data.len() == 26));
+
+        let mut control = self::control(&shared, Transfer::Bulk, Version::Uvc15);
+        let data = get(&mut control, GET_LEN, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(data.as_slice(), [48, 0]);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let shared = SharedControl::default();
+        let mut control = control(&shared, Transfer::Bulk, Version::Uvc11);
+
+        assert!(get(&mut control, GET_RES, VS_PROBE_CONTROL, STREAMING_INTERFACE).is_none());
+        assert_eq!(error_code(&mut control), ERROR_INVALID_REQUEST);
+        assert!(get(&mut control, GET_CUR, 0x03, STREAMING_INTERFACE).is_none());
+        assert_eq!(error_code(&mut control), ERROR_INVALID_CONTROL);
+
+        let short = &probe(2, 1, 0)[..25];
+        let response = set(&mut control, VS_PROBE_CONTROL, STREAMING_INTERFACE, short);
+        assert_eq!(response, OutResponse::Rejected);
+        assert_eq!(error_code(&mut control), ERROR_INVALID_REQUEST);
+        let data = get(&mut control, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(fields(&data), (1, 1, 333_333));
+
+        let response = set(&mut control, 0x03, STREAMING_INTERFACE, &probe(2, 1, 0));
+        assert_eq!(response, OutResponse::Rejected);
+        assert_eq!(error_code(&mut control), ERROR_INVALID_CONTROL);
+        let response = set(&mut control, VC_REQUEST_ERROR_CODE_CONTROL, CONTROL_INTERFACE, &[0]);
+        assert_eq!(response, OutResponse::Rejected);
+        let req = request(0x21, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE, 34);
+        assert_eq!(control.control_out(req, &probe(2, 1, 0)), Some(OutResponse::Rejected));
+        assert_eq!(error_code(&mut control), ERROR_INVALID_REQUEST);
+
+        let data = get(&mut control, GET_INFO, VC_REQUEST_ERROR_CODE_CONTROL, CONTROL_INTERFACE).unwrap();
+        assert_eq!(data.as_slice(), [INFO_GET_SUPPORTED]);
+        assert!(get(&mut control, GET_CUR, VC_VIDEO_POWER_MODE_CONTROL, CONTROL_INTERFACE).is_none());
+        assert_eq!(error_code(&mut control), ERROR_INVALID_CONTROL);
+
+        // Requests of other interfaces are not handled.
+        let req = request(0xA1, GET_CUR, VS_PROBE_CONTROL, 2, 34);
+        assert!(control.control_in(req, &mut [0; 64]).is_none());
+        let req = request(0xC1, GET_CUR, VS_PROBE_CONTROL, STREAMING_INTERFACE, 34);
+        assert!(control.control_in(req, &mut [0; 64]).is_none());
+    }
+
+    #[test]
+    fn commit() {
+        let shared = SharedControl::default();
+        let mut control = control(&shared, Transfer::Bulk, Version::Uvc11);
+
+        set(&mut control, VS_PROBE_CONTROL, STREAMING_INTERFACE, &probe(1, 2, 0));
+        assert!(!shared.streaming.load(Ordering::Relaxed));
+        let response = set(
+            &mut control,
+            VS_COMMIT_CONTROL,
+            STREAMING_INTERFACE,
+            &probe(2, 1, 490_000),
+        );
+        assert_eq!(response, OutResponse::Accepted);
+        // Bulk streaming starts with the commit.
+        assert!(shared.streaming.load(Ordering::Relaxed));
+        let settings = shared.commit.lock(|x| x.get()).settings();
+        assert_eq!(
+            settings,
+            Settings {
+                format: 1,
+                frame: 0,
+                frame_interval: 500_000,
+            }
+        );
+        let data = get(&mut control, GET_CUR, VS_COMMIT_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(fields(&data), (2, 1, 500_000));
+
+        // The probe changes, but not the committed settings.
+        set(&mut control, VS_PROBE_CONTROL, STREAMING_INTERFACE, &probe(1, 2, 0));
+        let data = get(&mut control, GET_CUR, VS_COMMIT_CONTROL, STREAMING_INTERFACE).unwrap();
+        assert_eq!(fields(&data), (2, 1, 500_000));
+        control.configured(false);
+        assert!(!shared.streaming.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn commit_isochronous() {
+        let shared = SharedControl::default();
+        let mut control = control(&shared, Transfer::Isochronous, Version::Uvc11);
+
+        set(&mut control, VS_COMMIT_CONTROL, STREAMING_INTERFACE, &probe(1, 1, 0));
+        // Isochronous streaming starts with the operational alternate setting.
+        assert!(!shared.streaming.load(Ordering::Relaxed));
+        control.set_alternate_setting(InterfaceNumber::new(STREAMING_INTERFACE as u8), 1);
+        assert!(shared.streaming.load(Ordering::Relaxed));
+        control.set_alternate_setting(InterfaceNumber::new(CONTROL_INTERFACE as u8), 0);
+        assert!(shared.streaming.load(Ordering::Relaxed));
+        control.set_alternate_setting(InterfaceNumber::new(STREAMING_INTERFACE as u8), 0);
+        assert!(!shared.streaming.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,1441 @@
+//! Compliance checks of the descriptors of a device.
+//!
+//! Mistakes in descriptors, such as endpoint addresses used by two interfaces, interface
+//! association descriptors (IAD) not grouping their interfaces, or MS OS 2.0 descriptor sets
+//! not matching their BOS capability, are not detected by every host: Windows, for example,
+//! refuses devices that other hosts accept. [`check`] validates the descriptors of a device
+//! against the USB 2.0 specification, the IAD ECN and the Microsoft OS 2.0 descriptors
+//! specification, and reports human-readable diagnostics.
+//!
+//! It can run in a host unit test, on the descriptors of a [`UsbDevice`](crate::UsbDevice)
+//! built with a mock driver, or on the device itself:
+//!
+//! ```ignore
+//! let usb = builder.build();
+//! let errors = embassy_usb::compliance::check(&usb.descriptors(), |diagnostic| {
+//!     println!("{}", diagnostic);
+//! });
+//! assert_eq!(errors, 0);
+//! ```
+
+use core::fmt;
+
+use crate::descriptor::{capability_type, descriptor_type};
+use crate::msos::DescriptorType as MsOsType;
+use crate::{UsbDeviceSpeed, msos};
+
+/// The descriptors of a device.
+#[derive(Clone, Copy, Debug)]
+pub struct Descriptors<'a> {
+    /// Device descriptor.
+    pub device: &'a [u8],
+    /// Configuration descriptors, with their interface and endpoint descriptors, one after the
+    /// other.
+    pub configurations: &'a [u8],
+    /// Binary device object store (BOS) descriptor, with its capabilities, or empty.
+    pub bos: &'a [u8],
+    /// Microsoft OS 2.0 descriptor set, or empty.
+    pub msos: &'a [u8],
+    /// Fastest speed of the device, which the endpoints are checked for.
+    pub speed: UsbDeviceSpeed,
+}
+
+/// A set of descriptors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DescriptorSet {
+    /// The device descriptor.
+    Device,
+    /// The configuration descriptors.
+    Configuration,
+    /// The BOS descriptor.
+    Bos,
+    /// The Microsoft OS 2.0 descriptor set.
+    MsOs,
+}
+
+/// Severity of a diagnostic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Severity {
+    /// The descriptors are valid, but hosts may handle them badly.
+    Warning,
+    /// The descriptors are invalid.
+    Error,
+}
+
+/// A problem found in the descriptors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum Problem {
+    /// A descriptor extends past the end of its set, or is shorter than 2 bytes.
+    Truncated,
+    /// A descriptor has the wrong length.
+    Length {
+        /// Type of the descriptor.
+        descriptor_type: u16,
+        /// Expected length, or minimum length.
+        expected: usize,
+        /// Actual length.
+        actual: usize,
+    },
+    /// The total length of a descriptor does not match the length of its children.
+    TotalLength {
+        /// Declared total length.
+        declared: usize,
+        /// Actual total length.
+        actual: usize,
+    },
+    /// A descriptor is not allowed at its position.
+    UnexpectedDescriptor {
+        /// Type of the descriptor.
+        descriptor_type: u16,
+    },
+    /// The maximum packet size of the control endpoint is invalid at the speed of the device.
+    MaxPacketSize0 {
+        /// `bMaxPacketSize0`
+        size: u8,
+    },
+    /// The device descriptor declares a different number of configurations.
+    ConfigurationCount {
+        /// `bNumConfigurations`
+        declared: u8,
+        /// Number of configuration descriptors.
+        actual: usize,
+    },
+    /// IADs are used, but the device class is not Miscellaneous, with the IAD subclass and protocol.
+    IadDeviceClass {
+        /// `bDeviceClass`
+        class: u8,
+        /// `bDeviceSubClass`
+        subclass: u8,
+        /// `bDeviceProtocol`
+        protocol: u8,
+    },
+    /// The USB version is too old for the host to read the BOS descriptor.
+    BcdUsb {
+        /// `bcdUSB`
+        bcd_usb: u16,
+    },
+    /// The configuration value is 0, or used by another configuration.
+    ConfigurationValue {
+        /// `bConfigurationValue`
+        value: u8,
+    },
+    /// The configuration attributes have bit 7 clear, or reserved bits set.
+    ConfigurationAttributes {
+        /// `bmAttributes`
+        attributes: u8,
+    },
+    /// The configuration descriptor declares a different number of interfaces.
+    InterfaceCount {
+        /// `bNumInterfaces`
+        declared: u8,
+        /// Number of interfaces.
+        actual: usize,
+    },
+    /// Interface numbers are not contiguous from 0, this one is missing.
+    MissingInterface {
+        /// The missing interface number.
+        interface: u8,
+    },
+    /// The alternate settings of an interface are not numbered in order from 0, or repeated.
+    AlternateSetting {
+        /// `bInterfaceNumber`
+        interface: u8,
+        /// `bAlternateSetting`
+        alternate_setting: u8,
+    },
+    /// The interface descriptor declares a different number of endpoints.
+    EndpointCount {
+        /// `bInterfaceNumber`
+        interface: u8,
+        /// `bAlternateSetting`
+        alternate_setting: u8,
+        /// `bNumEndpoints`
+        declared: u8,
+        /// Number of endpoint descriptors.
+        actual: usize,
+    },
+    /// The endpoint address is 0, or has reserved bits set.
+    EndpointAddress {
+        /// `bEndpointAddress`
+        address: u8,
+    },
+    /// The endpoint address is used twice in an alternate setting.
+    DuplicateEndpoint {
+        /// `bEndpointAddress`
+        address: u8,
+        /// `bInterfaceNumber`
+        interface: u8,
+    },
+    /// The endpoint address is used by two interfaces.
+    EndpointCollision {
+        /// `bEndpointAddress`
+        address: u8,
+        /// `bInterfaceNumber`
+        interface: u8,
+        /// The other interface using the endpoint.
+        other_interface: u8,
+    },
+    /// The endpoint attributes have reserved bits set.
+    EndpointAttributes {
+        /// `bEndpointAddress`
+        address: u8,
+        /// `bmAttributes`
+        attributes: u8,
+    },
+    /// The maximum packet size is invalid for the transfer type at the speed of the device.
+    MaxPacketSize {
+        /// `bEndpointAddress`
+        address: u8,
+        /// `wMaxPacketSize`
+        max_packet_size: u16,
+    },
+    /// The polling interval is invalid for the transfer type at the speed of the device.
+    Interval {
+        /// `bEndpointAddress`
+        address: u8,
+        /// `bInterval`
+        interval: u8,
+    },
+    /// The interfaces of an IAD are out of range, or do not exist.
+    IadRange {
+        /// `bFirstInterface`
+        first_interface: u8,
+        /// `bInterfaceCount`
+        interface_count: u8,
+    },
+    /// An interface is grouped by two IADs.
+    IadOverlap {
+        /// `bInterfaceNumber`
+        interface: u8,
+    },
+    /// The interfaces of an IAD do not follow it, another interface comes first.
+    IadGrouping {
+        /// `bFirstInterface`
+        first_interface: u8,
+        /// `bInterfaceCount`
+        interface_count: u8,
+        /// The interface found instead.
+        interface: u8,
+    },
+    /// The BOS descriptor declares a different number of capabilities.
+    CapabilityCount {
+        /// `bNumDeviceCaps`
+        declared: u8,
+        /// Number of capability descriptors.
+        actual: usize,
+    },
+    /// There is an MS OS 2.0 descriptor set, but no platform capability announcing it in the BOS
+    /// descriptor.
+    MissingMsOsCapability,
+    /// The length of the MS OS 2.0 descriptor set in the platform capability is wrong.
+    MsOsSetLength {
+        /// `wMSOSDescriptorSetTotalLength`
+        declared: usize,
+        /// Length of the descriptor set.
+        actual: usize,
+    },
+    /// The Windows version of the MS OS 2.0 descriptor set is older than Windows 8.1.
+    MsOsWindowsVersion {
+        /// `dwWindowsVersion`
+        version: u32,
+    },
+    /// A configuration subset refers to a configuration that does not exist. Its
+    /// `bConfigurationValue` is the index of the configuration, not its value.
+    MsOsConfiguration {
+        /// `bConfigurationValue`
+        index: u8,
+    },
+    /// A function subset refers to an interface that does not exist in its configuration.
+    MsOsFunction {
+        /// `bFirstInterface`
+        first_interface: u8,
+    },
+    /// A compatible ID is not made of uppercase letters, digits and underscores, padded with zeros.
+    MsOsCompatibleId,
+    /// The compatible ID of a composite device is set for the whole device, instead of a function
+    /// subset, so Windows ignores it.
+    MsOsDeviceCompatibleId,
+}
+
+impl Problem {
+    /// Returns the severity of the problem.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Problem::MsOsCompatibleId | Problem::MsOsDeviceCompatibleId => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Problem::Truncated => write!(f, "descriptor truncated"),
+            Problem::Length {
+                descriptor_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "descriptor of type {:#04x} is {} bytes long, expected {}",
+                descriptor_type, actual, expected
+            ),
+            Problem::TotalLength { declared, actual } => {
+                write!(
+                    f,
+                    "total length is {}, but the descriptors take {} bytes",
+                    declared, actual
+                )
+            }
+            Problem::UnexpectedDescriptor { descriptor_type } => {
+                write!(f, "unexpected descriptor of type {:#04x}", descriptor_type)
+            }
+            Problem::MaxPacketSize0 { size } => {
+                write!(f, "invalid control endpoint max packet size {}", size)
+            }
+            Problem::ConfigurationCount { declared, actual } => {
+                write!(f, "{} configurations declared, but there are {}", declared, actual)
+            }
+            Problem::IadDeviceClass {
+                class,
+                subclass,
+                protocol,
+            } => write!(
+                f,
+                "IADs require the device class 0xef/0x02/0x01, not {:#04x}/{:#04x}/{:#04x} (set `composite_with_iads`)",
+                class, subclass, protocol
+            ),
+            Problem::BcdUsb { bcd_usb } => write!(
+                f,
+                "USB version {:#06x} is below 0x0201, hosts do not read the BOS descriptor",
+                bcd_usb
+            ),
+            Problem::ConfigurationValue { value } => write!(f, "configuration value {} is 0 or repeated", value),
+            Problem::ConfigurationAttributes { attributes } => {
+                write!(f, "invalid configuration attributes {:#04x}", attributes)
+            }
+            Problem::InterfaceCount { declared, actual } => {
+                write!(f, "{} interfaces declared, but there are {}", declared, actual)
+            }
+            Problem::MissingInterface { interface } => {
+                write!(
+                    f,
+                    "interface numbers are not contiguous, interface {} is missing",
+                    interface
+                )
+            }
+            Problem::AlternateSetting {
+                interface,
+                alternate_setting,
+            } => write!(
+                f,
+                "alternate setting {} of interface {} is out of order",
+                alternate_setting, interface
+            ),
+            Problem::EndpointCount {
+                interface,
+                alternate_setting,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "interface {} alternate setting {} declares {} endpoints, but has {}",
+                interface, alternate_setting, declared, actual
+            ),
+            Problem::EndpointAddress { address } => write!(f, "invalid endpoint address {:#04x}", address),
+            Problem::DuplicateEndpoint { address, interface } => write!(
+                f,
+                "endpoint {:#04x} appears twice in an alternate setting of interface {}",
+                address, interface
+            ),
+            Problem::EndpointCollision {
+                address,
+                interface,
+                other_interface,
+            } => write!(
+                f,
+                "endpoint {:#04x} of interface {} is also used by interface {}",
+                address, interface, other_interface
+            ),
+            Problem::EndpointAttributes { address, attributes } => write!(
+                f,
+                "endpoint {:#04x} has reserved attribute bits set: {:#04x}",
+                address, attributes
+            ),
+            Problem::MaxPacketSize {
+                address,
+                max_packet_size,
+            } => write!(
+                f,
+                "endpoint {:#04x} has an invalid max packet size {:#06x} for its type and speed",
+                address, max_packet_size
+            ),
+            Problem::Interval { address, interval } => write!(
+                f,
+                "endpoint {:#04x} has an invalid interval {} for its type and speed",
+                address, interval
+            ),
+            Problem::IadRange {
+                first_interface,
+                interface_count,
+            } => write!(
+                f,
+                "IAD of {} interfaces from {} refers to missing interfaces",
+                interface_count, first_interface
+            ),
+            Problem::IadOverlap { interface } => write!(f, "interface {} is grouped by two IADs", interface),
+            Problem::IadGrouping {
+                first_interface,
+                interface_count,
+                interface,
+            } => write!(
+                f,
+                "IAD of {} interfaces from {} must be followed by them, not by interface {}",
+                interface_count, first_interface, interface
+            ),
+            Problem::CapabilityCount { declared, actual } => {
+                write!(f, "{} capabilities declared, but there are {}", declared, actual)
+            }
+            Problem::MissingMsOsCapability => write!(f, "no MS OS 2.0 platform capability in the BOS descriptor"),
+            Problem::MsOsSetLength { declared, actual } => write!(
+                f,
+                "MS OS 2.0 descriptor set length is {} in the platform capability, but it is {} bytes long",
+                declared, actual
+            ),
+            Problem::MsOsWindowsVersion { version } => {
+                write!(f, "Windows version {:#010x} is older than Windows 8.1", version)
+            }
+            Problem::MsOsConfiguration { index } => write!(
+                f,
+                "configuration subset refers to configuration index {}, which does not exist",
+                index
+            ),
+            Problem::MsOsFunction { first_interface } => write!(
+                f,
+                "function subset refers to interface {}, which does not exist",
+                first_interface
+            ),
+            Problem::MsOsCompatibleId => write!(f, "invalid characters in compatible ID"),
+            Problem::MsOsDeviceCompatibleId => {
+                write!(f, "compatible ID of a composite device must be in a function subset")
+            }
+        }
+    }
+}
+
+/// A diagnostic of [`check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Diagnostic {
+    /// The descriptor set of the problem.
+    pub set: DescriptorSet,
+    /// Offset of the descriptor in its set.
+    pub offset: usize,
+    /// The problem.
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    /// Returns the severity of the problem.
+    pub fn severity(&self) -> Severity {
+        self.problem.severity()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity() {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        let set = match self.set {
+            DescriptorSet::Device => "device descriptor",
+            DescriptorSet::Configuration => "configuration descriptors",
+            DescriptorSet::Bos => "BOS descriptor",
+            DescriptorSet::MsOs => "MS OS 2.0 descriptor set",
+        };
+        write!(f, "{}: {}, at byte {}: {}", severity, set, self.offset, self.problem)
+    }
+}
+
+/// Checks `descriptors`, calling `report` with each diagnostic. Returns the number of errors.
+pub fn check(descriptors: &Descriptors<'_>, mut report: impl FnMut(&Diagnostic)) -> usize {
+    let mut checker = Checker {
+        report: &mut report,
+        set: DescriptorSet::Device,
+        errors: 0,
+    };
+    checker.device(descriptors);
+    checker.configurations(descriptors);
+    checker.bos(descriptors);
+    checker.msos(descriptors);
+    checker.errors
+}
+
+// Transfer types, in endpoint attributes.
+const CONTROL: u8 = 0;
+const ISOCHRONOUS: u8 = 1;
+const BULK: u8 = 2;
+const INTERRUPT: u8 = 3;
+
+/// Microsoft OS 2.0 platform capability UUID, D8DD60DF-4589-4CC7-9CD2-659D9E648A9F.
+const MSOS_PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+struct Checker<'r> {
+    report: &'r mut dyn FnMut(&Diagnostic),
+    set: DescriptorSet,
+    errors: usize,
+}
+
+/// The interface descriptor being checked, with its endpoints.
+struct CurrentInterface {
+    offset: usize,
+    interface: u8,
+    alternate_setting: u8,
+    declared: u8,
+    endpoints: usize,
+    /// Endpoints of the alternate setting, by index of [`endpoint_index`].
+    used: u32,
+}
+
+/// An IAD whose interfaces have not all been seen.
+struct PendingIad {
+    offset: usize,
+    first_interface: u8,
+    interface_count: u8,
+    seen: u8,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, offset: usize, problem: Problem) {
+        if problem.severity() == Severity::Error {
+            self.errors += 1;
+        }
+        (self.report)(&Diagnostic {
+            set: self.set,
+            offset,
+            problem,
+        });
+    }
+
+    /// Reports descriptors shorter than `expected`, returning whether it is long enough.
+    fn min_length(&mut self, offset: usize, desc: &[u8], expected: usize) -> bool {
+        if desc.len() < expected {
+            self.problem(
+                offset,
+                Problem::Length {
+                    descriptor_type: desc[1] as u16,
+                    expected,
+                    actual: desc.len(),
+                },
+            );
+            return false;
+        }
+        true
+    }
+
+    fn device(&mut self, d: &Descriptors<'_>) {
+        self.set = DescriptorSet::Device;
+        let dev = d.device;
+        if dev.len() < 2 || dev[0] as usize != dev.len() {
+            self.problem(0, Problem::Truncated);
+            return;
+        }
+        if dev[1] != descriptor_type::DEVICE {
+            self.problem(
+                0,
+                Problem::UnexpectedDescriptor {
+                    descriptor_type: dev[1] as u16,
+                },
+            );
+            return;
+        }
+        if dev.len() != 18 {
+            self.problem(
+                0,
+                Problem::Length {
+                    descriptor_type: dev[1] as u16,
+                    expected: 18,
+                    actual: dev.len(),
+                },
+            );
+            return;
+        }
+
+        let bcd_usb = u16::from_le_bytes([dev[2], dev[3]]);
+        let (class, subclass, protocol) = (dev[4], dev[5], dev[6]);
+        let max_packet_size_0 = dev[7];
+        let valid = match d.speed {
+            UsbDeviceSpeed::Full => matches!(max_packet_size_0, 8 | 16 | 32 | 64),
+            UsbDeviceSpeed::High => max_packet_size_0 == 64,
+        };
+        if !valid {
+            self.problem(
+                7,
+                Problem::MaxPacketSize0 {
+                    size: max_packet_size_0,
+                },
+            );
+        }
+
+        let mut configurations = 0;
+        let mut iads = false;
+        for (_, desc) in Walk::new(d.configurations).flatten() {
+            match desc[1] {
+                descriptor_type::CONFIGURATION => configurations += 1,
+                descriptor_type::IAD => iads = true,
+                _ => {}
+            }
+        }
+        if dev[17] as usize != configurations {
+            self.problem(
+                17,
+                Problem::ConfigurationCount {
+                    declared: dev[17],
+                    actual: configurations,
+                },
+            );
+        }
+        if iads && (class, subclass, protocol) != (0xEF, 0x02, 0x01) {
+            self.problem(
+                4,
+                Problem::IadDeviceClass {
+                    class,
+                    subclass,
+                    protocol,
+                },
+            );
+        }
+        if !d.msos.is_empty() && bcd_usb < 0x0201 {
+            self.problem(2, Problem::BcdUsb { bcd_usb });
+        }
+    }
+
+    fn configurations(&mut self, d: &Descriptors<'_>) {
+        self.set = DescriptorSet::Configuration;
+        let buf = d.configurations;
+        let mut values = [false; 256];
+
+        // Each configuration extends up to the next one.
+        let mut start = None;
+        for res in Walk::new(buf) {
+            let (offset, desc) = match res {
+                Ok(x) => x,
+                Err(offset) => {
+                    self.problem(offset, Problem::Truncated);
+                    return;
+                }
+            };
+            if desc[1] == descriptor_type::CONFIGURATION {
+                if let Some(start) = start {
+                    self.configuration(d, start, &buf[start..offset], &mut values);
+                }
+                start = Some(offset);
+            } else if start.is_none() {
+                self.problem(
+                    offset,
+                    Problem::UnexpectedDescriptor {
+                        descriptor_type: desc[1] as u16,
+                    },
+                );
+                return;
+            }
+        }
+        if let Some(start) = start {
+            self.configuration(d, start, &buf[start..], &mut values);
+        }
+    }
+
+    fn configuration(&mut self, d: &Descriptors<'_>, base: usize, buf: &[u8], values: &mut [bool; 256]) {
+        if !self.min_length(base, &buf[..buf[0] as usize], 9) {
+            return;
+        }
+        let total_length = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if total_length != buf.len() {
+            self.problem(
+                base + 2,
+                Problem::TotalLength {
+                    declared: total_length,
+                    actual: buf.len(),
+                },
+            );
+        }
+        let value = buf[5];
+        if value == 0 || values[value as usize] {
+            self.problem(base + 5, Problem::ConfigurationValue { value });
+        }
+        values[value as usize] = true;
+        let attributes = buf[7];
+        if attributes & 0x80 == 0 || attributes & 0x1F != 0 {
+            self.problem(base + 7, Problem::ConfigurationAttributes { attributes });
+        }
+
+        // Interfaces seen, and the next alternate setting of each.
+        let mut interfaces = [false; 256];
+        let mut next_alternate_setting = [0u8; 256];
+        let mut grouped = [false; 256];
+        // Interface using each endpoint, by index of `endpoint_index`.
+        let mut owners = [None; 32];
+        let mut current: Option<CurrentInterface> = None;
+        let mut pending: Option<PendingIad> = None;
+
+        for (offset, desc) in Walk::new(buf).flatten().skip(1) {
+            let offset = base + offset;
+            match desc[1] {
+                descriptor_type::INTERFACE => {
+                    if !self.min_length(offset, desc, 9) {
+                        continue;
+                    }
+                    if let Some(c) = current.take() {
+                        self.end_interface(c);
+                    }
+                    let (interface, alternate_setting) = (desc[2], desc[3]);
+                    let first = !interfaces[interface as usize];
+                    if alternate_setting != next_alternate_setting[interface as usize] {
+                        self.problem(
+                            offset + 3,
+                            Problem::AlternateSetting {
+                                interface,
+                                alternate_setting,
+                            },
+                        );
+                    }
+                    interfaces[interface as usize] = true;
+                    next_alternate_setting[interface as usize] = alternate_setting.wrapping_add(1);
+
+                    if first && let Some(iad) = pending.as_mut() {
+                        let end = iad.first_interface as usize + iad.interface_count as usize;
+                        if (iad.first_interface as usize..end).contains(&(interface as usize)) {
+                            iad.seen += 1;
+                            if iad.seen == iad.interface_count {
+                                pending = None;
+                            }
+                        } else {
+                            let problem = Problem::IadGrouping {
+                                first_interface: iad.first_interface,
+                                interface_count: iad.interface_count,
+                                interface,
+                            };
+                            let iad_offset = iad.offset;
+                            pending = None;
+                            self.problem(iad_offset, problem);
+                        }
+                    }
+
+                    current = Some(CurrentInterface {
+                        offset,
+                        interface,
+                        alternate_setting,
+                        declared: desc[4],
+                        endpoints: 0,
+                        used: 0,
+                    });
+                }
+                descriptor_type::ENDPOINT => {
+                    if !self.min_length(offset, desc, 7) {
+                        continue;
+                    }
+                    let Some(c) = current.as_mut() else {
+                        self.problem(
+                            offset,
+                            Problem::UnexpectedDescriptor {
+                                descriptor_type: desc[1] as u16,
+                            },
+                        );
+                        continue;
+                    };
+                    c.endpoints += 1;
+                    let (interface, used) = (c.interface, &mut c.used);
+                    self.endpoint(d, offset, desc, interface, used, &mut owners);
+                }
+                descriptor_type::IAD => {
+                    if !self.min_length(offset, desc, 8) {
+                        continue;
+                    }
+                    if let Some(c) = current.take() {
+                        self.end_interface(c);
+                    }
+                    if let Some(iad) = pending.take() {
+                        self.problem(
+                            iad.offset,
+                            Problem::IadGrouping {
+                                first_interface: iad.first_interface,
+                                interface_count: iad.interface_count,
+                                interface: desc[2],
+                            },
+                        );
+                    }
+                    let (first_interface, interface_count) = (desc[2], desc[3]);
+                    let end = first_interface as usize + interface_count as usize;
+                    if interface_count == 0 || end > 256 {
+                        self.problem(
+                            offset,
+                            Problem::IadRange {
+                                first_interface,
+                                interface_count,
+                            },
+                        );
+                        continue;
+                    }
+                    for (interface, grouped) in grouped.iter_mut().enumerate().take(end).skip(first_interface as usize)
+                    {
+                        if *grouped {
+                            self.problem(
+                                offset,
+                                Problem::IadOverlap {
+                                    interface: interface as u8,
+                                },
+                            );
+                        }
+                        *grouped = true;
+                    }
+                    pending = Some(PendingIad {
+                        offset,
+                        first_interface,
+                        interface_count,
+                        seen: 0,
+                    });
+                }
+                // Class-specific descriptors.
+                _ => {}
+            }
+        }
+        if let Some(c) = current.take() {
+            self.end_interface(c);
+        }
+        if let Some(iad) = pending {
+            self.problem(
+                iad.offset,
+                Problem::IadRange {
+                    first_interface: iad.first_interface,
+                    interface_count: iad.interface_count,
+                },
+            );
+        }
+
+        let count = interfaces.iter().filter(|x| **x).count();
+        if buf[4] as usize != count {
+            self.problem(
+                base + 4,
+                Problem::InterfaceCount {
+                    declared: buf[4],
+                    actual: count,
+                },
+            );
+        }
+        if let Some(missing) = interfaces[..count].iter().position(|x| !*x) {
+            self.problem(
+                base,
+                Problem::MissingInterface {
+                    interface: missing as u8,
+                },
+            );
+        }
+    }
+
+    fn end_interface(&mut self, c: CurrentInterface) {
+        if c.endpoints != c.declared as usize {
+            self.problem(
+                c.offset + 4,
+                Problem::EndpointCount {
+                    interface: c.interface,
+                    alternate_setting: c.alternate_setting,
+                    declared: c.declared,
+                    actual: c.endpoints,
+                },
+            );
+        }
+    }
+
+    fn endpoint(
+        &mut self,
+        d: &Descriptors<'_>,
+        offset: usize,
+        desc: &[u8],
+        interface: u8,
+        used: &mut u32,
+        owners: &mut [Option<u8>; 32],
+    ) {
+        let address = desc[2];
+        if address & 0x0F == 0 || address & 0x70 != 0 {
+            self.problem(offset + 2, Problem::EndpointAddress { address });
+            return;
+        }
+        let index = endpoint_index(address);
+        if *used & (1 << index) != 0 {
+            self.problem(offset + 2, Problem::DuplicateEndpoint { address, interface });
+        }
+        *used |= 1 << index;
+        match owners[index] {
+            Some(other_interface) if other_interface != interface => self.problem(
+                offset + 2,
+                Problem::EndpointCollision {
+                    address,
+                    interface,
+                    other_interface,
+                },
+            ),
+            _ => owners[index] = Some(interface),
+        }
+
+        // Synchronization and usage types are only defined for isochronous endpoints, and the
+        // usage type of interrupt endpoints.
+        let attributes = desc[3];
+        let transfer_type = attributes & 0x03;
+        let reserved = match transfer_type {
+            ISOCHRONOUS => 0xC0,
+            INTERRUPT => 0xCC,
+            _ => 0xFC,
+        };
+        if attributes & reserved != 0 {
+            self.problem(offset + 3, Problem::EndpointAttributes { address, attributes });
+        }
+
+        let max_packet_size = u16::from_le_bytes([desc[4], desc[5]]);
+        let size = max_packet_size & 0x7FF;
+        let transactions = (max_packet_size >> 11) & 0x03;
+        let valid = max_packet_size >> 13 == 0
+            && match (d.speed, transfer_type) {
+                (UsbDeviceSpeed::Full, _) if transactions != 0 => false,
+                (UsbDeviceSpeed::Full, CONTROL | BULK) => matches!(size, 8 | 16 | 32 | 64),
+                (UsbDeviceSpeed::Full, INTERRUPT) => (1..=64).contains(&size),
+                (UsbDeviceSpeed::Full, _) => size <= 1023,
+                (UsbDeviceSpeed::High, CONTROL) => size == 64,
+                (UsbDeviceSpeed::High, BULK) => size == 512,
+                // Additional transactions require packets larger than what fewer would carry.
+                (UsbDeviceSpeed::High, _) => match transactions {
+                    0 => size <= 1024 && (transfer_type == ISOCHRONOUS || size > 0),
+                    1 => (513..=1024).contains(&size),
+                    2 => (683..=1024).contains(&size),
+                    _ => false,
+                },
+            };
+        if !valid {
+            self.problem(
+                offset + 4,
+                Problem::MaxPacketSize {
+                    address,
+                    max_packet_size,
+                },
+            );
+        }
+
+        // Full-speed interrupt endpoints have intervals in frames, others in powers of two.
+        let interval = desc[6];
+        let valid = match (d.speed, transfer_type) {
+            (UsbDeviceSpeed::Full, INTERRUPT) => interval >= 1,
+            (_, ISOCHRONOUS) | (UsbDeviceSpeed::High, INTERRUPT) => (1..=16).contains(&interval),
+            _ => true,
+        };
+        if !valid {
+            self.problem(offset + 6, Problem::Interval { address, interval });
+        }
+    }
+
+    fn bos(&mut self, d: &Descriptors<'_>) {
+        self.set = DescriptorSet::Bos;
+        let buf = d.bos;
+        if buf.is_empty() {
+            if !d.msos.is_empty() {
+                self.problem(0, Problem::MissingMsOsCapability);
+            }
+            return;
+        }
+
+        let mut capabilities = 0;
+        let mut msos_capability = false;
+        for res in Walk::new(buf) {
+            let (offset, desc) = match res {
+                Ok(x) => x,
+                Err(offset) => {
+                    self.problem(offset, Problem::Truncated);
+                    return;
+                }
+            };
+            if offset == 0 {
+                if desc[1] != descriptor_type::BOS {
+                    self.problem(
+                        0,
+                        Problem::UnexpectedDescriptor {
+                            descriptor_type: desc[1] as u16,
+                        },
+                    );
+                    return;
+                }
+                if !self.min_length(0, desc, 5) {
+                    return;
+                }
+                let total_length = u16::from_le_bytes([desc[2], desc[3]]) as usize;
+                if total_length != buf.len() {
+                    self.problem(
+                        2,
+                        Problem::TotalLength {
+                            declared: total_length,
+                            actual: buf.len(),
+                        },
+                    );
+                }
+                continue;
+            }
+
+            if desc[1] != descriptor_type::CAPABILITY {
+                self.problem(
+                    offset,
+                    Problem::UnexpectedDescriptor {
+                        descriptor_type: desc[1] as u16,
+                    },
+                );
+                continue;
+            }
+            capabilities += 1;
+            if !self.min_length(offset, desc, 3) {
+                continue;
+            }
+            if desc[2] == capability_type::PLATFORM && desc.len() >= 20 && desc[4..20] == MSOS_PLATFORM_UUID {
+                msos_capability = true;
+                if !self.min_length(offset, desc, 28) {
+                    continue;
+                }
+                let declared = u16::from_le_bytes([desc[24], desc[25]]) as usize;
+                if declared != d.msos.len() {
+                    self.problem(
+                        offset + 24,
+                        Problem::MsOsSetLength {
+                            declared,
+                            actual: d.msos.len(),
+                        },
+                    );
+                }
+            }
+        }
+        if buf.len() >= 5 && buf[4] as usize != capabilities {
+            self.problem(
+                4,
+                Problem::CapabilityCount {
+                    declared: buf[4],
+                    actual: capabilities,
+                },
+            );
+        }
+        if !msos_capability && !d.msos.is_empty() {
+            self.problem(0, Problem::MissingMsOsCapability);
+        }
+    }
+
+    fn msos(&mut self, d: &Descriptors<'_>) {
+        self.set = DescriptorSet::MsOs;
+        let buf = d.msos;
+        if buf.is_empty() {
+            return;
+        }
+
+        // End of the current configuration and function subsets, and the configuration.
+        let mut configuration_end = None;
+        let mut function_end = None;
+        let mut configuration = None;
+        let mut offset = 0;
+        while offset < buf.len() {
+            if buf.len() - offset < 4 {
+                self.problem(offset, Problem::Truncated);
+                return;
+            }
+            let len = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
+            let kind = u16::from_le_bytes([buf[offset + 2], buf[offset + 3]]);
+            if len < 4 || offset + len > buf.len() {
+                self.problem(offset, Problem::Truncated);
+                return;
+            }
+            let desc = &buf[offset..offset + len];
+            if configuration_end.is_some_and(|end| offset >= end) {
+                (configuration_end, configuration) = (None, None);
+            }
+            if function_end.is_some_and(|end| offset >= end) {
+                function_end = None;
+            }
+
+            let expected = match kind {
+                _ if offset == 0 && kind != MsOsType::SetHeaderDescriptor as u16 => {
+                    self.problem(0, Problem::UnexpectedDescriptor { descriptor_type: kind });
+                    return;
+                }
+                k if k == MsOsType::SetHeaderDescriptor as u16 => 10,
+                k if k == MsOsType::SubsetHeaderConfiguration as u16 => 8,
+                k if k == MsOsType::SubsetHeaderFunction as u16 => 8,
+                k if k == MsOsType::FeatureCompatibleId as u16 => 20,
+                k if k == MsOsType::FeatureRegProperty as u16 => 10,
+                k if k == MsOsType::FeatureMinResumeTime as u16 => 6,
+                k if k == MsOsType::FeatureModelId as u16 => 20,
+                k if k == MsOsType::FeatureCcgpDevice as u16 => 4,
+                k if k == MsOsType::FeatureVendorRevision as u16 => 6,
+                _ => {
+                    self.problem(offset, Problem::UnexpectedDescriptor { descriptor_type: kind });
+                    offset += len;
+                    continue;
+                }
+            };
+            let variable = kind == MsOsType::FeatureRegProperty as u16;
+            if len < expected || (!variable && len != expected) {
+                self.problem(
+                    offset,
+                    Problem::Length {
+                        descriptor_type: kind,
+                        expected,
+                        actual: len,
+                    },
+                );
+                offset += len;
+                continue;
+            }
+
+            match kind {
+                k if k == MsOsType::SetHeaderDescriptor as u16 => {
+                    if offset != 0 {
+                        self.problem(offset, Problem::UnexpectedDescriptor { descriptor_type: kind });
+                    }
+                    let version = u32::from_le_bytes([desc[4], desc[5], desc[6], desc[7]]);
+                    if version < msos::windows_version::WIN8_1 {
+                        self.problem(offset + 4, Problem::MsOsWindowsVersion { version });
+                    }
+                    let total_length = u16::from_le_bytes([desc[8], desc[9]]) as usize;
+                    if total_length != buf.len() {
+                        self.problem(
+                            offset + 8,
+                            Problem::TotalLength {
+                                declared: total_length,
+                                actual: buf.len(),
+                            },
+                        );
+                    }
+                }
+                k if k == MsOsType::SubsetHeaderConfiguration as u16 => {
+                    let index = desc[4];
+                    if configuration_index(d.configurations, index).is_none() {
+                        self.problem(offset + 4, Problem::MsOsConfiguration { index });
+                    }
+                    let total_length = u16::from_le_bytes([desc[6], desc[7]]) as usize;
+                    if total_length < 8 || offset + total_length > buf.len() {
+                        self.problem(
+                            offset + 6,
+                            Problem::TotalLength {
+                                declared: total_length,
+                                actual: buf.len() - offset,
+                            },
+                        );
+                    }
+                    configuration_end = Some(offset + total_length.max(8));
+                    configuration = Some(index);
+                    function_end = None;
+                }
+                k if k == MsOsType::SubsetHeaderFunction as u16 => {
+                    let Some(index) = configuration else {
+                        self.problem(offset, Problem::UnexpectedDescriptor { descriptor_type: kind });
+                        offset += len;
+                        continue;
+                    };
+                    let first_interface = desc[4];
+                    // Missing configurations are reported on their subset header.
+                    if let Some(config) = configuration_index(d.configurations, index)
+                        && !has_interface(config, first_interface)
+                    {
+                        self.problem(offset + 4, Problem::MsOsFunction { first_interface });
+                    }
+                    let subset_length = u16::from_le_bytes([desc[6], desc[7]]) as usize;
+                    if subset_length < 8 || configuration_end.is_some_and(|end| offset + subset_length > end) {
+                        self.problem(
+                            offset + 6,
+                            Problem::TotalLength {
+                                declared: subset_length,
+                                actual: configuration_end.unwrap_or(buf.len()) - offset,
+                            },
+                        );
+                    }
+                    function_end = Some(offset + subset_length.max(8));
+                }
+                k if k == MsOsType::FeatureCompatibleId as u16 => {
+                    if !valid_compatible_id(&desc[4..12]) || !valid_compatible_id(&desc[12..20]) {
+                        self.problem(offset + 4, Problem::MsOsCompatibleId);
+                    }
+                    // Composite devices, with several interfaces, need a compatible ID per function.
+                    let composite = configuration_index(d.configurations, 0).is_some_and(|config| config[4] > 1);
+                    if configuration.is_none() && composite {
+                        self.problem(offset, Problem::MsOsDeviceCompatibleId);
+                    }
+                }
+                k if k == MsOsType::FeatureRegProperty as u16 => {
+                    // wPropertyDataType, then the name and the data, with their lengths.
+                    let name_length = u16::from_le_bytes([desc[6], desc[7]]) as usize;
+                    let data_length = desc
+                        .get(8 + name_length..10 + name_length)
+                        .map(|x| u16::from_le_bytes([x[0], x[1]]) as usize);
+                    if data_length.is_none_or(|data_length| 10 + name_length + data_length != len) {
+                        self.problem(
+                            offset,
+                            Problem::TotalLength {
+                                declared: len,
+                                actual: 10 + name_length + data_length.unwrap_or(0),
+                            },
+                        );
+                    }
+                }
+                _ => {}
+            }
+            offset += len;
+        }
+    }
+}
+
+/// Index of an endpoint address, from 0 to 31.
+fn endpoint_index(address: u8) -> usize {
+    (address & 0x0F) as usize | if address & 0x80 != 0 { 16 } else { 0 }
+}
+
+/// Returns the configuration descriptor at `index`, with its interface and endpoint descriptors.
+fn configuration_index(buf: &[u8], index: u8) -> Option<&[u8]> {
+    let mut starts = Walk::new(buf)
+        .map_while(Result::ok)
+        .filter(|(_, desc)| desc[1] == descriptor_type::CONFIGURATION)
+        .map(|(offset, _)| offset);
+    let start = starts.nth(index as usize)?;
+    let end = starts.next().unwrap_or(buf.len());
+    Some(&buf[start..end])
+}
+
+fn has_interface(config: &[u8], interface: u8) -> bool {
+    Walk::new(config)
+        .map_while(Result::ok)
+        .any(|(_, desc)| desc[1] == descriptor_type::INTERFACE && desc.len() >= 3 && desc[2] == interface)
+}
+
+/// Compatible IDs are uppercase letters, digits and underscores, padded with zeros.
+fn valid_compatible_id(id: &[u8]) -> bool {
+    let len = id.iter().position(|c| *c == 0).unwrap_or(id.len());
+    id[..len]
+        .iter()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == b'_')
+        && id[len..].iter().all(|c| *c == 0)
+}
+
+/// Iterator over the descriptors of a buffer, with their offsets. Yields the offset of the first
+/// malformed descriptor as an error, and stops.
+struct Walk<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Walk<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Result<(usize, &'a [u8]), usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let rest = self.buf.get(offset..).filter(|rest| !rest.is_empty())?;
+        let len = rest[0] as usize;
+        if len < 2 || len > rest.len() {
+            self.offset = self.buf.len();
+            return Some(Err(offset));
+        }
+        self.offset += len;
+        Some(Ok((offset, &rest[..len])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::msos::CompatibleIdFeatureDescriptor;
+    use crate::test_driver::TestDriver;
+    use crate::{Builder, Config};
+
+    /// Copy of the descriptors of a device, to break them.
+    struct Device {
+        device: [u8; 18],
+        configurations: Vec<u8, 256>,
+        bos: Vec<u8, 64>,
+        msos: Vec<u8, 256>,
+        speed: UsbDeviceSpeed,
+    }
+
+    impl Device {
+        /// Build a composite device: a function of two interfaces with an interrupt and two bulk
+        /// endpoints, and a function of one interface with two bulk endpoints and a compatible ID.
+        fn build(speed: UsbDeviceSpeed) -> Self {
+            let mut config_descriptor = [0; 256];
+            let mut bos_descriptor = [0; 64];
+            let mut msos_descriptor = [0; 256];
+            let mut control_buf = [0; 64];
+            let mut config = Config::new(0xc0de, 0xcafe);
+            config.max_speed = speed;
+            let max_packet_size = match speed {
+                UsbDeviceSpeed::Full => 64,
+                UsbDeviceSpeed::High => 512,
+            };
+
+            let mut builder = Builder::new(
+                TestDriver::default(),
+                config,
+                &mut config_descriptor,
+                &mut bos_descriptor,
+                &mut msos_descriptor,
+                &mut control_buf,
+            );
+            builder.msos_descriptor(msos::windows_version::WIN8_1, 0x01);
+            {
+                let mut func = builder.function(0x02, 0x02, 0x00);
+                let mut iface = func.interface();
+                let mut alt = iface.alt_setting(0x02, 0x02, 0x00, None);
+                alt.endpoint_interrupt_in(None, 8, 10);
+                let mut iface = func.interface();
+                let mut alt = iface.alt_setting(0x0a, 0x00, 0x00, None);
+                alt.endpoint_bulk_in(None, max_packet_size);
+                alt.endpoint_bulk_out(None, max_packet_size);
+            }
+            {
+                let mut func = builder.function(0xff, 0x00, 0x00);
+                func.msos_feature(CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+                let mut iface = func.interface();
+                let mut alt = iface.alt_setting(0xff, 0x00, 0x00, None);
+                alt.endpoint_bulk_in(None, max_packet_size);
+                alt.endpoint_bulk_out(None, max_packet_size);
+            }
+            let usb = builder.build();
+
+            let d = usb.descriptors();
+            Self {
+                device: d.device.try_into().unwrap(),
+                configurations: Vec::from_slice(d.configurations).unwrap(),
+                bos: Vec::from_slice(d.bos).unwrap(),
+                msos: Vec::from_slice(d.msos).unwrap(),
+                speed,
+            }
+        }
+
+        fn problems(&self) -> Vec<Problem, 8> {
+            let descriptors = Descriptors {
+                device: &self.device,
+                configurations: &self.configurations,
+                bos: &self.bos,
+                msos: &self.msos,
+                speed: self.speed,
+            };
+            let mut problems = Vec::new();
+            let errors = check(&descriptors, |diagnostic| problems.push(diagnostic.problem).unwrap());
+            let expected = problems.iter().filter(|p| p.severity() == Severity::Error).count();
+            assert_eq!(errors, expected);
+            problems
+        }
+    }
+
+    /// Offset of the `n`th descriptor of type `descriptor_type` in `buf`.
+    fn find(buf: &[u8], descriptor_type: u8, n: usize) -> usize {
+        Walk::new(buf)
+            .map(Result::unwrap)
+            .filter(|(_, desc)| desc[1] == descriptor_type)
+            .nth(n)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn test_builder_device() {
+        for speed in [UsbDeviceSpeed::Full, UsbDeviceSpeed::High] {
+            let device = Device::build(speed);
+            assert!(!device.msos.is_empty());
+            assert_eq!(device.problems().as_slice(), []);
+        }
+    }
+
+    #[test]
+    fn test_iad_grouping() {
+        // Swap the interface numbers of the last interfaces of the two functions.
+        let mut device = Device::build(UsbDeviceSpeed::Full);
+        let second = find(&device.configurations, descriptor_type::INTERFACE, 1);
+        let third = find(&device.configurations, descriptor_type::INTERFACE, 2);
+        device.configurations[second + 2] = 2;
+        device.configurations[third + 2] = 1;
+
+        assert_eq!(
+            device.problems().as_slice(),
+            [
+                Problem::IadGrouping {
+                    first_interface: 0,
+                    interface_count: 2,
+                    interface: 2,
+                },
+                Problem::IadGrouping {
+                    first_interface: 2,
+                    interface_count: 1,
+                    interface: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_endpoint_collision() {
+        // Give the IN endpoint of the second function the address of the first function's.
+        let mut device = Device::build(UsbDeviceSpeed::Full);
+        let endpoint = find(&device.configurations, descriptor_type::ENDPOINT, 3);
+        assert_eq!(device.configurations[endpoint + 2], 0x83);
+        device.configurations[endpoint + 2] = 0x82;
+
+        assert_eq!(
+            device.problems().as_slice(),
+            [Problem::EndpointCollision {
+                address: 0x82,
+                interface: 2,
+                other_interface: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_high_speed_interval() {
+        // High-speed intervals are exponents from 1 to 16, not milliseconds.
+        let mut device = Device::build(UsbDeviceSpeed::High);
+        let endpoint = find(&device.configurations, descriptor_type::ENDPOINT, 0);
+        assert_eq!(device.configurations[endpoint + 6], 8);
+        device.configurations[endpoint + 6] = 32;
+
+        assert_eq!(
+            device.problems().as_slice(),
+            [Problem::Interval {
+                address: 0x81,
+                interval: 32,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_msos_set_length() {
+        // The platform capability announces a longer descriptor set.
+        let mut device = Device::build(UsbDeviceSpeed::Full);
+        let capability = Walk::new(&device.bos)
+            .map(Result::unwrap)
+            .find(|(_, desc)| desc[1] == descriptor_type::CAPABILITY && desc[2] == capability_type::PLATFORM)
+            .unwrap()
+            .0;
+        let actual = device.msos.len();
+        device.bos[capability + 24..capability + 26].copy_from_slice(&(actual as u16 + 4).to_le_bytes());
+
+        assert_eq!(
+            device.problems().as_slice(),
+            [Problem::MsOsSetLength {
+                declared: actual + 4,
+                actual,
+            }]
+        );
+    }
+}
@@ -1,8 +1,7 @@
 //! Utilities for writing USB descriptors.
 use embassy_usb_driver::EndpointType;
 
-use crate::CONFIGURATION_VALUE;
-use crate::builder::Config;
+use crate::builder::{Config, ConfigurationOptions};
 use crate::driver::EndpointInfo;
 use crate::types::{InterfaceNumber, StringIndex};
 
@@ -77,6 +76,7 @@ pub enum UsageType {
 pub(crate) struct DescriptorWriter<'a> {
     pub buf: &'a mut [u8],
     position: usize,
+    configuration_mark: Option<usize>,
     num_interfaces_mark: Option<usize>,
     num_endpoints_mark: Option<usize>,
 }
@@ -86,6 +86,7 @@ impl<'a> DescriptorWriter<'a> {
         DescriptorWriter {
             buf,
             position: 0,
+            configuration_mark: None,
             num_interfaces_mark: None,
             num_endpoints_mark: None,
         }
@@ -122,20 +123,27 @@ impl<'a> DescriptorWriter<'a> {
         self.position = start + total_length;
     }
 
-    pub(crate) fn configuration(&mut self, config: &Config) {
+    pub(crate) fn configuration(
+        &mut self,
+        value: u8,
+        configuration_string: Option<StringIndex>,
+        options: &ConfigurationOptions,
+    ) {
+        self.configuration_mark = Some(self.position);
         self.num_interfaces_mark = Some(self.position + 4);
+        self.num_endpoints_mark = None;
 
         self.write(
             descriptor_type::CONFIGURATION,
             &[
                 0,
-                0,                   // wTotalLength
-                0,                   // bNumInterfaces
-                CONFIGURATION_VALUE, // bConfigurationValue
-                0,                   // iConfiguration
-                0x80 | if config.self_powered { 0x40 } else { 0x00 }
-                    | if config.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
-                (config.max_power / 2) as u8, // bMaxPower
+                0,                                          // wTotalLength
+                0,                                          // bNumInterfaces
+                value,                                      // bConfigurationValue
+                configuration_string.map_or(0, Into::into), // iConfiguration
+                0x80 | if options.self_powered { 0x40 } else { 0x00 }
+                    | if options.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
+                (options.max_power / 2) as u8,              // bMaxPower
             ],
             &[],
         );
@@ -147,8 +155,12 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn end_configuration(&mut self) {
-        let position = self.position as u16;
-        self.buf[2..4].copy_from_slice(&position.to_le_bytes());
+        if let Some(mark) = self.configuration_mark.take() {
+            let total_length = (self.position - mark) as u16;
+            self.buf[mark + 2..mark + 4].copy_from_slice(&total_length.to_le_bytes());
+        }
+        self.num_interfaces_mark = None;
+        self.num_endpoints_mark = None;
     }
 
     /// Writes a interface association descriptor. Call from `UsbClass::get_configuration_descriptors`
@@ -321,7 +333,7 @@ impl<'a> DescriptorWriter<'a> {
 ///
 /// All device descriptors are always 18 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
-pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
+pub(crate) fn device_descriptor(config: &Config, num_configurations: u8) -> [u8; 18] {
     [
         18,   // bLength
         0x01, // bDescriptorType
@@ -340,7 +352,7 @@ pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
         config.manufacturer.map_or(0, |_| 1),  // iManufacturer
         config.product.map_or(0, |_| 2),       // iProduct
         config.serial_number.map_or(0, |_| 3), // iSerialNumber
-        1,                                     // bNumConfigurations
+        num_configurations,                    // bNumConfigurations
     ]
 }
 
@@ -348,7 +360,7 @@ pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
 ///
 /// All device qualifier descriptors are always 10 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
-pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
+pub(crate) fn device_qualifier_descriptor(config: &Config, num_configurations: u8) -> [u8; 10] {
     [
         10,   // bLength
         0x06, // bDescriptorType
@@ -358,7 +370,7 @@ pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
         config.device_sub_class,            // bDeviceSubClass
         config.device_protocol,             // bDeviceProtocol
         config.max_packet_size_0,           // bMaxPacketSize0
-        1,                                  // bNumConfigurations
+        num_configurations,                 // bNumConfigurations
         0,                                  // Reserved
     ]
 }
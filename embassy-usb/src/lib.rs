@@ -1,4 +1,5 @@
 #![no_std]
+#![allow(async_fn_in_trait)]
 #![allow(unsafe_op_in_unsafe_fn)]
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
@@ -10,10 +11,13 @@ pub use embassy_usb_driver as driver;
 
 mod builder;
 pub mod class;
+pub mod compliance;
 pub mod control;
 pub mod descriptor;
 mod descriptor_reader;
 pub mod msos;
+#[cfg(test)]
+mod test_driver;
 pub mod types;
 
 mod config {
@@ -21,13 +25,16 @@ mod config {
     include!(concat!(env!("OUT_DIR"), "/config.rs"));
 }
 
+use core::ops::Range;
+
 use embassy_futures::select::{Either, select};
 use heapless::Vec;
 
 pub use crate::builder::{
-    Builder, Config, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, UsbDeviceSpeed, UsbVersion,
+    Builder, Config, ConfigurationOptions, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, UsbDeviceSpeed,
+    UsbVersion,
 };
-use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
+use crate::config::{MAX_CONFIGURATION_COUNT, MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::descriptor::{descriptor_type, lang_id};
 use crate::descriptor_reader::foreach_endpoint;
@@ -76,7 +83,9 @@ impl From<driver::Unsupported> for RemoteWakeupError {
 /// The bConfiguration value for the not configured state.
 pub const CONFIGURATION_NONE: u8 = 0;
 
-/// The bConfiguration value for the single configuration supported by this device.
+/// The bConfiguration value for the first configuration of the device, described by [`Config`].
+///
+/// Configurations added with [`Builder::configuration`] take the following values.
 pub const CONFIGURATION_VALUE: u8 = 1;
 
 const STRING_INDEX_MANUFACTURER: u8 = 1;
@@ -88,6 +97,11 @@ const STRING_INDEX_CUSTOM_START: u8 = 4;
 ///
 /// All methods are optional callbacks that will be called by
 /// [`UsbDevice::run()`](crate::UsbDevice::run)
+///
+/// A handler belongs to the configuration it was added to with [`Builder::handler`]. Control
+/// requests, alternate settings and [`configured`](Self::configured) are only delivered to the
+/// handlers of the configuration selected by the host, or to all handlers while the device is not
+/// configured. The other callbacks are delivered to all handlers.
 pub trait Handler {
     /// Called when the USB device has been enabled or disabled.
     fn enabled(&mut self, _enabled: bool) {}
@@ -98,9 +112,13 @@ pub trait Handler {
     /// Called when the host has set the address of the device to `addr`.
     fn addressed(&mut self, _addr: u8) {}
 
-    /// Called when the host has enabled or disabled the configuration of the device.
+    /// Called when the host has enabled or disabled the configuration of the handler.
     fn configured(&mut self, _configured: bool) {}
 
+    /// Called when the host has selected the configuration with `bConfigurationValue` `value`, or
+    /// [`CONFIGURATION_NONE`] when the device is no longer configured.
+    fn set_configuration(&mut self, _value: u8) {}
+
     /// Called when the bus has entered or exited the suspend state.
     fn suspended(&mut self, _suspended: bool) {}
 
@@ -191,6 +209,14 @@ struct Interface {
     num_alt_settings: u8,
 }
 
+struct Configuration<'d> {
+    name: Option<(StringIndex, &'d str)>,
+    /// Position of the configuration descriptor in the descriptor buffer.
+    descriptor: Range<usize>,
+    /// Position of the interfaces of the configuration in the interface list.
+    interfaces: Range<usize>,
+}
+
 /// A report of the used size of the runtime allocated buffers
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -206,6 +232,40 @@ pub struct UsbBufferReport {
 }
 
 /// Main struct for the USB device stack.
+///
+/// # Re-enumeration
+///
+/// The layout of a device, its configurations, functions and endpoints, is fixed when it is built.
+/// To switch to another layout at runtime, for example from a CDC ACM serial port to a DFU
+/// interface, tear the device down and build a new one:
+///
+/// 1. Run the device with [`run_until`](Self::run_until), until the application decides to switch.
+///    The device is then disabled, and disconnected from the host if the driver supports it.
+/// 2. Drop the device and its classes, which releases the driver, its endpoints and the
+///    descriptor buffers.
+/// 3. Create a new driver, on a reborrowed peripheral, and build the new layout with the same
+///    buffers.
+///
+/// Hosts may not notice a disconnection shorter than a few milliseconds, so wait for some time
+/// before building the new device, and use a different product ID or device release for each
+/// layout, as hosts may cache the descriptors of a device.
+///
+/// ```rust,ignore
+/// let mut usb_peripheral = p.USB;
+/// let mut config_descriptor = [0; 256];
+/// let mut control_buf = [0; 64];
+/// let mut layout = Layout::Serial;
+/// loop {
+///     let driver = Driver::new(usb_peripheral.reborrow(), Irqs);
+///     let mut builder =
+///         Builder::new(driver, config(layout), &mut config_descriptor, &mut [], &mut [], &mut control_buf);
+///     // ... add the classes of `layout` ...
+///     let mut usb = builder.build();
+///     layout = usb.run_until(run_classes_until_switch()).await;
+///     drop(usb);
+///     Timer::after_millis(100).await;
+/// }
+/// ```
 pub struct UsbDevice<'d, D: Driver<'d>> {
     control_buf: &'d mut [u8],
     control: D::ControlPipe,
@@ -223,6 +283,8 @@ struct Inner<'d, D: Driver<'d>> {
     msos_descriptor: crate::msos::MsOsDescriptorSet<'d>,
 
     device_state: UsbDeviceState,
+    /// The selected configuration, or `CONFIGURATION_NONE`.
+    configuration: u8,
     suspended: bool,
     remote_wakeup_enabled: bool,
     self_powered: bool,
@@ -234,15 +296,17 @@ struct Inner<'d, D: Driver<'d>> {
     /// instead of regular `accept()`.
     set_address_pending: bool,
 
+    configurations: Vec<Configuration<'d>, MAX_CONFIGURATION_COUNT>,
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
-    handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    handlers: Vec<(u8, &'d mut dyn Handler), MAX_HANDLER_COUNT>,
 }
 
 impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
     pub(crate) fn build(
         driver: D,
         config: Config<'d>,
-        handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+        handlers: Vec<(u8, &'d mut dyn Handler), MAX_HANDLER_COUNT>,
+        configurations: Vec<Configuration<'d>, MAX_CONFIGURATION_COUNT>,
         config_descriptor: &'d [u8],
         bos_descriptor: &'d [u8],
         msos_descriptor: crate::msos::MsOsDescriptorSet<'d>,
@@ -252,8 +316,9 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         // Start the USB bus.
         // This prevent further allocation by consuming the driver.
         let (bus, control) = driver.start(config.max_packet_size_0 as u16);
-        let device_descriptor = descriptor::device_descriptor(&config);
-        let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config);
+        let num_configurations = configurations.len() as u8;
+        let device_descriptor = descriptor::device_descriptor(&config, num_configurations);
+        let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config, num_configurations);
 
         Self {
             control_buf,
@@ -268,11 +333,13 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                 msos_descriptor,
 
                 device_state: UsbDeviceState::Unpowered,
+                configuration: CONFIGURATION_NONE,
                 suspended: false,
                 remote_wakeup_enabled: false,
                 self_powered: false,
                 address: 0,
                 set_address_pending: false,
+                configurations,
                 interfaces,
                 handlers,
             },
@@ -291,6 +358,17 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
     }
 
+    /// Returns the descriptors of the device, to check them with [`compliance::check`].
+    pub fn descriptors(&self) -> compliance::Descriptors<'_> {
+        compliance::Descriptors {
+            device: &self.inner.device_descriptor,
+            configurations: self.inner.config_descriptor,
+            bos: self.inner.bos_descriptor,
+            msos: self.inner.msos_descriptor.descriptor(),
+            speed: self.inner.config.max_speed,
+        }
+    }
+
     /// Runs the `UsbDevice` forever.
     ///
     /// This future may leave the bus in an invalid state if it is dropped.
@@ -304,6 +382,27 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
     }
 
+    /// Runs the `UsbDevice` until `fut` completes, then disables it.
+    ///
+    /// This is used to tear the device down, see [Re-enumeration](Self#re-enumeration). The device
+    /// is left disabled, with all its endpoints disabled.
+    ///
+    /// Most drivers also soft-disconnect from the host when disabled, by disconnecting the pull-up
+    /// of D+, so that the host sees the device as detached: the nRF, RP and STM32 OTG drivers, and
+    /// the STM32 USB driver on chips with an internal pull-up. On chips without one, such as the
+    /// STM32F1, the pull-up is external: disconnect it before building the new layout, for example
+    /// with the GPIO driving it, otherwise the host only sees the new layout after a bus reset.
+    ///
+    /// This future may leave the bus in an invalid state if it is dropped, like [`run`](Self::run).
+    pub async fn run_until<F: Future>(&mut self, fut: F) -> F::Output {
+        let output = match select(self.run(), fut).await {
+            Either::First(never) => never,
+            Either::Second(output) => output,
+        };
+        self.disable().await;
+        output
+    }
+
     /// Runs the `UsbDevice` until the bus is suspended.
     ///
     /// This future may leave the bus in an invalid state if it is dropped.
@@ -322,14 +421,19 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
     }
 
     /// Disables the USB peripheral.
+    ///
+    /// If the device is configured, the endpoints of its configuration are disabled first.
     pub async fn disable(&mut self) {
         if self.inner.device_state != UsbDeviceState::Disabled {
+            if self.inner.configuration != CONFIGURATION_NONE {
+                self.inner.set_configuration(CONFIGURATION_NONE);
+            }
             self.inner.bus.disable().await;
             self.inner.device_state = UsbDeviceState::Disabled;
             self.inner.suspended = false;
             self.inner.remote_wakeup_enabled = false;
 
-            for h in &mut self.inner.handlers {
+            for (_, h) in &mut self.inner.handlers {
                 h.enabled(false);
             }
         }
@@ -358,7 +462,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
             self.inner.bus.remote_wakeup().await?;
             self.inner.suspended = false;
 
-            for h in &mut self.inner.handlers {
+            for (_, h) in &mut self.inner.handlers {
                 h.suspended(false);
             }
 
@@ -473,33 +577,38 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             Event::Reset => {
                 trace!("usb: reset");
                 self.device_state = UsbDeviceState::Default;
+                self.configuration = CONFIGURATION_NONE;
                 self.suspended = false;
                 self.remote_wakeup_enabled = false;
                 self.address = 0;
 
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.reset();
                 }
 
-                for (i, iface) in self.interfaces.iter_mut().enumerate() {
-                    iface.current_alt_setting = 0;
+                for (c, configuration) in self.configurations.iter().enumerate() {
+                    let value = c as u8 + 1;
+                    let interfaces = &mut self.interfaces[configuration.interfaces.clone()];
+                    for (i, iface) in interfaces.iter_mut().enumerate() {
+                        iface.current_alt_setting = 0;
 
-                    for h in &mut self.handlers {
-                        h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                        for (_, h) in self.handlers.iter_mut().filter(|(c, _)| *c == value) {
+                            h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                        }
                     }
                 }
             }
             Event::Resume => {
                 trace!("usb: resume");
                 self.suspended = false;
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.suspended(false);
                 }
             }
             Event::Suspend => {
                 trace!("usb: suspend");
                 self.suspended = true;
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.suspended(true);
                 }
             }
@@ -508,7 +617,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 self.bus.enable().await;
                 self.device_state = UsbDeviceState::Default;
 
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.enabled(true);
                 }
             }
@@ -517,7 +626,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 self.bus.disable().await;
                 self.device_state = UsbDeviceState::Unpowered;
 
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.enabled(false);
                 }
             }
@@ -526,20 +635,19 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
     fn handle_control_out(&mut self, req: Request, data: &[u8]) -> OutResponse {
         const CONFIGURATION_NONE_U16: u16 = CONFIGURATION_NONE as u16;
-        const CONFIGURATION_VALUE_U16: u16 = CONFIGURATION_VALUE as u16;
 
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Device) => match (req.request, req.value) {
                 (Request::CLEAR_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
                     self.remote_wakeup_enabled = false;
-                    for h in &mut self.handlers {
+                    for (_, h) in &mut self.handlers {
                         h.remote_wakeup_enabled(false);
                     }
                     OutResponse::Accepted
                 }
                 (Request::SET_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
                     self.remote_wakeup_enabled = true;
-                    for h in &mut self.handlers {
+                    for (_, h) in &mut self.handlers {
                         h.remote_wakeup_enabled(true);
                     }
                     OutResponse::Accepted
@@ -548,53 +656,32 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     self.address = addr as u8;
                     self.set_address_pending = true;
                     self.device_state = UsbDeviceState::Addressed;
-                    for h in &mut self.handlers {
+                    for (_, h) in &mut self.handlers {
                         h.addressed(self.address);
                     }
                     OutResponse::Accepted
                 }
-                (Request::SET_CONFIGURATION, CONFIGURATION_VALUE_U16) => {
-                    debug!("SET_CONFIGURATION: configured");
-                    self.device_state = UsbDeviceState::Configured;
-
-                    // Enable all endpoints of selected alt settings.
-                    foreach_endpoint(self.config_descriptor, |ep| {
-                        let iface = &self.interfaces[ep.interface.0 as usize];
-                        self.bus
-                            .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
-                    })
-                    .unwrap();
-
-                    // Notify handlers.
-                    for h in &mut self.handlers {
-                        h.configured(true);
-                    }
-
-                    OutResponse::Accepted
-                }
                 (Request::SET_CONFIGURATION, CONFIGURATION_NONE_U16) => {
                     if self.device_state != UsbDeviceState::Default {
                         debug!("SET_CONFIGURATION: unconfigured");
                         self.device_state = UsbDeviceState::Addressed;
-
-                        // Disable all endpoints.
-                        foreach_endpoint(self.config_descriptor, |ep| {
-                            self.bus.endpoint_set_enabled(ep.ep_address, false);
-                        })
-                        .unwrap();
-
-                        // Notify handlers.
-                        for h in &mut self.handlers {
-                            h.configured(false);
-                        }
+                        self.set_configuration(CONFIGURATION_NONE);
                     }
                     OutResponse::Accepted
                 }
+                (Request::SET_CONFIGURATION, value) if value as usize <= self.configurations.len() => {
+                    debug!("SET_CONFIGURATION: configured {}", value);
+                    self.device_state = UsbDeviceState::Configured;
+                    self.set_configuration(value as u8);
+                    OutResponse::Accepted
+                }
                 _ => OutResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
+                let configuration = self.configuration.max(CONFIGURATION_VALUE);
                 let iface_num = InterfaceNumber::new(req.index as _);
-                let Some(iface) = self.interfaces.get_mut(iface_num.0 as usize) else {
+                let interfaces = self.configurations[configuration as usize - 1].interfaces.clone();
+                let Some(iface) = self.interfaces[interfaces].get_mut(iface_num.0 as usize) else {
                     return OutResponse::Rejected;
                 };
 
@@ -611,7 +698,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
                         // Enable/disable EPs of this interface as needed.
                         foreach_endpoint(self.config_descriptor, |ep| {
-                            if ep.interface == iface_num {
+                            if ep.configuration == configuration && ep.interface == iface_num {
                                 self.bus
                                     .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
                             }
@@ -620,7 +707,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
                         // TODO check it is valid (not out of range)
 
-                        for h in &mut self.handlers {
+                        for (_, h) in self.handlers.iter_mut().filter(|(c, _)| *c == configuration) {
                             h.set_alternate_setting(iface_num, new_altsetting);
                         }
                         OutResponse::Accepted
@@ -662,7 +749,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 Request::GET_DESCRIPTOR => self.handle_get_descriptor(req, buf),
                 Request::GET_CONFIGURATION => {
                     let status = match self.device_state {
-                        UsbDeviceState::Configured => CONFIGURATION_VALUE,
+                        UsbDeviceState::Configured => self.configuration,
                         _ => CONFIGURATION_NONE,
                     };
                     buf[0] = status;
@@ -671,7 +758,9 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 _ => InResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
-                let Some(iface) = self.interfaces.get_mut(req.index as usize) else {
+                let configuration = self.configuration.max(CONFIGURATION_VALUE);
+                let interfaces = self.configurations[configuration as usize - 1].interfaces.clone();
+                let Some(iface) = self.interfaces[interfaces].get_mut(req.index as usize) else {
                     return InResponse::Rejected;
                 };
 
@@ -716,8 +805,54 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         }
     }
 
+    /// Select the configuration `value`, or none, and notify the handlers.
+    fn set_configuration(&mut self, value: u8) {
+        let previous = core::mem::replace(&mut self.configuration, value);
+
+        if previous != CONFIGURATION_NONE && previous != value {
+            // Disable all endpoints of the previous configuration.
+            foreach_endpoint(self.config_descriptor, |ep| {
+                if ep.configuration == previous {
+                    self.bus.endpoint_set_enabled(ep.ep_address, false);
+                }
+            })
+            .unwrap();
+
+            for (_, h) in self.handlers.iter_mut().filter(|(c, _)| *c == previous) {
+                h.configured(false);
+            }
+        }
+
+        if value != CONFIGURATION_NONE {
+            // Enable all endpoints of selected alt settings.
+            let interfaces = self.configurations[value as usize - 1].interfaces.clone();
+            let interfaces = &self.interfaces[interfaces];
+            foreach_endpoint(self.config_descriptor, |ep| {
+                if ep.configuration == value {
+                    let iface = &interfaces[ep.interface.0 as usize];
+                    self.bus
+                        .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
+                }
+            })
+            .unwrap();
+
+            for (_, h) in self.handlers.iter_mut().filter(|(c, _)| *c == value) {
+                h.configured(true);
+            }
+        }
+
+        for (_, h) in &mut self.handlers {
+            h.set_configuration(value);
+        }
+    }
+
     fn handle_control_out_delegated(&mut self, req: Request, data: &[u8]) -> OutResponse {
-        for h in &mut self.handlers {
+        let active = self.configuration;
+        for (_, h) in self
+            .handlers
+            .iter_mut()
+            .filter(|(c, _)| active == CONFIGURATION_NONE || *c == active)
+        {
             if let Some(res) = h.control_out(req, data) {
                 return res;
             }
@@ -730,7 +865,12 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             core::mem::transmute(r)
         }
 
-        for h in &mut self.handlers {
+        let active = self.configuration;
+        for (_, h) in self
+            .handlers
+            .iter_mut()
+            .filter(|(c, _)| active == CONFIGURATION_NONE || *c == active)
+        {
             if let Some(res) = h.control_in(req, buf) {
                 // safety: the borrow checker isn't smart enough to know this pattern (returning a
                 // borrowed value from inside the loop) is sound. Workaround by unsafely extending lifetime.
@@ -745,14 +885,17 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
     fn handle_get_descriptor<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
         let (dtype, index) = req.descriptor_type_index();
 
-        for handler in &mut self.handlers {
+        for (_, handler) in &mut self.handlers {
             handler.get_descriptor_requested(dtype, index, req.length);
         }
 
         match dtype {
             descriptor_type::BOS => InResponse::Accepted(self.bos_descriptor),
             descriptor_type::DEVICE => InResponse::Accepted(&self.device_descriptor),
-            descriptor_type::CONFIGURATION => InResponse::Accepted(self.config_descriptor),
+            descriptor_type::CONFIGURATION => match self.configurations.get(index as usize) {
+                Some(configuration) => InResponse::Accepted(&self.config_descriptor[configuration.descriptor.clone()]),
+                None => InResponse::Rejected,
+            },
             descriptor_type::STRING => {
                 if index == 0 {
                     buf[0] = 4; // len
@@ -766,13 +909,19 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                         STRING_INDEX_PRODUCT => self.config.product,
                         STRING_INDEX_SERIAL_NUMBER => self.config.serial_number,
                         _ => {
-                            let mut s = None;
-                            for handler in &mut self.handlers {
-                                let index = StringIndex::new(index);
-                                let lang_id = req.index;
-                                if let Some(res) = handler.get_string(index, lang_id) {
-                                    s = Some(res);
-                                    break;
+                            let configuration_name = self.configurations.iter().find_map(|c| match c.name {
+                                Some((i, name)) if i.0 == index => Some(name),
+                                _ => None,
+                            });
+                            let mut s = configuration_name;
+                            if s.is_none() {
+                                for (_, handler) in &mut self.handlers {
+                                    let index = StringIndex::new(index);
+                                    let lang_id = req.index;
+                                    if let Some(res) = handler.get_string(index, lang_id) {
+                                        s = Some(res);
+                                        break;
+                                    }
                                 }
                             }
                             s
@@ -817,3 +966,262 @@ fn first_last<T: Iterator>(iter: T) -> impl Iterator<Item = (bool, bool, T::Item
         Some((is_first, is_last, val))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+    use crate::driver::Endpoint as _;
+    use crate::test_driver::TestDriver;
+
+    /// Handler accepting all the requests delegated to it, and counting them.
+    struct TestHandler<'a> {
+        configured: &'a Cell<bool>,
+        requests: &'a Cell<usize>,
+    }
+
+    impl Handler for TestHandler<'_> {
+        fn configured(&mut self, configured: bool) {
+            self.configured.set(configured);
+        }
+
+        fn control_out(&mut self, _req: Request, _data: &[u8]) -> Option<OutResponse> {
+            self.requests.set(self.requests.get() + 1);
+            Some(OutResponse::Accepted)
+        }
+
+        fn control_in<'a>(&'a mut self, _req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+            self.requests.set(self.requests.get() + 1);
+            Some(InResponse::Accepted(&buf[..0]))
+        }
+    }
+
+    fn request(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Request {
+        let mut buf = [request_type, request, 0, 0, 0, 0, 0, 0];
+        buf[2..4].copy_from_slice(&value.to_le_bytes());
+        buf[4..6].copy_from_slice(&index.to_le_bytes());
+        buf[6..8].copy_from_slice(&length.to_le_bytes());
+        Request::parse(&buf)
+    }
+
+    fn get_descriptor(usb: &mut UsbDevice<'_, TestDriver>, descriptor_type: u8, index: u8) -> Option<Vec<u8, 256>> {
+        let req = request(
+            0x80,
+            Request::GET_DESCRIPTOR,
+            (descriptor_type as u16) << 8 | index as u16,
+            0,
+            256,
+        );
+        match usb.inner.handle_control_in(req, usb.control_buf) {
+            InResponse::Accepted(data) => Some(Vec::from_slice(data).unwrap()),
+            InResponse::Rejected => None,
+        }
+    }
+
+    fn set_configuration(usb: &mut UsbDevice<'_, TestDriver>, value: u8) {
+        let req = request(0x00, Request::SET_CONFIGURATION, value as u16, 0, 0);
+        assert_eq!(usb.inner.handle_control_out(req, &[]), OutResponse::Accepted);
+    }
+
+    /// Send a vendor request to the device, OUT and IN.
+    #[cfg(any(
+        feature = "max-configuration-count-2",
+        feature = "max-configuration-count-3",
+        feature = "max-configuration-count-4"
+    ))]
+    fn vendor_requests(usb: &mut UsbDevice<'_, TestDriver>) {
+        let req = request(0x41, 0x01, 0, 0, 0);
+        assert_eq!(usb.inner.handle_control_out(req, &[]), OutResponse::Accepted);
+        let req = request(0xc1, 0x02, 0, 0, 0);
+        assert_eq!(
+            usb.inner.handle_control_in(req, usb.control_buf),
+            InResponse::Accepted(&[])
+        );
+    }
+
+    /// Numbers of the interfaces described in a configuration descriptor.
+    #[cfg(any(
+        feature = "max-configuration-count-2",
+        feature = "max-configuration-count-3",
+        feature = "max-configuration-count-4"
+    ))]
+    fn interface_numbers(descriptor: &[u8]) -> Vec<u8, 8> {
+        let mut numbers = Vec::new();
+        let mut rest = descriptor;
+        while let [len, dtype, ..] = *rest {
+            if dtype == descriptor_type::INTERFACE {
+                numbers.push(rest[2]).unwrap();
+            }
+            rest = &rest[len as usize..];
+        }
+        numbers
+    }
+
+    #[test]
+    #[cfg(any(
+        feature = "max-configuration-count-2",
+        feature = "max-configuration-count-3",
+        feature = "max-configuration-count-4"
+    ))]
+    fn test_multiple_configurations() {
+        let mut config_descriptor = [0; 256];
+        let mut bos_descriptor = [0; 64];
+        let mut control_buf = [0; 64];
+        let (configured1, requests1) = (Cell::new(false), Cell::new(0));
+        let (configured2, requests2) = (Cell::new(false), Cell::new(0));
+        let mut handler1 = TestHandler {
+            configured: &configured1,
+            requests: &requests1,
+        };
+        let mut handler2 = TestHandler {
+            configured: &configured2,
+            requests: &requests2,
+        };
+
+        let mut builder = Builder::new(
+            TestDriver::default(),
+            Config::new(0xc0de, 0xcafe),
+            &mut config_descriptor,
+            &mut bos_descriptor,
+            &mut [],
+            &mut control_buf,
+        );
+
+        // Configuration 1: two interfaces, with endpoints 0x81, 0x01 and 0x82.
+        {
+            let mut func = builder.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(None, 64);
+            alt.endpoint_bulk_out(None, 64);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_interrupt_in(None, 8, 10);
+        }
+        builder.handler(&mut handler1);
+
+        // Configuration 2: one interface, with endpoint 0x83.
+        assert_eq!(builder.configuration(ConfigurationOptions::new()), 2);
+        {
+            let mut func = builder.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(None, 64);
+        }
+        builder.handler(&mut handler2);
+
+        let mut usb = builder.build();
+
+        let device = get_descriptor(&mut usb, descriptor_type::DEVICE, 0).unwrap();
+        assert_eq!(device[17], 2, "bNumConfigurations");
+
+        let config1 = get_descriptor(&mut usb, descriptor_type::CONFIGURATION, 0).unwrap();
+        assert_eq!(config1[1], descriptor_type::CONFIGURATION);
+        assert_eq!(u16::from_le_bytes([config1[2], config1[3]]) as usize, config1.len());
+        assert_eq!(config1[4], 2, "bNumInterfaces");
+        assert_eq!(config1[5], 1, "bConfigurationValue");
+        assert_eq!(interface_numbers(&config1), [0, 1]);
+
+        let config2 = get_descriptor(&mut usb, descriptor_type::CONFIGURATION, 1).unwrap();
+        assert_eq!(config2[1], descriptor_type::CONFIGURATION);
+        assert_eq!(u16::from_le_bytes([config2[2], config2[3]]) as usize, config2.len());
+        assert_eq!(config2[4], 1, "bNumInterfaces");
+        assert_eq!(config2[5], 2, "bConfigurationValue");
+        assert_eq!(interface_numbers(&config2), [0]);
+
+        assert_eq!(get_descriptor(&mut usb, descriptor_type::CONFIGURATION, 2), None);
+
+        // Only the handlers and endpoints of the selected configuration are active.
+        set_configuration(&mut usb, 2);
+        assert!(!configured1.get());
+        assert!(configured2.get());
+        assert_eq!(usb.inner.bus.enabled, [EndpointAddress::from(0x83)]);
+        vendor_requests(&mut usb);
+        assert_eq!((requests1.get(), requests2.get()), (0, 2));
+
+        set_configuration(&mut usb, 1);
+        assert!(configured1.get());
+        assert!(!configured2.get());
+        let mut enabled = usb.inner.bus.enabled.clone();
+        enabled.sort_unstable_by_key(|a| u8::from(*a));
+        assert_eq!(enabled, [0x01.into(), 0x81.into(), 0x82.into()] as [EndpointAddress; 3]);
+        vendor_requests(&mut usb);
+        assert_eq!((requests1.get(), requests2.get()), (2, 2));
+
+        // The interfaces of the selected configuration are addressed.
+        let req = request(0x81, Request::GET_INTERFACE, 0, 1, 1);
+        assert_eq!(
+            usb.inner.handle_control_in(req, usb.control_buf),
+            InResponse::Accepted(&[0])
+        );
+        set_configuration(&mut usb, 2);
+        assert_eq!(usb.inner.handle_control_in(req, usb.control_buf), InResponse::Rejected);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut config_descriptor = [0; 256];
+        let mut control_buf = [0; 64];
+        let (configured, requests) = (Cell::new(false), Cell::new(0));
+        let mut handler = TestHandler {
+            configured: &configured,
+            requests: &requests,
+        };
+
+        let mut builder = Builder::new(
+            TestDriver::default(),
+            Config::new(0xc0de, 0xcafe),
+            &mut config_descriptor,
+            &mut [],
+            &mut [],
+            &mut control_buf,
+        );
+        {
+            let mut func = builder.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(None, 64);
+            alt.endpoint_bulk_out(None, 64);
+        }
+        builder.handler(&mut handler);
+        let mut usb = builder.build();
+
+        set_configuration(&mut usb, 1);
+        assert!(configured.get());
+        assert_eq!(usb.inner.bus.enabled.len(), 2);
+
+        // The output of the future is returned, with the device disabled.
+        assert_eq!(embassy_futures::block_on(usb.run_until(async { 42 })), 42);
+        assert!(usb.inner.bus.disabled);
+        assert!(usb.inner.bus.enabled.is_empty());
+        assert!(!configured.get());
+        assert_eq!(usb.inner.device_state, UsbDeviceState::Disabled);
+
+        // The driver and the buffers are released, another layout can be built.
+        drop(usb);
+        let mut builder = Builder::new(
+            TestDriver::default(),
+            Config::new(0xc0de, 0xbabe),
+            &mut config_descriptor,
+            &mut [],
+            &mut [],
+            &mut control_buf,
+        );
+        {
+            let mut func = builder.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            let ep = alt.endpoint_interrupt_in(None, 8, 10);
+            assert_eq!(ep.info().addr, EndpointAddress::from(0x81));
+        }
+        let mut usb = builder.build();
+
+        let device = get_descriptor(&mut usb, descriptor_type::DEVICE, 0).unwrap();
+        assert_eq!(device[10..12], 0xbabe_u16.to_le_bytes(), "idProduct");
+        let config = get_descriptor(&mut usb, descriptor_type::CONFIGURATION, 0).unwrap();
+        assert_eq!(config[4], 1, "bNumInterfaces");
+        set_configuration(&mut usb, 1);
+        assert_eq!(usb.inner.bus.enabled[..], [EndpointAddress::from(0x81)]);
+    }
+}
@@ -152,6 +152,12 @@ impl<'d> MsOsDescriptorWriter<'d> {
         Self::end_subset::<FunctionSubsetHeader>(self.buf, self.position, &mut self.function_mark);
     }
 
+    /// Ends the current configuration subset (if any), and its function subset.
+    pub(crate) fn end_configuration(&mut self) {
+        self.end_function();
+        Self::end_subset::<ConfigurationSubsetHeader>(self.buf, self.position, &mut self.config_mark);
+    }
+
     fn write<T: Descriptor>(&mut self, desc: T) {
         desc.write_to(&mut self.buf[self.position..]);
         self.position += desc.size();
@@ -0,0 +1,193 @@
+//! Driver without hardware, to test the device stack.
+
+use core::future::pending;
+
+use heapless::{Deque, Vec};
+
+use crate::driver::{
+    self, Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType, Event,
+};
+
+/// Driver allocating endpoints in order, and recording which ones are enabled.
+#[derive(Default)]
+pub(crate) struct TestDriver {
+    next_in: u8,
+    next_out: u8,
+}
+
+impl TestDriver {
+    fn alloc(
+        next: &mut u8,
+        dir: Direction,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<TestEndpoint, EndpointAllocError> {
+        let addr = match ep_addr {
+            Some(addr) => addr,
+            None => {
+                *next += 1;
+                EndpointAddress::from_parts(*next as usize, dir)
+            }
+        };
+        if addr.index() > 15 {
+            return Err(EndpointAllocError);
+        }
+        Ok(TestEndpoint {
+            info: EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+            packets: Deque::new(),
+        })
+    }
+}
+
+impl<'a> driver::Driver<'a> for TestDriver {
+    type EndpointOut = TestEndpoint;
+    type EndpointIn = TestEndpoint;
+    type ControlPipe = TestControlPipe;
+    type Bus = TestBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<TestEndpoint, EndpointAllocError> {
+        Self::alloc(
+            &mut self.next_out,
+            Direction::Out,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<TestEndpoint, EndpointAllocError> {
+        Self::alloc(
+            &mut self.next_in,
+            Direction::In,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )
+    }
+
+    fn start(self, _control_max_packet_size: u16) -> (TestBus, TestControlPipe) {
+        (TestBus::default(), TestControlPipe)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TestBus {
+    /// Endpoints currently enabled.
+    pub enabled: Vec<EndpointAddress, 32>,
+    /// Whether the bus was disabled since it was last enabled.
+    pub disabled: bool,
+}
+
+impl driver::Bus for TestBus {
+    async fn enable(&mut self) {
+        self.disabled = false;
+    }
+
+    async fn disable(&mut self) {
+        self.disabled = true;
+    }
+
+    async fn poll(&mut self) -> Event {
+        pending().await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        self.enabled.retain(|a| *a != ep_addr);
+        if enabled {
+            self.enabled.push(ep_addr).unwrap();
+        }
+    }
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), driver::Unsupported> {
+        Err(driver::Unsupported)
+    }
+}
+
+/// Endpoint reading the packets queued by the test, and recording the packets written.
+pub(crate) struct TestEndpoint {
+    info: EndpointInfo,
+    /// Packets to read, for OUT endpoints, or written, for IN endpoints.
+    pub packets: Deque<Vec<u8, 512>, 16>,
+}
+
+impl driver::Endpoint for TestEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        pending().await
+    }
+}
+
+impl driver::EndpointOut for TestEndpoint {
+    /// Reads the next queued packet. The endpoint is disabled once all the packets are read.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let packet = self.packets.pop_front().ok_or(EndpointError::Disabled)?;
+        let dst = buf.get_mut(..packet.len()).ok_or(EndpointError::BufferOverflow)?;
+        dst.copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
+
+impl driver::EndpointIn for TestEndpoint {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        let packet = Vec::from_slice(buf).map_err(|_| EndpointError::BufferOverflow)?;
+        self.packets.push_back(packet).expect("too many packets written");
+        Ok(())
+    }
+}
+
+/// Control pipe receiving no requests: the tests call the request handlers of the device directly.
+pub(crate) struct TestControlPipe;
+
+impl driver::ControlPipe for TestControlPipe {
+    fn max_packet_size(&self) -> usize {
+        64
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        pending().await
+    }
+
+    async fn data_out(&mut self, _buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn data_in(&mut self, _data: &[u8], _first: bool, _last: bool) -> Result<(), EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn accept(&mut self) {}
+
+    async fn reject(&mut self) {}
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
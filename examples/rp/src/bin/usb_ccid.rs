@@ -0,0 +1,160 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This creates a smart card reader, with a card answering a few APDUs: SELECT of its
+//! application, and an ECHO command returning its data. With pcsc-lite, list the reader with
+//! `pcsc_scan`, and send APDUs with for example `opensc-tool -s 00A4040005F000000001 -s 80100000026869`.
+//!
+//! The card is removed and inserted again when the BOOTSEL button is pressed.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::join::join3;
+use embassy_rp::bind_interrupts;
+use embassy_rp::bootsel::is_bootsel_pressed;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_time::Timer;
+use embassy_usb::class::ccid::{self, CardError, CcidClass, SmartCard, State};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+/// Answer to reset, announcing the T=1 protocol.
+const ATR: &[u8] = &[0x3B, 0x80, 0x80, 0x01, 0x01];
+
+/// Application identifier of the card.
+const AID: &[u8] = &[0xF0, 0x00, 0x00, 0x00, 0x01];
+
+/// Status words.
+const SW_OK: [u8; 2] = [0x90, 0x00];
+const SW_FILE_NOT_FOUND: [u8; 2] = [0x6A, 0x82];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const SW_CONDITIONS_NOT_SATISFIED: [u8; 2] = [0x69, 0x85];
+
+/// Card answering APDUs in the firmware, standing in for a secure element.
+struct Card {
+    selected: bool,
+}
+
+impl SmartCard for Card {
+    async fn power_on(&mut self) -> Result<&[u8], CardError> {
+        info!("Power on");
+        self.selected = false;
+        Ok(ATR)
+    }
+
+    async fn power_off(&mut self) {
+        info!("Power off");
+    }
+
+    async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, CardError> {
+        info!("APDU: {:x}", command);
+        if command.len() < 4 {
+            response[..2].copy_from_slice(&SW_INS_NOT_SUPPORTED);
+            return Ok(2);
+        }
+
+        // Data of short APDUs with Lc.
+        let data = match command.get(4) {
+            Some(&lc) if command.len() >= 5 + lc as usize => &command[5..5 + lc as usize],
+            _ => &[],
+        };
+
+        let sw = match (command[0], command[1]) {
+            // SELECT by AID
+            (0x00, 0xA4) if data == AID => {
+                self.selected = true;
+                SW_OK
+            }
+            (0x00, 0xA4) => SW_FILE_NOT_FOUND,
+            // ECHO, which takes some time.
+            (0x80, 0x10) if self.selected => {
+                Timer::after_millis(100).await;
+                response[..data.len()].copy_from_slice(data);
+                response[data.len()..data.len() + 2].copy_from_slice(&SW_OK);
+                return Ok(data.len() + 2);
+            }
+            (0x80, 0x10) => SW_CONDITIONS_NOT_SATISFIED,
+            _ => SW_INS_NOT_SUPPORTED,
+        };
+        response[..2].copy_from_slice(&sw);
+        Ok(2)
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello world!");
+
+    let mut p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("CCID example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let ccid = CcidClass::new(&mut builder, &mut state, ccid::Config::default());
+    let (mut responder, mut notifier) = ccid.split();
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Answer the messages of the host.
+    let mut card = Card { selected: false };
+    let responder_fut = responder.run(&mut card);
+
+    // Remove and insert the card with the BOOTSEL button.
+    let notifier_fut = async {
+        let mut present = true;
+        loop {
+            Timer::after_millis(100).await;
+            if is_bootsel_pressed(p.BOOTSEL.reborrow()) {
+                present = !present;
+                info!("Card present: {}", present);
+                if let Err(e) = notifier.set_card_present(present).await {
+                    warn!("Error: {}", e);
+                }
+                while is_bootsel_pressed(p.BOOTSEL.reborrow()) {
+                    Timer::after_millis(100).await;
+                }
+            }
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join3(usb_fut, responder_fut, notifier_fut).await;
+}
@@ -0,0 +1,147 @@
+//! This example shows how to use several USB configurations in the RP2040 chip.
+//!
+//! The device offers a low-power configuration with one serial port, and a full-power one with two.
+//! The host selects one of them, on Linux with `echo 2 > /sys/bus/usb/devices/<device>/bConfigurationValue`
+//! for example. The LED is on while the full-power configuration is active.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, panic, unwrap};
+use embassy_executor::Spawner;
+use embassy_futures::join::{join, join3};
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Level, Output};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, Instance, InterruptHandler};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, ConfigurationOptions, Handler, UsbDevice};
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+static CONFIGURATION: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Hello there!");
+
+    let p = embassy_rp::init(Default::default());
+    let mut led = Output::new(p.PIN_25, Level::Low);
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config, which describes the first configuration.
+    let config = {
+        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+        config.manufacturer = Some("Embassy");
+        config.product = Some("USB configurations example");
+        config.serial_number = Some("12345678");
+        config.max_power = 100;
+        config.max_packet_size_0 = 64;
+        config
+    };
+
+    let mut builder = {
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+
+        Builder::new(
+            driver,
+            config,
+            CONFIG_DESCRIPTOR.init([0; 256]),
+            BOS_DESCRIPTOR.init([0; 256]),
+            &mut [], // no msos descriptors
+            CONTROL_BUF.init([0; 64]),
+        )
+    };
+
+    static STATES: StaticCell<[State; 3]> = StaticCell::new();
+    let [state0, state1, state2] = STATES.init([State::new(), State::new(), State::new()]);
+
+    // The handler is notified of the selected configuration, whichever configuration it belongs to.
+    static HANDLER: StaticCell<ConfigurationHandler> = StaticCell::new();
+    builder.handler(HANDLER.init(ConfigurationHandler));
+
+    // First configuration: low power.
+    let mut low_power = CdcAcmClass::new(&mut builder, state0, 64);
+
+    // Second configuration: full power.
+    let mut options = ConfigurationOptions::new();
+    options.name = Some("Full power");
+    options.max_power = 500;
+    let full_power_value = builder.configuration(options);
+    let mut full_power_a = CdcAcmClass::new(&mut builder, state1, 64);
+    let mut full_power_b = CdcAcmClass::new(&mut builder, state2, 64);
+
+    // Build the builder.
+    let usb = builder.build();
+
+    // Run the USB device.
+    spawner.spawn(unwrap!(usb_task(usb)));
+
+    // Only the classes of the selected configuration get connected.
+    let leds = async {
+        loop {
+            let value = CONFIGURATION.wait().await;
+            info!("Configuration {}", value);
+            led.set_level(Level::from(value == full_power_value));
+        }
+    };
+    let full_power = join(echo_loop(&mut full_power_a), echo_loop(&mut full_power_b));
+    join3(leds, echo_loop(&mut low_power), full_power).await;
+}
+
+type MyUsbDriver = Driver<'static, USB>;
+type MyUsbDevice = UsbDevice<'static, MyUsbDriver>;
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: MyUsbDevice) -> ! {
+    usb.run().await
+}
+
+struct ConfigurationHandler;
+
+impl Handler for ConfigurationHandler {
+    fn set_configuration(&mut self, value: u8) {
+        CONFIGURATION.signal(value);
+    }
+}
+
+struct Disconnected {}
+
+impl From<EndpointError> for Disconnected {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Disconnected {},
+        }
+    }
+}
+
+async fn echo_loop<'d, T: Instance + 'd>(class: &mut CdcAcmClass<'d, Driver<'d, T>>) {
+    loop {
+        class.wait_connection().await;
+        info!("Connected");
+        let _ = echo(class).await;
+        info!("Disconnected");
+    }
+}
+
+async fn echo<'d, T: Instance + 'd>(class: &mut CdcAcmClass<'d, Driver<'d, T>>) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        let data = &buf[..n];
+        info!("data: {:x}", data);
+        class.write_packet(data).await?;
+    }
+}
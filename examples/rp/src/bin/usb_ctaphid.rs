@@ -0,0 +1,131 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This creates a FIDO security key, using the CTAPHID transport. It only answers the
+//! authenticatorGetInfo command, enough for the host to discover it, with for example
+//! `fido2-token -L` and `fido2-token -I`.
+
+#![no_std]
+#![no_main]
+
+use core::cell::Cell;
+
+use defmt::info;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_time::Timer;
+use embassy_usb::class::ctaphid::{self, Command, CtapHid, Error, KeepaliveStatus, MAX_MESSAGE_SIZE, State};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+/// CTAP2 command of authenticatorGetInfo.
+const GET_INFO: u8 = 0x04;
+
+/// CTAP2 status codes.
+const CTAP2_OK: u8 = 0x00;
+const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+
+/// authenticatorGetInfo response: `{1: ["FIDO_2_0"], 3: h'00..00'}`, in CBOR.
+#[rustfmt::skip]
+const INFO: &[u8] = &[
+    CTAP2_OK,
+    0xA2, // map(2)
+    0x01, 0x81, 0x68, b'F', b'I', b'D', b'O', b'_', b'2', b'_', b'0', // versions
+    0x03, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // aaguid
+];
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello world!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("CTAPHID example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut config = ctaphid::Config::default();
+    config.wink = true;
+    let mut ctap = CtapHid::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Answer the requests of the host.
+    let ctap_fut = async {
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        loop {
+            ctap.ready().await;
+            let request = match ctap.read_request(&mut buf).await {
+                Ok(request) => request,
+                Err(e) => {
+                    info!("Error: {}", e);
+                    continue;
+                }
+            };
+            let (channel, command) = (request.channel, request.command);
+            info!("Request {} on channel {:x}: {:x}", command, channel, request.data);
+
+            let result = match command {
+                Command::Cbor if request.data.first() == Some(&GET_INFO) => {
+                    // Pretend to do some processing, while keeping the host informed.
+                    let status = Cell::new(KeepaliveStatus::Processing);
+                    match ctap.with_keepalive(channel, &status, Timer::after_millis(200)).await {
+                        Ok(()) => ctap.write_response(channel, command, INFO).await,
+                        Err(Error::Cancelled) => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                }
+                Command::Wink => {
+                    info!("Wink");
+                    ctap.write_response(channel, command, &[]).await
+                }
+                _ => {
+                    ctap.write_response(channel, command, &[CTAP1_ERR_INVALID_COMMAND])
+                        .await
+                }
+            };
+            if let Err(e) = result {
+                info!("Error: {}", e);
+            }
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, ctap_fut).await;
+}
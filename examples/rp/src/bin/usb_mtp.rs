@@ -0,0 +1,166 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This creates an MTP media device, browsed with the file managers of computers and phones. It
+//! exposes a read-only storage of a few files, embedded in the firmware.
+
+#![no_std]
+#![no_main]
+
+use defmt::info;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_usb::class::mtp::{
+    self, MtpClass, ObjectInfo, ObjectStore, Parent, ResponseCode, State, StorageInfo, StorageType, format,
+};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+const STORAGE_ID: u32 = 0x0001_0001;
+
+/// Files of the storage, all in the root folder. Their handles are their index, plus one.
+const FILES: &[(&str, &[u8])] = &[
+    ("README.txt", b"Hello from Embassy!\n"),
+    ("LICENSE.txt", include_bytes!("../../../../LICENSE-MIT")),
+];
+
+/// Read-only store of [`FILES`].
+struct Files;
+
+impl Files {
+    fn file(handle: u32) -> Result<(&'static str, &'static [u8]), ResponseCode> {
+        let index = (handle as usize).wrapping_sub(1);
+        FILES.get(index).copied().ok_or(ResponseCode::InvalidObjectHandle)
+    }
+
+    fn check_storage(storage_id: u32) -> Result<(), ResponseCode> {
+        match storage_id {
+            STORAGE_ID | mtp::ALL_STORAGES => Ok(()),
+            _ => Err(ResponseCode::InvalidStorageId),
+        }
+    }
+}
+
+impl ObjectStore for Files {
+    fn storage_ids(&self) -> &[u32] {
+        &[STORAGE_ID]
+    }
+
+    async fn storage_info(&mut self, _storage_id: u32) -> Result<StorageInfo<'_>, ResponseCode> {
+        let size = FILES.iter().map(|(_, data)| data.len() as u64).sum();
+        Ok(StorageInfo {
+            storage_type: StorageType::FixedRom,
+            read_only: true,
+            max_capacity: size,
+            free_space: 0,
+            description: "Firmware",
+            volume_label: "",
+        })
+    }
+
+    async fn object_count(&mut self, storage_id: u32, parent: Parent) -> Result<u32, ResponseCode> {
+        Self::check_storage(storage_id)?;
+        match parent {
+            Parent::All | Parent::Root => Ok(FILES.len() as u32),
+            Parent::Folder(_) => Err(ResponseCode::InvalidParentObject),
+        }
+    }
+
+    async fn object_handles(
+        &mut self,
+        storage_id: u32,
+        parent: Parent,
+        offset: u32,
+        handles: &mut [u32],
+    ) -> Result<usize, ResponseCode> {
+        let count = self.object_count(storage_id, parent).await?;
+        let mut n = 0;
+        for (handle, slot) in (offset + 1..=count).zip(handles.iter_mut()) {
+            *slot = handle;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn object_info(&mut self, handle: u32) -> Result<ObjectInfo<'_>, ResponseCode> {
+        let (name, data) = Self::file(handle)?;
+        Ok(ObjectInfo {
+            storage_id: STORAGE_ID,
+            format: format::TEXT,
+            size: data.len() as u64,
+            parent: 0,
+            name,
+            modified: "",
+        })
+    }
+
+    async fn read(&mut self, handle: u32, offset: u64, buf: &mut [u8]) -> Result<usize, ResponseCode> {
+        let (_, data) = Self::file(handle)?;
+        let data = data.get(offset as usize..).unwrap_or(&[]);
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello world!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("MTP example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut config = mtp::Config::default();
+    config.manufacturer = "Embassy";
+    config.model = "MTP example";
+    config.device_version = "1.0";
+    config.serial_number = "12345678";
+    let mut mtp = MtpClass::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Answer the operations of the host.
+    let mtp_fut = mtp.run(&mut Files);
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, mtp_fut).await;
+}
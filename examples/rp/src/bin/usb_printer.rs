@@ -0,0 +1,90 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This creates a USB printer, handled by the standard printer drivers of hosts. Print jobs are
+//! logged, for example sent on Linux with `echo hello > /dev/usb/lp0`.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_usb::class::printer::{self, Error, PrinterClass, State};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello world!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("Printer example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    // The control buffer holds the device ID.
+    let mut control_buf = [0; 128];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut config = printer::Config::default();
+    config.device_id = "MFG:Embassy;MDL:Printer example;CMD:TXT;CLS:PRINTER;";
+    let mut printer = PrinterClass::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Receive print jobs.
+    let printer_fut = async {
+        let mut buf = [0; 64];
+        loop {
+            printer.wait_connection().await;
+            info!("Connected");
+            loop {
+                match printer.read_packet(&mut buf).await {
+                    Ok(n) => info!("Data: {:a}", &buf[..n]),
+                    Err(Error::Reset) => info!("Reset, job discarded"),
+                    Err(Error::Disabled) => break,
+                    Err(e) => warn!("Error: {}", e),
+                }
+            }
+            info!("Disconnected");
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, printer_fut).await;
+}
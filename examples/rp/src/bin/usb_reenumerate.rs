@@ -0,0 +1,157 @@
+//! This example shows how to switch between USB device layouts at runtime in the RP2040 chip.
+//!
+//! The device starts as a USB serial port that echos. Pressing the button on pin 28 tears it
+//! down and re-enumerates it as a MIDI device, which echos MIDI packets, and the other way around.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, panic};
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_rp::{Peri, bind_interrupts};
+use embassy_time::Timer;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::class::midi::MidiClass;
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+#[derive(Clone, Copy, defmt::Format)]
+enum Layout {
+    Serial,
+    Midi,
+}
+
+/// Buffers reused by the devices of all layouts.
+struct Buffers {
+    config_descriptor: [u8; 256],
+    bos_descriptor: [u8; 256],
+    control_buf: [u8; 64],
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello there!");
+
+    let p = embassy_rp::init(Default::default());
+    let mut usb = p.USB;
+    let mut button = Input::new(p.PIN_28, Pull::Up);
+
+    let mut buffers = Buffers {
+        config_descriptor: [0; 256],
+        bos_descriptor: [0; 256],
+        control_buf: [0; 64],
+    };
+
+    let mut layout = Layout::Serial;
+    loop {
+        info!("Enumerating as {}", layout);
+        layout = match layout {
+            Layout::Serial => {
+                run_serial(usb.reborrow(), &mut buffers, &mut button).await;
+                Layout::Midi
+            }
+            Layout::Midi => {
+                run_midi(usb.reborrow(), &mut buffers, &mut button).await;
+                Layout::Serial
+            }
+        };
+
+        // The device is disconnected: give the host some time to notice it.
+        Timer::after_millis(100).await;
+    }
+}
+
+fn config(product_id: u16, product: &str) -> Config<'_> {
+    let mut config = Config::new(0xc0de, product_id);
+    config.manufacturer = Some("Embassy");
+    config.product = Some(product);
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+    config
+}
+
+/// Run the serial port layout until the button is pressed.
+async fn run_serial(usb: Peri<'_, USB>, buffers: &mut Buffers, button: &mut Input<'_>) {
+    let mut state = State::new();
+    let mut builder = Builder::new(
+        Driver::new(usb, Irqs),
+        config(0xcafe, "USB-serial layout"),
+        &mut buffers.config_descriptor,
+        &mut buffers.bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut buffers.control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb = builder.build();
+
+    let echo = async {
+        loop {
+            class.wait_connection().await;
+            info!("Connected");
+            let _ = echo_serial(&mut class).await;
+            info!("Disconnected");
+        }
+    };
+    usb.run_until(select(echo, button.wait_for_falling_edge())).await;
+}
+
+/// Run the MIDI layout until the button is pressed.
+async fn run_midi(usb: Peri<'_, USB>, buffers: &mut Buffers, button: &mut Input<'_>) {
+    let mut builder = Builder::new(
+        Driver::new(usb, Irqs),
+        config(0xcaff, "USB-MIDI layout"),
+        &mut buffers.config_descriptor,
+        &mut buffers.bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut buffers.control_buf,
+    );
+    let mut class = MidiClass::new(&mut builder, 1, 1, 64);
+    let mut usb = builder.build();
+
+    let echo = async {
+        loop {
+            class.wait_connection().await;
+            info!("Connected");
+            let _ = echo_midi(&mut class).await;
+            info!("Disconnected");
+        }
+    };
+    usb.run_until(select(echo, button.wait_for_falling_edge())).await;
+}
+
+struct Disconnected {}
+
+impl From<EndpointError> for Disconnected {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Disconnected {},
+        }
+    }
+}
+
+async fn echo_serial(class: &mut CdcAcmClass<'_, Driver<'_, USB>>) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        class.write_packet(&buf[..n]).await?;
+    }
+}
+
+async fn echo_midi(class: &mut MidiClass<'_, Driver<'_, USB>>) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        class.write_packet(&buf[..n]).await?;
+    }
+}
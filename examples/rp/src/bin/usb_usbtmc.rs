@@ -0,0 +1,148 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This creates a USBTMC (USB488) instrument, answering a few SCPI commands. It can be used with
+//! VISA libraries, such as PyVISA: `rm.open_resource("USB0::0xC0DE::0xCAFE::12345678::INSTR").query("*IDN?")`.
+
+#![no_std]
+#![no_main]
+
+use defmt::info;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, Instance, InterruptHandler};
+use embassy_usb::class::usbtmc::{self, Error, Message, State, UsbtmcClass};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello world!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USBTMC example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut config = usbtmc::Config::default();
+    config.indicator_pulse = true;
+    let mut class = UsbtmcClass::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Answer SCPI commands.
+    let scpi_fut = async {
+        loop {
+            class.wait_connection().await;
+            info!("Connected");
+            let _ = scpi(&mut class).await;
+            info!("Disconnected");
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, scpi_fut).await;
+}
+
+struct Disconnected {}
+
+async fn scpi<'d, T: Instance + 'd>(class: &mut UsbtmcClass<'d, Driver<'d, T>>) -> Result<(), Disconnected> {
+    let mut buf = [0; 256];
+    let mut triggers = 0u32;
+    // Response to the last query, sent when the host reads it.
+    let mut response = [0; 64];
+    let mut pending = 0..0;
+
+    loop {
+        match class.read_message(&mut buf).await {
+            Ok(Message::Data(command)) => {
+                let command = command.trim_ascii();
+                info!("Command: {:a}", command);
+                let len = if command.eq_ignore_ascii_case(b"*IDN?") {
+                    let idn = b"Embassy,USBTMC example,12345678,1.0\n";
+                    response[..idn.len()].copy_from_slice(idn);
+                    idn.len()
+                } else if command.eq_ignore_ascii_case(b"TRIG:COUN?") {
+                    format_count(triggers, &mut response)
+                } else {
+                    if command.eq_ignore_ascii_case(b"*RST") {
+                        triggers = 0;
+                    }
+                    0
+                };
+                pending = 0..len;
+            }
+            Ok(Message::ReadRequest(request)) => {
+                // Long responses are split over several reads of the host.
+                match class.write_response(&request, &response[pending.clone()]).await {
+                    Ok(n) => pending.start += n,
+                    Err(Error::Disabled) => return Err(Disconnected {}),
+                    Err(_) => pending = 0..0,
+                }
+            }
+            Ok(Message::Trigger) => {
+                info!("Trigger");
+                triggers += 1;
+            }
+            Ok(Message::IndicatorPulse) => info!("Indicator pulse"),
+            Err(Error::Disabled) => return Err(Disconnected {}),
+            Err(e) => info!("Error: {}", e),
+        }
+    }
+}
+
+/// Format `value` followed by a newline into `buf`, returning its length.
+fn format_count(value: u32, buf: &mut [u8]) -> usize {
+    let mut digits = [0; 10];
+    let mut len = 0;
+    let mut value = value;
+    loop {
+        digits[len] = b'0' + (value % 10) as u8;
+        len += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    for (i, digit) in digits[..len].iter().rev().enumerate() {
+        buf[i] = *digit;
+    }
+    buf[len] = b'\n';
+    len + 1
+}
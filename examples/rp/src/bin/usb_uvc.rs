@@ -0,0 +1,158 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This creates a USB video class (UVC) camera, streaming scrolling color bars in YUY2 format.
+//! It works with the standard webcam drivers of the hosts.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, panic};
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, Instance, InterruptHandler};
+use embassy_time::{Duration, Ticker};
+use embassy_usb::class::uvc::{self, Camera, Format, Frame, Settings, State, Transfer, Version};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 120;
+
+/// Frame sizes of the YUY2 format, at 10 or 5 frames per second.
+static FRAMES: [Frame; 1] = [Frame {
+    width: WIDTH as u16,
+    height: HEIGHT as u16,
+    intervals: &[1_000_000, 2_000_000],
+}];
+
+static FORMATS: [Format; 1] = [Format::Yuy2(&FRAMES)];
+
+/// 75% color bars, as (Y, U, V).
+const BARS: [(u8, u8, u8); 8] = [
+    (180, 128, 128), // white
+    (162, 44, 142),  // yellow
+    (131, 156, 44),  // cyan
+    (112, 72, 58),   // green
+    (84, 184, 198),  // magenta
+    (65, 100, 212),  // red
+    (35, 212, 114),  // blue
+    (16, 128, 128),  // black
+];
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello world!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB-UVC example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // The video class uses an interface association descriptor.
+    config.composite_with_iads = true;
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut camera = Camera::new(
+        &mut builder,
+        &mut state,
+        uvc::Config {
+            formats: &FORMATS,
+            transfer: Transfer::Isochronous,
+            max_packet_size: 1023,
+            version: Version::Uvc11,
+        },
+    );
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Stream video while the host asks for it.
+    let video_fut = async {
+        loop {
+            let settings = camera.wait_streaming().await;
+            info!("Streaming {}", settings);
+            let _ = color_bars(&mut camera, settings).await;
+            info!("Stopped");
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, video_fut).await;
+}
+
+struct Disconnected {}
+
+impl From<EndpointError> for Disconnected {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Disconnected {},
+        }
+    }
+}
+
+/// Write frames of scrolling color bars, line by line, with no frame buffer.
+async fn color_bars<'d, T: Instance + 'd>(
+    camera: &mut Camera<'d, Driver<'d, T>>,
+    settings: Settings,
+) -> Result<(), Disconnected> {
+    // Frame intervals are in units of 100 ns.
+    let mut ticker = Ticker::every(Duration::from_micros(settings.frame_interval as u64 / 10));
+    let mut offset = 0;
+    let mut line = [0; WIDTH * 2];
+
+    loop {
+        let mut frame = camera.frame();
+        for _ in 0..HEIGHT {
+            // Each YUY2 macropixel encodes two pixels as Y0 U Y1 V.
+            for (x, pixels) in line.chunks_exact_mut(4).enumerate() {
+                let (y, u, v) = BARS[((2 * x + offset) % WIDTH) * BARS.len() / WIDTH];
+                pixels.copy_from_slice(&[y, u, y, v]);
+            }
+            frame.write(&line).await?;
+        }
+        frame.finish().await?;
+
+        offset = (offset + 2) % WIDTH;
+        ticker.next().await;
+    }
+}
@@ -0,0 +1,74 @@
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::dhcp_server::{self, DhcpServer, DhcpServerResources};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap98")]
+    tap: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // The DHCP server needs a static address
+    let address = Ipv4Cidr::new(Ipv4Address::new(192, 168, 70, 1), 24);
+    let config = Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address,
+        dns_servers: Vec::new(),
+        gateway: None,
+    });
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner).unwrap());
+
+    // Hand out 192.168.70.100 to 192.168.70.149, with the server as DNS server and gateway
+    let mut server_config = dhcp_server::Config::new(address, Ipv4Address::new(192, 168, 70, 100), 50);
+    server_config.dns_servers.push(address.address()).unwrap();
+
+    static SERVER_RESOURCES: StaticCell<DhcpServerResources> = StaticCell::new();
+    let mut server = DhcpServer::<8>::new(stack, SERVER_RESOURCES.init(DhcpServerResources::new()), server_config);
+    info!("DHCP server running on {}", address);
+    server.run().await
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
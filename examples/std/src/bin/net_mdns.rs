@@ -0,0 +1,85 @@
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::mdns_responder::{MdnsResponder, MdnsResponderResources, Service};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// Host name, answered as `<hostname>.local`
+    #[clap(long, default_value = "embassy")]
+    hostname: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    let config = Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+        dns_servers: Vec::new(),
+        gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+    });
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner).unwrap());
+
+    // Answer for <hostname>.local, and advertise a web server
+    static MDNS_RESOURCES: StaticCell<MdnsResponderResources> = StaticCell::new();
+    let mut responder = MdnsResponder::<2>::new(
+        stack,
+        MDNS_RESOURCES.init(MdnsResponderResources::new()),
+        &opts.hostname,
+    );
+    responder
+        .add_service(Service {
+            instance: "Embassy web server",
+            service_type: "_http._tcp",
+            port: 80,
+            txt: &["path=/"],
+        })
+        .unwrap();
+
+    info!("mDNS responder running for {}.local", responder.hostname());
+    responder.run().await
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
@@ -0,0 +1,249 @@
+//! Connecting to the internet through a cellular modem, using CMUX to keep AT commands available while PPP
+//! runs on the same serial port.
+//!
+//!     RUST_LOG=trace cargo run --bin net_modem -- --device /dev/ttyUSB2 --apn internet
+//!     nc <the address assigned by the network> 1234
+
+#![allow(async_fn_in_trait)]
+
+#[path = "../serial_port.rs"]
+mod serial_port;
+
+use async_io::Async;
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_futures::select::{Either, select};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Config, ConfigV4, Ipv4Cidr, Stack, StackResources};
+use embassy_net_modem::{at, cmux};
+use embassy_time::{Duration, Timer};
+use embedded_io_adapters::futures_03::FromFutures;
+use embedded_io_async::Write;
+use futures::io::BufReader;
+use heapless::Vec;
+use log::*;
+use nix::sys::termios;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+use crate::serial_port::SerialPort;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// Serial port device name
+    #[clap(short, long)]
+    device: String,
+    /// Access point name of the cellular network
+    #[clap(short, long)]
+    apn: String,
+}
+
+type Port = FromFutures<BufReader<Async<SerialPort>>>;
+type Channel = cmux::Channel<'static, 1024>;
+type AtState = at::State<256, 4>;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, embassy_net_ppp::Device<'static>>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn mux_task(mut runner: cmux::Runner<'static, 2, 1024>, port: Port) -> ! {
+    match runner.run(port).await {
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[embassy_executor::task]
+async fn at_task(mut runner: at::Runner<'static, 256, 4>, channel: Channel) -> ! {
+    let mut config = at::Config::default();
+    config.urc_prefixes = &[b"+CREG:", b"+CEREG:"];
+    match runner.run(channel, &config).await {
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[embassy_executor::task]
+async fn ppp_task(stack: Stack<'static>, mut runner: embassy_net_ppp::Runner<'static>, channel: Channel) -> ! {
+    let config = embassy_net_ppp::Config {
+        username: b"",
+        password: b"",
+    };
+
+    let r = runner
+        .run(channel, config, |ipv4| {
+            let Some(addr) = ipv4.address else {
+                warn!("PPP did not provide an IP address.");
+                return;
+            };
+            info!("PPP up, address {}", addr);
+            let mut dns_servers = Vec::new();
+            for s in ipv4.dns_servers.iter().flatten() {
+                let _ = dns_servers.push(*s);
+            }
+            let config = ConfigV4::Static(embassy_net::StaticConfigV4 {
+                address: Ipv4Cidr::new(addr, 0),
+                gateway: None,
+                dns_servers,
+            });
+            stack.set_config_v4(config);
+        })
+        .await;
+    match r {
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Run an AT client on `rw` until `f` is done.
+async fn with_at<RW, F, R>(state: &'static mut AtState, rw: RW, f: impl FnOnce(at::Client<'static, 256, 4>) -> F) -> R
+where
+    RW: embedded_io_async::BufRead + embedded_io_async::Write,
+    RW::Error: core::fmt::Debug,
+    F: Future<Output = R>,
+{
+    let (client, mut runner) = at::new(state);
+    match select(runner.run(rw, &at::Config::default()), f(client)).await {
+        Either::First(r) => panic!("{:?}", r),
+        Either::Second(r) => r,
+    }
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Open serial port
+    let baudrate = termios::BaudRate::B115200;
+    let port = SerialPort::new(opts.device.as_str(), baudrate).unwrap();
+    let port = Async::new(port).unwrap();
+    let mut port = FromFutures::new(BufReader::new(port));
+
+    // Switch the modem to multiplexing mode.
+    static SETUP_STATE: StaticCell<AtState> = StaticCell::new();
+    with_at(SETUP_STATE.init(AtState::new()), &mut port, async |client| {
+        let mut resp = [0; 256];
+        client.command(b"ATE0", &mut resp, TIMEOUT).await.unwrap();
+        client.command(b"AT+CMEE=1", &mut resp, TIMEOUT).await.unwrap();
+        let n = client.command(b"ATI", &mut resp, TIMEOUT).await.unwrap();
+        info!("modem: {}", core::str::from_utf8(&resp[..n]).unwrap().trim());
+        client.command(b"AT+CMUX=0", &mut resp, TIMEOUT).await.unwrap();
+    })
+    .await;
+
+    static MUX_STATE: StaticCell<cmux::State<2, 1024>> = StaticCell::new();
+    let ([control, mut data], mux_runner) = cmux::new(MUX_STATE.init(cmux::State::new()), cmux::Config::default());
+    spawner.spawn(mux_task(mux_runner, port).unwrap());
+
+    // The control channel stays in AT command mode.
+    static CONTROL_STATE: StaticCell<AtState> = StaticCell::new();
+    let (control_client, control_runner) = at::new(CONTROL_STATE.init(AtState::new()));
+    spawner.spawn(at_task(control_runner, control).unwrap());
+
+    // Dial on the data channel, which then carries PPP.
+    static DATA_STATE: StaticCell<AtState> = StaticCell::new();
+    with_at(DATA_STATE.init(AtState::new()), &mut data, async |client| {
+        let mut resp = [0; 256];
+        let cgdcont = format!("AT+CGDCONT=1,\"IP\",\"{}\"", opts.apn);
+        client.command(cgdcont.as_bytes(), &mut resp, TIMEOUT).await.unwrap();
+        client.command(b"ATD*99#", &mut resp, Duration::from_secs(30)).await.unwrap();
+    })
+    .await;
+
+    // Init network device
+    static PPP_STATE: StaticCell<embassy_net_ppp::State<4, 4>> = StaticCell::new();
+    let (device, ppp_runner) = embassy_net_ppp::new(PPP_STATE.init(embassy_net_ppp::State::new()));
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, net_runner) = embassy_net::new(
+        device,
+        Config::default(), // don't configure IP yet
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Launch network task
+    spawner.spawn(net_task(net_runner).unwrap());
+    spawner.spawn(ppp_task(stack, ppp_runner, data).unwrap());
+
+    // Monitor the modem on the control channel while the data flows.
+    spawner.spawn(monitor_task(control_client).unwrap());
+
+    // Then we can use it!
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    let mut buf = [0; 4096];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        info!("Listening on TCP:1234...");
+        if let Err(e) = socket.accept(1234).await {
+            warn!("accept error: {:?}", e);
+            continue;
+        }
+
+        info!("Received connection from {:?}", socket.remote_endpoint());
+
+        loop {
+            let n = match socket.read(&mut buf).await {
+                Ok(0) => {
+                    warn!("read EOF");
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("read error: {:?}", e);
+                    break;
+                }
+            };
+
+            match socket.write_all(&buf[..n]).await {
+                Ok(()) => {}
+                Err(e) => {
+                    warn!("write error: {:?}", e);
+                    break;
+                }
+            };
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn monitor_task(client: at::Client<'static, 256, 4>) -> ! {
+    let mut resp = [0; 256];
+    loop {
+        match select(client.receive_urc(), Timer::after_secs(10)).await {
+            Either::First(urc) => info!("URC: {}", core::str::from_utf8(&urc).unwrap_or("<binary>")),
+            Either::Second(()) => match client.command(b"AT+CSQ", &mut resp, TIMEOUT).await {
+                Ok(n) => info!("{}", core::str::from_utf8(&resp[..n]).unwrap().trim()),
+                Err(e) => warn!("AT+CSQ failed: {}", e),
+            },
+        }
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Trace)
+        .filter_module("polling", log::LevelFilter::Info)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
@@ -0,0 +1,114 @@
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Config, InterfaceResources, IpCidr, Ipv4Address, Ipv4Cidr, Route, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name of the uplink
+    #[clap(long, default_value = "tap0")]
+    uplink: String,
+    /// TAP device name of the local network
+    #[clap(long, default_value = "tap1")]
+    local: String,
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // The uplink has the default gateway
+    let uplink = TunTapDevice::new(&opts.uplink).unwrap();
+    let uplink_config = Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+        dns_servers: Vec::new(),
+        gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+    });
+
+    // The local network has a router to 10.42.0.0/16
+    let local = TunTapDevice::new(&opts.local).unwrap();
+    let local_config = Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 70, 2), 24),
+        dns_servers: Vec::new(),
+        gateway: None,
+    });
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack with the uplink, then add the local network
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(uplink, uplink_config, RESOURCES.init(StackResources::new()), seed);
+    spawner.spawn(net_task(runner).unwrap());
+
+    static LOCAL_RESOURCES: StaticCell<InterfaceResources<2>> = StaticCell::new();
+    let (local, runner) = stack.add_interface(local, local_config, LOCAL_RESOURCES.init(InterfaceResources::new()));
+    spawner.spawn(net_task(runner).unwrap());
+
+    stack
+        .add_route(Route {
+            destination: IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::new(10, 42, 0, 0), 16)),
+            gateway: Some(Ipv4Address::new(192, 168, 70, 1).into()),
+            interface: local.id(),
+            metric: 0,
+        })
+        .unwrap();
+
+    // Routes only go through interfaces whose link is up
+    stack.wait_link_up().await;
+    local.wait_link_up().await;
+
+    // Connect to a host on each network, sockets are routed to the right interface
+    let hosts = [Ipv4Address::new(192, 168, 69, 100), Ipv4Address::new(192, 168, 70, 100)];
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    loop {
+        for host in hosts {
+            info!("{} is routed through {:?}", host, stack.route(host.into()));
+
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(Duration::from_secs(10)));
+            info!("connecting to {}:8000...", host);
+            if let Err(e) = socket.connect((host, 8000)).await {
+                warn!("connect error: {:?}", e);
+                continue;
+            }
+            if let Err(e) = socket.write_all(b"Hello!\r\n").await {
+                warn!("write error: {:?}", e);
+            }
+            socket.close();
+            let _ = socket.flush().await;
+        }
+        Timer::after_secs(1).await;
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
@@ -0,0 +1,111 @@
+use std::fs::File;
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::{Duration, Timer};
+use embedded_io_adapters::futures_03::FromFutures;
+use embedded_io_async::Write;
+use futures::io::AllowStdIo;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+type Device = embassy_net_pcap::Device<'static, TunTapDevice, 8192>;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// File to write the capture to
+    #[clap(long, default_value = "capture.pcapng")]
+    output: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, Device>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn capture_task(mut runner: embassy_net_pcap::Runner<'static, 8192>, file: File) {
+    // Writing to a file blocks for a short time only, this is fine for an example.
+    let sink = FromFutures::new(AllowStdIo::new(file));
+    let Err(e) = runner.run(sink).await;
+    error!("capture write error: {:?}", e);
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device, and capture it
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+    static CAPTURE: StaticCell<embassy_net_pcap::State<8192>> = StaticCell::new();
+    let (device, capture) =
+        embassy_net_pcap::new(device, CAPTURE.init(embassy_net_pcap::State::new()), Default::default());
+
+    let file = File::create(&opts.output).unwrap();
+    info!("capturing to {}", opts.output);
+    spawner.spawn(capture_task(capture, file).unwrap());
+
+    let config = Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+        dns_servers: Vec::new(),
+        gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+    });
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner).unwrap());
+
+    // Then we can use it! Everything the stack sends and receives ends up in the capture.
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        let remote_endpoint = (Ipv4Address::new(192, 168, 69, 100), 8000);
+        info!("connecting to {:?}...", remote_endpoint);
+        match socket.connect(remote_endpoint).await {
+            Ok(()) => {
+                if let Err(e) = socket.write_all(b"Hello!\r\n").await {
+                    warn!("write error: {:?}", e);
+                }
+                socket.close();
+                let _ = socket.flush().await;
+            }
+            Err(e) => warn!("connect error: {:?}", e),
+        }
+        Timer::after_secs(1).await;
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
@@ -0,0 +1,192 @@
+//! A device dashboard served over HTTP, with live updates pushed over a WebSocket.
+//!
+//!     cargo run --bin net_websocket -- --tap tap99 --static-ip
+//!
+//! Then open http://192.168.69.2:8080 in a browser.
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_futures::select::{Either, select};
+use embassy_net::tcp::client::TcpConnection;
+use embassy_net::tcp::listener::{TcpListener, TcpListenerState};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_net_websocket::server::Request;
+use embassy_net_websocket::{CloseCode, Message, MessageType, WebSocket};
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_io_async::Write as _;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+const SOCKETS: usize = 4;
+
+type Connection = TcpConnection<'static, SOCKETS, 1024, 1024>;
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>embassy dashboard</title></head>
+<body>
+<h1>embassy dashboard</h1>
+<p>Uptime: <span id="uptime">?</span> s</p>
+<input id="input" placeholder="Message to the device"><button id="send">Send</button>
+<script>
+const ws = new WebSocket(`ws://${location.host}/ws`);
+ws.onmessage = (e) => { document.getElementById("uptime").textContent = JSON.parse(e.data).uptime; };
+ws.onclose = () => { document.getElementById("uptime").textContent = "disconnected"; };
+document.getElementById("send").onclick = () => ws.send(document.getElementById("input").value);
+</script>
+</body>
+</html>
+"#;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// use a static IP instead of DHCP
+    #[clap(long)]
+    static_ip: bool,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task(pool_size = SOCKETS)]
+async fn http_task(mut conn: Connection) {
+    let mut buf = [0; 1024];
+    let request = match Request::read(&mut conn, &mut buf).await {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("invalid request: {:?}", e);
+            return;
+        }
+    };
+    info!("{} {}", request.method(), request.path());
+
+    match request.path() {
+        "/ws" => match request.accept(conn, None).await {
+            Ok(ws) => dashboard(ws).await,
+            Err(e) => warn!("handshake failed: {:?}", e),
+        },
+        "/" => {
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                PAGE.len()
+            );
+            let r = async {
+                conn.write_all(header.as_bytes()).await?;
+                conn.write_all(PAGE.as_bytes()).await?;
+                conn.flush().await
+            };
+            if let Err(e) = r.await {
+                warn!("write error: {:?}", e);
+            }
+        }
+        _ => {
+            let _ = request.reject(&mut conn, 404).await;
+        }
+    }
+}
+
+/// Push the uptime every second, and log the messages from the browser.
+async fn dashboard(mut ws: WebSocket<Connection>) {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut buf = [0; 256];
+    loop {
+        // Reading is cancel-safe, so it can be raced with the ticker.
+        let tick = match select(ws.read_message(&mut buf), ticker.next()).await {
+            Either::First(Ok(Message::Text(text))) => {
+                info!("received: {}", text);
+                if text == "bye" {
+                    let _ = ws.close(CloseCode::NORMAL, "bye").await;
+                }
+                false
+            }
+            Either::First(Ok(Message::Binary(_))) => false,
+            Either::First(Ok(Message::Close(frame))) => {
+                info!("closed: {:?}", frame);
+                return;
+            }
+            Either::First(Err(e)) => {
+                warn!("read error: {:?}", e);
+                return;
+            }
+            Either::Second(()) => true,
+        };
+        if tick {
+            let update = format!("{{\"uptime\": {}}}", Instant::now().as_secs());
+            if let Err(e) = ws.write_message(MessageType::Text, update.as_bytes()).await {
+                warn!("write error: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Choose between dhcp or static ip
+    let config = if opts.static_ip {
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+        })
+    } else {
+        Config::dhcpv4(Default::default())
+    };
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<{ SOCKETS + 1 }>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner).unwrap());
+
+    // Then we can use it!
+    static STATE: StaticCell<TcpListenerState<SOCKETS, 1024, 1024>> = StaticCell::new();
+    let mut listener = TcpListener::new(stack, STATE.init(TcpListenerState::new()), 8080, 2);
+    listener.set_timeout(Some(Duration::from_secs(30)));
+    info!("Listening on TCP:8080...");
+
+    loop {
+        match listener.accept().await {
+            Ok(conn) => spawner.spawn(http_task(conn).unwrap()),
+            Err(e) => {
+                warn!("accept error: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
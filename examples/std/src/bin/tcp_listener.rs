@@ -0,0 +1,122 @@
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::tcp::client::TcpConnection;
+use embassy_net::tcp::listener::{TcpListener, TcpListenerState};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::Duration;
+use embedded_io_async::{Read as _, Write as _};
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, TryRngCore};
+use static_cell::StaticCell;
+
+/// Sockets in the pool, shared by the listening sockets and the connections.
+const SOCKETS: usize = 4;
+/// Sockets kept listening, so that clients connecting together are all accepted.
+const BACKLOG: usize = 2;
+
+type Connection = TcpConnection<'static, SOCKETS, 1024, 1024>;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// use a static IP instead of DHCP
+    #[clap(long)]
+    static_ip: bool,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task(pool_size = SOCKETS)]
+async fn echo_task(mut conn: Connection) {
+    let remote = conn.remote_endpoint();
+    info!("Accepted a connection from {:?}", remote);
+
+    let mut buf = [0; 1024];
+    loop {
+        let n = match conn.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                warn!("read error: {:?}", e);
+                break;
+            }
+        };
+        if let Err(e) = conn.write_all(&buf[..n]).await {
+            warn!("write error: {:?}", e);
+            break;
+        }
+    }
+
+    info!("Closed the connection from {:?}", remote);
+    // Dropping the connection returns its socket to the listener's pool.
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Choose between dhcp or static ip
+    let config = if opts.static_ip {
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+        })
+    } else {
+        Config::dhcpv4(Default::default())
+    };
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.try_fill_bytes(&mut seed).unwrap();
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack, with a socket for DHCP in addition to the pool
+    static RESOURCES: StaticCell<StackResources<{ SOCKETS + 1 }>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner).unwrap());
+
+    // Then we can use it!
+    static STATE: StaticCell<TcpListenerState<SOCKETS, 1024, 1024>> = StaticCell::new();
+    let mut listener = TcpListener::new(stack, STATE.init(TcpListenerState::new()), 9999, BACKLOG);
+    listener.set_timeout(Some(Duration::from_secs(30)));
+    info!("Listening on TCP:9999...");
+
+    loop {
+        match listener.accept().await {
+            Ok(conn) => spawner.spawn(echo_task(conn).unwrap()),
+            Err(e) => {
+                warn!("accept error: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}